@@ -0,0 +1,42 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Syscalls through sallyport
+//!
+//! Each backend which the host supports runs a `Bench`, whose payload
+//! requests `close(-1)` from the host. An iteration enters the keep until it
+//! exits with the request, and executes it as the loader would, so that it
+//! measures one round trip through the backend and the shim. Criterion
+//! reports it as syscalls per second, too:
+//!
+//!     $ cargo bench --bench enter
+//!
+//! `ENARX_BACKEND` restricts the benchmarks to one backend.
+
+use enarx_keepldr::backend::{self, Config};
+use enarx_keepldr::bench::Bench;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+fn enter(c: &mut Criterion) {
+    let backends = backend::all();
+    let selected = std::env::var("ENARX_BACKEND").ok();
+
+    let mut group = c.benchmark_group("enter");
+    group.throughput(Throughput::Elements(1));
+
+    for backend in backends.iter().filter(|b| match selected.as_deref() {
+        Some(name) => name == b.name(),
+        None => b.have(),
+    }) {
+        let mut keep = Bench::new(&**backend, &Config::default()).unwrap();
+
+        group.bench_function(backend.name(), |b| {
+            b.iter_custom(|iters| keep.syscalls(iters).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, enter);
+criterion_main!(benches);
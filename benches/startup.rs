@@ -0,0 +1,51 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Startup of keeps
+//!
+//! An iteration builds a `Bench` with each backend which the host supports,
+//! and measures the time from the start of the build until its payload runs,
+//! when it asks the host for its first command:
+//!
+//!     $ cargo bench --bench startup
+//!
+//! `ENARX_BACKEND` restricts the benchmarks to one backend. Only one nil keep
+//! can be built in a process, so the nil backend is left out.
+
+use enarx_keepldr::backend::{self, Config};
+use enarx_keepldr::bench::Bench;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use std::time::Duration;
+
+fn startup(c: &mut Criterion) {
+    let backends = backend::all();
+    let selected = std::env::var("ENARX_BACKEND").ok();
+
+    // Building a keep takes long, so fewer samples are taken.
+    let mut group = c.benchmark_group("startup");
+    group.sample_size(10);
+
+    for backend in backends.iter().filter(|b| match selected.as_deref() {
+        Some(name) => name == b.name(),
+        None => b.have(),
+    }) {
+        if backend.name() == "nil" {
+            continue;
+        }
+
+        group.bench_function(backend.name(), |b| {
+            b.iter_custom(|iters| {
+                (0..iters)
+                    .map(|_| Bench::new(&**backend, &Config::default()).unwrap())
+                    .map(|keep| keep.startup())
+                    .sum::<Duration>()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, startup);
+criterion_main!(benches);
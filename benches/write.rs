@@ -0,0 +1,43 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Large writes from keeps
+//!
+//! Each backend which the host supports runs a `Bench`, whose payload writes
+//! to the host in writes of a MiB, which the shim splits into as many
+//! requests as it takes to pass the data through sallyport. An iteration
+//! writes a MiB, and Criterion reports the throughput:
+//!
+//!     $ cargo bench --bench write
+//!
+//! `ENARX_BACKEND` restricts the benchmarks to one backend.
+
+use enarx_keepldr::backend::{self, Config};
+use enarx_keepldr::bench::Bench;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+const MIB: u64 = 1 << 20;
+
+fn write(c: &mut Criterion) {
+    let backends = backend::all();
+    let selected = std::env::var("ENARX_BACKEND").ok();
+
+    let mut group = c.benchmark_group("write");
+    group.throughput(Throughput::Bytes(MIB));
+
+    for backend in backends.iter().filter(|b| match selected.as_deref() {
+        Some(name) => name == b.name(),
+        None => b.have(),
+    }) {
+        let mut keep = Bench::new(&**backend, &Config::default()).unwrap();
+
+        group.bench_function(backend.name(), |b| {
+            b.iter_custom(|iters| keep.write(iters * MIB).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, write);
+criterion_main!(benches);
@@ -9,6 +9,7 @@ use walkdir::WalkDir;
 
 const CRATE: &str = env!("CARGO_MANIFEST_DIR");
 const TEST_BINS_IN: &str = "tests/bin";
+const TEST_SYSCALLS_IN: &str = "tests/syscall";
 
 fn find_files_with_extensions<'a>(
     exts: &'a [&'a str],
@@ -141,12 +142,23 @@ fn main() {
         .run()
         .expect("Protobuf codegen failed");
 
+    #[cfg(feature = "serve")]
+    tonic_build::configure()
+        .build_client(false)
+        .compile(&["src/protobuf/keepldr.proto"], &["src/protobuf"])
+        .expect("gRPC codegen failed");
+
     let out_dir_bin = out_dir.join("bin");
     create(&out_dir_bin);
 
     build_cc_tests(&Path::new(CRATE).join(TEST_BINS_IN), &out_dir_bin);
     build_rs_tests(&Path::new(CRATE).join(TEST_BINS_IN), &out_dir_bin);
 
+    let out_dir_syscall = out_dir.join("syscall");
+    create(&out_dir_syscall);
+
+    build_cc_tests(&Path::new(CRATE).join(TEST_SYSCALLS_IN), &out_dir_syscall);
+
     let profile: &[&str] = match std::env::var("PROFILE").unwrap().as_str() {
         "release" => &["--release"],
         _ => &[],
@@ -154,9 +166,27 @@ fn main() {
 
     let target_name = "x86_64-unknown-linux-musl";
 
+    // The SEV shim verifies the payload manifests with this key, if it is
+    // set, and the loader refuses to build SGX keeps, which don't. The shims
+    // size their code slot after `ENARX_CODE_SIZE`.
+    println!("cargo:rerun-if-env-changed=ENARX_MANIFEST_KEY");
+    println!("cargo:rerun-if-env-changed=ENARX_CODE_SIZE");
+
+    // Prebuilt shims are found with these, by default (see `backend::shim`).
+    for var in &["ENARX_SHIM_SGX", "ENARX_SHIM_SEV"] {
+        println!("cargo:rerun-if-env-changed={}", var);
+        println!("cargo:rerun-if-env-changed={}_SHA256", var);
+    }
+
     let filtered_env: HashMap<String, String> = std::env::vars()
         .filter(|&(ref k, _)| {
-            k == "TERM" || k == "TZ" || k == "LANG" || k == "PATH" || k == "RUSTUP_HOME"
+            k == "TERM"
+                || k == "TZ"
+                || k == "LANG"
+                || k == "PATH"
+                || k == "RUSTUP_HOME"
+                || k == "ENARX_MANIFEST_KEY"
+                || k == "ENARX_CODE_SIZE"
         })
         .collect();
 
@@ -197,7 +227,19 @@ fn main() {
 
         rerun_src(&path);
 
-        if !shim_name.starts_with("shim-") {
+        // The WebAssembly runtime is built like a shim, and then runs as
+        // the payload.
+        if !shim_name.starts_with("shim-") && shim_name != "wasmldr" {
+            continue;
+        }
+
+        #[cfg(not(feature = "wasm"))]
+        if shim_name == "wasmldr" {
+            continue;
+        }
+
+        #[cfg(not(feature = "builtin-shims"))]
+        if shim_name.starts_with("shim-") {
             continue;
         }
 
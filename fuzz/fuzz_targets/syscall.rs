@@ -0,0 +1,52 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Executes arbitrary blocks, as a malicious keep could leave them
+//!
+//! The host executes the allowed syscalls with whatever addresses the block
+//! holds, which may point anywhere in the fuzzer, so only syscalls without
+//! pointers are allowed. The arguments of the request which are smaller
+//! than a block are offsets into it, so that the fuzzer finds the buffers
+//! which the host reads and writes.
+
+#![no_main]
+
+use enarx_keepldr::environ::Environ;
+use enarx_keepldr::executor::Executor;
+use enarx_keepldr::mount::Mounts;
+use enarx_keepldr::policy::SyscallPolicy;
+use enarx_keepldr::streams::Streams;
+
+use libfuzzer_sys::fuzz_target;
+use sallyport::Block;
+
+use std::mem::size_of;
+
+const SYSCALLS: &[&str] = &["close", "dup", "fcntl", "getpid", "sched_yield"];
+
+fuzz_target!(|data: &[u8]| {
+    let args = vec!["fuzz".into()];
+    let vars = vec!["HOME=/".into()];
+    let environ = Environ::new(args, vars).unwrap();
+    let mounts = Mounts::new(vec![]).unwrap();
+    let streams = Streams::default();
+    let policy = SyscallPolicy::default()
+        .syscalls(SYSCALLS)
+        .unwrap()
+        .fds(&[]);
+
+    let mut block = Block::default();
+    let start = &mut block as *mut Block as usize;
+    let len = data.len().min(size_of::<Block>());
+    unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), start as *mut u8, len) };
+
+    let req = unsafe { &mut block.msg.req };
+    for arg in req.arg.iter_mut() {
+        let offset = usize::from(*arg);
+        if offset < size_of::<Block>() {
+            *arg = (start + offset).into();
+        }
+    }
+
+    let mut executor = Executor::new(&environ, &mounts, &streams, &policy).deterministic(0);
+    executor.syscall(&mut block);
+});
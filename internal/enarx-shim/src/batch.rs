@@ -0,0 +1,189 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The queue of deferred writes
+//!
+//! The SGX shim queues writes in a block of their own, which it sends to the
+//! host as a whole (see its `batch` module). Only writes to stdout and stderr
+//! are deferred: the payload reads other files and sockets back, often
+//! through other descriptors or from another process, so their writes have
+//! to reach the host before the payload goes on.
+//!
+//! A deferred write is reported as done when it is queued. If the host fails
+//! it later, the error is kept for its descriptor and returned by the next
+//! write to or close of it, as Linux does for the errors of write-back.
+
+use libc::c_int;
+
+/// The maximum number of queued writes
+pub const SLOTS: usize = 16;
+
+/// The descriptors whose writes are deferred
+const DEFERRED: [c_int; 2] = [libc::STDOUT_FILENO, libc::STDERR_FILENO];
+
+/// The queued writes and the errors of those already sent
+#[derive(Debug)]
+pub struct Queue {
+    /// The offset of the data of the writes in their block
+    start: usize,
+
+    /// The size of their block
+    size: usize,
+
+    /// The number of queued writes
+    writes: usize,
+
+    /// The end of their data in the block
+    end: usize,
+
+    /// The first error of each descriptor in `DEFERRED`
+    errors: [Option<c_int>; DEFERRED.len()],
+}
+
+impl Queue {
+    /// Creates an empty queue, whose data lies in `start..size` of its block
+    pub const fn new(start: usize, size: usize) -> Self {
+        Self {
+            start,
+            size,
+            writes: 0,
+            end: start,
+            errors: [None; DEFERRED.len()],
+        }
+    }
+
+    /// Whether the writes to `fd` may be deferred
+    pub fn deferred(fd: c_int) -> bool {
+        DEFERRED.contains(&fd)
+    }
+
+    /// Whether a `write()` of `len` bytes to `fd` goes through the queue
+    ///
+    /// This holds for the writes which return a kept error, too.
+    pub fn defers(&self, fd: c_int, len: usize) -> bool {
+        match DEFERRED.iter().position(|d| *d == fd) {
+            Some(i) if self.errors[i].is_some() => true,
+            Some(_) => len > 0 && len <= self.size - self.start,
+            None => false,
+        }
+    }
+
+    /// Whether no writes are queued
+    pub fn is_empty(&self) -> bool {
+        self.writes == 0
+    }
+
+    /// Queues a write of `len` bytes
+    ///
+    /// The index of its slot and the offset of its data in the block are
+    /// returned, or `None` if the queue has to be sent first.
+    pub fn push(&mut self, len: usize) -> Option<(usize, usize)> {
+        if self.writes == SLOTS || len > self.size - self.end {
+            return None;
+        }
+
+        let queued = (self.writes, self.end);
+        self.writes += 1;
+        self.end += len;
+        Some(queued)
+    }
+
+    /// Empties the queue, returning the number of writes which were queued
+    pub fn take(&mut self) -> usize {
+        self.end = self.start;
+        core::mem::replace(&mut self.writes, 0)
+    }
+
+    /// Keeps the error of a deferred write to `fd`
+    ///
+    /// Only the first error is kept until it is returned.
+    pub fn fail(&mut self, fd: c_int, errno: c_int) {
+        if let Some(i) = DEFERRED.iter().position(|d| *d == fd) {
+            self.errors[i].get_or_insert(errno);
+        }
+    }
+
+    /// Returns the kept error of `fd`, forgetting it
+    pub fn error(&mut self, fd: c_int) -> Option<c_int> {
+        let i = DEFERRED.iter().position(|d| *d == fd)?;
+        self.errors[i].take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defers() {
+        assert!(Queue::deferred(libc::STDOUT_FILENO));
+        assert!(Queue::deferred(libc::STDERR_FILENO));
+        assert!(!Queue::deferred(libc::STDIN_FILENO));
+
+        let mut queue = Queue::new(100, 200);
+        assert!(queue.defers(libc::STDOUT_FILENO, 1));
+        assert!(queue.defers(libc::STDERR_FILENO, 100));
+        assert!(!queue.defers(libc::STDOUT_FILENO, 0));
+        assert!(!queue.defers(libc::STDOUT_FILENO, 101));
+        assert!(!queue.defers(libc::STDIN_FILENO, 1));
+        assert!(!queue.defers(3, 1));
+
+        // Writes which return an error are handled by the queue, too.
+        queue.fail(libc::STDOUT_FILENO, libc::EPIPE);
+        assert!(queue.defers(libc::STDOUT_FILENO, 0));
+        assert!(queue.defers(libc::STDOUT_FILENO, 1000));
+    }
+
+    #[test]
+    fn push() {
+        let mut queue = Queue::new(100, 200);
+        assert!(queue.is_empty());
+
+        assert_eq!(queue.push(60), Some((0, 100)));
+        assert_eq!(queue.push(30), Some((1, 160)));
+        assert!(!queue.is_empty());
+
+        // The data doesn't fit anymore.
+        assert_eq!(queue.push(11), None);
+        assert_eq!(queue.push(10), Some((2, 190)));
+        assert_eq!(queue.push(1), None);
+
+        assert_eq!(queue.take(), 3);
+        assert!(queue.is_empty());
+        assert_eq!(queue.push(100), Some((0, 100)));
+    }
+
+    #[test]
+    fn slots() {
+        let mut queue = Queue::new(0, SLOTS * 2);
+        for i in 0..SLOTS {
+            assert_eq!(queue.push(1), Some((i, i)));
+        }
+
+        // There is room for the data, but not for another write.
+        assert_eq!(queue.push(1), None);
+        assert_eq!(queue.take(), SLOTS);
+        assert_eq!(queue.push(1), Some((0, 0)));
+    }
+
+    #[test]
+    fn errors() {
+        let mut queue = Queue::new(0, 100);
+        assert_eq!(queue.error(libc::STDOUT_FILENO), None);
+
+        queue.fail(libc::STDOUT_FILENO, libc::EPIPE);
+        queue.fail(libc::STDOUT_FILENO, libc::EIO);
+        queue.fail(libc::STDERR_FILENO, libc::ENOSPC);
+        queue.fail(3, libc::EIO);
+
+        // The first error of each descriptor is returned once.
+        assert_eq!(queue.error(libc::STDOUT_FILENO), Some(libc::EPIPE));
+        assert_eq!(queue.error(libc::STDOUT_FILENO), None);
+        assert_eq!(queue.error(libc::STDERR_FILENO), Some(libc::ENOSPC));
+        assert_eq!(queue.error(3), None);
+
+        // Emptying the queue keeps the errors.
+        queue.fail(libc::STDOUT_FILENO, libc::EIO);
+        queue.take();
+        assert_eq!(queue.error(libc::STDOUT_FILENO), Some(libc::EIO));
+    }
+}
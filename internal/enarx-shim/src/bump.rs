@@ -0,0 +1,116 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A bump allocator of address space
+//!
+//! The SGX shim reserves the addresses of its dynamic mappings in a region
+//! of the enclave which has no pages until they are added (see its `edmm`
+//! module). Address space is handed out from the start of the region
+//! upwards, and only reclaimed when the last reservation is released.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use lset::Line;
+
+/// The address space of a region, handed out from its start
+pub struct Bump {
+    /// The end of the reserved address space, or 0 before the first
+    /// reservation
+    next: AtomicUsize,
+}
+
+impl Bump {
+    /// Creates an allocator which hasn't reserved anything yet
+    pub const fn new() -> Self {
+        Self {
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Reserves `length` bytes of address space in the region
+    ///
+    /// `None` is returned when the region has too little left.
+    pub fn reserve(&self, region: Line<usize>, length: usize) -> Option<usize> {
+        let mut next = self.next.load(Ordering::Relaxed);
+
+        loop {
+            let start = match next {
+                0 => region.start,
+                n => n,
+            };
+
+            let end = start.checked_add(length)?;
+            if end > region.end {
+                return None;
+            }
+
+            match self
+                .next
+                .compare_exchange_weak(next, end, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return Some(start),
+                Err(n) => next = n,
+            }
+        }
+    }
+
+    /// Returns address space to the region
+    ///
+    /// The address space is only reclaimed when it is at the end of the
+    /// reserved address space.
+    pub fn release(&self, addr: usize, length: usize) {
+        if let Some(end) = addr.checked_add(length) {
+            let _ = self
+                .next
+                .compare_exchange(end, addr, Ordering::Relaxed, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Default for Bump {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGION: Line<usize> = Line {
+        start: 0x10000,
+        end: 0x20000,
+    };
+
+    #[test]
+    fn reserve() {
+        let bump = Bump::new();
+
+        assert_eq!(bump.reserve(REGION, 0x1000), Some(0x10000));
+        assert_eq!(bump.reserve(REGION, 0x2000), Some(0x11000));
+        assert_eq!(bump.reserve(REGION, 0xd000), Some(0x13000));
+
+        // The region is full.
+        assert_eq!(bump.reserve(REGION, 0x1000), None);
+        assert_eq!(bump.reserve(REGION, usize::MAX), None);
+    }
+
+    #[test]
+    fn release() {
+        let bump = Bump::new();
+
+        let a = bump.reserve(REGION, 0x1000).unwrap();
+        let b = bump.reserve(REGION, 0x2000).unwrap();
+
+        // Only the last reservation is reclaimed.
+        bump.release(a, 0x1000);
+        assert_eq!(bump.reserve(REGION, 0x1000), Some(0x13000));
+        bump.release(0x13000, 0x1000);
+
+        bump.release(b, 0x2000);
+        assert_eq!(bump.reserve(REGION, 0x3000), Some(0x11000));
+
+        // Nothing beyond the reserved address space is reclaimed.
+        bump.release(0x18000, 0x1000);
+        assert_eq!(bump.reserve(REGION, 0x1000), Some(0x14000));
+    }
+}
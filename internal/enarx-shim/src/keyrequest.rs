@@ -0,0 +1,181 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Requests for SGX seal keys (`EGETKEY`)
+//!
+//! The CPU derives a seal key from the identity of the enclave and from the
+//! security versions in the request: that of the platform (CPUSVN) and that
+//! of the enclave (ISVSVN). It refuses versions above those of the enclave,
+//! so an enclave can derive the keys of its older versions, but never those
+//! of newer ones.
+//!
+//! Keys are requested for the versions which the report of the enclave
+//! holds, so that an update of the platform or of the enclave which fixes a
+//! vulnerability changes them. The only downgrade is explicit: a caller may
+//! ask for the key of an older ISVSVN of the enclave, e.g. to read what an
+//! older version sealed. The CPUSVN is always that of the platform.
+
+use core::convert::TryInto;
+
+/// The size of a report (`EREPORT`)
+pub const REPORT_SIZE: usize = 432;
+
+/// KEYREQUEST.KEYPOLICY (see Table 38-22)
+pub mod policy {
+    /// Derive the key from the measurement of the enclave
+    pub const MRENCLAVE: u16 = 1 << 0;
+
+    /// Derive the key from the signer of the enclave
+    pub const MRSIGNER: u16 = 1 << 1;
+}
+
+/// KEYREQUEST.KEYNAME of seal keys
+const SEAL_KEY: u16 = 4;
+
+/// The ATTRIBUTES.FLAGS bound to the key: INIT and DEBUG
+///
+/// Debug enclaves can be inspected by the host, so they must never derive
+/// the key of a production enclave.
+const FLAGS_MASK: u64 = 0b11;
+
+/// The offset of CPUSVN in a report
+const CPUSVN: usize = 0;
+
+/// The offset of ISVSVN in a report
+const ISVSVN: usize = 258;
+
+/// The security versions of an enclave, from its report
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    /// The security version of the platform
+    pub cpusvn: [u8; 16],
+
+    /// The security version of the enclave
+    pub isvsvn: u16,
+}
+
+impl Identity {
+    /// Reads the versions from a report of the enclave
+    pub fn from_report(report: &[u8; REPORT_SIZE]) -> Self {
+        Self {
+            cpusvn: report[CPUSVN..][..16].try_into().unwrap(),
+            isvsvn: u16::from_le_bytes(report[ISVSVN..][..2].try_into().unwrap()),
+        }
+    }
+}
+
+/// KEYREQUEST (see Table 38-21)
+#[repr(C, align(512))]
+#[derive(Copy, Clone)]
+pub struct KeyRequest {
+    name: u16,
+    policy: u16,
+    isvsvn: u16,
+    reserved0: u16,
+    cpusvn: [u8; 16],
+    attributes: [u64; 2],
+    keyid: [u8; 32],
+    miscmask: u32,
+    configsvn: u16,
+    reserved1: [u8; 434],
+}
+
+impl KeyRequest {
+    /// A request for the seal key of an enclave
+    ///
+    /// `isvsvn` asks for the key of an older version of the enclave, and
+    /// `None` for that of its own. Different values of `keyid` yield
+    /// unrelated keys. `None` is returned for invalid policies and for
+    /// versions above that of the enclave.
+    pub fn seal(
+        policy: u16,
+        identity: &Identity,
+        isvsvn: Option<u16>,
+        keyid: &[u8; 32],
+    ) -> Option<Self> {
+        if policy == 0 || policy & !(policy::MRENCLAVE | policy::MRSIGNER) != 0 {
+            return None;
+        }
+
+        let isvsvn = match isvsvn {
+            Some(isvsvn) if isvsvn > identity.isvsvn => return None,
+            Some(isvsvn) => isvsvn,
+            None => identity.isvsvn,
+        };
+
+        Some(Self {
+            name: SEAL_KEY,
+            policy,
+            isvsvn,
+            reserved0: 0,
+            cpusvn: identity.cpusvn,
+            attributes: [FLAGS_MASK, 0],
+            keyid: *keyid,
+            miscmask: 0,
+            configsvn: 0,
+            reserved1: [0; 434],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::mem::size_of;
+
+    fn identity() -> Identity {
+        let mut report = [0; REPORT_SIZE];
+        report[..16].copy_from_slice(&[7; 16]);
+        report[258..][..2].copy_from_slice(&3u16.to_le_bytes());
+        Identity::from_report(&report)
+    }
+
+    #[test]
+    fn layout() {
+        assert_eq!(size_of::<KeyRequest>(), 512);
+
+        let request = KeyRequest::seal(policy::MRENCLAVE, &identity(), None, &[0; 32]).unwrap();
+        let base = &request as *const _ as usize;
+        assert_eq!(&request.isvsvn as *const _ as usize - base, 4);
+        assert_eq!(&request.cpusvn as *const _ as usize - base, 8);
+        assert_eq!(&request.attributes as *const _ as usize - base, 24);
+        assert_eq!(&request.keyid as *const _ as usize - base, 40);
+        assert_eq!(&request.miscmask as *const _ as usize - base, 72);
+    }
+
+    #[test]
+    fn fields() {
+        let identity = identity();
+        assert_eq!(identity.cpusvn, [7; 16]);
+        assert_eq!(identity.isvsvn, 3);
+
+        let request = KeyRequest::seal(policy::MRSIGNER, &identity, None, &[9; 32]).unwrap();
+        assert_eq!(request.name, SEAL_KEY);
+        assert_eq!(request.policy, policy::MRSIGNER);
+        assert_eq!(request.isvsvn, 3);
+        assert_eq!(request.cpusvn, [7; 16]);
+        assert_eq!(request.attributes, [FLAGS_MASK, 0]);
+        assert_eq!(request.keyid, [9; 32]);
+    }
+
+    #[test]
+    fn older() {
+        let identity = identity();
+
+        let request = KeyRequest::seal(policy::MRENCLAVE, &identity, Some(1), &[0; 32]).unwrap();
+        assert_eq!(request.isvsvn, 1);
+        assert_eq!(request.cpusvn, identity.cpusvn);
+
+        assert!(KeyRequest::seal(policy::MRENCLAVE, &identity, Some(3), &[0; 32]).is_some());
+        assert!(KeyRequest::seal(policy::MRENCLAVE, &identity, Some(4), &[0; 32]).is_none());
+    }
+
+    #[test]
+    fn policies() {
+        let identity = identity();
+
+        assert!(KeyRequest::seal(0, &identity, None, &[0; 32]).is_none());
+        assert!(KeyRequest::seal(1 << 2, &identity, None, &[0; 32]).is_none());
+        assert!(KeyRequest::seal(0b11, &identity, None, &[0; 32]).is_some());
+    }
+}
@@ -0,0 +1,22 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! What the shims share
+//!
+//! The SGX and the SEV shim are binaries for their own targets, which can't
+//! be tested on the host. The parts of them which don't depend on their
+//! platform live here instead, where they are tested, and the shims use them
+//! from here.
+
+#![no_std]
+#![deny(clippy::all)]
+#![deny(missing_docs)]
+
+#[cfg(test)]
+extern crate std;
+
+pub mod batch;
+pub mod bump;
+pub mod keyrequest;
+pub mod perthread;
+pub mod random;
+pub mod syscall;
@@ -0,0 +1,82 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! State kept per thread
+//!
+//! Some of the state which the shims keep for the payload belongs to each of
+//! its threads, e.g. the signal mask. A table holds it for up to `N` threads,
+//! keyed by an identifier which the running threads don't share (on SGX, the
+//! address of their TCS). A thread gets its entry when it first asks for it.
+
+/// The state of up to `N` threads
+pub struct PerThread<T, const N: usize> {
+    threads: [Option<(usize, T)>; N],
+}
+
+impl<T, const N: usize> PerThread<T, N> {
+    /// Creates a table from its entries, which are usually all `None`
+    pub const fn new(threads: [Option<(usize, T)>; N]) -> Self {
+        Self { threads }
+    }
+
+    /// Returns the state of a thread, which starts as `init`
+    ///
+    /// `None` is returned if the table is full.
+    pub fn get(&mut self, id: usize, init: T) -> Option<&mut T> {
+        let index = match self.find(id) {
+            Some(index) => index,
+            None => {
+                let index = self.threads.iter().position(Option::is_none)?;
+                self.threads[index] = Some((id, init));
+                index
+            }
+        };
+
+        self.threads[index].as_mut().map(|(_, state)| state)
+    }
+
+    /// Forgets the state of a thread, e.g. when it exits
+    pub fn remove(&mut self, id: usize) {
+        if let Some(index) = self.find(id) {
+            self.threads[index] = None;
+        }
+    }
+
+    /// Returns the index of the entry of a thread
+    fn find(&self, id: usize) -> Option<usize> {
+        self.threads
+            .iter()
+            .position(|t| matches!(t, Some((i, _)) if *i == id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threads() {
+        let mut table = PerThread::<u64, 2>::new([None; 2]);
+
+        assert_eq!(*table.get(0x1000, 7).unwrap(), 7);
+        *table.get(0x1000, 7).unwrap() = 8;
+        assert_eq!(*table.get(0x1000, 0).unwrap(), 8);
+
+        // Each thread has its own state.
+        assert_eq!(*table.get(0x2000, 3).unwrap(), 3);
+        assert_eq!(*table.get(0x1000, 0).unwrap(), 8);
+    }
+
+    #[test]
+    fn full() {
+        let mut table = PerThread::<u64, 2>::new([None; 2]);
+        table.get(1, 1).unwrap();
+        table.get(2, 2).unwrap();
+        assert!(table.get(3, 3).is_none());
+
+        // The entry of a thread which is gone is reused.
+        table.remove(1);
+        assert_eq!(*table.get(3, 3).unwrap(), 3);
+        assert_eq!(*table.get(2, 0).unwrap(), 2);
+        assert!(table.get(1, 1).is_none());
+    }
+}
@@ -0,0 +1,73 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Random numbers from the CPU
+//!
+//! `RDRAND` may fail transiently, in which case it is retried. Its output is
+//! also checked for the failures seen in practice: a generator which keeps
+//! returning the same value, such as all ones.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// The number of attempts before the generator is considered broken
+const RETRIES: usize = 10;
+
+/// The last value returned by the generator
+static LAST: AtomicU64 = AtomicU64::new(0);
+
+/// Gets a random number
+///
+/// `None` is returned if the generator is broken.
+pub fn try_random() -> Option<u64> {
+    for _ in 0..RETRIES {
+        let mut r = 0u64;
+        if unsafe { core::arch::x86_64::_rdrand64_step(&mut r) } != 1 {
+            continue;
+        }
+
+        if r != u64::MAX && LAST.swap(r, Ordering::Relaxed) != r {
+            return Some(r);
+        }
+    }
+
+    None
+}
+
+/// Gets a random number, panicking if the generator is broken
+pub fn random() -> u64 {
+    match try_random() {
+        Some(r) => r,
+        None => panic!("Could not get random!"),
+    }
+}
+
+/// Fills a buffer with random bytes
+///
+/// `false` is returned if the generator is broken.
+pub fn fill(buf: &mut [u8]) -> bool {
+    for chunk in buf.chunks_mut(8) {
+        match try_random() {
+            Some(r) => chunk.copy_from_slice(&r.to_le_bytes()[..chunk.len()]),
+            None => return false,
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill() {
+        if !std::is_x86_feature_detected!("rdrand") {
+            return;
+        }
+
+        // Also the bytes of a partial chunk are filled.
+        let mut buf = [0u8; 1021];
+        assert!(super::fill(&mut buf));
+        assert!(buf[1013..].iter().any(|b| *b != 0));
+        assert_ne!(try_random(), try_random());
+    }
+}
@@ -0,0 +1,336 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! File syscalls on host file descriptors
+//!
+//! The data is gathered into and scattered from the data area of the block.
+//! Transfers which don't fit in it are done in part, which the payload sees
+//! as a short read or write. The descriptors which the host creates or
+//! duplicates may not be mistaken for those of the in-keep filesystem.
+//!
+//! `readv()` and `writev()` are methods of
+//! `sallyport::syscall::FileSyscallHandler` too, so the shims implement those
+//! by calling the ones here.
+
+use super::FsSyscallHandler;
+
+use core::mem::size_of;
+
+use memfs::MemFs;
+use primordial::Register;
+use sallyport::untrusted::{AddressValidator, UntrustedRef, UntrustedRefMut, ValidateSlice};
+use sallyport::{request, Block};
+
+/// The most buffers of a vector, as on Linux
+const IOV_MAX: libc::c_int = 1024;
+
+/// The lengths of the buffers of a vector which fit in a block along with it
+///
+/// Every buffer takes room for its `iovec` and its data. The vector ends
+/// with the first buffer which doesn't fit whole, so that the host fills the
+/// buffers in order.
+fn lengths(iovec: &[libc::iovec]) -> impl Iterator<Item = usize> + '_ {
+    iovec.iter().scan(Some(Block::buf_capacity()), |room, iov| {
+        let left = room.take()?.checked_sub(size_of::<libc::iovec>())?;
+        let len = iov.iov_len.min(left);
+        if len == iov.iov_len {
+            *room = Some(left - len);
+        }
+
+        Some(len)
+    })
+}
+
+/// Validates a vector and the buffers of it which fit in a block
+fn validate_iovec<'b>(
+    validator: &impl AddressValidator,
+    iovec: UntrustedRef<libc::iovec>,
+    iovcnt: libc::c_int,
+) -> Result<&'b [libc::iovec], libc::c_int> {
+    if !(0..=IOV_MAX).contains(&iovcnt) {
+        return Err(libc::EINVAL);
+    }
+
+    let trusted = iovec
+        .validate_slice(iovcnt, validator)
+        .ok_or(libc::EFAULT)?;
+    for (t, len) in trusted.iter().zip(lengths(trusted)) {
+        UntrustedRef::from(t.iov_base as *const u8)
+            .validate_slice(len, validator)
+            .ok_or(libc::EFAULT)?;
+    }
+
+    Ok(trusted)
+}
+
+/// The syscalls on host file descriptors
+pub trait HostFileSyscallHandler: FsSyscallHandler {
+    /// Whether a host descriptor may be duplicated
+    ///
+    /// The SGX shim doesn't duplicate sealed files, since the host would
+    /// write the duplicates unsealed.
+    fn duplicable(&self, _fd: libc::c_int) -> bool {
+        true
+    }
+
+    /// Do a pread64() syscall on a host file descriptor
+    fn pread64(
+        &mut self,
+        fd: Register<usize>,
+        buf: Register<usize>,
+        count: Register<usize>,
+        offset: Register<usize>,
+    ) -> sallyport::Result {
+        self.trace("pread64", 4);
+
+        let buf = self.buffer(buf, count)?;
+        let offset = Self::offset(offset)?;
+        let len = buf.len().min(Block::buf_capacity());
+
+        let c = self.new_cursor();
+        let (_, hbuf) = c.alloc::<u8>(len).or(Err(libc::EMSGSIZE))?;
+        let hbuf = Self::translate_shim_to_host_addr(hbuf.as_ptr());
+
+        let req = request!(libc::SYS_pread64 => usize::from(fd), hbuf, len, offset);
+        let ret = unsafe { self.proxy(req)? };
+
+        let size: usize = ret[0].into();
+        if size > len {
+            self.attacked();
+        }
+
+        let c = self.new_cursor();
+        unsafe { c.copy_into_raw_parts(len, buf.as_mut_ptr(), size) }.or(Err(libc::EMSGSIZE))?;
+
+        Ok(ret)
+    }
+
+    /// Do a pwrite64() syscall on a host file descriptor
+    fn pwrite64(
+        &mut self,
+        fd: Register<usize>,
+        buf: Register<usize>,
+        count: Register<usize>,
+        offset: Register<usize>,
+    ) -> sallyport::Result {
+        self.trace("pwrite64", 4);
+
+        let buf = self.buffer(buf, count)?;
+        let offset = Self::offset(offset)?;
+        let len = buf.len().min(Block::buf_capacity());
+
+        let c = self.new_cursor();
+        let (_, hbuf) = c.copy_from_slice(&buf[..len]).or(Err(libc::EMSGSIZE))?;
+        let hbuf = Self::translate_shim_to_host_addr(hbuf.as_ptr());
+
+        let req = request!(libc::SYS_pwrite64 => usize::from(fd), hbuf, len, offset);
+        let ret = unsafe { self.proxy(req)? };
+
+        if usize::from(ret[0]) > len {
+            self.attacked();
+        }
+
+        Ok(ret)
+    }
+
+    /// Do a pipe2() syscall
+    ///
+    /// The host creates the pipe. Its descriptors may not be mistaken for
+    /// those of the in-keep filesystem.
+    fn pipe2(
+        &mut self,
+        pipefd: UntrustedRefMut<libc::c_int>,
+        flags: libc::c_int,
+    ) -> sallyport::Result {
+        self.trace("pipe2", 2);
+
+        let pipefd = pipefd.validate_slice(2usize, self).ok_or(libc::EFAULT)?;
+
+        let c = self.new_cursor();
+        let (_, hpipefd) = c.alloc::<libc::c_int>(2).or(Err(libc::EMSGSIZE))?;
+        let hpipefd = Self::translate_shim_to_host_addr(hpipefd.as_ptr());
+
+        let ret = unsafe { self.proxy(request!(libc::SYS_pipe2 => hpipefd, flags))? };
+
+        let c = self.new_cursor();
+        unsafe { c.copy_into_raw_parts(2, pipefd.as_mut_ptr(), 2) }.or(Err(libc::EMSGSIZE))?;
+
+        if pipefd.iter().any(|fd| *fd < 0 || MemFs::owns(*fd)) {
+            self.attacked();
+        }
+
+        Ok(ret)
+    }
+
+    /// Do a dup(), dup2() or dup3() syscall on a host file descriptor
+    ///
+    /// Host descriptors can't be duplicated into the in-keep filesystem, and
+    /// the host refuses descriptors that high anyway.
+    fn dup(&mut self, nr: libc::c_long, args: [usize; 3]) -> sallyport::Result {
+        match nr {
+            libc::SYS_dup => self.trace("dup", 1),
+            libc::SYS_dup2 => self.trace("dup2", 2),
+            _ => self.trace("dup3", 3),
+        }
+
+        if !self.duplicable(args[0] as _) {
+            return Err(libc::ENOTSUP);
+        }
+
+        let ret = unsafe { self.proxy(request!(nr => args[0], args[1], args[2]))? };
+        if MemFs::owns(usize::from(ret[0]) as _) {
+            self.attacked();
+        }
+
+        Ok(ret)
+    }
+
+    /// Do a fcntl() syscall on a host file descriptor
+    ///
+    /// Only the commands which take an integer are passed to the host: those
+    /// duplicating descriptors and getting or setting their flags. Those which
+    /// `duplicable()` refuses aren't duplicated, like with `dup()`.
+    fn fcntl(&mut self, fd: libc::c_int, cmd: libc::c_int, arg: libc::c_int) -> sallyport::Result {
+        self.trace("fcntl", 3);
+
+        let dup = match cmd {
+            libc::F_DUPFD | libc::F_DUPFD_CLOEXEC => true,
+            libc::F_GETFD | libc::F_SETFD | libc::F_GETFL | libc::F_SETFL => false,
+            _ => return Err(libc::EINVAL),
+        };
+
+        if dup && !self.duplicable(fd) {
+            return Err(libc::ENOTSUP);
+        }
+
+        let ret = unsafe { self.proxy(request!(libc::SYS_fcntl => fd, cmd, arg))? };
+        if dup && MemFs::owns(usize::from(ret[0]) as _) {
+            self.attacked();
+        }
+
+        Ok(ret)
+    }
+
+    /// Do a readv() syscall
+    fn readv(
+        &mut self,
+        fd: libc::c_int,
+        iovec: UntrustedRef<libc::iovec>,
+        iovcnt: libc::c_int,
+    ) -> sallyport::Result {
+        self.trace("readv", 3);
+
+        let trusted = validate_iovec(self, iovec, iovcnt)?;
+        let count = lengths(trusted).count();
+
+        let c = self.new_cursor();
+        let (c, untrusted) = c
+            .copy_from_slice::<libc::iovec>(&trusted[..count])
+            .or(Err(libc::EMSGSIZE))?;
+
+        let mut size = 0usize;
+        let mut c = c;
+        for (u, len) in untrusted.iter_mut().zip(lengths(trusted)) {
+            let (nc, us) = c.alloc::<u8>(len).or(Err(libc::EMSGSIZE))?;
+            c = nc;
+            u.iov_base = Self::translate_shim_to_host_addr(us.as_ptr()) as _;
+            u.iov_len = len;
+            size = size.saturating_add(len);
+        }
+
+        let untrusted = Self::translate_shim_to_host_addr(untrusted.as_ptr());
+        let req = request!(libc::SYS_readv => fd, untrusted, count);
+        let ret = unsafe { self.proxy(req)? };
+
+        let mut read = ret[0].into();
+        if size < read {
+            self.attacked();
+        }
+
+        let c = self.new_cursor();
+        let (c, _) = c.alloc::<libc::iovec>(count).or(Err(libc::EMSGSIZE))?;
+
+        let mut c = c;
+        for (t, len) in trusted.iter().zip(lengths(trusted)) {
+            let sz = core::cmp::min(len, read);
+            let ts = t.iov_base as *mut u8;
+
+            let nc = unsafe { c.copy_into_raw_parts(len, ts, sz) }.or(Err(libc::EMSGSIZE))?;
+            c = nc;
+
+            read = read.saturating_sub(sz);
+        }
+
+        Ok(ret)
+    }
+
+    /// Do a writev() syscall
+    fn writev(
+        &mut self,
+        fd: libc::c_int,
+        iovec: UntrustedRef<libc::iovec>,
+        iovcnt: libc::c_int,
+    ) -> sallyport::Result {
+        self.trace("writev", 3);
+
+        let trusted = validate_iovec(self, iovec, iovcnt)?;
+        let count = lengths(trusted).count();
+
+        let c = self.new_cursor();
+        let (c, untrusted) = c
+            .copy_from_slice::<libc::iovec>(&trusted[..count])
+            .or(Err(libc::EMSGSIZE))?;
+
+        let mut size = 0usize;
+        let mut c = c;
+        for ((t, u), len) in trusted
+            .iter()
+            .zip(untrusted.iter_mut())
+            .zip(lengths(trusted))
+        {
+            let (nc, us) = unsafe { c.copy_from_raw_parts(t.iov_base as *const u8, len) }
+                .or(Err(libc::EMSGSIZE))?;
+            c = nc;
+            u.iov_base = Self::translate_shim_to_host_addr(us) as _;
+            u.iov_len = len;
+            size = size.saturating_add(len);
+        }
+
+        let untrusted = Self::translate_shim_to_host_addr(untrusted.as_ptr());
+        let req = request!(libc::SYS_writev => fd, untrusted, count);
+        let ret = unsafe { self.proxy(req)? };
+
+        if size < ret[0].into() {
+            self.attacked();
+        }
+
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::ptr::null_mut;
+    use std::vec::Vec;
+
+    fn iov(len: usize) -> libc::iovec {
+        libc::iovec {
+            iov_base: null_mut(),
+            iov_len: len,
+        }
+    }
+
+    #[test]
+    fn lengths() {
+        let fits = |iovec: &[libc::iovec]| super::lengths(iovec).collect::<Vec<_>>();
+        let room = Block::buf_capacity() - size_of::<libc::iovec>();
+
+        assert_eq!(fits(&[]), [0usize; 0]);
+        assert_eq!(fits(&[iov(1), iov(0), iov(2)]), [1, 0, 2]);
+
+        // The vector ends with the first buffer which is cut short.
+        let iovec = [iov(1), iov(usize::MAX), iov(1)];
+        assert_eq!(fits(&iovec), [1, room - 1 - size_of::<libc::iovec>()]);
+    }
+}
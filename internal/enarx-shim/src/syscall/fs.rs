@@ -0,0 +1,544 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! File syscalls served by the in-keep filesystem
+//!
+//! Syscalls taking a path operate on the in-keep filesystem, unless the path
+//! lies beneath a directory mounted from the host. Those are forwarded to the
+//! host, which only resolves them within the mounted directory. Files beneath
+//! a sealed mount are opened by the shim (see
+//! `FsSyscallHandler::open_sealed`). Syscalls taking a file descriptor are
+//! only handled here for descriptors owned by the in-keep filesystem.
+//!
+//! The pages of the in-keep filesystem are allocated from the shim through
+//! its implementation of `memfs::Pages`.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use enarx_syscall::SYS_ENARX_MOUNTS;
+use memfs::{MemFs, Mounted, Pages};
+use primordial::Register;
+use sallyport::request;
+use sallyport::syscall::{BaseSyscallHandler, SyscallHandler};
+use sallyport::untrusted::{
+    AddressValidator, UntrustedRef, UntrustedRefMut, Validate, ValidateSlice,
+};
+use spinning::{Mutex, RawMutex};
+
+/// The mount flag of directories whose files are sealed
+const MOUNT_SEALED: u32 = 1 << 0;
+
+/// The maximum number of bytes returned by a single host `getdents64()`
+const MAX_DIRENTS: usize = 2048;
+
+/// The in-keep filesystem
+static MEMFS: Mutex<MemFs> = Mutex::const_new(RawMutex::const_new(), MemFs::new());
+
+/// Whether the mount points have been created in the in-keep filesystem
+static MOUNTED: AtomicBool = AtomicBool::new(false);
+
+/// Syscalls whose first argument is a file descriptor
+pub const FD_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_lseek,
+    libc::SYS_close,
+    libc::SYS_fstat,
+    libc::SYS_ftruncate,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_getdents64,
+];
+
+/// Syscalls which duplicate or change a file descriptor
+///
+/// Those of the in-keep filesystem are handled here, the others by the host.
+pub const DUP_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_dup,
+    libc::SYS_dup2,
+    libc::SYS_dup3,
+    libc::SYS_fcntl,
+];
+
+/// The syscalls of the in-keep filesystem
+pub trait FsSyscallHandler:
+    BaseSyscallHandler + SyscallHandler + AddressValidator + Pages + Sized
+{
+    /// Opens a file beneath a sealed mount
+    ///
+    /// The `path` is the absolute, NUL-terminated path on the host. Shims
+    /// without a seal key refuse to open sealed files.
+    fn open_sealed(
+        &mut self,
+        _path: &[u8],
+        _flags: libc::c_int,
+        _mode: libc::mode_t,
+    ) -> sallyport::Result {
+        Err(libc::EOPNOTSUPP)
+    }
+
+    /// Do an openat() syscall
+    fn openat(
+        &mut self,
+        dirfd: libc::c_int,
+        path: *const u8,
+        flags: libc::c_int,
+        mode: libc::mode_t,
+    ) -> sallyport::Result {
+        self.trace("openat", 4);
+
+        let path = validate_path(self, path)?;
+        let mut buf = [0u8; libc::PATH_MAX as usize];
+        if let Some(Mounted { len, flags: mflags }) = mounted(self, dirfd, path, &mut buf)? {
+            if mflags & MOUNT_SEALED != 0 {
+                return self.open_sealed(&buf[..=len], flags, mode);
+            }
+
+            let c = self.new_cursor();
+            let (_, hpath) = c.copy_from_slice(&buf[..=len]).or(Err(libc::EMSGSIZE))?;
+            let hpath = Self::translate_shim_to_host_addr(hpath.as_ptr());
+
+            let req = request!(libc::SYS_openat => libc::AT_FDCWD, hpath, flags, mode);
+            let ret = unsafe { self.proxy(req)? };
+            if MemFs::owns(usize::from(ret[0]) as _) {
+                self.attacked();
+            }
+
+            return Ok(ret);
+        }
+
+        let fd = MEMFS.lock().open(self, dirfd, path, flags, mode)?;
+        Ok([(fd as usize).into(), Default::default()])
+    }
+
+    /// Do a newfstatat() syscall
+    fn newfstatat(
+        &mut self,
+        dirfd: libc::c_int,
+        path: *const u8,
+        statbuf: UntrustedRefMut<libc::stat>,
+        flags: libc::c_int,
+    ) -> sallyport::Result {
+        self.trace("newfstatat", 4);
+
+        let path = validate_path(self, path)?;
+        let stat = match (path, flags & libc::AT_EMPTY_PATH) {
+            (b"", 0) => return Err(libc::ENOENT),
+            (b"", _) if !MemFs::owns(dirfd) => {
+                let statbuf = statbuf.validate(self).ok_or(libc::EFAULT)? as *mut libc::stat;
+                let (fd, statbuf) = ((dirfd as usize).into(), (statbuf as usize).into());
+                let zero = Register::from(0usize);
+                return self.syscall(fd, statbuf, zero, zero, zero, zero, libc::SYS_fstat as _);
+            }
+            (b"", _) => MEMFS.lock().fstat(dirfd)?,
+            (path, _) => {
+                let mut buf = [0u8; libc::PATH_MAX as usize];
+                match mounted(self, dirfd, path, &mut buf)? {
+                    None => MEMFS.lock().stat(dirfd, path)?,
+                    Some(Mounted { len, .. }) => {
+                        let statbuf = statbuf.validate(self).ok_or(libc::EFAULT)?;
+
+                        let c = self.new_cursor();
+                        let (c, hstat) = c.alloc::<libc::stat>(1).or(Err(libc::EMSGSIZE))?;
+                        let (_, hpath) = c.copy_from_slice(&buf[..=len]).or(Err(libc::EMSGSIZE))?;
+                        let hstat = Self::translate_shim_to_host_addr(hstat.as_ptr());
+                        let hpath = Self::translate_shim_to_host_addr(hpath.as_ptr());
+
+                        let req =
+                            request!(libc::SYS_newfstatat => libc::AT_FDCWD, hpath, hstat, flags);
+                        let ret = unsafe { self.proxy(req)? };
+
+                        let c = self.new_cursor();
+                        unsafe { c.copy_into_raw_parts(1, statbuf as *mut libc::stat, 1) }
+                            .or(Err(libc::EMSGSIZE))?;
+                        return Ok(ret);
+                    }
+                }
+            }
+        };
+
+        *statbuf.validate(self).ok_or(libc::EFAULT)? = stat;
+        Ok(Default::default())
+    }
+
+    /// Do a faccessat() syscall
+    ///
+    /// There is a single user of the in-keep filesystem, who may do anything.
+    fn faccessat(
+        &mut self,
+        dirfd: libc::c_int,
+        path: *const u8,
+        mode: libc::c_int,
+    ) -> sallyport::Result {
+        self.trace("faccessat", 3);
+
+        let path = validate_path(self, path)?;
+        let mut buf = [0u8; libc::PATH_MAX as usize];
+        if let Some(Mounted { len, .. }) = mounted(self, dirfd, path, &mut buf)? {
+            let c = self.new_cursor();
+            let (_, hpath) = c.copy_from_slice(&buf[..=len]).or(Err(libc::EMSGSIZE))?;
+            let hpath = Self::translate_shim_to_host_addr(hpath.as_ptr());
+
+            return unsafe {
+                self.proxy(request!(libc::SYS_faccessat => libc::AT_FDCWD, hpath, mode))
+            };
+        }
+
+        MEMFS.lock().stat(dirfd, path)?;
+        Ok(Default::default())
+    }
+
+    /// Do a mkdirat() syscall
+    fn mkdirat(
+        &mut self,
+        dirfd: libc::c_int,
+        path: *const u8,
+        mode: libc::mode_t,
+    ) -> sallyport::Result {
+        self.trace("mkdirat", 3);
+
+        let path = validate_path(self, path)?;
+        let mut buf = [0u8; libc::PATH_MAX as usize];
+        if let Some(Mounted { len, .. }) = mounted(self, dirfd, path, &mut buf)? {
+            let c = self.new_cursor();
+            let (_, hpath) = c.copy_from_slice(&buf[..=len]).or(Err(libc::EMSGSIZE))?;
+            let hpath = Self::translate_shim_to_host_addr(hpath.as_ptr());
+
+            return unsafe {
+                self.proxy(request!(libc::SYS_mkdirat => libc::AT_FDCWD, hpath, mode))
+            };
+        }
+
+        MEMFS.lock().mkdir(dirfd, path, mode)?;
+        Ok(Default::default())
+    }
+
+    /// Do an unlinkat() syscall
+    fn unlinkat(
+        &mut self,
+        dirfd: libc::c_int,
+        path: *const u8,
+        flags: libc::c_int,
+    ) -> sallyport::Result {
+        self.trace("unlinkat", 3);
+
+        let path = validate_path(self, path)?;
+        let mut buf = [0u8; libc::PATH_MAX as usize];
+        if let Some(Mounted { len, .. }) = mounted(self, dirfd, path, &mut buf)? {
+            let c = self.new_cursor();
+            let (_, hpath) = c.copy_from_slice(&buf[..=len]).or(Err(libc::EMSGSIZE))?;
+            let hpath = Self::translate_shim_to_host_addr(hpath.as_ptr());
+
+            return unsafe {
+                self.proxy(request!(libc::SYS_unlinkat => libc::AT_FDCWD, hpath, flags))
+            };
+        }
+
+        match flags {
+            0 => MEMFS.lock().unlink(self, dirfd, path)?,
+            libc::AT_REMOVEDIR => MEMFS.lock().rmdir(self, dirfd, path)?,
+            _ => return Err(libc::EINVAL),
+        }
+
+        Ok(Default::default())
+    }
+
+    /// Do a renameat2() syscall
+    fn renameat2(
+        &mut self,
+        olddirfd: libc::c_int,
+        oldpath: *const u8,
+        newdirfd: libc::c_int,
+        newpath: *const u8,
+        flags: libc::c_uint,
+    ) -> sallyport::Result {
+        self.trace("renameat2", 5);
+
+        let oldpath = validate_path(self, oldpath)?;
+        let newpath = validate_path(self, newpath)?;
+
+        // Both paths must be on the same side of the mount points.
+        let mut buf = [0u8; libc::PATH_MAX as usize];
+        let hold = match mounted(self, olddirfd, oldpath, &mut buf)? {
+            None => None,
+            Some(Mounted { len, .. }) => {
+                let c = self.new_cursor();
+                let (_, hold) = c.copy_from_slice(&buf[..=len]).or(Err(libc::EMSGSIZE))?;
+                Some((Self::translate_shim_to_host_addr(hold.as_ptr()), hold.len()))
+            }
+        };
+
+        match (hold, mounted(self, newdirfd, newpath, &mut buf)?) {
+            (Some((hold, olen)), Some(Mounted { len, .. })) => {
+                let c = self.new_cursor();
+                let (c, _) = c.alloc::<u8>(olen).or(Err(libc::EMSGSIZE))?;
+                let (_, hnew) = c.copy_from_slice(&buf[..=len]).or(Err(libc::EMSGSIZE))?;
+                let hnew = Self::translate_shim_to_host_addr(hnew.as_ptr());
+
+                let at = libc::AT_FDCWD;
+                let req = request!(libc::SYS_renameat2 => at, hold, at, hnew, flags);
+                return unsafe { self.proxy(req) };
+            }
+            (None, None) => (),
+            _ => return Err(libc::EXDEV),
+        }
+
+        if flags != 0 {
+            return Err(libc::EINVAL);
+        }
+
+        MEMFS
+            .lock()
+            .rename(self, olddirfd, oldpath, newdirfd, newpath)?;
+        Ok(Default::default())
+    }
+
+    /// Do a getdents64() syscall on a host directory
+    fn getdents64(
+        &mut self,
+        fd: libc::c_int,
+        dirp: UntrustedRefMut<u8>,
+        count: usize,
+    ) -> sallyport::Result {
+        self.trace("getdents64", 3);
+
+        let buf = dirp.validate_slice(count, self).ok_or(libc::EFAULT)?;
+        let max = core::cmp::min(buf.len(), MAX_DIRENTS);
+
+        let c = self.new_cursor();
+        let (_, hbuf) = c.alloc::<u8>(max).or(Err(libc::EMSGSIZE))?;
+        let hbuf = Self::translate_shim_to_host_addr(hbuf.as_ptr());
+
+        let ret = unsafe { self.proxy(request!(libc::SYS_getdents64 => fd, hbuf, max))? };
+
+        let size: usize = ret[0].into();
+        if size > max {
+            self.attacked();
+        }
+
+        let c = self.new_cursor();
+        unsafe { c.copy_into_raw_parts(max, buf.as_mut_ptr(), size) }.or(Err(libc::EMSGSIZE))?;
+
+        Ok(ret)
+    }
+
+    /// Handles a syscall on a descriptor owned by the in-keep filesystem
+    fn memfs(
+        &mut self,
+        fd: libc::c_int,
+        b: Register<usize>,
+        c: Register<usize>,
+        d: Register<usize>,
+        nr: libc::c_long,
+    ) -> sallyport::Result {
+        let ret = match nr {
+            libc::SYS_read => {
+                self.trace("read", 3);
+                let buf = self.buffer(b, c)?;
+                MEMFS.lock().read(fd, buf)?
+            }
+
+            libc::SYS_pread64 => {
+                self.trace("pread64", 4);
+                let buf = self.buffer(b, c)?;
+                MEMFS.lock().pread(fd, buf, Self::offset(d)?)?
+            }
+
+            libc::SYS_write => {
+                self.trace("write", 3);
+                let buf = self.buffer(b, c)?;
+                MEMFS.lock().write(self, fd, buf)?
+            }
+
+            libc::SYS_pwrite64 => {
+                self.trace("pwrite64", 4);
+                let buf = self.buffer(b, c)?;
+                MEMFS.lock().pwrite(self, fd, buf, Self::offset(d)?)?
+            }
+
+            libc::SYS_readv | libc::SYS_writev => {
+                match nr {
+                    libc::SYS_readv => self.trace("readv", 3),
+                    _ => self.trace("writev", 3),
+                }
+
+                let iovec = UntrustedRef::from(usize::from(b) as *const libc::iovec);
+                let iovec = iovec
+                    .validate_slice(usize::from(c) as libc::c_int, self)
+                    .ok_or(libc::EFAULT)?;
+
+                let mut total = 0usize;
+                for iov in iovec {
+                    let buf = self.buffer((iov.iov_base as usize).into(), iov.iov_len.into())?;
+                    let len = match nr {
+                        libc::SYS_readv => MEMFS.lock().read(fd, buf)?,
+                        _ => MEMFS.lock().write(self, fd, buf)?,
+                    };
+
+                    total = total.checked_add(len).ok_or(libc::EINVAL)?;
+                    if len < buf.len() {
+                        break;
+                    }
+                }
+
+                total
+            }
+
+            libc::SYS_lseek => {
+                self.trace("lseek", 3);
+                let whence = usize::from(c) as libc::c_int;
+                MEMFS.lock().lseek(fd, usize::from(b) as _, whence)?
+            }
+
+            libc::SYS_close => {
+                self.trace("close", 1);
+                MEMFS.lock().close(self, fd)?;
+                0
+            }
+
+            libc::SYS_fstat => {
+                self.trace("fstat", 2);
+                let stat = MEMFS.lock().fstat(fd)?;
+                let statbuf = UntrustedRefMut::from(usize::from(b) as *mut libc::stat);
+                *statbuf.validate(self).ok_or(libc::EFAULT)? = stat;
+                0
+            }
+
+            libc::SYS_ftruncate => {
+                self.trace("ftruncate", 2);
+                MEMFS.lock().ftruncate(self, fd, Self::offset(b)?)?;
+                0
+            }
+
+            libc::SYS_getdents64 => {
+                self.trace("getdents64", 3);
+                let buf = self.buffer(b, c)?;
+                MEMFS.lock().getdents64(fd, buf)?
+            }
+
+            libc::SYS_dup => {
+                self.trace("dup", 1);
+                MEMFS.lock().dup(fd, 0, false)? as usize
+            }
+
+            libc::SYS_dup2 | libc::SYS_dup3 => {
+                let newfd = usize::from(b) as libc::c_int;
+                let flags = usize::from(c) as libc::c_int;
+                if nr == libc::SYS_dup3 {
+                    self.trace("dup3", 3);
+                    if newfd == fd || flags & !libc::O_CLOEXEC != 0 {
+                        return Err(libc::EINVAL);
+                    }
+                } else {
+                    self.trace("dup2", 2);
+                }
+
+                let cloexec = nr == libc::SYS_dup3 && flags & libc::O_CLOEXEC != 0;
+                MEMFS.lock().dup2(self, fd, newfd, cloexec)? as usize
+            }
+
+            libc::SYS_fcntl => {
+                self.trace("fcntl", 3);
+                let (cmd, arg) = (usize::from(b) as libc::c_int, usize::from(c) as libc::c_int);
+                MEMFS.lock().fcntl(fd, cmd, arg)? as usize
+            }
+
+            // Everything is already in memory.
+            libc::SYS_fsync | libc::SYS_fdatasync => {
+                self.trace("fsync", 1);
+                MEMFS.lock().fstat(fd)?;
+                0
+            }
+
+            _ => return Err(libc::ENOSYS),
+        };
+
+        Ok([ret.into(), Default::default()])
+    }
+
+    /// Validates a payload buffer
+    fn buffer<'b>(
+        &self,
+        ptr: Register<usize>,
+        len: Register<usize>,
+    ) -> Result<&'b mut [u8], libc::c_int> {
+        UntrustedRefMut::from(usize::from(ptr) as *mut u8)
+            .validate_slice(usize::from(len), self)
+            .ok_or(libc::EFAULT)
+    }
+
+    /// Validates a file offset
+    fn offset(offset: Register<usize>) -> Result<usize, libc::c_int> {
+        match usize::from(offset) as libc::off_t {
+            offset if offset < 0 => Err(libc::EINVAL),
+            offset => Ok(offset as usize),
+        }
+    }
+}
+
+/// Validates a NUL-terminated path
+fn validate_path<'b>(
+    validator: &impl AddressValidator,
+    ptr: *const u8,
+) -> Result<&'b [u8], libc::c_int> {
+    for len in 0..libc::PATH_MAX as usize {
+        let byte = UntrustedRef::from(ptr.wrapping_add(len));
+        if *byte.validate(validator).ok_or(libc::EFAULT)? == 0 {
+            return Ok(unsafe { core::slice::from_raw_parts(ptr, len) });
+        }
+    }
+
+    Err(libc::ENAMETOOLONG)
+}
+
+/// Creates the mount points of the host directories on first use
+fn mount(handler: &mut impl BaseSyscallHandler) {
+    if MOUNTED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    // Hosts without mounts don't know this syscall.
+    let len = match unsafe { handler.proxy(request!(SYS_ENARX_MOUNTS)) } {
+        Ok(ret) => usize::from(ret[0]),
+        Err(_) => return,
+    };
+
+    let mut list = [0u8; libc::PATH_MAX as usize];
+    if len > list.len() {
+        handler.attacked();
+    }
+
+    let c = handler.new_cursor();
+    if unsafe { c.copy_into_raw_parts(len, list.as_mut_ptr(), len) }.is_err() {
+        return;
+    }
+
+    let mut memfs = MEMFS.lock();
+    for entry in list[..len].split(|b| *b == 0).filter(|p| !p.is_empty()) {
+        let mut parts = entry.splitn(2, |b| *b == b':');
+        let path = parts.next().unwrap_or_default();
+        let flags = match parts.next() {
+            Some(b"sealed") => MOUNT_SEALED,
+            _ => 0,
+        };
+
+        let _ = memfs.mount(path, flags);
+    }
+}
+
+/// Checks whether a path is served by the host
+///
+/// If it is, the absolute path is written to `buf` and its length (not
+/// counting the terminating NUL) is returned with the mount flags.
+fn mounted(
+    handler: &mut impl BaseSyscallHandler,
+    dirfd: libc::c_int,
+    path: &[u8],
+    buf: &mut [u8],
+) -> Result<Option<Mounted>, libc::c_int> {
+    mount(handler);
+    MEMFS.lock().mounted(dirfd, path, buf)
+}
@@ -0,0 +1,31 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The arguments of the memory syscalls which both shims handle alike
+//!
+//! `libc` lacks some of them.
+
+/// The advice which is acknowledged without effect
+///
+/// Pages stay mapped until they are unmapped, so advice about paging them in
+/// or out doesn't apply, and the payload doesn't fork.
+pub const MADV_IGNORED: [libc::c_int; 13] = [
+    libc::MADV_NORMAL,
+    libc::MADV_RANDOM,
+    libc::MADV_SEQUENTIAL,
+    libc::MADV_WILLNEED,
+    libc::MADV_FREE,
+    libc::MADV_DONTFORK,
+    libc::MADV_DOFORK,
+    libc::MADV_MERGEABLE,
+    libc::MADV_UNMERGEABLE,
+    libc::MADV_HUGEPAGE,
+    libc::MADV_NOHUGEPAGE,
+    libc::MADV_DONTDUMP,
+    libc::MADV_DODUMP,
+];
+
+/// Locks pages of `mlock2()` once they are faulted in
+pub const MLOCK_ONFAULT: libc::c_int = 0x01;
+
+/// Locks pages of `mlockall()` once they are faulted in
+pub const MCL_ONFAULT: libc::c_int = 0x04;
@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Syscall handlers which both shims share
+//!
+//! The shims proxy the socket and I/O multiplexing syscalls to the host in
+//! the same way, except for the addresses of their blocks on the host (see
+//! `BaseSyscallHandler::translate_shim_to_host_addr`), both answer
+//! `getrandom()` from the CPU, and both spin short sleeps on the TSC. Those
+//! which `sallyport` doesn't handle are the default methods of the traits
+//! here, which the handlers of both shims implement.
+//!
+//! The methods have the names of their syscalls, and so do some of the
+//! methods of the `sallyport` traits. The shims call them by their trait,
+//! e.g. `PollSyscallHandler::poll(self, ...)`.
+//!
+//! The syscalls of the in-keep filesystem live here too. The shims only
+//! provide its pages (see `memfs::Pages`), and the SGX shim opens the files
+//! beneath sealed mounts (see `FsSyscallHandler::open_sealed`). So do the
+//! syscalls on host descriptors which copy through the block or create
+//! descriptors, and the memory syscall arguments which `libc` lacks.
+
+mod file;
+mod fs;
+mod memory;
+mod network;
+mod poll;
+mod random;
+mod sleep;
+
+pub use file::HostFileSyscallHandler;
+pub use fs::{FsSyscallHandler, DUP_SYSCALLS, FD_SYSCALLS};
+pub use memory::{MADV_IGNORED, MCL_ONFAULT, MLOCK_ONFAULT};
+pub use network::SocketSyscallHandler;
+pub use poll::PollSyscallHandler;
+pub use random::RandomSyscallHandler;
+pub use sleep::SleepSyscallHandler;
+
+use core::mem::{align_of, size_of};
+
+use libc::c_int;
+use sallyport::untrusted::{AddressValidator, UntrustedRefMut, Validate};
+
+/// Validates a pointer which may be NULL
+pub fn validate_nullable<'b, T>(
+    validator: &impl AddressValidator,
+    ptr: *mut T,
+) -> Result<Option<&'b mut T>, c_int> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+
+    UntrustedRefMut::from(ptr)
+        .validate(validator)
+        .map(Some)
+        .ok_or(libc::EFAULT)
+}
+
+/// Validates a pointer which may be NULL, without borrowing what it points to
+///
+/// Unlike references, the pointers may alias, e.g. when the payload passes
+/// the same set to `select()` for reading and for writing.
+pub fn validate_raw<T>(validator: &impl AddressValidator, ptr: *mut T) -> Result<*mut T, c_int> {
+    if ptr.is_null() {
+        return Ok(ptr);
+    }
+
+    if ptr as usize & (align_of::<T>() - 1) != 0
+        || !validator.validate_mut_mem_fn(ptr as *mut (), size_of::<T>())
+    {
+        return Err(libc::EFAULT);
+    }
+
+    Ok(ptr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::ptr::null_mut;
+
+    /// Accepts the addresses below `limit`
+    struct Validator {
+        limit: usize,
+    }
+
+    impl AddressValidator for Validator {
+        fn validate_const_mem_fn(&self, ptr: *const (), size: usize) -> bool {
+            ptr as usize + size <= self.limit
+        }
+
+        fn validate_mut_mem_fn(&self, ptr: *mut (), size: usize) -> bool {
+            ptr as usize + size <= self.limit
+        }
+    }
+
+    #[test]
+    fn raw() {
+        let mut set = 0u64;
+        let ptr = &mut set as *mut u64;
+        let validator = Validator { limit: usize::MAX };
+
+        assert_eq!(validate_raw::<u64>(&validator, null_mut()), Ok(null_mut()));
+
+        // The same pointer may be validated more than once.
+        assert_eq!(validate_raw(&validator, ptr), Ok(ptr));
+        assert_eq!(validate_raw(&validator, ptr), Ok(ptr));
+
+        let misaligned = (ptr as usize + 1) as *mut u64;
+        assert_eq!(validate_raw(&validator, misaligned), Err(libc::EFAULT));
+
+        let validator = Validator {
+            limit: ptr as usize,
+        };
+        assert_eq!(validate_raw(&validator, ptr), Err(libc::EFAULT));
+    }
+}
@@ -0,0 +1,176 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Socket syscalls which are not provided by `NetworkSyscallHandler`
+//!
+//! Every buffer is copied through the sallyport block. Lengths reported
+//! back by the host are checked against the buffers we provided.
+//!
+//! The shims don't encrypt the traffic of the sockets, so the host sees what
+//! the payload sends. Payloads which keep it from the host use TLS
+//! themselves, with keys which never leave the keep (e.g. from
+//! `SYS_ENARX_GETKEY`). The shims don't terminate TLS for them: it would take
+//! a TLS stack in each shim, and certificates which bind the keys to the
+//! attestation of the keep, which the SGX shim can't produce yet.
+
+use sallyport::request;
+use sallyport::syscall::BaseSyscallHandler;
+use sallyport::untrusted::{
+    AddressValidator, UntrustedRef, UntrustedRefMut, Validate, ValidateSlice,
+};
+
+/// The socket syscalls
+pub trait SocketSyscallHandler: BaseSyscallHandler + AddressValidator + Sized {
+    /// Do a sendto() syscall
+    fn sendto(
+        &mut self,
+        fd: libc::c_int,
+        buf: UntrustedRef<u8>,
+        len: libc::size_t,
+        flags: libc::c_int,
+        addr: UntrustedRef<u8>,
+        addrlen: libc::socklen_t,
+    ) -> sallyport::Result {
+        self.trace("sendto", 6);
+
+        let buf = buf.validate_slice(len, self).ok_or(libc::EFAULT)?;
+        let addr = match addr.as_ptr().is_null() {
+            true => None,
+            false => Some(
+                addr.validate_slice(addrlen as usize, self)
+                    .ok_or(libc::EFAULT)?,
+            ),
+        };
+
+        let c = self.new_cursor();
+        let (c, hbuf) = c.copy_from_slice(buf).or(Err(libc::EMSGSIZE))?;
+        let (haddr, haddrlen) = match addr {
+            None => (0, 0),
+            Some(addr) => {
+                let (_, haddr) = c.copy_from_slice(addr).or(Err(libc::EMSGSIZE))?;
+                (
+                    Self::translate_shim_to_host_addr(haddr.as_ptr()),
+                    haddr.len(),
+                )
+            }
+        };
+
+        let (hbuf, hbuf_len) = (Self::translate_shim_to_host_addr(hbuf.as_ptr()), hbuf.len());
+        let req = request!(libc::SYS_sendto => fd, hbuf, hbuf_len, flags, haddr, haddrlen);
+        let ret = unsafe { self.proxy(req)? };
+
+        if usize::from(ret[0]) > hbuf_len {
+            self.attacked();
+        }
+
+        Ok(ret)
+    }
+
+    /// Do a getsockopt() syscall
+    fn getsockopt(
+        &mut self,
+        fd: libc::c_int,
+        level: libc::c_int,
+        optname: libc::c_int,
+        optval: UntrustedRefMut<u8>,
+        optlen: UntrustedRefMut<libc::socklen_t>,
+    ) -> sallyport::Result {
+        self.trace("getsockopt", 5);
+
+        let optlen = optlen.validate(self).ok_or(libc::EFAULT)?;
+        let optval = optval
+            .validate_slice(*optlen as usize, self)
+            .ok_or(libc::EFAULT)?;
+
+        let c = self.new_cursor();
+        let (c, hoptval) = c.alloc::<u8>(optval.len()).or(Err(libc::EMSGSIZE))?;
+        let (_, hoptlen) = c.copy_from_slice(&[*optlen]).or(Err(libc::EMSGSIZE))?;
+
+        let hoptval = Self::translate_shim_to_host_addr(hoptval.as_ptr());
+        let hoptlen = Self::translate_shim_to_host_addr(hoptlen.as_ptr());
+        let req = request!(libc::SYS_getsockopt => fd, level, optname, hoptval, hoptlen);
+        let ret = unsafe { self.proxy(req)? };
+
+        copy_sized_out(self, optval, optlen)?;
+        Ok(ret)
+    }
+
+    /// Do a getpeername() syscall
+    fn getpeername(
+        &mut self,
+        fd: libc::c_int,
+        addr: UntrustedRefMut<u8>,
+        addrlen: UntrustedRefMut<libc::socklen_t>,
+    ) -> sallyport::Result {
+        self.trace("getpeername", 3);
+
+        let addrlen = addrlen.validate(self).ok_or(libc::EFAULT)?;
+        let addr = addr
+            .validate_slice(*addrlen as usize, self)
+            .ok_or(libc::EFAULT)?;
+
+        let c = self.new_cursor();
+        let (c, haddr) = c.alloc::<u8>(addr.len()).or(Err(libc::EMSGSIZE))?;
+        let (_, haddrlen) = c.copy_from_slice(&[*addrlen]).or(Err(libc::EMSGSIZE))?;
+
+        let haddr = Self::translate_shim_to_host_addr(haddr.as_ptr());
+        let haddrlen = Self::translate_shim_to_host_addr(haddrlen.as_ptr());
+        let req = request!(libc::SYS_getpeername => fd, haddr, haddrlen);
+        let ret = unsafe { self.proxy(req)? };
+
+        copy_sized_out(self, addr, addrlen)?;
+        Ok(ret)
+    }
+
+    /// Do a shutdown() syscall
+    fn shutdown(&mut self, fd: libc::c_int, how: libc::c_int) -> sallyport::Result {
+        self.trace("shutdown", 2);
+
+        unsafe { self.proxy(request!(libc::SYS_shutdown => fd, how)) }
+    }
+
+    /// Do a socketpair() syscall
+    fn socketpair(
+        &mut self,
+        domain: libc::c_int,
+        type_: libc::c_int,
+        protocol: libc::c_int,
+        sv: UntrustedRefMut<libc::c_int>,
+    ) -> sallyport::Result {
+        self.trace("socketpair", 4);
+
+        let sv = sv.validate_slice(2usize, self).ok_or(libc::EFAULT)?;
+
+        let c = self.new_cursor();
+        let (_, hsv) = c.alloc::<libc::c_int>(2).or(Err(libc::EMSGSIZE))?;
+
+        let hsv = Self::translate_shim_to_host_addr(hsv.as_ptr());
+        let req = request!(libc::SYS_socketpair => domain, type_, protocol, hsv);
+        let ret = unsafe { self.proxy(req)? };
+
+        let c = self.new_cursor();
+        unsafe { c.copy_into_raw_parts(2, sv.as_mut_ptr(), 2) }.or(Err(libc::EMSGSIZE))?;
+
+        Ok(ret)
+    }
+}
+
+/// Copies a buffer followed by its `socklen_t` length out of the block
+///
+/// The buffer is truncated to the smaller of the two lengths, just like
+/// the kernel does. The reported length is passed through unmodified.
+fn copy_sized_out(
+    handler: &mut impl BaseSyscallHandler,
+    buf: &mut [u8],
+    len: &mut libc::socklen_t,
+) -> Result<(), libc::c_int> {
+    let c = handler.new_cursor();
+    let (c, _) = c.alloc::<u8>(buf.len()).or(Err(libc::EMSGSIZE))?;
+    let (_, hlen) = unsafe { c.read::<libc::socklen_t>() }.or(Err(libc::EMSGSIZE))?;
+
+    let size = core::cmp::min(hlen as usize, buf.len());
+    let c = handler.new_cursor();
+    unsafe { c.copy_into_raw_parts(buf.len(), buf.as_mut_ptr(), size) }.or(Err(libc::EMSGSIZE))?;
+
+    *len = hlen;
+    Ok(())
+}
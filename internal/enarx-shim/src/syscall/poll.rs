@@ -0,0 +1,332 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! I/O multiplexing syscalls (epoll, poll and select)
+//!
+//! The file descriptors live on the host, so these are all proxied. The
+//! interest lists and result sets are copied through the sallyport block.
+//! So are the eventfd and timerfd descriptors which event loops wait for.
+//! They are read and written like any other host descriptor, and the host
+//! makes those which are created with `EFD_NONBLOCK` or `TFD_NONBLOCK` fail
+//! with `EAGAIN` rather than block.
+
+use super::{validate_nullable, validate_raw};
+
+use core::mem::size_of;
+use core::slice::from_ref;
+
+use memfs::MemFs;
+use primordial::Register;
+use sallyport::request;
+use sallyport::syscall::BaseSyscallHandler;
+use sallyport::untrusted::{
+    AddressValidator, UntrustedRef, UntrustedRefMut, Validate, ValidateSlice,
+};
+
+/// The maximum number of events returned by a single `epoll_wait()`
+///
+/// Returning fewer events than requested is always valid, so this only
+/// needs to keep the events within the sallyport block.
+const MAX_EVENTS: usize = 2048 / size_of::<libc::epoll_event>();
+
+/// The flags of `eventfd2()`
+const EFD_FLAGS: libc::c_int = libc::EFD_CLOEXEC | libc::EFD_NONBLOCK | libc::EFD_SEMAPHORE;
+
+/// The flags of `timerfd_create()`
+const TFD_FLAGS: libc::c_int = libc::TFD_CLOEXEC | libc::TFD_NONBLOCK;
+
+/// Cancels an absolute realtime timer when the clock is set
+const TFD_TIMER_CANCEL_ON_SET: libc::c_int = 1 << 1;
+
+/// The flags of `timerfd_settime()`
+const TFD_SETTIME_FLAGS: libc::c_int = libc::TFD_TIMER_ABSTIME | TFD_TIMER_CANCEL_ON_SET;
+
+/// The clocks which timers can be created on
+const TIMER_CLOCKS: [libc::clockid_t; 5] = [
+    libc::CLOCK_REALTIME,
+    libc::CLOCK_MONOTONIC,
+    libc::CLOCK_BOOTTIME,
+    libc::CLOCK_REALTIME_ALARM,
+    libc::CLOCK_BOOTTIME_ALARM,
+];
+
+/// The I/O multiplexing syscalls, and those of eventfd and timerfd
+pub trait PollSyscallHandler: BaseSyscallHandler + AddressValidator + Sized {
+    /// Do an epoll_create1() syscall
+    fn epoll_create1(&mut self, flags: libc::c_int) -> sallyport::Result {
+        self.trace("epoll_create1", 1);
+
+        unsafe { self.proxy(request!(libc::SYS_epoll_create1 => flags)) }
+    }
+
+    /// Do an epoll_ctl() syscall
+    fn epoll_ctl(
+        &mut self,
+        epfd: libc::c_int,
+        op: libc::c_int,
+        fd: libc::c_int,
+        event: UntrustedRef<libc::epoll_event>,
+    ) -> sallyport::Result {
+        self.trace("epoll_ctl", 4);
+
+        // The event is ignored (and may be NULL) for EPOLL_CTL_DEL.
+        let hevent = match (op, event.as_ptr().is_null()) {
+            (libc::EPOLL_CTL_DEL, true) => 0,
+            _ => {
+                let event = event.validate(self).ok_or(libc::EFAULT)?;
+
+                let c = self.new_cursor();
+                let (_, hevent) = c.copy_from_slice(from_ref(event)).or(Err(libc::EMSGSIZE))?;
+                Self::translate_shim_to_host_addr(hevent.as_ptr())
+            }
+        };
+
+        unsafe { self.proxy(request!(libc::SYS_epoll_ctl => epfd, op, fd, hevent)) }
+    }
+
+    /// Do an epoll_wait() syscall
+    fn epoll_wait(
+        &mut self,
+        epfd: libc::c_int,
+        events: UntrustedRefMut<libc::epoll_event>,
+        maxevents: libc::c_int,
+        timeout: libc::c_int,
+    ) -> sallyport::Result {
+        self.trace("epoll_wait", 4);
+
+        if maxevents <= 0 {
+            return Err(libc::EINVAL);
+        }
+
+        let events = events
+            .validate_slice(maxevents as usize, self)
+            .ok_or(libc::EFAULT)?;
+        let max = core::cmp::min(events.len(), MAX_EVENTS);
+
+        let c = self.new_cursor();
+        let (_, hevents) = c.alloc::<libc::epoll_event>(max).or(Err(libc::EMSGSIZE))?;
+        let hevents = Self::translate_shim_to_host_addr(hevents.as_ptr());
+
+        let req = request!(libc::SYS_epoll_wait => epfd, hevents, max, timeout);
+        let ret = unsafe { self.proxy(req)? };
+
+        let count: usize = ret[0].into();
+        if count > max {
+            self.attacked();
+        }
+
+        let c = self.new_cursor();
+        unsafe { c.copy_into_raw_parts(max, events.as_mut_ptr(), count) }
+            .or(Err(libc::EMSGSIZE))?;
+
+        Ok(ret)
+    }
+
+    /// Do a poll() syscall
+    fn poll(
+        &mut self,
+        fds: UntrustedRefMut<libc::pollfd>,
+        nfds: libc::nfds_t,
+        timeout: libc::c_int,
+    ) -> sallyport::Result {
+        self.trace("poll", 3);
+
+        // Polling no descriptors is a common way to sleep.
+        if nfds == 0 {
+            return unsafe { self.proxy(request!(libc::SYS_poll => 0usize, 0usize, timeout)) };
+        }
+
+        let fds = fds
+            .validate_slice(nfds as usize, self)
+            .ok_or(libc::EFAULT)?;
+
+        let c = self.new_cursor();
+        let (_, hfds) = c.copy_from_slice(&*fds).or(Err(libc::EMSGSIZE))?;
+        let hfds = Self::translate_shim_to_host_addr(hfds.as_ptr());
+
+        let req = request!(libc::SYS_poll => hfds, nfds, timeout);
+        let ret = unsafe { self.proxy(req)? };
+
+        if usize::from(ret[0]) > fds.len() {
+            self.attacked();
+        }
+
+        let c = self.new_cursor();
+        unsafe { c.copy_into_raw_parts(fds.len(), fds.as_mut_ptr(), fds.len()) }
+            .or(Err(libc::EMSGSIZE))?;
+
+        Ok(ret)
+    }
+
+    /// Do a select() syscall
+    fn select(
+        &mut self,
+        nfds: libc::c_int,
+        readfds: *mut libc::fd_set,
+        writefds: *mut libc::fd_set,
+        exceptfds: *mut libc::fd_set,
+        timeout: *mut libc::timeval,
+    ) -> sallyport::Result {
+        self.trace("select", 5);
+
+        if nfds < 0 || nfds as usize > libc::FD_SETSIZE {
+            return Err(libc::EINVAL);
+        }
+
+        // The payload may pass the same set more than once, so they are kept
+        // as raw pointers rather than as references, which may not alias.
+        let sets = [
+            validate_raw(self, readfds)?,
+            validate_raw(self, writefds)?,
+            validate_raw(self, exceptfds)?,
+        ];
+        let timeout = validate_raw(self, timeout)?;
+
+        // Copy the sets and the timeout into the block.
+        let mut hsets = [0usize; 3];
+        let mut c = self.new_cursor();
+        for (set, hset) in sets.iter().zip(hsets.iter_mut()) {
+            if !set.is_null() {
+                let (nc, h) = c
+                    .copy_from_slice(from_ref(unsafe { &**set }))
+                    .or(Err(libc::EMSGSIZE))?;
+                *hset = Self::translate_shim_to_host_addr(h.as_ptr());
+                c = nc;
+            }
+        }
+
+        let htimeout = match timeout.is_null() {
+            true => 0,
+            false => {
+                let (_, h) = c
+                    .copy_from_slice(from_ref(unsafe { &*timeout }))
+                    .or(Err(libc::EMSGSIZE))?;
+                Self::translate_shim_to_host_addr(h.as_ptr())
+            }
+        };
+
+        let req = request!(libc::SYS_select => nfds, hsets[0], hsets[1], hsets[2], htimeout);
+        let ret = unsafe { self.proxy(req)? };
+
+        if usize::from(ret[0]) > (nfds as usize).saturating_mul(sets.len()) {
+            self.attacked();
+        }
+
+        // Copy the modified sets and the remaining timeout back out, in the
+        // order in which they were copied in.
+        let mut c = self.new_cursor();
+        for set in sets.iter().filter(|s| !s.is_null()) {
+            c = unsafe { c.copy_into_raw_parts(1, *set, 1) }.or(Err(libc::EMSGSIZE))?;
+        }
+
+        if !timeout.is_null() {
+            unsafe { c.copy_into_raw_parts(1, timeout, 1) }.or(Err(libc::EMSGSIZE))?;
+        }
+
+        Ok(ret)
+    }
+
+    /// Do an eventfd2() syscall
+    fn eventfd2(&mut self, initval: libc::c_uint, flags: libc::c_int) -> sallyport::Result {
+        self.trace("eventfd2", 2);
+
+        if flags & !EFD_FLAGS != 0 {
+            return Err(libc::EINVAL);
+        }
+
+        let ret = unsafe { self.proxy(request!(libc::SYS_eventfd2 => initval, flags))? };
+        new_fd(self, ret)
+    }
+
+    /// Do a timerfd_create() syscall
+    fn timerfd_create(
+        &mut self,
+        clockid: libc::clockid_t,
+        flags: libc::c_int,
+    ) -> sallyport::Result {
+        self.trace("timerfd_create", 2);
+
+        if !TIMER_CLOCKS.contains(&clockid) || flags & !TFD_FLAGS != 0 {
+            return Err(libc::EINVAL);
+        }
+
+        let ret = unsafe { self.proxy(request!(libc::SYS_timerfd_create => clockid, flags))? };
+        new_fd(self, ret)
+    }
+
+    /// Do a timerfd_settime() syscall
+    fn timerfd_settime(
+        &mut self,
+        fd: libc::c_int,
+        flags: libc::c_int,
+        new_value: UntrustedRef<libc::itimerspec>,
+        old_value: *mut libc::itimerspec,
+    ) -> sallyport::Result {
+        self.trace("timerfd_settime", 4);
+
+        if flags & !TFD_SETTIME_FLAGS != 0 {
+            return Err(libc::EINVAL);
+        }
+
+        let new_value = new_value.validate(self).ok_or(libc::EFAULT)?;
+        let old_value = validate_nullable(self, old_value)?;
+
+        let c = self.new_cursor();
+        let (c, hnew) = c
+            .copy_from_slice(from_ref(new_value))
+            .or(Err(libc::EMSGSIZE))?;
+        let hnew = Self::translate_shim_to_host_addr(hnew.as_ptr());
+        let hold = match old_value {
+            None => 0,
+            Some(_) => {
+                let (_, hold) = c.alloc::<libc::itimerspec>(1).or(Err(libc::EMSGSIZE))?;
+                Self::translate_shim_to_host_addr(hold.as_ptr())
+            }
+        };
+
+        let req = request!(libc::SYS_timerfd_settime => fd, flags, hnew, hold);
+        let ret = unsafe { self.proxy(req)? };
+
+        if let Some(old_value) = old_value {
+            let c = self.new_cursor();
+            let (c, _) = c.alloc::<libc::itimerspec>(1).or(Err(libc::EMSGSIZE))?;
+            unsafe { c.copy_into_raw_parts(1, old_value as *mut libc::itimerspec, 1) }
+                .or(Err(libc::EMSGSIZE))?;
+        }
+
+        Ok(ret)
+    }
+
+    /// Do a timerfd_gettime() syscall
+    fn timerfd_gettime(
+        &mut self,
+        fd: libc::c_int,
+        curr_value: UntrustedRefMut<libc::itimerspec>,
+    ) -> sallyport::Result {
+        self.trace("timerfd_gettime", 2);
+
+        let curr_value = curr_value.validate(self).ok_or(libc::EFAULT)?;
+
+        let c = self.new_cursor();
+        let (_, hcurr) = c.alloc::<libc::itimerspec>(1).or(Err(libc::EMSGSIZE))?;
+        let hcurr = Self::translate_shim_to_host_addr(hcurr.as_ptr());
+
+        let ret = unsafe { self.proxy(request!(libc::SYS_timerfd_gettime => fd, hcurr))? };
+
+        let c = self.new_cursor();
+        unsafe { c.copy_into_raw_parts(1, curr_value as *mut libc::itimerspec, 1) }
+            .or(Err(libc::EMSGSIZE))?;
+
+        Ok(ret)
+    }
+}
+
+/// Checks the descriptor of a new eventfd or timerfd
+///
+/// It may not be mistaken for one of the in-keep filesystem.
+fn new_fd(handler: &mut impl BaseSyscallHandler, ret: [Register<usize>; 2]) -> sallyport::Result {
+    if MemFs::owns(usize::from(ret[0]) as _) {
+        handler.attacked();
+    }
+
+    Ok(ret)
+}
@@ -0,0 +1,72 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The `getrandom()` syscall
+//!
+//! It is answered by the random number generator of the CPU (see the
+//! `random` module). The host only sees the request if the generator is
+//! broken, or if the run is deterministic.
+
+use crate::random::fill;
+
+use primordial::Register;
+use sallyport::syscall::SyscallHandler;
+use sallyport::untrusted::{AddressValidator, UntrustedRefMut, ValidateSlice};
+
+/// The flags known to `getrandom()`: `GRND_NONBLOCK`, `GRND_RANDOM` and
+/// `GRND_INSECURE`
+const FLAGS: libc::c_uint = 0x7;
+
+/// The most bytes returned at once, as on Linux
+const MAX: usize = (1 << 25) - 1;
+
+/// The number of bytes which a `getrandom()` returns
+fn length(buflen: usize, flags: usize) -> Result<usize, libc::c_int> {
+    match flags as libc::c_uint & !FLAGS {
+        0 => Ok(buflen.min(MAX)),
+        _ => Err(libc::EINVAL),
+    }
+}
+
+/// The `getrandom()` syscall
+pub trait RandomSyscallHandler: SyscallHandler + AddressValidator + Sized {
+    /// Whether the random numbers come from the host, as in deterministic runs
+    fn proxied_random(&mut self) -> bool;
+
+    /// Do a `getrandom()` syscall
+    fn getrandom(
+        &mut self,
+        buf: Register<usize>,
+        buflen: Register<usize>,
+        flags: Register<usize>,
+    ) -> sallyport::Result {
+        self.trace("getrandom", 3);
+
+        let len = length(buflen.into(), flags.into())?;
+        let native = !self.proxied_random();
+        let data = UntrustedRefMut::from(usize::from(buf) as *mut u8);
+        let data = data.validate_slice(len, self).ok_or(libc::EFAULT)?;
+        if native && fill(data) {
+            return Ok([len.into(), 0.into()]);
+        }
+
+        let (zero, nr) = (Register::from(0usize), libc::SYS_getrandom as usize);
+        SyscallHandler::syscall(self, buf, buflen, flags, zero, zero, zero, nr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length() {
+        assert_eq!(super::length(16, 0), Ok(16));
+        assert_eq!(super::length(16, libc::GRND_NONBLOCK as _), Ok(16));
+        assert_eq!(super::length(16, FLAGS as _), Ok(16));
+        assert_eq!(super::length(usize::MAX, 0), Ok(MAX));
+        assert_eq!(super::length(16, 0x8), Err(libc::EINVAL));
+
+        // The flags are an `unsigned int`, as on Linux.
+        assert_eq!(super::length(16, 1 << 32), Ok(16));
+    }
+}
@@ -0,0 +1,248 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sleeping without exits
+//!
+//! Relative sleeps of up to `SPIN` are done in the keep by spinning on the
+//! TSC, since exiting to the host and resuming takes about as long. The TSC
+//! is calibrated once against the monotonic clock of the host, which is as
+//! untrusted as a proxied sleep. `RDTSC` is only legal in enclaves on SGX2
+//! platforms (see `SleepSyscallHandler::tsc_readable`), so elsewhere, in
+//! deterministic runs and for longer or absolute sleeps, the host sleeps. If it is interrupted, the time which
+//! was left is passed back to the payload.
+
+use super::validate_nullable;
+
+use core::arch::x86_64::_rdtsc;
+use core::convert::TryFrom;
+use core::slice::from_ref;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use sallyport::request;
+use sallyport::syscall::BaseSyscallHandler;
+use sallyport::untrusted::{AddressValidator, UntrustedRef, Validate};
+
+/// The longest sleep which is spun, in nanoseconds
+const SPIN: libc::c_long = 100_000;
+
+/// How long the host sleeps to calibrate the TSC, in nanoseconds
+const CALIBRATION: libc::c_long = 1_000_000;
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// The clocks whose relative sleeps are spun
+///
+/// They all run at the pace of the TSC while the keep runs.
+const CLOCKS: [libc::clockid_t; 3] = [
+    libc::CLOCK_REALTIME,
+    libc::CLOCK_MONOTONIC,
+    libc::CLOCK_BOOTTIME,
+];
+
+/// The frequency of the TSC in Hz, 0 if unknown and 1 if it can't be used
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// The sleeping syscalls
+pub trait SleepSyscallHandler: BaseSyscallHandler + AddressValidator + Sized {
+    /// Whether all sleeps are done by the host, as in deterministic runs
+    fn proxied_sleep(&mut self) -> bool;
+
+    /// Whether the TSC can be read in the keep
+    ///
+    /// It is asked once, before the TSC is calibrated.
+    fn tsc_readable(&mut self) -> bool {
+        true
+    }
+
+    /// Do a nanosleep() syscall
+    fn nanosleep(
+        &mut self,
+        req: UntrustedRef<libc::timespec>,
+        rem: *mut libc::timespec,
+    ) -> sallyport::Result {
+        self.trace("nanosleep", 2);
+        self.sleep(None, req, rem)
+    }
+
+    /// Do a clock_nanosleep() syscall
+    fn clock_nanosleep(
+        &mut self,
+        clockid: libc::clockid_t,
+        flags: libc::c_int,
+        req: UntrustedRef<libc::timespec>,
+        rem: *mut libc::timespec,
+    ) -> sallyport::Result {
+        self.trace("clock_nanosleep", 4);
+        self.sleep(Some((clockid, flags)), req, rem)
+    }
+
+    /// Sleeps on a clock, or like `nanosleep()` without one
+    fn sleep(
+        &mut self,
+        clock: Option<(libc::clockid_t, libc::c_int)>,
+        req: UntrustedRef<libc::timespec>,
+        rem: *mut libc::timespec,
+    ) -> sallyport::Result {
+        let req = *req.validate(self).ok_or(libc::EFAULT)?;
+        let rem = validate_nullable(self, rem)?;
+        if !valid(&req) {
+            return Err(libc::EINVAL);
+        }
+
+        let relative = match clock {
+            None => true,
+            Some((clockid, flags)) => CLOCKS.contains(&clockid) && flags & libc::TIMER_ABSTIME == 0,
+        };
+
+        if relative && req.tv_sec == 0 && req.tv_nsec <= SPIN && !self.proxied_sleep() {
+            if let Some(hz) = self.tsc() {
+                spin(req.tv_nsec as u64, hz);
+                return Ok(Default::default());
+            }
+        }
+
+        let c = self.new_cursor();
+        let (c, hreq) = c.copy_from_slice(from_ref(&req)).or(Err(libc::EMSGSIZE))?;
+        let (_, hrem) = c.alloc::<libc::timespec>(1).or(Err(libc::EMSGSIZE))?;
+        let hreq = Self::translate_shim_to_host_addr(hreq.as_ptr());
+        let hrem = Self::translate_shim_to_host_addr(hrem.as_ptr());
+
+        let ret = unsafe {
+            self.proxy(match clock {
+                None => request!(libc::SYS_nanosleep => hreq, hrem),
+                Some((clockid, flags)) => {
+                    request!(libc::SYS_clock_nanosleep => clockid, flags, hreq, hrem)
+                }
+            })
+        };
+
+        // Absolute sleeps leave nothing to resume.
+        let absolute = matches!(clock, Some((_, flags)) if flags & libc::TIMER_ABSTIME != 0);
+        if let (Err(libc::EINTR), Some(rem), false) = (&ret, rem, absolute) {
+            let c = self.new_cursor();
+            let (c, _) = c.alloc::<libc::timespec>(1).or(Err(libc::EMSGSIZE))?;
+            unsafe { c.copy_into_raw_parts(1, &mut *rem as *mut libc::timespec, 1) }
+                .or(Err(libc::EMSGSIZE))?;
+
+            if !valid(rem) || (rem.tv_sec, rem.tv_nsec) > (req.tv_sec, req.tv_nsec) {
+                self.attacked();
+            }
+        }
+
+        ret
+    }
+
+    /// The frequency of the TSC, if it can be used
+    ///
+    /// It is calibrated the first time.
+    fn tsc(&mut self) -> Option<u64> {
+        match TSC_HZ.load(Ordering::Relaxed) {
+            0 => (),
+            1 => return None,
+            hz => return Some(hz),
+        }
+
+        let hz = match self.tsc_readable() {
+            true => self.calibrate().unwrap_or(1),
+            false => 1,
+        };
+
+        TSC_HZ.store(hz, Ordering::Relaxed);
+        Some(hz).filter(|hz| *hz != 1)
+    }
+
+    /// Measures the frequency of the TSC while the host sleeps
+    fn calibrate(&mut self) -> Option<u64> {
+        let (start, tsc) = (self.monotonic()?, unsafe { _rdtsc() });
+
+        let pause = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: CALIBRATION,
+        };
+
+        let c = self.new_cursor();
+        let (_, hpause) = c.copy_from_slice(from_ref(&pause)).ok()?;
+        let hpause = Self::translate_shim_to_host_addr(hpause.as_ptr());
+        unsafe { self.proxy(request!(libc::SYS_nanosleep => hpause, 0usize)) }.ok()?;
+
+        let ticks = unsafe { _rdtsc() }.checked_sub(tsc)?;
+        let ns = self.monotonic()?.checked_sub(start).filter(|ns| *ns > 0)?;
+        frequency(ticks, ns)
+    }
+
+    /// Reads the monotonic clock of the host, in nanoseconds
+    fn monotonic(&mut self) -> Option<u64> {
+        let c = self.new_cursor();
+        let (_, hts) = c.alloc::<libc::timespec>(1).ok()?;
+        let hts = Self::translate_shim_to_host_addr(hts.as_ptr());
+
+        let req = request!(libc::SYS_clock_gettime => libc::CLOCK_MONOTONIC, hts);
+        unsafe { self.proxy(req) }.ok()?;
+
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+
+        let c = self.new_cursor();
+        unsafe { c.copy_into_raw_parts(1, &mut ts as *mut libc::timespec, 1) }.ok()?;
+
+        let sec = u64::try_from(ts.tv_sec).ok()?;
+        let nsec = u64::try_from(ts.tv_nsec).ok()?;
+        sec.checked_mul(NSEC_PER_SEC)?.checked_add(nsec)
+    }
+}
+
+/// Whether a time is normalized and not negative
+fn valid(ts: &libc::timespec) -> bool {
+    ts.tv_sec >= 0 && (0..NSEC_PER_SEC as libc::c_long).contains(&ts.tv_nsec)
+}
+
+/// The frequency of a TSC which ticked `ticks` times in `ns` nanoseconds
+///
+/// It is `None` if it is unknown, and above 1 otherwise, since 1 marks a TSC
+/// which can't be used.
+fn frequency(ticks: u64, ns: u64) -> Option<u64> {
+    let hz = u128::from(ticks).checked_mul(u128::from(NSEC_PER_SEC))?;
+    let hz = hz.checked_div(u128::from(ns))?;
+    u64::try_from(hz).ok().filter(|hz| *hz > 1)
+}
+
+/// The number of ticks of a TSC running at `hz` in `ns` nanoseconds
+fn ticks(ns: u64, hz: u64) -> Option<u128> {
+    u128::from(ns)
+        .checked_mul(u128::from(hz))?
+        .checked_div(u128::from(NSEC_PER_SEC))
+}
+
+/// Spins for `ns` nanoseconds on a TSC running at `hz`
+fn spin(ns: u64, hz: u64) {
+    let ticks = ticks(ns, hz).unwrap_or_default();
+    let start = unsafe { _rdtsc() };
+    while u128::from(unsafe { _rdtsc() }.wrapping_sub(start)) < ticks {
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frequency() {
+        assert_eq!(super::frequency(3_000_000, 1_000_000), Some(3_000_000_000));
+        assert_eq!(super::frequency(u64::MAX, 1), None);
+        assert_eq!(super::frequency(1, 1_000_000_000), None);
+        assert_eq!(super::frequency(0, 1_000_000), None);
+        assert_eq!(super::frequency(3_000_000, 0), None);
+    }
+
+    #[test]
+    fn ticks() {
+        assert_eq!(super::ticks(100_000, 3_000_000_000), Some(300_000));
+        assert_eq!(super::ticks(100_000, 0), Some(0));
+
+        // Two seconds of the fastest TSC don't fit in 64 bits.
+        let max = u128::from(u64::MAX);
+        assert_eq!(super::ticks(NSEC_PER_SEC * 2, u64::MAX), Some(max * 2));
+    }
+}
@@ -0,0 +1,147 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The numbers of the Enarx syscalls
+//!
+//! Besides the syscalls of Linux, the payload, the shims and the loader ask
+//! each other for what only Enarx knows. These requests are numbered in
+//! `0xEA00..=0xEAFF`, next to those which `sallyport::syscall` defines
+//! (e.g. `SYS_ENARX_GETATT`). The loader and both shims take the numbers from
+//! here, so that they always agree.
+//!
+//! The payload requests start at `0xEA00`, the SGX page requests at `0xEA10`
+//! and the requests to the loader at `0xEA20`.
+
+#![no_std]
+#![deny(clippy::all)]
+#![deny(missing_docs)]
+
+use libc::c_long;
+
+/// The numbers of all Enarx syscalls
+pub const ENARX: core::ops::RangeInclusive<c_long> = 0xEA00..=0xEAFF;
+
+/// Payload request for a seal key: `(policy, buf, len, svn)`
+///
+/// The 128-bit key is written to `buf` and its length is returned. The
+/// `policy` says which identity of the keep the key is bound to. The key is
+/// that of the security versions of the keep, unless `GETKEY_SVN` is set in
+/// `policy`, which asks for that of the older version `svn` instead.
+pub const SYS_ENARX_GETKEY: c_long = 0xEA02;
+
+/// Asks `SYS_ENARX_GETKEY` for the key of an older version of the keep
+pub const GETKEY_SVN: usize = 1 << 16;
+
+/// Payload request for the injected secret: `(buf, len)`
+///
+/// The secret is copied to `buf` (truncated to `len` bytes) and its full
+/// length is returned.
+pub const SYS_ENARX_GETSECRET: c_long = 0xEA03;
+
+/// Maps new readable and writable pages (`EAUG`) into the enclave: `(addr, length)`
+pub const SYS_ENARX_SGX_AUG: c_long = 0xEA10;
+
+/// Restricts (`EMODPR`) enclave page permissions: `(addr, length, prot)`
+pub const SYS_ENARX_SGX_PROTECT: c_long = 0xEA11;
+
+/// Trims (`EMODT`) enclave pages: `(addr, length)`
+pub const SYS_ENARX_SGX_TRIM: c_long = 0xEA12;
+
+/// Removes trimmed enclave pages: `(addr, length)`
+pub const SYS_ENARX_SGX_REMOVE: c_long = 0xEA13;
+
+/// Lists the keep paths of the host mounts, each followed by a NUL
+///
+/// The path of a sealed mount is followed by `:sealed`.
+pub const SYS_ENARX_MOUNTS: c_long = 0xEA20;
+
+/// Writes the arguments and environment variables to the block
+///
+/// Each string is followed by a NUL. The length of the list and the number
+/// of arguments in it are returned.
+pub const SYS_ENARX_ENVIRON: c_long = 0xEA21;
+
+/// Executes the requests at the start of the block: `(count)`
+///
+/// Each request is replaced by its reply. The number of requests is returned.
+pub const SYS_ENARX_BATCH: c_long = 0xEA22;
+
+/// Sets up the syscall ring of the SGX shim (`0`) or wakes its host thread
+/// (`1`): `(op)`
+///
+/// The address and the size of the ring are returned. It fails with `ENOSYS`
+/// for keeps which have no ring.
+pub const SYS_ENARX_RING: c_long = 0xEA23;
+
+/// Sets up a bounce region: `(size)`
+///
+/// The address and the size of the region are returned.
+pub const SYS_ENARX_BOUNCE: c_long = 0xEA24;
+
+/// Sets up the clock page
+///
+/// The address and the size of the page are returned.
+pub const SYS_ENARX_CLOCK: c_long = 0xEA25;
+
+/// Payload request for a snapshot of the keep, which the host takes
+///
+/// It returns 0, or 1 in the keeps restored from the snapshot.
+pub const SYS_ENARX_SNAPSHOT: c_long = 0xEA26;
+
+/// Reports that the shim stops the keep because it was attacked
+pub const SYS_ENARX_ATTACKED: c_long = 0xEA27;
+
+/// Sets up the doorbell page
+///
+/// The address and the size of the page are returned.
+pub const SYS_ENARX_DOORBELL: c_long = 0xEA28;
+
+/// Asks whether the time and the random numbers are deterministic
+///
+/// It fails unless they are.
+pub const SYS_ENARX_DETERMINISTIC: c_long = 0xEA29;
+
+/// Reports the backtrace of the payload: `(signal, count, base)`
+///
+/// The block holds `count` addresses, the address of the fault first. The
+/// payload is loaded at `base`.
+pub const SYS_ENARX_BACKTRACE: c_long = 0xEA2A;
+
+/// Reports the peak memory usage of the payload: `(brk, mmap, stack)`
+///
+/// The sizes are in bytes: the most memory which the payload had taken with
+/// `brk()` and with `mmap()`, and the deepest its main stack grew.
+pub const SYS_ENARX_MEMORY: c_long = 0xEA2B;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distinct() {
+        let all = [
+            SYS_ENARX_GETKEY,
+            SYS_ENARX_GETSECRET,
+            SYS_ENARX_SGX_AUG,
+            SYS_ENARX_SGX_PROTECT,
+            SYS_ENARX_SGX_TRIM,
+            SYS_ENARX_SGX_REMOVE,
+            SYS_ENARX_MOUNTS,
+            SYS_ENARX_ENVIRON,
+            SYS_ENARX_BATCH,
+            SYS_ENARX_RING,
+            SYS_ENARX_BOUNCE,
+            SYS_ENARX_CLOCK,
+            SYS_ENARX_SNAPSHOT,
+            SYS_ENARX_ATTACKED,
+            SYS_ENARX_DOORBELL,
+            SYS_ENARX_DETERMINISTIC,
+            SYS_ENARX_BACKTRACE,
+            SYS_ENARX_MEMORY,
+        ];
+
+        for (i, num) in all.iter().enumerate() {
+            assert!(ENARX.contains(num), "{:#x}", num);
+            assert!(!all[..i].contains(num), "{:#x}", num);
+        }
+    }
+}
@@ -0,0 +1,306 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Signed payload manifests
+//!
+//! A manifest names the payload which its signer allows to run, on which
+//! hosts and until when. The loader places it on the page after the image of
+//! the payload in the code slot (the `PT_LOAD` segments at their physical
+//! addresses), and a shim which has the public key of the signer baked in
+//! verifies it before it jumps to the entry point of the payload. Since the
+//! key is part of the shim, it is part of the measurement of the keep.
+//!
+//! A manifest has a fixed layout of `SIZE` bytes; integers are little endian
+//! and the Ed25519 signature covers everything before it:
+//!
+//! ```text
+//! magic | SHA-256 of the image | image size (u64) | hosts (u32) | 0 (u32) | expiry (u64) | signer | signature
+//! ```
+//!
+//! The expiry is in seconds since the Unix epoch (0 for never). The shims
+//! only have the clock of the host to check it against, so it keeps an
+//! honest host from running stale payloads, not a malicious one.
+
+#![no_std]
+#![deny(clippy::all)]
+#![deny(missing_docs)]
+
+#[cfg(test)]
+extern crate std;
+
+use core::convert::TryInto;
+use core::fmt;
+
+use ed25519_compact::{PublicKey, Signature};
+use sha2::{Digest, Sha256};
+
+/// The first bytes of every manifest
+pub const MAGIC: [u8; 8] = *b"ENARXMF1";
+
+/// The number of bytes of a manifest
+pub const SIZE: usize = 160;
+
+/// The number of bytes of an Ed25519 public key
+pub const KEY_SIZE: usize = 32;
+
+/// The number of bytes covered by the signature
+pub const SIGNED_SIZE: usize = SIZE - SIGNATURE_SIZE;
+
+const SIGNATURE_SIZE: usize = 64;
+
+/// The payload may run in plain KVM keeps
+pub const KVM: u32 = 1 << 0;
+
+/// The payload may run in SEV keeps
+pub const SEV: u32 = 1 << 1;
+
+/// The payload may run in SGX keeps
+pub const SGX: u32 = 1 << 2;
+
+/// Why a payload may not run
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// There is no manifest
+    Magic,
+
+    /// The manifest was signed by another key
+    Signer,
+
+    /// The signature is invalid
+    Signature,
+
+    /// The manifest is for an image of another size
+    Size,
+
+    /// The manifest is for another image
+    Digest,
+
+    /// The manifest does not allow this host
+    Host,
+
+    /// The manifest has expired, or the time is unknown
+    Expired,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Magic => "the payload has no manifest",
+            Self::Signer => "the manifest was signed by an unknown key",
+            Self::Signature => "the signature of the manifest is invalid",
+            Self::Size => "the manifest is for a payload of another size",
+            Self::Digest => "the manifest is for another payload",
+            Self::Host => "the manifest does not allow this host",
+            Self::Expired => "the manifest has expired",
+        })
+    }
+}
+
+/// A parsed manifest
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    /// The SHA-256 digest of the image
+    pub digest: [u8; 32],
+
+    /// The size of the image, in bytes
+    pub size: u64,
+
+    /// The hosts which may run the payload (`KVM`, `SEV` and `SGX`)
+    pub hosts: u32,
+
+    /// When the manifest expires, in seconds since the Unix epoch (0 for never)
+    pub expires: u64,
+
+    /// The public key of the signer
+    pub signer: [u8; KEY_SIZE],
+
+    /// The signature over the first `SIGNED_SIZE` bytes
+    pub signature: [u8; SIGNATURE_SIZE],
+}
+
+impl Manifest {
+    /// Parses a manifest, without verifying it
+    pub fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < SIZE || bytes[..MAGIC.len()] != MAGIC {
+            return Err(Error::Magic);
+        }
+
+        let u64_at = |offset: usize| u64::from_le_bytes(bytes[offset..][..8].try_into().unwrap());
+
+        Ok(Self {
+            digest: bytes[8..40].try_into().unwrap(),
+            size: u64_at(40),
+            hosts: u32::from_le_bytes(bytes[48..52].try_into().unwrap()),
+            expires: u64_at(56),
+            signer: bytes[64..96].try_into().unwrap(),
+            signature: bytes[96..SIZE].try_into().unwrap(),
+        })
+    }
+
+    /// Encodes the manifest
+    pub fn to_bytes(&self) -> [u8; SIZE] {
+        let mut bytes = [0; SIZE];
+        bytes[..8].copy_from_slice(&MAGIC);
+        bytes[8..40].copy_from_slice(&self.digest);
+        bytes[40..48].copy_from_slice(&self.size.to_le_bytes());
+        bytes[48..52].copy_from_slice(&self.hosts.to_le_bytes());
+        bytes[56..64].copy_from_slice(&self.expires.to_le_bytes());
+        bytes[64..96].copy_from_slice(&self.signer);
+        bytes[96..].copy_from_slice(&self.signature);
+        bytes
+    }
+
+    /// Checks that the payload may run
+    ///
+    /// `image` is the image of the payload, `host` the kind of keep and `now`
+    /// the current time in seconds since the Unix epoch, if it is known.
+    pub fn verify(
+        &self,
+        key: &[u8; KEY_SIZE],
+        image: &[u8],
+        host: u32,
+        now: Option<u64>,
+    ) -> Result<(), Error> {
+        if &self.signer != key {
+            return Err(Error::Signer);
+        }
+
+        let signer = PublicKey::from_slice(key).or(Err(Error::Signer))?;
+        let signature = Signature::from_slice(&self.signature).or(Err(Error::Signature))?;
+        signer
+            .verify(&self.to_bytes()[..SIGNED_SIZE], &signature)
+            .or(Err(Error::Signature))?;
+
+        if self.size != image.len() as u64 {
+            return Err(Error::Size);
+        }
+
+        if Sha256::digest(image)[..] != self.digest {
+            return Err(Error::Digest);
+        }
+
+        if self.hosts & host == 0 {
+            return Err(Error::Host);
+        }
+
+        match (self.expires, now) {
+            (0, _) => Ok(()),
+            (expires, Some(now)) if now < expires => Ok(()),
+            _ => Err(Error::Expired),
+        }
+    }
+}
+
+/// Parses a public key in hexadecimal
+pub fn parse_key(hex: &str) -> Option<[u8; KEY_SIZE]> {
+    let hex = hex.as_bytes();
+    if hex.len() != KEY_SIZE * 2 {
+        return None;
+    }
+
+    let nibble = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+
+    let mut key = [0; KEY_SIZE];
+    for (byte, pair) in key.iter_mut().zip(hex.chunks(2)) {
+        *byte = nibble(pair[0])? << 4 | nibble(pair[1])?;
+    }
+
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ed25519_compact::{KeyPair, Seed};
+    use std::vec;
+
+    fn signed(pair: &KeyPair, image: &[u8], hosts: u32, expires: u64) -> Manifest {
+        let mut manifest = Manifest {
+            digest: Sha256::digest(image).into(),
+            size: image.len() as _,
+            hosts,
+            expires,
+            signer: *pair.pk,
+            signature: [0; SIGNATURE_SIZE],
+        };
+
+        let signature = pair.sk.sign(&manifest.to_bytes()[..SIGNED_SIZE], None);
+        manifest.signature = *signature;
+        manifest
+    }
+
+    #[test]
+    fn encoding() {
+        let pair = KeyPair::from_seed(Seed::new([1; 32]));
+        let manifest = signed(&pair, &[2; 4096], KVM | SEV, 1000);
+
+        let bytes = manifest.to_bytes();
+        assert_eq!(&bytes[..8], b"ENARXMF1");
+        assert_eq!(Manifest::parse(&bytes), Ok(manifest));
+
+        let mut page = vec![0; 4096];
+        page[..SIZE].copy_from_slice(&bytes);
+        assert_eq!(Manifest::parse(&page), Ok(manifest));
+
+        assert_eq!(Manifest::parse(&bytes[..SIZE - 1]), Err(Error::Magic));
+        assert_eq!(Manifest::parse(&[0; SIZE]), Err(Error::Magic));
+    }
+
+    #[test]
+    fn verification() {
+        let pair = KeyPair::from_seed(Seed::new([1; 32]));
+        let other = KeyPair::from_seed(Seed::new([3; 32]));
+        let key = *pair.pk;
+        let image = vec![2; 8192];
+
+        let manifest = signed(&pair, &image, KVM, 1000);
+        assert_eq!(manifest.verify(&key, &image, KVM, Some(999)), Ok(()));
+        assert_eq!(
+            manifest.verify(&key, &image, KVM, Some(1000)),
+            Err(Error::Expired)
+        );
+        assert_eq!(
+            manifest.verify(&key, &image, KVM, None),
+            Err(Error::Expired)
+        );
+        assert_eq!(
+            manifest.verify(&key, &image, SEV, Some(0)),
+            Err(Error::Host)
+        );
+        assert_eq!(
+            manifest.verify(&key, &image[..4096], KVM, Some(0)),
+            Err(Error::Size)
+        );
+
+        let mut changed = image.clone();
+        changed[100] = 0;
+        assert_eq!(
+            manifest.verify(&key, &changed, KVM, Some(0)),
+            Err(Error::Digest)
+        );
+
+        let forever = signed(&pair, &image, KVM | SEV, 0);
+        assert_eq!(forever.verify(&key, &image, SEV, None), Ok(()));
+
+        let foreign = signed(&other, &image, KVM, 0);
+        assert_eq!(foreign.verify(&key, &image, KVM, None), Err(Error::Signer));
+
+        let mut tampered = manifest;
+        tampered.expires = 0;
+        assert_eq!(
+            tampered.verify(&key, &image, KVM, None),
+            Err(Error::Signature)
+        );
+    }
+
+    #[test]
+    fn keys() {
+        let hex = "00112233445566778899aabbccddeeff00112233445566778899AABBCCDDEEFF";
+        let key = parse_key(hex).unwrap();
+        assert_eq!(key[..4], [0x00, 0x11, 0x22, 0x33]);
+        assert_eq!(key[31], 0xff);
+
+        assert_eq!(parse_key(&hex[1..]), None);
+        assert_eq!(parse_key(&hex.replace('0', "g")), None);
+    }
+}
@@ -0,0 +1,1305 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! An in-memory filesystem for the shims
+//!
+//! Workloads frequently expect a writable filesystem (i.e. `/tmp`), but we
+//! don't want to expose the host filesystem to them. This crate implements a
+//! small hierarchical filesystem which lives entirely inside the keep.
+//!
+//! The filesystem does not allocate by itself. The node and descriptor
+//! tables have a fixed size and file contents are stored in memory obtained
+//! from the shim through the `Pages` trait.
+//!
+//! File descriptors handed out by the filesystem start at `FD_BASE`, so that
+//! they can be told apart from the descriptors of the host. As on Linux, a
+//! descriptor refers to an open file, which duplicated descriptors share
+//! along with its position and status flags.
+//!
+//! Directories can be marked as mount points. Nothing exists beneath a mount
+//! point in the filesystem itself: paths below it are served by the host.
+
+#![no_std]
+#![deny(clippy::all)]
+#![deny(missing_docs)]
+
+#[cfg(test)]
+extern crate std;
+
+use core::cmp::{max, min};
+use core::mem::size_of;
+use core::ptr::{copy_nonoverlapping, write_bytes};
+
+use libc::c_int;
+
+/// The first file descriptor used by the filesystem
+///
+/// Linux hands out the lowest available descriptor, so the host will not
+/// reach this number in practice.
+pub const FD_BASE: c_int = 1 << 30;
+
+/// The maximum number of files and directories (including the root)
+pub const MAX_NODES: usize = 256;
+
+/// The maximum number of open file descriptors
+pub const MAX_FILES: usize = 64;
+
+/// The status flags which `F_SETFL` changes
+const SETFL: c_int = libc::O_APPEND | libc::O_NONBLOCK;
+
+/// The flags of `open()` which aren't status flags of the open file
+const OPEN_ONLY: c_int =
+    libc::O_CREAT | libc::O_EXCL | libc::O_NOCTTY | libc::O_TRUNC | libc::O_CLOEXEC;
+
+/// The maximum length of a single path component
+pub const NAME_MAX: usize = 255;
+
+const PAGE_SIZE: usize = 4096;
+const ROOT: usize = 0;
+const DEV: u64 = 0xea;
+
+/// A source of memory for file contents
+pub trait Pages {
+    /// Allocates `len` bytes of page-aligned memory
+    ///
+    /// The memory does not need to be zeroed.
+    fn allocate(&mut self, len: usize) -> Option<*mut u8>;
+
+    /// Releases memory obtained from `allocate()`
+    ///
+    /// # Safety
+    ///
+    /// `ptr` and `len` must match a previous call to `allocate()`.
+    unsafe fn release(&mut self, ptr: *mut u8, len: usize);
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Kind {
+    Directory,
+    File,
+}
+
+struct Node {
+    kind: Kind,
+    mode: libc::mode_t,
+    parent: usize,
+    name: [u8; NAME_MAX],
+    nlen: usize,
+    linked: bool,
+    mounted: Option<u32>,
+    opens: usize,
+    data: *mut u8,
+    capacity: usize,
+    size: usize,
+}
+
+impl Node {
+    const fn root() -> Self {
+        Self {
+            kind: Kind::Directory,
+            mode: 0o1777,
+            parent: ROOT,
+            name: [0; NAME_MAX],
+            nlen: 0,
+            linked: true,
+            mounted: None,
+            opens: 0,
+            data: core::ptr::null_mut(),
+            capacity: 0,
+            size: 0,
+        }
+    }
+
+    fn name(&self) -> &[u8] {
+        &self.name[..self.nlen]
+    }
+
+    fn rename(&mut self, parent: usize, name: &[u8]) {
+        self.parent = parent;
+        self.name[..name.len()].copy_from_slice(name);
+        self.nlen = name.len();
+    }
+
+    fn contents(&self) -> &[u8] {
+        match self.size {
+            0 => &[],
+            n => unsafe { core::slice::from_raw_parts(self.data, n) },
+        }
+    }
+}
+
+/// A path on or beneath a mount point (see `MemFs::mounted()`)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Mounted {
+    /// The length of the absolute path, not counting the terminating NUL
+    pub len: usize,
+
+    /// The flags of the mount point
+    pub flags: u32,
+}
+
+#[derive(Copy, Clone)]
+struct File {
+    node: usize,
+    flags: c_int,
+    offset: usize,
+
+    /// The number of descriptors which refer to the file
+    refs: usize,
+}
+
+/// A file descriptor
+#[derive(Copy, Clone)]
+struct Fd {
+    /// The index of the open file
+    file: usize,
+
+    /// Whether `FD_CLOEXEC` is set
+    cloexec: bool,
+}
+
+impl File {
+    fn readable(&self) -> bool {
+        self.flags & libc::O_ACCMODE != libc::O_WRONLY
+    }
+
+    fn writable(&self) -> bool {
+        self.flags & libc::O_ACCMODE != libc::O_RDONLY
+    }
+}
+
+/// The in-memory filesystem
+///
+/// Paths are resolved relative to the root directory unless a directory
+/// descriptor is given.
+pub struct MemFs {
+    nodes: [Option<Node>; MAX_NODES],
+    files: [Option<File>; MAX_FILES],
+    fds: [Option<Fd>; MAX_FILES],
+}
+
+// The file contents are exclusively owned by the filesystem.
+unsafe impl Send for MemFs {}
+
+impl Default for MemFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemFs {
+    /// Creates a filesystem containing only an empty root directory
+    pub const fn new() -> Self {
+        const NODE: Option<Node> = None;
+        const FILE: Option<File> = None;
+        const FD: Option<Fd> = None;
+
+        let mut nodes = [NODE; MAX_NODES];
+        nodes[ROOT] = Some(Node::root());
+
+        Self {
+            nodes,
+            files: [FILE; MAX_FILES],
+            fds: [FD; MAX_FILES],
+        }
+    }
+
+    /// Whether a file descriptor belongs to the filesystem
+    pub fn owns(fd: c_int) -> bool {
+        fd >= FD_BASE && ((fd - FD_BASE) as usize) < MAX_FILES
+    }
+
+    fn node(&self, index: usize) -> &Node {
+        self.nodes[index].as_ref().unwrap()
+    }
+
+    fn node_mut(&mut self, index: usize) -> &mut Node {
+        self.nodes[index].as_mut().unwrap()
+    }
+
+    fn fd(&self, fd: c_int) -> Result<Fd, c_int> {
+        match Self::owns(fd) {
+            true => self.fds[(fd - FD_BASE) as usize].ok_or(libc::EBADF),
+            false => Err(libc::EBADF),
+        }
+    }
+
+    fn file(&self, fd: c_int) -> Result<File, c_int> {
+        Ok(self.files[self.fd(fd)?.file].unwrap())
+    }
+
+    fn file_mut(&mut self, fd: c_int) -> Result<&mut File, c_int> {
+        let index = self.fd(fd)?.file;
+        Ok(self.files[index].as_mut().unwrap())
+    }
+
+    /// Points a free descriptor at an open file
+    fn install(&mut self, slot: usize, file: usize, cloexec: bool) -> c_int {
+        self.files[file].as_mut().unwrap().refs += 1;
+        self.fds[slot] = Some(Fd { file, cloexec });
+        FD_BASE + slot as c_int
+    }
+
+    /// Finds a linked child of a directory by name
+    fn child(&self, dir: usize, name: &[u8]) -> Option<usize> {
+        self.nodes.iter().enumerate().find_map(|(i, n)| match n {
+            Some(n) if i != ROOT && n.linked && n.parent == dir && n.name() == name => Some(i),
+            _ => None,
+        })
+    }
+
+    /// Returns the directory that relative paths are resolved against
+    fn base(&self, dirfd: c_int, path: &[u8]) -> Result<usize, c_int> {
+        if path.first() == Some(&b'/') || dirfd == libc::AT_FDCWD {
+            return Ok(ROOT);
+        }
+
+        let file = self.file(dirfd)?;
+        match self.node(file.node).kind {
+            Kind::Directory => Ok(file.node),
+            Kind::File => Err(libc::ENOTDIR),
+        }
+    }
+
+    /// Walks `path` starting at `dir`, returning the node it names
+    fn walk(&self, mut dir: usize, path: &[u8]) -> Result<usize, c_int> {
+        for name in path.split(|b| *b == b'/') {
+            if self.node(dir).kind != Kind::Directory {
+                return Err(libc::ENOTDIR);
+            }
+
+            dir = match name {
+                b"" | b"." => dir,
+                b".." => self.node(dir).parent,
+                name if name.len() > NAME_MAX => return Err(libc::ENAMETOOLONG),
+                name => self.child(dir, name).ok_or(libc::ENOENT)?,
+            };
+        }
+
+        Ok(dir)
+    }
+
+    /// Resolves a path to a node
+    fn lookup(&self, dirfd: c_int, path: &[u8]) -> Result<usize, c_int> {
+        if path.is_empty() {
+            return Err(libc::ENOENT);
+        }
+
+        self.walk(self.base(dirfd, path)?, path)
+    }
+
+    /// Resolves all but the last component of a path
+    ///
+    /// Returns the parent directory and the name of the final component.
+    fn parent<'p>(&self, dirfd: c_int, path: &'p [u8]) -> Result<(usize, &'p [u8]), c_int> {
+        if path.is_empty() {
+            return Err(libc::ENOENT);
+        }
+
+        let trimmed = match path.iter().rposition(|b| *b != b'/') {
+            Some(end) => &path[..=end],
+            None => return Err(libc::EEXIST),
+        };
+
+        let (dir, name) = match trimmed.iter().rposition(|b| *b == b'/') {
+            Some(slash) => (&trimmed[..=slash], &trimmed[slash + 1..]),
+            None => (&b""[..], trimmed),
+        };
+
+        match name {
+            b"." | b".." => return Err(libc::EINVAL),
+            name if name.len() > NAME_MAX => return Err(libc::ENAMETOOLONG),
+            _ => (),
+        }
+
+        let dir = self.walk(self.base(dirfd, path)?, dir)?;
+        match self.node(dir).kind {
+            Kind::Directory => Ok((dir, name)),
+            Kind::File => Err(libc::ENOTDIR),
+        }
+    }
+
+    /// Creates a new node
+    fn insert(
+        &mut self,
+        parent: usize,
+        name: &[u8],
+        kind: Kind,
+        mode: libc::mode_t,
+    ) -> Result<usize, c_int> {
+        let index = self
+            .nodes
+            .iter()
+            .position(|n| n.is_none())
+            .ok_or(libc::ENOSPC)?;
+
+        let mut node = Node::root();
+        node.kind = kind;
+        node.mode = mode & 0o7777;
+        node.rename(parent, name);
+
+        self.nodes[index] = Some(node);
+        Ok(index)
+    }
+
+    /// Unlinks a node, releasing it if it isn't open
+    fn unlink_node(&mut self, pages: &mut impl Pages, index: usize) {
+        self.node_mut(index).linked = false;
+        self.release(pages, index);
+    }
+
+    /// Releases a node which is neither linked nor open
+    fn release(&mut self, pages: &mut impl Pages, index: usize) {
+        let node = self.node(index);
+        if node.linked || node.opens > 0 {
+            return;
+        }
+
+        if node.capacity > 0 {
+            unsafe { pages.release(node.data, node.capacity) };
+        }
+
+        self.nodes[index] = None;
+    }
+
+    /// Changes the size of a file
+    fn resize(&mut self, pages: &mut impl Pages, index: usize, size: usize) -> Result<(), c_int> {
+        let node = self.node_mut(index);
+
+        if size > node.capacity {
+            let capacity = max(size, node.capacity.saturating_mul(2));
+            let capacity =
+                capacity.checked_add(PAGE_SIZE - 1).ok_or(libc::EFBIG)? / PAGE_SIZE * PAGE_SIZE;
+
+            let data = pages.allocate(capacity).ok_or(libc::ENOSPC)?;
+            unsafe {
+                if node.capacity > 0 {
+                    copy_nonoverlapping(node.data, data, node.size);
+                    pages.release(node.data, node.capacity);
+                }
+
+                write_bytes(data.add(node.size), 0, capacity - node.size);
+            }
+
+            node.data = data;
+            node.capacity = capacity;
+        } else if size < node.size {
+            // Clear the tail so that growing the file again reads zeroes.
+            unsafe { write_bytes(node.data.add(size), 0, node.size - size) };
+        }
+
+        node.size = size;
+        Ok(())
+    }
+
+    /// Whether a node is, or contains, a mount point
+    fn contains_mount(&self, index: usize) -> bool {
+        self.nodes.iter().enumerate().any(|(mut i, n)| {
+            if !matches!(n, Some(n) if n.mounted.is_some()) {
+                return false;
+            }
+
+            loop {
+                match i {
+                    i if i == index => return true,
+                    ROOT => return false,
+                    _ => i = self.node(i).parent,
+                }
+            }
+        })
+    }
+
+    /// Fills in a `stat` structure for a node
+    fn stat_node(&self, index: usize) -> libc::stat {
+        let node = self.node(index);
+        let mut stat: libc::stat = unsafe { core::mem::zeroed() };
+
+        let (kind, nlink) = match node.kind {
+            Kind::Directory => (libc::S_IFDIR, 2),
+            Kind::File => (libc::S_IFREG, node.linked as _),
+        };
+
+        stat.st_dev = DEV;
+        stat.st_ino = index as u64 + 1;
+        stat.st_nlink = nlink;
+        stat.st_mode = kind | node.mode;
+        stat.st_size = node.size as _;
+        stat.st_blksize = PAGE_SIZE as _;
+        stat.st_blocks = (node.capacity / 512) as _;
+        stat
+    }
+
+    /// Opens (and possibly creates) a file or directory
+    pub fn open(
+        &mut self,
+        pages: &mut impl Pages,
+        dirfd: c_int,
+        path: &[u8],
+        flags: c_int,
+        mode: libc::mode_t,
+    ) -> Result<c_int, c_int> {
+        // There are as many open files as descriptors, so a free descriptor
+        // means a free open file.
+        let slot = self
+            .fds
+            .iter()
+            .position(|f| f.is_none())
+            .ok_or(libc::EMFILE)?;
+        let open = self.files.iter().position(|f| f.is_none()).unwrap();
+
+        let index = match self.lookup(dirfd, path) {
+            Ok(_) if flags & (libc::O_CREAT | libc::O_EXCL) == libc::O_CREAT | libc::O_EXCL => {
+                return Err(libc::EEXIST)
+            }
+
+            Err(libc::ENOENT) if flags & libc::O_CREAT != 0 => {
+                let (dir, name) = self.parent(dirfd, path)?;
+                self.insert(dir, name, Kind::File, mode)?
+            }
+
+            index => index?,
+        };
+
+        let file = File {
+            node: index,
+            flags: flags & !OPEN_ONLY,
+            offset: 0,
+            refs: 0,
+        };
+
+        match self.node(index).kind {
+            Kind::Directory if file.writable() || flags & libc::O_CREAT != 0 => {
+                return Err(libc::EISDIR)
+            }
+            Kind::File if flags & libc::O_DIRECTORY != 0 => return Err(libc::ENOTDIR),
+            Kind::File if flags & libc::O_TRUNC != 0 && file.writable() => {
+                self.resize(pages, index, 0)?
+            }
+            _ => (),
+        }
+
+        self.node_mut(index).opens += 1;
+        self.files[open] = Some(file);
+        Ok(self.install(slot, open, flags & libc::O_CLOEXEC != 0))
+    }
+
+    /// Closes a file descriptor
+    ///
+    /// The open file is closed with its last descriptor.
+    pub fn close(&mut self, pages: &mut impl Pages, fd: c_int) -> Result<(), c_int> {
+        let index = self.fd(fd)?.file;
+        self.fds[(fd - FD_BASE) as usize] = None;
+
+        let file = self.files[index].as_mut().unwrap();
+        file.refs -= 1;
+        if file.refs > 0 {
+            return Ok(());
+        }
+
+        let node = file.node;
+        self.files[index] = None;
+        self.node_mut(node).opens -= 1;
+        self.release(pages, node);
+        Ok(())
+    }
+
+    /// Duplicates a file descriptor to the lowest free one which isn't below
+    /// `min`
+    pub fn dup(&mut self, fd: c_int, min: c_int, cloexec: bool) -> Result<c_int, c_int> {
+        let file = self.fd(fd)?.file;
+        let start = max(min, FD_BASE) - FD_BASE;
+        let slot = (start as usize..MAX_FILES)
+            .find(|slot| self.fds[*slot].is_none())
+            .ok_or(libc::EMFILE)?;
+
+        Ok(self.install(slot, file, cloexec))
+    }
+
+    /// Duplicates a file descriptor to `newfd`, which is closed first
+    ///
+    /// `newfd` must be a descriptor of the filesystem as well.
+    pub fn dup2(
+        &mut self,
+        pages: &mut impl Pages,
+        fd: c_int,
+        newfd: c_int,
+        cloexec: bool,
+    ) -> Result<c_int, c_int> {
+        let file = self.fd(fd)?.file;
+        if !Self::owns(newfd) {
+            return Err(libc::EBADF);
+        }
+
+        if newfd == fd {
+            return Ok(newfd);
+        }
+
+        if self.fd(newfd).is_ok() {
+            self.close(pages, newfd)?;
+        }
+
+        Ok(self.install((newfd - FD_BASE) as usize, file, cloexec))
+    }
+
+    /// Manipulates a file descriptor
+    ///
+    /// Only duplicating descriptors and getting and setting their flags are
+    /// supported. `O_NONBLOCK` can be set, but nothing blocks anyway.
+    pub fn fcntl(&mut self, fd: c_int, cmd: c_int, arg: c_int) -> Result<c_int, c_int> {
+        match cmd {
+            libc::F_DUPFD | libc::F_DUPFD_CLOEXEC if arg < 0 => Err(libc::EINVAL),
+            libc::F_DUPFD => self.dup(fd, arg, false),
+            libc::F_DUPFD_CLOEXEC => self.dup(fd, arg, true),
+            libc::F_GETFD => match self.fd(fd)?.cloexec {
+                true => Ok(libc::FD_CLOEXEC),
+                false => Ok(0),
+            },
+            libc::F_SETFD => {
+                let file = self.fd(fd)?.file;
+                let cloexec = arg & libc::FD_CLOEXEC != 0;
+                self.fds[(fd - FD_BASE) as usize] = Some(Fd { file, cloexec });
+                Ok(0)
+            }
+            libc::F_GETFL => Ok(self.file(fd)?.flags),
+            libc::F_SETFL => {
+                let file = self.file_mut(fd)?;
+                file.flags = file.flags & !SETFL | arg & SETFL;
+                Ok(0)
+            }
+            _ => Err(libc::EINVAL),
+        }
+    }
+
+    /// Reads from a file at an offset without moving the file position
+    pub fn pread(&self, fd: c_int, buf: &mut [u8], offset: usize) -> Result<usize, c_int> {
+        let file = self.file(fd)?;
+        if !file.readable() {
+            return Err(libc::EBADF);
+        }
+
+        let node = self.node(file.node);
+        if node.kind == Kind::Directory {
+            return Err(libc::EISDIR);
+        }
+
+        let contents = node.contents();
+        let start = min(offset, contents.len());
+        let len = min(buf.len(), contents.len() - start);
+        buf[..len].copy_from_slice(&contents[start..start + len]);
+        Ok(len)
+    }
+
+    /// Reads from a file
+    pub fn read(&mut self, fd: c_int, buf: &mut [u8]) -> Result<usize, c_int> {
+        let offset = self.file(fd)?.offset;
+        let len = self.pread(fd, buf, offset)?;
+        self.file_mut(fd)?.offset += len;
+        Ok(len)
+    }
+
+    /// Writes to a file at an offset without moving the file position
+    pub fn pwrite(
+        &mut self,
+        pages: &mut impl Pages,
+        fd: c_int,
+        buf: &[u8],
+        offset: usize,
+    ) -> Result<usize, c_int> {
+        let file = self.file(fd)?;
+        if !file.writable() {
+            return Err(libc::EBADF);
+        }
+
+        let end = offset.checked_add(buf.len()).ok_or(libc::EFBIG)?;
+        if end > isize::MAX as usize {
+            return Err(libc::EFBIG);
+        }
+
+        if end > self.node(file.node).size {
+            self.resize(pages, file.node, end)?;
+        }
+
+        let node = self.node(file.node);
+        unsafe { copy_nonoverlapping(buf.as_ptr(), node.data.add(offset), buf.len()) };
+        Ok(buf.len())
+    }
+
+    /// Writes to a file
+    pub fn write(&mut self, pages: &mut impl Pages, fd: c_int, buf: &[u8]) -> Result<usize, c_int> {
+        let file = self.file(fd)?;
+        let offset = match file.flags & libc::O_APPEND {
+            0 => file.offset,
+            _ => self.node(file.node).size,
+        };
+
+        let len = self.pwrite(pages, fd, buf, offset)?;
+        self.file_mut(fd)?.offset = offset + len;
+        Ok(len)
+    }
+
+    /// Moves the file position
+    pub fn lseek(&mut self, fd: c_int, offset: libc::off_t, whence: c_int) -> Result<usize, c_int> {
+        let file = self.file(fd)?;
+        let node = self.node(file.node);
+
+        let base = match (node.kind, whence) {
+            (Kind::Directory, libc::SEEK_SET) if offset == 0 => 0,
+            (Kind::Directory, _) => return Err(libc::EINVAL),
+            (Kind::File, libc::SEEK_SET) => 0,
+            (Kind::File, libc::SEEK_CUR) => file.offset as libc::off_t,
+            (Kind::File, libc::SEEK_END) => node.size as libc::off_t,
+            _ => return Err(libc::EINVAL),
+        };
+
+        let offset = match base.checked_add(offset) {
+            Some(offset) if offset >= 0 => offset as usize,
+            _ => return Err(libc::EINVAL),
+        };
+
+        self.file_mut(fd)?.offset = offset;
+        Ok(offset)
+    }
+
+    /// Changes the size of an open file
+    pub fn ftruncate(
+        &mut self,
+        pages: &mut impl Pages,
+        fd: c_int,
+        size: usize,
+    ) -> Result<(), c_int> {
+        let file = self.file(fd)?;
+        if !file.writable() || self.node(file.node).kind != Kind::File {
+            return Err(libc::EINVAL);
+        }
+
+        if size > isize::MAX as usize {
+            return Err(libc::EFBIG);
+        }
+
+        self.resize(pages, file.node, size)
+    }
+
+    /// Gets the status of an open file
+    pub fn fstat(&self, fd: c_int) -> Result<libc::stat, c_int> {
+        Ok(self.stat_node(self.file(fd)?.node))
+    }
+
+    /// Gets the status of a path
+    pub fn stat(&self, dirfd: c_int, path: &[u8]) -> Result<libc::stat, c_int> {
+        Ok(self.stat_node(self.lookup(dirfd, path)?))
+    }
+
+    /// Creates a directory
+    pub fn mkdir(&mut self, dirfd: c_int, path: &[u8], mode: libc::mode_t) -> Result<(), c_int> {
+        let (dir, name) = self.parent(dirfd, path)?;
+        if self.child(dir, name).is_some() {
+            return Err(libc::EEXIST);
+        }
+
+        self.insert(dir, name, Kind::Directory, mode)?;
+        Ok(())
+    }
+
+    /// Removes an empty directory
+    pub fn rmdir(
+        &mut self,
+        pages: &mut impl Pages,
+        dirfd: c_int,
+        path: &[u8],
+    ) -> Result<(), c_int> {
+        let (dir, name) = self.parent(dirfd, path).map_err(|e| match e {
+            libc::EEXIST => libc::EBUSY,
+            e => e,
+        })?;
+
+        let index = self.child(dir, name).ok_or(libc::ENOENT)?;
+        if self.node(index).kind != Kind::Directory {
+            return Err(libc::ENOTDIR);
+        }
+
+        if self.contains_mount(index) {
+            return Err(libc::EBUSY);
+        }
+
+        if self
+            .nodes
+            .iter()
+            .flatten()
+            .any(|n| n.linked && n.parent == index)
+        {
+            return Err(libc::ENOTEMPTY);
+        }
+
+        self.unlink_node(pages, index);
+        Ok(())
+    }
+
+    /// Removes a file
+    pub fn unlink(
+        &mut self,
+        pages: &mut impl Pages,
+        dirfd: c_int,
+        path: &[u8],
+    ) -> Result<(), c_int> {
+        let (dir, name) = self.parent(dirfd, path).map_err(|e| match e {
+            libc::EEXIST => libc::EISDIR,
+            e => e,
+        })?;
+
+        let index = self.child(dir, name).ok_or(libc::ENOENT)?;
+        if self.node(index).kind == Kind::Directory {
+            return Err(libc::EISDIR);
+        }
+
+        self.unlink_node(pages, index);
+        Ok(())
+    }
+
+    /// Moves a file or directory, replacing any existing destination
+    pub fn rename(
+        &mut self,
+        pages: &mut impl Pages,
+        olddirfd: c_int,
+        oldpath: &[u8],
+        newdirfd: c_int,
+        newpath: &[u8],
+    ) -> Result<(), c_int> {
+        let busy = |e| match e {
+            libc::EEXIST => libc::EBUSY,
+            e => e,
+        };
+
+        let (odir, oname) = self.parent(olddirfd, oldpath).map_err(busy)?;
+        let (ndir, nname) = self.parent(newdirfd, newpath).map_err(busy)?;
+        let index = self.child(odir, oname).ok_or(libc::ENOENT)?;
+        let kind = self.node(index).kind;
+
+        if kind == Kind::Directory && self.contains_mount(index) {
+            return Err(libc::EBUSY);
+        }
+
+        // A directory cannot be moved inside itself.
+        if kind == Kind::Directory {
+            let mut dir = ndir;
+            while dir != ROOT {
+                if dir == index {
+                    return Err(libc::EINVAL);
+                }
+
+                dir = self.node(dir).parent;
+            }
+        }
+
+        match self.child(ndir, nname) {
+            Some(existing) if existing == index => return Ok(()),
+            Some(existing) => {
+                match (kind, self.node(existing).kind) {
+                    (Kind::File, Kind::Directory) => return Err(libc::EISDIR),
+                    (Kind::Directory, Kind::File) => return Err(libc::ENOTDIR),
+                    (Kind::Directory, Kind::Directory) => {
+                        let parent = |n: &Node| n.linked && n.parent == existing;
+                        if self.nodes.iter().flatten().any(parent) {
+                            return Err(libc::ENOTEMPTY);
+                        }
+                    }
+                    (Kind::File, Kind::File) => (),
+                }
+
+                self.unlink_node(pages, existing);
+            }
+            None => (),
+        }
+
+        self.node_mut(index).rename(ndir, nname);
+        Ok(())
+    }
+
+    /// Reads directory entries as `struct linux_dirent64`
+    ///
+    /// The file position counts entries: `.` and `..` come first, followed
+    /// by the children of the directory in node order.
+    pub fn getdents64(&mut self, fd: c_int, buf: &mut [u8]) -> Result<usize, c_int> {
+        const HEADER: usize = size_of::<u64>() + size_of::<i64>() + size_of::<u16>() + 1;
+
+        let file = self.file(fd)?;
+        let dir = file.node;
+        if self.node(dir).kind != Kind::Directory {
+            return Err(libc::ENOTDIR);
+        }
+
+        let mut position = file.offset;
+        let mut written = 0;
+        loop {
+            let (index, name, next) = match position {
+                0 => (dir, &b"."[..], 1),
+                1 => (self.node(dir).parent, &b".."[..], 2),
+                n => match self.nodes.iter().enumerate().skip(n - 2).find(
+                    |(i, n)| matches!(n, Some(n) if *i != ROOT && n.linked && n.parent == dir),
+                ) {
+                    Some((i, _)) => (i, self.node(i).name(), i + 3),
+                    None => break,
+                },
+            };
+
+            let reclen = (HEADER + name.len() + 1 + 7) & !7;
+            if written + reclen > buf.len() {
+                if written == 0 {
+                    return Err(libc::EINVAL);
+                }
+
+                break;
+            }
+
+            let kind = match self.node(index).kind {
+                Kind::Directory => libc::DT_DIR,
+                Kind::File => libc::DT_REG,
+            };
+
+            let record = &mut buf[written..written + reclen];
+            record[0..8].copy_from_slice(&(index as u64 + 1).to_ne_bytes());
+            record[8..16].copy_from_slice(&(next as i64).to_ne_bytes());
+            record[16..18].copy_from_slice(&(reclen as u16).to_ne_bytes());
+            record[18] = kind;
+            record[HEADER..HEADER + name.len()].copy_from_slice(name);
+            for b in record[HEADER + name.len()..].iter_mut() {
+                *b = 0;
+            }
+
+            written += reclen;
+            position = next;
+        }
+
+        self.file_mut(fd)?.offset = position;
+        Ok(written)
+    }
+
+    /// Marks a directory as a mount point, creating it if needed
+    ///
+    /// The `flags` are not interpreted, but returned by `mounted()`. Mount
+    /// points cannot be nested.
+    pub fn mount(&mut self, path: &[u8], flags: u32) -> Result<(), c_int> {
+        let mut dir = ROOT;
+        for name in path.split(|b| *b == b'/') {
+            if self.node(dir).mounted.is_some() {
+                return Err(libc::EBUSY);
+            }
+
+            dir = match name {
+                b"" | b"." => dir,
+                b".." => return Err(libc::EINVAL),
+                name if name.len() > NAME_MAX => return Err(libc::ENAMETOOLONG),
+                name => match self.child(dir, name) {
+                    Some(child) if self.node(child).kind == Kind::Directory => child,
+                    Some(_) => return Err(libc::ENOTDIR),
+                    None => self.insert(dir, name, Kind::Directory, 0o755)?,
+                },
+            };
+        }
+
+        if dir == ROOT || self.contains_mount(dir) {
+            return Err(libc::EBUSY);
+        }
+
+        self.node_mut(dir).mounted = Some(flags);
+        Ok(())
+    }
+
+    /// Checks whether a path lies on or beneath a mount point
+    ///
+    /// If it does, the equivalent absolute path is written to `buf` (with a
+    /// terminating NUL). The path is resolved lexically, so that it can be
+    /// handed to the host as is.
+    pub fn mounted(
+        &self,
+        dirfd: c_int,
+        path: &[u8],
+        buf: &mut [u8],
+    ) -> Result<Option<Mounted>, c_int> {
+        if path.is_empty() {
+            return Ok(None);
+        }
+
+        let len = self.absolute(self.base(dirfd, path)?, path, buf)?;
+
+        let mut dir = ROOT;
+        for name in buf[..len].split(|b| *b == b'/').filter(|n| !n.is_empty()) {
+            if let Some(flags) = self.node(dir).mounted {
+                return Ok(Some(Mounted { len, flags }));
+            }
+
+            dir = match self.child(dir, name) {
+                Some(child) => child,
+                None => return Ok(None),
+            };
+        }
+
+        Ok(self.node(dir).mounted.map(|flags| Mounted { len, flags }))
+    }
+
+    /// Writes the absolute path of `path`, relative to `dir`, to `buf`
+    fn absolute(&self, dir: usize, path: &[u8], buf: &mut [u8]) -> Result<usize, c_int> {
+        let mut len = 0;
+        let mut index = dir;
+        while index != ROOT {
+            len += 1 + self.node(index).nlen;
+            index = self.node(index).parent;
+        }
+
+        if len + 1 >= buf.len() {
+            return Err(libc::ENAMETOOLONG);
+        }
+
+        let (mut end, mut index) = (len, dir);
+        while index != ROOT {
+            let name = self.node(index).name();
+            buf[end - name.len()..end].copy_from_slice(name);
+            end -= name.len() + 1;
+            buf[end] = b'/';
+            index = self.node(index).parent;
+        }
+
+        for name in path.split(|b| *b == b'/') {
+            match name {
+                b"" | b"." => (),
+                b".." => len = buf[..len].iter().rposition(|b| *b == b'/').unwrap_or(0),
+                name if len + name.len() + 2 >= buf.len() => return Err(libc::ENAMETOOLONG),
+                name => {
+                    buf[len] = b'/';
+                    buf[len + 1..len + 1 + name.len()].copy_from_slice(name);
+                    len += 1 + name.len();
+                }
+            }
+        }
+
+        if len == 0 {
+            buf[0] = b'/';
+            len = 1;
+        }
+
+        buf[len] = 0;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::alloc::{alloc, dealloc, Layout};
+    use std::vec::Vec;
+
+    struct Heap(usize);
+
+    impl Pages for Heap {
+        fn allocate(&mut self, len: usize) -> Option<*mut u8> {
+            self.0 += 1;
+            Some(unsafe { alloc(Layout::from_size_align(len, PAGE_SIZE).unwrap()) })
+        }
+
+        unsafe fn release(&mut self, ptr: *mut u8, len: usize) {
+            self.0 -= 1;
+            dealloc(ptr, Layout::from_size_align(len, PAGE_SIZE).unwrap())
+        }
+    }
+
+    const RW: c_int = libc::O_RDWR | libc::O_CREAT;
+
+    fn names(fs: &mut MemFs, path: &[u8]) -> Vec<Vec<u8>> {
+        let mut heap = Heap(0);
+        let fd = fs.open(&mut heap, libc::AT_FDCWD, path, 0, 0).unwrap();
+
+        let mut names = Vec::new();
+        let mut buf = [0u8; 64];
+        loop {
+            let len = fs.getdents64(fd, &mut buf).unwrap();
+            if len == 0 {
+                break;
+            }
+
+            let mut offset = 0;
+            while offset < len {
+                let reclen = u16::from_ne_bytes([buf[offset + 16], buf[offset + 17]]) as usize;
+                let name = &buf[offset + 19..offset + reclen];
+                let end = name.iter().position(|b| *b == 0).unwrap();
+                names.push(name[..end].to_vec());
+                offset += reclen;
+            }
+        }
+
+        fs.close(&mut heap, fd).unwrap();
+        names
+    }
+
+    #[test]
+    fn read_write() {
+        let mut heap = Heap(0);
+        let mut fs = MemFs::new();
+
+        let fd = fs
+            .open(&mut heap, libc::AT_FDCWD, b"/file", RW, 0o644)
+            .unwrap();
+        assert!(MemFs::owns(fd));
+        assert_eq!(fs.write(&mut heap, fd, b"hello").unwrap(), 5);
+        assert_eq!(fs.pwrite(&mut heap, fd, b"!", 8192).unwrap(), 1);
+        assert_eq!(fs.fstat(fd).unwrap().st_size, 8193);
+
+        let mut buf = [0xffu8; 8];
+        assert_eq!(fs.lseek(fd, 0, libc::SEEK_SET).unwrap(), 0);
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), 8);
+        assert_eq!(&buf, b"hello\0\0\0");
+
+        fs.ftruncate(&mut heap, fd, 2).unwrap();
+        fs.ftruncate(&mut heap, fd, 4).unwrap();
+        assert_eq!(fs.pread(fd, &mut buf, 0).unwrap(), 4);
+        assert_eq!(&buf[..4], b"he\0\0");
+
+        fs.close(&mut heap, fd).unwrap();
+        assert_eq!(fs.close(&mut heap, fd), Err(libc::EBADF));
+
+        let fd = fs
+            .open(&mut heap, libc::AT_FDCWD, b"file", libc::O_RDONLY, 0)
+            .unwrap();
+        assert_eq!(fs.write(&mut heap, fd, b"x"), Err(libc::EBADF));
+        fs.close(&mut heap, fd).unwrap();
+
+        fs.unlink(&mut heap, libc::AT_FDCWD, b"/file").unwrap();
+        assert_eq!(heap.0, 0);
+    }
+
+    #[test]
+    fn open_flags() {
+        let mut heap = Heap(0);
+        let mut fs = MemFs::new();
+
+        let open = |fs: &mut MemFs, heap: &mut Heap, flags| {
+            fs.open(heap, libc::AT_FDCWD, b"/f", flags, 0o600)
+        };
+
+        assert_eq!(open(&mut fs, &mut heap, libc::O_RDONLY), Err(libc::ENOENT));
+
+        let fd = open(
+            &mut fs,
+            &mut heap,
+            libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL,
+        )
+        .unwrap();
+        fs.write(&mut heap, fd, b"abc").unwrap();
+        fs.close(&mut heap, fd).unwrap();
+
+        let excl = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL;
+        assert_eq!(open(&mut fs, &mut heap, excl), Err(libc::EEXIST));
+
+        let fd = open(&mut fs, &mut heap, libc::O_WRONLY | libc::O_APPEND).unwrap();
+        fs.write(&mut heap, fd, b"def").unwrap();
+        assert_eq!(fs.fstat(fd).unwrap().st_size, 6);
+        fs.close(&mut heap, fd).unwrap();
+
+        let fd = open(&mut fs, &mut heap, libc::O_WRONLY | libc::O_TRUNC).unwrap();
+        assert_eq!(fs.fstat(fd).unwrap().st_size, 0);
+        fs.close(&mut heap, fd).unwrap();
+
+        let dir = libc::O_RDONLY | libc::O_DIRECTORY;
+        assert_eq!(open(&mut fs, &mut heap, dir), Err(libc::ENOTDIR));
+        assert_eq!(
+            fs.open(&mut heap, libc::AT_FDCWD, b"/", libc::O_WRONLY, 0),
+            Err(libc::EISDIR)
+        );
+        assert_eq!(
+            fs.open(&mut heap, libc::AT_FDCWD, b"/f/g", RW, 0),
+            Err(libc::ENOTDIR)
+        );
+    }
+
+    #[test]
+    fn descriptors() {
+        let mut heap = Heap(0);
+        let mut fs = MemFs::new();
+
+        let flags = RW | libc::O_CLOEXEC | libc::O_TRUNC;
+        let fd = fs.open(&mut heap, libc::AT_FDCWD, b"/f", flags, 0).unwrap();
+        assert_eq!(fs.fcntl(fd, libc::F_GETFD, 0), Ok(libc::FD_CLOEXEC));
+        assert_eq!(fs.fcntl(fd, libc::F_GETFL, 0), Ok(libc::O_RDWR));
+
+        // Duplicates share the position and the status flags, but not
+        // FD_CLOEXEC.
+        let dup = fs.dup(fd, 0, false).unwrap();
+        assert_eq!(dup, fd + 1);
+        assert_eq!(fs.fcntl(dup, libc::F_GETFD, 0), Ok(0));
+        fs.write(&mut heap, fd, b"abc").unwrap();
+        assert_eq!(fs.lseek(dup, 0, libc::SEEK_CUR), Ok(3));
+        fs.fcntl(dup, libc::F_SETFL, libc::O_NONBLOCK | libc::O_TRUNC)
+            .unwrap();
+        let nonblock = libc::O_RDWR | libc::O_NONBLOCK;
+        assert_eq!(fs.fcntl(fd, libc::F_GETFL, 0), Ok(nonblock));
+
+        let high = fs.fcntl(fd, libc::F_DUPFD_CLOEXEC, FD_BASE + 10).unwrap();
+        assert_eq!(high, FD_BASE + 10);
+        assert_eq!(fs.fcntl(high, libc::F_GETFD, 0), Ok(libc::FD_CLOEXEC));
+        assert_eq!(fs.fcntl(fd, libc::F_DUPFD, -1), Err(libc::EINVAL));
+
+        // The open file is closed with its last descriptor.
+        let other = fs.open(&mut heap, libc::AT_FDCWD, b"/g", RW, 0).unwrap();
+        assert_eq!(fs.dup2(&mut heap, fd, other, false), Ok(other));
+        assert_eq!(fs.dup2(&mut heap, fd, 1, false), Err(libc::EBADF));
+        fs.unlink(&mut heap, libc::AT_FDCWD, b"/f").unwrap();
+        for fd in [fd, dup, high] {
+            fs.close(&mut heap, fd).unwrap();
+        }
+
+        let mut buf = [0u8; 3];
+        assert_eq!(fs.pread(other, &mut buf, 0), Ok(3));
+        assert_eq!(&buf, b"abc");
+        fs.close(&mut heap, other).unwrap();
+        assert_eq!(heap.0, 0);
+        assert_eq!(fs.close(&mut heap, other), Err(libc::EBADF));
+    }
+
+    #[test]
+    fn directories() {
+        let mut heap = Heap(0);
+        let mut fs = MemFs::new();
+
+        fs.mkdir(libc::AT_FDCWD, b"/tmp", 0o777).unwrap();
+        fs.mkdir(libc::AT_FDCWD, b"/tmp/a/", 0o755).unwrap();
+        assert_eq!(fs.mkdir(libc::AT_FDCWD, b"/tmp/a", 0), Err(libc::EEXIST));
+        assert_eq!(fs.mkdir(libc::AT_FDCWD, b"/", 0), Err(libc::EEXIST));
+        assert_eq!(fs.mkdir(libc::AT_FDCWD, b"/x/y", 0), Err(libc::ENOENT));
+
+        let dirfd = fs
+            .open(&mut heap, libc::AT_FDCWD, b"/tmp", libc::O_DIRECTORY, 0)
+            .unwrap();
+        let fd = fs.open(&mut heap, dirfd, b"a/../b", RW, 0o644).unwrap();
+        fs.close(&mut heap, fd).unwrap();
+        fs.close(&mut heap, dirfd).unwrap();
+
+        let stat = fs.stat(libc::AT_FDCWD, b"/tmp/./a/..//b").unwrap();
+        assert_eq!(stat.st_mode, libc::S_IFREG | 0o644);
+        let stat = fs.stat(libc::AT_FDCWD, b"/tmp/a").unwrap();
+        assert_eq!(stat.st_mode, libc::S_IFDIR | 0o755);
+
+        let mut entries = names(&mut fs, b"/tmp");
+        entries.sort();
+        assert_eq!(entries, [&b"."[..], b"..", b"a", b"b"]);
+
+        assert_eq!(
+            fs.rmdir(&mut heap, libc::AT_FDCWD, b"/tmp"),
+            Err(libc::ENOTEMPTY)
+        );
+        assert_eq!(
+            fs.rmdir(&mut heap, libc::AT_FDCWD, b"/tmp/b"),
+            Err(libc::ENOTDIR)
+        );
+        assert_eq!(
+            fs.unlink(&mut heap, libc::AT_FDCWD, b"/tmp/a"),
+            Err(libc::EISDIR)
+        );
+
+        fs.rmdir(&mut heap, libc::AT_FDCWD, b"/tmp/a").unwrap();
+        fs.unlink(&mut heap, libc::AT_FDCWD, b"/tmp/b").unwrap();
+        fs.rmdir(&mut heap, libc::AT_FDCWD, b"/tmp").unwrap();
+        assert_eq!(names(&mut fs, b"/"), [&b"."[..], b".."]);
+    }
+
+    #[test]
+    fn rename() {
+        let mut heap = Heap(0);
+        let mut fs = MemFs::new();
+
+        fs.mkdir(libc::AT_FDCWD, b"/a", 0o755).unwrap();
+        fs.mkdir(libc::AT_FDCWD, b"/a/b", 0o755).unwrap();
+        for path in [&b"/x"[..], b"/y"] {
+            let fd = fs.open(&mut heap, libc::AT_FDCWD, path, RW, 0o644).unwrap();
+            fs.write(&mut heap, fd, path).unwrap();
+            fs.close(&mut heap, fd).unwrap();
+        }
+
+        let at = libc::AT_FDCWD;
+        assert_eq!(
+            fs.rename(&mut heap, at, b"/a", at, b"/a/b/c"),
+            Err(libc::EINVAL)
+        );
+        assert_eq!(
+            fs.rename(&mut heap, at, b"/x", at, b"/a"),
+            Err(libc::EISDIR)
+        );
+        assert_eq!(
+            fs.rename(&mut heap, at, b"/a", at, b"/x"),
+            Err(libc::ENOTDIR)
+        );
+        assert_eq!(
+            fs.rename(&mut heap, at, b"/z", at, b"/x"),
+            Err(libc::ENOENT)
+        );
+
+        // Replacing a file releases its contents.
+        let allocations = heap.0;
+        fs.rename(&mut heap, at, b"/x", at, b"/a/b/y").unwrap();
+        fs.rename(&mut heap, at, b"/y", at, b"/a/b/y").unwrap();
+        assert_eq!(heap.0, allocations - 1);
+
+        let fd = fs
+            .open(&mut heap, at, b"/a/b/y", libc::O_RDONLY, 0)
+            .unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"/y");
+
+        // Unlinked files stay readable while they are open.
+        fs.unlink(&mut heap, at, b"/a/b/y").unwrap();
+        assert_eq!(fs.stat(at, b"/a/b/y").err(), Some(libc::ENOENT));
+        assert_eq!(fs.pread(fd, &mut buf, 0).unwrap(), 2);
+        fs.close(&mut heap, fd).unwrap();
+        assert_eq!(heap.0, 0);
+    }
+
+    #[test]
+    fn mounts() {
+        let mut heap = Heap(0);
+        let mut fs = MemFs::new();
+        let at = libc::AT_FDCWD;
+
+        fs.mount(b"/mnt/data", 0).unwrap();
+        fs.mount(b"/sealed", 1).unwrap();
+        assert_eq!(fs.mount(b"/mnt/data/x", 0), Err(libc::EBUSY));
+        assert_eq!(fs.mount(b"/mnt", 0), Err(libc::EBUSY));
+        assert_eq!(fs.mount(b"/", 0), Err(libc::EBUSY));
+        assert_eq!(names(&mut fs, b"/mnt"), [&b"."[..], b"..", b"data"]);
+
+        let mut buf = [0u8; 64];
+        let mut mounted = |fs: &MemFs, dirfd, path: &[u8]| {
+            fs.mounted(dirfd, path, &mut buf)
+                .unwrap()
+                .map(|m| buf[..m.len].to_vec())
+        };
+
+        assert_eq!(mounted(&fs, at, b"/mnt"), None);
+        assert_eq!(mounted(&fs, at, b"/tmp/x"), None);
+        assert_eq!(mounted(&fs, at, b"/mnt/data/../x"), None);
+        assert_eq!(mounted(&fs, at, b"mnt//data/"), Some(b"/mnt/data".to_vec()));
+        assert_eq!(
+            mounted(&fs, at, b"/mnt/data/a/../b/./c"),
+            Some(b"/mnt/data/b/c".to_vec())
+        );
+
+        let dirfd = fs
+            .open(&mut heap, at, b"/mnt", libc::O_DIRECTORY, 0)
+            .unwrap();
+        assert_eq!(
+            mounted(&fs, dirfd, b"data/x"),
+            Some(b"/mnt/data/x".to_vec())
+        );
+        assert_eq!(
+            mounted(&fs, dirfd, b"../mnt/data"),
+            Some(b"/mnt/data".to_vec())
+        );
+        fs.close(&mut heap, dirfd).unwrap();
+
+        let sealed = fs.mounted(at, b"/sealed/x", &mut buf).unwrap();
+        assert_eq!(sealed, Some(Mounted { len: 9, flags: 1 }));
+
+        assert_eq!(fs.rmdir(&mut heap, at, b"/mnt/data"), Err(libc::EBUSY));
+        assert_eq!(
+            fs.rename(&mut heap, at, b"/mnt", at, b"/m"),
+            Err(libc::EBUSY)
+        );
+    }
+}
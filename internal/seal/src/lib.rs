@@ -0,0 +1,510 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sealed files for the shims
+//!
+//! A sealed file is stored on the host, but its contents are encrypted and
+//! authenticated with a key which only the keep can derive. The file is
+//! split into blocks of `BLOCK_SIZE` bytes, each of which is sealed with
+//! AES-128-GCM under a fresh random nonce.
+//!
+//! The backing file starts with a sealed header holding a random file id
+//! and the size of the plaintext. The id and the block index are bound to
+//! each block as associated data, so blocks cannot be moved within a file or
+//! between files without being detected:
+//!
+//! ```text
+//! header (HEADER_SIZE bytes): nonce | sealed(magic | id | size) | tag
+//! block n (RECORD_SIZE bytes): nonce | sealed(data)              | tag
+//! ```
+//!
+//! Sealing does not protect against rolling back a whole file (or swapping
+//! two whole files) to contents that were once sealed with the same key.
+
+#![no_std]
+#![deny(clippy::all)]
+#![deny(missing_docs)]
+
+#[cfg(test)]
+extern crate std;
+
+use core::cmp::min;
+
+use aes_gcm::aead::{AeadInPlace, NewAead};
+use aes_gcm::{Aes128Gcm, Key, Nonce, Tag};
+use libc::c_int;
+
+/// The number of plaintext bytes in a block
+pub const BLOCK_SIZE: usize = 4096;
+
+/// The number of bytes reserved for the header of the backing file
+pub const HEADER_SIZE: u64 = 64;
+
+/// The number of bytes used for a block in the backing file
+pub const RECORD_SIZE: usize = NONCE_SIZE + BLOCK_SIZE + TAG_SIZE;
+
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+const ID_SIZE: usize = 16;
+const MAGIC: [u8; 8] = *b"ENARXSL1";
+const HEADER_INDEX: u64 = u64::MAX;
+
+type Header = [u8; MAGIC.len() + ID_SIZE + 8];
+
+/// The backing file of a sealed file
+pub trait Host {
+    /// Reads from the backing file, returning fewer bytes only at its end
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, c_int>;
+
+    /// Writes all of `buf` to the backing file
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), c_int>;
+
+    /// Truncates or extends the backing file
+    fn set_len(&mut self, len: u64) -> Result<(), c_int>;
+
+    /// Fills `buf` with random bytes
+    fn random(&mut self, buf: &mut [u8]) -> Result<(), c_int>;
+}
+
+/// The key used to seal files
+pub struct Sealer(Aes128Gcm);
+
+impl Sealer {
+    /// Creates a sealer from a 128-bit key
+    pub fn new(key: &[u8; 16]) -> Self {
+        Self(Aes128Gcm::new(&Key::from(*key)))
+    }
+
+    /// Encrypts `data` in place into a record
+    fn seal(&self, host: &mut impl Host, aad: &[u8], record: &mut [u8]) -> Result<(), c_int> {
+        let (nonce, rest) = record.split_at_mut(NONCE_SIZE);
+        let (data, tag) = rest.split_at_mut(rest.len() - TAG_SIZE);
+
+        let mut iv = [0u8; NONCE_SIZE];
+        host.random(&mut iv)?;
+        nonce.copy_from_slice(&iv);
+
+        let sealed = self
+            .0
+            .encrypt_in_place_detached(&Nonce::from(iv), aad, data)
+            .or(Err(libc::EIO))?;
+
+        tag.copy_from_slice(&sealed);
+        Ok(())
+    }
+
+    /// Decrypts a record in place, returning its data
+    fn unseal<'r>(&self, aad: &[u8], record: &'r mut [u8]) -> Result<&'r [u8], c_int> {
+        let (nonce, rest) = record.split_at_mut(NONCE_SIZE);
+        let (data, tag) = rest.split_at_mut(rest.len() - TAG_SIZE);
+
+        let mut iv = [0u8; NONCE_SIZE];
+        let mut mac = [0u8; TAG_SIZE];
+        iv.copy_from_slice(nonce);
+        mac.copy_from_slice(tag);
+
+        self.0
+            .decrypt_in_place_detached(&Nonce::from(iv), aad, data, &Tag::from(mac))
+            .or(Err(libc::EIO))?;
+
+        Ok(data)
+    }
+}
+
+/// The state of an open sealed file
+#[derive(Copy, Clone)]
+pub struct SealedFile {
+    id: [u8; ID_SIZE],
+    size: u64,
+}
+
+impl SealedFile {
+    /// Opens a sealed file, verifying its header
+    ///
+    /// An empty backing file is a new sealed file. If `writable` is set, its
+    /// header is written immediately.
+    pub fn open(sealer: &Sealer, host: &mut impl Host, writable: bool) -> Result<Self, c_int> {
+        let mut record = [0u8; NONCE_SIZE + core::mem::size_of::<Header>() + TAG_SIZE];
+
+        match host.read_at(&mut record, 0)? {
+            0 => {
+                let mut file = Self {
+                    id: [0; ID_SIZE],
+                    size: 0,
+                };
+
+                if writable {
+                    host.random(&mut file.id)?;
+                    file.store_header(sealer, host)?;
+                }
+
+                Ok(file)
+            }
+
+            n if n < record.len() => Err(libc::EIO),
+
+            _ => {
+                let header = sealer.unseal(&HEADER_INDEX.to_le_bytes(), &mut record)?;
+                let (magic, rest) = header.split_at(MAGIC.len());
+                let (id, size) = rest.split_at(ID_SIZE);
+                if magic != MAGIC {
+                    return Err(libc::EIO);
+                }
+
+                let mut file = Self {
+                    id: [0; ID_SIZE],
+                    size: 0,
+                };
+
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(size);
+                file.id.copy_from_slice(id);
+                file.size = u64::from_le_bytes(bytes);
+                Ok(file)
+            }
+        }
+    }
+
+    /// The size of the plaintext
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Reads from the file at an offset
+    pub fn read_at(
+        &self,
+        sealer: &Sealer,
+        host: &mut impl Host,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> Result<usize, c_int> {
+        if offset >= self.size {
+            return Ok(0);
+        }
+
+        let end = min(self.size, offset.saturating_add(buf.len() as u64));
+        let mut record = [0u8; RECORD_SIZE];
+        let mut done = 0;
+        let mut pos = offset;
+
+        while pos < end {
+            let index = pos / BLOCK_SIZE as u64;
+            let start = (pos % BLOCK_SIZE as u64) as usize;
+            let len = min(BLOCK_SIZE - start, (end - pos) as usize);
+
+            let block = self.load(sealer, host, index, &mut record)?;
+            buf[done..done + len].copy_from_slice(&block[start..start + len]);
+
+            done += len;
+            pos += len as u64;
+        }
+
+        Ok(done)
+    }
+
+    /// Writes to the file at an offset, extending it if needed
+    pub fn write_at(
+        &mut self,
+        sealer: &Sealer,
+        host: &mut impl Host,
+        buf: &[u8],
+        offset: u64,
+    ) -> Result<usize, c_int> {
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .filter(|end| Self::record(Self::blocks(*end)).is_some())
+            .ok_or(libc::EFBIG)?;
+
+        // Seal the gap first, so that every block below the size exists.
+        if offset > self.size {
+            self.set_len(sealer, host, offset)?;
+        }
+
+        let blocks = Self::blocks(self.size);
+        let mut record = [0u8; RECORD_SIZE];
+        let mut done = 0;
+        let mut pos = offset;
+
+        while pos < end {
+            let index = pos / BLOCK_SIZE as u64;
+            let start = (pos % BLOCK_SIZE as u64) as usize;
+            let len = min(BLOCK_SIZE - start, (end - pos) as usize);
+
+            // Partially written blocks are read back first.
+            if index < blocks && len < BLOCK_SIZE {
+                self.load(sealer, host, index, &mut record)?;
+            } else {
+                record.iter_mut().for_each(|b| *b = 0);
+            }
+
+            record[NONCE_SIZE + start..][..len].copy_from_slice(&buf[done..done + len]);
+            self.store(sealer, host, index, &mut record)?;
+
+            done += len;
+            pos += len as u64;
+        }
+
+        if end > self.size {
+            self.size = end;
+            self.store_header(sealer, host)?;
+        }
+
+        Ok(done)
+    }
+
+    /// Truncates or extends the file
+    ///
+    /// Extending a file seals zeroed blocks, since missing blocks would be
+    /// indistinguishable from blocks removed by the host.
+    pub fn set_len(
+        &mut self,
+        sealer: &Sealer,
+        host: &mut impl Host,
+        len: u64,
+    ) -> Result<(), c_int> {
+        let blocks = Self::blocks(len);
+        let backing = Self::record(blocks).ok_or(libc::EFBIG)?;
+        let mut record = [0u8; RECORD_SIZE];
+
+        if len > self.size {
+            // The tail of the last block is already zeroed.
+            for index in Self::blocks(self.size)..blocks {
+                record.iter_mut().for_each(|b| *b = 0);
+                self.store(sealer, host, index, &mut record)?;
+            }
+        } else if len < self.size {
+            let start = (len % BLOCK_SIZE as u64) as usize;
+            if start > 0 {
+                let index = len / BLOCK_SIZE as u64;
+                let block = self.load(sealer, host, index, &mut record)?.len();
+                record[NONCE_SIZE + start..NONCE_SIZE + block]
+                    .iter_mut()
+                    .for_each(|b| *b = 0);
+                self.store(sealer, host, index, &mut record)?;
+            }
+
+            host.set_len(backing)?;
+        }
+
+        self.size = len;
+        self.store_header(sealer, host)
+    }
+
+    /// The number of blocks needed for `size` bytes
+    fn blocks(size: u64) -> u64 {
+        match size % BLOCK_SIZE as u64 {
+            0 => size / BLOCK_SIZE as u64,
+            _ => size / BLOCK_SIZE as u64 + 1,
+        }
+    }
+
+    /// The offset of a block in the backing file
+    fn record(index: u64) -> Option<u64> {
+        index
+            .checked_mul(RECORD_SIZE as u64)?
+            .checked_add(HEADER_SIZE)
+            .filter(|offset| *offset <= i64::MAX as u64)
+    }
+
+    /// Associated data binding a record to this file and position
+    fn aad(&self, index: u64) -> [u8; ID_SIZE + 8] {
+        let mut aad = [0u8; ID_SIZE + 8];
+        aad[..ID_SIZE].copy_from_slice(&self.id);
+        aad[ID_SIZE..].copy_from_slice(&index.to_le_bytes());
+        aad
+    }
+
+    /// Reads and unseals a block into `record`
+    fn load<'r>(
+        &self,
+        sealer: &Sealer,
+        host: &mut impl Host,
+        index: u64,
+        record: &'r mut [u8; RECORD_SIZE],
+    ) -> Result<&'r [u8], c_int> {
+        let offset = Self::record(index).ok_or(libc::EFBIG)?;
+        if host.read_at(record, offset)? != RECORD_SIZE {
+            return Err(libc::EIO);
+        }
+
+        sealer.unseal(&self.aad(index), record)
+    }
+
+    /// Seals the block held in `record` and writes it
+    fn store(
+        &self,
+        sealer: &Sealer,
+        host: &mut impl Host,
+        index: u64,
+        record: &mut [u8; RECORD_SIZE],
+    ) -> Result<(), c_int> {
+        let offset = Self::record(index).ok_or(libc::EFBIG)?;
+        sealer.seal(host, &self.aad(index), record)?;
+        host.write_at(record, offset)
+    }
+
+    /// Seals and writes the header
+    fn store_header(&self, sealer: &Sealer, host: &mut impl Host) -> Result<(), c_int> {
+        let mut record = [0u8; NONCE_SIZE + core::mem::size_of::<Header>() + TAG_SIZE];
+
+        let header = &mut record[NONCE_SIZE..][..core::mem::size_of::<Header>()];
+        header[..MAGIC.len()].copy_from_slice(&MAGIC);
+        header[MAGIC.len()..][..ID_SIZE].copy_from_slice(&self.id);
+        header[MAGIC.len() + ID_SIZE..].copy_from_slice(&self.size.to_le_bytes());
+
+        sealer.seal(host, &HEADER_INDEX.to_le_bytes(), &mut record)?;
+        host.write_at(&record, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec;
+
+    #[derive(Default)]
+    struct Backing {
+        data: Vec<u8>,
+        counter: u64,
+    }
+
+    impl Host for Backing {
+        fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, c_int> {
+            let start = min(offset as usize, self.data.len());
+            let len = min(buf.len(), self.data.len() - start);
+            buf[..len].copy_from_slice(&self.data[start..start + len]);
+            Ok(len)
+        }
+
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), c_int> {
+            let end = offset as usize + buf.len();
+            if end > self.data.len() {
+                self.data.resize(end, 0);
+            }
+
+            self.data[offset as usize..end].copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn set_len(&mut self, len: u64) -> Result<(), c_int> {
+            self.data.resize(len as usize, 0);
+            Ok(())
+        }
+
+        fn random(&mut self, buf: &mut [u8]) -> Result<(), c_int> {
+            for chunk in buf.chunks_mut(8) {
+                self.counter += 1;
+                chunk.copy_from_slice(&self.counter.to_le_bytes()[..chunk.len()]);
+            }
+
+            Ok(())
+        }
+    }
+
+    const KEY: [u8; 16] = [7; 16];
+
+    fn read_all(sealer: &Sealer, backing: &mut Backing) -> Result<Vec<u8>, c_int> {
+        let file = SealedFile::open(sealer, backing, false)?;
+        let mut buf = std::vec![0u8; file.size() as usize];
+        assert_eq!(file.read_at(sealer, backing, &mut buf, 0)?, buf.len());
+        Ok(buf)
+    }
+
+    #[test]
+    fn round_trip() {
+        let sealer = Sealer::new(&KEY);
+        let mut backing = Backing::default();
+
+        let mut file = SealedFile::open(&sealer, &mut backing, true).unwrap();
+        let data: Vec<u8> = (0..10000u32).map(|i| i as u8).collect();
+        assert_eq!(file.write_at(&sealer, &mut backing, &data, 0), Ok(10000));
+        assert_eq!(file.write_at(&sealer, &mut backing, b"hole", 12000), Ok(4));
+        assert_eq!(file.size(), 12004);
+
+        // Nothing is stored in the clear.
+        assert!(!backing.data.windows(4).any(|w| w == b"hole"));
+
+        let contents = read_all(&sealer, &mut backing).unwrap();
+        assert_eq!(&contents[..10000], &data[..]);
+        assert!(contents[10000..12000].iter().all(|b| *b == 0));
+        assert_eq!(&contents[12000..], b"hole");
+
+        // Partial reads across block boundaries
+        let mut buf = [0u8; 100];
+        assert_eq!(file.read_at(&sealer, &mut backing, &mut buf, 4050), Ok(100));
+        assert_eq!(&buf[..], &data[4050..4150]);
+        assert_eq!(file.read_at(&sealer, &mut backing, &mut buf, 11950), Ok(54));
+        assert_eq!(file.read_at(&sealer, &mut backing, &mut buf, 20000), Ok(0));
+    }
+
+    #[test]
+    fn set_len() {
+        let sealer = Sealer::new(&KEY);
+        let mut backing = Backing::default();
+
+        let mut file = SealedFile::open(&sealer, &mut backing, true).unwrap();
+        file.write_at(&sealer, &mut backing, &[0xff; 5000], 0)
+            .unwrap();
+        file.set_len(&sealer, &mut backing, 10).unwrap();
+        assert_eq!(backing.data.len() as u64, HEADER_SIZE + RECORD_SIZE as u64);
+
+        file.set_len(&sealer, &mut backing, 9000).unwrap();
+        let contents = read_all(&sealer, &mut backing).unwrap();
+        assert_eq!(contents.len(), 9000);
+        assert_eq!(&contents[..10], &[0xff; 10]);
+        assert!(contents[10..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn tampering() {
+        let sealer = Sealer::new(&KEY);
+        let mut backing = Backing::default();
+
+        let mut file = SealedFile::open(&sealer, &mut backing, true).unwrap();
+        file.write_at(&sealer, &mut backing, &[1; 3 * BLOCK_SIZE], 0)
+            .unwrap();
+        let pristine = backing.data.clone();
+
+        // A different key
+        assert_eq!(
+            read_all(&Sealer::new(&[8; 16]), &mut backing).err(),
+            Some(libc::EIO)
+        );
+
+        // A flipped bit
+        backing.data[HEADER_SIZE as usize + 100] ^= 1;
+        assert_eq!(read_all(&sealer, &mut backing).err(), Some(libc::EIO));
+
+        // Swapped blocks
+        let mut swapped = pristine.clone();
+        let (first, second) = (HEADER_SIZE as usize, HEADER_SIZE as usize + RECORD_SIZE);
+        let block = swapped[first..second].to_vec();
+        swapped.copy_within(second..second + RECORD_SIZE, first);
+        swapped[second..second + RECORD_SIZE].copy_from_slice(&block);
+        backing.data = swapped;
+        assert_eq!(read_all(&sealer, &mut backing).err(), Some(libc::EIO));
+
+        // A block from another file
+        let mut other = Backing {
+            counter: 1000,
+            ..Default::default()
+        };
+        let mut file = SealedFile::open(&sealer, &mut other, true).unwrap();
+        file.write_at(&sealer, &mut other, &[1; 3 * BLOCK_SIZE], 0)
+            .unwrap();
+        backing.data = pristine.clone();
+        backing.data[first..second].copy_from_slice(&other.data[first..second]);
+        assert_eq!(read_all(&sealer, &mut backing).err(), Some(libc::EIO));
+
+        // A truncated backing file
+        backing.data = pristine.clone();
+        backing.data.truncate(pristine.len() - 1);
+        assert_eq!(read_all(&sealer, &mut backing).err(), Some(libc::EIO));
+
+        backing.data = pristine;
+        assert_eq!(
+            read_all(&sealer, &mut backing).unwrap(),
+            [1; 3 * BLOCK_SIZE]
+        );
+    }
+}
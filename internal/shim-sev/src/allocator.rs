@@ -495,6 +495,11 @@ impl EnarxAllocator {
     pub fn free(&self) -> usize {
         self.allocator.free()
     }
+
+    /// returns the amount of memory, which grows as the keep balloons
+    pub fn size(&self) -> usize {
+        self.allocator.size()
+    }
 }
 
 unsafe impl paging::FrameAllocator<Size4KiB> for EnarxAllocator {
@@ -73,4 +73,18 @@ impl SevSecret {
         self.try_len()
             .map(|len| &unsafe { &*self.data.as_ptr() }[..len])
     }
+
+    /// Get the content of the secret, without its CBOR header
+    pub fn try_content(&self) -> Option<&[u8]> {
+        let secret = self.try_as_slice()?;
+        let header = match secret.first()? & 0b00011111 {
+            0..=23 => 1,
+            24 => 2,
+            25 => 3,
+            26 => 5,
+            _ => 9,
+        };
+
+        secret.get(header..)
+    }
 }
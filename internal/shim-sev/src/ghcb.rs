@@ -0,0 +1,234 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The Guest-Hypervisor Communication Block (GHCB) of SEV-ES
+//!
+//! The register state of a SEV-ES guest is encrypted, so the host can't
+//! emulate `cpuid`, `rdmsr`, `wrmsr` or port IO by reading and writing the
+//! registers of the vCPU. These instructions raise a #VC exception instead
+//! (see [`interrupts`](crate::interrupts)), and the shim copies the registers
+//! the host needs to the GHCB, an unencrypted page, before it exits with
+//! `VMGEXIT`. The host places its results in the GHCB as well.
+//!
+//! Until the GHCB is registered, requests are exchanged in the GHCB MSR itself
+//! (the GHCB MSR protocol).
+//!
+//! see the "SEV-ES Guest-Hypervisor Communication Block Standardization"
+//! specification (AMD #56421)
+
+use crate::addr::SHIM_VIRT_OFFSET;
+use crate::asm::_early_debug_panic;
+use crate::_ENARX_GHCB;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::registers::xcontrol::XCr0;
+use x86_64::VirtAddr;
+
+/// The GHCB MSR
+const MSR_GHCB: u32 = 0xC001_0130;
+
+/// GHCB MSR protocol: the request for the supported protocol versions
+const MSR_SEV_INFO_REQ: u64 = 0x002;
+
+/// GHCB MSR protocol: the response with the supported protocol versions
+const MSR_SEV_INFO_RESP: u64 = 0x001;
+
+/// The mask of the request and response codes of the GHCB MSR protocol
+const MSR_INFO_MASK: u64 = 0xFFF;
+
+/// The version of the GHCB protocol spoken by the shim
+const PROTOCOL_VERSION: u16 = 1;
+
+/// The exit code of `cpuid`
+pub const EXIT_CPUID: u64 = 0x72;
+
+/// The exit code of port IO
+pub const EXIT_IOIO: u64 = 0x7B;
+
+/// The exit code of `rdmsr` and `wrmsr`
+pub const EXIT_MSR: u64 = 0x7C;
+
+/// IOIO exit information: a 16-bit operand
+const IOIO_SZ16: u64 = 1 << 5;
+
+/// IOIO exit information: a 64-bit address
+const IOIO_A64: u64 = 1 << 9;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns true, if the shim runs as a SEV-ES guest
+#[inline]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The layout of the GHCB (version 1)
+#[allow(dead_code)]
+#[repr(C, align(4096))]
+pub struct Ghcb {
+    reserved0: [u8; 0x1F8],
+    rax: u64,
+    reserved1: [u8; 0x108],
+    rcx: u64,
+    rdx: u64,
+    rbx: u64,
+    reserved2: [u8; 0x70],
+    sw_exit_code: u64,
+    sw_exit_info_1: u64,
+    sw_exit_info_2: u64,
+    sw_scratch: u64,
+    reserved3: [u8; 0x38],
+    xcr0: u64,
+    valid_bitmap: [u8; 16],
+    reserved4: [u8; 0xBFA],
+    protocol_version: u16,
+    ghcb_usage: u32,
+}
+
+impl Ghcb {
+    /// Get the GHCB
+    ///
+    /// # Safety
+    ///
+    /// The GHCB must have been registered with [`init`] and must not be in
+    /// use already, which holds in the #VC handler, because it doesn't nest.
+    pub unsafe fn get() -> &'static mut Self {
+        &mut *(ghcb_addr().as_u64() as *mut Self)
+    }
+
+    /// Emulate `cpuid` for `leaf` and `subleaf`
+    pub fn cpuid(&mut self, leaf: u32, subleaf: u32) -> Result<[u32; 4], ()> {
+        self.clear();
+        self.set(Field::Rax, leaf as _);
+        self.set(Field::Rcx, subleaf as _);
+        self.set(Field::Xcr0, XCr0::read_raw());
+        self.exit(EXIT_CPUID, 0, 0)?;
+
+        Ok([self.rax as _, self.rbx as _, self.rcx as _, self.rdx as _])
+    }
+
+    /// Emulate `out dx, ax`
+    #[allow(clippy::integer_arithmetic)]
+    pub fn outw(&mut self, port: u16, value: u16) -> Result<(), ()> {
+        self.clear();
+        self.set(Field::Rax, value as _);
+        self.exit(EXIT_IOIO, (port as u64) << 16 | IOIO_SZ16 | IOIO_A64, 0)
+    }
+
+    /// Emulate `rdmsr`
+    #[allow(clippy::integer_arithmetic)]
+    pub fn rdmsr(&mut self, msr: u32) -> Result<u64, ()> {
+        self.clear();
+        self.set(Field::Rcx, msr as _);
+        self.exit(EXIT_MSR, 0, 0)?;
+
+        Ok(self.rdx << 32 | self.rax & 0xFFFF_FFFF)
+    }
+
+    /// Emulate `wrmsr`
+    #[allow(clippy::integer_arithmetic)]
+    pub fn wrmsr(&mut self, msr: u32, value: u64) -> Result<(), ()> {
+        self.clear();
+        self.set(Field::Rcx, msr as _);
+        self.set(Field::Rax, value & 0xFFFF_FFFF);
+        self.set(Field::Rdx, value >> 32);
+        self.exit(EXIT_MSR, 1, 0)
+    }
+
+    fn clear(&mut self) {
+        self.valid_bitmap = [0; 16];
+    }
+
+    /// Set a field and mark it as valid for the host
+    #[allow(clippy::integer_arithmetic)]
+    fn set(&mut self, field: Field, value: u64) {
+        let ptr = match field {
+            Field::Rax => &mut self.rax,
+            Field::Rcx => &mut self.rcx,
+            Field::Rdx => &mut self.rdx,
+            Field::ExitCode => &mut self.sw_exit_code,
+            Field::ExitInfo1 => &mut self.sw_exit_info_1,
+            Field::ExitInfo2 => &mut self.sw_exit_info_2,
+            Field::Xcr0 => &mut self.xcr0,
+        } as *mut u64;
+
+        unsafe { ptr.write_volatile(value) };
+
+        // The bitmap has a bit for every quadword of the GHCB.
+        let qword = (ptr as usize - self as *mut Self as usize) / size_of::<u64>();
+        self.valid_bitmap[qword / 8] |= 1 << (qword % 8);
+    }
+
+    /// Exit to the host with the fields set so far
+    fn exit(&mut self, code: u64, info1: u64, info2: u64) -> Result<(), ()> {
+        self.set(Field::ExitCode, code);
+        self.set(Field::ExitInfo1, info1);
+        self.set(Field::ExitInfo2, info2);
+        self.protocol_version = PROTOCOL_VERSION;
+        self.ghcb_usage = 0;
+
+        unsafe {
+            Msr::new(MSR_GHCB).write(ghcb_addr().as_u64());
+            vmgexit();
+        }
+
+        // The lower half of the first exit information reports errors.
+        match unsafe { (&self.sw_exit_info_1 as *const u64).read_volatile() } as u32 {
+            0 => Ok(()),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The fields of the GHCB used by the shim
+enum Field {
+    Rax,
+    Rcx,
+    Rdx,
+    ExitCode,
+    ExitInfo1,
+    ExitInfo2,
+    Xcr0,
+}
+
+/// The GHCB is accessed through the unencrypted identity mapping, so its
+/// virtual address is its guest physical address.
+fn ghcb_addr() -> VirtAddr {
+    VirtAddr::from_ptr(unsafe { &_ENARX_GHCB }) - SHIM_VIRT_OFFSET
+}
+
+/// Exit to the host (`rep vmmcall`)
+#[inline(always)]
+unsafe fn vmgexit() {
+    asm!(
+        ".byte 0xf3, 0x0f, 0x01, 0xd9",
+        options(nostack, preserves_flags)
+    );
+}
+
+/// Negotiate the protocol version with the host and register the GHCB
+///
+/// # Safety
+///
+/// The GHCB has to be mapped unencrypted already and the caller has to
+/// ensure it is only called once and in a single-threaded context.
+#[allow(clippy::integer_arithmetic)]
+pub unsafe fn init() {
+    let mut msr = Msr::new(MSR_GHCB);
+    msr.write(MSR_SEV_INFO_REQ);
+    vmgexit();
+    let info = msr.read();
+
+    let max = (info >> 48) as u16;
+    let min = (info >> 32) as u16;
+    if info & MSR_INFO_MASK != MSR_SEV_INFO_RESP || !(min..=max).contains(&PROTOCOL_VERSION) {
+        _early_debug_panic(0xAFFE_AFFE_AFFE_0001, info);
+    }
+
+    // The page was loaded encrypted, so it reads as garbage now.
+    let ghcb = ghcb_addr().as_mut_ptr::<Ghcb>();
+    ghcb.write_bytes(0, 1);
+
+    msr.write(ghcb_addr().as_u64());
+    ENABLED.store(true, Ordering::Relaxed);
+}
@@ -8,6 +8,7 @@ use crate::spin::RwLocked;
 use array_const_fn_init::array_const_fn_init;
 use core::convert::TryFrom;
 use core::mem::size_of;
+use enarx_syscall::SYS_ENARX_ENVIRON;
 use primordial::{Address, Register};
 use sallyport::syscall::enarx::MemInfo;
 use sallyport::syscall::{SYS_ENARX_BALLOON_MEMORY, SYS_ENARX_MEM_INFO};
@@ -185,6 +186,52 @@ impl HostCall {
         Ok(mem_info)
     }
 
+    /// Get the time of the realtime clock of the host
+    ///
+    /// The host may lie about it.
+    pub fn clock_realtime(&mut self) -> Result<libc::timespec, libc::c_int> {
+        let zero = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+
+        let cursor = self.block.as_mut().unwrap().cursor();
+        let (_, buf) = cursor.copy_from_slice(&[zero]).or(Err(libc::EMSGSIZE))?;
+
+        let buf_address = Address::from(buf.as_ptr());
+        let phys_unencrypted = ShimPhysUnencryptedAddr::try_from(buf_address).unwrap();
+        let host_virt: HostVirtAddr<_> = phys_unencrypted.into();
+
+        self.block.as_mut().unwrap().msg.req =
+            request!(libc::SYS_clock_gettime => libc::CLOCK_REALTIME, host_virt);
+
+        let _result = unsafe { self.hostcall() }?;
+
+        let c = self.as_mut_block().cursor();
+        let (_, time) = unsafe { c.read::<libc::timespec>() }.or(Err(libc::EMSGSIZE))?;
+
+        Ok(time)
+    }
+
+    /// Get the arguments and environment of the payload
+    ///
+    /// Returns the length of the list copied to `buf` and the number of
+    /// arguments in it. The list itself is not validated.
+    pub fn environ(&mut self, buf: &mut [u8]) -> Result<(usize, usize), libc::c_int> {
+        self.block.as_mut().unwrap().msg.req = request!(SYS_ENARX_ENVIRON);
+
+        let ret = unsafe { self.hostcall() }?;
+        let (len, argc) = (usize::from(ret[0]), usize::from(ret[1]));
+        if len > buf.len() {
+            return Err(libc::EMSGSIZE);
+        }
+
+        let c = self.as_mut_block().cursor();
+        unsafe { c.copy_into_raw_parts(len, buf.as_mut_ptr(), len) }.or(Err(libc::EMSGSIZE))?;
+
+        Ok((len, argc))
+    }
+
     /// Exit the shim with a `status` code
     ///
     /// # Panics
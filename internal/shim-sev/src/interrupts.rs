@@ -0,0 +1,168 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Interrupt Descriptor Table init
+//!
+//! Only the #VC exception of SEV-ES guests is handled, every other exception
+//! still causes a triple fault.
+
+use crate::asm::_enarx_asm_triple_fault;
+use crate::ghcb::{self, Ghcb};
+use spinning::Lazy;
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::VirtAddr;
+
+/// The IST stack of the #VC handler
+///
+/// #VC exceptions are raised in the shim as well as in the payload, so the
+/// handler must not run on the stack of the interrupted code.
+const VC_STACK_INDEX: u16 = 0;
+
+/// The global IDT
+pub static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
+    let mut idt = InterruptDescriptorTable::new();
+
+    unsafe {
+        idt.vmm_communication_exception
+            .set_handler_addr(VirtAddr::new(_vc_enter as usize as u64))
+            .set_stack_index(VC_STACK_INDEX);
+    }
+
+    idt
+});
+
+/// The registers saved by `_vc_enter`, followed by the exception stack frame
+#[allow(dead_code)]
+#[repr(C)]
+struct Frame {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rbx: u64,
+    rax: u64,
+    code: u64,
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+/// #VC exception entry
+///
+/// # Safety
+///
+/// This function is not be called from rust.
+#[naked]
+unsafe extern "sysv64" fn _vc_enter() -> ! {
+    asm!("
+    push   rax
+    push   rbx
+    push   rcx
+    push   rdx
+    push   rsi
+    push   rdi
+    push   rbp
+    push   r8
+    push   r9
+    push   r10
+    push   r11
+    push   r12
+    push   r13
+    push   r14
+    push   r15
+
+    mov    rdi,    rsp          # arg1: the saved registers
+    mov    rbp,    rsp
+    and    rsp,    -16          # align the stack for the call
+    cld
+    call   {HANDLER}
+    mov    rsp,    rbp
+
+    pop    r15
+    pop    r14
+    pop    r13
+    pop    r12
+    pop    r11
+    pop    r10
+    pop    r9
+    pop    r8
+    pop    rbp
+    pop    rdi
+    pop    rsi
+    pop    rdx
+    pop    rcx
+    pop    rbx
+    pop    rax
+
+    add    rsp,    8            # skip the error code
+    iretq
+    ",
+    HANDLER = sym vc_handler,
+    options(noreturn)
+    )
+}
+
+/// Emulate the instruction which raised the #VC exception
+///
+/// The error code is the exit code of the instruction. Only the instructions
+/// of the shim and the payload which the host has to emulate are supported:
+/// `cpuid`, `rdmsr`, `wrmsr` and the `out dx, ax` of a hostcall.
+#[allow(clippy::integer_arithmetic)]
+extern "sysv64" fn vc_handler(frame: &mut Frame) {
+    let ghcb = unsafe { Ghcb::get() };
+    let insn = unsafe { (frame.rip as *const [u8; 2]).read_unaligned() };
+
+    let handled = match (frame.code, insn) {
+        (ghcb::EXIT_CPUID, [0x0F, 0xA2]) => {
+            ghcb.cpuid(frame.rax as _, frame.rcx as _)
+                .map(|[eax, ebx, ecx, edx]| {
+                    frame.rax = eax as _;
+                    frame.rbx = ebx as _;
+                    frame.rcx = ecx as _;
+                    frame.rdx = edx as _;
+                })
+        }
+
+        (ghcb::EXIT_MSR, [0x0F, 0x32]) => ghcb.rdmsr(frame.rcx as _).map(|value| {
+            frame.rax = value & 0xFFFF_FFFF;
+            frame.rdx = value >> 32;
+        }),
+
+        (ghcb::EXIT_MSR, [0x0F, 0x30]) => {
+            let value = frame.rdx << 32 | frame.rax & 0xFFFF_FFFF;
+            ghcb.wrmsr(frame.rcx as _, value)
+        }
+
+        (ghcb::EXIT_IOIO, [0x66, 0xEF]) => ghcb.outw(frame.rdx as _, frame.rax as _),
+
+        _ => Err(()),
+    };
+
+    match handled {
+        Ok(()) => frame.rip += insn.len() as u64,
+        Err(()) => unsafe { _enarx_asm_triple_fault() },
+    }
+}
+
+/// Initialize the IDT
+///
+/// # Safety
+///
+/// `unsafe` because the caller has to ensure it is only called once, in a
+/// single-threaded context and after the TSS with the IST stacks is loaded.
+pub unsafe fn init() {
+    #[cfg(debug_assertions)]
+    crate::eprintln!("init_idt");
+
+    IDT.load();
+}
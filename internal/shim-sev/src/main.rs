@@ -23,14 +23,16 @@ pub mod allocator;
 pub mod asm;
 pub mod attestation;
 pub mod gdt;
+pub mod ghcb;
 pub mod hostcall;
 pub mod hostmap;
+pub mod interrupts;
 pub mod no_std;
 pub mod pagetables;
 pub mod paging;
 pub mod payload;
-pub mod random;
 pub mod shim_stack;
+pub mod smp;
 pub mod spin;
 mod start;
 pub mod syscall;
@@ -51,8 +53,13 @@ use spinning::RwLock;
 use x86_64::structures::paging::Translate;
 use x86_64::VirtAddr;
 
+/// The sallyport ABI of the shim, which must match `SallyportAbi::LOADER`
+const SALLYPORT_ABI: [u32; 2] = [1, size_of::<Block>() as u32];
+
 noted! {
     static NOTE_ENARX_SALLYPORT<"sallyport", 0>: [u8; REQUIRES.len()] = REQUIRES;
+    static NOTE_ENARX_SALLYPORT_ABI<"enarx", 0x73707900>: [u32; 2] = SALLYPORT_ABI;
+    static NOTE_ENARX_STACK<"enarx", 0x70617901>: u64 = payload::PAYLOAD_STACK_SIZE;
 }
 
 static C_BIT_MASK: AtomicU64 = AtomicU64::new(0);
@@ -68,6 +75,8 @@ extern "C" {
     /// Extern
     pub static _ENARX_SALLYPORT_END: Page4KiB;
     /// Extern
+    pub static _ENARX_GHCB: Page4KiB;
+    /// Extern
     pub static _ENARX_MEM_START: Page4KiB;
     /// Extern
     pub static _ENARX_SHIM_START: Page4KiB;
@@ -108,7 +117,7 @@ pub unsafe fn switch_shim_stack(ip: extern "C" fn() -> !, sp: u64) -> ! {
 ///
 /// # Safety
 /// Do not call from Rust.
-pub unsafe extern "sysv64" fn _start_main(c_bit_mask: u64) -> ! {
+pub unsafe extern "sysv64" fn _start_main(c_bit_mask: u64, sev_es: bool) -> ! {
     C_BIT_MASK.store(c_bit_mask, Ordering::Relaxed);
 
     // make a local copy of boot_info, before the shared page gets overwritten
@@ -118,8 +127,12 @@ pub unsafe extern "sysv64" fn _start_main(c_bit_mask: u64) -> ! {
 
     switch_sallyport_to_unencrypted(c_bit_mask);
 
-    // Everything setup, so print works
-    enable_printing();
+    if sev_es {
+        ghcb::init();
+    } else {
+        // Everything setup, so print works
+        enable_printing();
+    }
 
     // Switch the stack to a guarded stack
     switch_shim_stack(shim_main, gdt::INITIAL_STACK.pointer.as_u64())
@@ -128,6 +141,16 @@ pub unsafe extern "sysv64" fn _start_main(c_bit_mask: u64) -> ! {
 /// The entry point for the shim
 extern "C" fn shim_main() -> ! {
     unsafe { gdt::init() };
+    unsafe { interrupts::init() };
+
+    // The port IO of printing raises #VC exceptions on SEV-ES, which can
+    // only be handled now.
+    if ghcb::is_enabled() {
+        enable_printing();
+    }
+
+    unsafe { smp::release() };
+
     payload::execute_payload()
 }
 
@@ -10,7 +10,8 @@ use crate::addr::{BYTES_1_GIB, BYTES_2_MIB};
 use crate::asm::_early_debug_panic;
 use crate::paging::EncPhysOffset;
 use crate::{
-    paging, _ENARX_CODE_END, _ENARX_SALLYPORT_END, _ENARX_SALLYPORT_START, _ENARX_SHIM_START,
+    paging, _ENARX_CODE_END, _ENARX_GHCB, _ENARX_SALLYPORT_END, _ENARX_SALLYPORT_START,
+    _ENARX_SHIM_START,
 };
 use array_const_fn_init::array_const_fn_init;
 use x86_64::instructions::tlb::flush;
@@ -111,12 +112,16 @@ pub static mut PDT_IDENT: AlignedPageTable =
 pub static mut PT_IDENT: AlignedPageTable =
     AlignedPageTable(array_const_fn_init![gen_4k_pt_entries_ffe0_0000; 512]);
 
-/// Map the sallyport Block pages to unencrypted memory.
+/// Map the sallyport Block pages and the GHCB to unencrypted memory.
 pub fn switch_sallyport_to_unencrypted(c_bit_mask: u64) {
     // Unmap some pages, because a TEE is not supposed to map the same physical memory
     // encrypted and unencrypted.
+    //
+    // The reset page stays mapped, because the application processors switch
+    // to these page tables while they execute it.
 
-    let start = VirtAddr::from_ptr(unsafe { &_ENARX_SHIM_START }) - SHIM_VIRT_OFFSET;
+    let start = VirtAddr::from_ptr(unsafe { &_ENARX_SHIM_START }) - SHIM_VIRT_OFFSET
+        + Page::<Size4KiB>::SIZE;
     let end = VirtAddr::from_ptr(unsafe { &_ENARX_CODE_END }) - SHIM_VIRT_OFFSET;
 
     if unmap_address_range(start, end).is_err() {
@@ -133,6 +138,15 @@ pub fn switch_sallyport_to_unencrypted(c_bit_mask: u64) {
             _early_debug_panic(0xAFFE_AFFE_AFFE_CAFE, 0);
         }
     }
+
+    let start = VirtAddr::from_ptr(unsafe { &_ENARX_GHCB }) - SHIM_VIRT_OFFSET;
+    let end = start + Page::<Size4KiB>::SIZE;
+
+    if clear_c_bit_address_range(start, end, c_bit_mask).is_err() {
+        unsafe {
+            _early_debug_panic(0xAFFE_AFFE_AFFE_6CB0, 0);
+        }
+    }
 }
 
 fn unmap_address_range(start: VirtAddr, end: VirtAddr) -> Result<(), ()> {
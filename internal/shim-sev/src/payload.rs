@@ -3,11 +3,12 @@
 //! Functions dealing with the payload
 use crate::addr::{ShimPhysAddr, ShimVirtAddr};
 use crate::allocator::ALLOCATOR;
+use crate::hostcall::HOST_CALL_ALLOC;
 use crate::paging::SHIM_PAGETABLE;
-use crate::random::random;
 use crate::shim_stack::init_stack_with_guard;
 use crate::usermode::usermode;
 use crate::{get_cbit_mask, PAYLOAD_READY};
+use enarx_shim::random::random;
 
 use core::convert::TryFrom;
 use core::ops::DerefMut;
@@ -20,7 +21,7 @@ use nbytes::bytes;
 use primordial::Address;
 use spinning::{Lazy, RwLock};
 use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
-use x86_64::{PhysAddr, VirtAddr};
+use x86_64::{align_up, PhysAddr, VirtAddr};
 
 /// Payload virtual address, where the elf binary is mapped to, plus a random offset
 const PAYLOAD_ELF_VIRT_ADDR_BASE: VirtAddr = VirtAddr::new_truncate(0x7f00_0000_0000);
@@ -33,7 +34,7 @@ const PAYLOAD_STACK_VIRT_ADDR_BASE: VirtAddr = VirtAddr::new_truncate(0x7ff0_000
 
 /// Initial payload stack size
 #[allow(clippy::integer_arithmetic)]
-const PAYLOAD_STACK_SIZE: u64 = bytes![8; MiB];
+pub const PAYLOAD_STACK_SIZE: u64 = bytes![8; MiB];
 
 /// The randomized virtual address of the payload
 pub static PAYLOAD_VIRT_ADDR: Lazy<RwLock<VirtAddr>> = Lazy::new(|| {
@@ -77,6 +78,8 @@ fn map_elf(app_virt_start: VirtAddr) -> &'static Header {
         )
     };
 
+    verify_manifest(header_ptr as *const u8, headers);
+
     // Convert to shim physical addresses with potential SEV C-Bit set
     let code_start_addr_virt = ShimVirtAddr::try_from(app_load_addr).unwrap();
 
@@ -122,15 +125,91 @@ fn map_elf(app_virt_start: VirtAddr) -> &'static Header {
     header
 }
 
+/// The key which payload manifests are verified with, if the shim has one
+const MANIFEST_KEY: Option<&str> = option_env!("ENARX_MANIFEST_KEY");
+
+/// Verifies the manifest on the page after the image of the payload
+///
+/// Shims built without `ENARX_MANIFEST_KEY` run any payload. The expiry of
+/// the manifest is checked against the clock of the host.
+fn verify_manifest(image_start: *const u8, headers: &[ProgramHeader]) {
+    let key = match MANIFEST_KEY {
+        Some(hex) => manifest::parse_key(hex).expect("Invalid ENARX_MANIFEST_KEY"),
+        None => return,
+    };
+
+    let end = headers
+        .iter()
+        .filter(|ph| ph.p_type == PT_LOAD)
+        .map(|ph| ph.p_paddr.checked_add(ph.p_memsz).unwrap())
+        .max()
+        .unwrap_or(0);
+    let size = align_up(end, Page::<Size4KiB>::SIZE) as usize;
+
+    let image = unsafe { core::slice::from_raw_parts(image_start, size) };
+    let bytes = unsafe { core::slice::from_raw_parts(image_start.add(size), manifest::SIZE) };
+
+    let host = match get_cbit_mask() {
+        0 => manifest::KVM,
+        _ => manifest::SEV,
+    };
+
+    let now = HOST_CALL_ALLOC
+        .try_alloc()
+        .and_then(|mut host_call| host_call.clock_realtime().ok())
+        .map(|time| time.tv_sec as u64);
+
+    let verified = manifest::Manifest::parse(bytes).and_then(|m| m.verify(&key, image, host, now));
+    if let Err(e) = verified {
+        panic!("Refusing to run the payload: {}", e);
+    }
+}
+
+/// The environment used when the host has none for us
+const DEFAULT_ENVIRON: &[u8] = b"LANG=C\0";
+
+/// Splits a list of NUL-terminated strings from the host
+fn strings(list: &[u8]) -> impl Iterator<Item = &str> {
+    assert!(
+        list.last().map_or(true, |b| *b == 0),
+        "unterminated string from the host"
+    );
+
+    let count = list.iter().filter(|b| **b == 0).count();
+    list.split(|b| *b == 0)
+        .take(count)
+        .map(|s| core::str::from_utf8(s).expect("invalid string from the host"))
+}
+
 fn crt0setup(
     app_virt_start: VirtAddr,
     stack_slice: &'static mut [u8],
     header: &Header,
 ) -> (VirtAddr, u64) {
+    let mut buf = [0u8; 2048];
+    let environ = HOST_CALL_ALLOC
+        .try_alloc()
+        .map(|mut host_call| host_call.environ(&mut buf));
+
+    let (list, argc) = match environ {
+        Some(Ok((len, argc))) => (&buf[..len], argc),
+        _ => (DEFAULT_ENVIRON, 0),
+    };
+
+    let mut strings = strings(list);
+
     let mut builder = Builder::new(stack_slice);
     builder.push("/init").unwrap();
+    for _ in 0..argc {
+        let arg = strings.next().expect("missing argument from the host");
+        builder.push(arg).unwrap();
+    }
+
     let mut builder = builder.done().unwrap();
-    builder.push("LANG=C").unwrap();
+    for var in strings {
+        builder.push(var).unwrap();
+    }
+
     let mut builder = builder.done().unwrap();
 
     let ph_header = app_virt_start + header.e_phoff;
@@ -0,0 +1,30 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Application processors
+//!
+//! The host starts every vCPU at the reset vector and gives the application
+//! processors (APs) their index in `edi`, which is 0 on the bootstrap
+//! processor. The APs wait in the boot code until the bootstrap processor has
+//! set up the shim and releases them. Then they switch to its page tables,
+//! count themselves as online and halt: the payload runs on a single thread,
+//! so there is nothing for them to do yet.
+
+use core::sync::atomic::AtomicU64;
+
+/// The number of processors which have booted
+pub static CPUS_ONLINE: AtomicU64 = AtomicU64::new(1);
+
+extern "C" {
+    /// Set to release the waiting APs (in the reset page, see `start`)
+    static mut _ENARX_AP_RELEASE: u32;
+}
+
+/// Release the application processors
+///
+/// # Safety
+///
+/// `unsafe` because the caller has to ensure that the page tables of the
+/// shim are set up, since the APs switch to them.
+pub unsafe fn release() {
+    core::ptr::write_volatile(&mut _ENARX_AP_RELEASE, 1);
+}
@@ -7,6 +7,7 @@
 use crate::_start_main;
 use crate::addr::SHIM_VIRT_OFFSET;
 use crate::pagetables::{PDPT_IDENT, PDPT_OFFSET, PDT_IDENT, PDT_OFFSET, PML4T, PT_IDENT};
+use crate::smp::CPUS_ONLINE;
 use core::mem::size_of;
 use primordial::Page;
 use rcrt1::dyn_reloc;
@@ -16,6 +17,11 @@ const INITIAL_STACK_PAGES: usize = 12;
 #[cfg(debug_assertions)]
 const INITIAL_STACK_PAGES: usize = 50;
 
+/// The value of `esi` in the initial registers of SEV-ES guests ("SEVE")
+///
+/// The host sets it, see `src/backend/sev/launch.rs` in the loader.
+const SEV_ES_MAGIC: u32 = 0x5345_5645;
+
 #[no_mangle]
 #[link_section = ".entry64_data"]
 static INITIAL_SHIM_STACK: [Page; INITIAL_STACK_PAGES] = [Page::zeroed(); INITIAL_STACK_PAGES];
@@ -84,6 +90,15 @@ define_addr gdt32_start 67f
 68:
 define_addr gdt32_end 68b
 
+// The application processors wait until this is set, see `smp`
+.align 4
+.globl _ENARX_AP_RELEASE
+.hidden _ENARX_AP_RELEASE
+_ENARX_AP_RELEASE:
+69:
+define_addr ap_release 69b
+    .long 0
+
 20:
 define_addr code16_start 20b
 .code16
@@ -109,6 +124,49 @@ define_addr code32_start 30f
     mov     ds,     ax
     mov     ss,     ax
 
+    // The host gives the application processors their index in edi. They
+    // wait until the bootstrap processor has set up the shim.
+    test    edi,    edi
+    jz      12f
+13:
+    pause
+    cmp     DWORD PTR [ap_release], 0
+    je      13b
+12:
+
+    // The host marks SEV-ES guests in their (measured) initial registers.
+    // `cpuid` raises a #VC exception on SEV-ES, which can't be handled yet,
+    // so the C-bit position is requested with the GHCB MSR protocol instead.
+    cmp     esi,    {SEV_ES_MAGIC}
+    jne     1f
+
+    // CPUID Fn8000_001F[EBX] with the GHCB MSR protocol:
+    //  request 0x004, register index in bits 31:30, function in bits 63:32
+    mov     ecx,    0xc0010130
+    mov     eax,    0x40000004
+    mov     edx,    0x8000001f
+    wrmsr
+    // VMGEXIT (rep vmmcall)
+    .byte   0xf3, 0x0f, 0x01, 0xd9
+    rdmsr
+
+    // The response 0x005 holds the register value in bits 63:32
+    and     eax,    0xfff
+    cmp     eax,    0x005
+    jne     8f
+
+    // set arg2 of _start_main: SEV-ES is active
+    mov     esi,    1
+    mov     ebx,    edx
+    jmp     7f
+
+8:
+    // No valid response: provoke a triple fault, there is no IDT
+    ud2
+
+1:
+    xor     esi,    esi
+
     // Check if we have a valid (0x8000_001F) CPUID leaf
     mov     eax,    0x80000000
     cpuid
@@ -133,6 +191,7 @@ define_addr code32_start 30f
     bt      eax,    0
     jnc     2f
 
+7:
     // Get pte bit position to enable memory encryption
     // CPUID Fn8000_001F[EBX] - Bits 5:0
     and     ebx,    0x3f
@@ -202,6 +261,14 @@ define_addr code64_start 40f
     mov     r11,    rbx
     shl     r12,    0x20
 
+    // backup the SEV-ES flag to r13
+    mov     r13d,   esi
+
+    // The application processors use the page tables of the bootstrap
+    // processor, which are set up already.
+    test    edi,    edi
+    jnz     9f
+
     // Setup the pagetables
     // done dynamically, otherwise we would have to correct the dynamic symbols twice
 
@@ -298,10 +365,32 @@ define_addr code64_start 40f
 
     // set arg1 to SEV C-Bit mask
     mov     rdi,    r12
+    // set arg2 to the SEV-ES flag
+    mov     rsi,    r13
     xor     rbp,    rbp
 
     // arg1 %rdi  = SEV C-bit mask
+    // arg2 %rsi  = SEV-ES flag
     call    {START_MAIN}
+
+9: // application processor
+    lea     rax,    [rip + {PML4T}]
+    or      rax,    r12         // set C-bit for new CR3
+    mov     cr3,    rax
+
+    // advance rip to kernel address space with {SHIM_VIRT_OFFSET}
+    xor     eax,    eax         // clear OF for adox
+    lea     rax,    [rip + 10f] // trampoline
+    mov     rsi,    {SHIM_VIRT_OFFSET}
+    adox    rax,    rsi
+    jmp     rax                 // trampoline
+
+10:
+    // There is nothing to run on the application processors yet
+    lock inc QWORD PTR [rip + {CPUS_ONLINE}]
+11:
+    hlt
+    jmp     11b
 97: // end of code
 .fill((0xFF0 - (97b - 99b)))
 
@@ -318,10 +407,12 @@ define_addr code64_start 40f
 // END OF PAGE
     ",
     SHIM_VIRT_OFFSET = const SHIM_VIRT_OFFSET,
+    SEV_ES_MAGIC = const SEV_ES_MAGIC,
     SIZE_OF_INITIAL_STACK = const INITIAL_STACK_PAGES * size_of::<Page>(),
     PAGE_SIZE = const size_of::<Page>(),
     DYN_RELOC = sym dyn_reloc,
     START_MAIN = sym _start_main,
+    CPUS_ONLINE = sym CPUS_ONLINE,
     PML4T = sym PML4T,
     PDPT_OFFSET = sym PDPT_OFFSET,
     PDT_OFFSET = sym PDT_OFFSET,
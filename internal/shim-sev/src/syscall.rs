@@ -2,32 +2,70 @@
 
 //! syscall interface layer between assembler and rust
 
+mod file;
+mod fs;
+
 use crate::addr::{HostVirtAddr, ShimPhysUnencryptedAddr};
 use crate::allocator::ALLOCATOR;
 use crate::asm::_enarx_asm_triple_fault;
 use crate::hostcall::{HostCall, HOST_CALL_ALLOC};
 use crate::paging::SHIM_PAGETABLE;
 use crate::payload::{NEXT_BRK_RWLOCK, NEXT_MMAP_RWLOCK};
+use crate::spin::RwLocked;
 use crate::{eprintln, C_BIT_MASK, SEV_SECRET};
 use core::convert::TryFrom;
 use core::mem::size_of;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use enarx_shim::syscall::{
+    FsSyscallHandler, HostFileSyscallHandler, PollSyscallHandler, RandomSyscallHandler,
+    SleepSyscallHandler, SocketSyscallHandler, DUP_SYSCALLS, FD_SYSCALLS, MADV_IGNORED,
+    MCL_ONFAULT, MLOCK_ONFAULT,
+};
+use enarx_syscall::{
+    SYS_ENARX_DETERMINISTIC, SYS_ENARX_GETKEY, SYS_ENARX_GETSECRET, SYS_ENARX_SNAPSHOT,
+};
+use memfs::MemFs;
 use primordial::{Address, Register};
 use sallyport::syscall::{
-    BaseSyscallHandler, EnarxSyscallHandler, FileSyscallHandler, MemorySyscallHandler,
-    NetworkSyscallHandler, ProcessSyscallHandler, SyscallHandler, SystemSyscallHandler,
-    ARCH_GET_FS, ARCH_GET_GS, ARCH_SET_FS, ARCH_SET_GS, SEV_TECH,
-};
-use sallyport::untrusted::{
-    AddressValidator, UntrustedRef, UntrustedRefMut, Validate, ValidateSlice,
+    BaseSyscallHandler, EnarxSyscallHandler, MemorySyscallHandler, NetworkSyscallHandler,
+    ProcessSyscallHandler, SyscallHandler, SystemSyscallHandler, ARCH_GET_FS, ARCH_GET_GS,
+    ARCH_SET_FS, ARCH_SET_GS, SEV_TECH,
 };
-use sallyport::{Cursor, Request};
+use sallyport::untrusted::{AddressValidator, UntrustedRef, UntrustedRefMut, Validate};
+use sallyport::{request, Cursor, Request};
 use x86_64::instructions::segmentation::{Segment64, FS, GS};
 use x86_64::instructions::tlb::flush_all;
-use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB, Translate};
 use x86_64::{align_up, VirtAddr};
 
+/// The `uname()` of every keep: sysname, nodename, release, version, machine
+/// and domainname
+const UNAME: [&str; 6] = ["Linux", "enarx", "5.11.0", "#1 SMP", "x86_64", "(none)"];
+
+/// The end of the lower half of the canonical address space
+///
+/// The payload may not move its segment bases out of it, into the shim or
+/// to non-canonical addresses.
+const USER_END: u64 = 1 << 47;
+
+/// Whether `getrandom()` is proxied: 0 if the host hasn't been asked yet,
+/// 1 if it is and 2 if it isn't
+static DETERMINISTIC: AtomicU8 = AtomicU8::new(0);
+
+/// The number of CPUs which the payload runs on
+///
+/// The application processors only halt (see `smp`), so the payload runs on
+/// the bootstrap processor alone, however many vCPUs the keep has.
+const CPUS: usize = 1;
+
+/// The name of the payload, which `PR_GET_NAME` returns, NUL-terminated
+static NAME: RwLocked<[u8; 16]> = RwLocked::new(*b"enarx\0\0\0\0\0\0\0\0\0\0\0");
+
+/// Whether the payload has set `PR_SET_NO_NEW_PRIVS`
+static NO_NEW_PRIVS: AtomicBool = AtomicBool::new(false);
+
 #[repr(C)]
 struct X8664DoubleReturn {
     rax: u64,
@@ -132,7 +170,7 @@ extern "sysv64" fn syscall_rust(
         argv: [a.into(), b.into(), c.into(), d.into(), e.into(), f.into()],
     };
 
-    let ret = h.syscall(a, b, c, d, e, f, nr);
+    let ret = h.dispatch(a, b, c, d, e, f, nr);
 
     match ret {
         Err(e) => X8664DoubleReturn {
@@ -153,6 +191,284 @@ struct Handler {
     argv: [usize; 6],
 }
 
+impl Handler {
+    /// Dispatch a syscall
+    ///
+    /// Syscalls implemented by the shim itself take precedence over those
+    /// implemented by `SyscallHandler`.
+    #[allow(clippy::many_single_char_names)]
+    fn dispatch(
+        &mut self,
+        a: Register<usize>,
+        b: Register<usize>,
+        c: Register<usize>,
+        d: Register<usize>,
+        e: Register<usize>,
+        f: Register<usize>,
+        nr: usize,
+    ) -> sallyport::Result {
+        match nr as libc::c_long {
+            // There is no key bound to the measurement of the guest yet.
+            SYS_ENARX_GETKEY => Err(libc::ENOSYS),
+            SYS_ENARX_GETSECRET => {
+                self.get_secret((usize::from(a) as *mut u8).into(), usize::from(b))
+            }
+            SYS_ENARX_SNAPSHOT => self.snapshot(),
+            libc::SYS_mlock | libc::SYS_munlock => self.mlock(nr as _, a.into(), b.into(), 0),
+            libc::SYS_mlock2 => self.mlock(nr as _, a.into(), b.into(), usize::from(c) as _),
+            libc::SYS_mlockall | libc::SYS_munlockall => {
+                self.mlockall(nr as _, usize::from(a) as _)
+            }
+            libc::SYS_getrandom => RandomSyscallHandler::getrandom(self, a, b, c),
+            libc::SYS_nanosleep => SleepSyscallHandler::nanosleep(
+                self,
+                (usize::from(a) as *const libc::timespec).into(),
+                usize::from(b) as *mut libc::timespec,
+            ),
+            libc::SYS_clock_nanosleep => SleepSyscallHandler::clock_nanosleep(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                (usize::from(c) as *const libc::timespec).into(),
+                usize::from(d) as *mut libc::timespec,
+            ),
+            libc::SYS_sched_getaffinity => {
+                self.sched_getaffinity(usize::from(b), (usize::from(c) as *mut u8).into())
+            }
+            libc::SYS_getcpu => self.getcpu(usize::from(a), usize::from(b)),
+            libc::SYS_uname => self.uname((usize::from(a) as *mut libc::utsname).into()),
+            libc::SYS_prctl => self.prctl(
+                usize::from(a) as _,
+                [b.into(), c.into(), d.into(), e.into()],
+            ),
+            libc::SYS_sysinfo => self.sysinfo((usize::from(a) as *mut libc::sysinfo).into()),
+            libc::SYS_sendto => SocketSyscallHandler::sendto(
+                self,
+                usize::from(a) as _,
+                (usize::from(b) as *const u8).into(),
+                c.into(),
+                usize::from(d) as _,
+                (usize::from(e) as *const u8).into(),
+                usize::from(f) as _,
+            ),
+            libc::SYS_getsockopt => SocketSyscallHandler::getsockopt(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+                (usize::from(d) as *mut u8).into(),
+                (usize::from(e) as *mut libc::socklen_t).into(),
+            ),
+            libc::SYS_getpeername => SocketSyscallHandler::getpeername(
+                self,
+                usize::from(a) as _,
+                (usize::from(b) as *mut u8).into(),
+                (usize::from(c) as *mut libc::socklen_t).into(),
+            ),
+            libc::SYS_shutdown => {
+                SocketSyscallHandler::shutdown(self, usize::from(a) as _, usize::from(b) as _)
+            }
+            libc::SYS_socketpair => SocketSyscallHandler::socketpair(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+                (usize::from(d) as *mut libc::c_int).into(),
+            ),
+            libc::SYS_epoll_create => match usize::from(a) as libc::c_int {
+                size if size <= 0 => Err(libc::EINVAL),
+                _ => PollSyscallHandler::epoll_create1(self, 0),
+            },
+            libc::SYS_epoll_create1 => PollSyscallHandler::epoll_create1(self, usize::from(a) as _),
+            libc::SYS_epoll_ctl => PollSyscallHandler::epoll_ctl(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+                (usize::from(d) as *const libc::epoll_event).into(),
+            ),
+            // This shim doesn't deliver signals yet, so the mask is ignored.
+            libc::SYS_epoll_wait | libc::SYS_epoll_pwait => PollSyscallHandler::epoll_wait(
+                self,
+                usize::from(a) as _,
+                (usize::from(b) as *mut libc::epoll_event).into(),
+                usize::from(c) as _,
+                usize::from(d) as _,
+            ),
+            libc::SYS_eventfd => PollSyscallHandler::eventfd2(self, usize::from(a) as _, 0),
+            libc::SYS_eventfd2 => {
+                PollSyscallHandler::eventfd2(self, usize::from(a) as _, usize::from(b) as _)
+            }
+            libc::SYS_timerfd_create => {
+                PollSyscallHandler::timerfd_create(self, usize::from(a) as _, usize::from(b) as _)
+            }
+            libc::SYS_timerfd_settime => PollSyscallHandler::timerfd_settime(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                (usize::from(c) as *const libc::itimerspec).into(),
+                usize::from(d) as *mut libc::itimerspec,
+            ),
+            libc::SYS_timerfd_gettime => PollSyscallHandler::timerfd_gettime(
+                self,
+                usize::from(a) as _,
+                (usize::from(b) as *mut libc::itimerspec).into(),
+            ),
+            libc::SYS_poll => PollSyscallHandler::poll(
+                self,
+                (usize::from(a) as *mut libc::pollfd).into(),
+                usize::from(b) as _,
+                usize::from(c) as _,
+            ),
+            libc::SYS_select => PollSyscallHandler::select(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as *mut libc::fd_set,
+                usize::from(c) as *mut libc::fd_set,
+                usize::from(d) as *mut libc::fd_set,
+                usize::from(e) as *mut libc::timeval,
+            ),
+            libc::SYS_open => FsSyscallHandler::openat(
+                self,
+                libc::AT_FDCWD,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+            ),
+            libc::SYS_openat => FsSyscallHandler::openat(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+                usize::from(d) as _,
+            ),
+            libc::SYS_creat => FsSyscallHandler::openat(
+                self,
+                libc::AT_FDCWD,
+                usize::from(a) as _,
+                libc::O_CREAT | libc::O_WRONLY | libc::O_TRUNC,
+                usize::from(b) as _,
+            ),
+            libc::SYS_stat => FsSyscallHandler::newfstatat(
+                self,
+                libc::AT_FDCWD,
+                usize::from(a) as _,
+                (usize::from(b) as *mut libc::stat).into(),
+                0,
+            ),
+            libc::SYS_lstat => FsSyscallHandler::newfstatat(
+                self,
+                libc::AT_FDCWD,
+                usize::from(a) as _,
+                (usize::from(b) as *mut libc::stat).into(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            ),
+            libc::SYS_newfstatat => FsSyscallHandler::newfstatat(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                (usize::from(c) as *mut libc::stat).into(),
+                usize::from(d) as _,
+            ),
+            libc::SYS_access => FsSyscallHandler::faccessat(
+                self,
+                libc::AT_FDCWD,
+                usize::from(a) as _,
+                usize::from(b) as _,
+            ),
+            libc::SYS_faccessat => FsSyscallHandler::faccessat(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+            ),
+            libc::SYS_mkdir => FsSyscallHandler::mkdirat(
+                self,
+                libc::AT_FDCWD,
+                usize::from(a) as _,
+                usize::from(b) as _,
+            ),
+            libc::SYS_mkdirat => FsSyscallHandler::mkdirat(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+            ),
+            libc::SYS_rmdir => FsSyscallHandler::unlinkat(
+                self,
+                libc::AT_FDCWD,
+                usize::from(a) as _,
+                libc::AT_REMOVEDIR,
+            ),
+            libc::SYS_unlink => {
+                FsSyscallHandler::unlinkat(self, libc::AT_FDCWD, usize::from(a) as _, 0)
+            }
+            libc::SYS_unlinkat => FsSyscallHandler::unlinkat(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+            ),
+            libc::SYS_rename => FsSyscallHandler::renameat2(
+                self,
+                libc::AT_FDCWD,
+                usize::from(a) as _,
+                libc::AT_FDCWD,
+                usize::from(b) as _,
+                0,
+            ),
+            libc::SYS_renameat => FsSyscallHandler::renameat2(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+                usize::from(d) as _,
+                0,
+            ),
+            libc::SYS_renameat2 => FsSyscallHandler::renameat2(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+                usize::from(d) as _,
+                usize::from(e) as _,
+            ),
+            n if MemFs::owns(usize::from(a) as _) && FD_SYSCALLS.contains(&n) => {
+                FsSyscallHandler::memfs(self, usize::from(a) as _, b, c, d, n)
+            }
+            n if MemFs::owns(usize::from(a) as _) && DUP_SYSCALLS.contains(&n) => {
+                FsSyscallHandler::memfs(self, usize::from(a) as _, b, c, d, n)
+            }
+            libc::SYS_dup | libc::SYS_dup2 | libc::SYS_dup3 => {
+                HostFileSyscallHandler::dup(self, nr as _, [a.into(), b.into(), c.into()])
+            }
+            libc::SYS_fcntl => HostFileSyscallHandler::fcntl(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+            ),
+            libc::SYS_pipe => {
+                HostFileSyscallHandler::pipe2(self, (usize::from(a) as *mut libc::c_int).into(), 0)
+            }
+            libc::SYS_pipe2 => HostFileSyscallHandler::pipe2(
+                self,
+                (usize::from(a) as *mut libc::c_int).into(),
+                usize::from(b) as _,
+            ),
+            libc::SYS_pread64 => HostFileSyscallHandler::pread64(self, a, b, c, d),
+            libc::SYS_pwrite64 => HostFileSyscallHandler::pwrite64(self, a, b, c, d),
+            libc::SYS_getdents64 => FsSyscallHandler::getdents64(
+                self,
+                usize::from(a) as _,
+                (usize::from(b) as *mut u8).into(),
+                usize::from(c),
+            ),
+            _ => self.syscall(a, b, c, d, e, f, nr),
+        }
+    }
+}
+
 impl AddressValidator for Handler {
     #[inline(always)]
     fn validate_const_mem_fn(&self, _ptr: *const (), _size: usize) -> bool {
@@ -170,7 +486,21 @@ impl AddressValidator for Handler {
 impl SyscallHandler for Handler {}
 impl SystemSyscallHandler for Handler {}
 impl NetworkSyscallHandler for Handler {}
-impl FileSyscallHandler for Handler {}
+impl SocketSyscallHandler for Handler {}
+impl PollSyscallHandler for Handler {}
+impl FsSyscallHandler for Handler {}
+
+impl RandomSyscallHandler for Handler {
+    fn proxied_random(&mut self) -> bool {
+        self.deterministic()
+    }
+}
+
+impl SleepSyscallHandler for Handler {
+    fn proxied_sleep(&mut self) -> bool {
+        self.deterministic()
+    }
+}
 
 impl BaseSyscallHandler for Handler {
     fn unknown_syscall(
@@ -218,6 +548,241 @@ impl BaseSyscallHandler for Handler {
     }
 }
 
+impl Handler {
+    /// Do a `SYS_ENARX_GETSECRET` syscall
+    fn get_secret(&mut self, buf: UntrustedRefMut<u8>, buf_len: libc::size_t) -> sallyport::Result {
+        self.trace("get_secret", 2);
+
+        let secret = SEV_SECRET.read();
+        let secret = (*secret)
+            .as_ref()
+            .and_then(|s| s.try_content())
+            .ok_or(libc::ENODATA)?;
+
+        let len = secret.len().min(buf_len);
+        if len > 0 {
+            let buf = buf.validate_slice(len, self).ok_or(libc::EFAULT)?;
+            buf.copy_from_slice(&secret[..len]);
+        }
+
+        Ok([secret.len().into(), Default::default()])
+    }
+
+    /// Do a `SYS_ENARX_SNAPSHOT` syscall
+    fn snapshot(&mut self) -> sallyport::Result {
+        self.trace("snapshot", 0);
+        unsafe { self.proxy(request!(SYS_ENARX_SNAPSHOT)) }
+    }
+
+    /// Whether the random numbers must come from the host
+    ///
+    /// The host could predict every secret of the payload this way, so only
+    /// guests without SEV, which the host can inspect anyway, ask.
+    fn deterministic(&mut self) -> bool {
+        match DETERMINISTIC.load(Ordering::Relaxed) {
+            0 => (),
+            state => return state == 1,
+        }
+
+        let deterministic = C_BIT_MASK.load(Ordering::Relaxed) == 0
+            && unsafe { self.proxy(request!(SYS_ENARX_DETERMINISTIC)) }.is_ok();
+
+        let state = if deterministic { 1 } else { 2 };
+        DETERMINISTIC.store(state, Ordering::Relaxed);
+        deterministic
+    }
+
+    /// Do a `sched_getaffinity()` syscall
+    ///
+    /// The set holds the CPUs which the payload runs on (see `CPUS`). As on
+    /// Linux, it must have room for all of them in whole words, and the size
+    /// of the part which was written is returned. The keep runs a single
+    /// process, so the pid is not checked.
+    fn sched_getaffinity(&mut self, len: usize, mask: UntrustedRefMut<u8>) -> sallyport::Result {
+        self.trace("sched_getaffinity", 3);
+
+        let size = cpu_set_size(CPUS);
+        if len < size || len.checked_rem(size_of::<libc::c_ulong>()) != Some(0) {
+            return Err(libc::EINVAL);
+        }
+
+        let mask = mask.validate_slice(size, self).ok_or(libc::EFAULT)?;
+        cpu_set(mask, CPUS);
+        Ok([size.into(), 0.into()])
+    }
+
+    /// Do a `getcpu()` syscall
+    ///
+    /// The payload runs on the first vCPU, which is in the only node.
+    fn getcpu(&mut self, cpu: usize, node: usize) -> sallyport::Result {
+        self.trace("getcpu", 3);
+
+        for addr in [cpu, node].iter().filter(|addr| **addr != 0) {
+            let id = UntrustedRefMut::from(*addr as *mut libc::c_uint);
+            *id.validate(self).ok_or(libc::EFAULT)? = 0;
+        }
+
+        Ok(Default::default())
+    }
+
+    /// Do a `uname()` syscall
+    ///
+    /// Every keep describes itself the same, whatever the host runs.
+    fn uname(&mut self, buf: UntrustedRefMut<libc::utsname>) -> sallyport::Result {
+        self.trace("uname", 1);
+
+        let buf = buf.validate(self).ok_or(libc::EFAULT)?;
+        let fields = [
+            &mut buf.sysname,
+            &mut buf.nodename,
+            &mut buf.release,
+            &mut buf.version,
+            &mut buf.machine,
+            &mut buf.domainname,
+        ];
+
+        for (field, value) in fields.iter_mut().zip(UNAME.iter()) {
+            field.iter_mut().for_each(|c| *c = 0);
+            for (c, b) in field.iter_mut().zip(value.bytes()) {
+                *c = b as libc::c_char;
+            }
+        }
+
+        Ok(Default::default())
+    }
+
+    /// Do a `sysinfo()` syscall
+    ///
+    /// The memory is that of the keep so far, which grows as it balloons. The
+    /// payload runs alone, without swap. The load and the uptime are not
+    /// reported.
+    fn sysinfo(&mut self, info: UntrustedRefMut<libc::sysinfo>) -> sallyport::Result {
+        self.trace("sysinfo", 1);
+
+        let (total, free) = {
+            let allocator = ALLOCATOR.read();
+            (allocator.size(), allocator.free())
+        };
+
+        let info = info.validate(self).ok_or(libc::EFAULT)?;
+        *info = unsafe { core::mem::zeroed() };
+        info.totalram = total as _;
+        info.freeram = free as _;
+        info.procs = 1;
+        info.mem_unit = 1;
+
+        Ok(Default::default())
+    }
+
+    /// Do a `prctl()` syscall
+    ///
+    /// Only the options which runtimes use early are known. The payload
+    /// can't gain privileges in the keep anyway, so `PR_SET_NO_NEW_PRIVS`
+    /// is only remembered.
+    fn prctl(&mut self, option: libc::c_int, args: [usize; 4]) -> sallyport::Result {
+        self.trace("prctl", 5);
+
+        match option {
+            libc::PR_SET_NAME => {
+                // The name is truncated to 15 bytes, and might end before
+                // a page which isn't mapped.
+                let mut name = [0u8; 16];
+                for (i, byte) in name.iter_mut().take(15).enumerate() {
+                    let addr = args[0].checked_add(i).ok_or(libc::EFAULT)?;
+                    let c = UntrustedRef::from(addr as *const u8);
+                    *byte = *c.validate(self).ok_or(libc::EFAULT)?;
+                    if *byte == 0 {
+                        break;
+                    }
+                }
+
+                *NAME.write() = name;
+                Ok(Default::default())
+            }
+
+            libc::PR_GET_NAME => {
+                let name = UntrustedRefMut::from(args[0] as *mut [u8; 16]);
+                *name.validate(self).ok_or(libc::EFAULT)? = *NAME.read();
+                Ok(Default::default())
+            }
+
+            libc::PR_SET_NO_NEW_PRIVS if args == [1, 0, 0, 0] => {
+                NO_NEW_PRIVS.store(true, Ordering::Relaxed);
+                Ok(Default::default())
+            }
+
+            libc::PR_GET_NO_NEW_PRIVS if args == [0; 4] => {
+                let set = NO_NEW_PRIVS.load(Ordering::Relaxed) as usize;
+                Ok([set.into(), Default::default()])
+            }
+
+            _ => Err(libc::EINVAL),
+        }
+    }
+
+    /// Do a mlock(), munlock() or mlock2() syscall
+    ///
+    /// The host can page out the memory of the guest regardless, so locking
+    /// pages has no effect.
+    fn mlock(
+        &mut self,
+        nr: libc::c_long,
+        addr: usize,
+        length: usize,
+        flags: libc::c_int,
+    ) -> sallyport::Result {
+        match nr {
+            libc::SYS_mlock => self.trace("mlock", 2),
+            libc::SYS_munlock => self.trace("munlock", 2),
+            _ => self.trace("mlock2", 3),
+        }
+
+        if flags & !MLOCK_ONFAULT != 0 {
+            return Err(libc::EINVAL);
+        }
+
+        addr.checked_add(length).ok_or(libc::ENOMEM)?;
+        Ok(Default::default())
+    }
+
+    /// Do a mlockall() or munlockall() syscall, which have no effect either
+    fn mlockall(&mut self, nr: libc::c_long, flags: libc::c_int) -> sallyport::Result {
+        if nr == libc::SYS_munlockall {
+            self.trace("munlockall", 0);
+            return Ok(Default::default());
+        }
+
+        self.trace("mlockall", 1);
+
+        let all = libc::MCL_CURRENT | libc::MCL_FUTURE | MCL_ONFAULT;
+        if flags & !all != 0 || flags & (libc::MCL_CURRENT | libc::MCL_FUTURE) == 0 {
+            return Err(libc::EINVAL);
+        }
+
+        Ok(Default::default())
+    }
+}
+
+/// The size of a CPU set with room for `cpus` CPUs, in bytes
+///
+/// Like Linux, it is a multiple of the size of a word.
+#[allow(clippy::integer_arithmetic)]
+fn cpu_set_size(cpus: usize) -> usize {
+    let bits = 8 * size_of::<libc::c_ulong>();
+    (cpus + bits - 1) / bits * size_of::<libc::c_ulong>()
+}
+
+/// Puts the first `cpus` CPUs in a CPU set
+#[allow(clippy::integer_arithmetic)]
+fn cpu_set(mask: &mut [u8], cpus: usize) {
+    for (i, byte) in mask.iter_mut().enumerate() {
+        *byte = match cpus.saturating_sub(i * 8) {
+            n if n >= 8 => 0xff,
+            n => (1 << n) - 1,
+        };
+    }
+}
+
 impl EnarxSyscallHandler for Handler {
     fn get_attestation(
         &mut self,
@@ -256,8 +821,8 @@ impl ProcessSyscallHandler for Handler {
     fn arch_prctl(&mut self, code: i32, addr: u64) -> sallyport::Result {
         self.trace("arch_prctl", 2);
         match code {
+            ARCH_SET_FS | ARCH_SET_GS if addr >= USER_END => Err(libc::EPERM),
             ARCH_SET_FS => {
-                // FIXME: check `addr` value
                 unsafe {
                     FS::write_base(VirtAddr::new(addr));
                 }
@@ -271,7 +836,6 @@ impl ProcessSyscallHandler for Handler {
                 Ok(Default::default())
             }
             ARCH_SET_GS => {
-                // FIXME: check `addr` value
                 unsafe {
                     GS::write_base(VirtAddr::new(addr));
                 }
@@ -473,13 +1037,55 @@ impl MemorySyscallHandler for Handler {
         }
     }
 
+    /// Do a madvise() syscall
+    ///
+    /// Linux zeroes private anonymous pages for `MADV_DONTNEED`, which
+    /// allocators rely on, so the writable pages of the payload are zeroed.
     fn madvise(
         &mut self,
-        _addr: *const libc::c_void,
-        _length: usize,
-        _advice: i32,
+        addr: *const libc::c_void,
+        length: usize,
+        advice: i32,
     ) -> sallyport::Result {
         self.trace("madvise", 3);
+
+        let start = VirtAddr::try_new(addr as u64).or(Err(libc::EINVAL))?;
+        if !start.is_aligned(Page::<Size4KiB>::SIZE) {
+            return Err(libc::EINVAL);
+        }
+
+        match advice {
+            libc::MADV_DONTNEED => (),
+            advice if MADV_IGNORED.contains(&advice) => return Ok(Default::default()),
+            _ => return Err(libc::EINVAL),
+        }
+
+        let last = match length.checked_sub(1) {
+            None => return Ok(Default::default()),
+            Some(len) => start.as_u64().checked_add(len as u64),
+        };
+        let last = last
+            .and_then(|last| VirtAddr::try_new(last).ok())
+            .ok_or(libc::ENOMEM)?;
+
+        let page_table = SHIM_PAGETABLE.read();
+        let writable = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+        let pages = Page::<Size4KiB>::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(last),
+        );
+
+        for page in pages {
+            match page_table.translate(page.start_address()) {
+                TranslateResult::Mapped { flags, .. } if flags.contains(writable) => unsafe {
+                    let page = page.start_address().as_mut_ptr::<u8>();
+                    core::ptr::write_bytes(page, 0, Page::<Size4KiB>::SIZE as usize);
+                },
+                TranslateResult::Mapped { .. } => (),
+                _ => return Err(libc::ENOMEM),
+            }
+        }
+
         Ok(Default::default())
     }
 }
@@ -0,0 +1,34 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! File syscalls on host file descriptors
+//!
+//! They are handled by `enarx_shim::syscall::HostFileSyscallHandler`, which
+//! `readv()` and `writev()` of `FileSyscallHandler` are passed on to.
+
+use enarx_shim::syscall::HostFileSyscallHandler;
+use sallyport::syscall::FileSyscallHandler;
+use sallyport::untrusted::UntrustedRef;
+
+impl HostFileSyscallHandler for super::Handler {}
+
+impl FileSyscallHandler for super::Handler {
+    /// Do a readv() syscall
+    fn readv(
+        &mut self,
+        fd: libc::c_int,
+        iovec: UntrustedRef<libc::iovec>,
+        iovcnt: libc::c_int,
+    ) -> sallyport::Result {
+        HostFileSyscallHandler::readv(self, fd, iovec, iovcnt)
+    }
+
+    /// Do a writev() syscall
+    fn writev(
+        &mut self,
+        fd: libc::c_int,
+        iovec: UntrustedRef<libc::iovec>,
+        iovcnt: libc::c_int,
+    ) -> sallyport::Result {
+        HostFileSyscallHandler::writev(self, fd, iovec, iovcnt)
+    }
+}
@@ -0,0 +1,24 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The pages of the in-keep filesystem
+//!
+//! The filesystem syscalls are handled by `enarx_shim::syscall`, which
+//! allocates the contents of its files with `mmap()`.
+
+use memfs::Pages;
+use sallyport::syscall::MemorySyscallHandler;
+
+impl Pages for super::Handler {
+    fn allocate(&mut self, len: usize) -> Option<*mut u8> {
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+        let addr = core::ptr::null::<u8>().into();
+
+        let ret = self.mmap(addr, len, prot, flags, -1, 0).ok()?;
+        Some(usize::from(ret[0]) as *mut u8)
+    }
+
+    unsafe fn release(&mut self, ptr: *mut u8, len: usize) {
+        let _ = self.munmap((ptr as *const u8).into(), len);
+    }
+}
@@ -1,5 +1,37 @@
 // SPDX-License-Identifier: Apache-2.0
 
+use std::path::PathBuf;
+
+/// The minimum size of the code slot, unless `ENARX_CODE_SIZE` is set
+///
+/// The code slot always extends to the next 128M boundary.
+const CODE_SIZE: &str = "0";
+
 fn main() {
     println!("cargo:rerun-if-changed=layout.ld");
+    println!("cargo:rerun-if-env-changed=ENARX_CODE_SIZE");
+
+    // The layout includes the size of the code slot from the output directory.
+    let size = std::env::var("ENARX_CODE_SIZE").unwrap_or_else(|_| CODE_SIZE.into());
+    let out = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    let script = format!("ENARX_CODE_SIZE = {:#x};\n", code_size(&size));
+    std::fs::write(out.join("code.ld"), script).unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+}
+
+/// Parses a size in bytes with an optional `K`, `M` or `G` suffix
+fn code_size(size: &str) -> u64 {
+    let (digits, shift) = match size.as_bytes().last() {
+        Some(b'K') => (&size[..size.len() - 1], 10),
+        Some(b'M') => (&size[..size.len() - 1], 20),
+        Some(b'G') => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .filter(|n| n % 4096 == 0)
+        .expect("ENARX_CODE_SIZE must be a multiple of 4K (e.g. `256M`)")
 }
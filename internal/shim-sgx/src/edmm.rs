@@ -0,0 +1,170 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Enclave Dynamic Memory Management (SGX2)
+//!
+//! The host adds (`EAUG`), restricts (`EMODPR`) and trims (`EMODT`) pages on
+//! our behalf. None of these changes take effect until we accept them with
+//! `EACCEPT`. Permissions are extended from inside the enclave with `EMODPE`.
+//!
+//! Dynamic mappings are allocated from a dedicated region of the enclave
+//! address space (`ENARX_EDMM_START` to `ENARX_EDMM_END`) which contains no
+//! pages when the enclave is initialized.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use enarx_shim::bump::Bump;
+use lset::Line;
+use primordial::Page;
+
+/// SECINFO.FLAGS (see Table 38-19)
+pub mod flags {
+    pub const R: u64 = 1 << 0;
+    pub const W: u64 = 1 << 1;
+    pub const X: u64 = 1 << 2;
+    pub const PENDING: u64 = 1 << 3;
+    pub const MODIFIED: u64 = 1 << 4;
+    pub const PR: u64 = 1 << 5;
+    pub const PT_REG: u64 = 2 << 8;
+    pub const PT_TRIM: u64 = 4 << 8;
+}
+
+const EACCEPT: usize = 5;
+const EMODPE: usize = 6;
+
+#[repr(C, align(64))]
+struct SecInfo {
+    flags: u64,
+    reserved: [u64; 7],
+}
+
+/// 0 = unknown, 1 = unavailable, 2 = available
+static AVAILABLE: AtomicU8 = AtomicU8::new(0);
+
+/// The address space of the dynamic region
+static NEXT: Bump = Bump::new();
+
+/// The number of pages in the dynamic region (see `layout.ld`)
+const PAGES: usize = (512 << 20) / Page::SIZE;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNPROTECTED: AtomicU8 = AtomicU8::new(0);
+
+/// The protection (`PROT_*`) of each page of the dynamic region
+static PROTECTION: [AtomicU8; PAGES] = [UNPROTECTED; PAGES];
+
+/// Returns whether SGX2 instructions are available
+///
+/// The `probe` closure is only called the first time.
+pub fn available(probe: impl FnOnce() -> bool) -> bool {
+    match AVAILABLE.load(Ordering::Relaxed) {
+        0 => {
+            let have = probe();
+            AVAILABLE.store(if have { 2 } else { 1 }, Ordering::Relaxed);
+            have
+        }
+        n => n == 2,
+    }
+}
+
+/// The region of the enclave reserved for dynamic mappings
+pub fn region() -> Line<usize> {
+    unsafe {
+        Line::new(
+            &crate::ENARX_EDMM_START as *const _ as usize,
+            &crate::ENARX_EDMM_END as *const _ as usize,
+        )
+    }
+}
+
+/// Reserves `length` bytes of address space in the dynamic region
+pub fn reserve(length: usize) -> Option<usize> {
+    NEXT.reserve(region(), length)
+}
+
+/// Returns address space to the dynamic region
+///
+/// Since the dynamic region is a simple bump allocator, the address space is
+/// only reclaimed when it is at the end of the allocated area.
+pub fn release(addr: usize, length: usize) {
+    NEXT.release(addr, length)
+}
+
+/// Records the protection of every page in `[addr, addr + length)`
+pub fn protected(addr: usize, length: usize, prot: libc::c_int) {
+    let first = (addr - region().start) / Page::SIZE;
+    for page in PROTECTION.iter().skip(first).take(length / Page::SIZE) {
+        page.store(prot as u8, Ordering::Relaxed);
+    }
+}
+
+/// Returns the protection which was recorded for the page at `addr`
+pub fn protection(addr: usize) -> libc::c_int {
+    let page = (addr - region().start) / Page::SIZE;
+    PROTECTION
+        .get(page)
+        .map_or(libc::PROT_NONE, |p| p.load(Ordering::Relaxed).into())
+}
+
+/// Converts `mmap()`-style protection flags to SECINFO flags
+pub fn perms(prot: libc::c_int) -> u64 {
+    let mut secinfo = 0;
+
+    for (input, output) in [
+        (libc::PROT_READ, flags::R),
+        (libc::PROT_WRITE, flags::W),
+        (libc::PROT_EXEC, flags::X),
+    ] {
+        if prot & input == input {
+            secinfo |= output;
+        }
+    }
+
+    secinfo
+}
+
+#[inline(always)]
+unsafe fn enclu(leaf: usize, secinfo: &SecInfo, page: usize) -> usize {
+    let rax: usize;
+
+    // LLVM reserves `rbx`, so we have to swap it manually.
+    asm!(
+        "xchg {SECINFO}, rbx",
+        "enclu",
+        "xchg {SECINFO}, rbx",
+        SECINFO = inout(reg) secinfo as *const SecInfo => _,
+        inout("rax") leaf => rax,
+        in("rcx") page,
+    );
+
+    rax
+}
+
+/// Accepts (`EACCEPT`) a pending change to every page in `[addr, addr + length)`
+///
+/// The `flags` must exactly match the expected state of the pages.
+pub fn accept(addr: usize, length: usize, flags: u64) -> Result<(), libc::c_int> {
+    let secinfo = SecInfo {
+        flags,
+        reserved: [0; 7],
+    };
+
+    for page in (addr..addr + length).step_by(Page::SIZE) {
+        if unsafe { enclu(EACCEPT, &secinfo, page) } != 0 {
+            return Err(libc::EFAULT);
+        }
+    }
+
+    Ok(())
+}
+
+/// Extends (`EMODPE`) the permissions of every page in `[addr, addr + length)`
+pub fn extend(addr: usize, length: usize, flags: u64) {
+    let secinfo = SecInfo {
+        flags,
+        reserved: [0; 7],
+    };
+
+    for page in (addr..addr + length).step_by(Page::SIZE) {
+        unsafe { enclu(EMODPE, &secinfo, page) };
+    }
+}
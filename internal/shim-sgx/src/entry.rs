@@ -1,8 +1,12 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crt0stack::{Builder, Entry, Handle, OutOfSpace};
+use enarx_syscall::SYS_ENARX_ENVIRON;
 use goblin::elf::header::{header64::Header, ELFMAG};
 
+/// The environment used when the host has none for us
+const DEFAULT_ENVIRON: &[u8] = b"LANG=C\0";
+
 fn exit(code: usize) -> ! {
     loop {
         unsafe {
@@ -16,32 +20,74 @@ fn exit(code: usize) -> ! {
 }
 
 fn random() -> u64 {
-    let mut r: u64 = 0;
+    enarx_shim::random::try_random().unwrap_or_else(|| exit(1))
+}
 
-    for _ in 0..1024 {
-        if unsafe { core::arch::x86_64::_rdrand64_step(&mut r) } == 1 {
-            return r;
-        }
+/// Gets the arguments and environment of the payload from the host
+///
+/// Returns the list of strings and the number of arguments in it. Like the
+/// payload, we trap to the handler, which asks the host.
+fn environ(buf: &mut [u8]) -> (&[u8], usize) {
+    let rax: isize;
+    let argc: usize;
+
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") SYS_ENARX_ENVIRON => rax,
+            in("rdi") buf.as_mut_ptr(),
+            in("rsi") buf.len(),
+            lateout("rdx") argc,
+            lateout("rcx") _,
+            lateout("r11") _,
+        );
+    }
+
+    match rax {
+        len if len < 0 => (DEFAULT_ENVIRON, 0),
+        len => (&buf[..len as usize], argc),
+    }
+}
+
+/// Splits a list of NUL-terminated strings
+///
+/// The host can't be trusted to send a valid list, so we exit if it didn't.
+fn strings(list: &[u8]) -> impl Iterator<Item = &str> {
+    if list.last().map_or(false, |b| *b != 0) {
+        exit(1);
     }
 
-    exit(1)
+    let count = list.iter().filter(|b| **b == 0).count();
+    list.split(|b| *b == 0)
+        .take(count)
+        .map(|s| core::str::from_utf8(s).unwrap_or_else(|_| exit(1)))
 }
 
 fn crt0setup<'a>(
     hdr: &Header,
     crt0: &'a mut [u8],
     off: *const (),
+    environ: (&[u8], usize),
 ) -> Result<Handle<'a>, OutOfSpace> {
     let rand = unsafe { core::mem::transmute([random(), random()]) };
     let phdr = off as u64 + hdr.e_phoff;
+    let entry = off as u64 + hdr.e_entry;
+
+    let (list, argc) = environ;
+    let mut strings = strings(list);
 
     // Set the arguments
     let mut builder = Builder::new(crt0);
     builder.push("/init")?;
+    for _ in 0..argc {
+        builder.push(strings.next().unwrap_or_else(|| exit(1)))?;
+    }
 
     // Set the environment
     let mut builder = builder.done()?;
-    builder.push("LANG=C")?;
+    for var in strings {
+        builder.push(var)?;
+    }
 
     // Set the aux vector
     let mut builder = builder.done()?;
@@ -61,6 +107,7 @@ fn crt0setup<'a>(
     builder.push(&Entry::PHent(hdr.e_phentsize as _))?;
     builder.push(&Entry::PHnum(hdr.e_phnum as _))?;
     builder.push(&Entry::Random(rand))?;
+    builder.push(&Entry::Entry(entry as _))?;
 
     builder.done()
 }
@@ -72,10 +119,14 @@ pub unsafe fn entry(offset: *const ()) -> ! {
         exit(1);
     }
 
+    // Get the arguments and environment.
+    let mut list = [0u8; 2048];
+    let environ = environ(&mut list);
+
     // Prepare the crt0 stack.
-    let mut crt0 = [0u8; 1024];
+    let mut crt0 = [0u8; 4096];
     let space = random() as usize & 0xf0;
-    let handle = match crt0setup(hdr, &mut crt0[space..], offset) {
+    let handle = match crt0setup(hdr, &mut crt0[space..], offset, environ) {
         Err(OutOfSpace) => exit(1),
         Ok(handle) => handle,
     };
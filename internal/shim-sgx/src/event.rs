@@ -0,0 +1,38 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! What the shim reports to the host when it exits
+//!
+//! When the host enters the shim on a nested SSA frame, the shim classifies
+//! the exception which it saved in the SSA: a syscall or `cpuid` of the
+//! payload is emulated, a request of the shim is passed on to the host, and
+//! any other fault of the payload is delivered as a signal. The `Event` in
+//! `rdx` on EEXIT tells the host which of them it has to act on.
+//!
+//! The encoding must match the one used by the host.
+
+const DONE: usize = 0;
+const REQUEST: usize = 1;
+const FAULT: usize = 2;
+
+/// What the shim leaves for the host
+#[derive(Copy, Clone, Debug)]
+pub enum Event {
+    /// The exception was handled in the enclave
+    Done,
+
+    /// The sallyport block with this index holds a request
+    Request(usize),
+
+    /// The shim faulted while handling an exception
+    Fault,
+}
+
+impl From<Event> for usize {
+    fn from(event: Event) -> usize {
+        match event {
+            Event::Done => DONE,
+            Event::Request(block) => block << 8 | REQUEST,
+            Event::Fault => FAULT,
+        }
+    }
+}
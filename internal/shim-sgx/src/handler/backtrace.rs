@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Backtraces of the payload
+//!
+//! Before a fault kills the payload, the shim reports where it was to the
+//! host with `SYS_ENARX_BACKTRACE`: the address of the fault, followed by the
+//! return addresses found by following the frame pointers of the payload on
+//! the stack it faulted on. The host symbolizes them against the payload.
+//! Payloads built without frame pointers only report the address of the
+//! fault.
+//!
+//! The addresses tell the host about the layout of the payload, so only
+//! enclaves with the DEBUG attribute, which the host can inspect anyway,
+//! report them.
+
+use core::mem::size_of;
+use core::sync::atomic::Ordering;
+
+use enarx_syscall::SYS_ENARX_BACKTRACE;
+use lset::Line;
+use sallyport::request;
+use sallyport::syscall::BaseSyscallHandler;
+
+/// The most addresses which are reported
+const FRAMES: usize = 32;
+
+impl<'a> super::Handler<'a> {
+    /// Reports the backtrace of the payload, which a fault kills
+    pub(super) fn backtrace(&mut self, signal: libc::c_int) {
+        if !super::deterministic::debug() {
+            return;
+        }
+
+        let (base, stack, heap) = unsafe {
+            let base = &crate::ENARX_EXEC_START as *const u8 as usize;
+            let stack = Line::new(
+                &crate::ENARX_STACK_START as *const u8 as usize,
+                &crate::ENARX_STACK_END as *const u8 as usize,
+            );
+            let heap = &crate::ENARX_HEAP_START as *const u8 as usize;
+            let heap = Line::new(heap, heap + crate::HEAP.load(Ordering::Relaxed));
+            (base, stack, heap)
+        };
+
+        // The main thread runs on the stack of the shim, the others on the
+        // heap. Frames are only followed up that stack.
+        let rsp = u64::from(self.gpr.rsp) as usize;
+        let end = match [stack, heap].iter().find(|l| l.start <= rsp && rsp < l.end) {
+            Some(line) => line.end,
+            None => rsp,
+        };
+
+        let mut frames = [0u64; FRAMES];
+        frames[0] = self.gpr.rip.into();
+        let mut count = 1;
+
+        // A frame starts with the frame pointer of the caller, followed by
+        // the return address.
+        let mut rbp = u64::from(self.gpr.rbp) as usize;
+        while count < FRAMES
+            && rbp >= rsp
+            && rbp % size_of::<u64>() == 0
+            && rbp <= end.saturating_sub(2 * size_of::<u64>())
+        {
+            let [next, ret] = unsafe { *(rbp as *const [u64; 2]) };
+            if ret == 0 {
+                break;
+            }
+
+            frames[count] = ret;
+            count += 1;
+
+            // The stack grows down, so the callers' frames are above.
+            if next as usize <= rbp {
+                break;
+            }
+            rbp = next as usize;
+        }
+
+        let c = self.new_cursor();
+        if c.copy_from_slice(&frames[..count]).is_ok() {
+            let _ = unsafe { self.proxy(request!(SYS_ENARX_BACKTRACE => signal, count, base)) };
+        }
+    }
+}
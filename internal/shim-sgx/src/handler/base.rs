@@ -1,8 +1,51 @@
 // SPDX-License-Identifier: Apache-2.0
 
+use core::sync::atomic::Ordering;
+
+use enarx_syscall::SYS_ENARX_ATTACKED;
 use primordial::Register;
 use sallyport::syscall::{BaseSyscallHandler, ProcessSyscallHandler};
-use sallyport::{Cursor, Request};
+use sallyport::{request, Block, Cursor, Request};
+
+impl<'a> super::Handler<'a> {
+    /// Sends a request to the host, ignoring the queued writes
+    pub(super) unsafe fn exchange(&mut self, req: Request) -> sallyport::Result {
+        send(self.block, 0, req)
+    }
+
+    /// Sends a request to the host in the block of the queued writes
+    pub(super) unsafe fn exchange_queue(&mut self, req: Request) -> sallyport::Result {
+        send(self.queue, 1, req)
+    }
+
+    /// Trips the circuit breaker unless a region of the host is entirely
+    /// outside of the enclave
+    pub(super) fn untrusted(&mut self, addr: usize, size: usize) {
+        let start = unsafe { &crate::ENARX_HEAP_START as *const u8 as usize };
+        let encl = start & !(crate::ENCL_SIZE - 1);
+        let end = addr.checked_add(size).unwrap_or_else(|| self.attacked());
+        if addr < encl + crate::ENCL_SIZE && encl < end {
+            self.attacked();
+        }
+    }
+}
+
+/// Sends a request to the host in the block with the given index
+unsafe fn send(block: &mut Block, index: usize, req: Request) -> sallyport::Result {
+    block.msg.req = req;
+    super::BLOCK.store(index, Ordering::Relaxed);
+
+    // prevent earlier writes from being moved beyond this point
+    core::sync::atomic::compiler_fence(Ordering::Release);
+
+    asm!("syscall");
+
+    // prevent later reads from being moved before this point
+    core::sync::atomic::compiler_fence(Ordering::Acquire);
+
+    super::BLOCK.store(0, Ordering::Relaxed);
+    block.msg.rep.into()
+}
 
 impl<'a> BaseSyscallHandler for super::Handler<'a> {
     fn translate_shim_to_host_addr<T>(buf: *const T) -> usize {
@@ -10,21 +53,13 @@ impl<'a> BaseSyscallHandler for super::Handler<'a> {
     }
 
     fn new_cursor(&mut self) -> Cursor {
+        self.flush();
         self.block.cursor()
     }
 
     unsafe fn proxy(&mut self, req: Request) -> sallyport::Result {
-        self.block.msg.req = req;
-
-        // prevent earlier writes from being moved beyond this point
-        core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::Release);
-
-        asm!("syscall");
-
-        // prevent later reads from being moved before this point
-        core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::Acquire);
-
-        self.block.msg.rep.into()
+        self.flush();
+        self.exchange(req)
     }
 
     /// When we are under attack, we trip this circuit breaker and
@@ -32,6 +67,9 @@ impl<'a> BaseSyscallHandler for super::Handler<'a> {
     /// tripping the circuit breaker causes the enclave to immediately
     /// EEXIT.
     fn attacked(&mut self) -> ! {
+        // The host stops the keep when it is told, so that it can tell the
+        // attack from an exit of the payload; the queued writes are dropped.
+        let _ = unsafe { self.exchange(request!(SYS_ENARX_ATTACKED)) };
         self.exit(1)
     }
 
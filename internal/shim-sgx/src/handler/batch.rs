@@ -0,0 +1,182 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Deferred writes
+//!
+//! Writes to stdout and stderr are queued in a block of their own instead of
+//! being sent to the host one by one (see `enarx_shim::batch` for why other
+//! writes aren't). The queue is sent as a single `SYS_ENARX_BATCH` request
+//! before anything else is sent to the host, so the host still executes all
+//! requests in order, but without an exit from the enclave per write.
+//!
+//! If the host offers a syscall ring, writes are submitted to it instead (see
+//! the `ring` module).
+//!
+//! The payload is told that a queued write succeeded. Writes which the host
+//! only executes in part are completed when the queue is sent. Errors are
+//! kept and returned by the next `write()` to or `close()` of the descriptor.
+
+use core::mem::size_of;
+use core::ptr::{read_unaligned, write_unaligned};
+
+use enarx_shim::batch::{Queue, SLOTS};
+use enarx_syscall::SYS_ENARX_BATCH;
+use libc::c_int;
+use primordial::Register;
+use sallyport::syscall::BaseSyscallHandler;
+use sallyport::untrusted::{UntrustedRef, ValidateSlice};
+use sallyport::{request, Block, Reply, Request};
+use spinning::{Mutex, RawMutex};
+
+/// The offset of the data of the queued writes in their block
+const DATA: usize = SLOTS * size_of::<Request>();
+
+static QUEUE: Mutex<Queue> =
+    Mutex::const_new(RawMutex::const_new(), Queue::new(DATA, size_of::<Block>()));
+
+/// Whether the writes to a descriptor may be deferred
+pub(super) fn deferred(fd: Register<usize>) -> bool {
+    Queue::deferred(usize::from(fd) as _)
+}
+
+impl<'a> super::Handler<'a> {
+    /// Whether a `write()` goes through the queue: `(fd, buf, count)`
+    pub(super) fn deferrable(&self, fd: Register<usize>, count: Register<usize>) -> bool {
+        QUEUE.lock().defers(usize::from(fd) as _, count.into())
+    }
+
+    /// Do a `write()` syscall by queuing it
+    pub(super) fn write_deferred(
+        &mut self,
+        fd: Register<usize>,
+        buf: Register<usize>,
+        count: Register<usize>,
+    ) -> sallyport::Result {
+        self.trace("write", 3);
+
+        if let Some(errno) = QUEUE.lock().error(usize::from(fd) as _) {
+            return Err(errno);
+        }
+
+        let buf = UntrustedRef::from(usize::from(buf) as *const u8);
+        let data = buf
+            .validate_slice(usize::from(count), self)
+            .ok_or(libc::EFAULT)?;
+
+        // Writes already in the queue must not be overtaken.
+        let empty = QUEUE.lock().is_empty();
+        if empty {
+            if let Some(ret) = self.submit(usize::from(fd), data) {
+                return ret;
+            }
+        }
+
+        loop {
+            // The write is queued under the lock which found room for it.
+            let mut queue = QUEUE.lock();
+            let (slot, start) = match queue.push(data.len()) {
+                Some(queued) => queued,
+                None => {
+                    drop(queue);
+                    self.flush();
+                    continue;
+                }
+            };
+
+            self.queue.buf[start..start + data.len()].copy_from_slice(data);
+
+            let ptr = Self::translate_shim_to_host_addr(self.queue.buf[start..].as_ptr());
+            let req = request!(libc::SYS_write => usize::from(fd), ptr, data.len());
+            unsafe { write_unaligned(self.slot(slot), req) };
+
+            return Ok([data.len().into(), 0.into()]);
+        }
+    }
+
+    /// Do a `close()` syscall of a descriptor whose writes may be deferred
+    ///
+    /// The descriptor is closed, but a kept error of its writes is returned,
+    /// so that it doesn't outlive the descriptor.
+    pub(super) fn close_deferred(&mut self, fd: Register<usize>) -> sallyport::Result {
+        self.flush();
+
+        let errno = QUEUE.lock().error(usize::from(fd) as _);
+        let ret = unsafe { self.proxy(request!(libc::SYS_close => fd)) };
+        match errno {
+            Some(errno) if ret.is_ok() => Err(errno),
+            _ => ret,
+        }
+    }
+
+    /// Sends the queued writes to the host
+    ///
+    /// This must be done before anything else is sent to the host.
+    pub(super) fn flush(&mut self) {
+        self.drain_ring();
+
+        // The lock isn't held while the host executes the writes: tripping
+        // the circuit breaker sends the exit to the host, which flushes again.
+        let writes = QUEUE.lock().take();
+        if writes == 0 {
+            return;
+        }
+
+        let mut reqs = [request!(libc::SYS_write); SLOTS];
+        for (i, req) in reqs[..writes].iter_mut().enumerate() {
+            *req = unsafe { read_unaligned(self.slot(i)) };
+        }
+
+        // A host which doesn't know batches gets the writes one by one.
+        let req = request!(SYS_ENARX_BATCH => writes);
+        let batched = match unsafe { self.exchange_queue(req) } {
+            Ok(ret) if usize::from(ret[0]) == writes => true,
+            Ok(_) => self.attacked(),
+            Err(_) => false,
+        };
+
+        for (i, req) in reqs[..writes].iter().enumerate() {
+            let done: sallyport::Result = match batched {
+                true => unsafe { read_unaligned(self.slot(i) as *const Reply) }.into(),
+                false => Ok([0.into(), 0.into()]),
+            };
+
+            if let Err(errno) = done.and_then(|done| self.complete(*req, done[0].into())) {
+                self.fail(*req, errno);
+            }
+        }
+    }
+
+    /// Writes what the host hasn't written of a deferred write
+    ///
+    /// A host which writes nothing fails the write with `EIO`.
+    pub(super) fn complete(&mut self, req: Request, mut done: usize) -> Result<(), c_int> {
+        let (ptr, len) = (usize::from(req.arg[1]), usize::from(req.arg[2]));
+        if done > len {
+            self.attacked();
+        }
+
+        while done < len {
+            let mut rest = req;
+            rest.arg[1] = (ptr + done).into();
+            rest.arg[2] = (len - done).into();
+
+            done += match unsafe { self.exchange(rest) }? {
+                ret if usize::from(ret[0]) > len - done => self.attacked(),
+                ret if usize::from(ret[0]) > 0 => usize::from(ret[0]),
+                _ => return Err(libc::EIO),
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Keeps the error of a deferred write for its descriptor
+    pub(super) fn fail(&mut self, req: Request, errno: c_int) {
+        QUEUE.lock().fail(usize::from(req.arg[0]) as _, errno);
+    }
+
+    /// Returns the place of a queued request in its block
+    fn slot(&mut self, index: usize) -> *mut Request {
+        debug_assert!(index < SLOTS);
+        unsafe { self.queue.buf.as_mut_ptr().cast::<Request>().add(index) }
+    }
+}
@@ -0,0 +1,133 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Large reads and writes
+//!
+//! The data of a read or a write is copied through the data area of the
+//! block, so a large transfer costs one exit per few kilobytes. Transfers of
+//! at least `MIN` bytes go through a bounce region instead: a dedicated
+//! mapping of untrusted memory which the host sets up on the first request.
+//! The data is copied once between the payload and the region, and a single
+//! request points the host at it.
+//!
+//! Transfers larger than the region are done in part, which the payload sees
+//! as a short read or write.
+
+use enarx_syscall::SYS_ENARX_BOUNCE;
+use primordial::{Page, Register};
+use sallyport::request;
+use sallyport::syscall::BaseSyscallHandler;
+use sallyport::untrusted::{UntrustedRef, UntrustedRefMut, ValidateSlice};
+use spinning::{Mutex, RawMutex};
+
+/// The size of the bounce region
+const SIZE: usize = 8 << 20;
+
+/// The smallest transfer which goes through the bounce region
+const MIN: usize = 64 << 10;
+
+#[derive(Copy, Clone)]
+enum State {
+    /// The host hasn't been asked yet
+    Unknown,
+
+    /// The host has no bounce region
+    Unavailable,
+
+    /// The address of the region
+    Ready(usize),
+}
+
+static BOUNCE: Mutex<State> = Mutex::const_new(RawMutex::const_new(), State::Unknown);
+
+impl<'a> super::Handler<'a> {
+    /// Whether a `read()` or a `write()` goes through the bounce region
+    pub(super) fn bounceable(&mut self, count: Register<usize>) -> bool {
+        usize::from(count) >= MIN && self.region().is_some()
+    }
+
+    /// Do a `read()` syscall through the bounce region
+    pub(super) fn read_bounced(
+        &mut self,
+        fd: Register<usize>,
+        buf: Register<usize>,
+        count: Register<usize>,
+    ) -> sallyport::Result {
+        self.trace("read", 3);
+
+        let buf = UntrustedRefMut::from(usize::from(buf) as *mut u8);
+        let buf = buf
+            .validate_slice(usize::from(count), self)
+            .ok_or(libc::EFAULT)?;
+
+        let addr = self.region().ok_or(libc::EIO)?;
+        let len = buf.len().min(SIZE);
+        let ptr = Self::translate_shim_to_host_addr(addr as *const u8);
+        let ret = unsafe { self.proxy(request!(libc::SYS_read => usize::from(fd), ptr, len))? };
+
+        let done = usize::from(ret[0]);
+        if done > len {
+            self.attacked();
+        }
+
+        let region = unsafe { core::slice::from_raw_parts(addr as *const u8, done) };
+        buf[..done].copy_from_slice(region);
+        Ok(ret)
+    }
+
+    /// Do a `write()` syscall through the bounce region
+    pub(super) fn write_bounced(
+        &mut self,
+        fd: Register<usize>,
+        buf: Register<usize>,
+        count: Register<usize>,
+    ) -> sallyport::Result {
+        self.trace("write", 3);
+
+        let buf = UntrustedRef::from(usize::from(buf) as *const u8);
+        let data = buf
+            .validate_slice(usize::from(count), self)
+            .ok_or(libc::EFAULT)?;
+
+        let addr = self.region().ok_or(libc::EIO)?;
+        let len = data.len().min(SIZE);
+        unsafe { (addr as *mut u8).copy_from_nonoverlapping(data.as_ptr(), len) };
+
+        let ptr = Self::translate_shim_to_host_addr(addr as *const u8);
+        let ret = unsafe { self.proxy(request!(libc::SYS_write => usize::from(fd), ptr, len))? };
+        if usize::from(ret[0]) > len {
+            self.attacked();
+        }
+
+        Ok(ret)
+    }
+
+    /// Returns the address of the bounce region, asking the host for it once
+    fn region(&mut self) -> Option<usize> {
+        let state = *BOUNCE.lock();
+        match state {
+            State::Unknown => (),
+            State::Unavailable => return None,
+            State::Ready(addr) => return Some(addr),
+        }
+
+        let state = match unsafe { self.proxy(request!(SYS_ENARX_BOUNCE => SIZE)) } {
+            Ok([addr, size]) => {
+                let (addr, size) = (usize::from(addr), usize::from(size));
+                if size < SIZE || addr % Page::SIZE != 0 {
+                    self.attacked();
+                }
+
+                self.untrusted(addr, SIZE);
+                State::Ready(addr)
+            }
+
+            Err(_) => State::Unavailable,
+        };
+
+        *BOUNCE.lock() = state;
+        match state {
+            State::Ready(addr) => Some(addr),
+            _ => None,
+        }
+    }
+}
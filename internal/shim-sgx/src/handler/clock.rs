@@ -0,0 +1,152 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Time without exits
+//!
+//! If the host offers a clock page, `clock_gettime()` of the monotonic and
+//! the realtime clocks is answered from the page, which a thread of the host
+//! updates regularly. The time lags behind by up to the interval between the
+//! updates. Other clocks, and all of them in deterministic runs, are proxied.
+//!
+//! The time on the page is as untrusted as a proxied answer. The monotonic
+//! clock is kept from going backwards.
+
+use core::mem::size_of;
+use core::sync::atomic::{fence, AtomicI64, AtomicU64, Ordering};
+
+use enarx_syscall::SYS_ENARX_CLOCK;
+use primordial::{Page, Register};
+use sallyport::request;
+use sallyport::syscall::BaseSyscallHandler;
+use sallyport::untrusted::{UntrustedRefMut, Validate};
+use spinning::{Mutex, RawMutex};
+
+/// The clocks answered from the page
+const CLOCKS: [libc::clockid_t; 4] = [
+    libc::CLOCK_MONOTONIC,
+    libc::CLOCK_MONOTONIC_COARSE,
+    libc::CLOCK_REALTIME,
+    libc::CLOCK_REALTIME_COARSE,
+];
+
+/// The memory shared with the host
+///
+/// The layout must match the one used by the host.
+#[repr(C, align(4096))]
+struct Shared {
+    seq: AtomicU64,
+    monotonic: [AtomicI64; 2],
+    realtime: [AtomicI64; 2],
+}
+
+#[derive(Copy, Clone)]
+enum State {
+    /// The host hasn't been asked yet
+    Unknown,
+
+    /// The host has no clock page
+    Unavailable,
+
+    /// The address of the page
+    Ready(usize),
+}
+
+static CLOCK: Mutex<State> = Mutex::const_new(RawMutex::const_new(), State::Unknown);
+
+/// The latest time of the monotonic clock returned to the payload
+static LAST: Mutex<[i64; 2]> = Mutex::const_new(RawMutex::const_new(), [0, 0]);
+
+impl<'a> super::Handler<'a> {
+    /// Whether a `clock_gettime()` is answered from the clock page
+    pub(super) fn clock_local(&mut self, clockid: Register<usize>) -> bool {
+        let clockid = usize::from(clockid) as libc::clockid_t;
+        CLOCKS.contains(&clockid) && !self.deterministic() && self.clock().is_some()
+    }
+
+    /// Do a `clock_gettime()` syscall from the clock page
+    pub(super) fn clock_gettime_local(
+        &mut self,
+        clockid: Register<usize>,
+        tp: Register<usize>,
+    ) -> sallyport::Result {
+        self.trace("clock_gettime", 2);
+
+        let tp = UntrustedRefMut::from(usize::from(tp) as *mut libc::timespec);
+        let tp = tp.validate(self).ok_or(libc::EFAULT)?;
+
+        let addr = self.clock().ok_or(libc::EIO)?;
+        let shared = unsafe { &*(addr as *const Shared) };
+        let (monotonic, realtime) = read(shared);
+
+        let time = match usize::from(clockid) as libc::clockid_t {
+            libc::CLOCK_REALTIME | libc::CLOCK_REALTIME_COARSE => realtime,
+            _ => {
+                let mut last = LAST.lock();
+                *last = monotonic.max(*last);
+                *last
+            }
+        };
+
+        if !(0..1_000_000_000).contains(&time[1]) {
+            self.attacked();
+        }
+
+        *tp = libc::timespec {
+            tv_sec: time[0],
+            tv_nsec: time[1],
+        };
+
+        Ok(Default::default())
+    }
+
+    /// Returns the address of the clock page, asking the host for it once
+    fn clock(&mut self) -> Option<usize> {
+        let state = *CLOCK.lock();
+        match state {
+            State::Unknown => (),
+            State::Unavailable => return None,
+            State::Ready(addr) => return Some(addr),
+        }
+
+        let state = match unsafe { self.proxy(request!(SYS_ENARX_CLOCK)) } {
+            Ok([addr, size]) => {
+                let (addr, size) = (usize::from(addr), usize::from(size));
+                if size != size_of::<Shared>() || addr % Page::SIZE != 0 {
+                    self.attacked();
+                }
+
+                self.untrusted(addr, size);
+                State::Ready(addr)
+            }
+
+            Err(_) => State::Unavailable,
+        };
+
+        *CLOCK.lock() = state;
+        match state {
+            State::Ready(addr) => Some(addr),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the monotonic and the realtime clocks from the page
+fn read(shared: &Shared) -> ([i64; 2], [i64; 2]) {
+    loop {
+        let seq = shared.seq.load(Ordering::Acquire);
+        let monotonic = [
+            shared.monotonic[0].load(Ordering::Relaxed),
+            shared.monotonic[1].load(Ordering::Relaxed),
+        ];
+        let realtime = [
+            shared.realtime[0].load(Ordering::Relaxed),
+            shared.realtime[1].load(Ordering::Relaxed),
+        ];
+
+        fence(Ordering::Acquire);
+        if seq & 1 == 0 && shared.seq.load(Ordering::Relaxed) == seq {
+            return (monotonic, realtime);
+        }
+
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,68 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Deterministic runs
+//!
+//! To replay a run of the payload, the host can ask for the time and the
+//! random numbers to come from a virtual source which it seeds. The shim asks
+//! the host once with `SYS_ENARX_DETERMINISTIC`; if it agrees, `getrandom()`
+//! and `clock_gettime()` are proxied rather than answered by `RDRAND` and the
+//! clock page.
+//!
+//! The host could predict every secret of the payload this way, so only
+//! enclaves with the DEBUG attribute, which the host can inspect anyway, ask.
+
+use crate::report;
+
+use core::convert::TryInto;
+
+use enarx_syscall::SYS_ENARX_DETERMINISTIC;
+use sallyport::request;
+use sallyport::syscall::BaseSyscallHandler;
+use spinning::{Mutex, RawMutex};
+
+/// The offset of ATTRIBUTES.FLAGS in a report
+const ATTRIBUTES: usize = 48;
+
+/// ATTRIBUTES.FLAGS.DEBUG
+const DEBUG: u64 = 1 << 1;
+
+#[derive(Copy, Clone)]
+enum State {
+    /// The host hasn't been asked yet
+    Unknown,
+
+    /// The time and the random numbers come from the host
+    Deterministic,
+
+    /// The time and the random numbers come from the CPU when possible
+    Native,
+}
+
+static STATE: Mutex<State> = Mutex::const_new(RawMutex::const_new(), State::Unknown);
+
+/// Whether the enclave has the DEBUG attribute
+///
+/// The attributes are read from a report of the enclave (`EREPORT`).
+pub(super) fn debug() -> bool {
+    let report = report::report();
+    let flags = u64::from_le_bytes(report[ATTRIBUTES..][..8].try_into().unwrap());
+    flags & DEBUG != 0
+}
+
+impl<'a> super::Handler<'a> {
+    /// Whether the time and the random numbers must come from the host
+    pub(super) fn deterministic(&mut self) -> bool {
+        let state = *STATE.lock();
+        let state = match state {
+            State::Unknown if !debug() => State::Native,
+            State::Unknown => match unsafe { self.proxy(request!(SYS_ENARX_DETERMINISTIC)) } {
+                Ok(_) => State::Deterministic,
+                Err(_) => State::Native,
+            },
+            state => return matches!(state, State::Deterministic),
+        };
+
+        *STATE.lock() = state;
+        matches!(state, State::Deterministic)
+    }
+}
@@ -0,0 +1,104 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Shutdown requests from the host
+//!
+//! When the host is asked to stop the keep, e.g. with `SIGTERM`, it writes
+//! the signal to a doorbell page in untrusted memory, which the shim asks for
+//! with `SYS_ENARX_DOORBELL`. The page is checked whenever a syscall returns
+//! to the payload, and when the host interrupts the payload, so that a payload
+//! which makes no syscalls hears of it as well. The signal is raised in the
+//! payload once, as the kernel would raise it.
+//!
+//! The host can ring the doorbell at will, but it can stop the keep at will
+//! as well, so this gives it nothing it doesn't have.
+
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+use enarx_syscall::SYS_ENARX_DOORBELL;
+use primordial::Page;
+use sallyport::request;
+use sallyport::syscall::BaseSyscallHandler;
+use spinning::{Mutex, RawMutex};
+
+/// The memory shared with the host
+///
+/// The layout must match the one used by the host.
+#[repr(C, align(4096))]
+struct Shared {
+    signal: AtomicI32,
+}
+
+#[derive(Copy, Clone)]
+enum State {
+    /// The host hasn't been asked yet
+    Unknown,
+
+    /// The host has no doorbell page
+    Unavailable,
+
+    /// The address of the page
+    Ready(usize),
+}
+
+static DOORBELL: Mutex<State> = Mutex::const_new(RawMutex::const_new(), State::Unknown);
+
+/// Whether the signal was raised
+static RAISED: AtomicBool = AtomicBool::new(false);
+
+impl<'a> super::Handler<'a> {
+    /// Raises the signal of the doorbell in the payload, if it was rung
+    pub(super) fn doorbell(&mut self) {
+        if RAISED.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let addr = match self.doorbell_page() {
+            Some(addr) => addr,
+            None => return,
+        };
+
+        let shared = unsafe { &*(addr as *const Shared) };
+        let signal = shared.signal.load(Ordering::Relaxed);
+        match signal {
+            0 => (),
+            libc::SIGINT | libc::SIGTERM => {
+                // Blocked signals stay pending until they are unblocked.
+                if self.raise(signal) {
+                    RAISED.store(true, Ordering::Relaxed);
+                }
+            }
+            _ => self.attacked(),
+        }
+    }
+
+    /// Returns the address of the doorbell page, asking the host for it once
+    fn doorbell_page(&mut self) -> Option<usize> {
+        let state = *DOORBELL.lock();
+        match state {
+            State::Unknown => (),
+            State::Unavailable => return None,
+            State::Ready(addr) => return Some(addr),
+        }
+
+        let state = match unsafe { self.proxy(request!(SYS_ENARX_DOORBELL)) } {
+            Ok([addr, size]) => {
+                let (addr, size) = (usize::from(addr), usize::from(size));
+                if size != size_of::<Shared>() || addr % Page::SIZE != 0 {
+                    self.attacked();
+                }
+
+                self.untrusted(addr, size);
+                State::Ready(addr)
+            }
+
+            Err(_) => State::Unavailable,
+        };
+
+        *DOORBELL.lock() = state;
+        match state {
+            State::Ready(addr) => Some(addr),
+            _ => None,
+        }
+    }
+}
@@ -1,7 +1,69 @@
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::key;
+
+use core::convert::TryFrom;
+
+use enarx_syscall::{GETKEY_SVN, SYS_ENARX_ENVIRON};
+use sallyport::request;
 use sallyport::syscall::{BaseSyscallHandler, EnarxSyscallHandler};
-use sallyport::untrusted::{UntrustedRef, UntrustedRefMut};
+use sallyport::untrusted::{UntrustedRef, UntrustedRefMut, ValidateSlice};
+
+impl<'a> super::Handler<'a> {
+    /// Do a `SYS_ENARX_GETKEY` syscall
+    ///
+    /// The key is derived inside the enclave and never passes the host. With
+    /// `GETKEY_SVN` in the policy, it is the key of the older version `svn`
+    /// of the enclave, which the CPU only derives up to the enclave's own.
+    pub(super) fn get_key(
+        &mut self,
+        policy: usize,
+        buf: UntrustedRefMut<u8>,
+        buf_len: libc::size_t,
+        svn: usize,
+    ) -> sallyport::Result {
+        self.trace("get_key", 4);
+
+        let isvsvn = match policy & GETKEY_SVN {
+            0 => None,
+            _ => Some(u16::try_from(svn).map_err(|_| libc::EINVAL)?),
+        };
+
+        let policy = policy & !GETKEY_SVN;
+        if policy > u16::MAX as usize || buf_len < key::KEY_SIZE {
+            return Err(libc::EINVAL);
+        }
+
+        let buf = buf
+            .validate_slice(key::KEY_SIZE, self)
+            .ok_or(libc::EFAULT)?;
+        buf.copy_from_slice(&key::seal(policy as u16, isvsvn, &[0; 32])?);
+        Ok([key::KEY_SIZE.into(), Default::default()])
+    }
+
+    /// Do a `SYS_ENARX_ENVIRON` syscall
+    ///
+    /// The list is copied from the host as it is; the caller validates it.
+    pub(super) fn environ(
+        &mut self,
+        buf: UntrustedRefMut<u8>,
+        buf_len: usize,
+    ) -> sallyport::Result {
+        self.trace("environ", 2);
+
+        let buf = buf.validate_slice(buf_len, self).ok_or(libc::EFAULT)?;
+        let ret = unsafe { self.proxy(request!(SYS_ENARX_ENVIRON))? };
+
+        let len = usize::from(ret[0]);
+        if len > buf.len() {
+            self.attacked();
+        }
+
+        let c = self.new_cursor();
+        unsafe { c.copy_into_raw_parts(len, buf.as_mut_ptr(), len) }.or(Err(libc::EMSGSIZE))?;
+        Ok(ret)
+    }
+}
 
 impl<'a> EnarxSyscallHandler for super::Handler<'a> {
     // NOTE: The 'nonce' field is called 'hash' here, as it is used to pass in
@@ -1,28 +1,270 @@
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::edmm::{self, flags::*};
+
+use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use enarx_shim::syscall::{MADV_IGNORED, MCL_ONFAULT, MLOCK_ONFAULT};
+use enarx_syscall::{
+    SYS_ENARX_MEMORY, SYS_ENARX_SGX_AUG, SYS_ENARX_SGX_PROTECT, SYS_ENARX_SGX_REMOVE,
+    SYS_ENARX_SGX_TRIM,
+};
+use primordial::Page;
+use sallyport::request;
 use sallyport::syscall::{BaseSyscallHandler, MemorySyscallHandler};
 use sallyport::untrusted::UntrustedRef;
 
+/// The most bytes the payload has had above the start of the heap with `brk()`
+static BRK: AtomicUsize = AtomicUsize::new(0);
+
+/// The bytes the payload has mapped with `mmap()`
+static MAPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// The most bytes the payload has had mapped with `mmap()`
+static MAPPED_PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// The most bytes the payload has taken from the heap
+pub(super) fn used() -> usize {
+    BRK.load(Ordering::Relaxed) + MAPPED.load(Ordering::Relaxed)
+}
+
+/// Counts pages mapped by the payload
+fn mapped(length: usize) {
+    let length = (length + Page::SIZE - 1) / Page::SIZE * Page::SIZE;
+    let mapped = MAPPED.fetch_add(length, Ordering::Relaxed) + length;
+    MAPPED_PEAK.fetch_max(mapped, Ordering::Relaxed);
+}
+
+/// Counts pages unmapped by the payload
+fn unmapped(length: usize) {
+    let length = (length + Page::SIZE - 1) / Page::SIZE * Page::SIZE;
+    let _ = MAPPED.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |mapped| {
+        Some(mapped.saturating_sub(length))
+    });
+}
+
+impl<'a> super::Handler<'a> {
+    /// Reports the peak memory usage of the payload to the host
+    ///
+    /// The main stack starts out zeroed, so it grew as deep as the lowest
+    /// word which isn't zero.
+    pub(super) fn usage(&mut self) {
+        let stack = unsafe {
+            let start = &crate::ENARX_STACK_START as *const u8 as *const u64;
+            let end = &crate::ENARX_STACK_END as *const u8 as *const u64;
+            core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+        };
+        let unused = stack.iter().take_while(|word| **word == 0).count();
+        let stack = (stack.len() - unused) * size_of::<u64>();
+
+        let brk = BRK.load(Ordering::Relaxed);
+        let mmap = MAPPED_PEAK.load(Ordering::Relaxed);
+        let _ = unsafe { self.proxy(request!(SYS_ENARX_MEMORY => brk, mmap, stack)) };
+    }
+
+    /// Whether the platform supports SGX2 (CPUID.(EAX=12H, ECX=0):EAX[1])
+    pub(super) fn sgx2(&mut self) -> bool {
+        edmm::available(|| self.cpuid(0x12, 0)[0] & (1 << 1) != 0)
+    }
+
+    /// Whether a range lies entirely inside the dynamic region
+    fn dynamic(addr: usize, length: usize) -> bool {
+        let region = edmm::region();
+        addr >= region.start && addr.checked_add(length).map_or(false, |e| e <= region.end)
+    }
+
+    /// Changes the permissions of pages in the dynamic region
+    fn edmm_protect(&mut self, addr: usize, length: usize, prot: libc::c_int) -> sallyport::Result {
+        let perms = edmm::perms(prot);
+
+        // Extend first, since the host can only restrict permissions.
+        edmm::extend(addr, length, perms);
+
+        let req = request!(SYS_ENARX_SGX_PROTECT => addr, length, prot);
+        unsafe { self.proxy(req)? };
+
+        edmm::accept(addr, length, perms | PR | PT_REG)?;
+        edmm::protected(addr, length, prot);
+        Ok(Default::default())
+    }
+
+    /// Protects pages of the dynamic region again as they were recorded
+    ///
+    /// Pages which were added again are readable and writable.
+    fn edmm_reprotect(&mut self, addr: usize, length: usize) -> sallyport::Result {
+        let end = addr + length;
+        let mut start = addr;
+        while start < end {
+            let prot = edmm::protection(start);
+            let mut next = start + Page::SIZE;
+            while next < end && edmm::protection(next) == prot {
+                next += Page::SIZE;
+            }
+
+            if prot != libc::PROT_READ | libc::PROT_WRITE {
+                self.edmm_protect(start, next - start, prot)?;
+            }
+
+            start = next;
+        }
+
+        Ok(Default::default())
+    }
+
+    /// Maps new anonymous pages into the dynamic region
+    fn edmm_mmap(&mut self, length: usize, prot: libc::c_int) -> Result<usize, libc::c_int> {
+        let length = (length + Page::SIZE - 1) / Page::SIZE * Page::SIZE;
+        let addr = edmm::reserve(length).ok_or(libc::ENOMEM)?;
+
+        if let Err(e) = self.edmm_add(addr, length) {
+            edmm::release(addr, length);
+            return Err(e);
+        }
+
+        let rw = libc::PROT_READ | libc::PROT_WRITE;
+        edmm::protected(addr, length, rw);
+
+        // The pages are removed again, unless the host refuses, in which case
+        // their address space stays reserved.
+        if prot != rw {
+            if let Err(e) = self.edmm_protect(addr, length, prot) {
+                if self.edmm_remove(addr, length).is_ok() {
+                    edmm::release(addr, length);
+                }
+
+                return Err(e);
+            }
+        }
+
+        Ok(addr)
+    }
+
+    /// Unmaps pages of the dynamic region
+    fn edmm_munmap(&mut self, addr: usize, length: usize) -> sallyport::Result {
+        let length = (length + Page::SIZE - 1) / Page::SIZE * Page::SIZE;
+        self.edmm_remove(addr, length)?;
+
+        edmm::release(addr, length);
+        Ok(Default::default())
+    }
+
+    /// Adds pages to reserved address space of the dynamic region
+    ///
+    /// New pages are always readable and writable.
+    fn edmm_add(&mut self, addr: usize, length: usize) -> Result<(), libc::c_int> {
+        let req = request!(SYS_ENARX_SGX_AUG => addr, length);
+        unsafe { self.proxy(req)? };
+
+        edmm::accept(addr, length, R | W | PENDING | PT_REG)
+    }
+
+    /// Removes pages from the dynamic region, keeping their address space
+    fn edmm_remove(&mut self, addr: usize, length: usize) -> Result<(), libc::c_int> {
+        let req = request!(SYS_ENARX_SGX_TRIM => addr, length);
+        unsafe { self.proxy(req)? };
+
+        edmm::accept(addr, length, MODIFIED | PT_TRIM)?;
+
+        let req = request!(SYS_ENARX_SGX_REMOVE => addr, length);
+        unsafe { self.proxy(req)? };
+        Ok(())
+    }
+
+    /// Whether a range lies entirely inside the heap
+    fn heap(addr: usize, length: usize) -> bool {
+        let start = unsafe { &crate::ENARX_HEAP_START as *const u8 as usize };
+        let end = start + crate::HEAP.load(Ordering::Relaxed);
+        addr >= start && addr.checked_add(length).map_or(false, |e| e <= end)
+    }
+
+    /// Checks the range of a madvise() and rounds it up to whole pages
+    fn pages(addr: usize, length: usize) -> Result<usize, libc::c_int> {
+        if addr % Page::SIZE != 0 {
+            return Err(libc::EINVAL);
+        }
+
+        let length = length.checked_add(Page::SIZE - 1).ok_or(libc::ENOMEM)?;
+        let length = length / Page::SIZE * Page::SIZE;
+        addr.checked_add(length).ok_or(libc::ENOMEM)?;
+        Ok(length)
+    }
+
+    /// Do a mlock(), munlock() or mlock2() syscall
+    ///
+    /// The host can page out the pages of the enclave regardless, so locking
+    /// them has no effect.
+    pub(super) fn mlock(
+        &mut self,
+        nr: libc::c_long,
+        addr: usize,
+        length: usize,
+        flags: libc::c_int,
+    ) -> sallyport::Result {
+        match nr {
+            libc::SYS_mlock => self.trace("mlock", 2),
+            libc::SYS_munlock => self.trace("munlock", 2),
+            _ => self.trace("mlock2", 3),
+        }
+
+        if flags & !MLOCK_ONFAULT != 0 {
+            return Err(libc::EINVAL);
+        }
+
+        addr.checked_add(length).ok_or(libc::ENOMEM)?;
+        Ok(Default::default())
+    }
+
+    /// Do a mlockall() or munlockall() syscall, which have no effect either
+    pub(super) fn mlockall(&mut self, nr: libc::c_long, flags: libc::c_int) -> sallyport::Result {
+        if nr == libc::SYS_munlockall {
+            self.trace("munlockall", 0);
+            return Ok(Default::default());
+        }
+
+        self.trace("mlockall", 1);
+
+        let all = libc::MCL_CURRENT | libc::MCL_FUTURE | MCL_ONFAULT;
+        if flags & !all != 0 || flags & (libc::MCL_CURRENT | libc::MCL_FUTURE) == 0 {
+            return Err(libc::EINVAL);
+        }
+
+        Ok(Default::default())
+    }
+}
+
 impl<'a> MemorySyscallHandler for super::Handler<'a> {
     /// Do a brk() system call
     fn brk(&mut self, addr: *const u8) -> sallyport::Result {
         self.trace("brk", 1);
 
         let ret = self.heap.brk(addr as _);
+        let start = unsafe { &crate::ENARX_HEAP_START as *const u8 as usize };
+        BRK.fetch_max(ret.saturating_sub(start), Ordering::Relaxed);
         Ok([ret.into(), Default::default()])
     }
 
     /// Do a mprotect() system call
-    // Until EDMM, we can't change any page permissions.
-    // What you get is what you get. Fake success.
+    // Without SGX2, we can't change any page permissions and pages
+    // loaded at build time stay as they are. Fake success.
     fn mprotect(
         &mut self,
-        _addr: UntrustedRef<u8>,
-        _len: libc::size_t,
-        _prot: libc::c_int,
+        addr: UntrustedRef<u8>,
+        len: libc::size_t,
+        prot: libc::c_int,
     ) -> sallyport::Result {
         self.trace("mprotect", 3);
 
+        let addr = addr.as_ptr() as usize;
+        if addr % Page::SIZE != 0 {
+            return Err(libc::EINVAL);
+        }
+
+        if Self::dynamic(addr, len) && self.sgx2() {
+            let len = (len + Page::SIZE - 1) / Page::SIZE * Page::SIZE;
+            return self.edmm_protect(addr, len, prot);
+        }
+
         Ok(Default::default())
     }
 
@@ -45,8 +287,23 @@ impl<'a> MemorySyscallHandler for super::Handler<'a> {
             flags,
             fd, // Allow truncation!
             offset,
-        )?;
+        );
+
+        // When the heap is exhausted, fall back to adding pages dynamically.
+        let ret = match ret {
+            Err(libc::ENOMEM)
+                if addr.as_ptr().is_null()
+                    && length > 0
+                    && flags == libc::MAP_PRIVATE | libc::MAP_ANONYMOUS
+                    && self.sgx2() =>
+            {
+                self.edmm_mmap(length, prot)? as *mut libc::c_void
+            }
 
+            ret => ret?,
+        };
+
+        mapped(length);
         Ok([ret.into(), Default::default()])
     }
 
@@ -54,20 +311,53 @@ impl<'a> MemorySyscallHandler for super::Handler<'a> {
     fn munmap(&mut self, addr: UntrustedRef<u8>, length: libc::size_t) -> sallyport::Result {
         self.trace("munmap", 2);
 
-        self.heap
-            .munmap::<libc::c_void>(addr.as_ptr() as _, length)?;
+        let addr = addr.as_ptr() as usize;
+        if Self::dynamic(addr, length) {
+            if addr % Page::SIZE != 0 {
+                return Err(libc::EINVAL);
+            }
+
+            self.edmm_munmap(addr, length)?;
+        } else {
+            self.heap.munmap::<libc::c_void>(addr as _, length)?;
+        }
+
+        unmapped(length);
         Ok(Default::default())
     }
 
-    // Do madvise syscall
-    // We don't actually support this. So, fake success.
+    /// Do a madvise() system call
+    ///
+    /// Linux zeroes private anonymous pages for `MADV_DONTNEED`, which
+    /// allocators rely on. The pages of the heap are zeroed. Those of the
+    /// dynamic region may have been made read-only, so they are replaced
+    /// with new pages instead, which get the protection of the old ones. The
+    /// memory which was loaded with the enclave is left as it is.
     fn madvise(
         &mut self,
-        _addr: *const libc::c_void,
-        _length: libc::size_t,
-        _advice: libc::c_int,
+        addr: *const libc::c_void,
+        length: libc::size_t,
+        advice: libc::c_int,
     ) -> sallyport::Result {
         self.trace("madvise", 3);
+
+        let addr = addr as usize;
+        let length = Self::pages(addr, length)?;
+
+        match advice {
+            libc::MADV_DONTNEED if Self::dynamic(addr, length) && self.sgx2() => {
+                self.edmm_remove(addr, length)?;
+                self.edmm_add(addr, length)?;
+                self.edmm_reprotect(addr, length)?;
+            }
+            libc::MADV_DONTNEED if Self::heap(addr, length) => unsafe {
+                core::ptr::write_bytes(addr as *mut u8, 0, length);
+            },
+            libc::MADV_DONTNEED => (),
+            advice if MADV_IGNORED.contains(&advice) => (),
+            _ => return Err(libc::EINVAL),
+        }
+
         Ok(Default::default())
     }
 }
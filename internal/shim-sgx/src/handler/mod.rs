@@ -23,31 +23,66 @@ macro_rules! debugln {
     };
 }
 
+mod backtrace;
 mod base;
+mod batch;
+mod bounce;
+mod clock;
+mod deterministic;
+mod doorbell;
 mod enarx;
 mod file;
+mod fs;
 mod memory;
 mod other;
+mod poll;
 mod process;
+mod ring;
+mod sched;
+mod sealed;
+mod signal;
+mod system;
 
-use crate::ssa::{Gpr, Vector};
+use crate::entry;
+use crate::event::Event;
+use crate::ssa::{ExceptionInfo, Gpr, StateSaveArea, Vector};
 
 use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use enarx_heap::Heap;
+use enarx_shim::syscall::{
+    FsSyscallHandler, HostFileSyscallHandler, PollSyscallHandler, RandomSyscallHandler,
+    SleepSyscallHandler, SocketSyscallHandler, DUP_SYSCALLS, FD_SYSCALLS,
+};
+use enarx_syscall::{SYS_ENARX_ENVIRON, SYS_ENARX_GETKEY, SYS_ENARX_GETSECRET};
 use lset::Line;
+use memfs::MemFs;
+use primordial::Register;
 use sallyport::syscall::*;
 use sallyport::{request, Block};
+use xsave::XSave;
 
 // Opcode constants, details in Volume 2 of the Intel 64 and IA-32 Architectures Software
 // Developer's Manual
 const OP_SYSCALL: &[u8] = &[0x0f, 0x05];
 const OP_CPUID: &[u8] = &[0x0f, 0xa2];
 
+/// The index of the block holding the request of the current exit
+///
+/// It is passed to the host in the `Event` of the exit.
+pub static BLOCK: AtomicUsize = AtomicUsize::new(0);
+
 pub struct Handler<'a> {
     block: &'a mut Block,
+    queue: &'a mut Block,
     gpr: &'a mut Gpr,
+    xsave: &'a mut XSave,
     heap: Heap,
+    page_fault: Option<ExceptionInfo>,
+
+    /// The address of the TCS of the thread, which tells threads apart
+    tcs: usize,
 }
 
 impl<'a> Write for Handler<'a> {
@@ -71,48 +106,91 @@ impl<'a> Write for Handler<'a> {
 }
 
 impl<'a> Handler<'a> {
-    fn new(gpr: &'a mut Gpr, block: &'a mut Block, heap: Line<usize>) -> Self {
+    fn new(
+        gpr: &'a mut Gpr,
+        xsave: &'a mut XSave,
+        blocks: &'a mut [Block; crate::BLOCKS as usize],
+        heap: Line<usize>,
+        tcs: usize,
+    ) -> Self {
+        let [block, queue] = blocks;
+
         Self {
             gpr,
+            xsave,
             block,
+            queue,
             heap: unsafe { Heap::new(heap.into()) },
+            page_fault: None,
+            tcs,
         }
     }
 
+    /// Handles the host interrupting the payload
+    ///
+    /// The payload is resumed where it was interrupted, unless the doorbell
+    /// was rung, which raises its signal in the payload first.
+    pub fn interrupted(
+        ssa: &'a mut StateSaveArea,
+        blocks: &'a mut [Block; crate::BLOCKS as usize],
+        heap: Line<usize>,
+        tcs: usize,
+    ) {
+        let mut h = Self::new(&mut ssa.gpr, &mut ssa.xsave, blocks, heap, tcs);
+        h.doorbell();
+    }
+
     /// Finish handling an exception
-    pub fn finish(gpr: &'a mut Gpr) {
+    ///
+    /// Exceptions on a nested SSA frame come from the shim itself: either it
+    /// sends a request to the host, or it faulted, which it can't handle.
+    pub fn finish(gpr: &'a mut Gpr) -> Event {
         if let Some(Vector::InvalidOpcode) = gpr.exitinfo.exception() {
             if let OP_SYSCALL | OP_CPUID = unsafe { gpr.rip.into_slice(2usize) } {
                 // Skip the instruction.
                 let rip = usize::from(gpr.rip);
                 gpr.rip = (rip + 2).into();
-                return;
+                return Event::Request(BLOCK.load(Ordering::Relaxed));
             }
         }
 
-        unsafe { asm!("ud2", options(noreturn)) };
+        Event::Fault
     }
 
     /// Handle an exception
-    pub fn handle(gpr: &'a mut Gpr, block: &'a mut Block, heap: Line<usize>) {
-        let mut h = Self::new(gpr, block, heap);
+    pub fn handle(
+        ssa: &'a mut StateSaveArea,
+        blocks: &'a mut [Block; crate::BLOCKS as usize],
+        heap: Line<usize>,
+        tcs: usize,
+    ) {
+        let vector = ssa.gpr.exitinfo.exception();
+        let page_fault = vector.and_then(|v| ssa.misc.page_fault(v));
+
+        let mut h = Self::new(&mut ssa.gpr, &mut ssa.xsave, blocks, heap, tcs);
+        h.page_fault = page_fault;
 
         match h.gpr.exitinfo.exception() {
             Some(Vector::InvalidOpcode) => match unsafe { h.gpr.rip.into_slice(2usize) } {
+                // `rt_sigreturn()` replaces all registers, so it is not dispatched.
+                OP_SYSCALL if u64::from(h.gpr.rax) == libc::SYS_rt_sigreturn as u64 => {
+                    h.rt_sigreturn()
+                }
                 OP_SYSCALL => h.handle_syscall(),
                 OP_CPUID => h.handle_cpuid(),
                 r => {
                     debugln!(h, "unsupported opcode: {:?}", r);
-                    h.exit(1)
+                    h.fault(Vector::InvalidOpcode)
                 }
             },
 
-            _ => h.attacked(),
+            Some(vector) => h.fault(vector),
+            None => h.attacked(),
         }
     }
 
     fn handle_syscall(&mut self) {
-        let ret = self.syscall(
+        let ret = self.dispatch(
             self.gpr.rdi.into(),
             self.gpr.rsi.into(),
             self.gpr.rdx.into(),
@@ -131,17 +209,326 @@ impl<'a> Handler<'a> {
                 self.gpr.rdx = rdx.into();
             }
         }
+
+        self.doorbell();
+        self.restore_sigmask();
     }
 
-    fn handle_cpuid(&mut self) {
-        debug!(
-            self,
-            "cpuid({:08x}, {:08x})",
-            usize::from(self.gpr.rax),
-            usize::from(self.gpr.rcx)
-        );
+    /// Dispatch a syscall
+    ///
+    /// Syscalls implemented by the shim itself take precedence over those
+    /// implemented by `SyscallHandler`.
+    #[allow(clippy::many_single_char_names)]
+    fn dispatch(
+        &mut self,
+        a: Register<usize>,
+        b: Register<usize>,
+        c: Register<usize>,
+        d: Register<usize>,
+        e: Register<usize>,
+        f: Register<usize>,
+        nr: usize,
+    ) -> sallyport::Result {
+        match nr as libc::c_long {
+            SYS_ENARX_GETKEY => self.get_key(
+                usize::from(a),
+                (usize::from(b) as *mut u8).into(),
+                usize::from(c),
+                usize::from(d),
+            ),
+            // Secrets are only released to attested enclaves, which is not
+            // supported yet.
+            SYS_ENARX_GETSECRET => Err(libc::ENOSYS),
+            SYS_ENARX_ENVIRON => self.environ((usize::from(a) as *mut u8).into(), usize::from(b)),
+            libc::SYS_rt_sigaction => self.rt_sigaction(
+                usize::from(a) as _,
+                usize::from(b),
+                usize::from(c),
+                usize::from(d),
+            ),
+            libc::SYS_rt_sigprocmask => self.rt_sigprocmask(
+                usize::from(a) as _,
+                usize::from(b),
+                usize::from(c),
+                usize::from(d),
+            ),
+            libc::SYS_sigaltstack => self.sigaltstack(usize::from(a), usize::from(b)),
+            libc::SYS_sched_getaffinity => self.sched_getaffinity(usize::from(b), usize::from(c)),
+            libc::SYS_getcpu => self.getcpu(usize::from(a), usize::from(b)),
+            libc::SYS_mlock | libc::SYS_munlock => self.mlock(nr as _, a.into(), b.into(), 0),
+            libc::SYS_mlock2 => self.mlock(nr as _, a.into(), b.into(), usize::from(c) as _),
+            libc::SYS_mlockall | libc::SYS_munlockall => {
+                self.mlockall(nr as _, usize::from(a) as _)
+            }
+            libc::SYS_uname => self.uname(usize::from(a)),
+            libc::SYS_prctl => self.prctl(
+                usize::from(a) as _,
+                [b.into(), c.into(), d.into(), e.into()],
+            ),
+            libc::SYS_sysinfo => self.sysinfo(usize::from(a)),
+            libc::SYS_exit | libc::SYS_exit_group => {
+                self.usage();
+                self.syscall(a, b, c, d, e, f, nr)
+            }
+            libc::SYS_sendto => SocketSyscallHandler::sendto(
+                self,
+                usize::from(a) as _,
+                (usize::from(b) as *const u8).into(),
+                c.into(),
+                usize::from(d) as _,
+                (usize::from(e) as *const u8).into(),
+                usize::from(f) as _,
+            ),
+            libc::SYS_getsockopt => SocketSyscallHandler::getsockopt(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+                (usize::from(d) as *mut u8).into(),
+                (usize::from(e) as *mut libc::socklen_t).into(),
+            ),
+            libc::SYS_getpeername => SocketSyscallHandler::getpeername(
+                self,
+                usize::from(a) as _,
+                (usize::from(b) as *mut u8).into(),
+                (usize::from(c) as *mut libc::socklen_t).into(),
+            ),
+            libc::SYS_shutdown => {
+                SocketSyscallHandler::shutdown(self, usize::from(a) as _, usize::from(b) as _)
+            }
+            libc::SYS_socketpair => SocketSyscallHandler::socketpair(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+                (usize::from(d) as *mut libc::c_int).into(),
+            ),
+            libc::SYS_epoll_create => match usize::from(a) as libc::c_int {
+                size if size <= 0 => Err(libc::EINVAL),
+                _ => PollSyscallHandler::epoll_create1(self, 0),
+            },
+            libc::SYS_epoll_create1 => PollSyscallHandler::epoll_create1(self, usize::from(a) as _),
+            libc::SYS_epoll_ctl => PollSyscallHandler::epoll_ctl(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+                (usize::from(d) as *const libc::epoll_event).into(),
+            ),
+            libc::SYS_epoll_wait => PollSyscallHandler::epoll_wait(
+                self,
+                usize::from(a) as _,
+                (usize::from(b) as *mut libc::epoll_event).into(),
+                usize::from(c) as _,
+                usize::from(d) as _,
+            ),
+            libc::SYS_epoll_pwait => self.epoll_pwait(
+                usize::from(a) as _,
+                (usize::from(b) as *mut libc::epoll_event).into(),
+                usize::from(c) as _,
+                usize::from(d) as _,
+                usize::from(e),
+                usize::from(f),
+            ),
+            libc::SYS_eventfd => PollSyscallHandler::eventfd2(self, usize::from(a) as _, 0),
+            libc::SYS_eventfd2 => {
+                PollSyscallHandler::eventfd2(self, usize::from(a) as _, usize::from(b) as _)
+            }
+            libc::SYS_timerfd_create => {
+                PollSyscallHandler::timerfd_create(self, usize::from(a) as _, usize::from(b) as _)
+            }
+            libc::SYS_timerfd_settime => PollSyscallHandler::timerfd_settime(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                (usize::from(c) as *const libc::itimerspec).into(),
+                usize::from(d) as *mut libc::itimerspec,
+            ),
+            libc::SYS_timerfd_gettime => PollSyscallHandler::timerfd_gettime(
+                self,
+                usize::from(a) as _,
+                (usize::from(b) as *mut libc::itimerspec).into(),
+            ),
+            libc::SYS_poll => PollSyscallHandler::poll(
+                self,
+                (usize::from(a) as *mut libc::pollfd).into(),
+                usize::from(b) as _,
+                usize::from(c) as _,
+            ),
+            libc::SYS_select => PollSyscallHandler::select(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as *mut libc::fd_set,
+                usize::from(c) as *mut libc::fd_set,
+                usize::from(d) as *mut libc::fd_set,
+                usize::from(e) as *mut libc::timeval,
+            ),
+            libc::SYS_open => FsSyscallHandler::openat(
+                self,
+                libc::AT_FDCWD,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+            ),
+            libc::SYS_openat => FsSyscallHandler::openat(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+                usize::from(d) as _,
+            ),
+            libc::SYS_creat => FsSyscallHandler::openat(
+                self,
+                libc::AT_FDCWD,
+                usize::from(a) as _,
+                libc::O_CREAT | libc::O_WRONLY | libc::O_TRUNC,
+                usize::from(b) as _,
+            ),
+            libc::SYS_stat => FsSyscallHandler::newfstatat(
+                self,
+                libc::AT_FDCWD,
+                usize::from(a) as _,
+                (usize::from(b) as *mut libc::stat).into(),
+                0,
+            ),
+            libc::SYS_lstat => FsSyscallHandler::newfstatat(
+                self,
+                libc::AT_FDCWD,
+                usize::from(a) as _,
+                (usize::from(b) as *mut libc::stat).into(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            ),
+            libc::SYS_newfstatat => FsSyscallHandler::newfstatat(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                (usize::from(c) as *mut libc::stat).into(),
+                usize::from(d) as _,
+            ),
+            libc::SYS_access => FsSyscallHandler::faccessat(
+                self,
+                libc::AT_FDCWD,
+                usize::from(a) as _,
+                usize::from(b) as _,
+            ),
+            libc::SYS_faccessat => FsSyscallHandler::faccessat(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+            ),
+            libc::SYS_mkdir => FsSyscallHandler::mkdirat(
+                self,
+                libc::AT_FDCWD,
+                usize::from(a) as _,
+                usize::from(b) as _,
+            ),
+            libc::SYS_mkdirat => FsSyscallHandler::mkdirat(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+            ),
+            libc::SYS_rmdir => FsSyscallHandler::unlinkat(
+                self,
+                libc::AT_FDCWD,
+                usize::from(a) as _,
+                libc::AT_REMOVEDIR,
+            ),
+            libc::SYS_unlink => {
+                FsSyscallHandler::unlinkat(self, libc::AT_FDCWD, usize::from(a) as _, 0)
+            }
+            libc::SYS_unlinkat => FsSyscallHandler::unlinkat(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+            ),
+            libc::SYS_rename => FsSyscallHandler::renameat2(
+                self,
+                libc::AT_FDCWD,
+                usize::from(a) as _,
+                libc::AT_FDCWD,
+                usize::from(b) as _,
+                0,
+            ),
+            libc::SYS_renameat => FsSyscallHandler::renameat2(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+                usize::from(d) as _,
+                0,
+            ),
+            libc::SYS_renameat2 => FsSyscallHandler::renameat2(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+                usize::from(d) as _,
+                usize::from(e) as _,
+            ),
+            n if FD_SYSCALLS.contains(&n) && sealed::owns(usize::from(a) as _) => {
+                self.sealed(usize::from(a) as _, b, c, d, n)
+            }
+            n if MemFs::owns(usize::from(a) as _) && FD_SYSCALLS.contains(&n) => {
+                FsSyscallHandler::memfs(self, usize::from(a) as _, b, c, d, n)
+            }
+            n if MemFs::owns(usize::from(a) as _) && DUP_SYSCALLS.contains(&n) => {
+                FsSyscallHandler::memfs(self, usize::from(a) as _, b, c, d, n)
+            }
+            libc::SYS_dup | libc::SYS_dup2 | libc::SYS_dup3 => {
+                HostFileSyscallHandler::dup(self, nr as _, [a.into(), b.into(), c.into()])
+            }
+            libc::SYS_fcntl => HostFileSyscallHandler::fcntl(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                usize::from(c) as _,
+            ),
+            libc::SYS_pipe => {
+                HostFileSyscallHandler::pipe2(self, (usize::from(a) as *mut libc::c_int).into(), 0)
+            }
+            libc::SYS_pipe2 => HostFileSyscallHandler::pipe2(
+                self,
+                (usize::from(a) as *mut libc::c_int).into(),
+                usize::from(b) as _,
+            ),
+            libc::SYS_pread64 => HostFileSyscallHandler::pread64(self, a, b, c, d),
+            libc::SYS_pwrite64 => HostFileSyscallHandler::pwrite64(self, a, b, c, d),
+            libc::SYS_write if self.deferrable(a, c) => self.write_deferred(a, b, c),
+            libc::SYS_close if batch::deferred(a) => self.close_deferred(a),
+            libc::SYS_read if self.bounceable(c) => self.read_bounced(a, b, c),
+            libc::SYS_write if self.bounceable(c) => self.write_bounced(a, b, c),
+            libc::SYS_getrandom => RandomSyscallHandler::getrandom(self, a, b, c),
+            libc::SYS_nanosleep => SleepSyscallHandler::nanosleep(
+                self,
+                (usize::from(a) as *const libc::timespec).into(),
+                usize::from(b) as *mut libc::timespec,
+            ),
+            libc::SYS_clock_nanosleep => SleepSyscallHandler::clock_nanosleep(
+                self,
+                usize::from(a) as _,
+                usize::from(b) as _,
+                (usize::from(c) as *const libc::timespec).into(),
+                usize::from(d) as *mut libc::timespec,
+            ),
+            libc::SYS_clock_gettime if self.clock_local(a) => self.clock_gettime_local(a, b),
+            libc::SYS_getdents64 => FsSyscallHandler::getdents64(
+                self,
+                usize::from(a) as _,
+                (usize::from(b) as *mut u8).into(),
+                usize::from(c),
+            ),
+            _ => self.syscall(a, b, c, d, e, f, nr),
+        }
+    }
 
-        self.block.msg.req = request!(SYS_ENARX_CPUID => self.gpr.rax, self.gpr.rcx);
+    /// Execute `cpuid` on the host
+    ///
+    /// The results are untrusted.
+    fn cpuid(&mut self, leaf: usize, subleaf: usize) -> [usize; 4] {
+        self.block.msg.req = request!(SYS_ENARX_CPUID => leaf, subleaf);
 
         unsafe {
             // prevent earlier writes from being moved beyond this point
@@ -152,11 +539,28 @@ impl<'a> Handler<'a> {
             // prevent later reads from being moved before this point
             core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::Acquire);
 
-            self.gpr.rax = self.block.msg.req.arg[0].into();
-            self.gpr.rbx = self.block.msg.req.arg[1].into();
-            self.gpr.rcx = self.block.msg.req.arg[2].into();
-            self.gpr.rdx = self.block.msg.req.arg[3].into();
+            [
+                self.block.msg.req.arg[0].into(),
+                self.block.msg.req.arg[1].into(),
+                self.block.msg.req.arg[2].into(),
+                self.block.msg.req.arg[3].into(),
+            ]
         }
+    }
+
+    fn handle_cpuid(&mut self) {
+        debug!(
+            self,
+            "cpuid({:08x}, {:08x})",
+            usize::from(self.gpr.rax),
+            usize::from(self.gpr.rcx)
+        );
+
+        let [eax, ebx, ecx, edx] = self.cpuid(self.gpr.rax.into(), self.gpr.rcx.into());
+        self.gpr.rax = eax.into();
+        self.gpr.rbx = ebx.into();
+        self.gpr.rcx = ecx.into();
+        self.gpr.rdx = edx.into();
 
         debugln!(
             self,
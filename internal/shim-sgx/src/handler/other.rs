@@ -2,12 +2,34 @@
 
 use super::Handler;
 
+use enarx_shim::syscall::{
+    PollSyscallHandler, RandomSyscallHandler, SleepSyscallHandler, SocketSyscallHandler,
+};
 use sallyport::syscall::{NetworkSyscallHandler, SyscallHandler, SystemSyscallHandler};
 use sallyport::untrusted::AddressValidator;
 
 impl<'a> NetworkSyscallHandler for Handler<'a> {}
 impl<'a> SystemSyscallHandler for Handler<'a> {}
 impl<'a> SyscallHandler for Handler<'a> {}
+impl<'a> SocketSyscallHandler for Handler<'a> {}
+impl<'a> PollSyscallHandler for Handler<'a> {}
+
+impl<'a> RandomSyscallHandler for Handler<'a> {
+    fn proxied_random(&mut self) -> bool {
+        self.deterministic()
+    }
+}
+
+impl<'a> SleepSyscallHandler for Handler<'a> {
+    fn proxied_sleep(&mut self) -> bool {
+        self.deterministic()
+    }
+
+    /// `RDTSC` is only legal in enclaves on SGX2 platforms.
+    fn tsc_readable(&mut self) -> bool {
+        self.sgx2()
+    }
+}
 
 impl<'a> AddressValidator for Handler<'a> {
     fn validate_const_mem_fn(&self, _ptr: *const (), _size: usize) -> bool {
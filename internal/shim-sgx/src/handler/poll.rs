@@ -0,0 +1,26 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! I/O multiplexing syscalls which depend on the signals of the shim
+//!
+//! The others are handled by `enarx_shim::syscall::PollSyscallHandler`.
+
+use enarx_shim::syscall::PollSyscallHandler;
+use sallyport::untrusted::UntrustedRefMut;
+
+impl<'a> super::Handler<'a> {
+    /// Do an epoll_pwait() syscall
+    ///
+    /// The signal mask is replaced until the syscall returns.
+    pub(super) fn epoll_pwait(
+        &mut self,
+        epfd: libc::c_int,
+        events: UntrustedRefMut<libc::epoll_event>,
+        maxevents: libc::c_int,
+        timeout: libc::c_int,
+        sigmask: usize,
+        sigsetsize: usize,
+    ) -> sallyport::Result {
+        self.replace_sigmask(sigmask, sigsetsize)?;
+        PollSyscallHandler::epoll_wait(self, epfd, events, maxevents, timeout)
+    }
+}
@@ -1,20 +1,94 @@
 // SPDX-License-Identifier: Apache-2.0
 
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use sallyport::syscall::{BaseSyscallHandler, ProcessSyscallHandler};
 use sallyport::syscall::{ARCH_GET_FS, ARCH_GET_GS, ARCH_SET_FS, ARCH_SET_GS};
+use sallyport::untrusted::{UntrustedRef, UntrustedRefMut, Validate};
+use spinning::{Mutex, RawMutex};
+
+/// The end of the lower half of the canonical address space
+///
+/// `ERESUME` fails on non-canonical segment bases, so they are refused.
+const USER_END: libc::c_ulong = 1 << 47;
+
+/// The name of the payload, which `PR_GET_NAME` returns, NUL-terminated
+static NAME: Mutex<[u8; 16]> =
+    Mutex::const_new(RawMutex::const_new(), *b"enarx\0\0\0\0\0\0\0\0\0\0\0");
+
+/// Whether the payload has set `PR_SET_NO_NEW_PRIVS`
+static NO_NEW_PRIVS: AtomicBool = AtomicBool::new(false);
+
+impl<'a> super::Handler<'a> {
+    /// Do a `prctl()` syscall
+    ///
+    /// Only the options which runtimes use early are known. The payload
+    /// can't gain privileges in the keep anyway, so `PR_SET_NO_NEW_PRIVS`
+    /// is only remembered.
+    pub(super) fn prctl(&mut self, option: libc::c_int, args: [usize; 4]) -> sallyport::Result {
+        self.trace("prctl", 5);
+
+        match option {
+            libc::PR_SET_NAME => {
+                // The name is truncated to 15 bytes, and might end before
+                // a page which isn't mapped.
+                let mut name = [0u8; 16];
+                for (i, byte) in name.iter_mut().take(15).enumerate() {
+                    let addr = args[0].checked_add(i).ok_or(libc::EFAULT)?;
+                    let c = UntrustedRef::from(addr as *const u8);
+                    *byte = *c.validate(self).ok_or(libc::EFAULT)?;
+                    if *byte == 0 {
+                        break;
+                    }
+                }
+
+                *NAME.lock() = name;
+                Ok(Default::default())
+            }
+
+            libc::PR_GET_NAME => {
+                let name = UntrustedRefMut::from(args[0] as *mut [u8; 16]);
+                *name.validate(self).ok_or(libc::EFAULT)? = *NAME.lock();
+                Ok(Default::default())
+            }
+
+            libc::PR_SET_NO_NEW_PRIVS if args == [1, 0, 0, 0] => {
+                NO_NEW_PRIVS.store(true, Ordering::Relaxed);
+                Ok(Default::default())
+            }
+
+            libc::PR_GET_NO_NEW_PRIVS if args == [0; 4] => {
+                let set = NO_NEW_PRIVS.load(Ordering::Relaxed) as usize;
+                Ok([set.into(), Default::default()])
+            }
+
+            _ => Err(libc::EINVAL),
+        }
+    }
+}
 
 impl<'a> ProcessSyscallHandler for super::Handler<'a> {
     /// Do an arch_prctl() syscall
+    ///
+    /// The segment bases are kept in the SSA, from where `ERESUME`
+    /// restores them. This is how payloads set up their thread pointer.
     fn arch_prctl(&mut self, code: libc::c_int, addr: libc::c_ulong) -> sallyport::Result {
         self.trace("arch_prctl", 2);
 
-        // TODO: Check that addr in %rdx does not point to an unmapped address
-        // and is not outside of the process address space.
         match code {
+            ARCH_SET_FS | ARCH_SET_GS if addr >= USER_END => return Err(libc::EPERM),
             ARCH_SET_FS => self.gpr.fsbase = addr.into(),
             ARCH_SET_GS => self.gpr.gsbase = addr.into(),
-            ARCH_GET_FS => return Err(libc::ENOSYS),
-            ARCH_GET_GS => return Err(libc::ENOSYS),
+            ARCH_GET_FS | ARCH_GET_GS => {
+                let base = match code {
+                    ARCH_GET_FS => self.gpr.fsbase,
+                    _ => self.gpr.gsbase,
+                };
+
+                let addr = UntrustedRefMut::from(addr as *mut libc::c_ulong);
+                let addr = addr.validate(self).ok_or(libc::EFAULT)?;
+                *addr = base.into();
+            }
             _ => return Err(libc::EINVAL),
         }
 
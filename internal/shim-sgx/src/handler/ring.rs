@@ -0,0 +1,229 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Asynchronous writes
+//!
+//! If the host offers a syscall ring, deferred writes are submitted to it
+//! instead of being queued in their block. A thread of the host executes them
+//! while the payload keeps running. The ring is drained before anything else
+//! is sent to the host, so the host still executes all requests in order.
+//!
+//! The ring lives in untrusted memory: only the requests and the data of the
+//! writes are placed there, and the replies are checked like any other. The
+//! errors of the writes are kept like those of queued writes.
+//!
+//! The host only executes writes to stdout and stderr from the ring. Its
+//! thread sleeps when the ring stays empty, and sets `NEED_WAKEUP` in `flags`
+//! until it is woken with `SYS_ENARX_RING(1)`.
+
+use core::mem::size_of;
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+use core::sync::atomic::{fence, AtomicU32, Ordering};
+
+use enarx_syscall::SYS_ENARX_RING;
+use sallyport::syscall::BaseSyscallHandler;
+use sallyport::{request, Reply, Request};
+use spinning::{Mutex, RawMutex};
+
+/// The number of entries in each queue
+const ENTRIES: usize = 64;
+
+/// The size of the data of each submission
+const DATA: usize = 4096;
+
+/// Set in `flags` while the thread of the host sleeps
+const NEED_WAKEUP: u32 = 1;
+
+/// A request and a value which is passed on to its reply
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Submission {
+    user: u64,
+    req: Request,
+}
+
+/// A reply and the value of its request
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Completion {
+    user: u64,
+    rep: Reply,
+}
+
+/// The memory shared with the host
+///
+/// The layout must match the one used by the host.
+#[repr(C, align(4096))]
+struct Shared {
+    sq_head: AtomicU32,
+    sq_tail: AtomicU32,
+    cq_head: AtomicU32,
+    cq_tail: AtomicU32,
+    flags: AtomicU32,
+    sqes: [Submission; ENTRIES],
+    cqes: [Completion; ENTRIES],
+    data: [[u8; DATA]; ENTRIES],
+}
+
+/// The writes in flight
+#[derive(Copy, Clone)]
+struct Ring {
+    /// The address of the shared memory
+    addr: usize,
+
+    /// The number of submitted writes
+    submitted: u32,
+
+    /// The number of completed writes
+    reaped: u32,
+
+    /// The submitted writes, by their index modulo `ENTRIES`
+    reqs: [Request; ENTRIES],
+}
+
+#[derive(Copy, Clone)]
+enum State {
+    /// The host hasn't been asked yet
+    Unknown,
+
+    /// The host has no ring
+    Unavailable,
+
+    Ready(Ring),
+}
+
+static RING: Mutex<State> = Mutex::const_new(RawMutex::const_new(), State::Unknown);
+
+impl<'a> super::Handler<'a> {
+    /// Submits a deferred write to the ring
+    ///
+    /// `None` is returned if the write has to be queued.
+    pub(super) fn submit(&mut self, fd: usize, data: &[u8]) -> Option<sallyport::Result> {
+        if data.len() > DATA {
+            return None;
+        }
+
+        let state = *RING.lock();
+        let mut ring = match state {
+            State::Unknown => self.setup()?,
+            State::Unavailable => return None,
+            State::Ready(ring) => ring,
+        };
+
+        if ring.submitted.wrapping_sub(ring.reaped) as usize == ENTRIES {
+            self.drain(&mut ring);
+        }
+
+        let shared = ring.addr as *mut Shared;
+        let index = ring.submitted as usize % ENTRIES;
+        let sqe = unsafe {
+            let buf = addr_of_mut!((*shared).data[index]).cast::<u8>();
+            buf.copy_from_nonoverlapping(data.as_ptr(), data.len());
+
+            let ptr = Self::translate_shim_to_host_addr(buf);
+            Submission {
+                user: ring.submitted.into(),
+                req: request!(libc::SYS_write => fd, ptr, data.len()),
+            }
+        };
+
+        let sleeps = unsafe {
+            write_volatile(addr_of_mut!((*shared).sqes[index]), sqe);
+            let tail = ring.submitted.wrapping_add(1);
+            (*shared).sq_tail.store(tail, Ordering::Release);
+
+            // The host sets the flag before it checks `sq_tail` a last time,
+            // so one of the two sees what the other did.
+            fence(Ordering::SeqCst);
+            (*shared).flags.load(Ordering::Relaxed) & NEED_WAKEUP != 0
+        };
+
+        ring.reqs[index] = sqe.req;
+        ring.submitted = ring.submitted.wrapping_add(1);
+        *RING.lock() = State::Ready(ring);
+
+        if sleeps {
+            let _ = unsafe { self.exchange(request!(SYS_ENARX_RING => 1)) };
+        }
+
+        Some(Ok([data.len().into(), 0.into()]))
+    }
+
+    /// Waits for the host to complete the submitted writes
+    ///
+    /// This must be done before anything else is sent to the host.
+    pub(super) fn drain_ring(&mut self) {
+        let state = *RING.lock();
+        if let State::Ready(mut ring) = state {
+            self.drain(&mut ring);
+            *RING.lock() = State::Ready(ring);
+        }
+    }
+
+    /// Asks the host for the ring
+    fn setup(&mut self) -> Option<Ring> {
+        let ret = unsafe { self.exchange(request!(SYS_ENARX_RING => 0)) };
+        let (addr, size) = match ret {
+            Ok([addr, size]) => (usize::from(addr), usize::from(size)),
+            Err(_) => {
+                *RING.lock() = State::Unavailable;
+                return None;
+            }
+        };
+
+        if size != size_of::<Shared>() || addr % DATA != 0 {
+            self.attacked();
+        }
+        self.untrusted(addr, size);
+
+        let ring = Ring {
+            addr,
+            submitted: 0,
+            reaped: 0,
+            reqs: [request!(libc::SYS_write); ENTRIES],
+        };
+
+        *RING.lock() = State::Ready(ring);
+        Some(ring)
+    }
+
+    /// Waits for all completions of a ring
+    fn drain(&mut self, ring: &mut Ring) {
+        let shared = ring.addr as *mut Shared;
+
+        while ring.reaped != ring.submitted {
+            let tail = unsafe { (*shared).cq_tail.load(Ordering::Acquire) };
+            let ready = tail.wrapping_sub(ring.reaped);
+            if ready == 0 {
+                core::hint::spin_loop();
+                continue;
+            }
+
+            if ready > ring.submitted.wrapping_sub(ring.reaped) {
+                self.abandon();
+            }
+
+            let index = ring.reaped as usize % ENTRIES;
+            let cqe = unsafe { read_volatile(addr_of!((*shared).cqes[index])) };
+            if cqe.user != u64::from(ring.reaped) {
+                self.abandon();
+            }
+
+            ring.reaped = ring.reaped.wrapping_add(1);
+            unsafe { (*shared).cq_head.store(ring.reaped, Ordering::Release) };
+
+            let req = ring.reqs[index];
+            let done: sallyport::Result = cqe.rep.into();
+            if let Err(errno) = done.and_then(|done| self.complete(req, done[0].into())) {
+                self.fail(req, errno);
+            }
+        }
+    }
+
+    /// Stops using the ring before tripping the circuit breaker
+    ///
+    /// Exiting sends a request to the host, which would drain the ring again.
+    fn abandon(&mut self) -> ! {
+        *RING.lock() = State::Unavailable;
+        self.attacked()
+    }
+}
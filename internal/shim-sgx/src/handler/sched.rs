@@ -0,0 +1,58 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The CPUs of the payload
+//!
+//! The payload is shown the CPUs of the keep instead of those of the host,
+//! so that runtimes size their thread pools for the keep. The enclave has a
+//! single TCS, so the payload runs on a single CPU.
+
+use core::mem::size_of;
+
+use sallyport::syscall::BaseSyscallHandler;
+use sallyport::untrusted::{UntrustedRefMut, Validate, ValidateSlice};
+
+/// The number of CPUs of the keep: one per TCS
+const CPUS: usize = 1;
+
+impl<'a> super::Handler<'a> {
+    /// Do a `sched_getaffinity()` syscall
+    ///
+    /// The payload may run on every CPU of the keep. As on Linux, the set
+    /// must have room for all of them in whole words, and the size of the
+    /// part which was written is returned. The keep runs a single process,
+    /// so the pid is not checked.
+    pub(super) fn sched_getaffinity(&mut self, len: usize, mask: usize) -> sallyport::Result {
+        self.trace("sched_getaffinity", 3);
+
+        let bits = 8 * size_of::<libc::c_ulong>();
+        let size = (CPUS + bits - 1) / bits * size_of::<libc::c_ulong>();
+        if len < size || len % size_of::<libc::c_ulong>() != 0 {
+            return Err(libc::EINVAL);
+        }
+
+        let mask = UntrustedRefMut::from(mask as *mut u8);
+        let mask = mask.validate_slice(size, self).ok_or(libc::EFAULT)?;
+        for (i, byte) in mask.iter_mut().enumerate() {
+            *byte = match CPUS.saturating_sub(i * 8) {
+                n if n >= 8 => 0xff,
+                n => (1 << n) - 1,
+            };
+        }
+
+        Ok([size.into(), 0.into()])
+    }
+
+    /// Do a `getcpu()` syscall
+    ///
+    /// The payload runs on the first CPU, which is in the only node.
+    pub(super) fn getcpu(&mut self, cpu: usize, node: usize) -> sallyport::Result {
+        self.trace("getcpu", 3);
+
+        for addr in [cpu, node].iter().filter(|addr| **addr != 0) {
+            let id = UntrustedRefMut::from(*addr as *mut libc::c_uint);
+            *id.validate(self).ok_or(libc::EFAULT)? = 0;
+        }
+
+        Ok(Default::default())
+    }
+}
@@ -0,0 +1,456 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Files in sealed mounts
+//!
+//! Regular files opened beneath a sealed mount are encrypted and
+//! authenticated with a key derived from the measurement of the enclave (see
+//! the `seal` crate). The key is bound to the security version of the
+//! platform, too, so the files sealed before an update of the platform
+//! which fixes a vulnerability can't be read after it. The host only ever sees the sealed backing file; reads
+//! and writes on the descriptor are handled here.
+//!
+//! Directories and file names are not sealed. Syscalls not handled here (e.g.
+//! `mmap()` or `dup()`) operate on the sealed backing file.
+
+use core::cmp::min;
+
+use enarx_shim::syscall::FsSyscallHandler;
+use libc::c_int;
+use memfs::MemFs;
+use primordial::Register;
+use sallyport::request;
+use sallyport::syscall::{BaseSyscallHandler, SyscallHandler};
+use sallyport::untrusted::{UntrustedRef, UntrustedRefMut, Validate, ValidateSlice};
+use seal::{SealedFile, Sealer};
+use spinning::{Mutex, RawMutex};
+
+use crate::key;
+
+/// The KEYID of the seal key of sealed mounts
+///
+/// Payloads request seal keys with a zero KEYID (see `enarx.rs`), so they
+/// can't obtain this one.
+const KEYID: [u8; 32] = *b"enarx-keepldr sealed mounts v1\0\0";
+
+/// The maximum number of sealed files open at once
+const MAX_SEALED: usize = 16;
+
+/// The maximum number of bytes moved through the block at once
+const MAX_IO: usize = 2048;
+
+/// An open sealed file
+#[derive(Copy, Clone)]
+struct Entry {
+    fd: c_int,
+    file: SealedFile,
+    flags: c_int,
+    offset: u64,
+}
+
+/// The open sealed files, by host descriptor
+static SEALED: Mutex<[Option<Entry>; MAX_SEALED]> =
+    Mutex::const_new(RawMutex::const_new(), [None; MAX_SEALED]);
+
+/// The sealer, created on first use
+static SEALER: Mutex<Option<Sealer>> = Mutex::const_new(RawMutex::const_new(), None);
+
+/// Runs `f` with the sealer, deriving the seal key if needed
+fn with_sealer<T>(f: impl FnOnce(&Sealer) -> Result<T, c_int>) -> Result<T, c_int> {
+    let mut sealer = SEALER.lock();
+    if sealer.is_none() {
+        *sealer = Some(Sealer::new(&key::seal(
+            key::policy::MRENCLAVE,
+            None,
+            &KEYID,
+        )?));
+    }
+
+    f(sealer.as_ref().unwrap())
+}
+
+/// Returns whether a host descriptor refers to a sealed file
+pub fn owns(fd: c_int) -> bool {
+    SEALED.lock().iter().flatten().any(|e| e.fd == fd)
+}
+
+/// The sealed backing file on the host
+struct Backing<'h, 'a> {
+    handler: &'h mut super::Handler<'a>,
+    fd: c_int,
+}
+
+impl<'h, 'a> seal::Host for Backing<'h, 'a> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, c_int> {
+        let mut done = 0;
+
+        while done < buf.len() {
+            let len = min(buf.len() - done, MAX_IO);
+            let pos = (offset + done as u64) as usize;
+
+            let c = self.handler.new_cursor();
+            let (_, hbuf) = c.alloc::<u8>(len).or(Err(libc::EMSGSIZE))?;
+            let hbuf = hbuf.as_ptr();
+
+            let req = request!(libc::SYS_pread64 => self.fd, hbuf, len, pos);
+            let ret: usize = unsafe { self.handler.proxy(req)? }[0].into();
+            if ret > len {
+                self.handler.attacked();
+            }
+
+            let c = self.handler.new_cursor();
+            unsafe { c.copy_into_raw_parts(len, buf[done..].as_mut_ptr(), ret) }
+                .or(Err(libc::EMSGSIZE))?;
+
+            if ret == 0 {
+                break;
+            }
+
+            done += ret;
+        }
+
+        Ok(done)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), c_int> {
+        let mut done = 0;
+
+        while done < buf.len() {
+            let len = min(buf.len() - done, MAX_IO);
+            let pos = (offset + done as u64) as usize;
+
+            let c = self.handler.new_cursor();
+            let (_, hbuf) = c
+                .copy_from_slice(&buf[done..done + len])
+                .or(Err(libc::EMSGSIZE))?;
+            let hbuf = hbuf.as_ptr();
+
+            let req = request!(libc::SYS_pwrite64 => self.fd, hbuf, len, pos);
+            let ret: usize = unsafe { self.handler.proxy(req)? }[0].into();
+            match ret {
+                0 => return Err(libc::EIO),
+                ret if ret > len => self.handler.attacked(),
+                ret => done += ret,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<(), c_int> {
+        let req = request!(libc::SYS_ftruncate => self.fd, len as usize);
+        unsafe { self.handler.proxy(req)? };
+        Ok(())
+    }
+
+    fn random(&mut self, buf: &mut [u8]) -> Result<(), c_int> {
+        match enarx_shim::random::fill(buf) {
+            true => Ok(()),
+            false => Err(libc::EIO),
+        }
+    }
+}
+
+impl<'a> super::Handler<'a> {
+    /// Gets the status of a host descriptor
+    fn host_fstat(&mut self, fd: c_int) -> Result<libc::stat, c_int> {
+        let c = self.new_cursor();
+        let (_, hstat) = c.alloc::<libc::stat>(1).or(Err(libc::EMSGSIZE))?;
+        let hstat = hstat.as_ptr();
+
+        unsafe { self.proxy(request!(libc::SYS_fstat => fd, hstat))? };
+
+        let mut stat: libc::stat = unsafe { core::mem::zeroed() };
+        let c = self.new_cursor();
+        unsafe { c.copy_into_raw_parts(1, &mut stat as *mut libc::stat, 1) }
+            .or(Err(libc::EMSGSIZE))?;
+        Ok(stat)
+    }
+
+    /// Closes a host descriptor after a failed open
+    fn host_close(&mut self, fd: c_int, err: c_int) -> sallyport::Result {
+        let _ = unsafe { self.proxy(request!(libc::SYS_close => fd)) };
+        Err(err)
+    }
+
+    /// Handles a syscall on a sealed file
+    pub(super) fn sealed(
+        &mut self,
+        fd: c_int,
+        b: Register<usize>,
+        c: Register<usize>,
+        d: Register<usize>,
+        nr: libc::c_long,
+    ) -> sallyport::Result {
+        let mut entry = SEALED
+            .lock()
+            .iter()
+            .flatten()
+            .find(|e| e.fd == fd)
+            .copied()
+            .ok_or(libc::EBADF)?;
+
+        let readable = entry.flags & libc::O_ACCMODE != libc::O_WRONLY;
+        let writable = entry.flags & libc::O_ACCMODE != libc::O_RDONLY;
+        let append = entry.flags & libc::O_APPEND != 0;
+
+        let ret = match nr {
+            libc::SYS_read | libc::SYS_pread64 | libc::SYS_readv if !readable => {
+                return Err(libc::EBADF)
+            }
+            libc::SYS_write | libc::SYS_pwrite64 | libc::SYS_writev if !writable => {
+                return Err(libc::EBADF)
+            }
+
+            libc::SYS_read => {
+                self.trace("read", 3);
+                let buf = self.buffer(b, c)?;
+                let len = self.read_sealed(&entry, buf, entry.offset)?;
+                entry.offset += len as u64;
+                len
+            }
+
+            libc::SYS_pread64 => {
+                self.trace("pread64", 4);
+                let buf = self.buffer(b, c)?;
+                self.read_sealed(&entry, buf, Self::offset(d)? as u64)?
+            }
+
+            libc::SYS_write => {
+                self.trace("write", 3);
+                let buf = self.buffer(b, c)?;
+                let offset = if append {
+                    entry.file.size()
+                } else {
+                    entry.offset
+                };
+                let len = self.write_sealed(&mut entry, buf, offset)?;
+                entry.offset = offset + len as u64;
+                len
+            }
+
+            libc::SYS_pwrite64 => {
+                self.trace("pwrite64", 4);
+                let buf = self.buffer(b, c)?;
+                let offset = if append {
+                    entry.file.size()
+                } else {
+                    Self::offset(d)? as u64
+                };
+                self.write_sealed(&mut entry, buf, offset)?
+            }
+
+            libc::SYS_readv | libc::SYS_writev => {
+                match nr {
+                    libc::SYS_readv => self.trace("readv", 3),
+                    _ => self.trace("writev", 3),
+                }
+
+                let iovec = UntrustedRef::from(usize::from(b) as *const libc::iovec);
+                let iovec = iovec
+                    .validate_slice(usize::from(c) as libc::c_int, self)
+                    .ok_or(libc::EFAULT)?;
+
+                let mut total = 0usize;
+                for iov in iovec {
+                    let buf = self.buffer((iov.iov_base as usize).into(), iov.iov_len.into())?;
+                    let len = match nr {
+                        libc::SYS_readv => self.read_sealed(&entry, buf, entry.offset)?,
+                        _ => {
+                            if append {
+                                entry.offset = entry.file.size();
+                            }
+
+                            self.write_sealed(&mut entry, buf, entry.offset)?
+                        }
+                    };
+
+                    entry.offset += len as u64;
+                    total = total.checked_add(len).ok_or(libc::EINVAL)?;
+                    if len < buf.len() {
+                        break;
+                    }
+                }
+
+                total
+            }
+
+            libc::SYS_lseek => {
+                self.trace("lseek", 3);
+                let offset = usize::from(b) as libc::off_t;
+                let base = match usize::from(c) as c_int {
+                    libc::SEEK_SET => 0,
+                    libc::SEEK_CUR => entry.offset,
+                    libc::SEEK_END => entry.file.size(),
+                    _ => return Err(libc::EINVAL),
+                };
+
+                let offset = (base as i64)
+                    .checked_add(offset)
+                    .filter(|offset| *offset >= 0)
+                    .ok_or(libc::EINVAL)?;
+                entry.offset = offset as u64;
+                offset as usize
+            }
+
+            libc::SYS_close => {
+                self.trace("close", 1);
+                let mut sealed = SEALED.lock();
+                if let Some(slot) = sealed
+                    .iter_mut()
+                    .find(|e| matches!(e, Some(e) if e.fd == fd))
+                {
+                    *slot = None;
+                }
+                drop(sealed);
+
+                let (fd, zero) = ((fd as usize).into(), Register::from(0usize));
+                return self.syscall(fd, zero, zero, zero, zero, zero, libc::SYS_close as _);
+            }
+
+            libc::SYS_fstat => {
+                self.trace("fstat", 2);
+                let mut stat = self.host_fstat(fd)?;
+                let size = entry.file.size();
+                stat.st_size = size as _;
+                stat.st_blocks = ((size + 511) / 512) as _;
+
+                let statbuf = UntrustedRefMut::from(usize::from(b) as *mut libc::stat);
+                *statbuf.validate(self).ok_or(libc::EFAULT)? = stat;
+                0
+            }
+
+            libc::SYS_ftruncate => {
+                self.trace("ftruncate", 2);
+                if !writable {
+                    return Err(libc::EINVAL);
+                }
+
+                let len = Self::offset(b)? as u64;
+                let mut file = entry.file;
+                with_sealer(|sealer| {
+                    let mut backing = Backing { handler: self, fd };
+                    file.set_len(sealer, &mut backing, len)
+                })?;
+                entry.file = file;
+                0
+            }
+
+            libc::SYS_fsync | libc::SYS_fdatasync => {
+                self.trace("fsync", 1);
+                let (fd, zero) = ((fd as usize).into(), Register::from(0usize));
+                return self.syscall(fd, zero, zero, zero, zero, zero, nr as _);
+            }
+
+            libc::SYS_getdents64 => return Err(libc::ENOTDIR),
+
+            _ => return Err(libc::ENOSYS),
+        };
+
+        if let Some(e) = SEALED.lock().iter_mut().flatten().find(|e| e.fd == fd) {
+            *e = entry;
+        }
+
+        Ok([ret.into(), Default::default()])
+    }
+
+    /// Reads from a sealed file
+    fn read_sealed(&mut self, entry: &Entry, buf: &mut [u8], offset: u64) -> Result<usize, c_int> {
+        let (file, fd) = (entry.file, entry.fd);
+        with_sealer(|sealer| {
+            let mut backing = Backing { handler: self, fd };
+            file.read_at(sealer, &mut backing, buf, offset)
+        })
+    }
+
+    /// Writes to a sealed file
+    fn write_sealed(&mut self, entry: &mut Entry, buf: &[u8], offset: u64) -> Result<usize, c_int> {
+        let (mut file, fd) = (entry.file, entry.fd);
+        let len = with_sealer(|sealer| {
+            let mut backing = Backing { handler: self, fd };
+            file.write_at(sealer, &mut backing, buf, offset)
+        })?;
+
+        entry.file = file;
+        Ok(len)
+    }
+}
+
+impl<'a> FsSyscallHandler for super::Handler<'a> {
+    /// Opens a file beneath a sealed mount
+    ///
+    /// The `path` is the absolute, NUL-terminated path on the host. Writable
+    /// files are opened for reading too, since partially written blocks must
+    /// be read back. Appending is done here rather than by the host.
+    fn open_sealed(&mut self, path: &[u8], flags: c_int, mode: libc::mode_t) -> sallyport::Result {
+        if flags & libc::O_TMPFILE == libc::O_TMPFILE {
+            return Err(libc::EOPNOTSUPP);
+        }
+
+        let plain = flags & (libc::O_PATH | libc::O_DIRECTORY) != 0;
+        let hflags = match (plain, flags & libc::O_ACCMODE) {
+            (true, _) => flags,
+            (false, libc::O_WRONLY) => flags & !(libc::O_ACCMODE | libc::O_APPEND) | libc::O_RDWR,
+            (false, _) => flags & !libc::O_APPEND,
+        };
+
+        let c = self.new_cursor();
+        let (_, hpath) = c.copy_from_slice(path).or(Err(libc::EMSGSIZE))?;
+        let hpath = hpath.as_ptr();
+
+        let req = request!(libc::SYS_openat => libc::AT_FDCWD, hpath, hflags, mode);
+        let ret = unsafe { self.proxy(req)? };
+        let fd = usize::from(ret[0]) as c_int;
+        if MemFs::owns(fd) || owns(fd) {
+            self.attacked();
+        }
+
+        if plain {
+            return Ok(ret);
+        }
+
+        // Only directories opened for reading are left unsealed, so that a
+        // host claiming a file is a directory learns nothing.
+        let writable = flags & libc::O_ACCMODE != libc::O_RDONLY;
+        let stat = match self.host_fstat(fd) {
+            Ok(stat) => stat,
+            Err(e) => return self.host_close(fd, e),
+        };
+
+        match (stat.st_mode & libc::S_IFMT, writable) {
+            (libc::S_IFDIR, false) => return Ok(ret),
+            (libc::S_IFDIR, true) => return self.host_close(fd, libc::EISDIR),
+            (libc::S_IFREG, _) => (),
+            _ => return self.host_close(fd, libc::ENXIO),
+        }
+
+        let file = with_sealer(|sealer| {
+            let mut backing = Backing { handler: self, fd };
+            SealedFile::open(sealer, &mut backing, writable)
+        });
+
+        let file = match file {
+            Ok(file) => file,
+            Err(e) => return self.host_close(fd, e),
+        };
+
+        let entry = Entry {
+            fd,
+            file,
+            flags,
+            offset: 0,
+        };
+
+        let stored = match SEALED.lock().iter_mut().find(|e| e.is_none()) {
+            Some(slot) => {
+                *slot = Some(entry);
+                true
+            }
+            None => false,
+        };
+
+        match stored {
+            true => Ok(ret),
+            false => self.host_close(fd, libc::EMFILE),
+        }
+    }
+}
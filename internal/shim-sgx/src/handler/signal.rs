@@ -0,0 +1,701 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Signals
+//!
+//! The signal actions, the signal masks and the alternate signal stacks are
+//! kept in the enclave: the actions for the whole keep, the masks and the
+//! stacks per thread (see `enarx_shim::perthread`). A fault of the payload
+//! which reaches the handler is turned into a signal frame on the payload's
+//! stack, as the kernel would build it. The only asynchronous signal is that
+//! of the doorbell.
+//!
+//! Syscalls which replace the mask for their duration (`epoll_pwait()`) keep
+//! the old one, which a signal delivered before they return saves in its
+//! frame, as the kernel does. Otherwise, it is restored once they return.
+//!
+//! The SEV shim doesn't deliver signals yet: a fault of its payload stops
+//! the keep.
+//!
+//! The extended CPU state of the payload, which the CPU saved in the SSA
+//! with the components of XFRM, is copied below the frame and pointed to by
+//! `fpstate`, so that a handler which uses the FPU or the vector registers
+//! doesn't corrupt the code it interrupted. `rt_sigreturn()` copies it back,
+//! keeping the format of the SSA and clearing what the CPU would reject on
+//! ERESUME.
+
+use core::convert::TryInto;
+use core::mem::size_of;
+
+use enarx_shim::perthread::PerThread;
+use libc::c_int;
+use sallyport::syscall::{BaseSyscallHandler, ProcessSyscallHandler};
+use sallyport::untrusted::{UntrustedRef, UntrustedRefMut, Validate};
+use spinning::{Mutex, RawMutex};
+use xsave::XSave;
+
+use crate::ssa::Vector;
+
+/// The number of signals
+const NSIG: usize = 64;
+
+/// The number of threads whose signal state is kept
+const THREADS: usize = 64;
+
+/// The size of the area below the stack pointer which the payload may use
+const RED_ZONE: u64 = 128;
+
+/// The signals which can't be caught or blocked
+const UNBLOCKABLE: u64 = bit(libc::SIGKILL) | bit(libc::SIGSTOP);
+
+/// The action has a restorer
+///
+/// The C library sets this itself, so the `libc` crate doesn't define it.
+const SA_RESTORER: u64 = 0x0400_0000;
+
+/// The flags which the payload may change with `rt_sigreturn()`
+///
+/// CF, PF, AF, ZF, SF, TF, DF, OF, RF and AC
+const USER_FLAGS: u64 = 0x0005_0dd5;
+
+/// The offsets of the fields of an XSAVE area which ERESUME checks
+///
+/// See Section 13.4 of Volume 1 of the Intel SDM.
+const XSAVE_MXCSR: usize = 24;
+const XSAVE_MXCSR_MASK: usize = 28;
+const XSAVE_XSTATE_BV: usize = 512;
+const XSAVE_XCOMP_BV: usize = 520;
+const XSAVE_HEADER_END: usize = 576;
+
+/// The MXCSR bits which are supported if the CPU reports no mask
+const MXCSR_DEFAULT_MASK: u32 = 0xffbf;
+
+/// Returns the bit of a signal in a signal set
+const fn bit(signal: c_int) -> u64 {
+    1 << (signal - 1)
+}
+
+/// Returns the state components which are enabled in the enclave (XFRM)
+fn xcr0() -> u64 {
+    let (lo, hi): (u32, u32);
+    unsafe {
+        asm!(
+            "xgetbv",
+            in("ecx") 0u32,
+            out("eax") lo,
+            out("edx") hi,
+            options(nomem, nostack, preserves_flags)
+        )
+    };
+
+    (hi as u64) << 32 | lo as u64
+}
+
+/// Copies the extended state from a signal frame back to the SSA
+///
+/// The payload may have changed anything in the frame, so only the state
+/// components of XFRM (and, in the compacted format, of the SSA) are
+/// restored, unsupported MXCSR bits are cleared and the format is kept.
+fn restore(ssa: &mut XSave, frame: &XSave) {
+    let dst =
+        unsafe { core::slice::from_raw_parts_mut(ssa as *mut _ as *mut u8, size_of::<XSave>()) };
+    let src =
+        unsafe { core::slice::from_raw_parts(frame as *const _ as *const u8, size_of::<XSave>()) };
+
+    let u32_at = |b: &[u8], o: usize| u32::from_le_bytes(b[o..o + 4].try_into().unwrap());
+    let u64_at = |b: &[u8], o: usize| u64::from_le_bytes(b[o..o + 8].try_into().unwrap());
+
+    let mask = match u32_at(dst, XSAVE_MXCSR_MASK) {
+        0 => MXCSR_DEFAULT_MASK,
+        mask => mask,
+    };
+
+    let xcomp = u64_at(dst, XSAVE_XCOMP_BV);
+    let mut components = xcr0();
+    if xcomp & (1 << 63) != 0 {
+        components &= xcomp;
+    }
+
+    let mxcsr = u32_at(src, XSAVE_MXCSR) & mask;
+    let xstate = u64_at(src, XSAVE_XSTATE_BV) & components;
+
+    dst.copy_from_slice(src);
+    dst[XSAVE_MXCSR..][..4].copy_from_slice(&mxcsr.to_le_bytes());
+    dst[XSAVE_MXCSR_MASK..][..4].copy_from_slice(&mask.to_le_bytes());
+    dst[XSAVE_XSTATE_BV..][..8].copy_from_slice(&xstate.to_le_bytes());
+    dst[XSAVE_XCOMP_BV..][..8].copy_from_slice(&xcomp.to_le_bytes());
+    dst[XSAVE_XCOMP_BV + 8..XSAVE_HEADER_END].fill(0);
+}
+
+/// `struct sigaction` of the kernel
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Action {
+    handler: u64,
+    flags: u64,
+    restorer: u64,
+    mask: u64,
+}
+
+impl Action {
+    const DEFAULT: Self = Self {
+        handler: libc::SIG_DFL as u64,
+        flags: 0,
+        restorer: 0,
+        mask: 0,
+    };
+}
+
+/// `stack_t`
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Stack {
+    sp: u64,
+    flags: c_int,
+    size: u64,
+}
+
+impl Stack {
+    const DISABLED: Self = Self {
+        sp: 0,
+        flags: libc::SS_DISABLE,
+        size: 0,
+    };
+
+    fn contains(&self, sp: u64) -> bool {
+        self.flags != libc::SS_DISABLE && sp > self.sp && sp - self.sp <= self.size
+    }
+}
+
+/// `struct sigcontext`
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct Context {
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rdi: u64,
+    rsi: u64,
+    rbp: u64,
+    rbx: u64,
+    rdx: u64,
+    rax: u64,
+    rcx: u64,
+    rsp: u64,
+    rip: u64,
+    rflags: u64,
+    segments: u64,
+    err: u64,
+    trapno: u64,
+    oldmask: u64,
+    cr2: u64,
+    fpstate: u64,
+    reserved: [u64; 8],
+}
+
+/// `struct ucontext`
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct UContext {
+    flags: u64,
+    link: u64,
+    stack: Stack,
+    mcontext: Context,
+    mask: u64,
+}
+
+/// `siginfo_t` of a fault
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct SigInfo {
+    signo: c_int,
+    errno: c_int,
+    code: c_int,
+    pad: c_int,
+    addr: u64,
+    rest: [u64; 13],
+}
+
+/// The signal frame (`struct rt_sigframe`)
+///
+/// The handler returns to the restorer, which calls `rt_sigreturn()` with
+/// the stack pointer at `ucontext`.
+#[repr(C)]
+struct Frame {
+    restorer: u64,
+    ucontext: UContext,
+    info: SigInfo,
+}
+
+/// The signal state of a thread
+#[derive(Copy, Clone)]
+struct Thread {
+    /// The blocked signals
+    mask: u64,
+
+    /// The mask to restore when the current syscall returns
+    saved: Option<u64>,
+
+    /// The alternate signal stack
+    altstack: Stack,
+}
+
+impl Thread {
+    const INITIAL: Self = Self {
+        mask: 0,
+        saved: None,
+        altstack: Stack::DISABLED,
+    };
+}
+
+/// The actions of the signals
+static ACTIONS: Mutex<[Action; NSIG]> =
+    Mutex::const_new(RawMutex::const_new(), [Action::DEFAULT; NSIG]);
+
+/// The signal state of each thread
+static SIGNALS: Mutex<PerThread<Thread, THREADS>> =
+    Mutex::const_new(RawMutex::const_new(), PerThread::new([None; THREADS]));
+
+impl<'a> super::Handler<'a> {
+    /// Do a `rt_sigaction()` syscall
+    pub(super) fn rt_sigaction(
+        &mut self,
+        signal: c_int,
+        act: usize,
+        oldact: usize,
+        size: usize,
+    ) -> sallyport::Result {
+        self.trace("rt_sigaction", 4);
+
+        if size != size_of::<u64>() || signal < 1 || signal > NSIG as c_int {
+            return Err(libc::EINVAL);
+        }
+
+        let new = match act {
+            0 => None,
+            _ if bit(signal) & UNBLOCKABLE != 0 => return Err(libc::EINVAL),
+            act => {
+                let act = UntrustedRef::from(act as *const Action);
+                Some(*act.validate(self).ok_or(libc::EFAULT)?)
+            }
+        };
+
+        let old = {
+            let mut actions = ACTIONS.lock();
+            let old = actions[signal as usize - 1];
+            if let Some(new) = new {
+                actions[signal as usize - 1] = new;
+            }
+
+            old
+        };
+
+        if oldact != 0 {
+            let oldact = UntrustedRefMut::from(oldact as *mut Action);
+            *oldact.validate(self).ok_or(libc::EFAULT)? = old;
+        }
+
+        Ok(Default::default())
+    }
+
+    /// Do a `rt_sigprocmask()` syscall
+    pub(super) fn rt_sigprocmask(
+        &mut self,
+        how: c_int,
+        set: usize,
+        oldset: usize,
+        size: usize,
+    ) -> sallyport::Result {
+        self.trace("rt_sigprocmask", 4);
+
+        if size != size_of::<u64>() {
+            return Err(libc::EINVAL);
+        }
+
+        let set = match set {
+            0 => None,
+            set => {
+                let set = UntrustedRef::from(set as *const u64);
+                Some(*set.validate(self).ok_or(libc::EFAULT)?)
+            }
+        };
+
+        let old = self.signals(|thread| {
+            let old = thread.mask;
+            if let Some(set) = set {
+                let mask = match how {
+                    libc::SIG_BLOCK => old | set,
+                    libc::SIG_UNBLOCK => old & !set,
+                    libc::SIG_SETMASK => set,
+                    _ => return Err(libc::EINVAL),
+                };
+
+                thread.mask = mask & !UNBLOCKABLE;
+            }
+
+            Ok(old)
+        })?;
+
+        if oldset != 0 {
+            let oldset = UntrustedRefMut::from(oldset as *mut u64);
+            *oldset.validate(self).ok_or(libc::EFAULT)? = old;
+        }
+
+        Ok(Default::default())
+    }
+
+    /// Do a `sigaltstack()` syscall
+    pub(super) fn sigaltstack(&mut self, ss: usize, old_ss: usize) -> sallyport::Result {
+        self.trace("sigaltstack", 2);
+
+        let ss = match ss {
+            0 => None,
+            ss => {
+                let ss = UntrustedRef::from(ss as *const Stack);
+                Some(*ss.validate(self).ok_or(libc::EFAULT)?)
+            }
+        };
+
+        let rsp = u64::from(self.gpr.rsp);
+        let old = self.signals(|thread| {
+            let on = thread.altstack.contains(rsp);
+
+            let mut old = thread.altstack;
+            if on {
+                old.flags = libc::SS_ONSTACK;
+            }
+
+            if let Some(ss) = ss {
+                if on {
+                    return Err(libc::EPERM);
+                }
+
+                thread.altstack = match ss.flags {
+                    libc::SS_DISABLE => Stack::DISABLED,
+                    0 | libc::SS_ONSTACK if ss.size < libc::MINSIGSTKSZ as u64 => {
+                        return Err(libc::ENOMEM)
+                    }
+                    0 | libc::SS_ONSTACK => Stack { flags: 0, ..ss },
+                    _ => return Err(libc::EINVAL),
+                };
+            }
+
+            Ok(old)
+        })?;
+
+        if old_ss != 0 {
+            let old_ss = UntrustedRefMut::from(old_ss as *mut Stack);
+            *old_ss.validate(self).ok_or(libc::EFAULT)? = old;
+        }
+
+        Ok(Default::default())
+    }
+
+    /// Do a `rt_sigreturn()` syscall
+    ///
+    /// This restores the registers and the signal mask from the frame of
+    /// the signal which is being returned from.
+    pub(super) fn rt_sigreturn(&mut self) {
+        self.trace("rt_sigreturn", 0);
+
+        let sp = u64::from(self.gpr.rsp);
+        let uc = UntrustedRef::from(sp as *const UContext);
+        let uc = match uc.validate(self) {
+            Some(uc) => *uc,
+            None => self.exit(128 + libc::SIGSEGV),
+        };
+
+        let mc = uc.mcontext;
+        let gpr = &mut *self.gpr;
+        gpr.r8 = mc.r8.into();
+        gpr.r9 = mc.r9.into();
+        gpr.r10 = mc.r10.into();
+        gpr.r11 = mc.r11.into();
+        gpr.r12 = mc.r12.into();
+        gpr.r13 = mc.r13.into();
+        gpr.r14 = mc.r14.into();
+        gpr.r15 = mc.r15.into();
+        gpr.rdi = mc.rdi.into();
+        gpr.rsi = mc.rsi.into();
+        gpr.rbp = mc.rbp.into();
+        gpr.rbx = mc.rbx.into();
+        gpr.rdx = mc.rdx.into();
+        gpr.rax = mc.rax.into();
+        gpr.rcx = mc.rcx.into();
+        gpr.rsp = mc.rsp.into();
+        gpr.rip = mc.rip.into();
+
+        let rflags = u64::from(gpr.rflags);
+        gpr.rflags = ((rflags & !USER_FLAGS) | (mc.rflags & USER_FLAGS)).into();
+
+        // Without `fpstate`, the extended state is left as the handler left it.
+        if mc.fpstate != 0 {
+            let fpstate = UntrustedRef::from(mc.fpstate as *const XSave);
+            let fpstate = match fpstate.validate(self) {
+                Some(fpstate) => fpstate as *const XSave,
+                None => self.exit(128 + libc::SIGSEGV),
+            };
+
+            restore(self.xsave, unsafe { &*fpstate });
+        }
+
+        let _ = self.signals(|thread| {
+            thread.mask = uc.mask & !UNBLOCKABLE;
+            Ok(())
+        });
+    }
+
+    /// Replaces the signal mask until the current syscall returns: `(set, size)`
+    ///
+    /// Nothing is replaced without a `set`.
+    pub(super) fn replace_sigmask(&mut self, set: usize, size: usize) -> Result<(), c_int> {
+        if set == 0 {
+            return Ok(());
+        }
+
+        if size != size_of::<u64>() {
+            return Err(libc::EINVAL);
+        }
+
+        let set = UntrustedRef::from(set as *const u64);
+        let set = *set.validate(self).ok_or(libc::EFAULT)?;
+
+        self.signals(|thread| {
+            thread.saved = Some(thread.mask);
+            thread.mask = set & !UNBLOCKABLE;
+            Ok(())
+        })
+    }
+
+    /// Restores the signal mask which a syscall replaced
+    ///
+    /// This is done once the syscall returned and its signals were raised.
+    pub(super) fn restore_sigmask(&mut self) {
+        let _ = self.signals(|thread| {
+            if let Some(saved) = thread.saved.take() {
+                thread.mask = saved;
+            }
+
+            Ok(())
+        });
+    }
+
+    /// Delivers the signal for a fault of the payload
+    ///
+    /// If the payload doesn't handle the signal, it is terminated with the
+    /// exit status a shell would report for it.
+    pub(super) fn fault(&mut self, vector: Vector) {
+        let trapno = vector as u8;
+
+        // With EXINFO, the error code tells protection violations apart.
+        let segv = match self.page_fault {
+            Some(pf) if pf.errcd & 1 != 0 => 2,
+            _ => 1,
+        };
+
+        // See `arch/x86/kernel/traps.c` for the signals and their codes.
+        let (signal, code) = match trapno {
+            0 => (libc::SIGFPE, 1),      // #DE: FPE_INTDIV
+            1 => (libc::SIGTRAP, 2),     // #DB: TRAP_TRACE
+            3 => (libc::SIGTRAP, 0x80),  // #BP: SI_KERNEL
+            5 => (libc::SIGSEGV, 0x80),  // #BR: SI_KERNEL
+            6 => (libc::SIGILL, 2),      // #UD: ILL_ILLOPN
+            13 => (libc::SIGSEGV, 0x80), // #GP: SI_KERNEL
+            14 => (libc::SIGSEGV, segv), // #PF: SEGV_MAPERR or SEGV_ACCERR
+            16 => (libc::SIGFPE, 0),     // #MF
+            17 => (libc::SIGBUS, 1),     // #AC: BUS_ADRALN
+            19 => (libc::SIGFPE, 0),     // #XM
+            _ => self.attacked(),
+        };
+
+        if !self.deliver(signal, code, trapno.into()) {
+            self.backtrace(signal);
+            self.usage();
+            self.exit(128 + signal)
+        }
+    }
+
+    /// Raises an asynchronous signal, as if another process sent it
+    ///
+    /// Returns `false` if the signal is blocked, so it is still pending. If
+    /// the payload doesn't handle the signal, it is terminated with the exit
+    /// status a shell would report for it.
+    pub(super) fn raise(&mut self, signal: c_int) -> bool {
+        match self.signals(|thread| Ok(thread.mask)) {
+            Ok(mask) if mask & bit(signal) == 0 => (),
+            _ => return false,
+        }
+
+        let action = ACTIONS.lock()[signal as usize - 1];
+        if action.handler == libc::SIG_IGN as u64 {
+            return true;
+        }
+
+        // SI_USER
+        if !self.deliver(signal, 0, 0) {
+            self.exit(128 + signal)
+        }
+
+        true
+    }
+
+    /// Pushes a signal frame and enters the signal handler
+    ///
+    /// Returns `false` if the signal is not handled. Like the kernel, we
+    /// don't deliver synchronous signals which are blocked or ignored.
+    fn deliver(&mut self, signal: c_int, code: c_int, trapno: u64) -> bool {
+        let action = ACTIONS.lock()[signal as usize - 1];
+        let thread = match self.signals(|thread| Ok(*thread)) {
+            Ok(thread) => thread,
+            Err(_) => return false,
+        };
+
+        // The frame holds the mask which `rt_sigreturn()` restores.
+        let mask = thread.mask;
+        let restored = thread.saved.unwrap_or(mask);
+
+        if action.handler == libc::SIG_DFL as u64
+            || action.handler == libc::SIG_IGN as u64
+            || action.flags & SA_RESTORER == 0
+            || mask & bit(signal) != 0
+        {
+            return false;
+        }
+
+        let rsp = u64::from(self.gpr.rsp);
+        let altstack = thread.altstack;
+        let on = altstack.contains(rsp);
+
+        let top = match action.flags & libc::SA_ONSTACK as u64 != 0 {
+            true if altstack.flags == 0 && !on => altstack.sp + altstack.size,
+            _ => rsp - RED_ZONE,
+        };
+
+        // The extended state is saved below the frame, as the kernel does.
+        // The handler is entered as if it was called.
+        let fpstate = (top - size_of::<XSave>() as u64) & !0x3f;
+        let sp = ((fpstate - size_of::<Frame>() as u64) & !0xf) - 8;
+
+        let mut stack = altstack;
+        if on {
+            stack.flags = libc::SS_ONSTACK;
+        }
+
+        // The address and the error code of a page fault are only known
+        // with EXINFO.
+        let (addr, err) = match self.page_fault {
+            Some(pf) if trapno == crate::PAGE_FAULT as u64 => (pf.maddr, pf.errcd as u64),
+            _ => (0, 0),
+        };
+
+        let frame = Frame {
+            restorer: action.restorer,
+            ucontext: UContext {
+                flags: 0,
+                link: 0,
+                stack,
+                mcontext: Context {
+                    err,
+                    trapno,
+                    oldmask: restored,
+                    cr2: addr,
+                    fpstate,
+                    ..self.context()
+                },
+                mask: restored,
+            },
+            info: SigInfo {
+                signo: signal,
+                errno: 0,
+                code,
+                pad: 0,
+                addr,
+                rest: [0; 13],
+            },
+        };
+
+        let dst = UntrustedRefMut::from(sp as *mut Frame);
+        match dst.validate(self) {
+            Some(dst) => *dst = frame,
+            None => return false,
+        }
+
+        let dst = UntrustedRefMut::from(fpstate as *mut XSave);
+        let dst = match dst.validate(self) {
+            Some(dst) => dst as *mut XSave,
+            None => return false,
+        };
+        unsafe { core::ptr::copy_nonoverlapping(&*self.xsave, dst, 1) };
+
+        let mut blocked = mask | action.mask;
+        if action.flags & libc::SA_NODEFER as u64 == 0 {
+            blocked |= bit(signal);
+        }
+
+        let _ = self.signals(|thread| {
+            thread.mask = blocked & !UNBLOCKABLE;
+            thread.saved = None;
+            Ok(())
+        });
+
+        if action.flags & libc::SA_RESETHAND as u64 != 0 {
+            ACTIONS.lock()[signal as usize - 1] = Action::DEFAULT;
+        }
+
+        let ucontext = sp + size_of::<u64>() as u64;
+        let info = ucontext + size_of::<UContext>() as u64;
+
+        self.gpr.rdi = (signal as u64).into();
+        self.gpr.rsi = info.into();
+        self.gpr.rdx = ucontext.into();
+        self.gpr.rax = 0u64.into();
+        self.gpr.rsp = sp.into();
+        self.gpr.rip = action.handler.into();
+
+        // Clear DF and TF, as the kernel does.
+        let rflags = u64::from(self.gpr.rflags);
+        self.gpr.rflags = (rflags & !0x500).into();
+
+        true
+    }
+
+    /// Runs `f` on the signal state of the current thread
+    ///
+    /// It fails with `ENOMEM` if the state of too many threads is kept.
+    fn signals<T>(&self, f: impl FnOnce(&mut Thread) -> Result<T, c_int>) -> Result<T, c_int> {
+        let mut signals = SIGNALS.lock();
+        let thread = signals.get(self.tcs, Thread::INITIAL).ok_or(libc::ENOMEM)?;
+        f(thread)
+    }
+
+    /// Returns the registers of the payload
+    fn context(&self) -> Context {
+        let gpr = &*self.gpr;
+
+        Context {
+            r8: gpr.r8.into(),
+            r9: gpr.r9.into(),
+            r10: gpr.r10.into(),
+            r11: gpr.r11.into(),
+            r12: gpr.r12.into(),
+            r13: gpr.r13.into(),
+            r14: gpr.r14.into(),
+            r15: gpr.r15.into(),
+            rdi: gpr.rdi.into(),
+            rsi: gpr.rsi.into(),
+            rbp: gpr.rbp.into(),
+            rbx: gpr.rbx.into(),
+            rdx: gpr.rdx.into(),
+            rax: gpr.rax.into(),
+            rcx: gpr.rcx.into(),
+            rsp: gpr.rsp.into(),
+            rip: gpr.rip.into(),
+            rflags: gpr.rflags.into(),
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,64 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The system the payload sees
+//!
+//! `uname()` and `sysinfo()` describe the keep rather than the host, so that
+//! runtimes which check the kernel version or size themselves to the memory
+//! of the machine behave the same on every host. The memory of the keep is
+//! its heap.
+
+use core::sync::atomic::Ordering;
+
+use sallyport::syscall::BaseSyscallHandler;
+use sallyport::untrusted::{UntrustedRefMut, Validate};
+
+/// The `uname()` of every keep: sysname, nodename, release, version, machine
+/// and domainname
+const UNAME: [&str; 6] = ["Linux", "enarx", "5.11.0", "#1 SMP", "x86_64", "(none)"];
+
+impl<'a> super::Handler<'a> {
+    /// Do a `uname()` syscall
+    pub(super) fn uname(&mut self, buf: usize) -> sallyport::Result {
+        self.trace("uname", 1);
+
+        let buf = UntrustedRefMut::from(buf as *mut libc::utsname);
+        let buf = buf.validate(self).ok_or(libc::EFAULT)?;
+        let fields = [
+            &mut buf.sysname,
+            &mut buf.nodename,
+            &mut buf.release,
+            &mut buf.version,
+            &mut buf.machine,
+            &mut buf.domainname,
+        ];
+
+        for (field, value) in fields.iter_mut().zip(UNAME.iter()) {
+            field.iter_mut().for_each(|c| *c = 0);
+            for (c, b) in field.iter_mut().zip(value.bytes()) {
+                *c = b as libc::c_char;
+            }
+        }
+
+        Ok(Default::default())
+    }
+
+    /// Do a `sysinfo()` syscall
+    ///
+    /// The payload runs alone in the keep, without swap, and has all of the
+    /// heap that it hasn't taken with `brk()` or `mmap()` yet. The load and
+    /// the uptime are not reported.
+    pub(super) fn sysinfo(&mut self, info: usize) -> sallyport::Result {
+        self.trace("sysinfo", 1);
+
+        let total = crate::HEAP.load(Ordering::Relaxed);
+        let info = UntrustedRefMut::from(info as *mut libc::sysinfo);
+        let info = info.validate(self).ok_or(libc::EFAULT)?;
+        *info = unsafe { core::mem::zeroed() };
+        info.totalram = total as _;
+        info.freeram = total.saturating_sub(super::memory::used()) as _;
+        info.procs = 1;
+        info.mem_unit = 1;
+
+        Ok(Default::default())
+    }
+}
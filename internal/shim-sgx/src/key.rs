@@ -0,0 +1,56 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Key derivation (`EGETKEY`)
+//!
+//! Seal keys are derived by the CPU from a secret fused into the platform and
+//! from the identity of the enclave, so no other enclave (and not the host)
+//! can derive the same key. They are bound to the security versions of the
+//! platform and of the enclave in its report, or to an older version of the
+//! enclave which is asked for (see `enarx_shim::keyrequest`).
+
+use crate::report;
+
+use enarx_shim::keyrequest::{Identity, KeyRequest};
+
+pub use enarx_shim::keyrequest::policy;
+
+/// The length of a seal key
+pub const KEY_SIZE: usize = 16;
+
+const EGETKEY: usize = 1;
+
+#[repr(C, align(16))]
+struct Key([u8; KEY_SIZE]);
+
+/// Derives a 128-bit seal key (`EGETKEY`)
+///
+/// Different values of `keyid` yield unrelated keys. `isvsvn` asks for the
+/// key of an older version of the enclave, and `None` for that of its own.
+pub fn seal(
+    policy: u16,
+    isvsvn: Option<u16>,
+    keyid: &[u8; 32],
+) -> Result<[u8; KEY_SIZE], libc::c_int> {
+    let identity = Identity::from_report(&report::report());
+    let request = KeyRequest::seal(policy, &identity, isvsvn, keyid).ok_or(libc::EINVAL)?;
+
+    let mut key = Key([0; KEY_SIZE]);
+    let rax: usize;
+
+    // LLVM reserves `rbx`, so we have to swap it manually.
+    unsafe {
+        asm!(
+            "xchg {REQUEST}, rbx",
+            "enclu",
+            "xchg {REQUEST}, rbx",
+            REQUEST = inout(reg) &request as *const KeyRequest => _,
+            inout("rax") EGETKEY => rax,
+            in("rcx") &mut key as *mut Key,
+        );
+    }
+
+    match rax {
+        0 => Ok(key.0),
+        _ => Err(libc::EIO),
+    }
+}
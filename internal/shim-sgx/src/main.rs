@@ -48,31 +48,73 @@ pub extern "C" fn rust_eh_personality() {
 
 // ============== REAL CODE HERE ===============
 
+mod edmm;
 mod entry;
+mod event;
 mod handler;
+mod key;
+mod report;
 mod ssa;
 
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use event::Event;
 use noted::noted;
-use sallyport::REQUIRES;
+use sallyport::{Block, REQUIRES};
 
 const DEBUG: bool = false;
 
+/// The sallyport ABI of the shim, which must match `SallyportAbi::LOADER`
+const SALLYPORT_ABI: [u32; 2] = [1, core::mem::size_of::<Block>() as u32];
+
 const SSA_FRAME_SIZE: u32 = 1;
+const BLOCKS: u32 = 2;
 const ENCL_SIZE_BITS: u32 = 31;
 const ENCL_SIZE: usize = 1 << ENCL_SIZE_BITS;
+const HEAP_SIZE: u64 = 128 * 1024 * 1024;
+
+/// The size of `.enarx.stk0` in `layout.ld`, which the payload shares
+const STACK_SIZE: u64 = 2 * 1024 * 1024 - 64 * 1024 - 4 * 4096;
 
 noted! {
     static NOTE_ENARX_SALLYPORT<"sallyport", 0>: [u8; REQUIRES.len()] = REQUIRES;
+    static NOTE_ENARX_SALLYPORT_ABI<"enarx", 0x73707900>: [u32; 2] = SALLYPORT_ABI;
     static NOTE_ENARX_SGX_SIZE<"enarx", 0x73677800>: u32 = ENCL_SIZE_BITS;
     static NOTE_ENARX_SGX_SSAP<"enarx", 0x73677801>: u32 = SSA_FRAME_SIZE;
+    static NOTE_ENARX_SGX_HEAP<"enarx", 0x73677802>: u64 = HEAP_SIZE;
+    static NOTE_ENARX_SGX_BLKS<"enarx", 0x73677803>: u32 = BLOCKS;
+    static NOTE_ENARX_STACK<"enarx", 0x70617901>: u64 = STACK_SIZE;
 }
 
+/// The size of the heap actually added by the host (see `heap()`)
+static HEAP: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the heap pages are added when they are first touched (see `heap()`)
+static LAZY: AtomicBool = AtomicBool::new(false);
+
+/// Set in the heap size when the host adds the heap lazily
+///
+/// This number must match the one used by the host.
+const HEAP_LAZY: usize = 1;
+
+/// The vector of a page fault
+const PAGE_FAULT: u8 = 14;
+
+/// Passed in `r9` when the host interrupted the payload, instead of a fault
+///
+/// This number must match the one used by the host.
+const INTERRUPTED: usize = usize::MAX;
+
 // NOTE: You MUST take the address of these symbols for them to work!
 extern "C" {
     static ENARX_EXEC_START: u8;
     //static ENARX_EXEC_END: u8;
     static ENARX_HEAP_START: u8;
     static ENARX_HEAP_END: u8;
+    static ENARX_STACK_START: u8;
+    static ENARX_STACK_END: u8;
+    static ENARX_EDMM_START: u8;
+    static ENARX_EDMM_END: u8;
 }
 
 /// Clear CPU flags, extended state and temporary registers (`r10` and `r11`)
@@ -193,10 +235,16 @@ unsafe extern "sysv64" fn relocate() {
 ///  rax = The current SSA index. (i.e. rbx->cssa)
 ///  rbx = The address of the TCS.
 ///  rcx = The next address after the EENTER instruction.
+///  rdi = The address of the sallyport blocks.
+///  r8  = The number of bytes of heap added by the host.
+///  r9  = The address of the page fault which caused the exit, if any, or
+///        `INTERRUPTED` if the host interrupted the payload.
 ///
 /// If rax == 0, we are doing normal execution.
 /// Otherwise, we are handling an exception.
 ///
+/// On EEXIT, rdx is the `Event` for the host.
+///
 /// # Safety
 ///
 /// Do not call this function from Rust. It is the entry point for SGX.
@@ -241,13 +289,15 @@ pub unsafe extern "sysv64" fn _start() -> ! {
         "call   {RELOC}                     ",  // Relocate symbols
 
         // Clear, call Rust, clear
-        "4:                                 ",  // rdi = &mut sallyport::Block (passthrough)
+        "4:                                 ",  // rdi = &mut [sallyport::Block; N] (passthrough)
         "lea    rsi,    [rcx + 4096]        ",  // rsi = &mut [StateSaveArea; N]
         "mov    rdx,    rax                 ",  // rdx = CSSA
         "call   {CLEARX}                    ",  // Clear CPU state
         "call   {ENTRY}                     ",  // Jump to Rust
+        "push   rax                         ",  // Save the event
         "call   {CLEARX}                    ",  // Clear CPU state
         "call   {CLEARP}                    ",  // Clear parameter registers
+        "pop    rdx                         ",  // rdx = the event
 
         // Exit
         "pop    rsp                         ",  // Restore old stack
@@ -264,19 +314,90 @@ pub unsafe extern "sysv64" fn _start() -> ! {
     )
 }
 
+/// Determines the heap region
+///
+/// The host tells us how many bytes of heap it has added on every entry.
+/// We only trust this value (within the bounds of the heap reservation) on
+/// the first entry. Since the heap pages are not measured, they are cleared
+/// before use. If the host lies about the size, we will fault when touching
+/// the missing pages, which is no worse than any other denial of service.
+///
+/// If the host says it adds the heap lazily, the first page is accepted to
+/// check that. Only pages added with `EAUG`, which the CPU clears, can be
+/// accepted, so the others are cleared as before.
+unsafe fn heap(size: usize, first: bool) -> lset::Line<usize> {
+    use edmm::flags::{PENDING, PT_REG, R, W};
+
+    let start = &ENARX_HEAP_START as *const _ as usize;
+    let end = &ENARX_HEAP_END as *const _ as usize;
+
+    if first {
+        let lazy = size & HEAP_LAZY != 0;
+        let size = size.min(end - start) & !0xfff;
+        if lazy && size > 0 && edmm::accept(start, 0x1000, R | W | PENDING | PT_REG).is_ok() {
+            LAZY.store(true, Ordering::Relaxed);
+        } else {
+            core::ptr::write_bytes(start as *mut u8, 0, size);
+        }
+        HEAP.store(size, Ordering::Relaxed);
+    }
+
+    lset::Line::new(start, start + HEAP.load(Ordering::Relaxed))
+}
+
+/// Accepts the page of a lazy heap which caused a page fault
+///
+/// Returns whether the page was accepted. With EXINFO, the CPU saved the
+/// address of the fault; without it, page faults are reported without an
+/// exception type and the address comes from the host, but only a page which
+/// it has just added can be accepted.
+unsafe fn lazy(ssa: &ssa::StateSaveArea, heap: lset::Line<usize>, fault: usize) -> bool {
+    use edmm::flags::{PENDING, PT_REG, R, W};
+
+    let fault = match ssa.gpr.exitinfo.exception() {
+        Some(vector) if vector as u8 != PAGE_FAULT => return false,
+        Some(vector) => ssa
+            .misc
+            .page_fault(vector)
+            .map_or(fault, |pf| pf.maddr as usize),
+        None => fault,
+    };
+
+    if !LAZY.load(Ordering::Relaxed) || fault < heap.start || fault >= heap.end {
+        return false;
+    }
+
+    edmm::accept(fault & !0xfff, 0x1000, R | W | PENDING | PT_REG).is_ok()
+}
+
 unsafe extern "C" fn main(
-    port: &mut sallyport::Block,
+    port: &mut [sallyport::Block; BLOCKS as usize],
     ssas: &mut [ssa::StateSaveArea; 3],
     cssa: usize,
-) {
-    let heap = lset::Line::new(
-        &ENARX_HEAP_START as *const _ as usize,
-        &ENARX_HEAP_END as *const _ as usize,
-    );
+    tcs: usize,
+    heap_size: usize,
+    fault: usize,
+) -> usize {
+    let heap = heap(heap_size, cssa == 0);
 
-    match cssa {
+    let event = match cssa {
         0 => entry::entry(&ENARX_EXEC_START as *const u8 as _),
-        1 => handler::Handler::handle(&mut ssas[0].gpr, port, heap),
+
+        // The host may only interrupt the payload, which is resumed as is.
+        // Interrupting the shim itself has no effect.
+        1 if fault == INTERRUPTED => {
+            handler::Handler::interrupted(&mut ssas[0], port, heap, tcs);
+            Event::Done
+        }
+        _ if fault == INTERRUPTED => Event::Done,
+
+        n if lazy(&ssas[n - 1], heap, fault) => Event::Done,
+        1 => {
+            handler::Handler::handle(&mut ssas[0], port, heap, tcs);
+            Event::Done
+        }
         n => handler::Handler::finish(&mut ssas[n - 1].gpr),
-    }
+    };
+
+    event.into()
 }
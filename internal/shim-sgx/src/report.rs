@@ -0,0 +1,42 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reports of the enclave (`EREPORT`)
+//!
+//! A report describes the enclave: its attributes, its measurement and its
+//! security versions. The shim asks the CPU for reports targeted at no
+//! enclave, only to read them itself.
+
+use enarx_shim::keyrequest::REPORT_SIZE;
+
+const EREPORT: usize = 0;
+
+#[repr(C, align(512))]
+struct TargetInfo([u8; 512]);
+
+#[repr(C, align(128))]
+struct ReportData([u8; 64]);
+
+#[repr(C, align(512))]
+struct Report([u8; REPORT_SIZE]);
+
+/// Returns a report of the enclave
+pub fn report() -> [u8; REPORT_SIZE] {
+    let target = TargetInfo([0; 512]);
+    let data = ReportData([0; 64]);
+    let mut report = Report([0; REPORT_SIZE]);
+
+    // LLVM reserves `rbx`, so we have to swap it manually.
+    unsafe {
+        asm!(
+            "xchg {TARGET}, rbx",
+            "enclu",
+            "xchg {TARGET}, rbx",
+            TARGET = inout(reg) &target as *const TargetInfo => _,
+            in("rax") EREPORT,
+            in("rcx") &data as *const ReportData,
+            in("rdx") &mut report as *mut Report,
+        );
+    }
+
+    report.0
+}
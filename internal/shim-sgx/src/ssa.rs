@@ -107,14 +107,14 @@ pub struct Gpr {
 }
 
 /// Section 38.9.2.1, Table 38-12
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 #[repr(C)]
-struct ExceptionInfo {
+pub struct ExceptionInfo {
     /// In case of a page fault, contains the linear address that caused the fault.
-    maddr: u64,
+    pub maddr: u64,
 
     /// Exception error code for GP fault or page fault.
-    errcd: u32,
+    pub errcd: u32,
 
     reserved: u32,
 }
@@ -128,6 +128,19 @@ pub struct Miscellaneous {
     exinfo: ExceptionInfo,
 }
 
+impl Miscellaneous {
+    /// Returns the address and the error code of a page fault
+    ///
+    /// Page faults are only reported in EXITINFO if EXINFO is enabled in
+    /// MISCSELECT, in which case the CPU also saved this information.
+    pub fn page_fault(&self, vector: Vector) -> Option<ExceptionInfo> {
+        match vector as u8 {
+            crate::PAGE_FAULT => Some(self.exinfo),
+            _ => None,
+        }
+    }
+}
+
 /// When an AEX occurs while running in an enclave, the architectural state is saved
 /// in the thread’s current StateSaveArea (SSA Frame), which is pointed to by TCS.CSSA.
 ///
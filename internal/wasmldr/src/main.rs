@@ -0,0 +1,68 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The WebAssembly runtime of the keep
+//!
+//! `enarx-keepldr` runs this as the payload when it is given a WebAssembly
+//! module. The loader opens the module on the host and names the file
+//! descriptor in `ENARX_WASM_FD`; since the shims proxy the reads of host
+//! descriptors, the module is streamed into the keep from it.
+//!
+//! The module is compiled with wasmtime and its `_start` is run with WASI.
+//! It gets the standard streams, the arguments and the environment of the
+//! payload (without `ENARX_WASM_FD`), and no preopened directories yet.
+
+#![deny(clippy::all)]
+
+use anyhow::{anyhow, Context, Result};
+use wasmtime::{Engine, Linker, Module, Store};
+use wasmtime_wasi::sync::WasiCtxBuilder;
+
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::{FromRawFd, RawFd};
+
+/// The environment variable which holds the descriptor of the module
+const FD_VAR: &str = "ENARX_WASM_FD";
+
+fn main() -> Result<()> {
+    let fd: RawFd = std::env::var(FD_VAR)
+        .with_context(|| format!("no module given ({} is not set)", FD_VAR))?
+        .parse()
+        .with_context(|| format!("invalid {}", FD_VAR))?;
+
+    let mut bytes = Vec::new();
+    unsafe { File::from_raw_fd(fd) }
+        .read_to_end(&mut bytes)
+        .context("unable to read the module")?;
+
+    let args: Vec<String> = std::env::args().collect();
+    let vars: Vec<(String, String)> = std::env::vars().filter(|(k, _)| k != FD_VAR).collect();
+
+    let engine = Engine::default();
+    let module = Module::new(&engine, &bytes).context("unable to compile the module")?;
+
+    let mut linker = Linker::new(&engine);
+    wasmtime_wasi::add_to_linker(&mut linker, |ctx| ctx)?;
+
+    let wasi = WasiCtxBuilder::new()
+        .inherit_stdio()
+        .args(&args)?
+        .envs(&vars)?
+        .build();
+
+    let mut store = Store::new(&engine, wasi);
+    linker.module(&mut store, "", &module)?;
+
+    let start = linker
+        .get_default(&mut store, "")?
+        .typed::<(), (), _>(&store)
+        .map_err(|_| anyhow!("the module has no `_start`"))?;
+
+    match start.call(&mut store, ()) {
+        Ok(()) => Ok(()),
+        Err(trap) => match trap.i32_exit_status() {
+            Some(status) => std::process::exit(status),
+            None => Err(trap.into()),
+        },
+    }
+}
@@ -0,0 +1,234 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Interrupting a thread inside a keep
+//!
+//! A thread of the host which entered a keep only returns to the host when
+//! the keep exits to it, which a payload that computes without making
+//! syscalls may never do. So that the host can stop it for a timeout, forward
+//! a signal to it or preempt it, another thread of the host interrupts it
+//! with `Interrupt`, which sends it `SIGRTMIN`. The backend decides what the
+//! signal does while its keep runs (see `Hook`):
+//!
+//!   * KVM and SEV: the signal makes `KVM_RUN` return. The hook also sets
+//!     `immediate_exit`, so that a vCPU which was about to run returns at
+//!     once instead.
+//!   * SGX: the signal exits the enclave asynchronously (AEX). Instead of
+//!     resuming it, the hook has the thread enter the shim, which checks the
+//!     doorbell page (see `shutdown`) and exits to the host.
+//!   * nil: the signal wakes the thread of the loader, which waits for the
+//!     next syscall of the payload.
+//!
+//! Since the signal may arrive just before the thread enters the keep, it is
+//! sent again until the thread leaves it. The handler is installed without
+//! `SA_RESTART`, so a syscall which the host executes for the keep when the
+//! signal arrives may fail with `EINTR`.
+
+use anyhow::Result;
+
+use std::cell::Cell;
+use std::sync::atomic::{compiler_fence, AtomicBool, Ordering};
+use std::sync::{Arc, Once};
+use std::time::Duration;
+
+/// How long the thread gets to leave the keep before it is signalled again
+const RETRY: Duration = Duration::from_millis(1);
+
+/// How often the thread is signalled before the interrupt gives up
+const ATTEMPTS: usize = 1000;
+
+/// What a backend does when a thread inside its keep is interrupted
+///
+/// The function is called in the signal handler, on the interrupted thread,
+/// with `data` and the context which the thread returns to. It must be
+/// async-signal-safe.
+#[cfg_attr(
+    not(any(feature = "backend-kvm", feature = "backend-sgx")),
+    allow(dead_code)
+)]
+pub(crate) struct Hook {
+    pub func: unsafe fn(data: usize, context: &mut libc::ucontext_t),
+    pub data: usize,
+}
+
+thread_local! {
+    /// The hook of the keep which the thread is inside of, if it is armed
+    static HOOK: Cell<*const Hook> = Cell::new(std::ptr::null());
+}
+
+/// Arms a hook on the calling thread, until the guard is dropped
+#[cfg_attr(
+    not(any(feature = "backend-kvm", feature = "backend-sgx")),
+    allow(dead_code)
+)]
+pub(crate) fn arm(hook: &Hook) -> Armed<'_> {
+    HOOK.with(|h| h.set(hook));
+    compiler_fence(Ordering::SeqCst);
+    Armed(std::marker::PhantomData)
+}
+
+/// An armed hook (see `arm()`)
+pub(crate) struct Armed<'a>(std::marker::PhantomData<&'a Hook>);
+
+impl Drop for Armed<'_> {
+    fn drop(&mut self) {
+        compiler_fence(Ordering::SeqCst);
+        HOOK.with(|h| h.set(std::ptr::null()));
+    }
+}
+
+/// What the thread and its interrupts share
+#[derive(Default)]
+struct State {
+    /// Whether the thread is inside the keep
+    inside: AtomicBool,
+
+    /// Whether the thread was interrupted since it entered the keep
+    pending: AtomicBool,
+}
+
+/// Interrupts a thread of the host while it is inside a keep
+#[derive(Clone)]
+pub struct Interrupt {
+    pid: libc::pid_t,
+    tid: libc::pid_t,
+    state: Arc<State>,
+}
+
+impl Interrupt {
+    /// Lets other threads interrupt the calling thread
+    pub fn current() -> Result<Self> {
+        install()?;
+
+        Ok(Self {
+            pid: unsafe { libc::getpid() },
+            tid: unsafe { libc::syscall(libc::SYS_gettid) } as _,
+            state: Arc::default(),
+        })
+    }
+
+    /// Whether only this handle is left, so that the thread is done with the
+    /// keep
+    pub fn orphaned(&self) -> bool {
+        Arc::strong_count(&self.state) == 1
+    }
+
+    /// Notes that the thread enters the keep, until the guard is dropped
+    pub fn enter(&self) -> Inside<'_> {
+        self.state.inside.store(true, Ordering::SeqCst);
+        Inside(self)
+    }
+
+    /// Interrupts the thread, if it is inside the keep
+    ///
+    /// Returns once the thread left the keep, or `false` if it didn't in
+    /// time, e.g. because it is blocked in the shim.
+    pub fn interrupt(&self) -> bool {
+        self.state.pending.store(true, Ordering::SeqCst);
+
+        for _ in 0..ATTEMPTS {
+            let state = &self.state;
+            if !state.inside.load(Ordering::SeqCst) || !state.pending.load(Ordering::SeqCst) {
+                return true;
+            }
+
+            self.signal();
+            std::thread::sleep(RETRY);
+        }
+
+        false
+    }
+
+    /// Whether the thread was interrupted and didn't leave the keep yet
+    pub(crate) fn pending(&self) -> bool {
+        self.state.pending.load(Ordering::SeqCst)
+    }
+
+    /// Sends the signal to the thread once, without waiting for it
+    ///
+    /// A thread which is inside a keep leaves it, and a syscall which the
+    /// thread is in may fail with `EINTR`.
+    pub(crate) fn signal(&self) {
+        let signal = libc::SIGRTMIN();
+        unsafe { libc::syscall(libc::SYS_tgkill, self.pid, self.tid, signal) };
+    }
+}
+
+/// A thread inside the keep (see `Interrupt::enter()`)
+pub struct Inside<'a>(&'a Interrupt);
+
+impl Drop for Inside<'_> {
+    fn drop(&mut self) {
+        self.0.state.inside.store(false, Ordering::SeqCst);
+        self.0.state.pending.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Installs the handler of `SIGRTMIN`, once per process
+fn install() -> Result<()> {
+    static INSTALL: Once = Once::new();
+
+    let mut result = Ok(());
+    INSTALL.call_once(|| {
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        let handler: extern "C" fn(_, _, _) = handle;
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_SIGINFO;
+
+        if unsafe { libc::sigaction(libc::SIGRTMIN(), &action, std::ptr::null_mut()) } < 0 {
+            result = Err(std::io::Error::last_os_error().into());
+        }
+    });
+
+    result
+}
+
+/// Runs the hook of the interrupted thread, if it is armed
+extern "C" fn handle(_: libc::c_int, _: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    let hook = HOOK.try_with(Cell::get).unwrap_or(std::ptr::null());
+    if let Some(hook) = unsafe { hook.as_ref() } {
+        unsafe { (hook.func)(hook.data, &mut *(context as *mut libc::ucontext_t)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc;
+
+    static HOOKED: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe fn count(data: usize, _: &mut libc::ucontext_t) {
+        HOOKED.fetch_add(data, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn interrupts() {
+        let (tx, rx) = mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            let interrupt = Interrupt::current().unwrap();
+            let hook = Hook {
+                func: count,
+                data: 1,
+            };
+
+            let _inside = interrupt.enter();
+            let _armed = arm(&hook);
+            tx.send(interrupt.clone()).unwrap();
+
+            // Waits in the "keep" until the hook ran.
+            while HOOKED.load(Ordering::SeqCst) == 0 {
+                unsafe { libc::pause() };
+            }
+        });
+
+        let interrupt = rx.recv().unwrap();
+        assert!(interrupt.interrupt());
+        thread.join().unwrap();
+        assert!(HOOKED.load(Ordering::SeqCst) >= 1);
+
+        // The thread isn't inside the keep any longer.
+        assert!(interrupt.interrupt());
+    }
+}
@@ -2,5 +2,68 @@
 
 use super::vm;
 
-pub struct Kvm;
-impl vm::builder::Hook for Kvm {}
+use anyhow::{bail, Result};
+use kvm_ioctls::VmFd;
+use sallyport::Block;
+use x86_64::VirtAddr;
+
+use std::mem::size_of;
+
+/// The maximum size of an encoded secret (see `SevSecret` in the shim)
+const SECRET_MAX_SIZE: usize = 16 * 1024;
+
+pub struct Kvm {
+    /// The secret to place in the first syscall block
+    pub secret: Option<Vec<u8>>,
+}
+
+impl vm::builder::Hook for Kvm {
+    fn code_loaded(
+        &mut self,
+        _vm: &mut VmFd,
+        _addr_space: &[u8],
+        syscall_blocks: VirtAddr,
+    ) -> Result<()> {
+        // The shim reads the secret from where `LAUNCH_SECRET` puts it on SEV.
+        if let Some(secret) = self.secret.take() {
+            let encoded = encode(&secret);
+            if encoded.len() > SECRET_MAX_SIZE.min(size_of::<Block>()) {
+                bail!("the secret is too large ({} bytes)", secret.len());
+            }
+
+            let dst = syscall_blocks.as_mut_ptr::<u8>();
+            let dst = unsafe { std::slice::from_raw_parts_mut(dst, encoded.len()) };
+            dst.copy_from_slice(&encoded);
+        }
+
+        Ok(())
+    }
+}
+
+/// Encodes a secret as a CBOR byte string
+fn encode(secret: &[u8]) -> Vec<u8> {
+    let len = secret.len();
+    let mut encoded = match len {
+        0..=23 => vec![0x40 | len as u8],
+        24..=0xff => vec![0x58, len as u8],
+        0x100..=0xffff => [&[0x59][..], &(len as u16).to_be_bytes()[..]].concat(),
+        _ => [&[0x5a][..], &(len as u32).to_be_bytes()[..]].concat(),
+    };
+
+    encoded.extend_from_slice(secret);
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cbor() {
+        assert_eq!(encode(b"abc"), b"\x43abc");
+        assert_eq!(encode(&[7; 24])[..2], [0x58, 24]);
+        assert_eq!(encode(&[7; 256])[..3], [0x59, 1, 0]);
+        assert_eq!(encode(&[7; 0x10000])[..5], [0x5a, 0, 1, 0, 0]);
+        assert_eq!(encode(&[7; 256]).len(), 259);
+    }
+}
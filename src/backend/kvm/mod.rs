@@ -4,22 +4,39 @@ mod builder;
 mod vm;
 
 pub use vm::{
+    create_vcpu,
     measure::{self, Measurement},
     personality::Personality,
-    Builder, Hook, Vm,
+    snapshot, Builder, Hook, KvmUserspaceMemoryRegion, Vm,
 };
 
-use crate::backend::{self, Datum, Keep};
-use crate::binary::Component;
+use crate::backend::shim::Shim;
+use crate::backend::{self, BuildError, Config, Datum, Keep};
+use crate::binary::{Component, PT_ENARX_SALLYPORT};
+use crate::cpuid::Policy;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use kvm_ioctls::Kvm;
 
+use std::path::Path;
 use std::sync::{Arc, RwLock};
 
-pub const SHIM: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/bin/shim-sev"));
+#[cfg(feature = "builtin-shims")]
+const BUILTIN: Option<&[u8]> = Some(include_bytes!(concat!(env!("OUT_DIR"), "/bin/shim-sev")));
 
-fn dev_kvm() -> Datum {
+#[cfg(not(feature = "builtin-shims"))]
+const BUILTIN: Option<&[u8]> = None;
+
+/// The shim of KVM and SEV keeps
+pub static SHIM: Shim = Shim::new(
+    "shim-sev",
+    "ENARX_SHIM_SEV",
+    BUILTIN,
+    option_env!("ENARX_SHIM_SEV"),
+    option_env!("ENARX_SHIM_SEV_SHA256"),
+);
+
+pub fn dev_kvm() -> Datum {
     let dev_kvm = std::path::Path::new("/dev/kvm");
 
     Datum {
@@ -30,7 +47,7 @@ fn dev_kvm() -> Datum {
     }
 }
 
-fn kvm_version() -> Datum {
+pub fn kvm_version() -> Datum {
     let version = Kvm::new().map(|kvm| kvm.get_api_version());
     let (pass, info) = match version {
         Ok(v) => (v == 12, Some(v.to_string())),
@@ -44,6 +61,17 @@ fn kvm_version() -> Datum {
     }
 }
 
+/// Checks that a shim has the sallyport blocks which KVM and SEV keeps
+/// exchange syscalls through
+pub fn check_shim(shim: &Component) -> Result<()> {
+    if shim.find_header(PT_ENARX_SALLYPORT).is_none() {
+        let name = "PT_ENARX_SALLYPORT";
+        return Err(BuildError::MissingHeader { name }.into());
+    }
+
+    Ok(())
+}
+
 pub struct Backend;
 
 impl backend::Backend for Backend {
@@ -51,16 +79,50 @@ impl backend::Backend for Backend {
         "kvm"
     }
 
-    fn shim(&self) -> &'static [u8] {
-        SHIM
+    fn shim(&self) -> Result<&'static [u8]> {
+        SHIM.bytes()
+    }
+
+    fn check_shim(&self, shim: &Component) -> Result<()> {
+        check_shim(shim)
     }
 
     fn data(&self) -> Vec<Datum> {
         vec![dev_kvm(), kvm_version()]
     }
 
-    fn build(&self, shim: Component, code: Component) -> Result<Arc<dyn Keep>> {
-        let vm = Builder::new(shim, code, builder::Kvm).build::<()>()?.vm()?;
+    fn build(&self, shim: Component, code: Component, config: &Config) -> Result<Arc<dyn Keep>> {
+        // The payload may request vCPUs.
+        let config = &config.requested(&shim, &code)?;
+
+        // Only the state of the first vCPU is saved.
+        if config.snapshot.is_some() && config.cpus() > 1 {
+            bail!("keeps with several vCPUs can't be snapshotted");
+        }
+
+        let hook = builder::Kvm {
+            secret: config.secret.clone(),
+        };
+
+        let vm = Builder::new(shim, code, hook)
+            .cpuid(Policy::kvm().with(&config.cpuid))
+            .cpus(config.cpus())
+            .memory(config.memory)
+            .snapshot(config.snapshot.clone())
+            .manifest(config.manifest()?)
+            .build::<()>()?
+            .vm()?;
+
+        Ok(Arc::new(RwLock::new(vm)))
+    }
+
+    fn restore(&self, path: &Path, config: &Config) -> Result<Arc<dyn Keep>> {
+        let vm = snapshot::restore::<()>(
+            path,
+            Policy::kvm().with(&config.cpuid),
+            config.memory,
+            config.snapshot.clone(),
+        )?;
 
         Ok(Arc::new(RwLock::new(vm)))
     }
@@ -1,12 +1,14 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::*;
+use crate::backend::BuildError;
 use crate::binary::{Component, PT_ENARX_CODE, PT_ENARX_SALLYPORT};
+use crate::cpuid::Policy;
 
 use personality::Personality;
 
-use anyhow::Result;
-use kvm_ioctls::{Kvm, VmFd};
+use anyhow::{bail, Result};
+use kvm_ioctls::{Kvm, VcpuFd, VmFd};
 use lset::Span;
 use mmarinus::{perms, Kind, Map};
 use x86_64::{align_up, VirtAddr};
@@ -14,6 +16,7 @@ use x86_64::{align_up, VirtAddr};
 use goblin::elf::program_header::PT_LOAD;
 use sallyport::Block;
 use std::mem::size_of;
+use std::path::PathBuf;
 
 pub trait Hook {
     fn preferred_digest() -> measure::Kind {
@@ -37,12 +40,30 @@ pub trait Hook {
     ) -> Result<()> {
         Ok(())
     }
+
+    /// The measurement which the platform took while the VM was loaded
+    fn measurement(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// The vCPUs which had to be created while the VM was loaded
+    ///
+    /// vCPUs are created in order, so these take the first IDs.
+    fn vcpus(&mut self) -> Vec<VcpuFd> {
+        Vec::new()
+    }
 }
 
 pub struct Builder<'a, T: Hook> {
     hook: T,
     shim: Component<'a>,
-    code: Component<'a>,
+    code: Option<Component<'a>>,
+    code_size: usize,
+    cpuid: Policy,
+    cpus: usize,
+    memory: Option<usize>,
+    snapshot: Option<PathBuf>,
+    manifest: Option<Vec<u8>>,
 }
 
 pub struct Built<P: Personality, T: Hook> {
@@ -52,7 +73,58 @@ pub struct Built<P: Personality, T: Hook> {
 
 impl<'a, T: Hook> Builder<'a, T> {
     pub fn new(shim: Component<'a>, code: Component<'a>, hook: T) -> Self {
-        Self { hook, shim, code }
+        let code_size = Span::from(code.region()).count;
+        Self {
+            code: Some(code),
+            code_size,
+            ..Self::empty(shim, 0, hook)
+        }
+    }
+
+    /// Reserves an empty code slot of `size` bytes instead of loading a
+    /// payload, which the hook fills in
+    pub fn empty(shim: Component<'a>, size: usize, hook: T) -> Self {
+        Self {
+            hook,
+            shim,
+            code: None,
+            code_size: size,
+            cpuid: Policy::kvm(),
+            cpus: 1,
+            memory: None,
+            snapshot: None,
+            manifest: None,
+        }
+    }
+
+    /// Places the signed manifest of the payload on the page after its image
+    pub fn manifest(mut self, manifest: Option<Vec<u8>>) -> Self {
+        self.manifest = manifest;
+        self
+    }
+
+    /// Saves a snapshot here when the payload asks for one
+    pub fn snapshot(mut self, path: Option<PathBuf>) -> Self {
+        self.snapshot = path;
+        self
+    }
+
+    /// Limits the guest memory, which is unlimited otherwise
+    pub fn memory(mut self, memory: Option<usize>) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    /// Sets the number of vCPUs
+    pub fn cpus(mut self, cpus: usize) -> Self {
+        self.cpus = cpus;
+        self
+    }
+
+    /// Replaces the CPUID policy of the vCPUs
+    pub fn cpuid(mut self, policy: Policy) -> Self {
+        self.cpuid = policy;
+        self
     }
 
     fn load_component(&self, start: VirtAddr, component: &Component) {
@@ -71,54 +143,41 @@ impl<'a, T: Hook> Builder<'a, T> {
         let kvm = Kvm::new()?;
         let mut fd = kvm.create_vm()?;
 
-        let mem_size = align_up(
-            (Span::from(self.shim.region()).count) as _,
-            size_of::<Page>() as _,
-        ) as usize
-            + align_up(
-                (Span::from(self.code.region()).count) as _,
-                size_of::<Page>() as _,
-            ) as usize;
+        let (mut map, sallyport_range) = self.load()?;
+        let mem_size = map.size();
 
-        let shim_start = self.shim.region().start;
+        if let Some(memory) = self.memory {
+            if memory < mem_size {
+                bail!(
+                    "the keep needs at least {} MiB of memory",
+                    align_up(mem_size as _, 1 << 20) >> 20
+                );
+            }
 
-        let (mut map, region) = Self::allocate_address_space(shim_start as _, mem_size as _)?;
-
-        unsafe { fd.set_user_memory_region(region)? };
+            let host = host_memory()?;
+            if memory > host {
+                bail!("the host has only {} MiB of memory", host >> 20);
+            }
+        }
 
-        let sallyport_range = Span::from(
-            self.shim
-                .find_header(PT_ENARX_SALLYPORT)
-                .ok_or_else(|| {
-                    anyhow::anyhow!("Couldn't find SALLYPORT program header in shim executable.")
-                })?
-                .vm_range(),
-        );
-
-        let code_range = Span::from(
-            self.shim
-                .find_header(PT_ENARX_CODE)
-                .ok_or_else(|| {
-                    anyhow::anyhow!("Couldn't find CODE program header in shim executable.")
-                })?
-                .vm_range(),
-        );
+        let shim_start = self.shim.region().start;
+        let region = KvmUserspaceMemoryRegion {
+            slot: 0,
+            flags: 0,
+            guest_phys_addr: shim_start as _,
+            memory_size: mem_size as _,
+            userspace_addr: map.addr() as _,
+        };
 
-        self.load_component(VirtAddr::new(map.addr() as _) - shim_start, &self.shim);
+        unsafe { fd.set_user_memory_region(region)? };
         self.hook.shim_loaded(&mut fd, map.as_mut(), &self.shim)?;
 
-        self.load_component(
-            VirtAddr::new(map.addr() as _) - shim_start + code_range.start,
-            &self.code,
-        );
-
         let syscall_blocks = Span {
             start: VirtAddr::new(sallyport_range.start as _) - shim_start + map.addr(),
             count: NonZeroUsize::new(sallyport_range.count / size_of::<Block>()).unwrap(),
         };
 
-        let mut cpus = VecDeque::new();
-        cpus.push_back(0);
+        let cpus = (0..self.cpus as u64).collect();
 
         let vm = Vm {
             kvm,
@@ -127,6 +186,13 @@ impl<'a, T: Hook> Builder<'a, T> {
             syscall_blocks,
             _personality: PhantomData,
             cpus,
+            vcpus: VecDeque::new(),
+            cpuid: self.cpuid,
+            measurement: None,
+            memory: self.memory,
+            snapshot: self.snapshot,
+            state: None,
+            shut: false,
         };
 
         Ok(Built {
@@ -135,27 +201,88 @@ impl<'a, T: Hook> Builder<'a, T> {
         })
     }
 
-    fn allocate_address_space(
-        guest_phys_addr: u64,
-        mem_size: usize,
-    ) -> Result<(Map<perms::ReadWrite>, KvmUserspaceMemoryRegion)> {
-        let map = Map::map(mem_size)
+    /// Returns the memory of the VM as it is before it runs, without
+    /// creating the VM
+    pub fn image(mut self) -> Result<Map<perms::ReadWrite>> {
+        Ok(self.load()?.0)
+    }
+
+    /// Loads the shim, the payload and its manifest into new memory
+    ///
+    /// The sallyport range of the shim is returned along with the memory.
+    fn load(&mut self) -> Result<(Map<perms::ReadWrite>, Span<usize>)> {
+        let manifest = match (&self.code, &self.manifest) {
+            (_, None) => None,
+            (None, Some(_)) => {
+                bail!("the guest owner delivers the manifest along with the payload")
+            }
+            (Some(code), Some(manifest)) => {
+                if manifest.len() != crate::manifest::SIZE {
+                    bail!("invalid manifest ({} bytes)", manifest.len());
+                }
+
+                let offset = crate::manifest::image_size(code);
+                self.code_size = self.code_size.max(offset + size_of::<Page>());
+                Some((offset, manifest))
+            }
+        };
+
+        let mem_size = align_up(
+            (Span::from(self.shim.region()).count) as _,
+            size_of::<Page>() as _,
+        ) as usize
+            + align_up(self.code_size as _, size_of::<Page>() as _) as usize;
+
+        let shim_start = self.shim.region().start;
+        let mut map = Map::map(mem_size)
             .anywhere()
             .anonymously()
             .known::<perms::ReadWrite>(Kind::Private)?;
 
-        let region = KvmUserspaceMemoryRegion {
-            slot: 0,
-            flags: 0,
-            guest_phys_addr,
-            memory_size: mem_size as _,
-            userspace_addr: map.addr() as _,
+        let header = |type_, name| {
+            self.shim
+                .find_header(type_)
+                .map(|phdr| Span::from(phdr.vm_range()))
+                .ok_or(BuildError::MissingHeader { name })
         };
 
-        let res = (map, region);
+        let sallyport_range = header(PT_ENARX_SALLYPORT, "PT_ENARX_SALLYPORT")?;
+        let code_range = header(PT_ENARX_CODE, "PT_ENARX_CODE")?;
+        if self.code_size > code_range.count {
+            return Err(BuildError::CodeTooLarge {
+                need: self.code_size,
+                have: code_range.count,
+            }
+            .into());
+        }
+
+        self.load_component(VirtAddr::new(map.addr() as _) - shim_start, &self.shim);
+
+        if let Some(code) = &self.code {
+            self.load_component(
+                VirtAddr::new(map.addr() as _) - shim_start + code_range.start,
+                code,
+            );
+        }
 
-        Ok(res)
+        if let Some((offset, manifest)) = manifest {
+            let start = code_range.start - shim_start + offset;
+            map.as_mut()[start..][..manifest.len()].copy_from_slice(manifest);
+        }
+
+        Ok((map, sallyport_range))
+    }
+}
+
+/// The physical memory of the host, in bytes
+fn host_memory() -> Result<usize> {
+    let mut info = std::mem::MaybeUninit::<libc::sysinfo>::uninit();
+    if unsafe { libc::sysinfo(info.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
     }
+
+    let info = unsafe { info.assume_init() };
+    Ok((info.totalram as usize).saturating_mul(info.mem_unit as usize))
 }
 
 impl<P: Personality, T: Hook> Built<P, T> {
@@ -166,6 +293,12 @@ impl<P: Personality, T: Hook> Built<P, T> {
             self.vm.syscall_blocks.start,
         )?;
 
+        self.vm.measurement = self.hook.measurement();
+        for vcpu in self.hook.vcpus() {
+            self.vm.cpus.pop_front();
+            self.vm.vcpus.push_back(vcpu);
+        }
+
         Ok(self.vm)
     }
 }
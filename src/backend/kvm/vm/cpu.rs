@@ -1,21 +1,56 @@
 // SPDX-License-Identifier: Apache-2.0
 
+use super::snapshot;
 use super::Vm;
 
+use crate::backend::interrupt::{self, Hook};
 use crate::backend::{Command, Thread};
+use crate::coredump::{self, Dump, Region};
+use crate::gdb::{Registers, Resume, Target};
+use enarx_syscall::{
+    SYS_ENARX_BATCH, SYS_ENARX_DETERMINISTIC, SYS_ENARX_ENVIRON, SYS_ENARX_MOUNTS,
+    SYS_ENARX_SNAPSHOT,
+};
 use sallyport::syscall::enarx::MemInfo;
 use sallyport::syscall::{SYS_ENARX_BALLOON_MEMORY, SYS_ENARX_MEM_INFO};
 use sallyport::KVM_SYSCALL_TRIGGER_PORT;
 
 use super::personality::Personality;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
+use goblin::elf::program_header::{PF_R, PF_W, PF_X};
+use kvm_bindings::{kvm_guest_debug, KVM_GUESTDBG_ENABLE};
+use kvm_bindings::{KVM_GUESTDBG_SINGLESTEP, KVM_GUESTDBG_USE_SW_BP};
 use kvm_ioctls::{VcpuExit, VcpuFd};
-use primordial::{Address, Register};
+use primordial::{Address, Page, Register};
 use sallyport::{Block, Reply};
+use tracing::info;
 
+use std::convert::TryInto;
 use std::sync::{Arc, RwLock};
 
+/// The bits of the page table entries of the guest
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+const USER: u64 = 1 << 2;
+const HUGE: u64 = 1 << 7;
+const NO_EXECUTE: u64 = 1 << 63;
+
+/// The address in a page table entry, without the C-bit of SEV guests
+const ADDRESS: u64 = 0x0000_7fff_ffff_f000;
+
+/// A page of guest memory, aligned as `DBG_DECRYPT` requires
+#[repr(C, align(4096))]
+struct Buffer([u8; Page::SIZE]);
+
+/// Makes `KVM_RUN` return when the vCPU is interrupted, or once it is called
+///
+/// `fd` is the address of the `VcpuFd`. This only writes to the `kvm_run`
+/// structure which the vCPU shares with KVM.
+unsafe fn kick(fd: usize, _: &mut libc::ucontext_t) {
+    (*(fd as *const VcpuFd)).set_kvm_immediate_exit(1);
+}
+
 pub struct Cpu<P: Personality> {
     fd: VcpuFd,
     keep: Arc<RwLock<Vm<P>>>,
@@ -25,14 +60,242 @@ impl<P: Personality> Cpu<P> {
     pub fn new(fd: VcpuFd, keep: Arc<RwLock<Vm<P>>>) -> Result<Self> {
         Ok(Self { fd, keep })
     }
+
+    /// Finds the host address of a guest virtual address
+    ///
+    /// Also returns the number of bytes to the end of the page.
+    fn host(&self, addr: u64) -> Result<(*mut u8, usize)> {
+        let translation = self.fd.translate_gva(addr)?;
+        if translation.valid == 0 {
+            bail!("unmapped address: {:#x}", addr);
+        }
+
+        let host = self
+            .keep
+            .read()
+            .unwrap()
+            .host(translation.physical_address)
+            .ok_or_else(|| anyhow!("address outside of guest memory: {:#x}", addr))?;
+
+        let len = Page::SIZE - addr as usize % Page::SIZE;
+        Ok((host as *mut u8, len))
+    }
+
+    /// Reads a page of guest physical memory, decrypted if need be
+    fn page(&self, phys: u64) -> Result<Buffer> {
+        let keep = self.keep.read().unwrap();
+        let host = keep
+            .host(phys)
+            .ok_or_else(|| anyhow!("address outside of guest memory: {:#x}", phys))?;
+
+        let src = unsafe { std::slice::from_raw_parts(host as *const u8, Page::SIZE) };
+        let mut page = Buffer([0; Page::SIZE]);
+        P::read(&keep.fd, src, &mut page.0)?;
+        Ok(page)
+    }
+
+    /// Collects the pages which user mode can access from a page table
+    ///
+    /// The table maps the virtual addresses from `base` at `level`, which is
+    /// 4 for the PML4. A page is only writable if every level allows it, and
+    /// not executable if any level forbids it.
+    fn walk(
+        &self,
+        table: u64,
+        level: u32,
+        base: u64,
+        flags: u32,
+        pages: &mut Vec<(u64, u64, u32)>,
+    ) -> Result<()> {
+        let shift = 12 + 9 * (level - 1);
+
+        let table = self.page(table)?;
+        for (index, entry) in table.0.chunks_exact(8).enumerate() {
+            let entry = u64::from_le_bytes(entry.try_into().unwrap());
+            if entry & (PRESENT | USER) != PRESENT | USER {
+                continue;
+            }
+
+            // Addresses are sign-extended from bit 47.
+            let mut virt = base | (index as u64) << shift;
+            if virt & (1 << 47) != 0 {
+                virt |= 0xffff_0000_0000_0000;
+            }
+
+            let mut flags = flags;
+            if entry & WRITABLE == 0 {
+                flags &= !PF_W;
+            }
+            if entry & NO_EXECUTE != 0 {
+                flags &= !PF_X;
+            }
+
+            let phys = entry & ADDRESS;
+            match level {
+                1 => pages.push((virt, phys, flags)),
+                2 | 3 if entry & HUGE != 0 => {
+                    let phys = phys & !((1 << shift) - 1);
+                    for offset in (0..1u64 << shift).step_by(Page::SIZE) {
+                        pages.push((virt + offset, phys + offset, flags));
+                    }
+                }
+                _ => self.walk(phys, level - 1, virt, flags, pages)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<P: Personality> Dump for Cpu<P> {
+    fn registers(&mut self) -> Result<Registers> {
+        self.read_registers()
+    }
+
+    /// Finds the memory of the payload in the page tables of the guest,
+    /// which map it for user mode only
+    fn memory(&mut self) -> Result<Vec<Region>> {
+        let cr3 = self.fd.get_sregs()?.cr3 & ADDRESS;
+        let mut pages = Vec::new();
+        self.walk(cr3, 4, 0, PF_R | PF_W | PF_X, &mut pages)?;
+
+        let mut regions = Vec::new();
+        for (virt, phys, flags) in pages {
+            // Pages mapped beyond the memory of the guest are left out.
+            if let Ok(page) = self.page(phys) {
+                coredump::push(&mut regions, virt, flags, &page.0);
+            }
+        }
+
+        Ok(regions)
+    }
+}
+
+impl<P: Personality> Target for Cpu<P> {
+    fn read_registers(&mut self) -> Result<Registers> {
+        let regs = self.fd.get_regs()?;
+
+        Ok(Registers {
+            gpr: [
+                regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp,
+                regs.r8, regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15,
+                regs.rip,
+            ],
+            eflags: regs.rflags as u32,
+        })
+    }
+
+    fn write_registers(&mut self, registers: &Registers) -> Result<()> {
+        let mut regs = self.fd.get_regs()?;
+
+        let [rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8, r9, r10, r11, r12, r13, r14, r15, rip] =
+            registers.gpr;
+        regs.rax = rax;
+        regs.rbx = rbx;
+        regs.rcx = rcx;
+        regs.rdx = rdx;
+        regs.rsi = rsi;
+        regs.rdi = rdi;
+        regs.rbp = rbp;
+        regs.rsp = rsp;
+        regs.r8 = r8;
+        regs.r9 = r9;
+        regs.r10 = r10;
+        regs.r11 = r11;
+        regs.r12 = r12;
+        regs.r13 = r13;
+        regs.r14 = r14;
+        regs.r15 = r15;
+        regs.rip = rip;
+        regs.rflags = (regs.rflags & !0xffff_ffff) | u64::from(registers.eflags);
+
+        self.fd.set_regs(&regs)?;
+        Ok(())
+    }
+
+    fn read_memory(&mut self, mut addr: u64, mut buf: &mut [u8]) -> Result<()> {
+        while !buf.is_empty() {
+            let (src, len) = self.host(addr)?;
+            let (head, tail) = std::mem::take(&mut buf).split_at_mut(len.min(buf.len()));
+            head.copy_from_slice(unsafe { std::slice::from_raw_parts(src, head.len()) });
+
+            addr += head.len() as u64;
+            buf = tail;
+        }
+
+        Ok(())
+    }
+
+    fn write_memory(&mut self, mut addr: u64, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let (dst, len) = self.host(addr)?;
+            let (head, tail) = data.split_at(len.min(data.len()));
+            unsafe { std::slice::from_raw_parts_mut(dst, head.len()) }.copy_from_slice(head);
+
+            addr += head.len() as u64;
+            data = tail;
+        }
+
+        Ok(())
+    }
+
+    fn resume(&mut self, resume: Resume) -> Result<()> {
+        let control = match resume {
+            Resume::Continue => KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_SW_BP,
+            Resume::Step => KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_SW_BP | KVM_GUESTDBG_SINGLESTEP,
+            Resume::Detach | Resume::Kill => 0,
+        };
+
+        let debug = kvm_guest_debug {
+            control,
+            ..Default::default()
+        };
+
+        self.fd.set_guest_debug(&debug)?;
+        Ok(())
+    }
 }
 
 impl<P: Personality> Thread for Cpu<P> {
+    fn debug(&mut self) -> Option<&mut dyn Target> {
+        Some(self)
+    }
+
+    fn dump(&mut self) -> Option<&mut dyn Dump> {
+        Some(self)
+    }
+
     fn enter(&mut self) -> Result<Command> {
-        match self.fd.run()? {
+        // A keep which was shut down has no memory left to run in.
+        if self.keep.read().unwrap().shut {
+            return Ok(Command::Halt);
+        }
+
+        let hook = Hook {
+            func: kick,
+            data: &self.fd as *const VcpuFd as usize,
+        };
+
+        let exit = {
+            let _armed = interrupt::arm(&hook);
+            self.fd.run()
+        };
+        self.fd.set_kvm_immediate_exit(0);
+
+        let exit = match exit {
+            // A signal of the host interrupted the vCPU.
+            Err(e) if e.errno() == libc::EINTR => return Ok(Command::Continue),
+            _ if self.keep.read().unwrap().shut => return Ok(Command::Halt),
+            exit => exit?,
+        };
+
+        match exit {
             VcpuExit::IoOut(port, data) => match port {
                 KVM_SYSCALL_TRIGGER_PORT => {
                     let mut keep = self.keep.write().unwrap();
+                    if keep.shut {
+                        return Ok(Command::Halt);
+                    }
 
                     debug_assert_eq!(data.len(), 2);
                     let block_nr = data[0] as usize + ((data[1] as usize) << 8);
@@ -49,17 +312,43 @@ impl<P: Personality> Thread for Cpu<P> {
                     let syscall_nr: i64 = unsafe { sallyport.msg.req.num.into() };
 
                     match syscall_nr {
-                        0..=512 => Ok(Command::SysCall(sallyport)),
+                        0..=512
+                        | SYS_ENARX_MOUNTS
+                        | SYS_ENARX_ENVIRON
+                        | SYS_ENARX_BATCH
+                        | SYS_ENARX_DETERMINISTIC => Ok(Command::SysCall(sallyport)),
 
                         SYS_ENARX_BALLOON_MEMORY => {
                             let pages = unsafe { sallyport.msg.req.arg[0].into() };
 
-                            let result = keep.add_memory(pages).map(|addr| {
-                                let ok_result: [Register<usize>; 2] = [addr.into(), 0.into()];
-                                ok_result
-                            })?;
+                            // The shim asks for less when it is refused.
+                            let result = match keep.fits(pages) {
+                                true => keep.add_memory(pages).map(|addr| {
+                                    let ok_result: [Register<usize>; 2] = [addr.into(), 0.into()];
+                                    Ok(ok_result)
+                                })?,
+                                false => Err(libc::ENOMEM),
+                            };
+
+                            sallyport.msg.rep = Reply::from(result);
+                            Ok(Command::Continue)
+                        }
+
+                        SYS_ENARX_SNAPSHOT => {
+                            let result: sallyport::Result = match &keep.snapshot {
+                                Some(path) => {
+                                    // The restored keeps find this reply.
+                                    let restored: [Register<usize>; 2] = [1.into(), 0.into()];
+                                    sallyport.msg.rep = Reply::from(Ok(restored));
+                                    snapshot::save(&keep, &self.fd, path)?;
+                                    info!(path = %path.display(), "saved a snapshot of the keep");
+
+                                    Ok([0.into(), 0.into()])
+                                }
+                                None => Err(libc::ENOSYS),
+                            };
 
-                            sallyport.msg.rep = Reply::from(Ok(result));
+                            sallyport.msg.rep = Reply::from(result);
                             Ok(Command::Continue)
                         }
 
@@ -89,6 +378,10 @@ impl<P: Personality> Thread for Cpu<P> {
                 }
                 _ => Err(anyhow!("data from unexpected port: {}", port)),
             },
+            VcpuExit::Debug(_) => Ok(Command::Trap),
+            VcpuExit::Hlt => Ok(Command::Halt),
+            // The shim triple faults when it detects an attack.
+            VcpuExit::Shutdown => Ok(Command::Attacked),
             exit_reason => {
                 if cfg!(debug_assertions) {
                     Err(anyhow!(
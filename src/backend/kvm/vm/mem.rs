@@ -23,6 +23,10 @@ impl Region {
         &mut self._backing
     }
 
+    pub fn as_kvm(&self) -> KvmUserspaceMemoryRegion {
+        self.kvm_region
+    }
+
     pub fn as_guest(&self) -> Span<PhysAddr, u64> {
         Span {
             start: PhysAddr::new(self.kvm_region.guest_phys_addr),
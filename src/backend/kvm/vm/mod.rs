@@ -5,8 +5,10 @@ mod cpu;
 pub mod measure;
 mod mem;
 pub mod personality;
+pub mod snapshot;
 
-use crate::backend::{Keep, Thread};
+use crate::backend::{Keep, Thread, Usage};
+use crate::cpuid::Policy;
 
 use cpu::Cpu;
 use mem::Region;
@@ -18,7 +20,7 @@ pub use kvm_bindings::kvm_userspace_memory_region as KvmUserspaceMemoryRegion;
 
 use anyhow::Result;
 use kvm_bindings::KVM_MAX_CPUID_ENTRIES;
-use kvm_ioctls::{Kvm, VmFd};
+use kvm_ioctls::{Kvm, VcpuFd, VmFd};
 use lset::Span;
 use mmarinus::{perms, Kind, Map};
 use primordial::Page;
@@ -29,6 +31,7 @@ use x86_64::VirtAddr;
 use std::collections::VecDeque;
 use std::marker::PhantomData;
 use std::num::NonZeroUsize;
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 
 pub struct Vm<P: Personality> {
@@ -38,9 +41,43 @@ pub struct Vm<P: Personality> {
     syscall_blocks: Span<VirtAddr, NonZeroUsize>,
     _personality: PhantomData<P>,
     cpus: VecDeque<u64>,
+    /// vCPUs created while the VM was loaded, which are spawned first
+    vcpus: VecDeque<VcpuFd>,
+    cpuid: Policy,
+    measurement: Option<Vec<u8>>,
+    /// The most guest memory the keep may balloon to, in bytes
+    memory: Option<usize>,
+    /// Where a snapshot is saved when the payload asks for one
+    snapshot: Option<PathBuf>,
+    /// The state which the first vCPU is restored to
+    state: Option<snapshot::Vcpu>,
+    /// Whether the keep was shut down, so that its vCPUs halt
+    shut: bool,
 }
 
 impl<P: Personality> Vm<P> {
+    /// Finds the host address of a guest physical address
+    fn host(&self, phys: u64) -> Option<u64> {
+        let region = self.regions.iter().find(|r| {
+            let guest = r.as_guest();
+            phys >= guest.start.as_u64() && phys - guest.start.as_u64() < guest.count
+        })?;
+
+        Some(region.as_virt().start.as_u64() + phys - region.as_guest().start.as_u64())
+    }
+
+    /// Returns true, if the guest memory may grow by `pages`
+    pub fn fits(&self, pages: usize) -> bool {
+        let used: u64 = self.regions.iter().map(|r| r.as_guest().count).sum();
+        let size = pages.checked_mul(Page::SIZE).map(|s| used + s as u64);
+
+        match (self.memory, size) {
+            (_, None) => false,
+            (Some(limit), Some(size)) => size <= limit as u64,
+            (None, Some(_)) => true,
+        }
+    }
+
     pub fn add_memory(&mut self, pages: usize) -> Result<i64> {
         let mem_size = pages * Page::SIZE;
         let last_region = self.regions.last().unwrap().as_guest();
@@ -71,18 +108,83 @@ impl<P: Personality> Vm<P> {
     }
 }
 
+/// Creates a vCPU, which the shim boots as an application processor unless
+/// it is the first one
+///
+/// The shim learns the index of an application processor from `rdi`.
+pub fn create_vcpu(vm: &VmFd, id: u64) -> Result<VcpuFd> {
+    let vcpu = vm.create_vcpu(id)?;
+
+    if id > 0 {
+        let mut regs = vcpu.get_regs()?;
+        regs.rdi = id;
+        vcpu.set_regs(&regs)?;
+    }
+
+    Ok(vcpu)
+}
+
 impl<P: 'static + Personality> Keep for RwLock<Vm<P>> {
     fn spawn(self: Arc<Self>) -> Result<Option<Box<dyn Thread>>> {
         let mut keep = self.write().unwrap();
 
-        let vcpu = match keep.cpus.pop_front() {
-            Some(id) => keep.fd.create_vcpu(id)?,
-            None => return Ok(None),
+        let vcpu = match keep.vcpus.pop_front() {
+            Some(vcpu) => vcpu,
+            None => match keep.cpus.pop_front() {
+                Some(id) => create_vcpu(&keep.fd, id)?,
+                None => return Ok(None),
+            },
         };
 
-        vcpu.set_cpuid2(&keep.kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)?)?;
+        let mut cpuid = keep.kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)?;
+        for entry in cpuid.as_mut_slice() {
+            let values = [entry.eax, entry.ebx, entry.ecx, entry.edx];
+            let values = keep.cpuid.apply(entry.function, entry.index, values);
+            entry.eax = values[0];
+            entry.ebx = values[1];
+            entry.ecx = values[2];
+            entry.edx = values[3];
+        }
+
+        vcpu.set_cpuid2(&cpuid)?;
+        if let Some(state) = keep.state.take() {
+            state.restore(&vcpu)?;
+        }
 
         let thread = Cpu::new(vcpu, self.clone())?;
         Ok(Some(Box::new(thread)))
     }
+
+    fn measurement(&self) -> Option<Vec<u8>> {
+        self.read().unwrap().measurement.clone()
+    }
+
+    fn shutdown(self: Arc<Self>) -> Result<Usage> {
+        let mut keep = self.write().unwrap();
+        let vm = &mut *keep;
+        vm.shut = true;
+
+        // KVM lets go of the guest memory before it is unmapped.
+        let mut usage = Usage::default();
+        for region in vm.regions.drain(..) {
+            let mut slot = region.as_kvm();
+            usage.memory += slot.memory_size as usize;
+
+            P::remove_memory(&mut vm.fd, &slot)?;
+            slot.memory_size = 0;
+            unsafe { vm.fd.set_user_memory_region(slot)? };
+        }
+
+        // The vCPUs which were never spawned are closed, and no more are
+        // created.
+        usage.fds = vm.vcpus.len();
+        vm.vcpus.clear();
+        vm.cpus.clear();
+        drop(keep);
+
+        // Each vCPU holds the keep, and the VM with its descriptor stays
+        // until the last one is dropped.
+        usage.threads = Arc::strong_count(&self) - 1;
+        Ok(usage)
+    }
 }
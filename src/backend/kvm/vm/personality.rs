@@ -1,5 +1,6 @@
 // SPDX-License-Identifier: Apache-2.0
 
+use anyhow::Result;
 use kvm_ioctls::VmFd;
 
 use super::KvmUserspaceMemoryRegion;
@@ -13,6 +14,17 @@ use super::KvmUserspaceMemoryRegion;
 /// If a personality is not needed, pass in Unit.
 pub trait Personality {
     fn add_memory(_vm: &mut VmFd, _region: &KvmUserspaceMemoryRegion) {}
+
+    /// Undoes `add_memory()` before the memory is removed from the VM
+    fn remove_memory(_vm: &mut VmFd, _region: &KvmUserspaceMemoryRegion) -> Result<()> {
+        Ok(())
+    }
+
+    /// Reads guest memory, which is at `src` on the host, into `dst`
+    fn read(_vm: &VmFd, src: &[u8], dst: &mut [u8]) -> Result<()> {
+        dst.copy_from_slice(src);
+        Ok(())
+    }
 }
 
 impl Personality for () {}
@@ -0,0 +1,325 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Snapshots of KVM keeps
+//!
+//! A payload which is done initializing asks for a snapshot with
+//! `SYS_ENARX_SNAPSHOT`. When the loader was given a path with `--snapshot`,
+//! it saves the guest memory and the state of the vCPU there, and keeps
+//! started with `--restore` pick up where the payload left off. The syscall
+//! returns 0 in the original keep and 1 in the restored ones.
+//!
+//! The file holds the length of a CBOR header, the header and then the memory
+//! of each region at page-aligned offsets, which restored keeps map
+//! copy-on-write. The shim knows the host addresses of the guest memory (see
+//! its `HostMap`), so the regions are mapped at the same host addresses again.
+
+use super::mem::Region;
+use super::personality::Personality;
+use super::{create_vcpu, KvmUserspaceMemoryRegion, Vm};
+use crate::backend::kvm::SHIM;
+use crate::cpuid::Policy;
+
+use anyhow::{anyhow, bail, Result};
+use kvm_bindings::{kvm_msr_entry, Msrs};
+use kvm_ioctls::{Kvm, VcpuFd};
+use lset::Span;
+use mmarinus::{perms, Kind, Map};
+use openssl::sha::sha256;
+use primordial::Page;
+use serde::{Deserialize, Serialize};
+use x86_64::{align_up, VirtAddr};
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::marker::PhantomData;
+use std::mem::{size_of, size_of_val};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+/// The largest header which is read
+const MAX_HEADER: usize = 64 * 1024;
+
+/// The MSRs which the shim sets, besides those in the special registers
+const MSRS: &[u32] = &[
+    0x0000_0010, // IA32_TSC
+    0x0000_0277, // IA32_PAT
+    0xc000_0081, // STAR
+    0xc000_0082, // LSTAR
+    0xc000_0083, // CSTAR
+    0xc000_0084, // SFMASK
+    0xc000_0102, // KERNEL_GS_BASE
+];
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    /// The SHA-256 digest of the shim which the keep runs
+    shim: [u8; 32],
+    regions: Vec<Memory>,
+    /// The host address and the number of the syscall blocks
+    blocks: (u64, usize),
+    vcpu: Vcpu,
+}
+
+/// A region of guest memory
+#[derive(Serialize, Deserialize)]
+struct Memory {
+    guest: u64,
+    host: u64,
+    size: u64,
+}
+
+/// The state of a vCPU
+#[derive(Serialize, Deserialize)]
+pub struct Vcpu {
+    regs: Vec<u8>,
+    sregs: Vec<u8>,
+    fpu: Vec<u8>,
+    xcrs: Vec<u8>,
+    msrs: Vec<(u32, u64)>,
+}
+
+impl Vcpu {
+    fn save(fd: &VcpuFd) -> Result<Self> {
+        let entries: Vec<_> = MSRS
+            .iter()
+            .map(|&index| kvm_msr_entry {
+                index,
+                ..Default::default()
+            })
+            .collect();
+
+        let mut msrs = Msrs::from_entries(&entries).map_err(|e| anyhow!("{:?}", e))?;
+        let read = fd.get_msrs(&mut msrs)?;
+        if read != MSRS.len() {
+            bail!("unable to read MSR {:#x}", MSRS[read]);
+        }
+
+        Ok(Self {
+            regs: bytes(&fd.get_regs()?),
+            sregs: bytes(&fd.get_sregs()?),
+            fpu: bytes(&fd.get_fpu()?),
+            xcrs: bytes(&fd.get_xcrs()?),
+            msrs: msrs.as_slice().iter().map(|e| (e.index, e.data)).collect(),
+        })
+    }
+
+    /// Restores the state
+    ///
+    /// KVM checks the special registers against the CPUID of the vCPU, so it
+    /// must be set before.
+    pub fn restore(&self, fd: &VcpuFd) -> Result<()> {
+        let entries: Vec<_> = self
+            .msrs
+            .iter()
+            .map(|&(index, data)| kvm_msr_entry {
+                index,
+                data,
+                ..Default::default()
+            })
+            .collect();
+
+        fd.set_xcrs(&from_bytes(&self.xcrs)?)?;
+        fd.set_sregs(&from_bytes(&self.sregs)?)?;
+        fd.set_regs(&from_bytes(&self.regs)?)?;
+        fd.set_fpu(&from_bytes(&self.fpu)?)?;
+
+        let msrs = Msrs::from_entries(&entries).map_err(|e| anyhow!("{:?}", e))?;
+        if fd.set_msrs(&msrs)? != entries.len() {
+            bail!("unable to restore the MSRs");
+        }
+
+        Ok(())
+    }
+}
+
+/// Saves a snapshot of the keep while the vCPU is in a hostcall
+pub fn save<P: Personality>(keep: &Vm<P>, vcpu: &VcpuFd, path: &Path) -> Result<()> {
+    // KVM only moves the vCPU past the `out` of the hostcall when it is run
+    // again, which an immediate exit does without running the guest.
+    vcpu.set_kvm_immediate_exit(1);
+    let completed = vcpu.run().map(|_| ());
+    vcpu.set_kvm_immediate_exit(0);
+    match completed {
+        Err(e) if e.errno() == libc::EINTR => (),
+        Err(e) => return Err(e.into()),
+        Ok(()) => bail!("the vCPU ran while the snapshot was taken"),
+    }
+
+    let header = Header {
+        shim: sha256(SHIM.bytes()?),
+        regions: keep
+            .regions
+            .iter()
+            .map(|r| Memory {
+                guest: r.as_guest().start.as_u64(),
+                host: r.as_virt().start.as_u64(),
+                size: r.as_guest().count,
+            })
+            .collect(),
+        blocks: (
+            keep.syscall_blocks.start.as_u64(),
+            keep.syscall_blocks.count.get(),
+        ),
+        vcpu: Vcpu::save(vcpu)?,
+    };
+
+    let mut encoded = Vec::new();
+    ciborium::ser::into_writer(&header, &mut encoded)
+        .map_err(|e| anyhow!("unable to encode the snapshot: {:?}", e))?;
+
+    let len = size_of::<u64>() + encoded.len();
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&(encoded.len() as u64).to_le_bytes())?;
+    file.write_all(&encoded)?;
+    file.write_all(&vec![0; align_up(len as _, Page::SIZE as _) as usize - len])?;
+
+    for region in &keep.regions {
+        let virt = region.as_virt();
+        let memory = unsafe { std::slice::from_raw_parts(virt.start.as_ptr(), virt.count as _) };
+        file.write_all(memory)?;
+    }
+
+    file.flush()?;
+    Ok(())
+}
+
+/// Creates a keep from a snapshot
+pub fn restore<P: Personality>(
+    path: &Path,
+    cpuid: Policy,
+    memory: Option<usize>,
+    snapshot: Option<PathBuf>,
+) -> Result<Vm<P>> {
+    let mut file = File::open(path)?;
+
+    let mut len = [0; size_of::<u64>()];
+    file.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len) as usize;
+    if len > MAX_HEADER {
+        bail!("invalid snapshot: {}", path.display());
+    }
+
+    let mut encoded = vec![0; len];
+    file.read_exact(&mut encoded)?;
+    let header: Header = ciborium::de::from_reader(&encoded[..])
+        .map_err(|e| anyhow!("invalid snapshot {}: {:?}", path.display(), e))?;
+
+    if header.shim != sha256(SHIM.bytes()?) {
+        bail!("the snapshot was taken with another shim");
+    }
+
+    let size: u64 = header.regions.iter().map(|m| m.size).sum();
+    if matches!(memory, Some(limit) if size > limit as u64) {
+        bail!("the snapshot has {} MiB of memory", size >> 20);
+    }
+
+    let kvm = Kvm::new()?;
+    let mut fd = kvm.create_vm()?;
+
+    let mut offset = align_up((size_of::<u64>() + len) as _, Page::SIZE as _);
+    let mut regions = Vec::new();
+    for (slot, mem) in header.regions.iter().enumerate() {
+        if !vacant(mem.host, mem.size)? {
+            bail!("the host memory at {:#x} is in use", mem.host);
+        }
+
+        let map = unsafe {
+            Map::map(mem.size as _)
+                .onto(mem.host as _)
+                .from(&mut file, offset as _)
+                .known::<perms::ReadWrite>(Kind::Private)?
+        };
+
+        let region = KvmUserspaceMemoryRegion {
+            slot: slot as _,
+            flags: 0,
+            guest_phys_addr: mem.guest,
+            memory_size: mem.size,
+            userspace_addr: mem.host,
+        };
+
+        unsafe {
+            fd.set_user_memory_region(region)?;
+        }
+
+        // The first region is the one the VM was built with.
+        if slot > 0 {
+            P::add_memory(&mut fd, &region);
+        }
+
+        regions.push(Region::new(region, map));
+        offset += mem.size;
+    }
+
+    let (start, count) = header.blocks;
+    let count = NonZeroUsize::new(count).ok_or_else(|| anyhow!("the snapshot has no blocks"))?;
+
+    let vcpu = create_vcpu(&fd, 0)?;
+
+    Ok(Vm {
+        kvm,
+        fd,
+        regions,
+        syscall_blocks: Span {
+            start: VirtAddr::new(start),
+            count,
+        },
+        _personality: PhantomData,
+        cpus: VecDeque::new(),
+        vcpus: vec![vcpu].into(),
+        cpuid,
+        measurement: None,
+        memory,
+        snapshot,
+        state: Some(header.vcpu),
+        shut: false,
+    })
+}
+
+/// Returns true, if nothing is mapped at the host addresses
+fn vacant(start: u64, size: u64) -> Result<bool> {
+    let maps = std::fs::read_to_string("/proc/self/maps")?;
+
+    for line in maps.lines() {
+        let range = line.split(' ').next().and_then(|r| r.split_once('-'));
+        if let Some((from, to)) = range {
+            let from = u64::from_str_radix(from, 16)?;
+            let to = u64::from_str_radix(to, 16)?;
+            if from < start + size && start < to {
+                return Ok(false);
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+/// The bytes of a KVM structure
+fn bytes<T>(value: &T) -> Vec<u8> {
+    let ptr = value as *const T as *const u8;
+    unsafe { std::slice::from_raw_parts(ptr, size_of_val(value)) }.to_vec()
+}
+
+/// Reads a KVM structure
+///
+/// Every bit pattern is a valid structure, since they only hold integers.
+fn from_bytes<T>(bytes: &[u8]) -> Result<T> {
+    if bytes.len() != size_of::<T>() {
+        bail!("invalid snapshot of a {}", std::any::type_name::<T>());
+    }
+
+    Ok(unsafe { (bytes.as_ptr() as *const T).read_unaligned() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vacancy() {
+        let used = MSRS.as_ptr() as u64;
+        assert!(!vacant(used, 1).unwrap());
+        assert!(vacant(0, Page::SIZE as _).unwrap());
+    }
+}
@@ -1,26 +1,139 @@
 // SPDX-License-Identifier: Apache-2.0
 
+//! The technologies which keeps are built with
+//!
+//! A `Backend` builds a `Keep` from a shim and a payload, and the threads of
+//! the keep are entered until they exit to the host, e.g. to have it execute
+//! a syscall, or until the host interrupts them (see `interrupt`).
+//!
+//! Keeps have no devices, not even the virtual machines of KVM and SEV: all
+//! that they ask of the host goes through sallyport. A payload reaches the
+//! vsock services of the host with `AF_VSOCK` sockets, which the host creates
+//! for it like any other socket (see `policy`).
+
 #[cfg(feature = "backend-kvm")]
-pub mod kvm;
+pub(crate) mod kvm;
+
+#[cfg(feature = "backend-sev")]
+pub(crate) mod sev;
 
 #[cfg(feature = "backend-sgx")]
-pub mod sgx;
+pub(crate) mod sgx;
+
+#[cfg(feature = "backend-nil")]
+pub(crate) mod nil;
 
+#[cfg(any(feature = "backend-kvm", feature = "backend-sgx"))]
+mod shim;
+
+pub(crate) mod interrupt;
 mod probe;
 
-use crate::binary::Component;
+use crate::binary::{Component, NOTE_ENARX_SALLYPORT_ABI, NOTE_ENARX_STACK, PT_ENARX_CODE};
+use crate::coredump::Dump;
+use crate::cpuid::Rule;
+use crate::gdb::Target;
+use crate::metrics::Metrics;
+use crate::policy::SyscallPolicy;
+use crate::streams::Streams;
 
+use std::fmt::Write;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use sallyport::Block;
 
-pub trait Backend {
+/// The backends which this build supports, by preference
+pub fn all() -> Vec<Box<dyn Backend>> {
+    vec![
+        #[cfg(feature = "backend-sgx")]
+        Box::new(sgx::Backend),
+        #[cfg(feature = "backend-sev")]
+        Box::new(sev::Backend),
+        #[cfg(feature = "backend-kvm")]
+        Box::new(kvm::Backend),
+        #[cfg(feature = "backend-nil")]
+        Box::new(nil::Backend),
+    ]
+}
+
+/// Selects the backend by name or, with `auto`, the first supported one
+///
+/// Backends are tried in the order in which `all()` lists them. `auto` only
+/// selects backends which protect the keep (see `Backend::protected()`). If
+/// no candidate is supported, the error lists the failed checks of each.
+pub fn select<'a>(backends: &'a [Box<dyn Backend>], name: &str) -> Result<&'a dyn Backend> {
+    let candidates = backends
+        .iter()
+        .filter(|b| match name {
+            "auto" => b.protected(),
+            name => name == b.name(),
+        })
+        .collect::<Vec<_>>();
+
+    if candidates.is_empty() {
+        let names = backends.iter().map(|b| b.name()).collect::<Vec<_>>();
+        bail!(
+            "unknown keep backend '{}' (expected `auto` or one of: {})",
+            name,
+            names.join(", ")
+        );
+    }
+
+    let mut failures = String::new();
+    for backend in candidates {
+        let failed = backend
+            .data()
+            .into_iter()
+            .filter(|d| !d.pass)
+            .collect::<Vec<_>>();
+
+        if failed.is_empty() {
+            return Ok(&**backend);
+        }
+
+        write!(failures, "\n  {}:", backend.name())?;
+        for datum in failed {
+            match datum.info {
+                Some(info) => write!(failures, "\n    {} ({})", datum.name, info)?,
+                None => write!(failures, "\n    {}", datum.name)?,
+            }
+        }
+    }
+
+    bail!("no supported keep backend found:{}", failures)
+}
+
+/// A technology which keeps are built with, such as SGX
+///
+/// Backends hold no state of the keeps they build, so threads which build
+/// and run keeps concurrently share them.
+pub trait Backend: Send + Sync {
     /// The name of the backend
     fn name(&self) -> &'static str;
 
     /// The builtin shim
-    fn shim(&self) -> &'static [u8];
+    ///
+    /// Backends which run the payload without a shim return an empty one.
+    /// Loaders built without their shims load them when they are first
+    /// needed (see `shim`), which may fail.
+    fn shim(&self) -> Result<&'static [u8]>;
+
+    /// Checks that a shim has what the backend needs of its shims, beyond
+    /// what every backend needs (see `check_shim()`)
+    fn check_shim(&self, _shim: &Component) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether the keeps are protected from the host
+    ///
+    /// Backends which aren't only serve to test payloads, so they must be
+    /// selected by name.
+    fn protected(&self) -> bool {
+        true
+    }
 
     /// Whether or not the platform has support for this keep type
     fn have(&self) -> bool {
@@ -31,9 +144,387 @@ pub trait Backend {
     fn data(&self) -> Vec<Datum>;
 
     /// Create a keep instance on this backend
-    fn build(&self, shim: Component, code: Component) -> Result<Arc<dyn Keep>>;
+    fn build(&self, shim: Component, code: Component, config: &Config) -> Result<Arc<dyn Keep>>;
+
+    /// Computes the measurement of a keep without creating it
+    ///
+    /// This is what attestation compares against, e.g. MRENCLAVE for SGX,
+    /// which is the same for every build over the same shim, payload and
+    /// settings. For SEV, it is the launch digest: the SHA-256 hash of the
+    /// memory of the guest as it is encrypted, from which the guest owner
+    /// computes the launch measurement. SEV-ES keeps have no predictable
+    /// digest.
+    ///
+    /// The measurements of the fixtures in `tests/measure` are recorded for
+    /// every release of the shims, so that a change which changes them fails
+    /// the tests.
+    fn measure(&self, _shim: Component, _code: Component, _config: &Config) -> Result<Vec<u8>> {
+        anyhow::bail!("the {} backend has no measurement", self.name())
+    }
+
+    /// Creates a keep with an empty code slot of `size` bytes, into which the
+    /// guest owner delivers the payload after attesting the keep
+    ///
+    /// The guest owner sends the image of the slot (the `PT_LOAD` segments of
+    /// the payload at their physical addresses) as one `LAUNCH_SECRET` packet
+    /// per page, so that only the keep can read it (SEV only).
+    fn build_empty(
+        &self,
+        _shim: Component,
+        _size: usize,
+        _config: &Config,
+    ) -> Result<Arc<dyn Keep>> {
+        anyhow::bail!(
+            "the {} backend can't receive the payload from the guest owner",
+            self.name()
+        )
+    }
+
+    /// Creates a keep from a snapshot of another one (see `Config::snapshot`)
+    fn restore(&self, _path: &Path, _config: &Config) -> Result<Arc<dyn Keep>> {
+        anyhow::bail!("the {} backend can't restore snapshots", self.name())
+    }
+
+    /// Predicts how much of the protected memory of the platform a keep needs
+    ///
+    /// Backends without such a limit return `None`.
+    fn footprint(
+        &self,
+        _shim: Component,
+        _code: Component,
+        _config: &Config,
+    ) -> Result<Option<Datum>> {
+        Ok(None)
+    }
+}
+
+/// Tunables for building a keep
+///
+/// Backends ignore the settings which do not apply to them.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// The number of bytes to reserve for the shim heap
+    ///
+    /// When `None`, the default size advertised by the shim is used.
+    pub heap_size: Option<usize>,
+
+    /// The number of vCPUs of VM-based keeps
+    ///
+    /// When `None`, keeps get a single vCPU. The shim brings the others up,
+    /// but they only halt, since the payload runs on a single thread, so the
+    /// payload sees a single CPU, e.g. in `sched_getaffinity()`. SEV-ES keeps
+    /// are measured with the registers of every vCPU, so their number is part
+    /// of the measurement.
+    pub cpus: Option<NonZeroUsize>,
+
+    /// The most memory VM-based keeps may use, in bytes
+    ///
+    /// When `None`, their memory grows for as long as the host has some.
+    /// Past the limit, the host refuses to add memory and the payload sees
+    /// `ENOMEM`. It must hold the shim and the payload, and may not exceed
+    /// the memory of the host.
+    pub memory: Option<usize>,
+
+    /// The signed manifest of the payload, which the shim verifies (KVM and
+    /// SEV only)
+    pub manifest: Option<PathBuf>,
+
+    /// A secret for the payload, which it reads with `SYS_ENARX_GETSECRET`
+    ///
+    /// The KVM backend places it where SEV's `LAUNCH_SECRET` would put it,
+    /// where the host can read it, so it only serves tests. SEV keeps get
+    /// their secret from the guest owner instead (see `owner`), and SGX keeps
+    /// don't accept secrets, since they have no attested channel for them.
+    pub secret: Option<Vec<u8>>,
+
+    /// The statistics which the backend updates as the keep runs
+    pub metrics: Option<Metrics>,
+
+    /// What the output of the payload may bypass the executor with (SGX only)
+    ///
+    /// When `None`, all syscalls go through the executor.
+    pub ring: Option<StdioRing>,
+
+    /// Rules applied after the CPUID policy of the backend
+    pub cpuid: Vec<Rule>,
+
+    /// Overrides of the SGX launch parameters
+    pub sgx: SgxParameters,
+
+    /// Overrides of the SEV launch parameters
+    pub sev: SevParameters,
+
+    /// Where a snapshot of the keep is saved when the payload asks for one
+    /// (KVM only)
+    ///
+    /// The payload asks with `SYS_ENARX_SNAPSHOT` once it is initialized, and
+    /// the syscall returns 1 in the keeps restored from the snapshot (see
+    /// `Backend::restore()`), which share the pages of the file until they
+    /// write to them. Only the standard streams of the payload survive, and
+    /// the restored keeps share whatever randomness it drew before. Snapshots
+    /// only work with the loader which took them, and with a single vCPU.
+    pub snapshot: Option<PathBuf>,
+
+    /// The guest owner which attests the keep and releases its secret (SEV only)
+    ///
+    /// When `None`, the loader acts as the guest owner itself.
+    pub owner: Option<GuestOwner>,
+
+    /// Whether the keep is built to be debugged
+    ///
+    /// Backends which protect the keep from the host (e.g. the SGX DEBUG
+    /// attribute) only allow debugging when this is set. The SGX attribute
+    /// lets the host read the memory of the enclave and changes its identity;
+    /// without it, the attribute is checked to be cleared before the enclave
+    /// is created.
+    pub debug: bool,
+
+    /// Whether the backend may reuse what it computed for an identical keep
+    ///
+    /// SGX keeps reuse their signature and SEV keeps the certificates of the
+    /// platform, which are cached on the disk.
+    pub cache: bool,
+
+    /// Whether a keep may need more protected memory than the platform has
+    ///
+    /// The kernel pages such a keep in and out (e.g. the SGX EPC), which is
+    /// slow, so it is only launched when this is set.
+    pub overcommit: bool,
+}
+
+impl Config {
+    /// The number of vCPUs of VM-based keeps
+    #[cfg(feature = "backend-kvm")]
+    pub fn cpus(&self) -> usize {
+        self.cpus.map_or(1, NonZeroUsize::get)
+    }
+
+    /// Fills in the settings which the payload requests with notes
+    ///
+    /// The settings of the user win over the notes of the payload. The stack
+    /// of the payload is fixed by the shim, so it is only checked.
+    pub fn requested(&self, shim: &Component, code: &Component) -> Result<Self> {
+        let requests = code.requests()?;
+
+        let have = unsafe { shim.read_note::<u64>("enarx", NOTE_ENARX_STACK)? };
+        if let (Some(need), Some(have)) = (requests.stack, have) {
+            if need > have as usize {
+                return Err(BuildError::StackTooLarge {
+                    need,
+                    have: have as usize,
+                }
+                .into());
+            }
+        }
+
+        Ok(Self {
+            heap_size: self.heap_size.or(requests.heap),
+            cpus: self.cpus.or(requests.threads),
+            ..self.clone()
+        })
+    }
+
+    /// Reads the manifest of the payload, if it has one
+    #[cfg(feature = "backend-kvm")]
+    pub fn manifest(&self) -> Result<Option<Vec<u8>>> {
+        self.manifest
+            .as_deref()
+            .map(|path| {
+                std::fs::read(path)
+                    .map_err(|e| anyhow::anyhow!("unable to read {}: {}", path.display(), e))
+            })
+            .transpose()
+    }
+}
+
+/// The streams and the syscall policy of a keep, for the SGX syscall ring
+///
+/// The ring writes the output of the payload on a thread of its own, past
+/// the executor, so it applies these itself. The builder only hands them to
+/// keeps which aren't traced, profiled, recorded or replayed, since the ring
+/// is hidden from those.
+#[derive(Clone, Debug)]
+pub struct StdioRing {
+    /// The streams of the keep
+    pub streams: Arc<Streams>,
+
+    /// The syscall policy of the keep
+    pub policy: SyscallPolicy,
+}
+
+/// Overrides of the SGX launch parameters
+///
+/// Each one left as `None` keeps the default of the backend. They are part of
+/// the signature of the enclave rather than of MRENCLAVE, and are checked
+/// against what the CPU supports. The CPUID policy still hides AVX-512 unless
+/// it is revealed with a rule (see `Config::cpuid`).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SgxParameters {
+    /// The state components which the enclave can use (e.g. `0xe7` for AVX-512)
+    pub xfrm: Option<u64>,
+
+    /// The extra information saved to the SSA on exceptions (`1` for EXINFO)
+    ///
+    /// By default, EXINFO is selected whenever the CPU supports it, so that
+    /// the shim learns where page faults happen.
+    pub misc_select: Option<u32>,
+
+    /// The product ID of the enclave (ISVPRODID)
+    pub prod_id: Option<u16>,
+
+    /// The security version of the enclave (ISVSVN)
+    pub svn: Option<u16>,
+
+    /// Whether the heap is added on first touch (SGX2) instead of at launch
+    ///
+    /// Each page is then added (`EAUG`) when the shim first touches it, and
+    /// accepted by the shim, so large heaps launch faster and only take up
+    /// EPC for the pages in use. The heap isn't measured either way, but
+    /// MRENCLAVE depends on whether it is added lazily.
+    pub lazy_heap: bool,
+}
+
+/// Overrides of the SEV launch parameters
+///
+/// They set the policy of the guest, which the firmware enforces for as long
+/// as the guest runs and which is part of its measurement. By default, the
+/// host may debug the guest and the guest may share its key. A keep whose
+/// policy the platform can't satisfy is not launched.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SevParameters {
+    /// Whether the register state is encrypted as well (SEV-ES)
+    ///
+    /// The shim then passes the registers of the instructions which the host
+    /// emulates (`cpuid`, `rdmsr`, `wrmsr` and the port IO of hostcalls)
+    /// through an unencrypted page, the GHCB. The initial registers become
+    /// part of the measurement.
+    pub es: bool,
+
+    /// Whether the host is forbidden to debug the guest (NODBG)
+    pub no_debug: bool,
+
+    /// Whether the guest is forbidden to share its key with others (NOKS)
+    pub no_key_sharing: bool,
+
+    /// The oldest firmware version (major, minor) the guest may run on
+    pub min_firmware: Option<(u8, u8)>,
+}
+
+/// A remote guest owner
+///
+/// The loader sends it the certificate chain of the platform over TLS,
+/// launches the keep with the policy, GODH certificate and session it replies
+/// with, and sends it the launch measurement. If it accepts the measurement,
+/// it may release a secret, and the payload itself (see
+/// `Backend::build_empty()`). The policy of `SevParameters` is ignored, since
+/// the guest owner sets it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GuestOwner {
+    /// The address of the guest owner (`HOST:PORT`)
+    pub addr: String,
+
+    /// The CA certificates to verify the guest owner with, instead of the
+    /// system ones
+    pub ca: Option<PathBuf>,
 }
 
+/// Checks that a shim can run the keeps of a backend
+///
+/// Every shim is an x86_64 binary with a code slot, which supports the
+/// version of sallyport of the loader; the backend checks the rest. Shims
+/// which aren't builtin, e.g. those under development, are checked before
+/// anything is built with them, so that a shim of another backend or of
+/// another loader is rejected with the reason.
+pub fn check_shim(backend: &dyn Backend, shim: &Component) -> Result<()> {
+    if shim.elf.header.e_machine != goblin::elf::header::EM_X86_64 {
+        bail!("the shim is not an x86_64 binary");
+    }
+
+    if shim.find_header(PT_ENARX_CODE).is_none() {
+        let name = "PT_ENARX_CODE";
+        return Err(BuildError::MissingHeader { name }.into());
+    }
+
+    let version = semver::Version::parse(sallyport::VERSION).unwrap();
+    let supported = shim
+        .filter_notes("sallyport", 0)
+        .filter_map(|n| std::str::from_utf8(n).ok())
+        .filter_map(|n| semver::VersionReq::parse(n).ok())
+        .any(|req| req.matches(&version));
+    if !supported {
+        bail!("the shim does not support sallyport {}", version);
+    }
+
+    let abi = SallyportAbi::of(shim)?;
+    if abi != SallyportAbi::LOADER {
+        return Err(BuildError::SallyportMismatch {
+            shim: abi,
+            loader: SallyportAbi::LOADER,
+        }
+        .into());
+    }
+
+    backend.check_shim(shim)
+}
+
+/// The sallyport ABI which the shim and the loader share the blocks with
+///
+/// The `sallyport` note of a shim only names the releases of sallyport which
+/// it supports, which the layout of the blocks may change between, e.g. from
+/// one revision of the crate to the next. A shim embeds its ABI in the
+/// `NOTE_ENARX_SALLYPORT_ABI` note instead, which must match the loader's
+/// exactly: a keep whose shim reads the blocks otherwise than the loader
+/// writes them corrupts its syscalls.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SallyportAbi {
+    /// The version of the ABI, which is bumped with every incompatible change
+    pub version: u32,
+
+    /// The size of a block in bytes
+    pub block: u32,
+}
+
+impl SallyportAbi {
+    /// The ABI of the loader
+    ///
+    /// Its version must be bumped along with that of the shims
+    /// (`SALLYPORT_ABI` in `internal/shim-*`).
+    pub const LOADER: Self = Self {
+        version: 1,
+        block: std::mem::size_of::<Block>() as u32,
+    };
+
+    /// Reads the ABI of a shim
+    pub fn of(shim: &Component) -> Result<Self> {
+        let note = unsafe { shim.read_note::<[u32; 2]>("enarx", NOTE_ENARX_SALLYPORT_ABI)? };
+        let [version, block] = note.ok_or(BuildError::MissingNote {
+            name: "NOTE_ENARX_SALLYPORT_ABI",
+        })?;
+
+        Ok(Self { version, block })
+    }
+}
+
+impl std::fmt::Display for SallyportAbi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}-byte blocks)", self.version, self.block)
+    }
+}
+
+/// The directory in which a backend caches what it computed or fetched
+///
+/// It is `$XDG_CACHE_HOME/enarx-keepldr/<backend>`, or beneath `~/.cache`.
+#[cfg(any(feature = "backend-sev", feature = "backend-sgx"))]
+pub fn cache_dir(backend: &str) -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+
+    Some(dir.join("enarx-keepldr").join(backend))
+}
+
+/// A check of the support of the platform for a backend
 pub struct Datum {
     /// The name of this datum.
     pub name: String,
@@ -48,19 +539,354 @@ pub struct Datum {
     pub mesg: Option<String>,
 }
 
+/// Why a keep can't be built from its shim and payload
+///
+/// The backends return these within `anyhow::Error`, from which they can be
+/// recovered with `downcast_ref()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    /// The payload doesn't fit in the code slot of the shim
+    CodeTooLarge {
+        /// The bytes which the payload needs
+        need: usize,
+
+        /// The bytes of the code slot
+        have: usize,
+    },
+
+    /// The payload needs a larger stack than the shim gives it
+    StackTooLarge {
+        /// The bytes which the payload requests
+        need: usize,
+
+        /// The bytes which the shim gives the payload
+        have: usize,
+    },
+
+    /// Two segments of the shim or the payload share memory
+    OverlappingSegments {
+        /// The lower segment
+        a: String,
+
+        /// The higher segment
+        b: String,
+    },
+
+    /// The shim lacks a note which the backend needs
+    MissingNote {
+        /// The name of the note
+        name: &'static str,
+    },
+
+    /// The shim lacks a program header which the backend needs
+    MissingHeader {
+        /// The name of the type of the header
+        name: &'static str,
+    },
+
+    /// The shim speaks another sallyport ABI than the loader
+    SallyportMismatch {
+        /// The ABI of the shim
+        shim: SallyportAbi,
+
+        /// The ABI of the loader
+        loader: SallyportAbi,
+    },
+
+    /// The binary can't be parsed as ELF
+    InvalidElf {
+        /// Why the parser rejected the binary
+        reason: String,
+    },
+
+    /// The binary isn't a 64-bit ELF (`EI_CLASS`)
+    UnsupportedClass {
+        /// The class of the binary
+        class: u8,
+    },
+
+    /// The binary isn't little-endian (`EI_DATA`)
+    UnsupportedEncoding {
+        /// The data encoding of the binary
+        data: u8,
+    },
+
+    /// The binary has an unknown ELF version (`EI_VERSION` or `e_version`)
+    UnsupportedVersion {
+        /// The version of the binary
+        version: u32,
+    },
+
+    /// The binary isn't built for x86_64 (`e_machine`)
+    UnsupportedMachine {
+        /// The machine of the binary
+        machine: u16,
+    },
+
+    /// The entry point of the binary isn't in exactly one `PT_LOAD` segment
+    EntryNotLoaded {
+        /// The entry point of the binary
+        entry: u64,
+    },
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CodeTooLarge { need, have } => write!(
+                f,
+                "the payload needs {} bytes, but the code slot of the shim has only {}: \
+                 rebuild the shims with `ENARX_CODE_SIZE={}M`",
+                need,
+                have,
+                (need + (1 << 20) - 1) >> 20
+            ),
+            Self::StackTooLarge { need, have } => write!(
+                f,
+                "the payload requests a stack of {} bytes, but the shim gives it only {}",
+                need, have
+            ),
+            Self::OverlappingSegments { a, b } => {
+                write!(f, "the segments {} and {} overlap", a, b)
+            }
+            Self::MissingNote { name } => write!(
+                f,
+                "the shim has no {} note: is it a shim of this backend?",
+                name
+            ),
+            Self::MissingHeader { name } => write!(
+                f,
+                "the shim has no {} program header: is it a shim of this backend?",
+                name
+            ),
+            Self::SallyportMismatch { shim, loader } => write!(
+                f,
+                "the shim speaks sallyport ABI {}, but the loader speaks {}: \
+                 rebuild the shim against the sallyport of the loader",
+                shim, loader
+            ),
+            Self::InvalidElf { reason } => write!(f, "the binary isn't a valid ELF: {}", reason),
+            Self::UnsupportedClass { class } => write!(
+                f,
+                "the binary is {}, but only ELF64 is supported",
+                goblin::elf::header::class_to_str(*class)
+            ),
+            Self::UnsupportedEncoding { data } => write!(
+                f,
+                "the binary has data encoding {}, but only little-endian is supported",
+                data
+            ),
+            Self::UnsupportedVersion { version } => write!(
+                f,
+                "the binary has ELF version {}, but only version 1 is supported",
+                version
+            ),
+            Self::UnsupportedMachine { machine } => write!(
+                f,
+                "the binary is built for {}, but only x86_64 is supported",
+                goblin::elf::header::machine_to_str(*machine)
+            ),
+            Self::EntryNotLoaded { entry } => write!(
+                f,
+                "the entry point {:#x} of the binary isn't in exactly one PT_LOAD segment",
+                entry
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// A keep which was built by a backend
 pub trait Keep {
     /// Creates a new thread in the keep.
     fn spawn(self: Arc<Self>) -> Result<Option<Box<dyn Thread>>>;
+
+    /// The measurement which the platform took when it launched the keep
+    ///
+    /// Callers can compare it against the value they expect. Backends which
+    /// are not measured at launch return `None`.
+    fn measurement(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Tears the keep down and returns what it released
+    ///
+    /// A keep is torn down when it is dropped, but only once all of its
+    /// threads are, which other OS threads may hold on to. This releases its
+    /// memory and closes its file descriptors at once instead: the kernel
+    /// removes the pages of an enclave (`EREMOVE`) as it is closed, and the
+    /// guest memory of a VM is unmapped. Threads halt when they are entered
+    /// afterwards, or when they leave the keep if they are inside it.
+    fn shutdown(self: Arc<Self>) -> Result<Usage>;
+}
+
+/// What a keep released when it was shut down (see `Keep::shutdown()`)
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// The memory of the keep, in bytes: the enclave of SGX keeps and the
+    /// guest memory of VM-based ones
+    pub memory: usize,
+
+    /// The file descriptors which were closed
+    pub fds: usize,
+
+    /// The threads of the keep which are still alive
+    ///
+    /// What they hold, such as the vCPUs of a VM and with them the VM
+    /// itself, is only released once they are dropped.
+    pub threads: usize,
 }
 
+/// A thread of a keep, such as a vCPU or an enclave thread
 pub trait Thread {
     /// Enters the keep.
     fn enter(&mut self) -> Result<Command>;
+
+    /// Gives the debugger access to the thread, if the backend supports it
+    fn debug(&mut self) -> Option<&mut dyn Target> {
+        None
+    }
+
+    /// Gives access to the state of the thread for a core file, if the
+    /// backend can read it
+    fn dump(&mut self) -> Option<&mut dyn Dump> {
+        None
+    }
+
+    /// Describes the state of the thread for diagnostics, e.g. the CSSA of
+    /// SGX threads
+    fn state(&self) -> Option<String> {
+        None
+    }
 }
 
+/// Why a thread exited to the host
 pub enum Command<'a> {
-    #[allow(dead_code)]
+    /// The thread asks the host to execute the request in the block
     SysCall(&'a mut Block),
-    #[allow(dead_code)]
+    /// The thread can be entered again right away
     Continue,
+    /// The thread stopped for the debugger (see `Thread::debug()`)
+    Trap,
+    /// The thread halted, because it has nothing to run
+    Halt,
+    /// The shim stopped the keep, because the host attacked it
+    Attacked,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn order() {
+        let names = all().iter().map(|b| b.name()).collect::<Vec<_>>();
+        let expected: &[&str] = &[
+            #[cfg(feature = "backend-sgx")]
+            "sgx",
+            #[cfg(feature = "backend-sev")]
+            "sev",
+            #[cfg(feature = "backend-kvm")]
+            "kvm",
+            #[cfg(feature = "backend-nil")]
+            "nil",
+        ];
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn unknown() {
+        let backends = all();
+        let error = select(&backends, "none").err().unwrap().to_string();
+        for backend in &backends {
+            assert!(error.contains(backend.name()), "{}", error);
+        }
+    }
+
+    #[test]
+    fn build_errors() {
+        let abi = |version| SallyportAbi {
+            version,
+            block: 4096,
+        };
+        let errors = [
+            (
+                BuildError::CodeTooLarge {
+                    need: (3 << 20) + 1,
+                    have: 2 << 20,
+                },
+                "`ENARX_CODE_SIZE=4M`",
+            ),
+            (
+                BuildError::StackTooLarge {
+                    need: 16 << 20,
+                    have: 8 << 20,
+                },
+                "stack of 16777216 bytes, but the shim gives it only 8388608",
+            ),
+            (
+                BuildError::OverlappingSegments {
+                    a: "0x1000..0x3000".into(),
+                    b: "0x2000..0x4000".into(),
+                },
+                "0x1000..0x3000 and 0x2000..0x4000 overlap",
+            ),
+            (
+                BuildError::MissingNote {
+                    name: "NOTE_ENARX_SALLYPORT_ABI",
+                },
+                "no NOTE_ENARX_SALLYPORT_ABI note",
+            ),
+            (
+                BuildError::MissingHeader {
+                    name: "PT_ENARX_CODE",
+                },
+                "no PT_ENARX_CODE program header",
+            ),
+            (
+                BuildError::SallyportMismatch {
+                    shim: abi(2),
+                    loader: abi(1),
+                },
+                "ABI 2 (4096-byte blocks), but the loader speaks 1 (4096-byte blocks)",
+            ),
+            (
+                BuildError::InvalidElf {
+                    reason: "bad magic".into(),
+                },
+                "isn't a valid ELF: bad magic",
+            ),
+            (
+                BuildError::UnsupportedClass { class: 1 },
+                "the binary is ELF32",
+            ),
+            (
+                BuildError::UnsupportedEncoding { data: 2 },
+                "data encoding 2",
+            ),
+            (
+                BuildError::UnsupportedVersion { version: 0 },
+                "ELF version 0",
+            ),
+            (
+                BuildError::UnsupportedMachine { machine: 3 },
+                "built for 386",
+            ),
+            (
+                BuildError::EntryNotLoaded { entry: 0x1000 },
+                "entry point 0x1000",
+            ),
+        ];
+
+        for (error, message) in errors.iter() {
+            assert!(error.to_string().contains(message), "{}", error);
+
+            // The builder adds context, which the error is recovered from.
+            let wrapped: anyhow::Error = error.clone().into();
+            let wrapped = wrapped.context("unable to build the keep");
+            assert_eq!(wrapped.downcast_ref::<BuildError>(), Some(error));
+            assert!(format!("{:#}", wrapped).ends_with(&error.to_string()));
+        }
+    }
 }
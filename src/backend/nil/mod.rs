@@ -0,0 +1,229 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The nil backend
+//!
+//! Nil keeps protect nothing: the payload runs without a shim on a thread of
+//! the loader, in its address space. They show whether a payload works in
+//! keeps on hosts without a TEE, e.g. in CI, so `auto` never selects them.
+//!
+//! The thread of the payload traps its syscalls (see `trap`), and the thread
+//! of the loader answers them as the shims would: it handles the syscalls
+//! which the shims handle in the keep, and passes the others in a block to
+//! the executor, so that they are subject to the same policy, interceptors
+//! and sandbox as those of the other keeps (see `thread`).
+//!
+//! A fault of the payload kills the loader, and nothing stops the payload
+//! from touching the memory of the loader.
+
+mod thread;
+mod trap;
+
+use crate::backend::{self, BuildError, Config, Datum};
+use crate::binary::Component;
+
+use anyhow::{bail, Result};
+use goblin::elf::header::ET_DYN;
+use goblin::elf::program_header::{PF_R, PF_W, PF_X, PT_LOAD};
+use mmarinus::{perms, Kind, Map};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// The default size of the stack of the payload, as the shims give it
+const STACK: usize = 8 << 20;
+
+const PAGE: usize = 4096;
+
+/// Whether a nil keep was built in this process
+///
+/// The handler of `SIGSYS` belongs to the process, so there is only one.
+static BUILT: AtomicBool = AtomicBool::new(false);
+
+pub struct Backend;
+
+impl backend::Backend for Backend {
+    fn name(&self) -> &'static str {
+        "nil"
+    }
+
+    fn shim(&self) -> Result<&'static [u8]> {
+        Ok(&[])
+    }
+
+    fn check_shim(&self, _shim: &Component) -> Result<()> {
+        bail!("the nil backend runs payloads without a shim")
+    }
+
+    fn protected(&self) -> bool {
+        false
+    }
+
+    fn data(&self) -> Vec<Datum> {
+        vec![seccomp()]
+    }
+
+    fn build(
+        &self,
+        _shim: Component,
+        code: Component,
+        _config: &Config,
+    ) -> Result<Arc<dyn backend::Keep>> {
+        if code.elf.header.e_type != ET_DYN {
+            bail!("the nil backend only runs position-independent payloads (`-static-pie`)");
+        }
+
+        let requests = code.requests()?;
+        if requests.threads.map_or(false, |t| t.get() > 1) {
+            bail!("the nil backend runs payloads on a single thread");
+        }
+
+        if BUILT.swap(true, Ordering::SeqCst) {
+            bail!("only one nil keep can be built in a process");
+        }
+
+        Ok(Arc::new(Keep::new(&code, requests.stack.unwrap_or(0))?))
+    }
+}
+
+/// The payload, loaded in the loader
+struct Keep {
+    /// The memory of the payload
+    ///
+    /// The permissions of the segments are set on it once they are loaded.
+    _image: Map<perms::ReadWrite>,
+    _stack: Map<perms::ReadWrite>,
+
+    /// The addresses of the entry point and the program headers
+    entry: usize,
+    phdr: usize,
+    phent: usize,
+    phnum: usize,
+
+    /// The program break, which stays at the end of the image
+    brk: usize,
+
+    /// The top of the stack
+    top: usize,
+
+    spawned: AtomicBool,
+}
+
+impl Keep {
+    /// Loads the payload and maps its stack
+    fn new(code: &Component, stack: usize) -> Result<Self> {
+        let end = align_up(code.region().end);
+        let mut image = Map::map(end)
+            .anywhere()
+            .anonymously()
+            .known::<perms::ReadWrite>(Kind::Private)?;
+
+        // The headers come from whatever linked the payload, so the bytes
+        // of each segment are checked to be in the file and in the image.
+        for seg in code.filter_header(PT_LOAD) {
+            let invalid = |place| BuildError::InvalidElf {
+                reason: format!(
+                    "the segment at {:#x} ends beyond the {}",
+                    seg.p_vaddr, place
+                ),
+            };
+
+            let (offset, vaddr, size) = (
+                seg.p_offset as usize,
+                seg.p_vaddr as usize,
+                seg.p_filesz as usize,
+            );
+            let src = offset
+                .checked_add(size)
+                .and_then(|end| code.bytes.get(offset..end))
+                .ok_or_else(|| invalid("file"))?;
+            let dst = vaddr
+                .checked_add(size)
+                .and_then(|end| image.as_mut().get_mut(vaddr..end))
+                .ok_or_else(|| invalid("image"))?;
+            dst.copy_from_slice(src);
+        }
+
+        for seg in code.filter_header(PT_LOAD) {
+            let start = seg.p_vaddr as usize & !(PAGE - 1);
+            let end = align_up((seg.p_vaddr + seg.p_memsz) as usize);
+
+            let mut prot = libc::PROT_NONE;
+            for &(flag, p) in [
+                (PF_R, libc::PROT_READ),
+                (PF_W, libc::PROT_WRITE),
+                (PF_X, libc::PROT_EXEC),
+            ]
+            .iter()
+            {
+                if seg.p_flags & flag != 0 {
+                    prot |= p;
+                }
+            }
+
+            let addr = (image.addr() + start) as *mut libc::c_void;
+            if unsafe { libc::mprotect(addr, end - start, prot) } < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+
+        let size = align_up(stack.max(STACK));
+        let stack = Map::map(size)
+            .anywhere()
+            .anonymously()
+            .known::<perms::ReadWrite>(Kind::Private)?;
+
+        let header = &code.elf.header;
+        let base = image.addr();
+
+        Ok(Self {
+            entry: base + header.e_entry as usize,
+            phdr: base + header.e_phoff as usize,
+            phent: header.e_phentsize as _,
+            phnum: header.e_phnum as _,
+            brk: base + end,
+            top: stack.addr() + size,
+            _image: image,
+            _stack: stack,
+            spawned: AtomicBool::new(false),
+        })
+    }
+}
+
+impl backend::Keep for Keep {
+    fn spawn(self: Arc<Self>) -> Result<Option<Box<dyn backend::Thread>>> {
+        if self.spawned.swap(true, Ordering::SeqCst) {
+            return Ok(None);
+        }
+
+        std::thread::Builder::new()
+            .name("payload".into())
+            .spawn(trap::run)?;
+
+        Ok(Some(Box::new(thread::Thread::new(self))))
+    }
+
+    /// Releases nothing, since the thread of the payload may still run in
+    /// the memory of the keep
+    fn shutdown(self: Arc<Self>) -> Result<backend::Usage> {
+        Ok(backend::Usage {
+            threads: Arc::strong_count(&self) - 1,
+            ..Default::default()
+        })
+    }
+}
+
+fn align_up(addr: usize) -> usize {
+    (addr + PAGE - 1) & !(PAGE - 1)
+}
+
+/// Whether the kernel can filter syscalls
+fn seccomp() -> Datum {
+    let mode = unsafe { libc::prctl(libc::PR_GET_SECCOMP) };
+
+    Datum {
+        name: "Seccomp".into(),
+        pass: mode >= 0,
+        info: None,
+        mesg: None,
+    }
+}
@@ -0,0 +1,832 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The thread of the loader, which answers the syscalls of the payload
+//!
+//! The syscalls which the shims handle in the keep are answered here, and
+//! the others are passed to the executor in a block. The interceptors of the
+//! executor and the policy only look for the data of a syscall in the block,
+//! where the shims put it, so paths and the results which they check are
+//! copied through it, and the legacy syscalls with paths are passed as their
+//! `*at()` forms, like the shims do.
+
+use super::trap::{self, Call, Reply};
+use super::Keep;
+use crate::backend::{self, Command};
+use crate::sandbox;
+
+use anyhow::{anyhow, bail, Result};
+use crt0stack::{Builder, Entry, Handle, OutOfSpace};
+use enarx_syscall::SYS_ENARX_ENVIRON;
+use sallyport::Block;
+
+use std::mem::size_of;
+use std::sync::Arc;
+
+/// The room at the top of the stack for the arguments and environment
+const CRT0: usize = 16 << 10;
+
+/// The user and group of the payload, as in the other keeps
+const ID: isize = 1000;
+
+/// Locks pages of `mlock2()` once they are faulted in
+const MLOCK_ONFAULT: libc::c_int = 0x01;
+
+/// Locks pages of `mlockall()` once they are faulted in
+const MCL_ONFAULT: libc::c_int = 0x04;
+
+/// Disables the alternate signal stack when a handler is entered on it
+const SS_AUTODISARM: libc::c_int = 1 << 31;
+
+/// The signals which the payload can't block
+const UNBLOCKABLE: u64 = bit(libc::SIGKILL) | bit(libc::SIGSTOP) | bit(libc::SIGSYS);
+
+const fn bit(signal: libc::c_int) -> u64 {
+    1 << (signal - 1)
+}
+
+/// What the next entry does
+enum Stage {
+    /// Asks the executor for the arguments and environment
+    Environ,
+
+    /// Starts the payload with them
+    Start,
+
+    /// Waits for the next syscall of the payload
+    Running,
+
+    /// Answers the syscall of the payload
+    Answered(Reply),
+
+    /// Answers the syscall of the payload with what the executor replied
+    Forwarded(Option<Out>),
+}
+
+/// A result which the executor writes to the block for the payload
+#[derive(Copy, Clone, Debug)]
+struct Out {
+    /// The offset in the data area of the block
+    offset: usize,
+
+    /// The address in the payload
+    addr: usize,
+
+    /// The size, in bytes
+    len: usize,
+
+    /// Whether only as many bytes as the syscall returns are written
+    counted: bool,
+}
+
+/// The data area of a block, which is filled for a syscall
+struct Room<'a> {
+    buf: &'a mut [u8],
+    used: usize,
+    out: Option<Out>,
+}
+
+impl<'a> Room<'a> {
+    /// Reserves `len` bytes, returning their address
+    fn reserve(&mut self, len: usize) -> Result<(usize, &mut [u8]), libc::c_int> {
+        let offset = (self.used + 7) & !7;
+        let end = offset.checked_add(len).ok_or(libc::EMSGSIZE)?;
+        if end > self.buf.len() {
+            return Err(libc::EMSGSIZE);
+        }
+
+        self.used = end;
+        let buf = &mut self.buf[offset..end];
+        Ok((buf.as_ptr() as usize, buf))
+    }
+
+    /// The bytes which are left
+    fn left(&self) -> usize {
+        self.buf.len().saturating_sub((self.used + 7) & !7)
+    }
+
+    /// Copies a path of the payload, returning its address
+    fn path(&mut self, addr: usize) -> Result<usize, libc::c_int> {
+        if addr == 0 {
+            return Err(libc::EFAULT);
+        }
+
+        let path = addr as *const u8;
+        let len = (0..libc::PATH_MAX as usize)
+            .find(|i| unsafe { *path.add(*i) } == 0)
+            .ok_or(libc::ENAMETOOLONG)?;
+
+        let (addr, buf) = self.reserve(len + 1)?;
+        buf.copy_from_slice(unsafe { std::slice::from_raw_parts(path, len + 1) });
+        Ok(addr)
+    }
+
+    /// Reserves room for a result of `len` bytes, returning its address
+    ///
+    /// A null address is passed on as it is.
+    fn out(&mut self, addr: usize, len: usize, counted: bool) -> Result<usize, libc::c_int> {
+        if addr == 0 {
+            return Ok(0);
+        }
+
+        let start = self.buf.as_ptr() as usize;
+        let (host, _) = self.reserve(len)?;
+        self.out = Some(Out {
+            offset: host - start,
+            addr,
+            len,
+            counted,
+        });
+
+        Ok(host)
+    }
+}
+
+pub(super) struct Thread {
+    keep: Arc<Keep>,
+    block: Block,
+    stage: Stage,
+}
+
+impl Thread {
+    pub(super) fn new(keep: Arc<Keep>) -> Self {
+        Self {
+            keep,
+            block: Block::default(),
+            stage: Stage::Environ,
+        }
+    }
+
+    /// Puts a request in the block
+    fn request(&mut self, num: libc::c_long, args: [usize; 6]) -> Command {
+        let req = unsafe { &mut self.block.msg.req };
+        req.num = (num as usize).into();
+        for (reg, arg) in req.arg.iter_mut().zip(args.iter()) {
+            *reg = (*arg).into();
+        }
+
+        Command::SysCall(&mut self.block)
+    }
+
+    /// The reply of the executor, as the syscall returns it
+    fn reply(&mut self, out: Option<Out>) -> isize {
+        let ret: sallyport::Result = unsafe { self.block.msg.rep }.into();
+        let ret = match ret {
+            Ok(ret) => usize::from(ret[0]),
+            Err(errno) => return -(errno as isize),
+        };
+
+        if let Some(out) = out {
+            let len = match out.counted {
+                true => ret.min(out.len),
+                false => out.len,
+            };
+
+            let src = &self.block.buf[out.offset..][..len];
+            unsafe { std::ptr::copy_nonoverlapping(src.as_ptr(), out.addr as *mut u8, len) };
+        }
+
+        ret as isize
+    }
+
+    /// Sets up the initial stack of the payload, returning its top
+    ///
+    /// The arguments and environment are in the block, as the executor
+    /// replied to `SYS_ENARX_ENVIRON`.
+    fn stack(&mut self) -> Result<usize> {
+        let ret: sallyport::Result = unsafe { self.block.msg.rep }.into();
+        let (list, argc) = match ret {
+            Ok([len, argc]) => (&self.block.buf[..usize::from(len)], usize::from(argc)),
+            Err(errno) => bail!(
+                "unable to get the arguments and environment: {}",
+                std::io::Error::from_raw_os_error(errno)
+            ),
+        };
+
+        let count = list.iter().filter(|b| **b == 0).count();
+        let strings = list
+            .split(|b| *b == 0)
+            .take(count)
+            .map(std::str::from_utf8)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if argc > strings.len() {
+            bail!("invalid arguments and environment");
+        }
+
+        let mut random = [0u8; 16];
+        let len = random.len();
+        if unsafe { libc::getrandom(random.as_mut_ptr().cast(), len, 0) } != len as isize {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let crt0 =
+            unsafe { std::slice::from_raw_parts_mut((self.keep.top - CRT0) as *mut u8, CRT0) };
+        let handle = crt0setup(&self.keep, crt0, &strings[..argc], &strings[argc..], random)
+            .map_err(|_| anyhow!("the arguments and environment don't fit on the stack"))?;
+
+        Ok(&*handle as *const _ as usize)
+    }
+
+    /// Answers a syscall of the payload, or passes it to the executor
+    fn dispatch(&mut self, call: Call) -> Command {
+        let num = call.num as libc::c_long;
+        let [a0, a1, a2, a3, _, _] = call.args;
+
+        let ret = match num {
+            // These only concern the thread of the payload.
+            libc::SYS_arch_prctl | libc::SYS_sysinfo => {
+                self.stage = Stage::Answered(Reply::Native);
+                return Command::Continue;
+            }
+
+            // The handler restores the signal mask and the alternate signal
+            // stack when it returns, so they are changed there.
+            libc::SYS_rt_sigprocmask => unsafe { sigprocmask(call.context, a0, a1, a2, a3) },
+            libc::SYS_sigaltstack => unsafe { sigaltstack(call.context, a0, a1) },
+
+            // The payload can't take its syscalls back.
+            libc::SYS_rt_sigaction if a0 as libc::c_int == libc::SIGSYS => -libc::EINVAL as isize,
+
+            // The payload gets no heap, as with the shims, so it maps memory.
+            libc::SYS_brk => self.keep.brk as isize,
+
+            libc::SYS_getuid | libc::SYS_geteuid | libc::SYS_getgid | libc::SYS_getegid => ID,
+
+            // The payload runs as if on the first CPU of the keep.
+            libc::SYS_getcpu => {
+                for addr in [a0, a1].iter().filter(|addr| **addr != 0) {
+                    unsafe { *(*addr as *mut libc::c_uint) = 0 };
+                }
+
+                0
+            }
+
+            libc::SYS_sched_getaffinity => {
+                let size = size_of::<libc::c_ulong>();
+                match a1 {
+                    len if len < size || len % size != 0 => -libc::EINVAL as isize,
+                    _ if a2 == 0 => -libc::EFAULT as isize,
+                    _ => {
+                        unsafe { *(a2 as *mut libc::c_ulong) = 1 };
+                        size as isize
+                    }
+                }
+            }
+
+            // Locking pages makes no difference to the payload.
+            libc::SYS_mlock | libc::SYS_munlock | libc::SYS_munlockall => 0,
+            libc::SYS_mlock2 if a2 as libc::c_int & !MLOCK_ONFAULT != 0 => -libc::EINVAL as isize,
+            libc::SYS_mlock2 => 0,
+            libc::SYS_mlockall => {
+                let flags = a0 as libc::c_int;
+                let all = libc::MCL_CURRENT | libc::MCL_FUTURE | MCL_ONFAULT;
+                match flags & !all != 0 || flags & (libc::MCL_CURRENT | libc::MCL_FUTURE) == 0 {
+                    true => -libc::EINVAL as isize,
+                    false => 0,
+                }
+            }
+
+            _ => return self.forward(num, call.args),
+        };
+
+        self.stage = Stage::Answered(Reply::Ret(ret));
+        Command::Continue
+    }
+
+    /// Passes a syscall of the payload to the executor
+    ///
+    /// Only the syscalls which the sandbox allows are passed, since those
+    /// are the ones which the shims request.
+    fn forward(&mut self, num: libc::c_long, args: [usize; 6]) -> Command {
+        let mut room = Room {
+            buf: &mut self.block.buf,
+            used: 0,
+            out: None,
+        };
+
+        match translate(&mut room, num, args) {
+            Ok((num, args)) if sandbox::allowed(num) => {
+                self.stage = Stage::Forwarded(room.out);
+                self.request(num, args)
+            }
+            Ok(_) => {
+                self.stage = Stage::Answered(Reply::Ret(-libc::ENOSYS as isize));
+                Command::Continue
+            }
+            Err(errno) => {
+                self.stage = Stage::Answered(Reply::Ret(-errno as isize));
+                Command::Continue
+            }
+        }
+    }
+}
+
+impl backend::Thread for Thread {
+    fn enter(&mut self) -> Result<Command> {
+        match std::mem::replace(&mut self.stage, Stage::Running) {
+            Stage::Environ => {
+                self.stage = Stage::Start;
+                let buf = self.block.buf.as_mut_ptr() as usize;
+                let len = self.block.buf.len();
+                return Ok(self.request(SYS_ENARX_ENVIRON as _, [buf, len, 0, 0, 0, 0]));
+            }
+
+            Stage::Start => {
+                let sp = self.stack()?;
+                trap::start(self.keep.entry, sp);
+            }
+
+            Stage::Forwarded(out) => {
+                let ret = self.reply(out);
+                trap::answer(Reply::Ret(ret));
+            }
+
+            Stage::Answered(reply) => trap::answer(reply),
+            Stage::Running => (),
+        }
+
+        match trap::next() {
+            // The loader may have to handle the signal.
+            None => Ok(Command::Continue),
+            Some(Err(())) => bail!("unable to start the payload"),
+            Some(Ok(call)) => Ok(self.dispatch(call)),
+        }
+    }
+}
+
+/// Moves the data of a syscall which the executor checks to the block
+fn translate(
+    room: &mut Room,
+    num: libc::c_long,
+    args: [usize; 6],
+) -> Result<(libc::c_long, [usize; 6]), libc::c_int> {
+    let [a0, a1, a2, a3, a4, _] = args;
+    let at = libc::AT_FDCWD as usize;
+    let stat = size_of::<libc::stat>();
+
+    Ok(match num {
+        libc::SYS_open => (libc::SYS_openat, [at, room.path(a0)?, a1, a2, 0, 0]),
+        libc::SYS_creat => {
+            let flags = (libc::O_CREAT | libc::O_WRONLY | libc::O_TRUNC) as usize;
+            (libc::SYS_openat, [at, room.path(a0)?, flags, a1, 0, 0])
+        }
+        libc::SYS_openat => (num, [a0, room.path(a1)?, a2, a3, 0, 0]),
+
+        libc::SYS_stat | libc::SYS_lstat => {
+            let flags = match num {
+                libc::SYS_lstat => libc::AT_SYMLINK_NOFOLLOW as usize,
+                _ => 0,
+            };
+
+            let path = room.path(a0)?;
+            let buf = room.out(a1, stat, false)?;
+            (libc::SYS_newfstatat, [at, path, buf, flags, 0, 0])
+        }
+        libc::SYS_newfstatat => {
+            let path = room.path(a1)?;
+            let buf = room.out(a2, stat, false)?;
+            (num, [a0, path, buf, a3, 0, 0])
+        }
+
+        libc::SYS_access => (libc::SYS_faccessat, [at, room.path(a0)?, a1, 0, 0, 0]),
+        libc::SYS_faccessat => (num, [a0, room.path(a1)?, a2, 0, 0, 0]),
+
+        libc::SYS_mkdir => (libc::SYS_mkdirat, [at, room.path(a0)?, a1, 0, 0, 0]),
+        libc::SYS_mkdirat => (num, [a0, room.path(a1)?, a2, 0, 0, 0]),
+
+        libc::SYS_unlink => (libc::SYS_unlinkat, [at, room.path(a0)?, 0, 0, 0, 0]),
+        libc::SYS_rmdir => {
+            let flags = libc::AT_REMOVEDIR as usize;
+            (libc::SYS_unlinkat, [at, room.path(a0)?, flags, 0, 0, 0])
+        }
+        libc::SYS_unlinkat => (num, [a0, room.path(a1)?, a2, 0, 0, 0]),
+
+        libc::SYS_rename => {
+            let (old, new) = (room.path(a0)?, room.path(a1)?);
+            (libc::SYS_renameat2, [at, old, at, new, 0, 0])
+        }
+        libc::SYS_renameat | libc::SYS_renameat2 => {
+            let (old, new) = (room.path(a1)?, room.path(a3)?);
+            let flags = match num {
+                libc::SYS_renameat2 => a4,
+                _ => 0,
+            };
+
+            (libc::SYS_renameat2, [a0, old, a2, new, flags, 0])
+        }
+
+        // The policy tracks the descriptors which these return, and the
+        // executor answers the others in deterministic keeps.
+        libc::SYS_pipe | libc::SYS_pipe2 => {
+            let flags = match num {
+                libc::SYS_pipe2 => a1,
+                _ => 0,
+            };
+
+            let fds = room.out(a0, 2 * size_of::<libc::c_int>(), false)?;
+            (libc::SYS_pipe2, [fds, flags, 0, 0, 0, 0])
+        }
+        libc::SYS_socketpair => {
+            let fds = room.out(a3, 2 * size_of::<libc::c_int>(), false)?;
+            (num, [a0, a1, a2, fds, 0, 0])
+        }
+        libc::SYS_clock_gettime => {
+            let ts = room.out(a1, size_of::<libc::timespec>(), false)?;
+            (num, [a0, ts, 0, 0, 0, 0])
+        }
+        libc::SYS_getrandom => {
+            let len = a1.min(room.left());
+            (num, [room.out(a0, len, true)?, len, a2, 0, 0, 0])
+        }
+
+        _ => (num, args),
+    })
+}
+
+/// Fills in the initial stack of the payload, as the shims do
+fn crt0setup<'a>(
+    keep: &Keep,
+    crt0: &'a mut [u8],
+    args: &[&str],
+    vars: &[&str],
+    random: [u8; 16],
+) -> Result<Handle<'a>, OutOfSpace> {
+    // Set the arguments
+    let mut builder = Builder::new(crt0);
+    builder.push("/init")?;
+    for arg in args {
+        builder.push(arg)?;
+    }
+
+    // Set the environment
+    let mut builder = builder.done()?;
+    for var in vars {
+        builder.push(var)?;
+    }
+
+    // Set the aux vector, with the features of the CPU which the payload
+    // runs on
+    let (hwcap, hwcap2) = unsafe {
+        (
+            libc::getauxval(libc::AT_HWCAP),
+            libc::getauxval(libc::AT_HWCAP2),
+        )
+    };
+
+    let mut builder = builder.done()?;
+    builder.push(&Entry::ExecFilename("/init"))?;
+    builder.push(&Entry::Platform("x86_64"))?;
+    builder.push(&Entry::Uid(ID as _))?;
+    builder.push(&Entry::EUid(ID as _))?;
+    builder.push(&Entry::Gid(ID as _))?;
+    builder.push(&Entry::EGid(ID as _))?;
+    builder.push(&Entry::PageSize(super::PAGE))?;
+    builder.push(&Entry::Secure(false))?;
+    builder.push(&Entry::ClockTick(100))?;
+    builder.push(&Entry::Flags(0))?;
+    builder.push(&Entry::HwCap(hwcap as _))?;
+    builder.push(&Entry::HwCap2(hwcap2 as _))?;
+    builder.push(&Entry::PHdr(keep.phdr))?;
+    builder.push(&Entry::PHent(keep.phent))?;
+    builder.push(&Entry::PHnum(keep.phnum))?;
+    builder.push(&Entry::Random(random))?;
+    builder.push(&Entry::Entry(keep.entry))?;
+
+    builder.done()
+}
+
+/// Does a `rt_sigprocmask()` syscall on the mask which the handler restores
+unsafe fn sigprocmask(
+    context: *mut libc::ucontext_t,
+    how: usize,
+    set: usize,
+    oldset: usize,
+    size: usize,
+) -> isize {
+    if size != size_of::<u64>() {
+        return -libc::EINVAL as isize;
+    }
+
+    let mask = &mut (*context).uc_sigmask as *mut libc::sigset_t as *mut u64;
+    let old = *mask;
+
+    if set != 0 {
+        let set = *(set as *const u64);
+        let new = match how as libc::c_int {
+            libc::SIG_BLOCK => old | set,
+            libc::SIG_UNBLOCK => old & !set,
+            libc::SIG_SETMASK => set,
+            _ => return -libc::EINVAL as isize,
+        };
+
+        *mask = new & !UNBLOCKABLE;
+    }
+
+    if oldset != 0 {
+        *(oldset as *mut u64) = old;
+    }
+
+    0
+}
+
+/// Does a `sigaltstack()` syscall on the stack which the handler restores
+unsafe fn sigaltstack(context: *mut libc::ucontext_t, ss: usize, old: usize) -> isize {
+    let current = &mut (*context).uc_stack;
+    let prev = *current;
+
+    if ss != 0 {
+        if prev.ss_flags & libc::SS_ONSTACK != 0 {
+            return -libc::EPERM as isize;
+        }
+
+        let ss = *(ss as *const libc::stack_t);
+        *current = match ss.ss_flags & !SS_AUTODISARM {
+            libc::SS_DISABLE => libc::stack_t {
+                ss_sp: std::ptr::null_mut(),
+                ss_flags: libc::SS_DISABLE,
+                ss_size: 0,
+            },
+            0 | libc::SS_ONSTACK if ss.ss_size < libc::MINSIGSTKSZ => {
+                return -libc::ENOMEM as isize
+            }
+            0 | libc::SS_ONSTACK => libc::stack_t {
+                ss_flags: ss.ss_flags & SS_AUTODISARM,
+                ..ss
+            },
+            _ => return -libc::EINVAL as isize,
+        };
+    }
+
+    if old != 0 {
+        *(old as *mut libc::stack_t) = prev;
+    }
+
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PAGE;
+    use super::*;
+
+    use mmarinus::{perms, Kind, Map};
+
+    use std::ffi::CString;
+    use std::sync::atomic::AtomicBool;
+
+    const EINVAL: isize = -libc::EINVAL as isize;
+
+    fn map() -> Map<perms::ReadWrite> {
+        Map::map(PAGE)
+            .anywhere()
+            .anonymously()
+            .known::<perms::ReadWrite>(Kind::Private)
+            .unwrap()
+    }
+
+    fn thread() -> Thread {
+        let stack = map();
+        let keep = Keep {
+            entry: 0,
+            phdr: 0,
+            phent: 0,
+            phnum: 0,
+            brk: 0x10000,
+            top: stack.addr() + PAGE,
+            _image: map(),
+            _stack: stack,
+            spawned: AtomicBool::new(false),
+        };
+
+        Thread::new(Arc::new(keep))
+    }
+
+    /// The arguments of a syscall, padded with zeros
+    fn args(args: &[usize]) -> [usize; 6] {
+        let mut all = [0; 6];
+        all[..args.len()].copy_from_slice(args);
+        all
+    }
+
+    /// Dispatches a syscall, returning what the thread answers itself
+    fn answer(
+        thread: &mut Thread,
+        context: &mut libc::ucontext_t,
+        num: libc::c_long,
+        args: [usize; 6],
+    ) -> Option<Reply> {
+        let call = Call {
+            num: num as usize,
+            args,
+            reply: Reply::Native,
+            context,
+        };
+
+        thread.dispatch(call);
+        match thread.stage {
+            Stage::Answered(reply) => Some(reply),
+            _ => None,
+        }
+    }
+
+    /// Dispatches a syscall which the thread answers with a return value
+    fn ret(
+        thread: &mut Thread,
+        context: &mut libc::ucontext_t,
+        num: libc::c_long,
+        args: [usize; 6],
+    ) -> isize {
+        match answer(thread, context, num, args) {
+            Some(Reply::Ret(ret)) => ret,
+            reply => panic!("{:?}", reply),
+        }
+    }
+
+    #[test]
+    fn answered() {
+        let mut thread = thread();
+        let mut context: libc::ucontext_t = unsafe { std::mem::zeroed() };
+        let mut run = |num, a: &[usize]| ret(&mut thread, &mut context, num, args(a));
+
+        assert_eq!(run(libc::SYS_brk, &[]), 0x10000);
+        assert_eq!(run(libc::SYS_geteuid, &[]), ID);
+        assert_eq!(run(libc::SYS_rt_sigaction, &[libc::SIGSYS as _]), EINVAL);
+
+        let (mut cpu, mut node) = (7u32, 7u32);
+        let ptrs = [&mut cpu as *mut _ as usize, &mut node as *mut _ as usize];
+        assert_eq!(run(libc::SYS_getcpu, &ptrs), 0);
+        assert_eq!((cpu, node), (0, 0));
+
+        let mut mask = [0 as libc::c_ulong; 2];
+        let (size, ptr) = (size_of::<libc::c_ulong>(), mask.as_mut_ptr() as usize);
+        assert_eq!(
+            run(libc::SYS_sched_getaffinity, &[0, 2 * size, ptr]),
+            size as isize
+        );
+        assert_eq!(
+            run(libc::SYS_sched_getaffinity, &[0, size - 1, ptr]),
+            EINVAL
+        );
+        assert_eq!(
+            run(libc::SYS_sched_getaffinity, &[0, size, 0]),
+            -libc::EFAULT as isize
+        );
+        assert_eq!(mask, [1, 0]);
+
+        let onfault = MLOCK_ONFAULT as usize;
+        assert_eq!(run(libc::SYS_mlock2, &[0, 0, onfault]), 0);
+        assert_eq!(run(libc::SYS_mlock2, &[0, 0, 0x100]), EINVAL);
+        assert_eq!(run(libc::SYS_mlockall, &[libc::MCL_CURRENT as _]), 0);
+        assert_eq!(run(libc::SYS_mlockall, &[MCL_ONFAULT as _]), EINVAL);
+
+        // The thread of the payload executes these itself.
+        let native = answer(&mut thread, &mut context, libc::SYS_arch_prctl, args(&[]));
+        assert!(matches!(native, Some(Reply::Native)));
+    }
+
+    #[test]
+    fn signals() {
+        let mut thread = thread();
+        let mut context: libc::ucontext_t = unsafe { std::mem::zeroed() };
+
+        let set = bit(libc::SIGUSR1) | bit(libc::SIGKILL) | bit(libc::SIGSYS);
+        let (set, mut old) = (&set as *const _ as usize, u64::MAX);
+        let block = args(&[libc::SIG_BLOCK as _, set, &mut old as *mut _ as _, 8]);
+        assert_eq!(
+            ret(&mut thread, &mut context, libc::SYS_rt_sigprocmask, block),
+            0
+        );
+        assert_eq!(old, 0);
+
+        // Only the mask which the handler restores changes, and the signals
+        // which the payload can't block stay unblocked.
+        let mask = unsafe { *(&context.uc_sigmask as *const _ as *const u64) };
+        assert_eq!(mask, bit(libc::SIGUSR1));
+
+        let size = args(&[libc::SIG_BLOCK as _, set, 0, 4]);
+        assert_eq!(
+            ret(&mut thread, &mut context, libc::SYS_rt_sigprocmask, size),
+            EINVAL
+        );
+
+        let mut stack = vec![0u8; libc::SIGSTKSZ];
+        let ss = libc::stack_t {
+            ss_sp: stack.as_mut_ptr().cast(),
+            ss_flags: 0,
+            ss_size: stack.len(),
+        };
+        let set = args(&[&ss as *const _ as usize]);
+        assert_eq!(
+            ret(&mut thread, &mut context, libc::SYS_sigaltstack, set),
+            0
+        );
+        assert_eq!(context.uc_stack.ss_sp, ss.ss_sp);
+
+        let small = libc::stack_t { ss_size: 1, ..ss };
+        let set = args(&[&small as *const _ as usize]);
+        assert_eq!(
+            ret(&mut thread, &mut context, libc::SYS_sigaltstack, set),
+            -libc::ENOMEM as isize
+        );
+    }
+
+    #[test]
+    fn forwarded() {
+        let mut thread = thread();
+        let mut context: libc::ucontext_t = unsafe { std::mem::zeroed() };
+        let path = CString::new("/etc/hostname").unwrap();
+
+        let open = args(&[path.as_ptr() as _, libc::O_RDONLY as _]);
+        assert!(answer(&mut thread, &mut context, libc::SYS_open, open).is_none());
+        assert!(matches!(thread.stage, Stage::Forwarded(None)));
+
+        // The path is copied to the block, where the executor looks for it.
+        let req = unsafe { &thread.block.msg.req };
+        assert_eq!(usize::from(req.num), libc::SYS_openat as usize);
+        assert_eq!(usize::from(req.arg[0]), libc::AT_FDCWD as usize);
+        assert_eq!(usize::from(req.arg[1]), thread.block.buf.as_ptr() as usize);
+        assert_eq!(&thread.block.buf[..14], path.as_bytes_with_nul());
+
+        let null = args(&[0, libc::O_RDONLY as _]);
+        assert_eq!(
+            ret(&mut thread, &mut context, libc::SYS_open, null),
+            -libc::EFAULT as isize
+        );
+
+        // The syscalls which the shims don't request aren't executed.
+        let execve = args(&[path.as_ptr() as _]);
+        assert_eq!(
+            ret(&mut thread, &mut context, libc::SYS_execve, execve),
+            -libc::ENOSYS as isize
+        );
+    }
+
+    #[test]
+    fn reply() {
+        let mut thread = thread();
+        let mut context: libc::ucontext_t = unsafe { std::mem::zeroed() };
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+
+        let get = args(&[0, &mut ts as *mut _ as _]);
+        assert!(answer(&mut thread, &mut context, libc::SYS_clock_gettime, get).is_none());
+        let out = match thread.stage {
+            Stage::Forwarded(Some(out)) => out,
+            _ => panic!("the result isn't copied back"),
+        };
+
+        // The executor writes the result to the block, and it is copied back.
+        let result = libc::timespec {
+            tv_sec: 7,
+            tv_nsec: 8,
+        };
+        let size = size_of::<libc::timespec>();
+        let src = unsafe { std::slice::from_raw_parts(&result as *const _ as *const u8, size) };
+        thread.block.buf[out.offset..][..size].copy_from_slice(src);
+        thread.block.msg.rep = sallyport::Reply::from(Ok([0usize.into(), 0usize.into()]));
+        assert_eq!(thread.reply(Some(out)), 0);
+        assert_eq!((ts.tv_sec, ts.tv_nsec), (7, 8));
+
+        thread.block.msg.rep = sallyport::Reply::from(Err(libc::EINVAL));
+        assert_eq!(thread.reply(None), EINVAL);
+    }
+
+    #[test]
+    fn translated() {
+        let mut buf = [0u8; 256];
+        let mut room = Room {
+            buf: &mut buf,
+            used: 0,
+            out: None,
+        };
+
+        let (old, new) = (CString::new("a").unwrap(), CString::new("b").unwrap());
+        let rename = args(&[old.as_ptr() as _, new.as_ptr() as _]);
+        let (num, at) = translate(&mut room, libc::SYS_rename, rename).unwrap();
+        assert_eq!(num, libc::SYS_renameat2);
+        assert_eq!(at[0], libc::AT_FDCWD as usize);
+        assert_eq!(at[2], libc::AT_FDCWD as usize);
+        assert_eq!(at[3] - at[1], 8);
+
+        let mut fds = [0 as libc::c_int; 2];
+        let pipe = args(&[fds.as_mut_ptr() as _]);
+        let (num, pipe2) = translate(&mut room, libc::SYS_pipe, pipe).unwrap();
+        assert_eq!((num, pipe2[1]), (libc::SYS_pipe2, 0));
+        assert_eq!(room.out.unwrap().addr, fds.as_mut_ptr() as usize);
+        assert!(!room.out.unwrap().counted);
+
+        // Only as many random bytes as fit are asked for.
+        let (mut random, left) = ([0u8; 1024], room.left());
+        let get = args(&[random.as_mut_ptr() as _, random.len()]);
+        let (_, get) = translate(&mut room, libc::SYS_getrandom, get).unwrap();
+        assert_eq!(get[1], left);
+        assert!(room.out.unwrap().counted);
+        assert_eq!(room.left(), 0);
+
+        let unlink = args(&[old.as_ptr() as _]);
+        let full = translate(&mut room, libc::SYS_unlink, unlink);
+        assert_eq!(full, Err(libc::EMSGSIZE));
+    }
+}
@@ -0,0 +1,295 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The thread of the payload
+//!
+//! It is confined with a seccomp filter which traps every syscall but those
+//! of `native()`, so that the kernel raises `SIGSYS` instead of executing
+//! them. The handler passes the syscall to the thread of the loader and
+//! sleeps until it is answered. The two threads take turns with a futex.
+//!
+//! The handler runs on the stack and with the thread pointer of the payload,
+//! so it must not touch thread-local storage, allocate or panic.
+
+use crate::sandbox::{self, BPF_JMP_JEQ_K};
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// The payload thread waits to be started
+const STARTING: u32 = 0;
+
+/// The loader answers a syscall of the payload
+pub(super) const HOST: u32 = 1;
+
+/// The payload runs
+const KEEP: u32 = 2;
+
+/// The payload thread couldn't be set up
+pub(super) const FAILED: u32 = 3;
+
+/// The `si_code` of the `SIGSYS` raised by seccomp
+const SYS_SECCOMP: libc::c_int = 1;
+
+/// How the loader answers a syscall
+#[derive(Copy, Clone, Debug)]
+pub(super) enum Reply {
+    /// The syscall returns this
+    Ret(isize),
+
+    /// The payload thread executes the syscall itself
+    Native,
+}
+
+/// A syscall of the payload
+#[derive(Copy, Clone, Debug)]
+pub(super) struct Call {
+    pub num: usize,
+    pub args: [usize; 6],
+    pub reply: Reply,
+
+    /// The context which the handler returns to, e.g. for the signal mask
+    pub context: *mut libc::ucontext_t,
+}
+
+/// What the threads share
+struct Shared {
+    turn: AtomicU32,
+    call: UnsafeCell<Call>,
+
+    /// The entry point and the initial stack pointer of the payload
+    start: UnsafeCell<(usize, usize)>,
+
+    /// Where the syscall of `native()` returns to
+    native: UnsafeCell<usize>,
+}
+
+// The turn tells which thread may touch the rest.
+unsafe impl Sync for Shared {}
+
+static SHARED: Shared = Shared {
+    turn: AtomicU32::new(STARTING),
+    call: UnsafeCell::new(Call {
+        num: 0,
+        args: [0; 6],
+        reply: Reply::Ret(0),
+        context: std::ptr::null_mut(),
+    }),
+    start: UnsafeCell::new((0, 0)),
+    native: UnsafeCell::new(0),
+};
+
+/// The `siginfo_t` of `SIGSYS`
+#[repr(C)]
+struct SigSys {
+    signo: libc::c_int,
+    errno: libc::c_int,
+    code: libc::c_int,
+    call_addr: *mut libc::c_void,
+    syscall: libc::c_int,
+    arch: libc::c_uint,
+}
+
+/// Executes a syscall on the calling thread
+///
+/// This is the only place from which the payload thread may make syscalls
+/// once it is confined. The return value of the syscall is returned along
+/// with the address which it returns to.
+#[inline(never)]
+unsafe fn native(num: usize, args: [usize; 6]) -> (isize, usize) {
+    let ret: isize;
+    let ip: usize;
+
+    asm!(
+        "syscall",
+        "2:",
+        "lea {ip}, [rip + 2b]",
+        ip = lateout(reg) ip,
+        inlateout("rax") num as isize => ret,
+        in("rdi") args[0],
+        in("rsi") args[1],
+        in("rdx") args[2],
+        in("r10") args[3],
+        in("r8") args[4],
+        in("r9") args[5],
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack),
+    );
+
+    (ret, ip)
+}
+
+/// Waits until it is the turn of `who`
+///
+/// Returns `false` if a signal interrupted the wait.
+fn wait(who: u32) -> bool {
+    loop {
+        let turn = SHARED.turn.load(Ordering::Acquire);
+        if turn == who || turn == FAILED {
+            return true;
+        }
+
+        let addr = &SHARED.turn as *const AtomicU32 as usize;
+        let (ret, _) = unsafe {
+            let op = (libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG) as usize;
+            native(libc::SYS_futex as _, [addr, op, turn as _, 0, 0, 0])
+        };
+
+        if ret == -libc::EINTR as isize {
+            return false;
+        }
+    }
+}
+
+/// Passes the turn to `who`
+fn pass(who: u32) {
+    SHARED.turn.store(who, Ordering::Release);
+
+    let addr = &SHARED.turn as *const AtomicU32 as usize;
+    unsafe {
+        let op = (libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG) as usize;
+        native(libc::SYS_futex as _, [addr, op, 1, 0, 0, 0]);
+    }
+}
+
+/// Starts the payload at `entry` with the stack pointer at `sp`
+pub(super) fn start(entry: usize, sp: usize) {
+    unsafe { *SHARED.start.get() = (entry, sp) };
+    pass(KEEP);
+}
+
+/// Answers the syscall which the payload waits for
+pub(super) fn answer(reply: Reply) {
+    unsafe { (*SHARED.call.get()).reply = reply };
+    pass(KEEP);
+}
+
+/// Waits for the next syscall of the payload
+///
+/// Returns `None` if a signal interrupted the wait, and an error if the
+/// payload thread couldn't be set up.
+pub(super) fn next() -> Option<Result<Call, ()>> {
+    if !wait(HOST) {
+        return None;
+    }
+
+    match SHARED.turn.load(Ordering::Acquire) {
+        FAILED => Some(Err(())),
+        _ => Some(Ok(unsafe { *SHARED.call.get() })),
+    }
+}
+
+/// Handles the syscalls of the payload
+extern "C" fn sigsys(_: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    unsafe {
+        let info = &*(info as *const SigSys);
+        if info.code != SYS_SECCOMP {
+            return;
+        }
+
+        let context = context as *mut libc::ucontext_t;
+        let gregs = &mut (*context).uc_mcontext.gregs;
+        let reg = |r: libc::c_int| gregs[r as usize] as usize;
+
+        let call = &mut *SHARED.call.get();
+        call.num = info.syscall as _;
+        call.args = [
+            reg(libc::REG_RDI),
+            reg(libc::REG_RSI),
+            reg(libc::REG_RDX),
+            reg(libc::REG_R10),
+            reg(libc::REG_R8),
+            reg(libc::REG_R9),
+        ];
+        call.context = context;
+
+        pass(HOST);
+        while !wait(KEEP) {}
+
+        let ret = match call.reply {
+            Reply::Ret(ret) => ret,
+            Reply::Native => native(call.num, call.args).0,
+        };
+
+        gregs[libc::REG_RAX as usize] = ret as _;
+    }
+}
+
+/// Installs the handler of `SIGSYS`
+fn handle() -> std::io::Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        let handler: extern "C" fn(_, _, _) = sigsys;
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_SIGINFO;
+
+        // Nothing may interrupt the handler, as it borrows the payload's
+        // thread pointer.
+        libc::sigfillset(&mut action.sa_mask);
+
+        if libc::sigaction(libc::SIGSYS, &action, std::ptr::null_mut()) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Traps all syscalls of the calling thread, but those of `native()`
+///
+/// `rt_sigreturn()` is allowed as well, so that the handler can return.
+fn confine() -> anyhow::Result<()> {
+    let ip = unsafe { *SHARED.native.get() } as u64;
+
+    sandbox::confine(&[
+        sandbox::load(sandbox::ARCH),
+        sandbox::jump(BPF_JMP_JEQ_K, sandbox::AUDIT_ARCH_X86_64, 1, 0),
+        sandbox::ret(sandbox::SECCOMP_RET_KILL_PROCESS),
+        sandbox::load(sandbox::NR),
+        sandbox::jump(BPF_JMP_JEQ_K, libc::SYS_rt_sigreturn as u32, 0, 1),
+        sandbox::ret(sandbox::SECCOMP_RET_ALLOW),
+        sandbox::load(sandbox::IP),
+        sandbox::jump(BPF_JMP_JEQ_K, ip as u32, 0, 3),
+        sandbox::load(sandbox::IP + 4),
+        sandbox::jump(BPF_JMP_JEQ_K, (ip >> 32) as u32, 0, 1),
+        sandbox::ret(sandbox::SECCOMP_RET_ALLOW),
+        sandbox::ret(sandbox::SECCOMP_RET_TRAP),
+    ])
+}
+
+/// Runs the payload on the calling thread, once the loader starts it
+///
+/// The thread is only confined once the payload starts, since the loader
+/// may switch the user of the process before, which glibc does by
+/// signalling every thread.
+pub(super) fn run() {
+    let (_, ip) = unsafe { native(libc::SYS_gettid as _, [0; 6]) };
+    unsafe { *SHARED.native.get() = ip };
+
+    if let Err(e) = handle() {
+        tracing::error!("unable to handle the syscalls of the payload: {}", e);
+        pass(FAILED);
+        return;
+    }
+
+    while !wait(KEEP) {}
+
+    if let Err(e) = confine() {
+        tracing::error!("{:#}", e);
+        pass(FAILED);
+        return;
+    }
+
+    let (entry, sp) = unsafe { *SHARED.start.get() };
+    unsafe {
+        asm!(
+            "mov rsp, rsi",
+            "xor ebp, ebp",
+            "xor edx, edx",
+            "jmp rdi",
+            in("rdi") entry,
+            in("rsi") sp,
+            options(noreturn),
+        )
+    }
+}
@@ -0,0 +1,206 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use super::launch::{Launcher, SEV_ES_MAGIC};
+use super::owner::Remote;
+use super::{certs, policy};
+use crate::backend::kvm::{create_vcpu, Hook};
+use crate::backend::{BuildError, GuestOwner};
+use crate::binary::{Component, PT_ENARX_CODE};
+
+use anyhow::{anyhow, bail, Result};
+use kvm_bindings::kvm_enc_region;
+use kvm_ioctls::{VcpuFd, VmFd};
+use primordial::Page;
+use sallyport::Block;
+use sev::firmware::Firmware;
+use sev::launch::{Policy, PolicyFlags, Secret};
+use sev::session::Session;
+use tracing::debug;
+use x86_64::VirtAddr;
+
+use std::convert::TryFrom;
+use std::mem::size_of;
+
+/// Launches the VM of a keep through the SEV firmware
+///
+/// A remote guest owner starts the session, verifies the measurement and
+/// releases the secret of the keep (see [`owner`](super::owner)). Without
+/// one, the loader acts as the guest owner: it starts the session with the
+/// policy below, computes the measurement it expects and checks the one
+/// returned by the firmware against it.
+///
+/// SEV-ES guests are measured with the state of their vCPUs, which the loader
+/// doesn't predict, so their measurement is only reported.
+#[derive(Default)]
+pub struct Sev {
+    /// The policy of the guest, unless the guest owner sets it
+    pub policy: Policy,
+
+    /// Whether the certificates of the platform may be cached
+    pub cache: bool,
+
+    /// The remote guest owner
+    pub owner: Option<GuestOwner>,
+
+    /// The number of vCPUs, which SEV-ES guests are measured with
+    pub cpus: usize,
+
+    /// Whether the guest owner delivers the payload into the empty code slot
+    pub remote_payload: bool,
+
+    /// The offset of the code slot in the address space
+    code: usize,
+    measurement: Option<Vec<u8>>,
+    vcpus: Vec<VcpuFd>,
+}
+
+impl Hook for Sev {
+    fn shim_loaded(
+        &mut self,
+        _vm: &mut VmFd,
+        _addr_space: &mut [u8],
+        shim: &Component,
+    ) -> Result<()> {
+        let code = shim
+            .find_header(PT_ENARX_CODE)
+            .ok_or_else(|| anyhow!("Couldn't find CODE program header in shim executable."))?;
+        self.code = code.vm_range().start - shim.region().start;
+        Ok(())
+    }
+
+    fn code_loaded(
+        &mut self,
+        vm: &mut VmFd,
+        addr_space: &[u8],
+        syscall_blocks: VirtAddr,
+    ) -> Result<()> {
+        let mut sev = Firmware::open()?;
+        let status = sev
+            .platform_status()
+            .map_err(|e| anyhow!("unable to get the SEV platform status: {:?}", e))?;
+        let chain = certs::chain(&mut sev, self.cache)?;
+
+        if self.remote_payload && self.owner.is_none() {
+            bail!("only a remote guest owner can deliver the payload (--sev-owner)");
+        }
+
+        let mut remote = self.owner.as_ref().map(Remote::connect).transpose()?;
+        let (start, session) = match remote.as_mut() {
+            Some(remote) => (remote.start(&chain, status.build)?, None),
+            None => {
+                let session = Session::try_from(self.policy)?;
+                let start = session.start(chain)?;
+
+                // The guest owner measures the plaintext, so it goes first.
+                let mut session = session.measure();
+                session.update_data(addr_space)?;
+                (start, Some(session))
+            }
+        };
+
+        policy::check(&start.policy, status.build.version)?;
+        let es = start.policy.flags.contains(PolicyFlags::ENCRYPTED_STATE);
+
+        // `KVM_SEV_INIT` must come before the memory of the VM is registered
+        // as encrypted.
+        let mut launcher = Launcher::new(vm, &sev, es)?;
+        vm.register_enc_memory_region(&kvm_enc_region {
+            addr: addr_space.as_ptr() as _,
+            size: addr_space.len() as _,
+        })?;
+
+        launcher.start(&start)?;
+        launcher.update_data(addr_space)?;
+
+        // The vCPUs start with the registers which are measured now, so they
+        // are created here instead of when the keep is spawned.
+        if es {
+            for id in 0..self.cpus as u64 {
+                let vcpu = create_vcpu(vm, id)?;
+                let mut regs = vcpu.get_regs()?;
+                regs.rsi = SEV_ES_MAGIC;
+                vcpu.set_regs(&regs)?;
+                self.vcpus.push(vcpu);
+            }
+
+            launcher.update_vmsa()?;
+        }
+
+        let measurement = launcher.measure()?;
+        match (remote, session) {
+            (Some(remote), _) => {
+                let release = remote.measured(&measurement)?;
+
+                // The shim reads the secret from the first syscall block.
+                if let Some(secret) = release.secret {
+                    if secret.ciphertext.len() > size_of::<Block>() {
+                        bail!(
+                            "the secret is too large ({} bytes)",
+                            secret.ciphertext.len()
+                        );
+                    }
+
+                    launcher.inject(&secret, syscall_blocks.as_u64())?;
+                }
+
+                self.deliver(&mut launcher, addr_space, &release.payload)?;
+            }
+
+            (None, Some(session)) if !es => {
+                session
+                    .verify(status.build, measurement)
+                    .map_err(|_| anyhow!("the SEV launch measurement does not match"))?;
+            }
+
+            _ => (),
+        }
+        launcher.finish()?;
+
+        debug!(es, remote = self.owner.is_some(), "launched the SEV guest");
+        self.measurement = Some([&measurement.measure[..], &measurement.mnonce[..]].concat());
+        Ok(())
+    }
+
+    fn measurement(&mut self) -> Option<Vec<u8>> {
+        self.measurement.take()
+    }
+
+    fn vcpus(&mut self) -> Vec<VcpuFd> {
+        std::mem::take(&mut self.vcpus)
+    }
+}
+
+impl Sev {
+    /// Injects the pages of the payload into the empty code slot
+    ///
+    /// `LAUNCH_SECRET` only writes to physically contiguous memory, so each
+    /// page arrives in a packet of its own.
+    fn deliver(&self, launcher: &mut Launcher, addr_space: &[u8], pages: &[Secret]) -> Result<()> {
+        match (self.remote_payload, pages.is_empty()) {
+            (true, true) => bail!("the guest owner sent no payload"),
+            (false, false) => bail!("the guest owner sent a payload, but the keep has one"),
+            (false, true) => return Ok(()),
+            (true, false) => (),
+        }
+
+        let slot = &addr_space[self.code..];
+        if pages.len() > slot.len() / Page::SIZE {
+            return Err(BuildError::CodeTooLarge {
+                need: pages.len() * Page::SIZE,
+                have: slot.len(),
+            }
+            .into());
+        }
+
+        for (page, chunk) in pages.iter().zip(slot.chunks(Page::SIZE)) {
+            if page.ciphertext.len() > Page::SIZE {
+                bail!("a page of the payload is too large");
+            }
+
+            launcher.inject(page, chunk.as_ptr() as _)?;
+        }
+
+        debug!(pages = pages.len(), "delivered the payload");
+        Ok(())
+    }
+}
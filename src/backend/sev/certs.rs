@@ -0,0 +1,155 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The certificate chain of the platform
+//!
+//! The firmware exports the platform half of the chain: the PDH, the PEK, the
+//! OCA and the CEK. The CEK is only trusted once it is signed by the ASK of
+//! the processor generation, which is in turn signed by the ARK, AMD's root
+//! of trust. The AMD Key Distribution Service (KDS) hands out the CEK of each
+//! chip (by its ID) signed by the ASK, and AMD publishes the ASK and the ARK
+//! of each generation.
+//!
+//! Both downloads are kept beneath `$XDG_CACHE_HOME/enarx-keepldr/sev` (or
+//! `~/.cache`), so only the first launch on a platform needs the network.
+//! The chain is verified every time it is assembled; removing the cache
+//! directory recovers from an unusable entry.
+
+use crate::backend::cache_dir;
+
+use anyhow::{anyhow, bail, Context, Result};
+use codicon::Decoder;
+use sev::certs::{ca, sev as platform, Chain, Verifiable};
+use sev::firmware::Firmware;
+use tracing::debug;
+
+use std::arch::x86_64::__cpuid;
+use std::fs::{self, DirBuilder};
+use std::io::Read;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::Path;
+
+/// The KDS endpoint which hands out the CEK of a chip
+const CEK_URL: &str = "https://kdsintf.amd.com/cek/id";
+
+/// Where AMD publishes the ASK and the ARK of a processor generation
+const ASK_ARK_URL: &str = "https://developer.amd.com/wp-content/resources";
+
+/// The largest certificate file which is downloaded
+const MAX_SIZE: u64 = 64 * 1024;
+
+/// Assembles the full certificate chain of the platform
+///
+/// With `cache` unset, the certificates are always downloaded and the cache
+/// is not touched.
+pub fn chain(sev: &mut Firmware, cache: bool) -> Result<Chain> {
+    let mut platform = sev
+        .pdh_cert_export()
+        .map_err(|e| anyhow!("unable to export the SEV certificate chain: {:?}", e))?;
+
+    let id = sev
+        .get_identifier()
+        .map_err(|e| anyhow!("unable to get the SEV chip ID: {:?}", e))?
+        .to_string();
+
+    let eax = unsafe { __cpuid(1) }.eax;
+    let gen = generation(eax).ok_or_else(|| anyhow!("unknown SEV processor: {:#x}", eax))?;
+
+    let dir = match cache {
+        true => cache_dir("sev"),
+        false => None,
+    };
+
+    let name = format!("cek-{}.cert", id);
+    let cek = fetch(dir.as_deref(), &name, &format!("{}/{}", CEK_URL, id))?;
+
+    let name = format!("ask_ark_{}.cert", gen);
+    let ask_ark = fetch(dir.as_deref(), &name, &format!("{}/{}", ASK_ARK_URL, name))?;
+
+    platform.cek = platform::Certificate::decode(&mut &cek[..], ()).context("invalid CEK")?;
+
+    let mut rdr = &ask_ark[..];
+    let ask = ca::Certificate::decode(&mut rdr, ()).context("invalid ASK")?;
+    let ark = ca::Certificate::decode(&mut rdr, ()).context("invalid ARK")?;
+
+    let chain = Chain {
+        ca: ca::Chain { ark, ask },
+        sev: platform,
+    };
+
+    if (&chain).verify().is_err() {
+        bail!("the SEV certificate chain does not verify");
+    }
+
+    Ok(chain)
+}
+
+/// The processor generation of a CPUID Fn0000_0001[EAX]
+fn generation(eax: u32) -> Option<&'static str> {
+    let family = ((eax >> 8) & 0xf) + ((eax >> 20) & 0xff);
+    let model = ((eax >> 4) & 0xf) | ((eax >> 16) & 0xf) << 4;
+
+    match (family, model) {
+        (0x17, 0x00..=0x0f) => Some("naples"),
+        (0x17, 0x30..=0x3f) => Some("rome"),
+        (0x19, 0x00..=0x0f) => Some("milan"),
+        _ => None,
+    }
+}
+
+/// Reads a file from the cache, or downloads it and stores it there
+fn fetch(dir: Option<&Path>, name: &str, url: &str) -> Result<Vec<u8>> {
+    if let Some(bytes) = dir.and_then(|dir| fs::read(dir.join(name)).ok()) {
+        debug!("using the cached {}", name);
+        return Ok(bytes);
+    }
+
+    debug!("downloading {}", url);
+
+    let mut bytes = Vec::new();
+    ureq::get(url)
+        .call()
+        .map_err(|e| anyhow!("unable to download {}: {}", url, e))?
+        .into_reader()
+        .take(MAX_SIZE)
+        .read_to_end(&mut bytes)?;
+
+    if let Some(dir) = dir {
+        store(dir, name, &bytes);
+    }
+
+    Ok(bytes)
+}
+
+/// Stores a file in the cache
+///
+/// A keep can be launched without the cache, so failures are only logged.
+fn store(dir: &Path, name: &str, bytes: &[u8]) {
+    let path = dir.join(name);
+
+    // Concurrent launches must never see a partial entry.
+    let tmp = path.with_extension(std::process::id().to_string());
+    let result = DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .and_then(|_| fs::write(&tmp, bytes))
+        .and_then(|_| fs::rename(&tmp, &path));
+
+    if let Err(e) = result {
+        debug!("unable to store {}: {}", path.display(), e);
+        let _ = fs::remove_file(&tmp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generations() {
+        assert_eq!(generation(0x0080_0f12), Some("naples"));
+        assert_eq!(generation(0x0083_0f10), Some("rome"));
+        assert_eq!(generation(0x00a0_0f11), Some("milan"));
+        assert_eq!(generation(0x0009_06ea), None);
+    }
+}
@@ -0,0 +1,119 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::backend::probe::x86_64::{CpuId, Vendor};
+use crate::backend::Datum;
+
+use sev::firmware::Firmware;
+
+use std::fs::OpenOptions;
+
+pub const CPUIDS: &[CpuId] = &[
+    CpuId {
+        name: "CPU Manufacturer",
+        leaf: 0x00000000,
+        subl: 0x00000000,
+        func: |res| {
+            let name: [u8; 12] = unsafe { std::mem::transmute([res.ebx, res.edx, res.ecx]) };
+            let name = std::str::from_utf8(&name[..]).unwrap();
+            (name == "AuthenticAMD", Some(name.into()))
+        },
+        vend: None,
+    },
+    CpuId {
+        name: " Microcode support",
+        leaf: 0x80000000,
+        subl: 0x00000000,
+        func: |res| (res.eax >= 0x8000001f, None),
+        vend: Some(Vendor::Amd),
+    },
+    CpuId {
+        name: "  Secure Memory Encryption (SME)",
+        leaf: 0x8000001f,
+        subl: 0x00000000,
+        func: |res| (res.eax & 0x1 != 0, None),
+        vend: Some(Vendor::Amd),
+    },
+    CpuId {
+        name: "   Physical address bit reduction",
+        leaf: 0x8000001f,
+        subl: 0x00000000,
+        func: |res| (true, Some(format!("{}", (res.ebx >> 6) & 0x3f))),
+        vend: Some(Vendor::Amd),
+    },
+    CpuId {
+        name: "   C-bit location in page table entry",
+        leaf: 0x8000001f,
+        subl: 0x00000000,
+        func: |res| (true, Some(format!("{}", res.ebx & 0x3f))),
+        vend: Some(Vendor::Amd),
+    },
+    CpuId {
+        name: "  Secure Encrypted Virtualization (SEV)",
+        leaf: 0x8000001f,
+        subl: 0x00000000,
+        func: |res| (res.eax & (1 << 1) != 0, None),
+        vend: Some(Vendor::Amd),
+    },
+    CpuId {
+        name: "   Number of encrypted guests supported simultaneously",
+        leaf: 0x8000001f,
+        subl: 0x00000000,
+        func: |res| (true, Some(format!("{}", res.ecx))),
+        vend: Some(Vendor::Amd),
+    },
+];
+
+pub fn dev_sev() -> Datum {
+    Datum {
+        name: "Driver".into(),
+        pass: std::path::Path::new("/dev/sev").exists(),
+        info: Some("/dev/sev".into()),
+        mesg: None,
+    }
+}
+
+pub fn dev_sev_writable() -> Datum {
+    let opts = OpenOptions::new().read(true).write(true).open("/dev/sev");
+
+    Datum {
+        name: " /dev/sev is readable and writable by user".into(),
+        pass: opts.is_ok(),
+        info: None,
+        mesg: None,
+    }
+}
+
+pub fn firmware() -> Datum {
+    let status = Firmware::open()
+        .ok()
+        .and_then(|mut sev| sev.platform_status().ok());
+
+    Datum {
+        name: " Firmware".into(),
+        pass: status.is_some(),
+        info: status.map(|s| {
+            let b = s.build;
+            format!(
+                "{}.{} (build {})",
+                b.version.major, b.version.minor, b.build
+            )
+        }),
+        mesg: None,
+    }
+}
+
+pub fn sev_enabled_in_kernel() -> Datum {
+    let mut datum = Datum {
+        name: " SEV is enabled in host kernel".into(),
+        pass: false,
+        info: None,
+        mesg: None,
+    };
+
+    let mod_param = "/sys/module/kvm_amd/parameters/sev";
+    if let Ok(param) = std::fs::read_to_string(mod_param) {
+        datum.pass = param.trim() == "1" || param.trim() == "Y";
+    }
+
+    datum
+}
@@ -0,0 +1,210 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The SEV launch commands, issued with `KVM_MEMORY_ENCRYPT_OP`
+//!
+//! The `Launcher` of the `sev` crate always initializes the guest with
+//! `KVM_SEV_INIT`. SEV-ES guests need `KVM_SEV_ES_INIT` instead, and the
+//! state of their vCPUs is encrypted and measured with
+//! `KVM_SEV_LAUNCH_UPDATE_VMSA` before the launch is measured. So both kinds
+//! of guests are launched with the commands below.
+
+use super::policy;
+
+use anyhow::{anyhow, Result};
+use iocuddle::*;
+use kvm_ioctls::VmFd;
+use sev::firmware::Firmware;
+use sev::launch::{Measurement, Secret, Start};
+
+use std::mem::{size_of, size_of_val, MaybeUninit};
+use std::os::raw::c_ulong;
+use std::os::unix::io::AsRawFd;
+
+/// The value of `esi` in the initial registers of SEV-ES guests ("SEVE")
+///
+/// The shim can't execute `cpuid` before its #VC handler is set up, so it
+/// learns that it runs as a SEV-ES guest from its (measured) registers.
+pub const SEV_ES_MAGIC: u64 = 0x5345_5645;
+
+const KVM: Group = Group::new(0xAE);
+
+/// `KVM_MEMORY_ENCRYPT_OP` is declared with an `unsigned long` argument
+const ENC_OP: Ioctl<WriteRead, &c_ulong> = unsafe { KVM.write_read(0xBA) };
+
+/// `KVM_MEMORY_ENCRYPT_OP`, with the argument it actually takes
+const MEMORY_ENCRYPT_OP: Ioctl<WriteRead, &Command> = unsafe { ENC_OP.lie() };
+
+/// The commands of `KVM_MEMORY_ENCRYPT_OP` (see `enum sev_cmd_id` in Linux)
+const INIT: u32 = 0;
+const ES_INIT: u32 = 1;
+const LAUNCH_START: u32 = 2;
+const LAUNCH_UPDATE_DATA: u32 = 3;
+const LAUNCH_UPDATE_VMSA: u32 = 4;
+const LAUNCH_SECRET: u32 = 5;
+const LAUNCH_MEASURE: u32 = 6;
+const LAUNCH_FINISH: u32 = 7;
+const DBG_DECRYPT: u32 = 17;
+
+#[repr(C)]
+struct Command {
+    id: u32,
+    data: u64,
+    error: u32,
+    sev_fd: u32,
+}
+
+#[repr(C)]
+struct LaunchStart {
+    handle: u32,
+    policy: u32,
+    dh_uaddr: u64,
+    dh_len: u32,
+    session_uaddr: u64,
+    session_len: u32,
+}
+
+#[repr(C)]
+struct LaunchBuffer {
+    uaddr: u64,
+    len: u32,
+}
+
+#[repr(C)]
+struct LaunchSecret {
+    hdr_uaddr: u64,
+    hdr_len: u32,
+    guest_uaddr: u64,
+    guest_len: u32,
+    trans_uaddr: u64,
+    trans_len: u32,
+}
+
+#[repr(C)]
+struct DebugBuffer {
+    src_uaddr: u64,
+    dst_uaddr: u64,
+    len: u32,
+}
+
+/// Launches a VM through the SEV firmware
+pub struct Launcher<'a> {
+    vm: &'a VmFd,
+    sev: &'a Firmware,
+}
+
+impl<'a> Launcher<'a> {
+    /// Initializes the VM as a SEV guest, or as a SEV-ES guest if `es` is set
+    ///
+    /// This must happen before the vCPUs are created and before the memory
+    /// of the VM is registered as encrypted.
+    pub fn new(vm: &'a VmFd, sev: &'a Firmware, es: bool) -> Result<Self> {
+        let launcher = Self { vm, sev };
+        match es {
+            true => launcher.op("ES_INIT", ES_INIT, 0)?,
+            false => launcher.op("INIT", INIT, 0)?,
+        }
+
+        Ok(launcher)
+    }
+
+    /// Starts the launch with the session of the guest owner
+    pub fn start(&mut self, start: &Start) -> Result<()> {
+        let mut data = LaunchStart {
+            handle: 0,
+            policy: policy::encode(&start.policy),
+            dh_uaddr: &start.cert as *const _ as _,
+            dh_len: size_of_val(&start.cert) as _,
+            session_uaddr: &start.session as *const _ as _,
+            session_len: size_of_val(&start.session) as _,
+        };
+
+        self.op("LAUNCH_START", LAUNCH_START, &mut data as *mut _ as _)
+    }
+
+    /// Encrypts and measures memory of the VM
+    pub fn update_data(&mut self, data: &[u8]) -> Result<()> {
+        let mut data = LaunchBuffer {
+            uaddr: data.as_ptr() as _,
+            len: data.len() as _,
+        };
+
+        self.op(
+            "LAUNCH_UPDATE_DATA",
+            LAUNCH_UPDATE_DATA,
+            &mut data as *mut _ as _,
+        )
+    }
+
+    /// Encrypts and measures the state of every vCPU (SEV-ES only)
+    pub fn update_vmsa(&mut self) -> Result<()> {
+        self.op("LAUNCH_UPDATE_VMSA", LAUNCH_UPDATE_VMSA, 0)
+    }
+
+    /// Returns the launch measurement
+    pub fn measure(&mut self) -> Result<Measurement> {
+        let mut measurement = MaybeUninit::<Measurement>::uninit();
+        let mut data = LaunchBuffer {
+            uaddr: measurement.as_mut_ptr() as _,
+            len: size_of::<Measurement>() as _,
+        };
+
+        self.op("LAUNCH_MEASURE", LAUNCH_MEASURE, &mut data as *mut _ as _)?;
+        Ok(unsafe { measurement.assume_init() })
+    }
+
+    /// Decrypts a secret of the guest owner into the memory at `guest`
+    ///
+    /// This must happen after the launch is measured.
+    pub fn inject(&mut self, secret: &Secret, guest: u64) -> Result<()> {
+        let mut data = LaunchSecret {
+            hdr_uaddr: &secret.header as *const _ as _,
+            hdr_len: size_of_val(&secret.header) as _,
+            guest_uaddr: guest,
+            guest_len: secret.ciphertext.len() as _,
+            trans_uaddr: secret.ciphertext.as_ptr() as _,
+            trans_len: secret.ciphertext.len() as _,
+        };
+
+        self.op("LAUNCH_SECRET", LAUNCH_SECRET, &mut data as *mut _ as _)
+    }
+
+    /// Finishes the launch, after which the VM can run
+    pub fn finish(self) -> Result<()> {
+        self.op("LAUNCH_FINISH", LAUNCH_FINISH, 0)
+    }
+
+    fn op(&self, name: &str, id: u32, data: u64) -> Result<()> {
+        op(self.vm, self.sev, name, id, data)
+    }
+}
+
+/// Decrypts the memory of a running guest at `src` into `dst` (`DBG_DECRYPT`)
+///
+/// The firmware refuses, unless the policy of the guest allows debugging.
+/// Both buffers must be aligned to 16 bytes.
+pub fn decrypt(vm: &VmFd, sev: &Firmware, src: &[u8], dst: &mut [u8]) -> Result<()> {
+    let mut data = DebugBuffer {
+        src_uaddr: src.as_ptr() as _,
+        dst_uaddr: dst.as_mut_ptr() as _,
+        len: src.len().min(dst.len()) as _,
+    };
+
+    let data = &mut data as *mut _ as _;
+    op(vm, sev, "DBG_DECRYPT", DBG_DECRYPT, data)
+}
+
+fn op(vm: &VmFd, sev: &Firmware, name: &str, id: u32, data: u64) -> Result<()> {
+    let mut cmd = Command {
+        id,
+        data,
+        error: 0,
+        sev_fd: sev.as_raw_fd() as _,
+    };
+
+    let mut fd = vm.as_raw_fd();
+    MEMORY_ENCRYPT_OP
+        .ioctl(&mut fd, &mut cmd)
+        .map_err(|e| anyhow!("SEV {} failed: {} (firmware error {})", name, e, cmd.error))?;
+
+    Ok(())
+}
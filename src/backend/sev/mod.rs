@@ -0,0 +1,124 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The SEV backend
+//!
+//! SEV keeps are KVM virtual machines whose memory is encrypted with a key
+//! which only the AMD Secure Processor knows. They run the same shim as KVM
+//! keeps. Once the shim and the payload are loaded, the memory is encrypted
+//! and measured by the firmware (`LAUNCH_START`, `LAUNCH_UPDATE_DATA`,
+//! `LAUNCH_MEASURE` and `LAUNCH_FINISH`), and the launch measurement is kept
+//! for callers to verify (see `Keep::measurement()`). With `--sev-owner`, a
+//! remote guest owner verifies it and may release a secret to the keep.
+//!
+//! With SEV-ES (`--sev-es`), the register state of the vCPUs is encrypted as
+//! well. The shim then handles the #VC exceptions raised by the instructions
+//! which the host has to emulate, and exchanges their registers with the host
+//! through the GHCB.
+
+mod builder;
+mod certs;
+mod data;
+mod launch;
+mod owner;
+mod personality;
+mod policy;
+
+use crate::backend::kvm::{self, Builder};
+use crate::backend::{self, Config, Datum, Keep};
+use crate::binary::Component;
+use crate::cpuid::Policy;
+
+use anyhow::Result;
+use openssl::sha::sha256;
+
+use std::sync::{Arc, RwLock};
+
+pub struct Backend;
+
+impl backend::Backend for Backend {
+    fn name(&self) -> &'static str {
+        "sev"
+    }
+
+    fn shim(&self) -> Result<&'static [u8]> {
+        kvm::SHIM.bytes()
+    }
+
+    fn check_shim(&self, shim: &Component) -> Result<()> {
+        kvm::check_shim(shim)
+    }
+
+    fn data(&self) -> Vec<Datum> {
+        let mut data = vec![data::dev_sev(), data::dev_sev_writable(), data::firmware()];
+        data.extend(data::CPUIDS.iter().map(|c| c.into()));
+        data.push(data::sev_enabled_in_kernel());
+        data.push(kvm::dev_kvm());
+        data.push(kvm::kvm_version());
+        data
+    }
+
+    fn build(&self, shim: Component, code: Component, config: &Config) -> Result<Arc<dyn Keep>> {
+        // The payload may request vCPUs.
+        let config = &config.requested(&shim, &code)?;
+        let hook = hook(config, false)?;
+        launch(Builder::new(shim, code, hook), config)
+    }
+
+    fn build_empty(&self, shim: Component, size: usize, config: &Config) -> Result<Arc<dyn Keep>> {
+        let hook = hook(config, true)?;
+        launch(Builder::empty(shim, size, hook), config)
+    }
+
+    /// Computes the launch digest, which the measurement is taken over
+    ///
+    /// The digest is the SHA-256 hash of the memory of the VM as it is
+    /// encrypted at launch. The measurement itself is keyed by the session
+    /// of the guest owner, who computes it from the digest.
+    fn measure(&self, shim: Component, code: Component, config: &Config) -> Result<Vec<u8>> {
+        // SEV-ES guests are measured with the state of their vCPUs as well.
+        if config.sev.es {
+            anyhow::bail!("the launch digest of SEV-ES keeps can't be predicted");
+        }
+
+        let config = &config.requested(&shim, &code)?;
+        let image = Builder::new(shim, code, builder::Sev::default())
+            .manifest(config.manifest()?)
+            .image()?;
+
+        Ok(sha256(&image).to_vec())
+    }
+}
+
+/// Checks the configuration and creates the hook which launches the VM
+fn hook(config: &Config, remote_payload: bool) -> Result<builder::Sev> {
+    // The secret must only be released to a measured guest.
+    if config.secret.is_some() {
+        anyhow::bail!("SEV keeps receive their secret from the guest owner (--sev-owner)");
+    }
+
+    if config.snapshot.is_some() {
+        anyhow::bail!("SEV keeps can't be snapshotted, since their memory is encrypted");
+    }
+
+    Ok(builder::Sev {
+        policy: policy::policy(&config.sev, config.debug)?,
+        cache: config.cache,
+        owner: config.owner.clone(),
+        cpus: config.cpus(),
+        remote_payload,
+        ..Default::default()
+    })
+}
+
+/// Builds the VM of a keep
+fn launch(builder: Builder<builder::Sev>, config: &Config) -> Result<Arc<dyn Keep>> {
+    let vm = builder
+        .cpuid(Policy::kvm().with(&config.cpuid))
+        .cpus(config.cpus())
+        .memory(config.memory)
+        .manifest(config.manifest()?)
+        .build::<personality::Sev>()?
+        .vm()?;
+
+    Ok(Arc::new(RwLock::new(vm)))
+}
@@ -0,0 +1,227 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The session with a remote guest owner
+//!
+//! The guest owner is the party which the keep runs for. It checks that the
+//! keep was launched on a genuine platform, with the workload it expects,
+//! before it releases a secret to it. The loader connects to it over TLS and
+//! exchanges CBOR-encoded messages:
+//!
+//!  1. `chain`: the loader sends the firmware build and the certificate chain
+//!     of the platform (PDH, PEK, OCA, CEK, ASK and ARK).
+//!  2. `start`: the guest owner replies with the policy, its Diffie-Hellman
+//!     certificate (GODH) and the session blob, which `LAUNCH_START` takes.
+//!  3. `measurement`: the loader sends the launch measurement.
+//!  4. `verified`: the guest owner replies with the secret (if it has one),
+//!     which `LAUNCH_SECRET` injects; or it replies with `rejected`.
+//!
+//! Keeps launched with an empty code slot (`--code-slot`) receive their
+//! payload in `verified` as well: the image of the slot (the `PT_LOAD`
+//! segments of the payload at their physical addresses), as one packet per
+//! page, which are injected in order. So the host never sees the plaintext.
+//!
+//! Certificates are encoded as the firmware encodes them, and the session
+//! blob, the measurement and the secret header as the structures of the SEV
+//! API specification.
+
+use super::policy;
+use crate::backend::GuestOwner;
+
+use anyhow::{anyhow, bail, Result};
+use codicon::{Decoder, Encoder};
+use openssl::ssl::{SslConnector, SslMethod, SslStream};
+use serde::{Deserialize, Serialize};
+use sev::certs::{sev as platform, Chain};
+use sev::launch::{Measurement, Secret, Start};
+use sev::Build;
+use tracing::debug;
+
+use std::mem::{size_of, size_of_val};
+use std::net::TcpStream;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Message {
+    Chain {
+        build: [u8; 3],
+        chain: Vec<u8>,
+    },
+    Start {
+        policy: u32,
+        godh: Vec<u8>,
+        session: Vec<u8>,
+    },
+    Measurement {
+        measurement: Vec<u8>,
+    },
+    Verified {
+        secret: Option<Packet>,
+        #[serde(default)]
+        payload: Vec<Packet>,
+    },
+    Rejected {
+        reason: String,
+    },
+}
+
+/// The `LAUNCH_SECRET` packet
+#[derive(Debug, Serialize, Deserialize)]
+struct Packet {
+    header: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+/// What the guest owner releases to a keep once it verified it
+pub struct Release {
+    /// The secret of the keep, if it has one
+    pub secret: Option<Secret>,
+
+    /// The pages of the code slot, if it is empty
+    pub payload: Vec<Secret>,
+}
+
+/// A connection to the guest owner
+pub struct Remote {
+    stream: SslStream<TcpStream>,
+}
+
+impl Remote {
+    /// Connects to the guest owner
+    pub fn connect(owner: &GuestOwner) -> Result<Self> {
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        if let Some(ca) = &owner.ca {
+            builder.set_ca_file(ca)?;
+        }
+
+        // The certificate of the guest owner is checked against its host name.
+        let host = match owner.addr.rsplit_once(':') {
+            Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => bail!("the guest owner address has no port: {}", owner.addr),
+        };
+
+        let tcp = TcpStream::connect(&owner.addr)?;
+        let stream = builder
+            .build()
+            .connect(host, tcp)
+            .map_err(|e| anyhow!("unable to connect to the guest owner: {}", e))?;
+
+        debug!(addr = %owner.addr, "connected to the guest owner");
+        Ok(Self { stream })
+    }
+
+    /// Sends the certificate chain and receives the launch parameters
+    pub fn start(&mut self, chain: &Chain, build: Build) -> Result<Start> {
+        let mut encoded = Vec::new();
+        chain
+            .encode(&mut encoded, ())
+            .map_err(|e| anyhow!("unable to encode the certificate chain: {}", e))?;
+
+        self.send(Message::Chain {
+            build: [build.version.0, build.version.1, build.build],
+            chain: encoded,
+        })?;
+
+        match self.receive()? {
+            Message::Start {
+                policy,
+                godh,
+                session,
+            } => Ok(Start {
+                policy: policy::decode(policy)?,
+                cert: platform::Certificate::decode(&mut &godh[..], ())
+                    .map_err(|e| anyhow!("invalid GODH certificate: {}", e))?,
+                session: from_bytes(&session)?,
+            }),
+            message => unexpected(message),
+        }
+    }
+
+    /// Sends the launch measurement and receives what the guest owner
+    /// releases
+    pub fn measured(mut self, measurement: &Measurement) -> Result<Release> {
+        self.send(Message::Measurement {
+            measurement: bytes(measurement),
+        })?;
+
+        match self.receive()? {
+            Message::Verified { secret, payload } => Ok(Release {
+                secret: secret.map(Packet::secret).transpose()?,
+                payload: payload
+                    .into_iter()
+                    .map(Packet::secret)
+                    .collect::<Result<_>>()?,
+            }),
+            message => unexpected(message),
+        }
+    }
+
+    fn send(&mut self, message: Message) -> Result<()> {
+        ciborium::ser::into_writer(&message, &mut self.stream)
+            .map_err(|e| anyhow!("unable to send to the guest owner: {:?}", e))
+    }
+
+    fn receive(&mut self) -> Result<Message> {
+        ciborium::de::from_reader(&mut self.stream)
+            .map_err(|e| anyhow!("unable to receive from the guest owner: {:?}", e))
+    }
+}
+
+impl Packet {
+    fn secret(self) -> Result<Secret> {
+        Ok(Secret {
+            header: from_bytes(&self.header)?,
+            ciphertext: self.ciphertext,
+        })
+    }
+}
+
+fn unexpected<T>(message: Message) -> Result<T> {
+    match message {
+        Message::Rejected { reason } => bail!("the guest owner rejected the keep: {}", reason),
+        message => bail!("unexpected message from the guest owner: {:?}", message),
+    }
+}
+
+/// The bytes of a structure of the SEV API
+fn bytes<T>(value: &T) -> Vec<u8> {
+    let ptr = value as *const T as *const u8;
+    unsafe { std::slice::from_raw_parts(ptr, size_of_val(value)) }.to_vec()
+}
+
+/// Reads a structure of the SEV API
+///
+/// Every bit pattern is a valid structure, since they only hold integers and
+/// byte arrays.
+fn from_bytes<T>(bytes: &[u8]) -> Result<T> {
+    if bytes.len() != size_of::<T>() {
+        bail!(
+            "invalid {} from the guest owner ({} bytes)",
+            std::any::type_name::<T>(),
+            bytes.len()
+        );
+    }
+
+    Ok(unsafe { (bytes.as_ptr() as *const T).read_unaligned() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn structures() {
+        let measurement = Measurement {
+            measure: [1; 32],
+            mnonce: [2; 16],
+        };
+
+        let encoded = bytes(&measurement);
+        assert_eq!(encoded.len(), 48);
+
+        let decoded: Measurement = from_bytes(&encoded).unwrap();
+        assert_eq!(decoded.measure, measurement.measure);
+        assert_eq!(decoded.mnonce, measurement.mnonce);
+
+        assert!(from_bytes::<Measurement>(&encoded[1..]).is_err());
+    }
+}
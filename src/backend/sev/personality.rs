@@ -0,0 +1,40 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use super::launch;
+use crate::backend::kvm::{KvmUserspaceMemoryRegion, Personality};
+
+use anyhow::Result;
+use kvm_bindings::kvm_enc_region;
+use kvm_ioctls::VmFd;
+use sev::firmware::Firmware;
+
+/// Registers the memory added to a running VM as encrypted
+pub struct Sev;
+
+impl Personality for Sev {
+    fn add_memory(vm: &mut VmFd, region: &KvmUserspaceMemoryRegion) {
+        let region = kvm_enc_region {
+            addr: region.userspace_addr,
+            size: region.memory_size,
+        };
+
+        vm.register_enc_memory_region(&region)
+            .expect("unable to register the memory with SEV");
+    }
+
+    /// Unpins the memory, which SEV pins while it is registered
+    fn remove_memory(vm: &mut VmFd, region: &KvmUserspaceMemoryRegion) -> Result<()> {
+        let region = kvm_enc_region {
+            addr: region.userspace_addr,
+            size: region.memory_size,
+        };
+
+        Ok(vm.unregister_enc_memory_region(&region)?)
+    }
+
+    /// Decrypts the memory, which only guests whose policy allows debugging
+    /// permit
+    fn read(vm: &VmFd, src: &[u8], dst: &mut [u8]) -> Result<()> {
+        launch::decrypt(vm, &Firmware::open()?, src, dst)
+    }
+}
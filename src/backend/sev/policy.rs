@@ -0,0 +1,132 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Launch policy
+//!
+//! Without overrides, guests are launched with `Policy::default()`: the host
+//! may debug them, they may share their key with other guests and they run
+//! on any firmware. The firmware enforces the policy for the lifetime of the
+//! guest, and the guest owner checks it when the launch is measured.
+
+use crate::backend::SevParameters;
+
+use anyhow::{anyhow, bail, Result};
+use sev::launch::{Policy, PolicyFlags};
+use sev::Version;
+
+use std::arch::x86_64::__cpuid;
+
+/// Applies the overrides to the default policy
+pub fn policy(config: &SevParameters, debug: bool) -> Result<Policy> {
+    let mut policy = Policy::default();
+
+    if config.no_debug {
+        if debug {
+            bail!("a debug keep cannot forbid debugging (--sev-no-debug)");
+        }
+
+        policy.flags |= PolicyFlags::NO_DEBUG;
+    }
+
+    if config.no_key_sharing {
+        policy.flags |= PolicyFlags::NO_KEY_SHARING;
+    }
+
+    if config.es {
+        policy.flags |= PolicyFlags::ENCRYPTED_STATE;
+    }
+
+    if let Some((major, minor)) = config.min_firmware {
+        policy.minfw = Version(major, minor);
+    }
+
+    Ok(policy)
+}
+
+/// Checks that the platform can launch a guest with the policy
+pub fn check(policy: &Policy, firmware: Version) -> Result<()> {
+    // CPUID Fn8000_001F[EAX] - Bit 3 (SEV-ES)
+    let es = unsafe { __cpuid(0x8000_001f) }.eax & (1 << 3) != 0;
+    if policy.flags.contains(PolicyFlags::ENCRYPTED_STATE) && !es {
+        bail!("SEV-ES is not supported by this CPU");
+    }
+
+    let minfw = &policy.minfw;
+    if (firmware.0, firmware.1) < (minfw.0, minfw.1) {
+        bail!(
+            "the SEV firmware {}.{} is older than the {}.{} required by the policy",
+            firmware.0,
+            firmware.1,
+            minfw.0,
+            minfw.1
+        );
+    }
+
+    Ok(())
+}
+
+/// The policy as the firmware reads it: the flags, then the minimum
+/// firmware version
+pub fn encode(policy: &Policy) -> u32 {
+    let minfw = &policy.minfw;
+    policy.flags.bits() as u32 | (minfw.0 as u32) << 16 | (minfw.1 as u32) << 24
+}
+
+/// Reads a policy as the firmware does
+pub fn decode(bits: u32) -> Result<Policy> {
+    let flags = PolicyFlags::from_bits(bits as u16 as _)
+        .ok_or_else(|| anyhow!("unknown SEV policy flags: {:#x}", bits & 0xffff))?;
+
+    Ok(Policy {
+        flags,
+        minfw: Version((bits >> 16) as u8, (bits >> 24) as u8),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let default = policy(&SevParameters::default(), false).unwrap();
+        assert_eq!(default.flags, Policy::default().flags);
+        assert!(check(&default, Version(0, 0)).is_ok());
+    }
+
+    #[test]
+    fn overrides() {
+        let config = SevParameters {
+            no_debug: true,
+            no_key_sharing: true,
+            min_firmware: Some((0, 24)),
+            ..Default::default()
+        };
+
+        let strict = policy(&config, false).unwrap();
+        assert!(strict.flags.contains(PolicyFlags::NO_DEBUG));
+        assert!(strict.flags.contains(PolicyFlags::NO_KEY_SHARING));
+        assert!(!strict.flags.contains(PolicyFlags::ENCRYPTED_STATE));
+        assert!(check(&strict, Version(0, 24)).is_ok());
+        assert!(check(&strict, Version(0, 23)).is_err());
+        assert!(check(&strict, Version(1, 0)).is_ok());
+
+        assert!(policy(&config, true).is_err());
+    }
+
+    #[test]
+    fn encoding() {
+        let config = SevParameters {
+            no_debug: true,
+            min_firmware: Some((0, 24)),
+            ..Default::default()
+        };
+
+        let policy = policy(&config, false).unwrap();
+        let bits = encode(&policy);
+        assert_eq!(bits, 0x1800_0001);
+
+        let decoded = decode(bits).unwrap();
+        assert_eq!(decoded.flags, policy.flags);
+        assert_eq!((decoded.minfw.0, decoded.minfw.1), (0, 24));
+    }
+}
@@ -0,0 +1,69 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Interrupting enclave threads
+//!
+//! A signal to a thread inside the enclave exits it asynchronously (AEX), and
+//! once the handler returns, the vDSO resumes the enclave with `ERESUME` at
+//! its asynchronous exit pointer (AEP). So that the shim notices that the host
+//! interrupted the thread, the hook turns this `ERESUME` into an `EENTER`:
+//! the shim is entered on the next SSA frame, as for an exception, with
+//! `INTERRUPTED` in `r9`. It checks the doorbell, raising its signal in the
+//! payload, and exits to the host, which returns from the vDSO as if the shim
+//! had handled an exception.
+//!
+//! Only the payload is interrupted this way, once it is resumed at CSSA 0:
+//! the shim exits to the host by itself soon enough, and the SSA frames above
+//! are left for the exceptions which it handles.
+
+use super::enclave::Entry;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Tells the shim that the host interrupted the thread (in `r9`)
+///
+/// No page fault is reported at this address. It must match the one used by
+/// the shim.
+pub const INTERRUPTED: usize = usize::MAX;
+
+/// What the shim is entered with, by the host or by the hook
+pub struct Target {
+    /// The TCS of the thread
+    pub tcs: usize,
+
+    /// The sallyport blocks, which the shim is entered with in `rdi`
+    pub rdi: usize,
+
+    /// The size of the heap, which the shim is entered with in `r8`
+    pub r8: usize,
+
+    /// Whether the hook entered the shim
+    pub entered: AtomicBool,
+}
+
+/// Enters the shim instead of resuming the enclave, if the thread is at the
+/// AEP of the vDSO after an AEX
+///
+/// `target` is the address of the `Target`.
+pub unsafe fn hook(target: usize, context: &mut libc::ucontext_t) {
+    let target = &*(target as *const Target);
+    let gregs = &mut context.uc_mcontext.gregs;
+    let reg = |r: libc::c_int| gregs[r as usize] as usize;
+
+    // An AEX leaves the leaf of `ERESUME` in `rax`, the TCS in `rbx` and the
+    // AEP in `rcx`, from which the thread continues.
+    if reg(libc::REG_RAX) != Entry::Resume as usize
+        || reg(libc::REG_RBX) != target.tcs
+        || reg(libc::REG_RCX) != reg(libc::REG_RIP)
+    {
+        return;
+    }
+
+    if target.entered.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    gregs[libc::REG_RAX as usize] = Entry::Enter as i64;
+    gregs[libc::REG_RDI as usize] = target.rdi as i64;
+    gregs[libc::REG_R8 as usize] = target.r8 as i64;
+    gregs[libc::REG_R9 as usize] = INTERRUPTED as i64;
+}
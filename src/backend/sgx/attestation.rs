@@ -16,6 +16,7 @@ use std::os::unix::net::UnixStream;
 use std::slice::{from_raw_parts, from_raw_parts_mut};
 
 use protobuf::Message;
+use tracing::debug;
 
 const AESM_SOCKET: &str = "/var/run/aesmd/aesm.socket";
 const TIMEOUT: u32 = 1_000_000;
@@ -276,6 +277,7 @@ pub fn get_attestation(
     // TODO: This should be changed to indicate no connection could be made, but to
     // also still run the tests. See https://github.com/enarx/enarx-keepldr/issues/228.
     if UnixStream::connect(AESM_SOCKET).is_err() {
+        debug!("attestation: AESM is unavailable, using dummy data");
         match nonce {
             // Returns dummy TargetInfo
             0 => {
@@ -292,11 +294,13 @@ pub fn get_attestation(
 
     // Returns TargetInfo
     if nonce == 0 {
+        debug!("attestation: requesting the target info");
         let akid = get_ak_id().expect("error obtaining att key id");
         let pkeysize = get_key_size(akid.clone()).expect("error obtaining key size");
         get_ti(akid, pkeysize, out_buf)
     // Returns Quote
     } else {
+        debug!("attestation: requesting a quote");
         let akid = get_ak_id().unwrap();
         let report: &[u8] = unsafe { from_raw_parts(nonce as *const u8, nonce_len) };
         get_quote(report, akid, out_buf)
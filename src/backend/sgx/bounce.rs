@@ -0,0 +1,41 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bounce regions for large I/O
+//!
+//! The shim asks for a bounce region with `SYS_ENARX_BOUNCE`. The region is
+//! a mapping of untrusted memory, which the enclave can access without
+//! leaving it. Large reads and writes then point into the region instead of
+//! being copied through the block, one block at a time.
+
+use anyhow::Result;
+use mmarinus::{perms, Kind, Map};
+use primordial::Page;
+
+/// The largest bounce region
+pub const MAX_SIZE: usize = 64 << 20;
+
+/// A bounce region
+pub struct Bounce(Map<perms::ReadWrite>);
+
+impl Bounce {
+    /// Maps a region of at least `size` bytes
+    pub fn new(size: usize) -> Result<Self> {
+        let size = (size + Page::SIZE - 1) / Page::SIZE * Page::SIZE;
+        let map = Map::map(size)
+            .anywhere()
+            .anonymously()
+            .known::<perms::ReadWrite>(Kind::Private)?;
+
+        Ok(Self(map))
+    }
+
+    /// The size of the region
+    pub fn size(&self) -> usize {
+        self.0.size()
+    }
+
+    /// Handles `SYS_ENARX_BOUNCE`
+    pub fn reply(&self) -> sallyport::Result {
+        Ok([self.0.addr().into(), self.0.size().into()])
+    }
+}
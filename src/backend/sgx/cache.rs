@@ -0,0 +1,196 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A cache of enclave signatures
+//!
+//! Building an SGX keep hashes every page of the enclave and signs the result
+//! with a new 3072-bit RSA key, which takes a while for large payloads. The
+//! signature only depends on the shim, the payload and the settings which are
+//! measured, so it is kept beneath `$XDG_CACHE_HOME/enarx-keepldr/sgx` (or
+//! `~/.cache`) and reused by later builds of the same keep. The pages are
+//! still added to the enclave every time.
+//!
+//! The signatures are also kept in memory, so that a process which builds the
+//! same keep many times, such as a `Pool`, neither signs nor reads it again,
+//! even without a cache directory.
+//!
+//! If an entry is unusable, `EINIT` fails and the keep is not launched. The
+//! entry is then removed, so that the next build signs the keep again;
+//! `--no-cache` ignores the cache altogether.
+
+use crate::backend::{cache_dir, Config};
+
+use openssl::sha::Sha256;
+use sgx::types::sig::Signature;
+use tracing::debug;
+
+use std::collections::BTreeMap;
+use std::fs::{self, DirBuilder};
+use std::mem::{size_of, MaybeUninit};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Changes whenever the format or the meaning of the entries changes
+const FORMAT: &str = "2";
+
+/// The signatures which this process signed or read, by key
+static SIGNATURES: Mutex<BTreeMap<String, Vec<u8>>> = Mutex::new(BTreeMap::new());
+
+/// The entry of one keep in the cache
+pub struct Cache {
+    key: String,
+    path: Option<PathBuf>,
+}
+
+impl Cache {
+    /// Finds the entry of a keep
+    pub fn new(shim: &[u8], code: &[u8], config: &Config) -> Self {
+        let key = key(shim, code, config);
+        let path = cache_dir("sgx").map(|dir| dir.join(&key));
+        Self { key, path }
+    }
+
+    /// Reads the signature of the keep, if it has been stored before
+    pub fn load(&self) -> Option<Signature> {
+        let mut signatures = SIGNATURES.lock().unwrap();
+        let bytes = match signatures.get(&self.key) {
+            Some(bytes) => bytes.clone(),
+            None => {
+                let path = self.path.as_ref()?;
+                let bytes = fs::read(path).ok()?;
+                if bytes.len() != size_of::<Signature>() {
+                    debug!("ignoring {}: invalid size", path.display());
+                    return None;
+                }
+
+                debug!("using the signature in {}", path.display());
+                signatures.insert(self.key.clone(), bytes.clone());
+                bytes
+            }
+        };
+
+        let mut signature = MaybeUninit::<Signature>::uninit();
+        unsafe {
+            let dst = signature.as_mut_ptr().cast::<u8>();
+            dst.copy_from_nonoverlapping(bytes.as_ptr(), bytes.len());
+            Some(signature.assume_init())
+        }
+    }
+
+    /// Stores the signature of the keep
+    ///
+    /// A keep can be launched without the cache, so failures are only logged.
+    pub fn store(&self, signature: &Signature) {
+        let bytes = unsafe {
+            let ptr = (signature as *const Signature).cast::<u8>();
+            std::slice::from_raw_parts(ptr, size_of::<Signature>())
+        };
+
+        let mut signatures = SIGNATURES.lock().unwrap();
+        signatures.insert(self.key.clone(), bytes.to_vec());
+
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return,
+        };
+
+        // Concurrent builds must never see a partial entry.
+        let tmp = path.with_extension(std::process::id().to_string());
+        let result = path.parent().map_or(Ok(()), |dir| {
+            DirBuilder::new().recursive(true).mode(0o700).create(dir)
+        });
+        let result = result
+            .and_then(|_| fs::write(&tmp, bytes))
+            .and_then(|_| fs::rename(&tmp, path));
+
+        if let Err(e) = result {
+            debug!("unable to store {}: {}", path.display(), e);
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+
+    /// Forgets the signature of the keep, e.g. after `EINIT` rejected it
+    pub fn forget(&self) {
+        SIGNATURES.lock().unwrap().remove(&self.key);
+        if let Some(path) = self.path.as_ref() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// The name of the entry: a hash of everything the signature depends on
+fn key(shim: &[u8], code: &[u8], config: &Config) -> String {
+    let settings = format!(
+        "{}:{:?}:{:?}:{:?}:{:?}:{:?}:{}:{}",
+        FORMAT,
+        config.heap_size,
+        config.sgx.xfrm,
+        config.sgx.misc_select,
+        config.sgx.prod_id,
+        config.sgx.svn,
+        config.sgx.lazy_heap,
+        config.debug,
+    );
+
+    let mut hasher = Sha256::new();
+    for part in [settings.as_bytes(), shim, code] {
+        hasher.update(&(part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+
+    hasher
+        .finish()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::SgxParameters;
+
+    #[test]
+    fn keys() {
+        let config = Config::default();
+        let first = key(b"shim", b"code", &config);
+        assert_eq!(first.len(), 64);
+        assert_eq!(key(b"shim", b"code", &config), first);
+
+        // The parts can't be shifted into each other.
+        assert_ne!(key(b"shimc", b"ode", &config), first);
+        assert_ne!(key(b"shim", b"code2", &config), first);
+
+        let sgx = SgxParameters {
+            svn: Some(1),
+            ..Default::default()
+        };
+        let config = Config {
+            sgx,
+            ..Default::default()
+        };
+        assert_ne!(key(b"shim", b"code", &config), first);
+
+        let config = Config {
+            debug: true,
+            ..Default::default()
+        };
+        assert_ne!(key(b"shim", b"code", &config), first);
+    }
+
+    #[test]
+    fn memory() {
+        let cache = Cache {
+            key: "memory".into(),
+            path: None,
+        };
+        assert!(cache.load().is_none());
+
+        let signature = unsafe { MaybeUninit::<Signature>::zeroed().assume_init() };
+        cache.store(&signature);
+        assert!(cache.load().is_some());
+
+        cache.forget();
+        assert!(cache.load().is_none());
+    }
+}
@@ -0,0 +1,195 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A clock page
+//!
+//! The shim asks for the clock page with `SYS_ENARX_CLOCK`. The page is
+//! allocated in untrusted memory, which the enclave can access without
+//! leaving it. A thread of the host writes the time of `CLOCK_MONOTONIC` and
+//! `CLOCK_REALTIME` to the page every `TICK`, so the shim can answer
+//! `clock_gettime()` for those clocks without an exit. The time read from the
+//! page lags behind by at most a `TICK`, plus the delay in scheduling the
+//! thread.
+//!
+//! The thread starts with the keep, but only updates the page once the shim
+//! asked for it.
+//!
+//! The page is a sequence lock: the sequence number is odd while the time is
+//! being written. The layout must match the one used by the shim.
+
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::sync::atomic::{fence, AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+
+/// The interval between updates of the page
+const TICK: Duration = Duration::from_micros(100);
+
+/// The memory shared with the shim
+#[repr(C, align(4096))]
+struct Shared {
+    seq: AtomicU64,
+    monotonic: [AtomicI64; 2],
+    realtime: [AtomicI64; 2],
+}
+
+/// A clock page and the thread which updates it
+pub struct Clock {
+    shared: *mut Shared,
+    stop: Arc<AtomicBool>,
+
+    /// Whether the shim asked for the page
+    started: Arc<AtomicBool>,
+
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Clock {
+    /// Allocates the page and starts the thread, which waits for the shim
+    pub fn new() -> Result<Self> {
+        let shared = unsafe { alloc_zeroed(Layout::new::<Shared>()) }.cast::<Shared>();
+        if shared.is_null() {
+            bail!("unable to allocate the clock page");
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let started = Arc::new(AtomicBool::new(false));
+        let addr = shared as usize;
+        let (flag, start) = (stop.clone(), started.clone());
+        let thread = std::thread::spawn(move || {
+            let shared = unsafe { &*(addr as *const Shared) };
+            while !start.load(Ordering::Acquire) && !flag.load(Ordering::Relaxed) {
+                std::thread::park();
+            }
+
+            while !flag.load(Ordering::Relaxed) {
+                std::thread::sleep(TICK);
+                update(shared);
+            }
+        });
+
+        Ok(Self {
+            shared,
+            stop,
+            started,
+            thread: Some(thread),
+        })
+    }
+
+    /// Handles `SYS_ENARX_CLOCK`
+    pub fn reply(&self) -> sallyport::Result {
+        // The page holds the time before the shim sees it. Only one thread
+        // writes to the page at a time.
+        if !self.started.load(Ordering::Relaxed) {
+            update(unsafe { &*self.shared });
+            self.started.store(true, Ordering::Release);
+            if let Some(thread) = &self.thread {
+                thread.thread().unpark();
+            }
+        }
+
+        Ok([
+            (self.shared as usize).into(),
+            Layout::new::<Shared>().size().into(),
+        ])
+    }
+}
+
+impl Drop for Clock {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+
+        unsafe { dealloc(self.shared.cast(), Layout::new::<Shared>()) };
+    }
+}
+
+/// Writes the current time to the page
+fn update(shared: &Shared) {
+    let now = |clock| {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+
+        unsafe { libc::clock_gettime(clock, &mut ts) };
+        [ts.tv_sec, ts.tv_nsec]
+    };
+
+    let (monotonic, realtime) = (now(libc::CLOCK_MONOTONIC), now(libc::CLOCK_REALTIME));
+
+    let seq = shared.seq.load(Ordering::Relaxed);
+    shared.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+    fence(Ordering::Release);
+
+    for (dst, src) in shared.monotonic.iter().zip(&monotonic) {
+        dst.store(*src, Ordering::Relaxed);
+    }
+    for (dst, src) in shared.realtime.iter().zip(&realtime) {
+        dst.store(*src, Ordering::Relaxed);
+    }
+
+    shared.seq.store(seq.wrapping_add(2), Ordering::Release);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads the page as the shim would
+    fn read(shared: &Shared) -> [i64; 4] {
+        loop {
+            let seq = shared.seq.load(Ordering::Acquire);
+            let time = [
+                shared.monotonic[0].load(Ordering::Relaxed),
+                shared.monotonic[1].load(Ordering::Relaxed),
+                shared.realtime[0].load(Ordering::Relaxed),
+                shared.realtime[1].load(Ordering::Relaxed),
+            ];
+
+            fence(Ordering::Acquire);
+            if seq & 1 == 0 && shared.seq.load(Ordering::Relaxed) == seq {
+                return time;
+            }
+        }
+    }
+
+    #[test]
+    fn idle() {
+        let clock = Clock::new().unwrap();
+        let shared = unsafe { &*clock.shared };
+
+        // Nothing is written until the shim asks for the page.
+        std::thread::sleep(TICK * 5);
+        assert_eq!(shared.seq.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn tick() {
+        let clock = Clock::new().unwrap();
+        let shared = unsafe { &*clock.shared };
+
+        let [addr, size] = clock.reply().unwrap();
+        assert_eq!(usize::from(addr), clock.shared as usize);
+        assert_eq!(usize::from(size), 4096);
+
+        let first = read(shared);
+        assert!(first[0] > 0 && first[2] > 0);
+
+        std::thread::sleep(TICK * 20);
+        let second = read(shared);
+        assert!((second[0], second[1]) > (first[0], first[1]));
+
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut ts) };
+        assert!((ts.tv_sec - second[2]).abs() <= 1);
+    }
+}
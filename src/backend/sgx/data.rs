@@ -3,6 +3,7 @@
 use crate::backend::probe::x86_64::{CpuId, Vendor};
 use crate::backend::Datum;
 
+use anyhow::anyhow;
 use sgx::types::{
     attr::{Flags, Xfrm},
     misc::MiscSelect,
@@ -11,8 +12,17 @@ use sgx::types::{
 use std::arch::x86_64::__cpuid_count;
 use std::fs::File;
 use std::mem::transmute;
+use std::os::unix::fs::FileExt;
 use std::str::from_utf8;
 
+/// `IA32_FEATURE_CONTROL`, whose `SGX_LC` bit lets the kernel choose the key
+/// of the enclaves it launches
+const FEATURE_CONTROL: u64 = 0x3a;
+const SGX_LC: u64 = 1 << 17;
+
+/// The first of the four `IA32_SGXLEPUBKEYHASH` MSRs
+const LE_PUBKEY_HASH: u64 = 0x8c;
+
 fn humanize(mut size: f64) -> (f64, &'static str) {
     let mut iter = 0;
 
@@ -37,6 +47,12 @@ fn humanize(mut size: f64) -> (f64, &'static str) {
     (size, suffix)
 }
 
+/// Formats a number of bytes for humans
+pub fn size(bytes: u64) -> String {
+    let (n, s) = humanize(bytes as f64);
+    format!("{:.0} {}", n, s)
+}
+
 pub const CPUIDS: &[CpuId] = &[
     CpuId {
         name: "CPU Manufacturer",
@@ -131,37 +147,74 @@ pub const CPUIDS: &[CpuId] = &[
     },
 ];
 
-pub fn epc_size(max: u32) -> Datum {
-    let mut pass = false;
-    let mut info = None;
-
-    if max >= 0x00000012 {
-        let mut size = 0;
+/// The size of the EPC in bytes, if the CPU reports it
+pub fn epc(max: u32) -> Option<u64> {
+    if max < 0x00000012 {
+        return None;
+    }
 
-        for i in 2.. {
-            let result = unsafe { __cpuid_count(0x00000012, i) };
-            if result.eax & 0xf != 1 {
-                break;
-            }
+    let mut size = 0;
 
-            let low = result.ecx as u64 & 0xfffff000;
-            let high = result.edx as u64 & 0x000fffff;
-            size += high << 12 | low;
+    for i in 2.. {
+        let result = unsafe { __cpuid_count(0x00000012, i) };
+        if result.eax & 0xf != 1 {
+            break;
         }
 
-        let (n, s) = humanize(size as f64);
-        info = Some(format!("{:.0} {}", n, s));
-        pass = true;
+        let low = result.ecx as u64 & 0xfffff000;
+        let high = result.edx as u64 & 0x000fffff;
+        size += high << 12 | low;
     }
 
+    Some(size)
+}
+
+pub fn epc_size(max: u32) -> Datum {
+    let info = epc(max).map(size);
+
     Datum {
         name: "  EPC Size".into(),
         mesg: None,
-        pass,
+        pass: info.is_some(),
         info,
     }
 }
 
+/// The EPC which a keep needs
+///
+/// The `eager` bytes are added when the keep is built, and up to `lazy` more
+/// bytes when the pages of a lazy heap are first touched. Only the former
+/// must fit in the EPC for the keep to launch without `--sgx-overcommit`.
+pub fn footprint(eager: u64, lazy: u64, epc: Option<u64>) -> Datum {
+    let mut info = size(eager);
+    if lazy > 0 {
+        info += &format!(" + up to {} lazily", size(lazy));
+    }
+
+    let fits = |bytes| epc.map_or(true, |epc| bytes <= epc);
+    let mesg = match (fits(eager), fits(eager + lazy)) {
+        (true, true) => None,
+        (true, false) => Some(
+            "The lazy heap can outgrow the EPC, at which point the kernel swaps \
+             its pages out, which slows the keep down."
+                .into(),
+        ),
+        (false, _) => Some(
+            "The keep does not fit in the EPC. Use a smaller `--heap-size`, add \
+             the heap lazily with `--sgx-lazy-heap`, or accept the slowdown of \
+             paging with `--sgx-overcommit`."
+                .into(),
+        ),
+    };
+
+    Datum {
+        name: "  Footprint".into(),
+        pass: fits(eager),
+        info: Some(info),
+        mesg,
+    }
+}
+
 pub fn dev_sgx_enclave() -> Datum {
     let mut pass = false;
 
@@ -176,3 +229,82 @@ pub fn dev_sgx_enclave() -> Datum {
         mesg: None,
     }
 }
+
+/// Reads an MSR of the first CPU, which requires root and the `msr` module
+fn msr(index: u64) -> std::io::Result<u64> {
+    let mut bytes = [0; 8];
+    File::open("/dev/cpu/0/msr")?.read_exact_at(&mut bytes, index)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// The hash of the key which the launch control allows, in hexadecimal
+fn le_pubkey_hash() -> std::io::Result<String> {
+    let mut hash = String::new();
+    for i in 0..4 {
+        for b in msr(LE_PUBKEY_HASH + i)?.to_le_bytes() {
+            hash += &format!("{:02x}", b);
+        }
+    }
+
+    Ok(hash)
+}
+
+pub fn launch_control() -> Datum {
+    let (pass, info, mesg) = match msr(FEATURE_CONTROL) {
+        Ok(fc) if fc & SGX_LC != 0 => (true, "flexible".into(), None),
+        Ok(_) => (
+            false,
+            match le_pubkey_hash() {
+                Ok(hash) => format!("locked to {}", hash),
+                Err(_) => "locked".into(),
+            },
+            Some(
+                "The firmware locked the launch control, so only enclaves signed \
+                 with the key it allows can launch, which keeps are not. Enable \
+                 Flexible Launch Control in the BIOS setup."
+                    .into(),
+            ),
+        ),
+        Err(e) => (
+            false,
+            format!("unknown ({})", e),
+            Some("Inspecting the launch control requires root and the `msr` module.".into()),
+        ),
+    };
+
+    Datum {
+        name: "  Launch Control".into(),
+        pass,
+        info: Some(info),
+        mesg,
+    }
+}
+
+/// Explains why `EINIT` rejected an enclave
+///
+/// The kernel reports all the reasons of the platform as `EPERM`, so the
+/// launch control and the origin of the signature narrow them down.
+pub fn einit(err: std::io::Error, cached: bool) -> anyhow::Error {
+    let reason = match err.raw_os_error() {
+        Some(libc::EPERM) => match msr(FEATURE_CONTROL) {
+            Ok(fc) if fc & SGX_LC == 0 => {
+                "the firmware locked the launch control to another key: \
+                 enable Flexible Launch Control in the BIOS setup"
+            }
+            _ if cached => "the cached signature was rejected and removed: try again",
+            _ => {
+                "the platform rejected the attributes, XFRM or MISCSELECT of the \
+                 keep: compare them with `enarx-keepldr info`"
+            }
+        },
+        Some(libc::EACCES) => {
+            "the attributes request a key which this process may not use, such as \
+             the provisioning key"
+        }
+        Some(libc::EINVAL) => "the attributes or MISCSELECT set bits which the kernel reserves",
+        Some(libc::EIO) => "the EPC was lost, e.g. because the machine was suspended: try again",
+        _ => return anyhow!("unable to initialize the enclave: {}", err),
+    };
+
+    anyhow!("unable to initialize the enclave ({}): {}", err, reason)
+}
@@ -1,6 +1,6 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use super::{ioctls, Enclave};
+use super::{ioctls, Enclave, Gate};
 
 use lset::Span;
 use mmarinus::{perms, Kind, Map};
@@ -13,18 +13,20 @@ use std::fs::{File, OpenOptions};
 use std::io::{Error, Result};
 use std::mem::forget;
 use std::num::NonZeroU32;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
 /// A structs which assists in enclave creation
 ///
 /// 1. Instantiate the `Builder` using `Builder::new()` or `Builder::new_at()`.
 /// 2. Add pages to the enclave using `Builder::load()` (see the `Loader` trait).
-/// 3. Finalize the enclave contents using `Builder::build()`.
+/// 3. Initialize the enclave with its signature using `Builder::init()`.
+/// 4. Finalize the enclave contents using `Builder::build()`.
 pub struct Builder {
     file: File,
     mmap: Map<perms::Unknown>,
     perm: Vec<(Span<usize>, SecInfo)>,
     tcsp: Vec<usize>,
+    edmm: Span<usize>,
 }
 
 impl Builder {
@@ -71,6 +73,7 @@ impl Builder {
             mmap: mmap.into(), // Discard typed permissions
             perm: Vec::new(),
             tcsp: Vec::new(),
+            edmm: Span { start: 0, count: 0 },
         })
     }
 
@@ -103,17 +106,31 @@ impl Builder {
         Self::new_at(l, ssa_frame_pages, parameters)
     }
 
-    /// Finalizes the SGX enclave
+    /// Reserves pages at an offset of the enclave for dynamic memory (SGX2)
+    ///
+    /// The enclave only has pages added, restricted, trimmed and removed
+    /// inside this region once it runs.
+    pub fn reserve(&mut self, span: Span<usize>) {
+        self.edmm = span;
+    }
+
+    /// Initializes the SGX enclave
     ///
-    /// This function finalizes the SGX enclave and prepares it for execution.
+    /// The platform checks the signature against the measurement of the
+    /// pages which were added, and against what it allows to launch.
     ///
     /// For those familiar with the Intel documentation, this function wraps
     /// the call to the kernel to issue the `EINIT` instruction.
-    pub fn build(mut self, signature: &Signature) -> Result<Arc<Enclave>> {
-        // Initialize the enclave.
+    pub fn init(&mut self, signature: &Signature) -> Result<()> {
         let init = ioctls::Init::new(signature);
         ioctls::ENCLAVE_INIT.ioctl(&mut self.file, &init)?;
+        Ok(())
+    }
 
+    /// Finalizes the SGX enclave
+    ///
+    /// This function prepares the initialized SGX enclave for execution.
+    pub fn build(mut self) -> Result<Arc<Enclave>> {
         // Fix up mapped permissions.
         self.perm.sort_by(|l, r| l.0.start.cmp(&r.0.start));
         for (span, si) in self.perm {
@@ -152,7 +169,17 @@ impl Builder {
         }
 
         Ok(Arc::new(Enclave {
-            _mem: self.mmap,
+            file: Mutex::new(Some(self.file)),
+            span: Span {
+                start: self.mmap.addr(),
+                count: self.mmap.size(),
+            },
+            edmm: Span {
+                start: self.mmap.addr() + self.edmm.start,
+                count: self.edmm.count,
+            },
+            mem: Mutex::new(Some(self.mmap)),
+            gate: Gate::default(),
             tcs: RwLock::new(self.tcsp),
         }))
     }
@@ -0,0 +1,72 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reading the memory of debug enclaves
+//!
+//! The host can't read enclave memory through its mappings. For debug
+//! enclaves, the kernel reads it with `EDBGRD` (see Section 40.3) on behalf
+//! of `/proc/PID/mem`, so that is where these methods read it. Pages which
+//! were never added, such as those of a lazy heap which the enclave hasn't
+//! touched, can't be read.
+
+use super::Enclave;
+
+use lset::Line;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Result};
+use std::os::unix::fs::FileExt;
+
+/// A mapping of the enclave
+#[derive(Copy, Clone, Debug)]
+pub struct Mapping {
+    /// The addresses of the mapping
+    pub line: Line<usize>,
+
+    /// Whether the mapping is writable
+    pub write: bool,
+
+    /// Whether the mapping is executable
+    pub exec: bool,
+}
+
+impl Enclave {
+    /// Reads memory of a debug enclave at an absolute address
+    pub fn read(&self, addr: usize, buf: &mut [u8]) -> Result<()> {
+        File::open("/proc/self/mem")?.read_exact_at(buf, addr as u64)
+    }
+
+    /// Lists the readable mappings of the enclave
+    pub fn mappings(&self) -> Result<Vec<Mapping>> {
+        let start = self.span.start;
+        let end = start + self.span.count;
+
+        let mut mappings = Vec::new();
+        for line in BufReader::new(File::open("/proc/self/maps")?).lines() {
+            // e.g. "7f0000000000-7f0000200000 r-xs 00000000 00:05 1234 /dev/sgx_enclave"
+            let line = line?;
+            let mut fields = line.split_whitespace();
+            let (range, perms) = match (fields.next(), fields.next()) {
+                (Some(range), Some(perms)) => (range, perms.as_bytes()),
+                _ => continue,
+            };
+
+            let mut bounds = range.split('-').map(|n| usize::from_str_radix(n, 16));
+            let line = match (bounds.next(), bounds.next()) {
+                (Some(Ok(start)), Some(Ok(end))) => Line { start, end },
+                _ => continue,
+            };
+
+            if line.start < start || line.end > end || perms.first() != Some(&b'r') {
+                continue;
+            }
+
+            mappings.push(Mapping {
+                line,
+                write: perms.get(1) == Some(&b'w'),
+                exec: perms.get(2) == Some(&b'x'),
+            });
+        }
+
+        Ok(mappings)
+    }
+}
@@ -0,0 +1,200 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Enclave Dynamic Memory Management (SGX2)
+//!
+//! These methods implement the host half of the SGX2 page operations. Each
+//! one leaves the affected pages in a pending state which the enclave must
+//! resolve using `EACCEPT` (see Section 38.5.7). The enclave only has pages
+//! changed inside the region which its shim reserved for them
+//! (`PT_ENARX_EDMM`), never those which were loaded when it was built.
+
+use super::{ioctls, Enclave};
+
+use lset::Span;
+use mmarinus::{perms, Kind, Map};
+use primordial::Page;
+
+use std::io::{Error, ErrorKind, Result};
+use std::mem::forget;
+
+/// SECINFO permission bits (see Table 38-19)
+const SECINFO_R: u64 = 1 << 0;
+const SECINFO_W: u64 = 1 << 1;
+const SECINFO_X: u64 = 1 << 2;
+
+impl Enclave {
+    /// Validates an absolute range of enclave pages
+    ///
+    /// Returns the offset of the range from the start of the enclave.
+    fn offset(&self, span: Span<usize>) -> Result<usize> {
+        let start = self.span.start;
+        let end = start + self.span.count;
+
+        if span.count == 0
+            || span.start % Page::SIZE != 0
+            || span.count % Page::SIZE != 0
+            || span.start < start
+            || span.start.checked_add(span.count).map_or(true, |e| e > end)
+        {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        Ok(span.start - start)
+    }
+
+    /// Validates an absolute range of pages of the dynamic region
+    ///
+    /// Returns the offset of the range from the start of the enclave.
+    fn dynamic(&self, span: Span<usize>) -> Result<usize> {
+        let end = self.edmm.start + self.edmm.count;
+
+        if span.start < self.edmm.start
+            || span.start.checked_add(span.count).map_or(true, |e| e > end)
+        {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        self.offset(span)
+    }
+
+    /// Maps new pages into the dynamic region of an initialized enclave
+    ///
+    /// The kernel adds (`EAUG`) each page lazily when it is first touched,
+    /// which will typically be the enclave executing `EACCEPT` on it. New
+    /// pages are readable and writable.
+    pub fn augment(&self, span: Span<usize>) -> Result<()> {
+        self.dynamic(span)?;
+        self.map_pending(span)
+    }
+
+    /// Maps new pages anywhere into an initialized enclave
+    ///
+    /// Like `augment()`, but for pages which the loader reserved outside of
+    /// the dynamic region, such as those of a lazy heap.
+    pub fn augment_lazily(&self, span: Span<usize>) -> Result<()> {
+        self.offset(span)?;
+        self.map_pending(span)
+    }
+
+    /// Maps the enclave onto `span`, so that the kernel adds pages to it
+    fn map_pending(&self, span: Span<usize>) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let file = file.as_mut().ok_or_else(destroyed)?;
+        forget(unsafe {
+            Map::map(span.count)
+                .onto(span.start)
+                .from(&mut *file, 0)
+                .known::<perms::ReadWrite>(Kind::Shared)?
+        });
+
+        Ok(())
+    }
+
+    /// Changes the permissions of a range of enclave pages
+    ///
+    /// The EPCM permissions are restricted (`EMODPR`) to `prot` and the page
+    /// tables are updated to match. Since `EMODPR` can only remove permissions,
+    /// any extension must already have been performed by the enclave using
+    /// `EMODPE`.
+    pub fn restrict(&self, span: Span<usize>, prot: libc::c_int) -> Result<()> {
+        let offset = self.dynamic(span)?;
+
+        let mut perms = 0;
+        for (input, output) in [
+            (libc::PROT_READ, SECINFO_R),
+            (libc::PROT_WRITE, SECINFO_W),
+            (libc::PROT_EXEC, SECINFO_X),
+        ] {
+            if prot & input == input {
+                perms |= output;
+            }
+        }
+
+        let mut file = self.file.lock().unwrap();
+        let file = file.as_mut().ok_or_else(destroyed)?;
+        let mut done = 0;
+        while done < span.count {
+            let mut rp = ioctls::RestrictPermissions::new(offset + done, span.count - done, perms);
+            let ret = ioctls::ENCLAVE_RESTRICT_PERMISSIONS.ioctl(&mut *file, &mut rp);
+            done += rp.count() as usize;
+
+            match ret {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.raw_os_error() == Some(libc::EAGAIN) => continue,
+                Err(e) => return Err(e),
+                Ok(_) => (),
+            }
+        }
+
+        match unsafe { libc::mprotect(span.start as _, span.count, prot) } {
+            0 => Ok(()),
+            _ => Err(Error::last_os_error()),
+        }
+    }
+
+    /// Marks a range of enclave pages for removal
+    ///
+    /// For those familiar with the Intel documentation, this function wraps
+    /// the call to the kernel to issue the `EMODT` instruction with the
+    /// `PT_TRIM` page type.
+    pub fn trim(&self, span: Span<usize>) -> Result<()> {
+        let offset = self.dynamic(span)?;
+
+        let mut file = self.file.lock().unwrap();
+        let file = file.as_mut().ok_or_else(destroyed)?;
+        let mut done = 0;
+        while done < span.count {
+            let mut mt = ioctls::ModifyTypes::trim(offset + done, span.count - done);
+            let ret = ioctls::ENCLAVE_MODIFY_TYPES.ioctl(&mut *file, &mut mt);
+            done += mt.count() as usize;
+
+            match ret {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.raw_os_error() == Some(libc::EAGAIN) => continue,
+                Err(e) => return Err(e),
+                Ok(_) => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes a range of trimmed pages from the enclave
+    ///
+    /// The enclave must have accepted the trimmed pages before calling this.
+    /// Afterwards, the address range is returned to its initial inaccessible
+    /// state so that it may be augmented again later.
+    pub fn remove(&self, span: Span<usize>) -> Result<()> {
+        let offset = self.dynamic(span)?;
+
+        let mut file = self.file.lock().unwrap();
+        let file = file.as_mut().ok_or_else(destroyed)?;
+        let mut done = 0;
+        while done < span.count {
+            let mut rp = ioctls::RemovePages::new(offset + done, span.count - done);
+            let ret = ioctls::ENCLAVE_REMOVE_PAGES.ioctl(&mut *file, &mut rp);
+            done += rp.count() as usize;
+
+            match ret {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.raw_os_error() == Some(libc::EAGAIN) => continue,
+                Err(e) => return Err(e),
+                Ok(_) => (),
+            }
+        }
+
+        forget(unsafe {
+            Map::map(span.count)
+                .onto(span.start)
+                .anonymously()
+                .known::<perms::None>(Kind::Private)?
+        });
+
+        Ok(())
+    }
+}
+
+/// The error of operations on an enclave which was destroyed
+fn destroyed() -> Error {
+    Error::from_raw_os_error(libc::EBADF)
+}
@@ -1,11 +1,21 @@
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::backend::interrupt::Interrupt;
+
 use primordial::{Address, Register};
 
 pub use x86_64::InterruptVector;
 
 use super::Thread;
 
+thread_local! {
+    /// Lets `Enclave::destroy()` pull the calling thread out of the enclave
+    ///
+    /// The handler of the interrupts is installed before any thread enters a
+    /// keep (see `KeepBuilder`).
+    static INTERRUPT: Interrupt = Interrupt::current().expect("interrupts are not handled");
+}
+
 /// How to enter an enclave
 #[repr(u32)]
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -53,7 +63,7 @@ pub struct Registers {
 // See: https://github.com/torvalds/linux/blob/84292fffc2468125632a21c09533a89426ea212e/arch/x86/include/uapi/asm/sgx.h#L112
 #[repr(C)]
 #[derive(Default, Debug)]
-struct Run {
+pub(super) struct Run {
     tcs: Register<u64>,
     function: u32,
     exception_vector: u16,
@@ -64,6 +74,17 @@ struct Run {
     reserved: [u64; 27],
 }
 
+impl Run {
+    /// Sets up the calls of the vDSO for the thread with this TCS
+    pub(super) fn new(tcs: usize) -> Self {
+        Self {
+            tcs: tcs.into(),
+            user_handler: (handler as usize).into(),
+            ..Default::default()
+        }
+    }
+}
+
 // This function signature is dictated by the Linux kernel.
 //
 // See: https://github.com/torvalds/linux/blob/84292fffc2468125632a21c09533a89426ea212e/arch/x86/include/uapi/asm/sgx.h#L92
@@ -94,14 +115,22 @@ impl Thread {
     /// variable contains the registers returned from the enclave. Otherwise,
     /// an asynchronous exit (AEX) has occurred and the details about the
     /// exception are returned.
+    ///
+    /// `None` is returned without entering if the enclave was destroyed.
     #[inline(always)]
-    pub fn enter(&mut self, how: Entry, registers: &mut Registers) -> Result<(), ExceptionInfo> {
-        let mut run = Run {
-            tcs: self.tcs.into(),
-            user_handler: (handler as usize).into(),
-            user_data: registers.into(),
-            ..Default::default()
-        };
+    pub fn enter(
+        &mut self,
+        how: Entry,
+        registers: &mut Registers,
+    ) -> Option<Result<(), ExceptionInfo>> {
+        // The enclave stays mapped until the thread left it.
+        let interrupt = INTERRUPT.with(Clone::clone);
+        let _entered = self.enc.gate.enter(self.tcs, &interrupt)?;
+        let _inside = interrupt.enter();
+
+        // The vDSO only writes the fields which it reports the exit in, and
+        // the reserved ones must stay zero, so only the registers are set.
+        self.run.user_data = registers.into();
 
         // The `enclu` instruction consumes `rax`, `rbx` and `rcx`. However,
         // the vDSO function preserves `rbx` AND sets `rax` as the return
@@ -133,7 +162,7 @@ impl Thread {
                 inout("rcx") how as u32 => _,
                 inout("r8") usize::from(registers.r8) => _,
                 inout("r9") usize::from(registers.r9) => _,
-                inout("r10") &mut run => _,
+                inout("r10") &mut self.run => _,
                 inout("r11") self.fnc => _,
                 lateout("r12") _,
                 lateout("r13") _,
@@ -143,17 +172,18 @@ impl Thread {
             );
         }
 
+        let run = &self.run;
         match (rax, run.function) {
-            (0, 4) => return Ok(()),
+            (0, 4) => return Some(Ok(())),
             (0, 2) | (0, 3) => (),
             _ => unreachable!(),
         }
 
-        Err(ExceptionInfo {
+        Some(Err(ExceptionInfo {
             trap: unsafe { core::mem::transmute(run.exception_vector as u8) },
             code: run.exception_error_code,
             addr: run.exception_addr,
             last: unsafe { core::mem::transmute(run.function) },
-        })
+        }))
     }
 }
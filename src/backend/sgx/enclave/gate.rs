@@ -0,0 +1,151 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Keeping an enclave mapped while its threads are inside it
+//!
+//! Unmapping an enclave while a thread is inside it faults the thread, and
+//! with it the loader. So threads pass a `Gate` to enter the enclave, which
+//! `Enclave::destroy()` closes: it turns away the threads which enter next,
+//! interrupts those which are inside (see `backend::interrupt`) and waits
+//! until they left, so that the enclave is unmapped once nobody uses it.
+//!
+//! A thread is listed as inside before it checks whether the gate is closed,
+//! so that the gate either interrupts it or turns it away.
+
+use crate::backend::interrupt::Interrupt;
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Lets threads into an enclave until it is destroyed
+#[derive(Default)]
+pub struct Gate {
+    /// Held for reading by the threads inside, and for writing once closed
+    lock: RwLock<()>,
+
+    /// Whether the gate was closed
+    closed: AtomicBool,
+
+    /// The threads inside, by the address of their TCS
+    inside: Mutex<BTreeMap<usize, Interrupt>>,
+}
+
+impl Gate {
+    /// Lets the thread of `tcs` in, until the guard is dropped
+    ///
+    /// Returns `None` if the gate was closed. `interrupt` must interrupt the
+    /// calling thread, which is noted inside it meanwhile.
+    pub fn enter(&self, tcs: usize, interrupt: &Interrupt) -> Option<Entered<'_>> {
+        let lock = self.lock.read().unwrap();
+        self.inside.lock().unwrap().insert(tcs, interrupt.clone());
+
+        let entered = Entered {
+            gate: self,
+            tcs,
+            _lock: lock,
+        };
+
+        match self.closed.load(Ordering::SeqCst) {
+            true => None,
+            false => Some(entered),
+        }
+    }
+
+    /// Closes the gate once all threads left
+    ///
+    /// The threads inside are interrupted. Returns `None` if the gate was
+    /// closed before, or else a guard which keeps the threads out for as long
+    /// as the enclave is torn down.
+    pub fn close(&self) -> Option<RwLockWriteGuard<'_, ()>> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+
+        // The threads take the lock of the list when they leave.
+        let inside = self
+            .inside
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for interrupt in inside {
+            interrupt.interrupt();
+        }
+
+        Some(self.lock.write().unwrap())
+    }
+
+    /// Whether the gate was closed
+    pub fn closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+/// A thread inside the enclave (see `Gate::enter()`)
+pub struct Entered<'a> {
+    gate: &'a Gate,
+    tcs: usize,
+    _lock: RwLockReadGuard<'a, ()>,
+}
+
+impl Drop for Entered<'_> {
+    fn drop(&mut self) {
+        self.gate.inside.lock().unwrap().remove(&self.tcs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{mpsc, Arc};
+
+    #[test]
+    fn closed() {
+        let gate = Gate::default();
+        let interrupt = Interrupt::current().unwrap();
+
+        drop(gate.enter(0x1000, &interrupt).unwrap());
+        assert!(gate.inside.lock().unwrap().is_empty());
+        assert!(!gate.closed());
+
+        drop(gate.close().unwrap());
+        assert!(gate.closed());
+        assert!(gate.close().is_none());
+
+        // The threads which are turned away aren't listed either.
+        assert!(gate.enter(0x1000, &interrupt).is_none());
+        assert!(gate.inside.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn waits() {
+        let gate = Arc::new(Gate::default());
+        let left = Arc::new(AtomicBool::new(false));
+
+        let (tx, rx) = mpsc::channel();
+        let thread = {
+            let (gate, left) = (gate.clone(), left.clone());
+            std::thread::spawn(move || {
+                let interrupt = Interrupt::current().unwrap();
+                let entered = gate.enter(0x1000, &interrupt).unwrap();
+                let inside = interrupt.enter();
+                tx.send(()).unwrap();
+
+                // Stays "inside the enclave" until it is interrupted.
+                unsafe { libc::pause() };
+                left.store(true, Ordering::SeqCst);
+                drop(inside);
+                drop(entered);
+            })
+        };
+
+        rx.recv().unwrap();
+        let closed = gate.close().unwrap();
+        assert!(left.load(Ordering::SeqCst));
+        drop(closed);
+
+        thread.join().unwrap();
+    }
+}
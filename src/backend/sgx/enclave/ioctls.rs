@@ -25,6 +25,19 @@ pub const ENCLAVE_INIT: Ioctl<Write, &Init> = unsafe { SGX.write(0x02) };
 
 //pub const ENCLAVE_SET_ATTRIBUTE: Ioctl<Write, &SetAttribute> = unsafe { SGX.write(0x03) };
 
+/// IOCTL identifier for EMODPR (see Section 41-59)
+pub const ENCLAVE_RESTRICT_PERMISSIONS: Ioctl<WriteRead, &RestrictPermissions> =
+    unsafe { SGX.write_read(0x05) };
+
+/// IOCTL identifier for EMODT (see Section 41-61)
+pub const ENCLAVE_MODIFY_TYPES: Ioctl<WriteRead, &ModifyTypes> = unsafe { SGX.write_read(0x06) };
+
+/// IOCTL identifier for EREMOVE of trimmed pages (see Section 40-41)
+pub const ENCLAVE_REMOVE_PAGES: Ioctl<WriteRead, &RemovePages> = unsafe { SGX.write_read(0x07) };
+
+/// The page type of a trimmed page (see Section 34-7)
+const PAGE_TYPE_TRIM: u64 = 4;
+
 #[repr(C)]
 #[derive(Debug)]
 /// Struct for creating a new enclave from SECS
@@ -112,3 +125,88 @@ impl<'a> SetAttribute<'a> {
         SetAttribute(fd.as_raw_fd() as _, PhantomData)
     }
 }
+
+#[repr(C)]
+#[derive(Debug)]
+/// Struct for restricting the EPCM permissions of enclave pages (SGX2)
+pub struct RestrictPermissions {
+    offset: u64,
+    length: u64,
+    permissions: u64,
+    result: u64,
+    count: u64,
+}
+
+impl RestrictPermissions {
+    /// Creates a new RestrictPermissions struct for a range of pages
+    ///
+    /// The `permissions` are the SECINFO `R`, `W` and `X` bits.
+    pub fn new(offset: usize, length: usize, permissions: u64) -> Self {
+        Self {
+            offset: offset as _,
+            length: length as _,
+            permissions,
+            result: 0,
+            count: 0,
+        }
+    }
+
+    /// The number of bytes successfully processed by the kernel
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+/// Struct for changing the type of enclave pages (SGX2)
+pub struct ModifyTypes {
+    offset: u64,
+    length: u64,
+    page_type: u64,
+    result: u64,
+    count: u64,
+}
+
+impl ModifyTypes {
+    /// Creates a new ModifyTypes struct which trims a range of pages
+    pub fn trim(offset: usize, length: usize) -> Self {
+        Self {
+            offset: offset as _,
+            length: length as _,
+            page_type: PAGE_TYPE_TRIM,
+            result: 0,
+            count: 0,
+        }
+    }
+
+    /// The number of bytes successfully processed by the kernel
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+/// Struct for removing trimmed pages from an enclave (SGX2)
+pub struct RemovePages {
+    offset: u64,
+    length: u64,
+    count: u64,
+}
+
+impl RemovePages {
+    /// Creates a new RemovePages struct for a range of trimmed pages
+    pub fn new(offset: usize, length: usize) -> Self {
+        Self {
+            offset: offset as _,
+            length: length as _,
+            count: 0,
+        }
+    }
+
+    /// The number of bytes successfully processed by the kernel
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
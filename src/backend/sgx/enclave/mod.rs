@@ -33,6 +33,28 @@
 //! passing the specified registers. When the enclave returns, you can read
 //! the register state from the same structure.
 //!
+//! # Dynamic Memory (SGX2)
+//!
+//! On platforms with SGX2, an initialized `Enclave` can grow and shrink.
+//! The host issues the privileged half of each operation (`EAUG`, `EMODPR`,
+//! `EMODT` and `EREMOVE`) using `Enclave::augment()`, `Enclave::restrict()`,
+//! `Enclave::trim()` and `Enclave::remove()`. None of these take effect
+//! until the enclave has executed `EACCEPT` on the affected pages.
+//!
+//! # Destroying an Enclave
+//!
+//! An `Enclave` is destroyed when it is dropped, once all of its threads are.
+//! `Enclave::destroy()` does it at once: it unmaps the enclave and closes its
+//! file, so that the kernel removes its pages (`EREMOVE`) and the EPC is free
+//! for other enclaves. The threads which are inside the enclave are
+//! interrupted, and it is only unmapped once they left it (see `gate`). They
+//! can't enter it afterwards.
+//!
+//! # Debug Enclaves
+//!
+//! The memory of a debug enclave can be read using `Enclave::read()`, e.g.
+//! to find the state which an asynchronous exit left in the SSA frames.
+//!
 //! # Additional Information
 //!
 //! The Intel SGX documentation is available [here]. Section references in
@@ -41,14 +63,23 @@
 //! [here]: https://www.intel.com/content/dam/www/public/emea/xe/en/documents/manuals/64-ia-32-architectures-software-developer-vol-3d-part-4-manual.pdf
 
 mod builder;
+mod debug;
+mod edmm;
 mod execute;
+mod gate;
 mod ioctls;
 
 pub use builder::Builder;
+pub use debug::Mapping;
 pub use execute::{Entry, ExceptionInfo, InterruptVector, Registers};
 
-use std::sync::{Arc, RwLock};
+use execute::Run;
+use gate::Gate;
+
+use std::fs::File;
+use std::sync::{Arc, Mutex, RwLock};
 
+use lset::Span;
 use mmarinus::{perms, Map};
 use vdso::Symbol;
 
@@ -57,11 +88,47 @@ use vdso::Symbol;
 /// To begin execution in this enclave, create a new `Thread` object using
 /// `Enclave::spawn()`.
 pub struct Enclave {
-    _mem: Map<perms::Unknown>,
+    /// The file and the mapping of the enclave, until it is destroyed
+    file: Mutex<Option<File>>,
+    mem: Mutex<Option<Map<perms::Unknown>>>,
+
+    /// Where the enclave is mapped
+    span: Span<usize>,
+
+    /// What the threads enter the enclave through, until it is destroyed
+    gate: Gate,
+
     tcs: RwLock<Vec<usize>>,
+
+    /// The region which pages are added to at runtime (see `Builder::reserve()`)
+    edmm: Span<usize>,
 }
 
 impl Enclave {
+    /// The address at which the enclave is mapped
+    pub fn addr(&self) -> usize {
+        self.span.start
+    }
+
+    /// Unmaps the enclave and closes its file, so that the kernel removes
+    /// its pages
+    ///
+    /// Returns the size of the enclave, or `None` if it was destroyed before.
+    /// The threads which are inside the enclave are interrupted first, and it
+    /// is unmapped once they left it.
+    pub fn destroy(&self) -> Option<usize> {
+        let _closed = self.gate.close()?;
+
+        drop(self.mem.lock().unwrap().take());
+        drop(self.file.lock().unwrap().take());
+        Some(self.span.count)
+    }
+
+    /// Whether the enclave was destroyed (see `Enclave::destroy()`)
+    pub fn destroyed(&self) -> bool {
+        self.gate.closed()
+    }
+
     /// Create a new thread of execuation for an enclave.
     ///
     /// Note that this method does not create a system thread. If you want to
@@ -78,6 +145,7 @@ impl Enclave {
             enc: self,
             tcs,
             fnc,
+            run: Run::new(tcs),
         })
     }
 }
@@ -89,6 +157,21 @@ pub struct Thread {
     enc: Arc<Enclave>,
     tcs: usize,
     fnc: &'static Symbol,
+
+    /// What the vDSO is called with, which is set up once
+    run: Run,
+}
+
+impl Thread {
+    /// The enclave this thread is executing in
+    pub fn enclave(&self) -> &Enclave {
+        &self.enc
+    }
+
+    /// The address of the TCS of this thread
+    pub fn tcs(&self) -> usize {
+        self.tcs
+    }
 }
 
 impl Drop for Thread {
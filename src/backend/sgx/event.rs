@@ -0,0 +1,59 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! What the shim reports when it exits
+//!
+//! Whenever the enclave exits asynchronously, the shim is entered on the next
+//! SSA frame to handle the exception. When it exits again, it passes an
+//! `Event` in `rdx`: either it handled the exception in the enclave, e.g. by
+//! accepting a page of a lazy heap, or one of the sallyport blocks holds a
+//! request, or the shim itself faulted. The sallyport is only evaluated for
+//! requests, so a stale request is never handled twice.
+//!
+//! The kind of the event is in the low byte and the block of a request above
+//! it. The encoding must match the one used by the shim.
+
+const DONE: usize = 0;
+const REQUEST: usize = 1;
+const FAULT: usize = 2;
+
+/// What the shim left for the host
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The exception was handled in the enclave
+    Done,
+
+    /// The sallyport block with this index holds a request
+    Request(usize),
+
+    /// The shim faulted while handling an exception
+    Fault,
+}
+
+impl Event {
+    /// Decodes the event passed in `rdx`
+    pub fn decode(rdx: usize) -> Option<Self> {
+        match (rdx & 0xff, rdx >> 8) {
+            (DONE, 0) => Some(Self::Done),
+            (REQUEST, block) => Some(Self::Request(block)),
+            (FAULT, 0) => Some(Self::Fault),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        assert_eq!(Event::decode(0), Some(Event::Done));
+        assert_eq!(Event::decode(1), Some(Event::Request(0)));
+        assert_eq!(Event::decode(3 << 8 | 1), Some(Event::Request(3)));
+        assert_eq!(Event::decode(2), Some(Event::Fault));
+
+        assert_eq!(Event::decode(3), None);
+        assert_eq!(Event::decode(1 << 8), None);
+        assert_eq!(Event::decode(1 << 8 | 2), None);
+    }
+}
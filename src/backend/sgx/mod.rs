@@ -2,12 +2,28 @@
 
 mod enclave;
 
+use crate::backend::interrupt::{self, Hook};
 use crate::backend::sgx::attestation::get_attestation;
-use crate::backend::{Command, Datum, Keep};
+use crate::backend::shim::Shim;
+use crate::backend::{BuildError, Command, Config, Datum, StdioRing, Usage};
 use crate::binary::*;
-use enclave::{Builder, Enclave, Entry, InterruptVector, Registers};
+use crate::coredump::{self, Dump, Region};
+use crate::cpuid::Policy;
+use crate::gdb;
+use crate::metrics::Metrics;
+use aep::Target;
+use bounce::Bounce;
+use cache::Cache;
+use clock::Clock;
+use enclave::{Builder, Enclave, Entry, ExceptionInfo, InterruptVector, Registers};
+use event::Event;
+use ring::Ring;
 
 use anyhow::Result;
+use enarx_syscall::{
+    SYS_ENARX_ATTACKED, SYS_ENARX_BOUNCE, SYS_ENARX_CLOCK, SYS_ENARX_RING, SYS_ENARX_SGX_AUG,
+    SYS_ENARX_SGX_PROTECT, SYS_ENARX_SGX_REMOVE, SYS_ENARX_SGX_TRIM,
+};
 use goblin::elf::program_header::*;
 use lset::{Line, Span};
 use primordial::{Page, Pages};
@@ -18,14 +34,41 @@ use sgx::loader::{self, Loader};
 use sgx::types::page::{Class, Flags, SecInfo};
 use sgx::types::sig::{Author, Parameters};
 
+use tracing::{debug, debug_span, trace, warn};
+
 use std::arch::x86_64::__cpuid_count;
 use std::convert::TryInto;
 use std::fmt::Debug;
 use std::num::NonZeroU32;
-use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc};
 
+mod aep;
 mod attestation;
+mod bounce;
+mod cache;
+mod clock;
 mod data;
+mod event;
+mod parameters;
+mod ring;
+
+/// The maximum number of sallyport blocks per thread
+const MAX_BLOCKS: usize = 64;
+
+/// Set in the heap size passed to the shim when the heap is added lazily
+///
+/// This number must match the one used by the shim.
+const HEAP_LAZY: usize = 1;
+
+/// The vector of a page fault
+const PAGE_FAULT: u8 = 14;
+
+/// The size of the GPRSGX region, which ends each SSA frame (see Table 38-9)
+const GPRSGX_SIZE: usize = 184;
+
+/// The offset of OSSA, the offset of the first SSA frame, in the TCS
+const TCS_OSSA: usize = 16;
 
 struct Segment {
     fline: Line<usize>,
@@ -57,18 +100,33 @@ impl Debug for Segment {
     }
 }
 
-impl Segment {
-    pub fn new(component: &Component, phdr: &ProgramHeader, relocate: usize) -> Self {
-        assert_eq!(relocate % Page::SIZE, 0);
+/// The numbers of the pages which hold the bytes at `mline`
+///
+/// Segments without bytes hold no pages.
+fn pages(mline: Line<usize>) -> Line<usize> {
+    let start = mline.start / Page::SIZE;
+    match mline.start == mline.end {
+        true => Line::new(start, start),
+        false => Line::new(start, (mline.end + Page::SIZE - 1) / Page::SIZE),
+    }
+}
 
-        let fline = Line::from(phdr.file_range());
-        let mline = Line::from(phdr.vm_range()) >> relocate;
-        let skipb = mline.start % Page::SIZE;
-        let vpage = mline.start / Page::SIZE;
+impl Segment {
+    /// Copies the pages of a `PT_LOAD` header from the bytes of its component
+    pub fn new(file: &[u8], phdr: &ProgramHeader, relocate: usize) -> Result<Self> {
+        let (fline, mline) = Self::lines(phdr, file.len(), relocate)?;
+        let vpage = pages(mline).start;
+        let skipb = mline.start - vpage * Page::SIZE;
 
+        // The pages are zeroed around the bytes of the file: the head of the
+        // first page, the BSS and the tail of the last page are measured as
+        // zeroes, never as whatever the allocator returned.
         let mspan = Span::from(mline);
-        let bytes = &component.bytes[phdr.file_range()];
-        let pages = Pages::copy_into(bytes, mspan.count, skipb);
+        let bytes = &file[fline.start..fline.end];
+        let pages = match mspan.count {
+            0 => Pages::copy_into(&[], 0, 0),
+            _ => Pages::copy_into(bytes, mspan.count, skipb),
+        };
 
         let mut rwx = Flags::empty();
         for (input, output) in [(PF_R, Flags::R), (PF_W, Flags::W), (PF_X, Flags::X)] {
@@ -77,7 +135,7 @@ impl Segment {
             }
         }
 
-        Self {
+        Ok(Self {
             fline,
             mline,
             pages,
@@ -90,10 +148,96 @@ impl Segment {
                 0 => loader::Flags::Measure.into(),
                 _ => None.into(),
             },
+        })
+    }
+
+    /// Checks a `PT_LOAD` header of a component of `len` bytes
+    ///
+    /// Returns the bytes of the file and the relocated addresses which they
+    /// are loaded to. The headers come from whatever linked the component,
+    /// so nothing in them is trusted.
+    fn lines(
+        phdr: &ProgramHeader,
+        len: usize,
+        relocate: usize,
+    ) -> Result<(Line<usize>, Line<usize>)> {
+        let vaddr = phdr.p_vaddr;
+        if relocate % Page::SIZE != 0 {
+            anyhow::bail!("unaligned relocation of the segment at {:#x}", vaddr);
+        }
+
+        if phdr.p_filesz > phdr.p_memsz {
+            anyhow::bail!(
+                "the segment at {:#x} has more bytes in the file ({}) than in memory ({})",
+                vaddr,
+                phdr.p_filesz,
+                phdr.p_memsz
+            );
+        }
+
+        let align = phdr.p_align;
+        if align > 1 && (!align.is_power_of_two() || phdr.p_offset % align != vaddr % align) {
+            anyhow::bail!(
+                "the segment at {:#x} is misaligned: offset {:#x}, alignment {:#x}",
+                vaddr,
+                phdr.p_offset,
+                align
+            );
+        }
+
+        let fend = phdr.p_offset.checked_add(phdr.p_filesz);
+        let fend = fend
+            .filter(|end| *end <= len as u64)
+            .ok_or_else(|| anyhow::anyhow!("the segment at {:#x} ends beyond the file", vaddr))?;
+
+        // The end is rounded up to a page.
+        let mstart = vaddr.checked_add(relocate as u64);
+        let mend = mstart.and_then(|start| start.checked_add(phdr.p_memsz));
+        let mend = mend.filter(|end| end.checked_add(Page::SIZE as u64 - 1).is_some());
+        let (mstart, mend) = match (mstart, mend) {
+            (Some(start), Some(end)) => (start, end),
+            _ => anyhow::bail!("the segment at {:#x} ends beyond the address space", vaddr),
+        };
+
+        Ok((
+            Line::new(phdr.p_offset as usize, fend as usize),
+            Line::new(mstart as usize, mend as usize),
+        ))
+    }
+
+    /// Creates a segment of unmeasured heap pages
+    ///
+    /// Since the host controls the contents of these pages, the shim clears
+    /// them before use.
+    pub fn heap(span: Span<usize>) -> Self {
+        assert_eq!(span.start % Page::SIZE, 0);
+        assert_eq!(span.count % Page::SIZE, 0);
+
+        Self {
+            fline: Line::new(0, 0),
+            mline: span.into(),
+            pages: Pages::copy_into(&[], span.count, 0),
+            vpage: span.start / Page::SIZE,
+            sinfo: SecInfo::reg(Flags::R | Flags::W),
+            flags: None.into(),
         }
     }
 }
 
+#[cfg(feature = "builtin-shims")]
+const BUILTIN: Option<&[u8]> = Some(include_bytes!(concat!(env!("OUT_DIR"), "/bin/shim-sgx")));
+
+#[cfg(not(feature = "builtin-shims"))]
+const BUILTIN: Option<&[u8]> = None;
+
+static SHIM: Shim = Shim::new(
+    "shim-sgx",
+    "ENARX_SHIM_SGX",
+    BUILTIN,
+    option_env!("ENARX_SHIM_SGX"),
+    option_env!("ENARX_SHIM_SGX_SHA256"),
+);
+
 pub struct Backend;
 
 impl crate::backend::Backend for Backend {
@@ -101,8 +245,37 @@ impl crate::backend::Backend for Backend {
         "sgx"
     }
 
-    fn shim(&self) -> &'static [u8] {
-        include_bytes!(concat!(env!("OUT_DIR"), "/bin/shim-sgx"))
+    fn shim(&self) -> Result<&'static [u8]> {
+        SHIM.bytes()
+    }
+
+    /// Checks what `Layout::new()` reads of the shim
+    fn check_shim(&self, shim: &Component) -> Result<()> {
+        let notes = [
+            ("NOTE_ENARX_SGX_SIZE", NOTE_ENARX_SGX_SIZE),
+            ("NOTE_ENARX_SGX_SSAP", NOTE_ENARX_SGX_SSAP),
+            ("NOTE_ENARX_SGX_HEAP", NOTE_ENARX_SGX_HEAP),
+        ];
+        for (name, kind) in notes.iter().copied() {
+            if shim.filter_notes("enarx", kind).next().is_none() {
+                return Err(BuildError::MissingNote { name }.into());
+            }
+        }
+
+        if shim.find_header(PT_ENARX_HEAP).is_none() {
+            let name = "PT_ENARX_HEAP";
+            return Err(BuildError::MissingHeader { name }.into());
+        }
+
+        // The threads of the keep enter it through its TCS pages.
+        let tcs = shim
+            .filter_header(PT_LOAD)
+            .any(|phdr| phdr.p_flags & PF_ENARX_SGX_TCS != 0);
+        if !tcs {
+            anyhow::bail!("the shim has no TCS segment: is it a shim of this backend?");
+        }
+
+        Ok(())
     }
 
     fn have(&self) -> bool {
@@ -116,147 +289,893 @@ impl crate::backend::Backend for Backend {
 
         let max = unsafe { __cpuid_count(0x00000000, 0x00000000) }.eax;
         data.push(data::epc_size(max));
+        data.push(data::launch_control());
 
         data
     }
 
     /// Create a keep instance on this backend
-    fn build(&self, shim: Component, code: Component) -> Result<Arc<dyn Keep>> {
+    fn build(
+        &self,
+        shim: Component,
+        code: Component,
+        config: &Config,
+    ) -> Result<Arc<dyn super::Keep>> {
+        // The secret must only be released to an attested enclave.
+        if config.secret.is_some() {
+            anyhow::bail!("secrets cannot be injected into SGX keeps yet");
+        }
+
+        if config.snapshot.is_some() {
+            anyhow::bail!("SGX keeps can't be snapshotted");
+        }
+
+        let layout = Layout::new(&shim, &code, config)?;
+        if parameters::debug(&layout.parameters) != config.debug {
+            anyhow::bail!("the DEBUG attribute does not match the requested mode");
+        }
+        if config.debug {
+            warn!("building a debug keep: its memory is readable by the host");
+        }
+        if config.sgx.lazy_heap && unsafe { __cpuid_count(0x12, 0) }.eax & (1 << 1) == 0 {
+            anyhow::bail!("a lazy heap requires SGX2");
+        }
+
+        // A keep which doesn't fit in the EPC is paged by the kernel, which
+        // works but is slow.
+        let max = unsafe { __cpuid_count(0x00000000, 0x00000000) }.eax;
+        let (eager, lazy) = layout.footprint();
+        if let Some(epc) = data::epc(max).filter(|epc| eager > *epc) {
+            let (eager, epc) = (data::size(eager), data::size(epc));
+            let message = format!("the keep needs {} of EPC, the platform has {}", eager, epc);
+            if !config.overcommit {
+                anyhow::bail!(
+                    "{}: use a smaller --heap-size, --sgx-lazy-heap or --sgx-overcommit",
+                    message
+                );
+            }
+
+            warn!("{}: its pages will be swapped", message);
+        }
+        debug!(eager, lazy, "EPC footprint");
+
+        // Look for the signature of an identical keep.
+        let cache = match config.cache {
+            true => Some(Cache::new(shim.bytes, code.bytes, config)),
+            false => None,
+        };
+        let cached = cache.as_ref().and_then(Cache::load);
+        let from_cache = cached.is_some();
+
+        // Initialize the new enclave.
+        let (size, ssap, parameters) = (layout.size, layout.ssap, layout.parameters);
+        let mut builder = Builder::new(size, ssap, parameters)?;
+
+        // Shims without a dynamic region can't have pages added at runtime.
+        if let Some(edmm) = layout.edmm {
+            builder.reserve(edmm);
+        }
+
+        // Without a cached signature, the pages are hashed on one thread and
+        // the signing key is generated on another, while this one adds the
+        // pages to the enclave.
+        let (tx, rx) = mpsc::channel::<Segment>();
+        let signer = match cached {
+            Some(_) => None,
+            None => {
+                let mut hasher = Hasher::new(size, ssap, parameters);
+                let hashing = std::thread::spawn(move || -> Result<Hasher> {
+                    for seg in rx {
+                        hasher.load(seg.pages, seg.vpage, seg.sinfo, seg.flags)?;
+                    }
+
+                    Ok(hasher)
+                });
+
+                let keygen = std::thread::spawn(|| {
+                    let exp = openssl::bn::BigNum::from_u32(3u32)?;
+                    openssl::rsa::Rsa::generate_with_e(3072, &exp)
+                });
+
+                Some((hashing, keygen))
+            }
+        };
+
+        // Map all the pages.
+        for seg in layout.segs {
+            builder.load(&seg.pages, seg.vpage, seg.sinfo, seg.flags)?;
+            if signer.is_some() {
+                // A failure of the hashing thread is reported by `join()`.
+                let _ = tx.send(seg);
+            }
+        }
+        drop(tx);
+
+        let signature = match (cached, signer) {
+            (Some(signature), _) => signature,
+            (None, Some((hashing, keygen))) => {
+                let hasher = hashing.join().unwrap()?;
+                let key = keygen.join().unwrap()?;
+
+                // Create the enclave signature
+                let vendor = Author::new(0, 0);
+                let signature =
+                    debug_span!("measure").in_scope(|| hasher.finish().sign(vendor, key))?;
+
+                if let Some(cache) = cache.as_ref() {
+                    cache.store(&signature);
+                }
+
+                signature
+            }
+            (None, None) => unreachable!(),
+        };
+
+        // Launch the enclave. A cached signature which the platform rejects
+        // is not used again.
+        if let Err(e) = builder.init(&signature) {
+            if let (true, Some(cache)) = (from_cache, cache.as_ref()) {
+                cache.forget();
+            }
+
+            return Err(data::einit(e, from_cache));
+        }
+        let enclave = builder.build()?;
+
+        // A lazy heap is mapped now, and its pages are added by the kernel
+        // when the shim first touches them.
+        let lazy = match config.sgx.lazy_heap && layout.heap_size > 0 {
+            true => Some(Span {
+                start: enclave.addr() + layout.heap.start,
+                count: layout.heap_size,
+            }),
+            false => None,
+        };
+        if let Some(span) = lazy {
+            enclave.augment_lazily(span)?;
+        }
+
+        let guards = layout.guards.iter().map(|guard| {
+            let guard = Span {
+                start: enclave.addr() + guard.start,
+                count: guard.count,
+            };
+
+            Line::from(guard)
+        });
+        let guards = guards.collect();
+
+        if let Some(metrics) = &config.metrics {
+            metrics.epc_built(eager as usize / Page::SIZE);
+        }
+
+        Ok(Arc::new(Keep {
+            enclave,
+            ssa: layout.ssap.get() as usize * Page::SIZE,
+            debug: config.debug,
+            heap_size: layout.heap_size,
+            lazy,
+            guards,
+            blocks: layout.blocks,
+            metrics: config.metrics.clone(),
+            ring: config.ring.clone(),
+            cpuid: Policy::sgx().with(&config.cpuid),
+        }))
+    }
+
+    /// Computes MRENCLAVE as `build()` would, without creating the enclave
+    fn measure(&self, shim: Component, code: Component, config: &Config) -> Result<Vec<u8>> {
+        let layout = Layout::new(&shim, &code, config)?;
+        let mut hasher = Hasher::new(layout.size, layout.ssap, layout.parameters);
+        for seg in layout.segs {
+            hasher.load(seg.pages, seg.vpage, seg.sinfo, seg.flags)?;
+        }
+
+        Ok(hasher.finish().mrenclave().to_vec())
+    }
+
+    fn footprint(
+        &self,
+        shim: Component,
+        code: Component,
+        config: &Config,
+    ) -> Result<Option<Datum>> {
+        let layout = Layout::new(&shim, &code, config)?;
+        let (eager, lazy) = layout.footprint();
+        let max = unsafe { __cpuid_count(0x00000000, 0x00000000) }.eax;
+        Ok(Some(data::footprint(eager, lazy, data::epc(max))))
+    }
+}
+
+/// The contents and the launch parameters of an enclave
+struct Layout {
+    size: usize,
+    ssap: NonZeroU32,
+    blocks: usize,
+    heap: Span<usize>,
+    heap_size: usize,
+    lazy: bool,
+    edmm: Option<Span<usize>>,
+    guards: Vec<Span<usize>>,
+    segs: Vec<Segment>,
+    parameters: Parameters,
+}
+
+impl Layout {
+    /// Lays out the shim and the code as the notes of the shim describe
+    fn new(shim: &Component, code: &Component, config: &Config) -> Result<Self> {
+        if config.manifest.is_some() {
+            anyhow::bail!("SGX keeps don't verify payload manifests yet");
+        }
+
+        // A loader built for signed payloads mustn't run any payload at all.
+        if option_env!("ENARX_MANIFEST_KEY").is_some() {
+            anyhow::bail!("SGX keeps don't verify the manifests which ENARX_MANIFEST_KEY requires");
+        }
+
+        // The payload may request a heap size.
+        let config = &config.requested(shim, code)?;
+
+        let header = |type_, name| {
+            shim.find_header(type_)
+                .map(|phdr| Span::from(phdr.vm_range()))
+                .ok_or(BuildError::MissingHeader { name })
+        };
+        let note = |name| BuildError::MissingNote { name };
+
         // Find the offset for loading the code.
-        let slot = Span::from(shim.find_header(PT_ENARX_CODE).unwrap().vm_range());
-        assert!(Span::from(code.region()).count <= slot.count);
+        let slot = header(PT_ENARX_CODE, "PT_ENARX_CODE")?;
 
         // Find the size of the enclave (in powers of two).
-        let size: u32 = unsafe { shim.read_note("enarx", NOTE_ENARX_SGX_SIZE)?.unwrap() };
+        let size: u32 = unsafe { shim.read_note("enarx", NOTE_ENARX_SGX_SIZE)? }
+            .ok_or_else(|| note("NOTE_ENARX_SGX_SIZE"))?;
+        if size >= usize::BITS {
+            anyhow::bail!("invalid size of the enclave: 2^{} bytes", size);
+        }
         let size = 1 << size;
 
         // Find the number of pages in an SSA frame.
-        let ssap: u32 = unsafe { shim.read_note("enarx", NOTE_ENARX_SGX_SSAP)?.unwrap() };
-        let ssap = NonZeroU32::new(ssap).unwrap();
+        let ssap: u32 = unsafe { shim.read_note("enarx", NOTE_ENARX_SGX_SSAP)? }
+            .ok_or_else(|| note("NOTE_ENARX_SGX_SSAP"))?;
+        let ssap = NonZeroU32::new(ssap)
+            .ok_or_else(|| anyhow::anyhow!("invalid number of pages in an SSA frame: 0"))?;
+
+        // Find the number of sallyport blocks per thread (one, by default).
+        let blocks = unsafe { shim.read_note::<u32>("enarx", NOTE_ENARX_SGX_BLKS)? };
+        let blocks = blocks.unwrap_or(1) as usize;
+        if blocks == 0 || blocks > MAX_BLOCKS {
+            anyhow::bail!("invalid number of sallyport blocks: {}", blocks);
+        }
 
-        // Get an array of all final segment (relative) locations.
+        // Find the guard pages, which are never added to the enclave.
+        let guards = shim.filter_header(PT_ENARX_GUARD);
+        let guards = guards.map(|phdr| Span::from(phdr.vm_range())).collect();
+
+        // Find the heap reservation and the size of the heap.
+        let heap = header(PT_ENARX_HEAP, "PT_ENARX_HEAP")?;
+        let heap_size = match config.heap_size {
+            Some(size) => size,
+            None => unsafe { shim.read_note::<u64>("enarx", NOTE_ENARX_SGX_HEAP)? }
+                .ok_or_else(|| note("NOTE_ENARX_SGX_HEAP"))? as usize,
+        };
+        let heap_size = (heap_size + Page::SIZE - 1) / Page::SIZE * Page::SIZE;
+        if heap_size > heap.count {
+            anyhow::bail!(
+                "heap size ({} bytes) exceeds the shim heap reservation ({} bytes)",
+                heap_size,
+                heap.count
+            );
+        }
+
+        // Get an array of all final segment (relative) locations. The pages
+        // of each segment are copied on a thread of their own, which shares
+        // the bytes of its component with the others.
+        let (sfile, cfile) = (Arc::<[u8]>::from(shim.bytes), Arc::<[u8]>::from(code.bytes));
         let ssegs = shim
             .filter_header(PT_LOAD)
-            .map(|phdr| Segment::new(&shim, phdr, 0));
+            .map(|phdr| (sfile.clone(), phdr.clone(), 0));
         let csegs = code
             .filter_header(PT_LOAD)
-            .map(|phdr| Segment::new(&code, phdr, slot.start));
-        let mut segs: Vec<_> = ssegs.chain(csegs).collect();
+            .map(|phdr| (cfile.clone(), phdr.clone(), slot.start));
+        let threads: Vec<_> = ssegs
+            .chain(csegs)
+            .map(|(file, phdr, relocate)| {
+                std::thread::spawn(move || Segment::new(&file, &phdr, relocate))
+            })
+            .collect();
 
-        // Ensure no segments overlap in memory.
-        segs.sort_unstable_by_key(|x| x.vpage);
-        for pair in segs.windows(2) {
-            assert!(pair[0].vpage + pair[0].pages.len() <= pair[1].vpage);
+        // All threads are joined before any error is returned.
+        let segs: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        let mut segs = segs.into_iter().collect::<Result<Vec<_>>>()?;
+
+        // The headers of the code are valid, so its region can be found.
+        let region = Span::from(code.region());
+        if region.count > slot.count {
+            return Err(BuildError::CodeTooLarge {
+                need: region.count,
+                have: slot.count,
+            }
+            .into());
         }
 
-        // Initialize the new enclave.
-        let parameters = Parameters::default();
-        let mut builder = Builder::new(size, ssap, parameters)?;
-        let mut hasher = Hasher::new(size, ssap, parameters);
+        // A lazy heap is not part of the measurement.
+        if !config.sgx.lazy_heap {
+            segs.push(Segment::heap(Span {
+                start: heap.start,
+                count: heap_size,
+            }));
+        }
 
-        // Map all the pages.
-        for seg in segs {
-            builder.load(&seg.pages, seg.vpage, seg.sinfo, seg.flags)?;
-            hasher.load(seg.pages, seg.vpage, seg.sinfo, seg.flags)?;
+        // Segments without pages add nothing to the enclave, but their
+        // position in the order would depend on the headers.
+        segs.retain(|seg| !seg.pages.is_empty());
+
+        // The measurement depends on the order in which the pages are added,
+        // so the segments are added by address, whatever the order of the
+        // program headers.
+        segs.sort_by_key(|x| x.vpage);
+
+        // Ensure no segments overlap in memory.
+        for pair in segs.windows(2) {
+            if pair[0].vpage + pair[0].pages.len() > pair[1].vpage {
+                return Err(BuildError::OverlappingSegments {
+                    a: format!("{:?}", pair[0]),
+                    b: format!("{:?}", pair[1]),
+                }
+                .into());
+            }
         }
 
-        // Generate a signing key.
-        let exp = openssl::bn::BigNum::from_u32(3u32).unwrap();
-        let key = openssl::rsa::Rsa::generate_with_e(3072, &exp)?;
+        Ok(Self {
+            size,
+            ssap,
+            blocks,
+            heap,
+            heap_size,
+            lazy: config.sgx.lazy_heap,
+            edmm: header(PT_ENARX_EDMM, "PT_ENARX_EDMM").ok(),
+            guards,
+            segs,
+            parameters: parameters::parameters(&config.sgx, config.debug, ssap)?,
+        })
+    }
 
-        // Create the enclave signature
-        let vendor = Author::new(0, 0);
-        let signature = hasher.finish().sign(vendor, key)?;
+    /// The bytes of EPC which the keep needs when it is built, and those
+    /// which a lazy heap can add
+    fn footprint(&self) -> (u64, u64) {
+        // The SECS takes a page of its own.
+        let pages = 1 + self.segs.iter().map(|seg| seg.pages.len()).sum::<usize>();
+        let lazy = match self.lazy {
+            true => self.heap_size,
+            false => 0,
+        };
 
-        // Build the enclave.
-        Ok(builder.build(&signature)?)
+        ((pages * Page::SIZE) as u64, lazy as u64)
     }
 }
 
-impl super::Keep for Enclave {
+struct Keep {
+    enclave: Arc<Enclave>,
+    /// The size of an SSA frame
+    ssa: usize,
+    debug: bool,
+    heap_size: usize,
+    lazy: Option<Span<usize>>,
+    guards: Vec<Line<usize>>,
+    blocks: usize,
+    metrics: Option<Metrics>,
+    ring: Option<StdioRing>,
+    cpuid: Policy,
+}
+
+impl super::Keep for Keep {
     fn spawn(self: Arc<Self>) -> Result<Option<Box<dyn crate::backend::Thread>>> {
-        let thread = match self.spawn() {
+        let thread = match self.enclave.clone().spawn() {
             Some(thread) => thread,
             None => return Ok(None),
         };
 
+        // The blocks never move, since they are never resized.
+        let mut blocks: Vec<_> = (0..self.blocks).map(|_| Block::default()).collect();
+        let target = Target {
+            tcs: thread.tcs(),
+            rdi: blocks.as_mut_ptr() as usize,
+            r8: match self.lazy {
+                Some(_) => self.heap_size | HEAP_LAZY,
+                None => self.heap_size,
+            },
+            entered: Default::default(),
+        };
+
         Ok(Some(Box::new(Thread {
             thread,
+            ssa: self.ssa,
+            debug: self.debug,
             registers: Registers::default(),
-            block: Block::default(),
+            blocks,
+            current: 0,
             cssa: usize::default(),
             how: Entry::Enter,
+            target,
+            lazy: self.lazy,
+            guards: self.guards.clone(),
+            metrics: self.metrics.clone(),
+            ring: match self.ring.clone() {
+                Some(stdio) => Some(Ring::new(stdio, self.metrics.clone())?),
+                None => None,
+            },
+            bounce: None,
+            clock: Clock::new()?,
+            cpuid: self.cpuid.clone(),
         })))
     }
+
+    fn shutdown(self: Arc<Self>) -> Result<Usage> {
+        // Each thread holds the enclave, besides the keep.
+        let threads = Arc::strong_count(&self.enclave) - 1;
+
+        Ok(match self.enclave.destroy() {
+            Some(memory) => Usage {
+                memory,
+                fds: 1,
+                threads,
+            },
+            None => Usage {
+                threads,
+                ..Default::default()
+            },
+        })
+    }
 }
 
 struct Thread {
     thread: enclave::Thread,
+    ssa: usize,
+    debug: bool,
     registers: Registers,
-    block: Block,
+    blocks: Vec<Block>,
+    current: usize,
     cssa: usize,
     how: Entry,
+
+    /// What the shim is entered with, which interrupts enter it with, too
+    /// (see `aep`)
+    target: Target,
+
+    lazy: Option<Span<usize>>,
+    guards: Vec<Line<usize>>,
+    metrics: Option<Metrics>,
+
+    /// The ring and the clock run on threads of their own, which are started
+    /// with the thread: the sandbox of the loader (see `crate::sandbox`)
+    /// doesn't let the thread start any once it runs the keep. Keeps without
+    /// `Config::ring` have no ring.
+    ring: Option<Ring>,
+    bounce: Option<Bounce>,
+    clock: Clock,
+    cpuid: Policy,
 }
 
 impl Thread {
+    /// The block holding the current request
+    fn block(&mut self) -> &mut Block {
+        &mut self.blocks[self.current]
+    }
+
+    /// Whether the address is in one of the guard pages
+    fn guarded(&self, addr: usize) -> bool {
+        self.guards.iter().any(|g| g.start <= addr && addr < g.end)
+    }
+
+    /// Prepares to enter the shim for an asynchronous exit
+    fn exception(&mut self, prev: Entry, ei: ExceptionInfo) -> Result<()> {
+        // The shim can't handle a fault in the guard pages, as it handles
+        // exceptions on the stack which overflowed.
+        if ei.trap as u8 == PAGE_FAULT && self.guarded(ei.addr.raw() as usize) {
+            let level = match prev {
+                Entry::Enter => self.cssa,
+                Entry::Resume => self.cssa - 1,
+            };
+
+            match level {
+                0 => anyhow::bail!("payload stack overflow at {:#x}", ei.addr.raw()),
+                _ => anyhow::bail!("shim stack overflow at {:#x}", ei.addr.raw()),
+            }
+        }
+
+        if ei.last != Entry::Resume {
+            anyhow::bail!("unable to enter the keep: {:?}", ei);
+        }
+
+        if let Some(metrics) = &self.metrics {
+            metrics.exception(ei.trap as u8);
+        }
+
+        // The shim accepts the pages of a lazy heap when they fault.
+        let fault = match ei.trap as u8 {
+            PAGE_FAULT => ei.addr.raw() as usize,
+            _ => 0,
+        };
+        self.registers.r9 = fault.into();
+
+        let heap = self.lazy.map(Line::from);
+        let lazy = heap.map_or(false, |h| h.start <= fault && fault < h.end);
+
+        match ei.trap {
+            InterruptVector::InvalidOpcode => trace!("asynchronous exit: {:?}", ei.trap),
+            _ if lazy => {
+                if let Some(metrics) = &self.metrics {
+                    metrics.epc_added(1);
+                }
+
+                trace!(addr = fault, "lazy heap fault");
+            }
+            _ => debug!(
+                code = ei.code,
+                addr = ei.addr.raw(),
+                "asynchronous exit: {:?}",
+                ei.trap
+            ),
+        }
+
+        Ok(())
+    }
+
+    /// Evaluates what the shim left for the host in `rdx` when it exited
+    ///
+    /// Only requests are evaluated, so that nothing is evaluated twice.
+    fn exited(&mut self) -> Result<Command> {
+        let rdx = self.registers.rdx.into();
+        self.current = match Event::decode(rdx) {
+            Some(Event::Done) => return Ok(Command::Continue),
+            Some(Event::Request(block)) if block < self.blocks.len() => block,
+            Some(Event::Request(block)) => anyhow::bail!("invalid sallyport block: {}", block),
+            Some(Event::Fault) => anyhow::bail!("the shim faulted at CSSA {}", self.cssa + 1),
+            None => anyhow::bail!("invalid event from the shim: {:#x}", rdx),
+        };
+
+        match unsafe { self.block().msg.req }.num.into() {
+            SYS_ENARX_CPUID => self.cpuid(),
+            SYS_ENARX_GETATT => self.attest()?,
+            num @ SYS_ENARX_SGX_AUG..=SYS_ENARX_SGX_REMOVE => self.edmm(num),
+            SYS_ENARX_RING => self.ring(),
+            SYS_ENARX_BOUNCE => self.bounce(),
+            SYS_ENARX_CLOCK => self.clock(),
+            SYS_ENARX_ATTACKED => return Ok(Command::Attacked),
+            _ => return Ok(Command::SysCall(self.block())),
+        }
+
+        Ok(Command::Continue)
+    }
+
     fn cpuid(&mut self) {
+        let block = &mut self.blocks[self.current];
         unsafe {
-            let cpuid = core::arch::x86_64::__cpuid_count(
-                self.block.msg.req.arg[0].try_into().unwrap(),
-                self.block.msg.req.arg[1].try_into().unwrap(),
-            );
+            let leaf = block.msg.req.arg[0].try_into().unwrap();
+            let subleaf = block.msg.req.arg[1].try_into().unwrap();
+            let cpuid = __cpuid_count(leaf, subleaf);
 
-            self.block.msg.req.arg[0] = cpuid.eax.into();
-            self.block.msg.req.arg[1] = cpuid.ebx.into();
-            self.block.msg.req.arg[2] = cpuid.ecx.into();
-            self.block.msg.req.arg[3] = cpuid.edx.into();
+            let values = [cpuid.eax, cpuid.ebx, cpuid.ecx, cpuid.edx];
+            let values = self.cpuid.apply(leaf, subleaf, values);
+            for (arg, value) in block.msg.req.arg.iter_mut().zip(&values) {
+                *arg = (*value).into();
+            }
         }
     }
 
     fn attest(&mut self) -> Result<()> {
+        let block = self.block();
         let result = unsafe {
             get_attestation(
-                self.block.msg.req.arg[0].into(),
-                self.block.msg.req.arg[1].into(),
-                self.block.msg.req.arg[2].into(),
-                self.block.msg.req.arg[3].into(),
+                block.msg.req.arg[0].into(),
+                block.msg.req.arg[1].into(),
+                block.msg.req.arg[2].into(),
+                block.msg.req.arg[3].into(),
             )?
         };
 
-        self.block.msg.rep = Ok([result.into(), 0.into()]).into();
+        block.msg.rep = Ok([result.into(), 0.into()]).into();
         Ok(())
     }
+
+    fn edmm(&mut self, num: i64) {
+        let (span, prot) = unsafe {
+            let span = Span {
+                start: self.block().msg.req.arg[0].into(),
+                count: self.block().msg.req.arg[1].into(),
+            };
+
+            let prot: usize = self.block().msg.req.arg[2].into();
+            (span, prot as libc::c_int)
+        };
+
+        let enclave = self.thread.enclave();
+        let result = match num {
+            SYS_ENARX_SGX_AUG => enclave.augment(span),
+            SYS_ENARX_SGX_PROTECT => enclave.restrict(span, prot),
+            SYS_ENARX_SGX_TRIM => enclave.trim(span),
+            SYS_ENARX_SGX_REMOVE => enclave.remove(span),
+            _ => unreachable!(),
+        };
+
+        if let (Some(metrics), SYS_ENARX_SGX_AUG, Ok(())) = (&self.metrics, num, &result) {
+            metrics.epc_added(span.count / Page::SIZE);
+        }
+
+        self.block().msg.rep = match result {
+            Ok(()) => Ok([0.into(), 0.into()]),
+            Err(e) => Err(e.raw_os_error().unwrap_or(libc::EIO)),
+        }
+        .into();
+    }
+
+    /// Sets up the ring (`0`) or wakes its thread (`1`)
+    fn ring(&mut self) {
+        let op: usize = unsafe { self.block().msg.req.arg[0].into() };
+        let reply = match (&self.ring, op) {
+            (None, _) => Err(libc::ENOSYS),
+            (Some(ring), 0) => ring.reply(),
+            (Some(ring), 1) => {
+                ring.wake();
+                Ok(Default::default())
+            }
+            _ => Err(libc::EINVAL),
+        };
+        self.block().msg.rep = reply.into();
+    }
+
+    fn clock(&mut self) {
+        let reply = self.clock.reply();
+        self.block().msg.rep = reply.into();
+    }
+
+    fn bounce(&mut self) {
+        let size: usize = unsafe { self.block().msg.req.arg[0].into() };
+        if size == 0 || size > bounce::MAX_SIZE {
+            self.block().msg.rep = Err(libc::EINVAL).into();
+            return;
+        }
+
+        // A region is kept until the shim asks for a larger one.
+        if self.bounce.as_ref().map_or(true, |b| b.size() < size) {
+            self.bounce = match Bounce::new(size) {
+                Ok(bounce) => Some(bounce),
+                Err(e) => {
+                    debug!("unable to map a bounce region: {}", e);
+                    self.block().msg.rep = Err(libc::ENOMEM).into();
+                    return;
+                }
+            };
+        }
+
+        self.block().msg.rep = self.bounce.as_ref().unwrap().reply().into();
+    }
+}
+
+impl Dump for Thread {
+    /// Reads the registers of the payload from the first SSA frame, where
+    /// the exit from the payload saved them
+    fn registers(&mut self) -> Result<gdb::Registers> {
+        let enclave = self.thread.enclave();
+
+        let mut ossa = [0; 8];
+        enclave.read(self.thread.tcs() + TCS_OSSA, &mut ossa)?;
+        let frame = enclave.addr() + u64::from_le_bytes(ossa) as usize;
+
+        // rax, rcx, rdx, rbx, rsp, rbp, rsi, rdi, r8-r15, rflags, rip, ...
+        let mut gpr = [0; GPRSGX_SIZE];
+        enclave.read(frame + self.ssa - GPRSGX_SIZE, &mut gpr)?;
+        let reg = |index: usize| u64::from_le_bytes(gpr[index * 8..][..8].try_into().unwrap());
+
+        let mut registers = gdb::Registers {
+            gpr: [0; 17],
+            eflags: reg(16) as u32,
+        };
+        let order = [0, 3, 1, 2, 6, 7, 5, 4, 8, 9, 10, 11, 12, 13, 14, 15, 17];
+        for (register, index) in registers.gpr.iter_mut().zip(order.iter()) {
+            *register = reg(*index);
+        }
+
+        Ok(registers)
+    }
+
+    /// Reads every page of the enclave which was added
+    fn memory(&mut self) -> Result<Vec<Region>> {
+        let enclave = self.thread.enclave();
+
+        let mut regions = Vec::new();
+        let mut page = vec![0; Page::SIZE];
+        for mapping in enclave.mappings()? {
+            let mut flags = PF_R;
+            if mapping.write {
+                flags |= PF_W;
+            }
+            if mapping.exec {
+                flags |= PF_X;
+            }
+
+            for addr in (mapping.line.start..mapping.line.end).step_by(Page::SIZE) {
+                if enclave.read(addr, &mut page).is_ok() {
+                    coredump::push(&mut regions, addr as u64, flags, &page);
+                }
+            }
+        }
+
+        Ok(regions)
+    }
 }
 
 impl super::Thread for Thread {
+    fn dump(&mut self) -> Option<&mut dyn Dump> {
+        match self.debug {
+            true => Some(self),
+            false => None,
+        }
+    }
+
     fn enter(&mut self) -> Result<Command> {
         let prev = self.how;
-        self.registers.rdi = (&mut self.block).into();
 
-        self.how = match self.thread.enter(prev, &mut self.registers) {
-            Err(ei) if ei.trap == InterruptVector::InvalidOpcode => Entry::Enter,
-            Ok(_) => Entry::Resume,
-            e => panic!("Unexpected AEX: {:?}", e),
+        let entered = match (prev, self.cssa) {
+            // The shim is always entered with the same arguments, while the
+            // registers hold what it exited with.
+            (Entry::Enter, _) => {
+                self.registers.rdi = self.target.rdi.into();
+                self.registers.r8 = self.target.r8.into();
+                self.thread.enter(prev, &mut self.registers)
+            }
+
+            // The payload can be interrupted once it is resumed (see `aep`).
+            (Entry::Resume, 0) => {
+                self.target.entered.store(false, Ordering::Relaxed);
+                let hook = Hook {
+                    func: aep::hook,
+                    data: &self.target as *const Target as usize,
+                };
+
+                let entered = {
+                    let _armed = interrupt::arm(&hook);
+                    self.thread.enter(prev, &mut self.registers)
+                };
+
+                // The AEX which the shim was entered after is accounted for,
+                // as if it handled an exception.
+                if self.target.entered.load(Ordering::Relaxed) {
+                    self.cssa += 1;
+                }
+
+                entered
+            }
+
+            (Entry::Resume, _) => self.thread.enter(prev, &mut self.registers),
         };
 
-        // Keep track of the CSSA
-        match self.how {
-            Entry::Enter => self.cssa += 1,
-            Entry::Resume => match self.cssa {
-                0 => unreachable!(),
-                _ => self.cssa -= 1,
-            },
+        // A keep which was shut down has nothing left to enter, and a thread
+        // which was inside it was interrupted.
+        let entered = match entered {
+            Some(_) if self.thread.enclave().destroyed() => return Ok(Command::Halt),
+            Some(entered) => entered,
+            None => return Ok(Command::Halt),
+        };
+
+        // Exceptions in the enclave are handled by the shim, which converts
+        // those of the payload into signals. Syscalls arrive as `#UD`.
+        match entered {
+            Ok(()) => {
+                self.how = Entry::Resume;
+                // The shim can only EEXIT from a frame it was entered on, so
+                // an exit at CSSA 0 means that the enclave is broken.
+                self.cssa = match self.cssa.checked_sub(1) {
+                    Some(cssa) => cssa,
+                    None => anyhow::bail!("the shim exited at CSSA 0"),
+                };
+                self.exited()
+            }
+
+            Err(ei) => {
+                self.exception(prev, ei)?;
+                self.how = Entry::Enter;
+                self.cssa += 1;
+                Ok(Command::Continue)
+            }
+        }
+    }
+
+    fn state(&self) -> Option<String> {
+        Some(format!("CSSA {}, next {:?}", self.cssa, self.how))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    fn phdr(offset: u64, filesz: u64, vaddr: u64, memsz: u64, align: u64) -> ProgramHeader {
+        ProgramHeader {
+            p_type: PT_LOAD,
+            p_flags: PF_R,
+            p_offset: offset,
+            p_vaddr: vaddr,
+            p_paddr: vaddr,
+            p_filesz: filesz,
+            p_memsz: memsz,
+            p_align: align,
         }
+    }
+
+    /// Small numbers, page boundaries and whatever else
+    fn number() -> impl Strategy<Value = u64> {
+        prop_oneof![
+            0u64..0x4000,
+            (0u64..16).prop_map(|n| n * Page::SIZE as u64),
+            Just(u64::MAX),
+            any::<u64>(),
+        ]
+    }
+
+    fn header() -> impl Strategy<Value = ProgramHeader> {
+        let align = prop_oneof![Just(0), Just(1), Just(0x1000), Just(0x200000), number()];
+        (number(), number(), number(), number(), align)
+            .prop_map(|(o, f, v, m, a)| phdr(o, f, v, m, a))
+    }
+
+    proptest! {
+        #[test]
+        fn layout(
+            phdr in header(),
+            len in 0usize..0x8000,
+            relocate in prop_oneof![Just(0usize), Just(0x1000), any::<usize>()],
+        ) {
+            let (fline, mline) = match Segment::lines(&phdr, len, relocate) {
+                Ok(lines) => lines,
+                Err(_) => return Ok(()),
+            };
+
+            prop_assert!(fline.start <= fline.end && fline.end <= len);
+            prop_assert!(mline.start <= mline.end);
+            prop_assert!(fline.end - fline.start <= mline.end - mline.start);
+            prop_assert_eq!(mline.start, phdr.p_vaddr as usize + relocate);
 
-        // If we have handled an InvalidOpcode error, evaluate the sallyport.
-        if let (Entry::Enter, Entry::Resume) = (prev, self.how) {
-            match unsafe { self.block.msg.req }.num.into() {
-                SYS_ENARX_CPUID => self.cpuid(),
-                SYS_ENARX_GETATT => self.attest()?,
-                _ => return Ok(Command::SysCall(&mut self.block)),
+            // The pages hold all the bytes, and no more pages than needed.
+            let vpages = pages(mline);
+            prop_assert!(vpages.start <= vpages.end);
+            if mline.start == mline.end {
+                prop_assert_eq!(vpages.start, vpages.end);
+            } else {
+                prop_assert!(vpages.start * Page::SIZE <= mline.start);
+                prop_assert!(mline.start < (vpages.start + 1) * Page::SIZE);
+                prop_assert!((vpages.end - 1) * Page::SIZE < mline.end);
+                prop_assert!(mline.end <= vpages.end * Page::SIZE);
             }
         }
+    }
 
-        Ok(Command::Continue)
+    #[test]
+    fn degenerate() {
+        let lines = |phdr, relocate| Segment::lines(&phdr, 0x3000, relocate);
+
+        assert!(lines(phdr(0x1000, 0x800, 0x1000, 0x2000, 0x1000), 0x1000).is_ok());
+
+        // More bytes in the file than in memory
+        assert!(lines(phdr(0x1000, 0x800, 0x1000, 0x400, 0x1000), 0).is_err());
+
+        // Offset and address disagree modulo the alignment
+        assert!(lines(phdr(0x1000, 0x800, 0x1080, 0x800, 0x1000), 0).is_err());
+        assert!(lines(phdr(0x1000, 0x800, 0x1000, 0x800, 0x1800), 0).is_err());
+        assert!(lines(phdr(0x1008, 0x800, 0x1080, 0x800, 0), 0).is_ok());
+
+        // Beyond the end of the file or of the address space
+        assert!(lines(phdr(0x2000, 0x1001, 0x1000, 0x2000, 0x1000), 0).is_err());
+        assert!(lines(phdr(u64::MAX, 1, 0x1000, 0x2000, 0), 0).is_err());
+        assert!(lines(phdr(0, 0, u64::MAX - 0x800, 0x1000, 0), 0).is_err());
+
+        // An unaligned relocation
+        assert!(lines(phdr(0x1000, 0x800, 0x1000, 0x800, 0x1000), 0x800).is_err());
+
+        // An empty segment has no pages, wherever it is.
+        let (_, mline) = lines(phdr(0x1000, 0, 0x1234, 0, 0), 0).unwrap();
+        assert_eq!(pages(mline), Line::new(1, 1));
     }
 }
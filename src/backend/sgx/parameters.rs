@@ -0,0 +1,178 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Launch parameters
+//!
+//! Without overrides, enclaves are created with `Parameters::default()`: only
+//! the x87 and SSE states are enabled, EXINFO is saved to the SSA on
+//! exceptions if the CPU supports it, so that the shim learns the address and
+//! the error code of page faults, and the product ID and the security version
+//! are zero.
+//! Each of them can be overridden, which changes the identity which the
+//! enclave is signed and reported with, but not MRENCLAVE.
+//!
+//! The DEBUG attribute is never taken from the defaults: it is set for debug
+//! keeps and cleared otherwise, and the signature requires it to match.
+
+use crate::backend::SgxParameters;
+
+use anyhow::{bail, Result};
+use primordial::Page;
+use sgx::types::attr::{Attributes, Flags, Xfrm};
+use sgx::types::misc::MiscSelect;
+use sgx::types::sig::Parameters;
+
+use std::arch::x86_64::__cpuid_count;
+use std::num::NonZeroU32;
+
+/// The x87 and SSE states, which every enclave must enable
+const XFRM_LEGACY: u64 = 0b11;
+
+/// The size of the legacy region and the header of the XSAVE area
+const XSAVE_LEGACY: usize = 576;
+
+/// The size of the GPR area at the end of an SSA frame
+const SSA_GPR: usize = 184;
+
+/// The size of the EXINFO area before the GPR area
+const SSA_EXINFO: usize = 16;
+
+/// The bit of MISCSELECT which enables EXINFO
+const MISC_EXINFO: u32 = 1;
+
+/// Checks the overrides against the CPU and applies them to the defaults
+pub fn parameters(config: &SgxParameters, debug: bool, ssap: NonZeroU32) -> Result<Parameters> {
+    let mut parameters = Parameters::default();
+    let mut ssa = SSA_GPR;
+
+    let data = parameters.attr.data;
+    let flags = match debug {
+        true => data.flags() | Flags::DEBUG,
+        false => data.flags() - Flags::DEBUG,
+    };
+    parameters.attr.data = Attributes::new(flags, data.xfrm());
+
+    let mask = parameters.attr.mask;
+    parameters.attr.mask = Attributes::new(mask.flags() | Flags::DEBUG, mask.xfrm());
+
+    let supported = unsafe { __cpuid_count(0x12, 0) }.ebx;
+    let misc = match config.misc_select {
+        Some(misc) if misc & !supported != 0 => bail!("unsupported MISCSELECT: {:#x}", misc),
+        Some(misc) => misc,
+        None => supported & MISC_EXINFO,
+    };
+
+    let known = match MiscSelect::from_bits(misc) {
+        Some(known) => known,
+        None => bail!("invalid MISCSELECT: {:#x}", misc),
+    };
+
+    // EXINFO is the only extra information defined so far.
+    if misc & MISC_EXINFO != 0 {
+        ssa += SSA_EXINFO;
+    }
+
+    parameters.misc.data = known;
+    parameters.misc.mask = known;
+
+    match config.xfrm {
+        Some(xfrm) => {
+            if xfrm & XFRM_LEGACY != XFRM_LEGACY {
+                bail!("XFRM {:#x} lacks the x87 and SSE states", xfrm);
+            }
+
+            let known = match Xfrm::from_bits(xfrm) {
+                Some(known) => known,
+                None => bail!("invalid XFRM: {:#x}", xfrm),
+            };
+
+            let res = unsafe { __cpuid_count(0x12, 1) };
+            let supported = (res.edx as u64) << 32 | res.ecx as u64;
+            if xfrm & !supported != 0 {
+                bail!("unsupported XFRM: {:#x}", xfrm);
+            }
+
+            ssa += xsave_size(xfrm);
+
+            parameters.attr.data = Attributes::new(flags, known);
+        }
+
+        None => ssa += xsave_size(XFRM_LEGACY),
+    }
+
+    let frame = ssap.get() as usize * Page::SIZE;
+    if ssa > frame {
+        bail!("the SSA frame ({} bytes) cannot hold {} bytes", frame, ssa);
+    }
+
+    if let Some(pid) = config.prod_id {
+        parameters.pid = pid;
+    }
+
+    if let Some(svn) = config.svn {
+        parameters.svn = svn;
+    }
+
+    Ok(parameters)
+}
+
+/// Whether the parameters create a debug enclave
+pub fn debug(parameters: &Parameters) -> bool {
+    parameters.attr.data.flags().contains(Flags::DEBUG)
+}
+
+/// The size of the XSAVE area which holds the states of `xfrm`
+fn xsave_size(xfrm: u64) -> usize {
+    (2..64)
+        .filter(|i| xfrm & (1 << i) != 0)
+        .map(|i| unsafe { __cpuid_count(0xd, i) })
+        .map(|res| (res.ebx + res.eax) as usize)
+        .fold(XSAVE_LEGACY, usize::max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let ssap = NonZeroU32::new(1).unwrap();
+        let config = SgxParameters::default();
+        let defaults = Parameters::default();
+
+        let production = parameters(&config, false, ssap).unwrap();
+        assert!(!debug(&production));
+        assert!(production.attr.mask.flags().contains(Flags::DEBUG));
+        assert_eq!(production.attr.data.xfrm(), defaults.attr.data.xfrm());
+
+        // EXINFO is enabled whenever the CPU supports it.
+        let exinfo = unsafe { __cpuid_count(0x12, 0) }.ebx & MISC_EXINFO;
+        assert_eq!(production.misc.data.bits(), exinfo);
+        assert_eq!(production.misc.mask, production.misc.data);
+
+        let debuggable = parameters(&config, true, ssap).unwrap();
+        assert!(debug(&debuggable));
+    }
+
+    #[test]
+    fn invalid() {
+        let ssap = NonZeroU32::new(1).unwrap();
+
+        let config = SgxParameters {
+            xfrm: Some(0b100),
+            ..Default::default()
+        };
+        assert!(parameters(&config, false, ssap).is_err());
+
+        let config = SgxParameters {
+            misc_select: Some(1 << 31),
+            ..Default::default()
+        };
+        assert!(parameters(&config, false, ssap).is_err());
+    }
+
+    #[test]
+    fn legacy() {
+        assert_eq!(xsave_size(XFRM_LEGACY), XSAVE_LEGACY);
+        assert!(xsave_size(0b111) >= XSAVE_LEGACY);
+    }
+}
@@ -0,0 +1,397 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! An asynchronous ring for the output of the payload
+//!
+//! The shim asks for the ring with `SYS_ENARX_RING`. The ring is allocated in
+//! untrusted memory, which the enclave can access without leaving it. The
+//! shim places writes to stdout and stderr (and their data) in the
+//! submission queue, where a thread of the host picks them up, executes them
+//! and places the replies in the completion queue, in the order of the
+//! requests.
+//!
+//! The ring only executes `write()` on descriptors 1 and 2, which it passes
+//! through the streams (see `crate::streams`) and the syscall policy of the
+//! keep, as the executor would. Everything else fails with `ENOSYS` and goes
+//! through the block. The ring is only offered to keeps which aren't traced,
+//! profiled, recorded or replayed (see `crate::backend::StdioRing`), since
+//! those see each syscall in the order of the block.
+//!
+//! The thread polls the ring for a while and then sleeps on `sq_tail`, after
+//! setting `NEED_WAKEUP` in `flags`. A shim which finds the flag set after
+//! a submission wakes it with `SYS_ENARX_RING(1)`.
+//!
+//! The layout must match the one used by the shim.
+
+use crate::backend::StdioRing;
+use crate::metrics::Metrics;
+use crate::policy::Guard;
+
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+use std::sync::atomic::{fence, AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use anyhow::{bail, Result};
+use sallyport::{Block, Reply, Request};
+use tracing::warn;
+
+/// The number of entries in each queue
+const ENTRIES: usize = 64;
+
+/// The size of the data of each submission
+const DATA: usize = 4096;
+
+/// The number of empty polls after which the thread goes to sleep
+const SPINS: u32 = 1 << 10;
+
+/// The longest the thread sleeps without being woken, in nanoseconds
+const SLEEP: i64 = 100_000_000;
+
+/// Set in `flags` while the thread sleeps
+const NEED_WAKEUP: u32 = 1;
+
+/// A request and a value which is passed on to its reply
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Submission {
+    user: u64,
+    req: Request,
+}
+
+/// A reply and the value of its request
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Completion {
+    user: u64,
+    rep: Reply,
+}
+
+/// The memory shared with the shim
+///
+/// The shim produces submissions at `sq_tail` and consumes completions at
+/// `cq_head`. The host does the opposite. The queues are indexed with their
+/// counters modulo `ENTRIES`. The data of submission `n` is `data[n]`.
+#[repr(C, align(4096))]
+struct Shared {
+    sq_head: AtomicU32,
+    sq_tail: AtomicU32,
+    cq_head: AtomicU32,
+    cq_tail: AtomicU32,
+    flags: AtomicU32,
+    sqes: [Submission; ENTRIES],
+    cqes: [Completion; ENTRIES],
+    data: [[u8; DATA]; ENTRIES],
+}
+
+/// A ring and the thread which polls it
+pub struct Ring {
+    shared: *mut Shared,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Ring {
+    /// Allocates the ring and starts polling it
+    ///
+    /// The writes go to the streams of `stdio`, as its policy allows, and
+    /// are counted in `metrics`.
+    pub fn new(stdio: StdioRing, metrics: Option<Metrics>) -> Result<Self> {
+        let shared = unsafe { alloc_zeroed(Layout::new::<Shared>()) }.cast::<Shared>();
+        if shared.is_null() {
+            bail!("unable to allocate the syscall ring");
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let addr = shared as usize;
+        let flag = stop.clone();
+        let thread = std::thread::spawn(move || unsafe {
+            poll(addr as *mut Shared, &flag, &stdio, metrics.as_ref())
+        });
+
+        Ok(Self {
+            shared,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Handles `SYS_ENARX_RING`
+    pub fn reply(&self) -> sallyport::Result {
+        Ok([
+            (self.shared as usize).into(),
+            Layout::new::<Shared>().size().into(),
+        ])
+    }
+
+    /// Wakes the thread, if it sleeps
+    pub fn wake(&self) {
+        let addr = unsafe { addr_of!((*self.shared).sq_tail) };
+        let op = libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG;
+        unsafe { libc::syscall(libc::SYS_futex, addr, op, 1) };
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.wake();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        unsafe { dealloc(self.shared.cast(), Layout::new::<Shared>()) };
+    }
+}
+
+/// Executes the submissions until `stop` is set
+///
+/// # Safety
+///
+/// `shared` must stay allocated until this function returns.
+unsafe fn poll(
+    shared: *mut Shared,
+    stop: &AtomicBool,
+    stdio: &StdioRing,
+    metrics: Option<&Metrics>,
+) {
+    let (sq_head, sq_tail) = (&(*shared).sq_head, &(*shared).sq_tail);
+    let (cq_head, cq_tail) = (&(*shared).cq_head, &(*shared).cq_tail);
+    let flags = &(*shared).flags;
+    let mut block = Box::new(Block::default());
+    let mut idle = 0;
+
+    while !stop.load(Ordering::Relaxed) {
+        let head = sq_head.load(Ordering::Relaxed);
+        if head == sq_tail.load(Ordering::Acquire) {
+            idle += 1;
+            if idle < SPINS {
+                std::hint::spin_loop();
+                continue;
+            }
+
+            // The shim checks the flag after it moves `sq_tail`, so one of
+            // the two sees what the other did.
+            flags.fetch_or(NEED_WAKEUP, Ordering::Relaxed);
+            fence(Ordering::SeqCst);
+            if sq_tail.load(Ordering::Relaxed) == head && !stop.load(Ordering::Relaxed) {
+                wait(sq_tail, head);
+            }
+            flags.fetch_and(!NEED_WAKEUP, Ordering::Relaxed);
+            idle = 0;
+            continue;
+        }
+
+        let full = cq_tail
+            .load(Ordering::Relaxed)
+            .wrapping_sub(cq_head.load(Ordering::Acquire));
+        if full as usize >= ENTRIES {
+            std::thread::yield_now();
+            continue;
+        }
+        idle = 0;
+
+        let sqe = read_volatile(addr_of!((*shared).sqes[head as usize % ENTRIES]));
+        let rep = execute(shared, &mut block, sqe.req, stdio, metrics).into();
+
+        let tail = cq_tail.load(Ordering::Relaxed);
+        let cqe = Completion {
+            user: sqe.user,
+            rep,
+        };
+        write_volatile(addr_of_mut!((*shared).cqes[tail as usize % ENTRIES]), cqe);
+        cq_tail.store(tail.wrapping_add(1), Ordering::Release);
+        sq_head.store(head.wrapping_add(1), Ordering::Release);
+    }
+}
+
+/// Sleeps until `word` is no longer `value`, the thread is woken or a while
+/// passed
+fn wait(word: &AtomicU32, value: u32) {
+    let timeout = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: SLEEP,
+    };
+
+    let op = libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG;
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word as *const AtomicU32,
+            op,
+            value,
+            &timeout,
+        )
+    };
+}
+
+/// Executes a submission, which must be a write to stdout or stderr of data
+/// in the ring
+///
+/// # Safety
+///
+/// `shared` must point to the ring.
+unsafe fn execute(
+    shared: *mut Shared,
+    block: &mut Block,
+    req: Request,
+    stdio: &StdioRing,
+    metrics: Option<&Metrics>,
+) -> sallyport::Result {
+    let num: i64 = req.num.into();
+    let fd = usize::from(req.arg[0]) as libc::c_int;
+    if num != libc::SYS_write || (fd != libc::STDOUT_FILENO && fd != libc::STDERR_FILENO) {
+        return Err(libc::ENOSYS);
+    }
+
+    // The shim copies the data into the ring, so nothing else is written.
+    let data = addr_of!((*shared).data) as usize;
+    let size = DATA * ENTRIES;
+    let (buf, len) = (usize::from(req.arg[1]), usize::from(req.arg[2]));
+    if buf < data || len > size || buf - data > size - len {
+        return Err(libc::EFAULT);
+    }
+
+    if let Some(metrics) = metrics {
+        metrics.syscall(num);
+    }
+
+    if let Err(e) = Guard::new(&stdio.policy).check(&req) {
+        warn!(syscall = "write", "denied by the syscall policy: {}", e);
+        return Err(libc::EPERM);
+    }
+
+    block.msg.req = req;
+    match stdio.streams.syscall(block) {
+        Some(ret) => ret,
+        None => block.msg.req.syscall().into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::policy::SyscallPolicy;
+    use crate::streams::Streams;
+
+    use std::io::Read;
+
+    /// Submits a request as the shim would
+    unsafe fn submit(ring: &Ring, user: u64, num: i64, args: &[usize]) {
+        let shared = ring.shared;
+        let tail = (*shared).sq_tail.load(Ordering::Relaxed);
+
+        let mut req = Request {
+            num: (num as usize).into(),
+            arg: [0usize.into(); 6],
+        };
+        for (arg, value) in req.arg.iter_mut().zip(args) {
+            *arg = (*value).into();
+        }
+
+        let sqe = Submission { user, req };
+        write_volatile(addr_of_mut!((*shared).sqes[tail as usize % ENTRIES]), sqe);
+        (*shared)
+            .sq_tail
+            .store(tail.wrapping_add(1), Ordering::Release);
+
+        fence(Ordering::SeqCst);
+        if (*shared).flags.load(Ordering::Relaxed) & NEED_WAKEUP != 0 {
+            ring.wake();
+        }
+    }
+
+    /// Waits for a completion as the shim would
+    unsafe fn reap(ring: &Ring) -> (u64, sallyport::Result) {
+        let shared = ring.shared;
+        let head = (*shared).cq_head.load(Ordering::Relaxed);
+        while (*shared).cq_tail.load(Ordering::Acquire) == head {
+            std::hint::spin_loop();
+        }
+
+        let cqe = read_volatile(addr_of!((*shared).cqes[head as usize % ENTRIES]));
+        (*shared)
+            .cq_head
+            .store(head.wrapping_add(1), Ordering::Release);
+        (cqe.user, cqe.rep.into())
+    }
+
+    /// A ring whose stdout goes to the returned pipe, which only allows
+    /// descriptor 1
+    fn ring() -> (Ring, std::fs::File) {
+        let mut streams = Streams::default();
+        let stdout = streams.pipe(libc::STDOUT_FILENO).unwrap();
+        let stdio = StdioRing {
+            streams: Arc::new(streams),
+            policy: SyscallPolicy::default().fds(&[libc::STDOUT_FILENO]),
+        };
+
+        (Ring::new(stdio, Some(Metrics::default())).unwrap(), stdout)
+    }
+
+    fn ok(r: sallyport::Result) -> Result<usize, libc::c_int> {
+        r.map(|r| usize::from(r[0]))
+    }
+
+    #[test]
+    fn execute() {
+        let (ring, mut stdout) = ring();
+        let shared = ring.shared;
+
+        let [addr, size] = ring.reply().unwrap();
+        assert_eq!(usize::from(addr), shared as usize);
+        assert_eq!(usize::from(size), Layout::new::<Shared>().size());
+
+        unsafe {
+            let data = addr_of_mut!((*shared).data[0]).cast::<u8>();
+            data.copy_from_nonoverlapping(b"hi\n".as_ptr(), 3);
+            let outside = b"no\n";
+
+            submit(&ring, 6, libc::SYS_write, &[1, data as usize, 3]);
+            submit(&ring, 7, libc::SYS_write, &[2, data as usize, 3]);
+            submit(&ring, 8, libc::SYS_write, &[1, outside.as_ptr() as _, 3]);
+            submit(&ring, 9, libc::SYS_close, &[1]);
+            submit(&ring, 10, libc::SYS_exit, &[0]);
+
+            let reaped = |user, ret| {
+                let (u, r) = reap(&ring);
+                assert_eq!((u, ok(r)), (user, ret));
+            };
+            reaped(6, Ok(3));
+            reaped(7, Err(libc::EPERM));
+            reaped(8, Err(libc::EFAULT));
+            reaped(9, Err(libc::ENOSYS));
+            reaped(10, Err(libc::ENOSYS));
+        }
+
+        // The streams are closed with the ring.
+        drop(ring);
+        let mut output = String::new();
+        stdout.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "hi\n");
+    }
+
+    #[test]
+    fn wake() {
+        let (ring, mut stdout) = ring();
+        let shared = ring.shared;
+
+        unsafe {
+            while (*shared).flags.load(Ordering::Relaxed) & NEED_WAKEUP == 0 {
+                std::thread::yield_now();
+            }
+
+            let data = addr_of_mut!((*shared).data[1]).cast::<u8>();
+            data.copy_from_nonoverlapping(b"up\n".as_ptr(), 3);
+            submit(&ring, 1, libc::SYS_write, &[1, data as usize, 3]);
+
+            let (user, ret) = reap(&ring);
+            assert_eq!((user, ok(ret)), (1, Ok(3)));
+        }
+
+        let mut output = [0; 3];
+        stdout.read_exact(&mut output).unwrap();
+        assert_eq!(&output, b"up\n");
+    }
+}
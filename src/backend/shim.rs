@@ -0,0 +1,208 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Where the shims come from
+//!
+//! With the `builtin-shims` feature (the default), `build.rs` builds the
+//! shims with their nightly toolchain and they are embedded in the loader.
+//! Without it, the loader builds without that toolchain and loads a shim
+//! when it is first needed, from the path or the `https://` URL in
+//! `ENARX_SHIM_SGX` or `ENARX_SHIM_SEV`. These are read when the loader runs
+//! and, as defaults, when it is built, so that packagers can point it at the
+//! shims which they ship. A shim named this way wins over the builtin one.
+//!
+//! `ENARX_SHIM_SGX_SHA256` and `ENARX_SHIM_SEV_SHA256` pin the SHA-256 hash
+//! of the shims (in hex), which they are checked against when they are
+//! loaded. Shims from URLs must be pinned; they are cached by their hash in
+//! `$XDG_CACHE_HOME/enarx-keepldr/shims` (or beneath `~/.cache`).
+
+use anyhow::{anyhow, bail, Result};
+use openssl::sha::sha256;
+
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+/// The largest shim which is downloaded
+#[cfg(feature = "ureq")]
+const MAX_SIZE: u64 = 64 << 20;
+
+/// The shim of a backend
+pub struct Shim {
+    name: &'static str,
+    var: &'static str,
+    builtin: Option<&'static [u8]>,
+
+    /// `var` and its pin, as they were when the loader was built
+    location: Option<&'static str>,
+    pin: Option<&'static str>,
+
+    /// The shim, once it is loaded
+    loaded: AtomicPtr<Vec<u8>>,
+}
+
+impl Shim {
+    /// Creates the shim `name`, which `var` may name the path or URL of
+    pub const fn new(
+        name: &'static str,
+        var: &'static str,
+        builtin: Option<&'static [u8]>,
+        location: Option<&'static str>,
+        pin: Option<&'static str>,
+    ) -> Self {
+        Self {
+            name,
+            var,
+            builtin,
+            location,
+            pin,
+            loaded: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    /// Returns the shim, loading it if need be
+    ///
+    /// A loaded shim lives as long as the process.
+    pub fn bytes(&self) -> Result<&'static [u8]> {
+        let loaded = self.loaded.load(Ordering::Acquire);
+        if !loaded.is_null() {
+            return Ok(unsafe { &*loaded });
+        }
+
+        let var = |var: &str, built: Option<&str>| {
+            std::env::var(var).ok().or_else(|| built.map(String::from))
+        };
+
+        let location = match (var(self.var, self.location), self.builtin) {
+            (Some(location), _) => location,
+            (None, Some(builtin)) => return Ok(builtin),
+            (None, None) => bail!(
+                "the loader was built without its shims; set {} to the path or the URL of `{}`",
+                self.var,
+                self.name
+            ),
+        };
+
+        let pin = var(&format!("{}_SHA256", self.var), self.pin)
+            .map(|pin| {
+                parse(&pin)
+                    .ok_or_else(|| anyhow!("invalid SHA-256 hash of `{}`: {}", self.name, pin))
+            })
+            .transpose()?;
+
+        let bytes = match location.starts_with("https://") {
+            true => {
+                let pin = pin.ok_or_else(|| {
+                    anyhow!("the hash of `{}` must be pinned to download it", self.name)
+                })?;
+                download(&location, &pin)?
+            }
+
+            false => std::fs::read(&location)
+                .map_err(|e| anyhow!("unable to read {}: {}", location, e))?,
+        };
+
+        if matches!(pin, Some(pin) if pin != sha256(&bytes)) {
+            bail!(
+                "`{}` in {} does not match its pinned hash",
+                self.name,
+                location
+            );
+        }
+
+        let new = Box::into_raw(Box::new(bytes));
+        match self
+            .loaded
+            .compare_exchange(null_mut(), new, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => Ok(unsafe { &*new }),
+
+            // Another thread loaded the shim first.
+            Err(old) => {
+                drop(unsafe { Box::from_raw(new) });
+                Ok(unsafe { &*old })
+            }
+        }
+    }
+}
+
+/// Parses a SHA-256 hash in hex
+fn parse(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut hash = [0; 32];
+    for (byte, pair) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+
+    Some(hash)
+}
+
+/// Reads a shim from the cache, or downloads it and stores it there
+#[cfg(feature = "ureq")]
+fn download(url: &str, pin: &[u8; 32]) -> Result<Vec<u8>> {
+    use std::io::Read;
+
+    let name: String = pin.iter().map(|b| format!("{:02x}", b)).collect();
+    let dir = super::cache_dir("shims");
+
+    let cached = dir
+        .as_ref()
+        .and_then(|dir| std::fs::read(dir.join(&name)).ok());
+    if let Some(bytes) = cached.filter(|bytes| sha256(bytes) == *pin) {
+        return Ok(bytes);
+    }
+
+    tracing::debug!("downloading {}", url);
+
+    let mut bytes = Vec::new();
+    ureq::get(url)
+        .call()
+        .map_err(|e| anyhow!("unable to download {}: {}", url, e))?
+        .into_reader()
+        .take(MAX_SIZE)
+        .read_to_end(&mut bytes)?;
+
+    // A keep can be launched without the cache, so it is only filled if it
+    // can be, and never with a partial entry.
+    if let Some(dir) = dir {
+        let path = dir.join(&name);
+        let tmp = path.with_extension(std::process::id().to_string());
+        let stored = std::fs::create_dir_all(&dir)
+            .and_then(|_| std::fs::write(&tmp, &bytes))
+            .and_then(|_| std::fs::rename(&tmp, &path));
+        if let Err(e) = stored {
+            tracing::debug!("unable to cache {}: {}", path.display(), e);
+            let _ = std::fs::remove_file(&tmp);
+        }
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(not(feature = "ureq"))]
+fn download(url: &str, _pin: &[u8; 32]) -> Result<Vec<u8>> {
+    bail!(
+        "unable to download {}: the loader was built without `ureq`",
+        url
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pinned() {
+        let hash = sha256(b"shim");
+        let hex: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(parse(&hex), Some(hash));
+        assert_eq!(parse(&hex.to_uppercase()), Some(hash));
+
+        assert_eq!(parse(&hex[1..]), None);
+        assert_eq!(parse(&format!("{}0", hex)), None);
+        assert_eq!(parse(&hex.replace(&hex[..1], "g")), None);
+        assert_eq!(parse(&format!("+{}", &hex[1..])), None);
+    }
+}
@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Backtraces of payloads killed by faults
+//!
+//! Before a fault kills the payload, the shims of debug keeps report the
+//! address of the fault and the return addresses on the stack of the payload
+//! with `SYS_ENARX_BACKTRACE`. They follow the frame pointers, so payloads
+//! built without them (`-fno-omit-frame-pointer` or
+//! `-C force-frame-pointers=yes`) only report the address of the fault. The loader looks
+//! the addresses up in the symbol table of the payload and prints them to
+//! stderr, relative to the start of the payload, where `addr2line` finds the
+//! lines of stripped or optimized code in the unstripped binary. Names are
+//! printed as they are in the symbol table, so Rust names are still mangled.
+//!
+//! The shims of VM-based keeps stop them on exceptions of the payload before
+//! they can report anything, so their payloads are inspected with core files
+//! instead (see the `coredump` module).
+
+use enarx_syscall::SYS_ENARX_BACKTRACE;
+use goblin::elf::sym::STT_FUNC;
+use goblin::elf::Elf;
+use primordial::Register;
+use sallyport::Block;
+
+use std::fmt::Write;
+use std::mem::size_of;
+
+/// The functions of a payload
+#[derive(Clone, Debug, Default)]
+pub struct Symbols(Vec<(u64, u64, String)>);
+
+impl Symbols {
+    /// Reads the symbol table of a payload
+    ///
+    /// Payloads without one, such as stripped ones, have no symbols.
+    pub fn new(bytes: &[u8]) -> Self {
+        let elf = match Elf::parse(bytes) {
+            Ok(elf) => elf,
+            Err(_) => return Self::default(),
+        };
+
+        let mut symbols: Vec<_> = elf
+            .syms
+            .iter()
+            .filter(|sym| sym.st_type() == STT_FUNC && sym.st_value != 0)
+            .filter_map(|sym| {
+                let name = elf.strtab.get_at(sym.st_name)?;
+                Some((sym.st_value, sym.st_size, name.to_string()))
+            })
+            .collect();
+
+        symbols.sort();
+        Self(symbols)
+    }
+
+    /// Finds the function at an address of the payload, and the offset of
+    /// the address in it
+    pub fn lookup(&self, addr: u64) -> Option<(&str, u64)> {
+        let index = match self.0.binary_search_by(|(start, ..)| start.cmp(&addr)) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+
+        let (start, size, name) = &self.0[index];
+        match addr - start {
+            offset if offset < *size || *size == 0 => Some((name, offset)),
+            _ => None,
+        }
+    }
+
+    /// Formats a backtrace of addresses relative to the start of the payload
+    pub fn format(&self, signal: i32, addrs: &[u64]) -> String {
+        let mut out = format!("backtrace of the payload, killed by signal {}:\n", signal);
+        for (i, addr) in addrs.iter().enumerate() {
+            // Return addresses point after the call, maybe past the caller.
+            let at = match i {
+                0 => *addr,
+                _ => addr.wrapping_sub(1),
+            };
+
+            let _ = match self.lookup(at) {
+                Some((name, offset)) => {
+                    let offset = offset + addr.wrapping_sub(at);
+                    writeln!(out, "{:>4}: {:#018x} {}+{:#x}", i, addr, name, offset)
+                }
+                None => writeln!(out, "{:>4}: {:#018x} ??", i, addr),
+            };
+        }
+        out
+    }
+}
+
+/// Handles `SYS_ENARX_BACKTRACE`
+pub fn syscall(block: &mut Block, symbols: &Symbols) -> sallyport::Result {
+    let req = unsafe { block.msg.req };
+    let signal = usize::from(req.arg[0]) as i32;
+    let count = usize::from(req.arg[1]);
+    let base = usize::from(req.arg[2]) as u64;
+    if count > block.buf.len() / size_of::<u64>() {
+        return Err(libc::EINVAL);
+    }
+
+    let addrs: Vec<_> = block.buf[..count * size_of::<u64>()]
+        .chunks_exact(size_of::<u64>())
+        .map(|chunk| {
+            let mut bytes = [0; size_of::<u64>()];
+            bytes.copy_from_slice(chunk);
+            u64::from_ne_bytes(bytes).wrapping_sub(base)
+        })
+        .collect();
+
+    eprint!("{}", symbols.format(signal, &addrs));
+    Ok([Register::default(), Register::default()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The symbols of the test itself
+    fn symbols() -> Symbols {
+        Symbols::new(&std::fs::read(std::env::current_exe().unwrap()).unwrap())
+    }
+
+    #[test]
+    fn lookup() {
+        let symbols = symbols();
+        let (start, _, name) = symbols.0.iter().find(|(_, size, _)| *size > 1).unwrap();
+
+        assert_eq!(symbols.lookup(*start), Some((name.as_str(), 0)));
+        assert_eq!(symbols.lookup(start + 1), Some((name.as_str(), 1)));
+        assert_eq!(symbols.lookup(0), None);
+        assert!(Symbols::new(b"not an ELF binary").0.is_empty());
+    }
+
+    #[test]
+    fn format() {
+        let symbols = Symbols(vec![(0x1000, 0x10, "main".into())]);
+        let out = symbols.format(libc::SIGFPE, &[0x1004, 0x1010, 0x2000]);
+        let lines: Vec<_> = out.lines().collect();
+
+        assert_eq!(lines[0], "backtrace of the payload, killed by signal 8:");
+        assert_eq!(lines[1], "   0: 0x0000000000001004 main+0x4");
+        assert_eq!(lines[2], "   1: 0x0000000000001010 main+0x10");
+        assert_eq!(lines[3], "   2: 0x0000000000002000 ??");
+    }
+
+    #[test]
+    fn syscall() {
+        let mut block = Block::default();
+        block.buf[..8].copy_from_slice(&0x5004u64.to_ne_bytes());
+        let req = unsafe { &mut block.msg.req };
+        req.num = (SYS_ENARX_BACKTRACE as usize).into();
+        req.arg[0] = (libc::SIGSEGV as usize).into();
+        req.arg[1] = 1.into();
+        req.arg[2] = 0x4000.into();
+        assert!(super::syscall(&mut block, &Symbols::default()).is_ok());
+
+        let req = unsafe { &mut block.msg.req };
+        req.arg[1] = usize::MAX.into();
+        assert!(matches!(
+            super::syscall(&mut block, &Symbols::default()),
+            Err(libc::EINVAL)
+        ));
+    }
+}
@@ -0,0 +1,110 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Batches of syscalls
+//!
+//! Every request costs an exit from the keep. With `SYS_ENARX_BATCH`, a shim
+//! sends several requests in one round trip: they are placed at the start of
+//! the block, one after another, followed by their data. The requests are
+//! executed in order and each one is replaced by its reply.
+//!
+//! Only Linux syscalls can be batched, except for `exit()` and
+//! `exit_group()`. All other requests are answered with `ENOSYS`.
+
+use std::mem::size_of;
+use std::ptr::{read_unaligned, write_unaligned};
+
+use enarx_syscall::SYS_ENARX_BATCH;
+use sallyport::{Block, Reply, Request};
+
+/// Handles `SYS_ENARX_BATCH`
+///
+/// Each request is passed to `execute` in `block.msg`, where it leaves the
+/// reply.
+pub fn syscall(block: &mut Block, mut execute: impl FnMut(&mut Block)) -> sallyport::Result {
+    let count: usize = unsafe { block.msg.req.arg[0].into() };
+    if count > block.buf.len() / size_of::<Request>() {
+        return Err(libc::EINVAL);
+    }
+
+    for i in 0..count {
+        let slot = |block: &mut Block| unsafe { block.buf.as_mut_ptr().cast::<Request>().add(i) };
+        let req = unsafe { read_unaligned(slot(block)) };
+        let num: i64 = req.num.into();
+
+        let rep = match num {
+            libc::SYS_exit | libc::SYS_exit_group => Reply::from(Err(libc::ENOSYS)),
+            0..=512 => {
+                block.msg.req = req;
+                execute(block);
+                unsafe { block.msg.rep }
+            }
+            _ => Reply::from(Err(libc::ENOSYS)),
+        };
+
+        unsafe { write_unaligned(slot(block).cast::<Reply>(), rep) };
+    }
+
+    Ok([count.into(), 0.into()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(num: i64, arg: usize) -> Request {
+        let mut req = Request {
+            num: (num as usize).into(),
+            arg: [0usize.into(); 6],
+        };
+
+        req.arg[0] = arg.into();
+        req
+    }
+
+    fn reply(block: &Block, i: usize) -> sallyport::Result {
+        let slot = unsafe { block.buf.as_ptr().cast::<Request>().add(i) };
+        unsafe { read_unaligned(slot.cast::<Reply>()) }.into()
+    }
+
+    #[test]
+    fn execute() {
+        let mut block = Block::default();
+        let requests = [
+            libc::SYS_getpid,
+            libc::SYS_exit_group,
+            0xEA20,
+            libc::SYS_write,
+        ];
+        for (i, num) in requests.iter().enumerate() {
+            let slot = unsafe { block.buf.as_mut_ptr().cast::<Request>().add(i) };
+            unsafe { write_unaligned(slot, request(*num, 0)) };
+        }
+
+        block.msg.req = request(SYS_ENARX_BATCH, requests.len());
+
+        let mut executed = Vec::new();
+        let ret = syscall(&mut block, |block| {
+            let num: usize = unsafe { block.msg.req.num.into() };
+            executed.push(num as i64);
+            block.msg.rep = Reply::from(Ok([num.into(), 0.into()]));
+        });
+
+        assert_eq!(ret.map(|r| usize::from(r[0])), Ok(4));
+        assert_eq!(executed, [libc::SYS_getpid, libc::SYS_write]);
+
+        let num = |i| reply(&block, i).map(|r| usize::from(r[0]) as i64);
+        assert_eq!(num(0), Ok(libc::SYS_getpid));
+        assert_eq!(num(1), Err(libc::ENOSYS));
+        assert_eq!(num(2), Err(libc::ENOSYS));
+        assert_eq!(num(3), Ok(libc::SYS_write));
+    }
+
+    #[test]
+    fn oversized() {
+        let mut block = Block::default();
+        block.msg.req = request(SYS_ENARX_BATCH, usize::MAX);
+
+        let ret = syscall(&mut block, |_| panic!("executed"));
+        assert_eq!(ret.map(|_| ()), Err(libc::EINVAL));
+    }
+}
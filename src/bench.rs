@@ -0,0 +1,162 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Benchmarking keeps
+//!
+//! A `Bench` is a keep of `tests/bin/bench.c`, a payload which reads commands
+//! from stdin and requests from the host what they say. It measures:
+//!
+//!   * how long the keep takes to start: from the start of its build until
+//!     the payload asks for its first command;
+//!   * how many syscalls per second pass through sallyport, as round trips
+//!     into the keep and back for `close(-1)`, which the host executes;
+//!   * how many bytes per second the payload writes to the host, in writes
+//!     of a MiB, which the shims split into blocks.
+//!
+//! The criterion benchmarks in `benches/` and `enarx-keepldr bench` are built
+//! on it. The output of the payload goes to `/dev/null`.
+
+use crate::backend::{Backend, Command, Config, Thread};
+use crate::binary::Component;
+use crate::environ::Environ;
+use crate::executor::Executor;
+use crate::mount::Mounts;
+use crate::policy::SyscallPolicy;
+use crate::streams::Streams;
+
+use anyhow::{anyhow, bail, Result};
+use sallyport::Block;
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// The payload of the keeps
+const PAYLOAD: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/bin/bench"));
+
+/// Has the payload request `close(-1)`, as often as the count says
+const SYSCALLS: u64 = 0;
+
+/// Has the payload write as many bytes as the count says
+const WRITE: u64 = 1;
+
+/// A keep which runs the benchmarks
+pub struct Bench {
+    thread: Box<dyn Thread>,
+
+    /// The stdin of the payload
+    commands: File,
+
+    environ: Environ,
+    mounts: Mounts,
+    streams: Streams,
+    policy: SyscallPolicy,
+
+    startup: Duration,
+}
+
+impl Bench {
+    /// Builds a keep with the backend and runs it until the payload waits for
+    /// a command
+    ///
+    /// Only one nil keep can be built in a process.
+    pub fn new(backend: &dyn Backend, config: &Config) -> Result<Self> {
+        let mut streams = Streams::default();
+        let commands = streams.pipe(libc::STDIN_FILENO)?;
+        streams.create(libc::STDOUT_FILENO, Path::new("/dev/null"))?;
+
+        // Backends without a shim, such as nil, are given the payload instead.
+        let shim = match backend.shim()? {
+            [] => Component::from_bytes(PAYLOAD)?,
+            shim => Component::from_bytes(shim)?,
+        };
+        let code = Component::from_bytes(PAYLOAD)?;
+
+        let start = Instant::now();
+        let keep = backend.build(shim, code, config)?;
+        let thread = keep
+            .spawn()?
+            .ok_or_else(|| anyhow!("the keep has no thread"))?;
+
+        let mut bench = Self {
+            thread,
+            commands,
+            environ: Environ::new(vec![], vec![])?,
+            mounts: Mounts::new(vec![])?,
+            streams,
+            policy: SyscallPolicy::default(),
+            startup: Duration::default(),
+        };
+
+        bench.run(false)?;
+        bench.startup = start.elapsed();
+        Ok(bench)
+    }
+
+    /// How long the keep took from the start of its build until the payload
+    /// asked for its first command
+    pub fn startup(&self) -> Duration {
+        self.startup
+    }
+
+    /// Has the payload request `count` syscalls from the host, one after the
+    /// other, and returns how long they took
+    pub fn syscalls(&mut self, count: u64) -> Result<Duration> {
+        self.command(SYSCALLS, count)
+    }
+
+    /// Has the payload write `bytes` to the host, and returns how long it took
+    pub fn write(&mut self, bytes: u64) -> Result<Duration> {
+        self.command(WRITE, bytes)
+    }
+
+    fn command(&mut self, op: u64, count: u64) -> Result<Duration> {
+        let mut command = [0; 16];
+        command[..8].copy_from_slice(&op.to_ne_bytes());
+        command[8..].copy_from_slice(&count.to_ne_bytes());
+        self.commands.write_all(&command)?;
+
+        let start = Instant::now();
+        self.run(true)?;
+        Ok(start.elapsed())
+    }
+
+    /// Enters the keep and executes its syscalls until the payload asks for
+    /// a command
+    ///
+    /// If `command` is set, the first request is answered with the command in
+    /// the pipe. The one which follows fails with `EAGAIN`, so that the
+    /// payload asks again the next time the keep is entered.
+    fn run(&mut self, mut command: bool) -> Result<()> {
+        let mut host = Executor::new(&self.environ, &self.mounts, &self.streams, &self.policy);
+
+        loop {
+            match self.thread.enter()? {
+                Command::SysCall(block) if waits(block) && !command => {
+                    block.msg.rep = sallyport::Result::Err(libc::EAGAIN).into();
+                    return Ok(());
+                }
+
+                Command::SysCall(block) => {
+                    command &= !waits(block);
+                    host.syscall(block);
+
+                    if let Some(exit) = host.exited() {
+                        bail!("the payload exited: {}", exit);
+                    }
+                }
+
+                Command::Continue => (),
+                _ => bail!("the keep stopped"),
+            }
+        }
+    }
+}
+
+/// Whether the payload asks for its next command
+fn waits(block: &Block) -> bool {
+    let req = unsafe { block.msg.req };
+    let num: i64 = req.num.into();
+
+    num == libc::SYS_read && usize::from(req.arg[0]) == libc::STDIN_FILENO as usize
+}
@@ -1,8 +1,11 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::Result;
+use crate::backend::BuildError;
+
+use anyhow::{bail, Result};
 use goblin::elf::{header::*, note::NoteIterator, program_header::*, Elf};
 
+use std::num::NonZeroUsize;
 use std::ops::Range;
 
 /// The sallyport program header type
@@ -12,6 +15,27 @@ pub const PT_ENARX_SALLYPORT: u32 = PT_LOOS + 0x34a0001;
 /// The enarx code program header type
 pub const PT_ENARX_CODE: u32 = PT_LOOS + 0x34a0003;
 
+/// The enarx heap program header type
+///
+/// This segment reserves address space for the shim heap. Its pages are
+/// added at load time according to the configured heap size.
+#[cfg(feature = "backend-sgx")]
+pub const PT_ENARX_HEAP: u32 = PT_LOOS + 0x34a0004;
+
+/// The enarx guard program header type
+///
+/// This segment reserves address space which is never mapped, such as the
+/// guard pages below the stack of each thread.
+#[cfg(feature = "backend-sgx")]
+pub const PT_ENARX_GUARD: u32 = PT_LOOS + 0x34a0005;
+
+/// The enarx dynamic memory program header type
+///
+/// This segment reserves address space to which the shim adds pages at
+/// runtime with SGX2. The host only changes pages inside it for the shim.
+#[cfg(feature = "backend-sgx")]
+pub const PT_ENARX_EDMM: u32 = PT_LOOS + 0x34a0006;
+
 /// This segment contains TCS pages.
 #[cfg(feature = "backend-sgx")]
 pub const PF_ENARX_SGX_TCS: u32 = 1 << 20;
@@ -28,8 +52,51 @@ pub const NOTE_ENARX_SGX_SIZE: u32 = 0x73677800;
 #[cfg(feature = "backend-sgx")]
 pub const NOTE_ENARX_SGX_SSAP: u32 = 0x73677801;
 
+/// This note indicates the default heap size (u64; in bytes)
+#[cfg(feature = "backend-sgx")]
+pub const NOTE_ENARX_SGX_HEAP: u32 = 0x73677802;
+
+/// This note indicates the number of sallyport blocks per thread (u32)
+#[cfg(feature = "backend-sgx")]
+pub const NOTE_ENARX_SGX_BLKS: u32 = 0x73677803;
+
+/// This note indicates the sallyport ABI of a shim (u32, u32)
+///
+/// These are the version of the ABI and the size of a block in bytes, which
+/// must match those of the loader (see `backend::SallyportAbi`).
+pub const NOTE_ENARX_SALLYPORT_ABI: u32 = 0x73707900;
+
+/// This note requests a heap size for the payload (u64; in bytes)
+pub const NOTE_ENARX_HEAP: u32 = 0x70617900;
+
+/// This note indicates the stack size of the payload (u64; in bytes)
+///
+/// In a payload, it is the size which the payload needs; in a shim, it is
+/// the size which the shim gives the payload.
+pub const NOTE_ENARX_STACK: u32 = 0x70617901;
+
+/// This note requests a number of threads (vCPUs) for the payload (u32)
+pub const NOTE_ENARX_THREADS: u32 = 0x70617902;
+
+/// The resources which a payload requests with notes
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Requests {
+    /// The heap size in bytes (`NOTE_ENARX_HEAP`)
+    pub heap: Option<usize>,
+
+    /// The stack size in bytes (`NOTE_ENARX_STACK`)
+    pub stack: Option<usize>,
+
+    /// The number of threads (`NOTE_ENARX_THREADS`)
+    pub threads: Option<NonZeroUsize>,
+}
+
+/// An ELF binary which is loaded into a keep: a shim or a payload
 pub struct Component<'a> {
+    /// The contents of the file
     pub bytes: &'a [u8],
+
+    /// The parsed headers
     pub elf: Elf<'a>,
 }
 
@@ -39,39 +106,70 @@ impl<'a> Component<'a> {
         let bytes = bytes.as_ref();
 
         // Parse the file.
-        let elf = Elf::parse(bytes).unwrap();
+        let elf = Elf::parse(bytes).map_err(|e| BuildError::InvalidElf {
+            reason: e.to_string(),
+        })?;
 
         // Validate identity assumptions.
-        assert_eq!(elf.header.e_ident[EI_CLASS], ELFCLASS64);
-        assert_eq!(elf.header.e_ident[EI_DATA], ELFDATA2LSB);
-        assert_eq!(elf.header.e_ident[EI_VERSION], EV_CURRENT);
+        let ident = &elf.header.e_ident;
+        if ident[EI_CLASS] != ELFCLASS64 {
+            let class = ident[EI_CLASS];
+            return Err(BuildError::UnsupportedClass { class }.into());
+        }
+
+        if ident[EI_DATA] != ELFDATA2LSB {
+            let data = ident[EI_DATA];
+            return Err(BuildError::UnsupportedEncoding { data }.into());
+        }
+
+        if ident[EI_VERSION] != EV_CURRENT {
+            let version = ident[EI_VERSION].into();
+            return Err(BuildError::UnsupportedVersion { version }.into());
+        }
 
         // Validate header assumptions.
-        assert_eq!(elf.header.e_machine, EM_X86_64);
-        assert_eq!(elf.header.e_version, EV_CURRENT as _);
+        if elf.header.e_machine != EM_X86_64 {
+            let machine = elf.header.e_machine;
+            return Err(BuildError::UnsupportedMachine { machine }.into());
+        }
 
-        // Validate that there is no interpreter.
-        assert!(!elf
-            .program_headers
-            .iter()
-            .fold(false, |a, ph| a | (ph.p_type == PT_INTERP)));
+        if elf.header.e_version != EV_CURRENT.into() {
+            let version = elf.header.e_version;
+            return Err(BuildError::UnsupportedVersion { version }.into());
+        }
+
+        // Validate that the binary is linked statically (see `binary`).
+        if elf.program_headers.iter().any(|ph| ph.p_type == PT_INTERP) {
+            bail!(
+                "dynamically linked binaries are not supported (interpreter: {}); \
+                 link the payload statically (e.g. with `-static-pie`)",
+                elf.interpreter.unwrap_or("unknown")
+            );
+        }
+
+        if !elf.libraries.is_empty() {
+            bail!(
+                "binaries which need shared objects are not supported ({}); \
+                 link the payload statically (e.g. with `-static-pie`)",
+                elf.libraries.join(", ")
+            );
+        }
 
         // Validate that the entry point is in one of the loaded sections.
-        assert_eq!(
-            1,
-            elf.program_headers
-                .iter()
-                .filter(|ph| {
-                    ph.p_type == PT_LOAD
-                        && elf.header.e_entry >= ph.p_vaddr
-                        && elf.header.e_entry < ph.p_vaddr + ph.p_memsz
-                })
-                .count()
-        );
+        let entry = elf.header.e_entry;
+        let loaded = elf
+            .program_headers
+            .iter()
+            .filter(|ph| ph.p_type == PT_LOAD && ph.vm_range().contains(&(entry as usize)))
+            .count();
+        if loaded != 1 {
+            return Err(BuildError::EntryNotLoaded { entry }.into());
+        }
 
         Ok(Self { bytes, elf })
     }
 
+    /// The program headers of a type
     pub fn filter_header(&self, type_: u32) -> impl Iterator<Item = &ProgramHeader> {
         self.elf
             .program_headers
@@ -79,6 +177,7 @@ impl<'a> Component<'a> {
             .filter(move |ph| ph.p_type == type_)
     }
 
+    /// The first program header of a type
     pub fn find_header(&self, type_: u32) -> Option<&ProgramHeader> {
         self.elf
             .program_headers
@@ -86,6 +185,7 @@ impl<'a> Component<'a> {
             .find(|ph| ph.p_type == type_)
     }
 
+    /// The descriptors of the notes with a name and type
     pub fn filter_notes(&'a self, name: &'a str, kind: u32) -> impl Iterator<Item = &'a [u8]> {
         let empty = NoteIterator {
             iters: vec![],
@@ -117,7 +217,10 @@ impl<'a> Component<'a> {
     }
 
     /// Read a note from the note section
-    #[cfg(feature = "backend-sgx")]
+    ///
+    /// # Safety
+    ///
+    /// The note must hold a valid `T`.
     pub unsafe fn read_note<T: Copy>(&self, name: &str, kind: u32) -> Result<Option<T>> {
         use core::mem::size_of;
 
@@ -129,4 +232,64 @@ impl<'a> Component<'a> {
 
         Ok(None)
     }
+
+    /// The resources which the payload requests with notes
+    pub fn requests(&self) -> Result<Requests> {
+        let heap = unsafe { self.read_note::<u64>("enarx", NOTE_ENARX_HEAP)? };
+        let stack = unsafe { self.read_note::<u64>("enarx", NOTE_ENARX_STACK)? };
+        let threads = unsafe { self.read_note::<u32>("enarx", NOTE_ENARX_THREADS)? };
+
+        let threads = match threads {
+            Some(0) => bail!("the payload requests no threads"),
+            threads => threads.and_then(|n| NonZeroUsize::new(n as usize)),
+        };
+
+        Ok(Requests {
+            heap: heap.map(|n| n as usize),
+            stack: stack.map(|n| n as usize),
+            threads,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dynamic() {
+        // The test itself is linked dynamically, unless it was built with
+        // `crt-static`.
+        let bytes = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let elf = Elf::parse(&bytes).unwrap();
+        if elf.interpreter.is_none() && elf.libraries.is_empty() {
+            return;
+        }
+
+        let error = Component::from_bytes(&bytes).err().unwrap();
+        assert!(error.to_string().contains("link the payload statically"));
+    }
+
+    #[test]
+    fn invalid() {
+        let error = Component::from_bytes(b"garbage, not an ELF binary")
+            .err()
+            .unwrap();
+        let error = error.downcast_ref::<BuildError>().unwrap();
+        assert!(matches!(error, BuildError::InvalidElf { .. }), "{}", error);
+    }
+
+    #[test]
+    fn elf32() {
+        // An ELF32 header for i386 without any program or section headers
+        let mut bytes = vec![0u8; 52];
+        bytes[..7].copy_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1, 1]);
+        bytes[16..20].copy_from_slice(&[2, 0, 3, 0]); // e_type, e_machine
+        bytes[20] = 1; // e_version
+        bytes[40] = 52; // e_ehsize
+
+        let error = Component::from_bytes(&bytes).err().unwrap();
+        let error = error.downcast_ref::<BuildError>().unwrap();
+        assert_eq!(error, &BuildError::UnsupportedClass { class: ELFCLASS32 });
+    }
 }
@@ -1,5 +1,41 @@
 // SPDX-License-Identifier: Apache-2.0
 
+//! The ELF binaries which keeps are built from: the shim and the payload
+//!
+//! The shims load the payload into a code slot (`PT_ENARX_CODE`) of a fixed
+//! size: 4 MiB for `kvm` and `sev`, and about 124 MiB for `sgx`. A payload
+//! which doesn't fit is rejected with the size it needs. `ENARX_CODE_SIZE`
+//! sets the size of the slot when the shims are built (in bytes, or with a
+//! `K`, `M` or `G` suffix). The `sgx` shim rounds it up to a multiple of 128
+//! MiB, and its heap and code slot must fit in the enclave together, which
+//! leaves at most about 500 MiB; the slot of the `kvm` and `sev` shims can be
+//! up to about 2 GiB. The measurements of `sgx` and `sev` keeps change with
+//! the size of the slot.
+//!
+//! Keeps only run statically linked payloads, preferably static PIEs, which
+//! the shims load at a random address. A payload with an interpreter
+//! (`PT_INTERP`) or which needs shared objects (`DT_NEEDED`) is rejected
+//! before the keep is built. Running it would take more than loading
+//! `ld.so` and the objects into the code slot: the dynamic linker opens and
+//! maps the objects itself, and neither shim maps files, or anonymous memory
+//! at a fixed address on `kvm` and `sev`. Such payloads have to be relinked,
+//! e.g. with `musl-gcc -static-pie`. `enarx-keepldr validate` reports how a
+//! payload is linked.
+//!
+//! A payload can declare the resources it needs with notes named `enarx`
+//! (`NOTE_ENARX_HEAP`, `NOTE_ENARX_STACK` and `NOTE_ENARX_THREADS`), instead
+//! of leaving it to the user to pass the right options, which win over the
+//! notes. In Rust, they can be declared with `noted`:
+//!
+//! ```text
+//! noted::noted! {
+//!     static HEAP<"enarx", 0x70617900>: u64 = 512 << 20;
+//!     static THREADS<"enarx", 0x70617902>: u32 = 4;
+//! }
+//! ```
+
 mod component;
+mod validate;
 
 pub use component::*;
+pub use validate::*;
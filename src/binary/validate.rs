@@ -0,0 +1,357 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Checks whether a payload can run in a keep
+//!
+//! Keeps load payloads without a dynamic linker into a single process, so a
+//! payload must be a static PIE which relocates itself, doesn't import
+//! symbols and uses a TLS model which needs no dynamic linker. Its stack
+//! must not be executable, and it must not make syscalls which keeps never
+//! support, such as `execve()`. The syscalls of the payload are found by
+//! scanning its code for `syscall` instructions after a constant number, so
+//! syscalls which are made differently are missed.
+//!
+//! Each failed check explains how to fix it, and `enarx-keepldr validate`
+//! exits with status 1.
+
+use crate::backend::Datum;
+use crate::trace;
+
+use goblin::elf::header::*;
+use goblin::elf::program_header::*;
+use goblin::elf::reloc::*;
+use goblin::elf::sym::STB_GLOBAL;
+use goblin::elf::Elf;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The syscalls which keeps never support: they run a single process
+const UNSUPPORTED: &[i64] = &[
+    libc::SYS_fork,
+    libc::SYS_vfork,
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+];
+
+/// The relocations which a static PIE applies to itself
+const SELF_RELOCATIONS: &[u32] = &[R_X86_64_NONE, R_X86_64_RELATIVE, R_X86_64_IRELATIVE];
+
+/// The relocations of TLS models which need a dynamic linker
+const TLS_RELOCATIONS: &[u32] = &[
+    R_X86_64_DTPMOD64,
+    R_X86_64_DTPOFF64,
+    R_X86_64_TPOFF64,
+    R_X86_64_TLSDESC,
+];
+
+/// How many names a datum lists at most
+const NAMES: usize = 5;
+
+fn datum(name: &str, pass: bool, info: impl Into<String>, mesg: Option<&str>) -> Datum {
+    Datum {
+        name: name.into(),
+        pass,
+        info: Some(info.into()),
+        mesg: match pass {
+            true => None,
+            false => mesg.map(Into::into),
+        },
+    }
+}
+
+/// Lists some names of a set, and how many were left out
+fn list<T: AsRef<str>>(names: impl IntoIterator<Item = T>) -> String {
+    let names: Vec<_> = names.into_iter().collect();
+    let mut list = names
+        .iter()
+        .take(NAMES)
+        .map(AsRef::as_ref)
+        .collect::<Vec<_>>()
+        .join(", ");
+    if names.len() > NAMES {
+        list += &format!(" and {} more", names.len() - NAMES);
+    }
+    list
+}
+
+/// Checks a payload, which must be an ELF binary
+pub fn validate(bytes: &[u8]) -> Vec<Datum> {
+    let elf = match Elf::parse(bytes) {
+        Ok(elf) => elf,
+        Err(e) => return vec![datum("ELF", false, e.to_string(), None)],
+    };
+
+    let ident = &elf.header.e_ident;
+    let x86_64 = ident[EI_CLASS] == ELFCLASS64
+        && ident[EI_DATA] == ELFDATA2LSB
+        && elf.header.e_machine == EM_X86_64;
+    let mut data = vec![datum(
+        "ELF",
+        x86_64,
+        match x86_64 {
+            true => "x86_64".into(),
+            false => machine_to_str(elf.header.e_machine).to_lowercase(),
+        },
+        Some("Keeps only run 64-bit x86 binaries."),
+    )];
+    if !x86_64 {
+        return data;
+    }
+
+    data.push(linkage(&elf));
+    data.push(entry(&elf));
+    data.push(relocations(&elf));
+    data.push(imports(&elf));
+    data.push(tls(&elf));
+    data.push(stack(&elf));
+    data.push(syscalls(&elf, bytes));
+    data
+}
+
+fn linkage(elf: &Elf) -> Datum {
+    let (pass, info) = match (elf.interpreter, elf.libraries.is_empty(), elf.header.e_type) {
+        (Some(interp), _, _) => (
+            false,
+            format!("dynamically linked (interpreter: {})", interp),
+        ),
+        (None, false, _) => (false, format!("needs {}", list(&elf.libraries))),
+        (None, true, ET_DYN) => (true, "static PIE".into()),
+        (None, true, _) => (false, "not position-independent".into()),
+    };
+
+    datum(
+        "Static PIE",
+        pass,
+        info,
+        Some("Keeps load payloads without a dynamic linker. Link the payload with `-static-pie`."),
+    )
+}
+
+fn entry(elf: &Elf) -> Datum {
+    let entry = elf.header.e_entry;
+    let loads = elf.program_headers.iter().filter(|ph| {
+        ph.p_type == PT_LOAD
+            && entry >= ph.p_vaddr
+            && entry - ph.p_vaddr < ph.p_memsz
+            && ph.p_flags & PF_X != 0
+    });
+
+    datum(
+        "Entry Point",
+        loads.count() == 1,
+        format!("{:#x}", entry),
+        Some("The entry point must be in one executable segment."),
+    )
+}
+
+/// The dynamic relocations of a binary by type
+fn types(elf: &Elf) -> BTreeMap<u32, usize> {
+    let mut types = BTreeMap::new();
+    let relocs = elf.dynrelas.iter().chain(elf.dynrels.iter());
+    for reloc in relocs.chain(elf.pltrelocs.iter()) {
+        *types.entry(reloc.r_type).or_default() += 1;
+    }
+    types
+}
+
+fn relocations(elf: &Elf) -> Datum {
+    let types = types(elf);
+    let forbidden: Vec<_> = types
+        .iter()
+        .filter(|(t, _)| !SELF_RELOCATIONS.contains(t) && !TLS_RELOCATIONS.contains(t))
+        .map(|(t, n)| format!("{} x{}", r_to_str(*t, EM_X86_64), n))
+        .collect();
+
+    let count: usize = types.values().sum();
+    let info = match forbidden.is_empty() {
+        true => format!("{} applied by the payload itself", count),
+        false => format!("needs a dynamic linker for {}", list(&forbidden)),
+    };
+
+    datum(
+        "Dynamic Relocations",
+        forbidden.is_empty(),
+        info,
+        Some(
+            "Only relative relocations can be applied without a dynamic linker. \
+             Link the payload with `-static-pie` against a libc which supports it.",
+        ),
+    )
+}
+
+fn imports(elf: &Elf) -> Datum {
+    let names: BTreeSet<_> = elf
+        .dynsyms
+        .iter()
+        .filter(|sym| sym.st_bind() == STB_GLOBAL && sym.st_shndx == 0)
+        .filter_map(|sym| elf.dynstrtab.get_at(sym.st_name))
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    datum(
+        "Imported Symbols",
+        names.is_empty(),
+        match names.is_empty() {
+            true => "none".into(),
+            false => list(&names),
+        },
+        Some("Nothing provides the symbols which the payload imports. Link it statically."),
+    )
+}
+
+fn tls(elf: &Elf) -> Datum {
+    let size = elf
+        .program_headers
+        .iter()
+        .find(|ph| ph.p_type == PT_TLS)
+        .map(|ph| ph.p_memsz);
+    let dynamic: Vec<_> = types(elf)
+        .into_iter()
+        .filter(|(t, _)| TLS_RELOCATIONS.contains(t))
+        .map(|(t, _)| r_to_str(t, EM_X86_64))
+        .collect();
+
+    let info = match (size, dynamic.is_empty()) {
+        (None, _) => "none".into(),
+        (Some(size), true) => format!("{} bytes, local-exec", size),
+        (Some(size), false) => format!("{} bytes, needs {}", size, list(&dynamic)),
+    };
+
+    datum(
+        "TLS",
+        dynamic.is_empty(),
+        info,
+        Some(
+            "The TLS model of the payload needs a dynamic linker. \
+             Compile it with `-ftls-model=local-exec`.",
+        ),
+    )
+}
+
+fn stack(elf: &Elf) -> Datum {
+    let stack = elf
+        .program_headers
+        .iter()
+        .find(|ph| ph.p_type == PT_GNU_STACK);
+    let executable = !matches!(stack, Some(ph) if ph.p_flags & PF_X == 0);
+    let size = match stack.map(|ph| ph.p_memsz) {
+        None | Some(0) => "default size".into(),
+        Some(size) => format!("{} bytes requested", size),
+    };
+
+    datum(
+        "Stack",
+        !executable,
+        match executable {
+            true => format!("executable, {}", size),
+            false => size,
+        },
+        Some("Keeps don't execute the stack. Link the payload with `-z noexecstack`."),
+    )
+}
+
+fn syscalls(elf: &Elf, bytes: &[u8]) -> Datum {
+    let mut found = BTreeSet::new();
+    for ph in elf.program_headers.iter() {
+        if ph.p_type != PT_LOAD || ph.p_flags & PF_X == 0 {
+            continue;
+        }
+
+        let start = ph.p_offset as usize;
+        let end = start.saturating_add(ph.p_filesz as usize).min(bytes.len());
+        found.extend(scan(bytes.get(start..end).unwrap_or_default()));
+    }
+
+    let unsupported: Vec<_> = found
+        .iter()
+        .filter(|num| UNSUPPORTED.contains(num))
+        .map(|num| trace::name(*num))
+        .collect();
+
+    datum(
+        "Syscalls",
+        unsupported.is_empty(),
+        match unsupported.is_empty() {
+            true => format!("{} found", found.len()),
+            false => format!("{} found, unsupported: {}", found.len(), list(&unsupported)),
+        },
+        Some(
+            "Keeps run a single process, so the payload can't create processes, \
+             execute programs or trace them.",
+        ),
+    )
+}
+
+/// Finds the syscalls which code makes with a constant number
+///
+/// Those are `syscall` instructions shortly after `mov $imm32, %eax` or
+/// `mov $imm32, %rax`.
+fn scan(code: &[u8]) -> BTreeSet<i64> {
+    const WINDOW: usize = 16;
+
+    let mut found = BTreeSet::new();
+    for end in 2..=code.len() {
+        if code[end - 2..end] != [0x0f, 0x05] {
+            continue;
+        }
+
+        let window = &code[(end - 2).saturating_sub(WINDOW)..end - 2];
+        let imm = |at: usize| {
+            u32::from_le_bytes([window[at], window[at + 1], window[at + 2], window[at + 3]])
+        };
+        let num = (0..window.len()).rev().find_map(|at| match &window[at..] {
+            [0xb8, _, _, _, _, ..] => Some(imm(at + 1)),
+            [0x48, 0xc7, 0xc0, _, _, _, _, ..] => Some(imm(at + 3)),
+            _ => None,
+        });
+
+        if let Some(num) = num.filter(|num| *num < 1024) {
+            found.insert(num as i64);
+        }
+    }
+
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan() {
+        // mov $0x3c, %eax; xor %edi, %edi; syscall
+        let exit = [0xb8, 0x3c, 0, 0, 0, 0x31, 0xff, 0x0f, 0x05];
+        assert_eq!(
+            super::scan(&exit),
+            [libc::SYS_exit].iter().copied().collect()
+        );
+
+        // mov $0x39, %rax; syscall
+        let fork = [0x90, 0x48, 0xc7, 0xc0, 0x39, 0, 0, 0, 0x0f, 0x05];
+        assert_eq!(
+            super::scan(&fork),
+            [libc::SYS_fork].iter().copied().collect()
+        );
+
+        // A number which isn't a syscall, and a syscall without a number
+        let other = [0xb8, 0, 0, 1, 0, 0x0f, 0x05, 0x0f, 0x05];
+        assert!(super::scan(&other).is_empty());
+        assert!(super::scan(&[0x0f]).is_empty());
+    }
+
+    #[test]
+    fn invalid() {
+        let data = validate(b"not an ELF binary");
+        assert_eq!(data.len(), 1);
+        assert!(!data[0].pass);
+    }
+
+    #[test]
+    fn host() {
+        // The test itself is an x86_64 binary, however it is linked.
+        let bytes = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let data = validate(&bytes);
+        assert!(data[0].pass);
+        assert_eq!(data.len(), 8);
+    }
+}
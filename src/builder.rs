@@ -0,0 +1,850 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Building and running keeps
+//!
+//! `KeepBuilder` gathers what a keep is built from and how the host serves
+//! it, and `KeepBuilder::spawn()` builds the keep and runs it. The main
+//! thread of the keep runs on the calling thread, which executes the
+//! syscalls that the payload requests from the host; the other threads of
+//! the keep get OS threads of their own. The calling thread may be shared
+//! with other keeps (see the `scheduler` module).
+
+use crate::backend::interrupt::Interrupt;
+use crate::backend::{self, Backend, Command, Config, StdioRing};
+use crate::backtrace::Symbols;
+use crate::binary::Component;
+use crate::cgroup::{Cgroup, Limits};
+use crate::control::{Control, Event};
+use crate::coredump;
+use crate::deterministic::Deterministic;
+use crate::environ::Environ;
+use crate::executor::Executor;
+use crate::exit::Exit;
+use crate::gdb::{Gdb, Resume, SIGTRAP};
+use crate::isolation::Isolation;
+use crate::metrics::Metrics;
+use crate::mount::Mounts;
+use crate::policy::SyscallPolicy;
+use crate::profile::Profile;
+use crate::record::{Recorder, Replayer};
+use crate::registry::{KeepId, Registry, Status};
+use crate::sandbox;
+use crate::scheduler;
+use crate::shutdown;
+use crate::streams::Streams;
+use crate::watchdog::Watchdog;
+
+use anyhow::{anyhow, bail, Context, Result};
+use tracing::{debug, debug_span, info, info_span, warn};
+
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// What the code slot of a keep holds
+#[allow(clippy::large_enum_variant)]
+enum Code<'a> {
+    /// A payload
+    Payload(Component<'a>),
+
+    /// Nothing yet, in this many bytes
+    Empty(usize),
+
+    /// Whatever it held when the snapshot at this path was taken
+    Restore(&'a Path),
+}
+
+/// Builds a keep and runs it
+///
+/// Only the backend and the code are required; everything else defaults to
+/// what `enarx-keepldr exec` does without options.
+#[derive(Default)]
+pub struct KeepBuilder<'a> {
+    backend: Option<&'a dyn Backend>,
+    shim: Option<Component<'a>>,
+    code: Option<Code<'a>>,
+    config: Config,
+    environ: Option<Environ>,
+    mounts: Option<Mounts>,
+    streams: Streams,
+    control: Option<Control>,
+    gdb: Option<&'a str>,
+    trace: bool,
+    profile: bool,
+    usage: bool,
+    grace: Option<Duration>,
+    timeout: Option<Duration>,
+    stall: Option<Duration>,
+    sandbox: bool,
+    isolation: Isolation,
+    policy: SyscallPolicy,
+    limits: Limits,
+    registry: Option<(&'a Registry, KeepId)>,
+    seed: Option<u64>,
+    record: Option<&'a Path>,
+    replay: Option<&'a Path>,
+    core: Option<&'a Path>,
+}
+
+impl<'a> KeepBuilder<'a> {
+    /// Starts with the defaults
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the backend which builds the keep
+    pub fn backend(mut self, backend: &'a dyn Backend) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Replaces the builtin shim of the backend
+    ///
+    /// The shim is checked against the backend when the keep is built (see
+    /// `backend::check_shim()`).
+    pub fn shim(mut self, shim: Component<'a>) -> Self {
+        self.shim = Some(shim);
+        self
+    }
+
+    /// Sets the payload
+    pub fn code(mut self, code: Component<'a>) -> Self {
+        self.code = Some(Code::Payload(code));
+        self
+    }
+
+    /// Reserves an empty code slot of `size` bytes instead of loading a
+    /// payload, which the guest owner delivers (see `Backend::build_empty()`)
+    pub fn code_slot(mut self, size: usize) -> Self {
+        self.code = Some(Code::Empty(size));
+        self
+    }
+
+    /// Starts the keep from a snapshot instead of a payload
+    pub fn restore(mut self, path: &'a Path) -> Self {
+        self.code = Some(Code::Restore(path));
+        self
+    }
+
+    /// Sets the tunables of the backend
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Sets the arguments and environment of the payload
+    ///
+    /// The payload gets no arguments and `LANG=C` by default.
+    pub fn environ(mut self, environ: Environ) -> Self {
+        self.environ = Some(environ);
+        self
+    }
+
+    /// Exposes host directories to the keep
+    pub fn mounts(mut self, mounts: Mounts) -> Self {
+        self.mounts = Some(mounts);
+        self
+    }
+
+    /// Gives the standard streams of the payload host files of their own
+    ///
+    /// The payload shares the streams of the process by default.
+    pub fn streams(mut self, streams: Streams) -> Self {
+        self.streams = streams;
+        self
+    }
+
+    /// Restricts the syscalls which the host executes for the keep
+    pub fn policy(mut self, policy: SyscallPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Bounds the resources of the keep with a cgroup of its own
+    ///
+    /// The whole process moves to the cgroup until `spawn()` returns.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Records the keep in a registry under an ID from `Registry::add()`
+    pub fn registry(mut self, registry: &'a Registry, id: KeepId) -> Self {
+        self.registry = Some((registry, id));
+        self
+    }
+
+    /// Makes the time and the random numbers of the payload deterministic
+    ///
+    /// They come from a virtual source which `seed` starts, so that runs
+    /// with the same seed and input can be replayed. Only keeps which the
+    /// host can inspect, such as debug enclaves, use it (see the
+    /// `deterministic` module).
+    pub fn deterministic(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Records the syscalls which the host executes for the keep to a file
+    /// (see the `record` module)
+    pub fn record(mut self, path: &'a Path) -> Self {
+        self.record = Some(path);
+        self
+    }
+
+    /// Answers the syscalls of the keep from a recording instead of the host
+    /// OS (see the `record` module)
+    pub fn replay(mut self, path: &'a Path) -> Self {
+        self.replay = Some(path);
+        self
+    }
+
+    /// Writes a core file of the payload to this path when it crashes (see
+    /// the `coredump` module)
+    ///
+    /// Only debug keeps can leave core files.
+    pub fn core(mut self, path: &'a Path) -> Self {
+        self.core = Some(path);
+        self
+    }
+
+    /// Emits the lifecycle events of the keep on a control socket
+    pub fn control(mut self, control: Control) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    /// Waits for GDB to connect to this address before starting the keep
+    pub fn gdb(mut self, addr: &'a str) -> Self {
+        self.gdb = Some(addr);
+        self
+    }
+
+    /// Prints the syscalls which the keep requests from the host to stderr
+    pub fn trace(mut self, trace: bool) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Prints where the keep spent its time to stderr when the payload exits
+    pub fn profile(mut self, profile: bool) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Prints the memory which the keep used to stderr when the payload exits
+    ///
+    /// It is counted in the metrics of the keep, which are created if the
+    /// configuration has none.
+    pub fn usage(mut self, usage: bool) -> Self {
+        self.usage = usage;
+        self
+    }
+
+    /// Shuts the keep down in an orderly way on `SIGTERM` and `SIGINT`
+    ///
+    /// The handlers of these signals are replaced for the whole process. The
+    /// payload is asked to exit and gets `grace` to do so, before the process
+    /// is killed by the signal.
+    pub fn grace(mut self, grace: Duration) -> Self {
+        self.grace = Some(grace);
+        self
+    }
+
+    /// Stops the keep after it ran for `timeout`
+    ///
+    /// The thread inside the keep is interrupted, and the keep ends with
+    /// `Exit::TimedOut`. The process exits with `exit::TIMED_OUT` instead, if
+    /// the thread doesn't leave the keep in time.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Stops the keep when it hangs for `stall`
+    ///
+    /// The keep hangs when its main thread doesn't exit to the host, or when
+    /// the payload repeats a syscall which keeps failing in the same way. It
+    /// is stopped as for `timeout()`.
+    pub fn watchdog(mut self, stall: Duration) -> Self {
+        self.stall = Some(stall);
+        self
+    }
+
+    /// Confines the calling thread to the syscalls which are executed for
+    /// keeps before the keep runs, so that a compromised shim can't make
+    /// arbitrary syscalls on the host
+    ///
+    /// The calling thread stays confined after `spawn()` returns. Keeps on a
+    /// scheduler can't be sandboxed (see `scheduler`).
+    pub fn sandbox(mut self, sandbox: bool) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    /// Isolates the calling thread in new namespaces and switches the process
+    /// to an unprivileged user once the keep is built
+    ///
+    /// The calling thread stays isolated after `spawn()` returns.
+    pub fn isolation(mut self, isolation: Isolation) -> Self {
+        self.isolation = isolation;
+        self
+    }
+
+    /// Builds the keep and runs it until the payload exits
+    ///
+    /// Returns how the payload ended. The keep is shut down then (see
+    /// `Keep::shutdown()`), so that the other threads of the keep, which are
+    /// parked on their OS threads, halt and are joined. If one of them failed,
+    /// the keep fails with its error.
+    pub fn spawn(mut self) -> Result<Exit> {
+        let control = self.control.take();
+
+        let registry = self.registry;
+
+        let result = self.run(control.as_ref());
+        if let (Err(e), Some(control)) = (&result, &control) {
+            control.emit(Event::Fault {
+                details: format!("{:#}", e),
+            });
+        }
+
+        if let Some((registry, id)) = registry {
+            registry.update(id, |r| {
+                r.status = match &result {
+                    Ok(exit) => Status::Exited(*exit),
+                    Err(e) => Status::Failed(format!("{:#}", e)),
+                }
+            });
+            registry.stopped(id);
+        }
+
+        result
+    }
+
+    fn run(mut self, control: Option<&Control>) -> Result<Exit> {
+        let backend = self
+            .backend
+            .ok_or_else(|| anyhow!("no keep backend given"))?;
+        let code = self.code.ok_or_else(|| anyhow!("no payload given"))?;
+
+        if let Some((registry, id)) = self.registry {
+            registry.update(id, |r| {
+                r.status = Status::Building;
+                r.backend = Some(backend.name());
+            });
+        }
+
+        // Backends without a shim, such as nil, are given the payload instead.
+        let shim = match self.shim {
+            Some(shim) => Some(shim),
+            None => match backend.shim()? {
+                [] => None,
+                shim => Some(Component::from_bytes(shim)?),
+            },
+        };
+        let shimless = shim.is_none();
+        let shim = match (shim, &code) {
+            (Some(shim), _) => shim,
+            (None, Code::Payload(code)) => Component::from_bytes(code.bytes)?,
+            (None, _) => bail!("the {} backend can only run payloads", backend.name()),
+        };
+
+        if !shimless {
+            backend::check_shim(backend, &shim)?;
+        }
+
+        let environ = match self.environ {
+            Some(environ) => environ,
+            None => Environ::new(vec![], vec![])?,
+        };
+
+        let mounts = match self.mounts {
+            Some(mounts) => mounts,
+            None => Mounts::new(vec![])?,
+        };
+
+        let _cgroup = match self.limits.is_empty() {
+            true => None,
+            false => Some(Cgroup::enter(&self.limits)?),
+        };
+
+        if let Some(grace) = self.grace {
+            shutdown::install(grace)?;
+        }
+
+        if self.usage && self.config.metrics.is_none() {
+            self.config.metrics = Some(Metrics::default());
+        }
+
+        if self.core.is_some() && !self.config.debug {
+            bail!("only debug keeps can leave core files (--debug-keep)");
+        }
+
+        if scheduler::scheduled()
+            && (self.sandbox || self.isolation != Isolation::default() || self.gdb.is_some())
+        {
+            bail!("keeps on a scheduler can't be sandboxed, isolated or debugged");
+        }
+
+        if self.record.is_some() && self.replay.is_some() {
+            bail!("a keep can't record and replay its syscalls at once");
+        }
+        let recorder = self.record.map(Recorder::create).transpose()?;
+        let replayer = self.replay.map(Replayer::open).transpose()?;
+
+        // The syscall ring of SGX keeps writes the output past the executor,
+        // so it isn't offered to keeps whose syscalls are all looked at.
+        let streams = Arc::new(std::mem::take(&mut self.streams));
+        if !(self.trace || self.profile || recorder.is_some() || replayer.is_some()) {
+            self.config.ring = Some(StdioRing {
+                streams: streams.clone(),
+                policy: self.policy.clone(),
+            });
+        }
+
+        let watchdog = match (self.timeout, self.stall) {
+            (None, None) => None,
+            (timeout, stall) => Some(Watchdog::start(timeout, stall)),
+        };
+
+        run(
+            backend,
+            shim,
+            code,
+            &self.config,
+            &environ,
+            &mounts,
+            &streams,
+            control,
+            self.gdb,
+            self.trace,
+            self.profile,
+            self.usage,
+            watchdog.as_ref(),
+            self.sandbox,
+            &self.isolation,
+            &self.policy,
+            self.registry,
+            self.seed,
+            recorder,
+            replayer,
+            self.core,
+        )
+    }
+}
+
+/// Hands a stopped thread to GDB until it resumes
+///
+/// Returns `false` once GDB has detached.
+fn debug(gdb: &mut Gdb, thread: &mut dyn backend::Thread, signal: u8) -> Result<bool> {
+    let target = thread.debug().unwrap();
+
+    match gdb.stop(target, signal)? {
+        Resume::Continue | Resume::Step => Ok(true),
+        Resume::Detach => Ok(false),
+        Resume::Kill => bail!("the keep was killed by GDB"),
+    }
+}
+
+/// Writes a core file of a crashed payload, if one was asked for
+fn dump(
+    thread: &mut dyn backend::Thread,
+    path: Option<&Path>,
+    signal: i32,
+    payload: Option<&[u8]>,
+) {
+    let path = match path {
+        Some(path) => path,
+        None => return,
+    };
+
+    let result = match thread.dump() {
+        Some(dump) => coredump::write(path, dump, signal, payload),
+        None => Err(anyhow!("the keep can't be read")),
+    };
+
+    match result {
+        Ok(()) => info!(path = %path.display(), signal, "wrote a core file"),
+        Err(e) => warn!("unable to write a core file: {:#}", e),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run(
+    backend: &dyn Backend,
+    shim: Component,
+    code: Code,
+    config: &Config,
+    environ: &Environ,
+    mounts: &Mounts,
+    streams: &Streams,
+    control: Option<&Control>,
+    gdb: Option<&str>,
+    trace_syscalls: bool,
+    profile: bool,
+    usage: bool,
+    watchdog: Option<&Watchdog>,
+    sandboxed: bool,
+    isolation: &Isolation,
+    policy: &SyscallPolicy,
+    registry: Option<(&Registry, KeepId)>,
+    seed: Option<u64>,
+    recorder: Option<Recorder>,
+    replayer: Option<Replayer>,
+    core: Option<&Path>,
+) -> Result<Exit> {
+    let _keep = match registry {
+        Some((_, id)) => info_span!("keep", backend = backend.name(), %id),
+        None => info_span!("keep", backend = backend.name()),
+    }
+    .entered();
+
+    // GDB finds the payload in the core by its ELF header.
+    let payload = match &code {
+        Code::Payload(code) => Some(code.bytes),
+        _ => None,
+    };
+
+    let keep = info_span!("build").in_scope(|| match code {
+        Code::Payload(code) => backend.build(shim, code, config),
+        Code::Empty(size) => backend.build_empty(shim, size, config),
+        Code::Restore(path) => backend.restore(path, config),
+    })
+    .context("unable to build the keep")?;
+    if let Some(control) = control {
+        control.emit(Event::Built {
+            backend: backend.name(),
+        });
+    }
+
+    if let Some(measurement) = keep.measurement() {
+        let measurement: String = measurement.iter().map(|b| format!("{:02x}", b)).collect();
+        info!(%measurement, "measured the keep");
+        if let Some((registry, id)) = registry {
+            registry.update(id, |r| r.measurement = Some(measurement.clone()));
+        }
+        if let Some(control) = control {
+            control.emit(Event::Measured { measurement });
+        }
+    }
+
+    // Whichever way the keep ends, its memory is released at once instead of
+    // when its last thread is dropped, and its other threads are joined. The
+    // main thread is dropped before.
+    let (failed, failures) = mpsc::channel();
+    let mut teardown = Teardown {
+        keep: None,
+        threads: Vec::new(),
+        failures,
+        registry,
+    };
+
+    let mut thread = info_span!("spawn")
+        .in_scope(|| keep.clone().spawn())?
+        .unwrap();
+
+    // The watchdog, the shutdown, the registry and the other threads of the
+    // keep pull this thread out of the keep when they stop it.
+    let interrupt = Interrupt::current()?;
+
+    // The other threads, such as the application processors of VM-based
+    // keeps, run on their own until they halt. One which fails sends its
+    // error to this thread, which fails the keep with it.
+    let mut id = 1;
+    while let Some(other) = info_span!("spawn").in_scope(|| keep.clone().spawn())? {
+        let other = Detached(other);
+        let span = info_span!("thread", id);
+        let (failed, interrupt) = (failed.clone(), interrupt.clone());
+        let (parked, interruptible) = mpsc::sync_channel(1);
+        let thread = std::thread::spawn(move || {
+            let _thread = span.entered();
+            if let Err(e) = park(other, parked) {
+                let _ = failed.send(e.context(format!("thread {} of the keep failed", id)));
+                interrupt.interrupt();
+                interrupt.signal();
+            }
+        });
+
+        // A thread which fails before it parks can't be interrupted.
+        teardown.threads.push((thread, interruptible.recv().ok()));
+
+        id += 1;
+    }
+
+    teardown.keep = Some(keep);
+
+    let _thread = info_span!("thread", id = 0).entered();
+
+    let mut gdb = match gdb {
+        Some(_) if thread.debug().is_none() => {
+            bail!("the {} backend does not support debugging", backend.name())
+        }
+        Some(addr) => Some(Gdb::accept(addr)?),
+        None => None,
+    };
+
+    if core.is_some() && thread.dump().is_none() {
+        bail!("the {} backend can't write core files", backend.name());
+    }
+
+    if let Some(control) = control {
+        control.emit(Event::Launched);
+    }
+
+    let metrics = config.metrics.as_ref();
+    if let Some(metrics) = metrics {
+        metrics.launched();
+    }
+
+    if let Some((registry, id)) = registry {
+        registry.update(id, |r| {
+            r.status = Status::Running;
+            r.metrics = metrics.cloned();
+        });
+    }
+
+    let mut host = Executor {
+        deterministic: seed.map(Deterministic::new),
+        control,
+        metrics,
+        watchdog,
+        profile: match profile {
+            true => Some(Profile::default()),
+            false => None,
+        },
+        trace: trace_syscalls,
+        recorder,
+        replayer,
+        symbols: payload.map(Symbols::new).unwrap_or_default(),
+        usage,
+        ..Executor::new(environ, mounts, streams, policy)
+    };
+
+    if let Some(g) = gdb.as_mut() {
+        if !debug(g, &mut *thread, 0)? {
+            gdb = None;
+        }
+    }
+
+    if let Some(watchdog) = watchdog {
+        watchdog.interrupt(interrupt.clone());
+    }
+    shutdown::interrupt(&interrupt);
+    scheduler::interrupt(&interrupt);
+
+    if let Some((registry, id)) = registry {
+        if registry.interruptible(id, interrupt.clone()) {
+            host.exit(Exit::Signal(libc::SIGKILL));
+            return Ok(Exit::Signal(libc::SIGKILL));
+        }
+    }
+
+    isolation.apply()?;
+
+    if sandboxed {
+        sandbox::install()?;
+    }
+
+    loop {
+        let _enter = debug_span!("enter").entered();
+
+        if let Some(metrics) = metrics {
+            metrics.entered();
+        }
+
+        if let Some(watchdog) = watchdog {
+            watchdog.enter(thread.state());
+        }
+
+        let entered = Instant::now();
+        let inside = interrupt.enter();
+        let cmd = match thread.enter() {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                dump(&mut *thread, core, libc::SIGSEGV, payload);
+                return Err(e);
+            }
+        };
+        drop(inside);
+        if let Some(metrics) = metrics {
+            metrics.exited();
+        }
+
+        if let Some(watchdog) = watchdog {
+            watchdog.exited();
+        }
+
+        if let Some(profile) = host.profile.as_mut() {
+            let exit = match cmd {
+                Command::SysCall(_) => "syscall",
+                Command::Continue => "continue",
+                Command::Trap => "trap",
+                Command::Halt => "halt",
+                Command::Attacked => "attacked",
+            };
+
+            profile.entered(entered.elapsed(), exit);
+        }
+
+        match cmd {
+            Command::SysCall(block) => host.syscall(block),
+            Command::Continue => (),
+            Command::Trap => match gdb.as_mut() {
+                Some(g) => {
+                    if !debug(g, &mut *thread, SIGTRAP)? {
+                        gdb = None;
+                    }
+                }
+                None => bail!("the keep stopped without a debugger"),
+            },
+            Command::Halt => bail!("the main thread of the keep halted"),
+            // The shims of VM-based keeps stop them on exceptions of the
+            // payload, too.
+            Command::Attacked => {
+                dump(&mut *thread, core, libc::SIGSEGV, payload);
+                host.exit(Exit::Attacked)
+            }
+        }
+
+        // Shims which don't listen for shutdown requests can't be asked.
+        if let (Some(signal), false, None) = (shutdown::pending(), host.doorbell, host.exited) {
+            info!(signal, "stopping the keep");
+            host.exit(Exit::Signal(signal));
+        }
+
+        if let (Some(watchdog), None) = (watchdog, host.exited) {
+            if watchdog.expired() {
+                host.exit(Exit::TimedOut);
+            }
+        }
+
+        if let (Some((registry, id)), None) = (registry, host.exited) {
+            if registry.stopping(id) {
+                info!("stopping the keep");
+                host.exit(Exit::Signal(libc::SIGKILL));
+            }
+        }
+
+        if let Some(e) = host.failed.take() {
+            return Err(e);
+        }
+
+        if let Ok(e) = teardown.failures.try_recv() {
+            return Err(e);
+        }
+
+        if let Some(exit) = host.exited {
+            if let Exit::Signal(signal) = exit {
+                if coredump::dumps(signal) {
+                    dump(&mut *thread, core, signal, payload);
+                }
+            }
+
+            drop(thread);
+            teardown.finish()?;
+            return Ok(exit);
+        }
+
+        scheduler::checkpoint();
+    }
+}
+
+/// Shuts a keep down when it is dropped
+struct Teardown<'a> {
+    keep: Option<Arc<dyn backend::Keep>>,
+
+    /// The other threads of the keep, and how to pull them out of it
+    threads: Vec<(JoinHandle<()>, Option<Interrupt>)>,
+
+    /// The errors of the other threads of the keep
+    failures: mpsc::Receiver<anyhow::Error>,
+
+    registry: Option<(&'a Registry, KeepId)>,
+}
+
+impl Teardown<'_> {
+    /// Shuts the keep down and joins its other threads
+    ///
+    /// Returns the first error of the other threads. They are left to
+    /// themselves if the keep can't be shut down, since they may never halt.
+    fn finish(&mut self) -> Result<()> {
+        let usage = match self.keep.take().map(|keep| keep.shutdown()) {
+            Some(Ok(usage)) => usage,
+            Some(Err(e)) => {
+                warn!("unable to shut the keep down: {:#}", e);
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+
+        debug!(
+            memory = usage.memory,
+            fds = usage.fds,
+            threads = usage.threads,
+            "shut the keep down"
+        );
+
+        if let Some((registry, id)) = self.registry {
+            registry.update(id, |r| r.usage = Some(usage));
+        }
+
+        // Threads which are inside the keep halt once they leave it.
+        let mut panicked = false;
+        for (thread, interrupt) in self.threads.drain(..) {
+            if let Some(interrupt) = interrupt {
+                interrupt.interrupt();
+            }
+
+            panicked |= thread.join().is_err();
+        }
+
+        if panicked {
+            bail!("a thread of the keep panicked");
+        }
+
+        match self.failures.try_recv() {
+            Ok(e) => Err(e),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+impl Drop for Teardown<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            warn!("{:#}", e);
+        }
+    }
+}
+
+/// A thread of the keep which is moved to its own OS thread
+///
+/// It is only ever entered from that thread, and the state it shares with
+/// the other threads of the keep is behind locks.
+struct Detached(Box<dyn backend::Thread>);
+
+unsafe impl Send for Detached {}
+
+/// Runs a thread other than the main one until it halts
+///
+/// Only the main thread runs the payload, so the others can't request
+/// syscalls. The thread sends how to interrupt it to `owner` before it
+/// enters the keep.
+fn park(mut thread: Detached, owner: mpsc::SyncSender<Interrupt>) -> Result<()> {
+    let interrupt = Interrupt::current()?;
+    let _ = owner.send(interrupt.clone());
+
+    loop {
+        let inside = interrupt.enter();
+        let cmd = thread.0.enter();
+        drop(inside);
+
+        match cmd? {
+            Command::Continue => (),
+            Command::Halt => return Ok(()),
+            _ => bail!("a thread other than the main one stopped unexpectedly"),
+        }
+    }
+}
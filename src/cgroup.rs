@@ -0,0 +1,165 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bounding the resources of a keep with a cgroup
+//!
+//! With resource limits, the loader moves itself to a cgroup of its own
+//! before it builds the keep, so that the limits apply to everything which
+//! runs the keep: the vCPU threads, the threads which enter the enclave and
+//! the syscalls which the host executes for the keep. The cgroup is created
+//! next to the one the loader was started in, with the controllers it needs
+//! enabled in their parent, which requires the cgroup v2 hierarchy at
+//! `/sys/fs/cgroup` and the permission to write to it.
+//!
+//! The memory limit only applies to the memory of the host: SGX keeps live
+//! in the EPC, which the memory controller doesn't account. When the keep
+//! ends, the loader moves back and removes the cgroup.
+
+use anyhow::{anyhow, Result};
+use tracing::debug;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The root of the cgroup v2 hierarchy
+const ROOT: &str = "/sys/fs/cgroup";
+
+/// The period of the CPU quota, in microseconds
+const PERIOD: u64 = 100_000;
+
+/// The resource limits of a keep
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Limits {
+    /// How many CPUs the keep may use at most, e.g. `1.5`
+    pub cpus: Option<f64>,
+
+    /// How much memory of the host the keep may use at most, in bytes
+    pub memory: Option<usize>,
+
+    /// How many threads the keep may use at most
+    pub pids: Option<u64>,
+}
+
+impl Limits {
+    /// Whether there are no limits
+    pub fn is_empty(&self) -> bool {
+        self.cpus.is_none() && self.memory.is_none() && self.pids.is_none()
+    }
+
+    /// The controllers and the files which hold the limits, with their values
+    fn files(&self) -> Vec<(&'static str, &'static str, String)> {
+        let mut files = Vec::new();
+
+        if let Some(cpus) = self.cpus {
+            let quota = (cpus * PERIOD as f64).ceil() as u64;
+            files.push(("cpu", "cpu.max", format!("{} {}", quota, PERIOD)));
+        }
+
+        if let Some(memory) = self.memory {
+            files.push(("memory", "memory.max", memory.to_string()));
+        }
+
+        if let Some(pids) = self.pids {
+            files.push(("pids", "pids.max", pids.to_string()));
+        }
+
+        files
+    }
+}
+
+/// The cgroup which the loader moved to
+pub(crate) struct Cgroup {
+    path: PathBuf,
+    origin: PathBuf,
+}
+
+impl Cgroup {
+    /// Moves the loader to a new cgroup with the limits
+    pub fn enter(limits: &Limits) -> Result<Self> {
+        let text = fs::read_to_string("/proc/self/cgroup")?;
+        let own = unified(&text).ok_or_else(|| anyhow!("the cgroup v2 hierarchy is not used"))?;
+
+        let origin = Path::new(ROOT).join(own.trim_start_matches('/'));
+        let parent = match own {
+            "/" => origin.clone(),
+            _ => origin.parent().unwrap().to_owned(),
+        };
+
+        let files = limits.files();
+        let controllers: Vec<_> = files.iter().map(|(c, _, _)| format!("+{}", c)).collect();
+        write(
+            &parent.join("cgroup.subtree_control"),
+            &controllers.join(" "),
+        )?;
+
+        let path = parent.join(format!("enarx-keep-{}", std::process::id()));
+        fs::create_dir(&path).map_err(|e| anyhow!("unable to create {}: {}", path.display(), e))?;
+        let cgroup = Self { path, origin };
+
+        for (_, file, value) in &files {
+            write(&cgroup.path.join(file), value)?;
+        }
+
+        write(
+            &cgroup.path.join("cgroup.procs"),
+            &std::process::id().to_string(),
+        )?;
+
+        debug!(path = %cgroup.path.display(), "moved to the cgroup of the keep");
+        Ok(cgroup)
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        let procs = self.origin.join("cgroup.procs");
+        let result = write(&procs, &std::process::id().to_string())
+            .and_then(|_| fs::remove_dir(&self.path).map_err(Into::into));
+
+        if let Err(e) = result {
+            debug!("unable to remove {}: {:#}", self.path.display(), e);
+        }
+    }
+}
+
+/// Returns the path of the cgroup in the unified hierarchy, from the lines
+/// of `/proc/self/cgroup`
+fn unified(text: &str) -> Option<&str> {
+    text.lines().find_map(|line| line.strip_prefix("0::"))
+}
+
+fn write(path: &Path, value: &str) -> Result<()> {
+    fs::write(path, value).map_err(|e| anyhow!("unable to write {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits() {
+        assert!(Limits::default().is_empty());
+        assert!(Limits::default().files().is_empty());
+
+        let limits = Limits {
+            cpus: Some(1.5),
+            memory: Some(1 << 30),
+            pids: Some(64),
+        };
+
+        assert_eq!(
+            limits.files(),
+            [
+                ("cpu", "cpu.max", "150000 100000".into()),
+                ("memory", "memory.max", "1073741824".into()),
+                ("pids", "pids.max", "64".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn paths() {
+        let text = "12:cpuset:/\n0::/user.slice/session-1.scope\n";
+        assert_eq!(unified(text), Some("/user.slice/session-1.scope"));
+        assert_eq!(unified("1:name=systemd:/init.scope\n"), None);
+    }
+}
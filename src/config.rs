@@ -0,0 +1,376 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Keep configuration files (`Enarx.toml`)
+//!
+//! A configuration file holds the same settings as the `exec` options:
+//!
+//! ```toml
+//! code = "target/debug/app"
+//! backend = "sgx"
+//! shim = "shim-sgx"
+//! heap-size = "512M"
+//! cpus = 4
+//! memory = "1G"
+//! args = ["--verbose"]
+//! env = ["RUST_LOG=info", "TZ"]
+//! mounts = ["data:/data:ro", "state:/state:sealed"]
+//! secret = "secret.bin"
+//! control = "keep.sock"
+//! metrics = "127.0.0.1:9100"
+//! cpuid = ["0x7.0.ebx&=0xfffeffff"]
+//! allow-syscalls = ["read", "write", "openat", "close", "socket", "accept4"]
+//! allow-fds = [0, 1, 2]
+//! allow-families = ["inet", "inet6"]
+//! sgx-xfrm = 0xe7
+//! sgx-miscselect = 1
+//! sgx-prod-id = 1
+//! sgx-svn = 3
+//! sgx-lazy-heap = true
+//! sgx-overcommit = false
+//! sev-es = true
+//! sev-no-debug = true
+//! sev-no-key-sharing = true
+//! sev-min-firmware = "0.24"
+//! sev-owner = "owner.example.com:8443"
+//! sev-owner-ca = "owner-ca.pem"
+//! debug-keep = false
+//! ```
+//!
+//! Relative paths are resolved against the directory of the file, except for
+//! `secret = "-"`, which reads the secret from stdin. Options given on the
+//! command line take precedence, except for environment variables, mounts
+//! and CPUID rules, which are added to those of the file. The backend of the
+//! file takes precedence over `ENARX_BACKEND`.
+
+use enarx_keepldr::backend::{SevParameters, SgxParameters};
+use enarx_keepldr::cpuid::Rule;
+use enarx_keepldr::mount::Mount;
+use enarx_keepldr::policy::SyscallPolicy;
+
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+/// The file as it is written
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Raw {
+    code: Option<PathBuf>,
+    backend: Option<String>,
+    shim: Option<PathBuf>,
+    heap_size: Option<String>,
+    cpus: Option<NonZeroUsize>,
+    memory: Option<String>,
+    manifest: Option<PathBuf>,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: Vec<String>,
+    #[serde(default)]
+    mounts: Vec<String>,
+    secret: Option<PathBuf>,
+    stdout: Option<PathBuf>,
+    stderr: Option<PathBuf>,
+    control: Option<PathBuf>,
+    metrics: Option<String>,
+    #[serde(default)]
+    cpuid: Vec<String>,
+    allow_syscalls: Option<Vec<String>>,
+    allow_fds: Option<Vec<i32>>,
+    allow_families: Option<Vec<String>>,
+    sgx_xfrm: Option<u64>,
+    sgx_miscselect: Option<u32>,
+    sgx_prod_id: Option<u16>,
+    sgx_svn: Option<u16>,
+    #[serde(default)]
+    sgx_lazy_heap: bool,
+    #[serde(default)]
+    sgx_overcommit: bool,
+    #[serde(default)]
+    sev_es: bool,
+    #[serde(default)]
+    sev_no_debug: bool,
+    #[serde(default)]
+    sev_no_key_sharing: bool,
+    sev_min_firmware: Option<String>,
+    sev_owner: Option<String>,
+    sev_owner_ca: Option<PathBuf>,
+    #[serde(default)]
+    debug_keep: bool,
+}
+
+/// A validated configuration file
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ConfigFile {
+    /// The payload to run inside the keep
+    pub code: Option<PathBuf>,
+
+    /// The keep backend to use
+    pub backend: Option<String>,
+
+    /// The shim to use instead of the builtin one
+    pub shim: Option<PathBuf>,
+
+    /// The size of the shim heap
+    pub heap_size: Option<usize>,
+
+    /// The number of vCPUs
+    pub cpus: Option<NonZeroUsize>,
+
+    /// The most memory the keep may use
+    pub memory: Option<usize>,
+
+    /// The signed manifest of the payload (KVM and SEV only)
+    pub manifest: Option<PathBuf>,
+
+    /// The arguments of the payload
+    pub args: Vec<String>,
+
+    /// The environment variables of the payload
+    pub env: Vec<String>,
+
+    /// Host directories exposed to the keep
+    pub mounts: Vec<Mount>,
+
+    /// A file holding the secret to inject into the keep
+    pub secret: Option<PathBuf>,
+
+    /// The file which the stdout of the payload is written to
+    pub stdout: Option<PathBuf>,
+
+    /// The file which the stderr of the payload is written to
+    pub stderr: Option<PathBuf>,
+
+    /// The path of the control socket
+    pub control: Option<PathBuf>,
+
+    /// The address of the metrics endpoint
+    pub metrics: Option<String>,
+
+    /// Rules applied after the CPUID policy of the backend
+    pub cpuid: Vec<Rule>,
+
+    /// The syscalls which the host executes for the keep
+    pub policy: SyscallPolicy,
+
+    /// Overrides of the SGX launch parameters
+    pub sgx: SgxParameters,
+
+    /// Whether the keep may need more EPC than the platform has
+    pub overcommit: bool,
+
+    /// Overrides of the SEV launch parameters
+    pub sev: SevParameters,
+
+    /// The address of the SEV guest owner
+    pub sev_owner: Option<String>,
+
+    /// The CA certificates to verify the SEV guest owner with
+    pub sev_owner_ca: Option<PathBuf>,
+
+    /// Whether the keep is built to be debugged
+    pub debug_keep: bool,
+}
+
+impl ConfigFile {
+    /// Loads and validates a configuration file
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("unable to read {}: {}", path.display(), e))?;
+
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        Self::parse(&text, dir).map_err(|e| anyhow!("{}: {:#}", path.display(), e))
+    }
+
+    /// Parses a configuration file with relative paths beneath `dir`
+    fn parse(text: &str, dir: &Path) -> Result<Self> {
+        let raw: Raw = toml::from_str(text)?;
+
+        let mut mounts = Vec::new();
+        for mount in raw.mounts {
+            let mut mount = mount.parse::<Mount>()?;
+            mount.host = dir.join(&mount.host);
+            mounts.push(mount);
+        }
+
+        let heap_size = raw.heap_size.as_deref().map(crate::parse_size);
+        let memory = raw.memory.as_deref().map(crate::parse_size);
+        let cpuid = raw.cpuid.iter().map(|r| r.parse()).collect::<Result<_>>()?;
+        let min_firmware = raw.sev_min_firmware.as_deref().map(crate::parse_version);
+
+        let mut policy = SyscallPolicy::default();
+        if let Some(syscalls) = &raw.allow_syscalls {
+            policy = policy.syscalls(syscalls)?;
+        }
+        if let Some(fds) = &raw.allow_fds {
+            policy = policy.fds(fds);
+        }
+        if let Some(families) = &raw.allow_families {
+            policy = policy.families(families)?;
+        }
+
+        Ok(Self {
+            code: raw.code.map(|p| dir.join(p)),
+            backend: raw.backend,
+            shim: raw.shim.map(|p| dir.join(p)),
+            heap_size: heap_size.transpose()?,
+            cpus: raw.cpus,
+            memory: memory.transpose()?,
+            manifest: raw.manifest.map(|p| dir.join(p)),
+            args: raw.args,
+            env: raw.env,
+            mounts,
+            secret: raw.secret.map(|p| match p.as_os_str() == "-" {
+                true => p,
+                false => dir.join(p),
+            }),
+            stdout: raw.stdout.map(|p| dir.join(p)),
+            stderr: raw.stderr.map(|p| dir.join(p)),
+            control: raw.control.map(|p| dir.join(p)),
+            metrics: raw.metrics,
+            cpuid,
+            policy,
+            sgx: SgxParameters {
+                xfrm: raw.sgx_xfrm,
+                misc_select: raw.sgx_miscselect,
+                prod_id: raw.sgx_prod_id,
+                svn: raw.sgx_svn,
+                lazy_heap: raw.sgx_lazy_heap,
+            },
+            overcommit: raw.sgx_overcommit,
+            sev: SevParameters {
+                es: raw.sev_es,
+                no_debug: raw.sev_no_debug,
+                no_key_sharing: raw.sev_no_key_sharing,
+                min_firmware: min_firmware.transpose()?,
+            },
+            sev_owner: raw.sev_owner,
+            sev_owner_ca: raw.sev_owner_ca.map(|p| dir.join(p)),
+            debug_keep: raw.debug_keep,
+        })
+    }
+}
+
+/// The backend of the command line, of the file or of `ENARX_BACKEND`
+///
+/// The first one which is given is used.
+pub fn backend(cli: Option<String>, file: Option<String>, env: Option<String>) -> Option<String> {
+    cli.or(file).or(env)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let text = r#"
+            code = "app"
+            backend = "sgx"
+            shim = "/opt/shim-sgx"
+            heap-size = "2M"
+            cpus = 4
+            memory = "1G"
+            manifest = "app.manifest"
+            args = ["a", "b"]
+            mounts = ["data:/data:ro", "/abs:/abs"]
+            stdout = "out.log"
+            control = "/run/keep.sock"
+            metrics = "[::1]:9100"
+            cpuid = ["0x1.ecx|=0x80000000"]
+            allow-syscalls = ["read", "write", "1"]
+            allow-families = ["unix"]
+            sgx-xfrm = 0xe7
+            sgx-svn = 2
+            sgx-lazy-heap = true
+            sgx-overcommit = true
+            sev-es = true
+            sev-no-key-sharing = true
+            sev-min-firmware = "0.24"
+            sev-owner = "owner:8443"
+            sev-owner-ca = "ca.pem"
+            debug-keep = true
+        "#;
+
+        let file = ConfigFile::parse(text, Path::new("/etc/app")).unwrap();
+        assert_eq!(file.code, Some("/etc/app/app".into()));
+        assert_eq!(file.backend.as_deref(), Some("sgx"));
+        assert_eq!(file.shim, Some("/opt/shim-sgx".into()));
+        assert_eq!(file.heap_size, Some(2 << 20));
+        assert_eq!(file.cpus, NonZeroUsize::new(4));
+        assert_eq!(file.memory, Some(1 << 30));
+        assert_eq!(file.manifest, Some("/etc/app/app.manifest".into()));
+        assert_eq!(file.args, ["a", "b"]);
+        assert!(file.env.is_empty());
+        assert_eq!(file.mounts[0].host, Path::new("/etc/app/data"));
+        assert!(file.mounts[0].read_only);
+        assert_eq!(file.mounts[1].host, Path::new("/abs"));
+        assert_eq!(file.secret, None);
+        assert_eq!(file.stdout, Some("/etc/app/out.log".into()));
+        assert_eq!(file.stderr, None);
+        assert_eq!(file.control, Some("/run/keep.sock".into()));
+        assert_eq!(file.metrics.as_deref(), Some("[::1]:9100"));
+        assert_eq!(file.cpuid, ["0x1.ecx|=0x80000000".parse::<Rule>().unwrap()]);
+        assert_eq!(
+            file.policy.syscalls,
+            Some([libc::SYS_read, libc::SYS_write].iter().copied().collect())
+        );
+        assert_eq!(file.policy.fds, None);
+        assert_eq!(
+            file.policy.families,
+            Some(std::iter::once(libc::AF_UNIX).collect())
+        );
+        assert_eq!(file.sgx.xfrm, Some(0xe7));
+        assert_eq!(file.sgx.misc_select, None);
+        assert_eq!(file.sgx.svn, Some(2));
+        assert!(file.sgx.lazy_heap);
+        assert!(file.overcommit);
+        assert!(file.sev.es);
+        assert!(!file.sev.no_debug);
+        assert!(file.sev.no_key_sharing);
+        assert_eq!(file.sev.min_firmware, Some((0, 24)));
+        assert_eq!(file.sev_owner.as_deref(), Some("owner:8443"));
+        assert_eq!(file.sev_owner_ca, Some("/etc/app/ca.pem".into()));
+        assert!(file.debug_keep);
+    }
+
+    #[test]
+    fn secret() {
+        let dir = Path::new("/etc/app");
+        let file = ConfigFile::parse("secret = \"key\"", dir).unwrap();
+        assert_eq!(file.secret, Some("/etc/app/key".into()));
+
+        // `-` stands for stdin.
+        let file = ConfigFile::parse("secret = \"-\"", dir).unwrap();
+        assert_eq!(file.secret, Some("-".into()));
+    }
+
+    #[test]
+    fn precedence() {
+        let some = |name: &str| Some(name.to_string());
+        assert_eq!(backend(some("sgx"), some("kvm"), some("sev")), some("sgx"));
+        assert_eq!(backend(None, some("kvm"), some("sev")), some("kvm"));
+        assert_eq!(backend(None, None, some("sev")), some("sev"));
+        assert_eq!(backend(None, None, None), None);
+    }
+
+    #[test]
+    fn invalid() {
+        let dir = Path::new("");
+        assert_eq!(ConfigFile::parse("", dir).unwrap(), ConfigFile::default());
+        assert!(ConfigFile::parse("threads = 4", dir).is_err());
+        assert!(ConfigFile::parse("heap-size = 512", dir).is_err());
+        assert!(ConfigFile::parse("heap-size = \"lots\"", dir).is_err());
+        assert!(ConfigFile::parse("cpus = 0", dir).is_err());
+        assert!(ConfigFile::parse("memory = 512", dir).is_err());
+        assert!(ConfigFile::parse("sev-min-firmware = \"24\"", dir).is_err());
+        assert!(ConfigFile::parse("mounts = [\"data\"]", dir).is_err());
+        assert!(ConfigFile::parse("env = { A = \"1\" }", dir).is_err());
+        assert!(ConfigFile::parse("cpuid = [\"0x1.esi=0\"]", dir).is_err());
+        assert!(ConfigFile::parse("sgx-svn = 65536", dir).is_err());
+        assert!(ConfigFile::parse("allow-syscalls = [\"execve\"]", dir).is_err());
+        assert!(ConfigFile::parse("allow-families = [\"ipx\"]", dir).is_err());
+    }
+}
@@ -0,0 +1,300 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Keep lifecycle events for supervisors
+//!
+//! With `--control`, a Unix socket is bound at the given path. Every client
+//! that connects receives the events emitted so far and then each new event,
+//! as one JSON object per line:
+//!
+//! ```text
+//! {"event":"built","backend":"sev"}
+//! {"event":"measured","measurement":"5b4e...c1d0"}
+//! {"event":"launched"}
+//! {"event":"exited","code":0}
+//! ```
+//!
+//! The `measured` event is only emitted by backends which measure the keep
+//! when they launch it. A payload which kills itself with a signal emits a
+//! `killed` event instead of `exited`, a keep which the shim stops because
+//! the host attacked it emits `attacked`, and one which the watchdog stops
+//! emits `timed_out`. A keep which fails emits a `fault` event with the error.
+//! Clients which don't keep up with the events are disconnected.
+//!
+//! Only the latest `HISTORY` events are kept for the clients which connect
+//! later. They are replayed without holding up the keep, and a client which
+//! doesn't take them within `REPLAY_TIMEOUT` is disconnected.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+/// The most events which are replayed to new clients
+const HISTORY: usize = 64;
+
+/// How long a new client may take to receive the replayed events
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A keep lifecycle event
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// The keep was built by a backend
+    Built {
+        /// The name of the backend
+        backend: &'a str,
+    },
+
+    /// The platform measured the keep at launch
+    Measured {
+        /// The measurement, in hexadecimal
+        measurement: String,
+    },
+
+    /// The payload is about to run
+    Launched,
+
+    /// The payload exited
+    Exited {
+        /// The exit status of the payload
+        code: i32,
+    },
+
+    /// The payload was killed by a signal it sent itself
+    Killed {
+        /// The number of the signal
+        signal: i32,
+    },
+
+    /// The shim stopped the keep, because the host attacked it
+    Attacked,
+
+    /// The watchdog stopped the keep, because it ran for too long or hung
+    TimedOut,
+
+    /// The keep failed
+    Fault {
+        /// What went wrong
+        details: String,
+    },
+}
+
+impl Event<'_> {
+    /// Formats the event as a line of JSON
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// The latest events, each a line of JSON
+    history: VecDeque<String>,
+
+    /// The number of events emitted so far
+    emitted: usize,
+
+    clients: Vec<UnixStream>,
+}
+
+impl State {
+    /// Keeps an event for the clients which connect later
+    fn push(&mut self, line: String) {
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+
+        self.history.push_back(line);
+        self.emitted += 1;
+    }
+
+    /// The kept events which were emitted after the first `emitted` ones
+    fn since(&self, emitted: usize) -> impl Iterator<Item = &String> {
+        let missed = self.emitted - emitted;
+        self.history
+            .iter()
+            .skip(self.history.len().saturating_sub(missed))
+    }
+}
+
+/// The control socket of a keep
+pub struct Control {
+    path: PathBuf,
+    state: Arc<Mutex<State>>,
+}
+
+impl Control {
+    /// Binds the control socket and starts accepting clients
+    pub fn bind(path: &Path) -> Result<Self> {
+        let listener = UnixListener::bind(path)
+            .map_err(|e| anyhow!("unable to bind {}: {}", path.display(), e))?;
+
+        let state = Arc::new(Mutex::new(State::default()));
+        let shared = state.clone();
+        std::thread::spawn(move || {
+            for mut client in listener.incoming().flatten() {
+                // The history is replayed without the lock, so that a slow
+                // client doesn't block the events of the keep.
+                let (history, emitted) = {
+                    let state = shared.lock().unwrap();
+                    (state.history.clone(), state.emitted)
+                };
+
+                let replayed = client
+                    .set_write_timeout(Some(REPLAY_TIMEOUT))
+                    .and_then(|_| {
+                        history
+                            .iter()
+                            .try_for_each(|line| client.write_all(line.as_bytes()))
+                    })
+                    .and_then(|_| client.set_nonblocking(true));
+
+                if replayed.is_err() {
+                    continue;
+                }
+
+                // The events emitted meanwhile are sent like new ones.
+                let mut state = shared.lock().unwrap();
+                let caught_up = state
+                    .since(emitted)
+                    .try_for_each(|line| client.write_all(line.as_bytes()));
+
+                if caught_up.is_ok() {
+                    state.clients.push(client);
+                }
+            }
+        });
+
+        Ok(Self {
+            path: path.into(),
+            state,
+        })
+    }
+
+    /// Sends an event to all clients
+    pub fn emit(&self, event: Event<'_>) {
+        let mut line = event.to_json();
+        line.push('\n');
+
+        let mut state = self.state.lock().unwrap();
+        state
+            .clients
+            .retain(|mut client| client.write_all(line.as_bytes()).is_ok());
+        state.push(line);
+    }
+
+    /// Removes the control socket
+    ///
+    /// This is needed when the process ends without dropping the control
+    /// socket, as it does on the payload's `exit()`.
+    pub fn close(&self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn json() {
+        let built = Event::Built { backend: "kvm" };
+        assert_eq!(built.to_json(), r#"{"event":"built","backend":"kvm"}"#);
+
+        let measured = Event::Measured {
+            measurement: "00ff".into(),
+        };
+        assert_eq!(
+            measured.to_json(),
+            r#"{"event":"measured","measurement":"00ff"}"#
+        );
+
+        let exited = Event::Exited { code: -1 };
+        assert_eq!(exited.to_json(), r#"{"event":"exited","code":-1}"#);
+
+        let killed = Event::Killed { signal: 6 };
+        assert_eq!(killed.to_json(), r#"{"event":"killed","signal":6}"#);
+        assert_eq!(Event::Attacked.to_json(), r#"{"event":"attacked"}"#);
+        assert_eq!(Event::TimedOut.to_json(), r#"{"event":"timed_out"}"#);
+
+        let fault = Event::Fault {
+            details: "a \"b\"\n\\\u{1}".into(),
+        };
+        assert_eq!(
+            fault.to_json(),
+            r#"{"event":"fault","details":"a \"b\"\n\\\u0001"}"#
+        );
+    }
+
+    #[test]
+    fn replay() {
+        let dir = tempdir::TempDir::new("control").unwrap();
+        let path = dir.path().join("control.sock");
+        let control = Control::bind(&path).unwrap();
+
+        control.emit(Event::Built { backend: "kvm" });
+        let mut early = BufReader::new(UnixStream::connect(&path).unwrap());
+
+        let mut line = String::new();
+        early.read_line(&mut line).unwrap();
+        assert_eq!(line, "{\"event\":\"built\",\"backend\":\"kvm\"}\n");
+
+        control.emit(Event::Launched);
+        line.clear();
+        early.read_line(&mut line).unwrap();
+        assert_eq!(line, "{\"event\":\"launched\"}\n");
+
+        drop(control);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn history() {
+        let dir = tempdir::TempDir::new("control").unwrap();
+        let path = dir.path().join("control.sock");
+        let control = Control::bind(&path).unwrap();
+
+        for code in 0..=HISTORY as i32 {
+            control.emit(Event::Exited { code });
+        }
+
+        // Only the latest events are replayed.
+        let late = BufReader::new(UnixStream::connect(&path).unwrap());
+        let lines = late.lines().take(HISTORY).collect::<Result<Vec<_>, _>>();
+        let lines = lines.unwrap();
+        assert_eq!(lines[0], r#"{"event":"exited","code":1}"#);
+        assert_eq!(
+            lines[HISTORY - 1],
+            format!(r#"{{"event":"exited","code":{}}}"#, HISTORY)
+        );
+    }
+
+    #[test]
+    fn since() {
+        let mut state = State::default();
+        for line in 0..HISTORY + 2 {
+            state.push(line.to_string());
+        }
+
+        assert_eq!(state.history.len(), HISTORY);
+        assert_eq!(state.since(HISTORY + 2).count(), 0);
+        let missed = state.since(HISTORY).cloned().collect::<Vec<_>>();
+        assert_eq!(missed, [HISTORY.to_string(), (HISTORY + 1).to_string()]);
+
+        // Events which are no longer kept are skipped.
+        assert_eq!(state.since(0).count(), HISTORY);
+    }
+}
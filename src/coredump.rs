@@ -0,0 +1,352 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Core files of crashed payloads
+//!
+//! With `--core PATH`, a debug keep whose payload crashes leaves an ELF core
+//! file, which GDB loads along with the payload:
+//!
+//! ```text
+//! $ enarx-keepldr exec --debug-keep --core core ./app
+//! $ gdb ./app core
+//! ```
+//!
+//! A payload crashes when it is killed by a fatal signal, when the main
+//! thread fails (e.g. on a stack overflow of an SGX payload) and when the
+//! shim stops the keep, which is how the shims of VM-based keeps handle the
+//! exceptions of the payload. The core holds the registers of the main thread
+//! and the memory which the backend can read: the pages which the payload can
+//! access for KVM and SEV, found in the page tables of the guest, and the
+//! whole enclave for SGX.
+//!
+//! The payloads are position-independent, so the auxiliary vector of the core
+//! tells GDB where the payload was loaded.
+
+use crate::gdb::Registers;
+
+use anyhow::{bail, Result};
+use goblin::elf::header::{EM_X86_64, ET_CORE, ET_DYN};
+use goblin::elf::program_header::{PT_LOAD, PT_NOTE};
+use goblin::elf::Elf;
+
+use std::path::Path;
+
+/// The note holding the registers of a thread
+const NT_PRSTATUS: u32 = 1;
+
+/// The note holding the auxiliary vector
+const NT_AUXV: u32 = 6;
+
+/// The size of `struct elf_prstatus` on x86_64
+const PRSTATUS_SIZE: usize = 336;
+
+/// The offset of the registers (`pr_reg`) in `struct elf_prstatus`
+const PRSTATUS_REGS: usize = 112;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_ENTRY: u64 = 9;
+
+const PAGE: usize = 4096;
+
+/// Memory of a keep which goes into the core
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    /// The virtual address of the memory
+    pub start: u64,
+
+    /// The permissions of the memory (`PF_R`, `PF_W` and `PF_X`)
+    pub flags: u32,
+
+    /// The contents of the memory
+    pub data: Vec<u8>,
+}
+
+/// A crashed thread whose state the backend can read
+pub trait Dump {
+    /// Reads the registers of the payload on the thread
+    fn registers(&mut self) -> Result<Registers>;
+
+    /// Reads the memory of the keep which the payload may use
+    fn memory(&mut self) -> Result<Vec<Region>>;
+}
+
+/// Whether a signal leaves a core file, as its default action does on Linux
+pub fn dumps(signal: i32) -> bool {
+    matches!(
+        signal,
+        libc::SIGQUIT
+            | libc::SIGILL
+            | libc::SIGTRAP
+            | libc::SIGABRT
+            | libc::SIGBUS
+            | libc::SIGFPE
+            | libc::SIGSEGV
+            | libc::SIGXCPU
+            | libc::SIGXFSZ
+            | libc::SIGSYS
+    )
+}
+
+/// Adds a page to the regions, extending the last one if the page follows it
+pub fn push(regions: &mut Vec<Region>, addr: u64, flags: u32, page: &[u8]) {
+    match regions.last_mut() {
+        Some(last) if last.flags == flags && last.start + last.data.len() as u64 == addr => {
+            last.data.extend_from_slice(page)
+        }
+        _ => regions.push(Region {
+            start: addr,
+            flags,
+            data: page.to_vec(),
+        }),
+    }
+}
+
+/// Writes a core file of a crashed thread
+///
+/// `payload` is the payload which the keep was built from, if it was, so
+/// that GDB can find it in memory.
+pub fn write(path: &Path, dump: &mut dyn Dump, signal: i32, payload: Option<&[u8]>) -> Result<()> {
+    let registers = dump.registers()?;
+    let regions = dump.memory()?;
+    if regions.is_empty() {
+        bail!("the backend found no memory of the payload");
+    }
+
+    let auxv = payload.map(|p| auxv(p, &regions)).unwrap_or_default();
+    std::fs::write(path, core(&registers, &regions, signal, &auxv))?;
+    Ok(())
+}
+
+/// The auxiliary vector which GDB relocates the payload with
+///
+/// It is empty unless the ELF header of the payload is found at the start
+/// of a page.
+fn auxv(payload: &[u8], regions: &[Region]) -> Vec<(u64, u64)> {
+    let header = match Elf::parse_header(payload) {
+        Ok(header) if header.e_type == ET_DYN => header,
+        _ => return Vec::new(),
+    };
+    let size = usize::from(header.e_ehsize);
+
+    let base = regions.iter().find_map(|region| {
+        region
+            .data
+            .chunks(PAGE)
+            .position(|page| page.get(..size) == payload.get(..size))
+            .map(|index| region.start + (index * PAGE) as u64)
+    });
+
+    match base {
+        Some(base) => vec![
+            (AT_PHDR, base + header.e_phoff),
+            (AT_PHENT, header.e_phentsize.into()),
+            (AT_PHNUM, header.e_phnum.into()),
+            (AT_ENTRY, base + header.e_entry),
+            (AT_NULL, 0),
+        ],
+        None => Vec::new(),
+    }
+}
+
+fn page_align(size: usize) -> usize {
+    (size + PAGE - 1) & !(PAGE - 1)
+}
+
+/// Appends a note with the name `CORE`
+fn note(out: &mut Vec<u8>, kind: u32, desc: &[u8]) {
+    out.extend_from_slice(&5u32.to_le_bytes());
+    out.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(b"CORE\0\0\0\0");
+    out.extend_from_slice(desc);
+    out.resize((out.len() + 3) & !3, 0);
+}
+
+/// The `struct elf_prstatus` of a thread
+fn prstatus(registers: &Registers, signal: i32) -> Vec<u8> {
+    let [rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8, r9, r10, r11, r12, r13, r14, r15, rip] =
+        registers.gpr;
+    let eflags = u64::from(registers.eflags);
+
+    // The order of `struct user_regs_struct`, with `orig_rax`, the segment
+    // registers and their bases left out
+    let regs = [
+        r15, r14, r13, r12, rbp, rbx, r11, r10, r9, r8, rax, rcx, rdx, rsi, rdi, 0, rip, 0, eflags,
+        rsp,
+    ];
+
+    let mut status = vec![0; PRSTATUS_SIZE];
+    status[..4].copy_from_slice(&signal.to_le_bytes());
+    status[12..14].copy_from_slice(&(signal as u16).to_le_bytes());
+    for (reg, chunk) in regs.iter().zip(status[PRSTATUS_REGS..].chunks_exact_mut(8)) {
+        chunk.copy_from_slice(&reg.to_le_bytes());
+    }
+
+    status
+}
+
+/// Lays out a core file
+fn core(registers: &Registers, regions: &[Region], signal: i32, auxv: &[(u64, u64)]) -> Vec<u8> {
+    const EHDR: usize = 64;
+    const PHDR: usize = 56;
+
+    let mut notes = Vec::new();
+    note(&mut notes, NT_PRSTATUS, &prstatus(registers, signal));
+    if !auxv.is_empty() {
+        let bytes: Vec<u8> = auxv
+            .iter()
+            .flat_map(|(k, v)| {
+                k.to_le_bytes()
+                    .iter()
+                    .chain(&v.to_le_bytes())
+                    .copied()
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        note(&mut notes, NT_AUXV, &bytes);
+    }
+
+    let phnum = 1 + regions.len();
+    let notes_offset = EHDR + PHDR * phnum;
+    let mut offset = page_align(notes_offset + notes.len());
+
+    // The ELF header
+    let mut out = Vec::with_capacity(offset);
+    out.extend_from_slice(b"\x7fELF\x02\x01\x01");
+    out.resize(16, 0);
+    out.extend_from_slice(&ET_CORE.to_le_bytes());
+    out.extend_from_slice(&EM_X86_64.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes()); // e_version
+    out.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+    out.extend_from_slice(&(EHDR as u64).to_le_bytes()); // e_phoff
+    out.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    out.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    out.extend_from_slice(&(EHDR as u16).to_le_bytes());
+    out.extend_from_slice(&(PHDR as u16).to_le_bytes());
+    out.extend_from_slice(&(phnum as u16).to_le_bytes());
+    out.extend_from_slice(&[0; 6]); // no sections
+
+    let mut phdr = |type_: u32, flags: u32, offset: usize, vaddr: u64, size: usize, align: u64| {
+        out.extend_from_slice(&type_.to_le_bytes());
+        out.extend_from_slice(&flags.to_le_bytes());
+        out.extend_from_slice(&(offset as u64).to_le_bytes());
+        out.extend_from_slice(&vaddr.to_le_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes()); // p_paddr
+        out.extend_from_slice(&(size as u64).to_le_bytes()); // p_filesz
+        out.extend_from_slice(&(size as u64).to_le_bytes()); // p_memsz
+        out.extend_from_slice(&align.to_le_bytes());
+    };
+
+    phdr(PT_NOTE, 0, notes_offset, 0, notes.len(), 4);
+    for region in regions {
+        phdr(
+            PT_LOAD,
+            region.flags,
+            offset,
+            region.start,
+            region.data.len(),
+            PAGE as u64,
+        );
+        offset += page_align(region.data.len());
+    }
+
+    out.extend_from_slice(&notes);
+    for region in regions {
+        out.resize(page_align(out.len()), 0);
+        out.extend_from_slice(&region.data);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use goblin::elf::program_header::{PF_R, PF_X};
+    use std::convert::TryInto;
+
+    #[test]
+    fn layout() {
+        let registers = Registers {
+            gpr: [
+                1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 0x1234,
+            ],
+            eflags: 0x202,
+        };
+
+        // A payload whose ELF header starts the second page
+        let payload = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let mut data = vec![0; PAGE];
+        data.extend_from_slice(&payload[..PAGE]);
+        let regions = [
+            Region {
+                start: 0x7000_0000,
+                flags: PF_R | PF_X,
+                data,
+            },
+            Region {
+                start: 0x7100_0000,
+                flags: PF_R,
+                data: vec![0xaa; 100],
+            },
+        ];
+
+        let auxv = auxv(&payload, &regions);
+        let bytes = core(&registers, &regions, libc::SIGSEGV, &auxv);
+        let elf = Elf::parse(&bytes).unwrap();
+        assert_eq!(elf.header.e_type, ET_CORE);
+
+        let loads: Vec<_> = elf
+            .program_headers
+            .iter()
+            .filter(|ph| ph.p_type == PT_LOAD)
+            .collect();
+        assert_eq!(loads.len(), 2);
+        assert_eq!(loads[1].p_vaddr, 0x7100_0000);
+        assert_eq!(&bytes[loads[1].file_range()], &[0xaa; 100][..]);
+
+        let notes: Vec<_> = elf
+            .iter_note_headers(&bytes)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[0].n_type, NT_PRSTATUS);
+        assert_eq!(notes[0].desc[..4], libc::SIGSEGV.to_le_bytes());
+        let rip = &notes[0].desc[PRSTATUS_REGS + 16 * 8..][..8];
+        assert_eq!(u64::from_le_bytes(rip.try_into().unwrap()), 0x1234);
+
+        // The entry is relocated to the page which holds the ELF header.
+        let header = Elf::parse_header(&payload).unwrap();
+        assert!(auxv.contains(&(AT_ENTRY, 0x7000_1000 + header.e_entry)));
+        assert_eq!(notes[1].n_type, NT_AUXV);
+    }
+
+    #[test]
+    fn push() {
+        let mut regions = Vec::new();
+        super::push(&mut regions, 0x1000, PF_R, &[1; PAGE]);
+        super::push(&mut regions, 0x2000, PF_R, &[2; PAGE]);
+        super::push(&mut regions, 0x3000, PF_R | PF_X, &[3; PAGE]);
+        super::push(&mut regions, 0x5000, PF_R | PF_X, &[4; PAGE]);
+
+        let starts: Vec<_> = regions.iter().map(|r| (r.start, r.data.len())).collect();
+        assert_eq!(starts, [(0x1000, 2 * PAGE), (0x3000, PAGE), (0x5000, PAGE)]);
+    }
+
+    #[test]
+    fn unknown() {
+        // Without the payload in memory, GDB gets no auxiliary vector.
+        let payload = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let region = Region {
+            start: 0,
+            flags: PF_R,
+            data: vec![0; PAGE],
+        };
+        assert!(auxv(&payload, &[region]).is_empty());
+        assert!(auxv(b"not a payload", &[]).is_empty());
+    }
+}
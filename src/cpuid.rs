@@ -0,0 +1,197 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! CPUID policies
+//!
+//! The CPUID of the host is not passed to the payload as it is: it reveals
+//! the topology of the host and it advertises features which a keep may not
+//! be able to use. Each backend filters it through a policy, which is a list
+//! of rules applied in order. Every rule sets, clears or replaces the bits of
+//! one register of a leaf. Rules given with `--cpuid` are applied last.
+//!
+//! A rule is written as `LEAF[.SUBLEAF].REG OP VALUE`, where `OP` is `=`,
+//! `&=` or `|=`. For example, `0x7.0.ebx&=0xfffeffff` hides AVX-512. Without
+//! a subleaf, the rule applies to all subleaves of the leaf.
+//!
+//! Besides the topology, the policy of the `sgx` backend hides the features
+//! whose extended states aren't enabled in every enclave, such as AVX-512.
+//! Leaves which KVM doesn't report can't be added.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+
+/// The names of the registers, in the order of the values of a leaf
+const REGISTERS: [&str; 4] = ["eax", "ebx", "ecx", "edx"];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Op {
+    Set,
+    And,
+    Or,
+}
+
+/// A change to one register of a leaf
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    leaf: u32,
+    subleaf: Option<u32>,
+    register: usize,
+    op: Op,
+    value: u32,
+}
+
+impl Rule {
+    const fn new(leaf: u32, subleaf: Option<u32>, register: usize, op: Op, value: u32) -> Self {
+        Self {
+            leaf,
+            subleaf,
+            register,
+            op,
+            value,
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    /// Parses `LEAF[.SUBLEAF].REG OP VALUE`
+    fn from_str(rule: &str) -> Result<Self> {
+        let (op, at, len) = match (rule.find("&="), rule.find("|="), rule.find('=')) {
+            (Some(at), _, _) => (Op::And, at, 2),
+            (_, Some(at), _) => (Op::Or, at, 2),
+            (_, _, Some(at)) => (Op::Set, at, 1),
+            _ => bail!("invalid CPUID rule: {}", rule),
+        };
+
+        let (target, value) = (rule[..at].trim(), rule[at + len..].trim());
+        let parts = target.split('.').collect::<Vec<_>>();
+        let (leaf, subleaf, register) = match parts[..] {
+            [leaf, register] => (leaf, None, register),
+            [leaf, subleaf, register] => (leaf, Some(subleaf), register),
+            _ => bail!("invalid CPUID rule: {}", rule),
+        };
+
+        let register = REGISTERS
+            .iter()
+            .position(|r| r.eq_ignore_ascii_case(register))
+            .ok_or_else(|| anyhow!("invalid CPUID register: {}", register))?;
+
+        Ok(Self {
+            leaf: number(leaf)?,
+            subleaf: subleaf.map(number).transpose()?,
+            register,
+            op,
+            value: number(value)?,
+        })
+    }
+}
+
+/// Parses a decimal or a hexadecimal (`0x`) number
+fn number(text: &str) -> Result<u32> {
+    let number = match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+
+    number.map_err(|_| anyhow!("invalid CPUID number: {}", text))
+}
+
+/// Hides the topology of the host: the initial APIC ID and the x2APIC IDs
+const TOPOLOGY: [Rule; 3] = [
+    Rule::new(0x1, None, 1, Op::And, 0x00ff_ffff),
+    Rule::new(0xb, None, 3, Op::Set, 0),
+    Rule::new(0x1f, None, 3, Op::Set, 0),
+];
+
+/// Hides the extended states beyond x87, SSE and AVX, which are not in the
+/// XFRM of every enclave, and the features which need them: AVX-512,
+/// protection keys and AMX
+#[cfg(feature = "backend-sgx")]
+const XSAVE: [Rule; 6] = [
+    Rule::new(0x7, Some(0), 1, Op::And, !0xdc23_0000),
+    Rule::new(0x7, Some(0), 2, Op::And, !0x0000_585a),
+    Rule::new(0x7, Some(0), 3, Op::And, !0x03c0_010c),
+    Rule::new(0xd, Some(0), 0, Op::And, 0x0000_0007),
+    Rule::new(0xd, Some(0), 3, Op::Set, 0),
+    Rule::new(0xd, Some(1), 2, Op::Set, 0),
+];
+
+/// A list of rules
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Policy(Vec<Rule>);
+
+impl Policy {
+    /// The policy of KVM keeps, on top of the filtering done by KVM
+    #[cfg(feature = "backend-kvm")]
+    pub fn kvm() -> Self {
+        Self(TOPOLOGY.to_vec())
+    }
+
+    /// The policy of SGX keeps, where CPUID comes from the host as it is
+    #[cfg(feature = "backend-sgx")]
+    pub fn sgx() -> Self {
+        Self(TOPOLOGY.iter().chain(&XSAVE).copied().collect())
+    }
+
+    /// Adds rules after those of the policy
+    pub fn with(mut self, rules: &[Rule]) -> Self {
+        self.0.extend_from_slice(rules);
+        self
+    }
+
+    /// Applies the policy to the values of a leaf
+    pub fn apply(&self, leaf: u32, subleaf: u32, mut values: [u32; 4]) -> [u32; 4] {
+        let rules = self.0.iter().filter(|r| r.leaf == leaf);
+        for rule in rules.filter(|r| r.subleaf.unwrap_or(subleaf) == subleaf) {
+            let value = &mut values[rule.register];
+            match rule.op {
+                Op::Set => *value = rule.value,
+                Op::And => *value &= rule.value,
+                Op::Or => *value |= rule.value,
+            }
+        }
+
+        values
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let rule = "0x7.0.ebx&=0xfffeffff".parse::<Rule>().unwrap();
+        assert_eq!(rule, Rule::new(7, Some(0), 1, Op::And, 0xfffe_ffff));
+
+        let rule = "1.ECX |= 0x80000000".parse::<Rule>().unwrap();
+        assert_eq!(rule, Rule::new(1, None, 2, Op::Or, 0x8000_0000));
+
+        let rule = "0x40000000.eax=0".parse::<Rule>().unwrap();
+        assert_eq!(rule, Rule::new(0x4000_0000, None, 0, Op::Set, 0));
+
+        assert!("0x7.0.ebx".parse::<Rule>().is_err());
+        assert!("0x7.0.0.ebx=0".parse::<Rule>().is_err());
+        assert!("0x7.esi=0".parse::<Rule>().is_err());
+        assert!("leaf.eax=0".parse::<Rule>().is_err());
+        assert!("0x7.eax=-1".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn apply() {
+        let rules = ["0x1.ebx&=0x00ffffff", "0xd.0.eax&=0x7", "0xd.0.edx=0"];
+        let rules = rules.iter().map(|r| r.parse().unwrap()).collect::<Vec<_>>();
+        let policy = Policy::default()
+            .with(&rules)
+            .with(&["0xd.ecx|=1".parse().unwrap()]);
+
+        let leaf = [0x1234, 0x0708_0910, 0, 0];
+        assert_eq!(policy.apply(0x1, 0, leaf), [0x1234, 0x0008_0910, 0, 0]);
+
+        let leaf = [0x2e7, 0xa88, 0x2e8, 0x1];
+        assert_eq!(policy.apply(0xd, 0, leaf), [0x7, 0xa88, 0x2e9, 0]);
+        assert_eq!(policy.apply(0xd, 1, leaf), [0x2e7, 0xa88, 0x2e9, 0x1]);
+        assert_eq!(policy.apply(0x2, 0, leaf), leaf);
+    }
+}
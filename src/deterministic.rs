@@ -0,0 +1,210 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Deterministic runs
+//!
+//! With a seed, the time and the random numbers of the payload come from a
+//! virtual source, so that runs with the same seed and input behave the
+//! same, which helps debugging the payload and testing the shims. Every read
+//! of a clock advances the virtual time by a `STEP`, and `getrandom()` is
+//! answered by a generator which the seed starts.
+//!
+//! The shims ask whether the run is deterministic with
+//! `SYS_ENARX_DETERMINISTIC` and only believe it in keeps which the host can
+//! inspect anyway: SGX enclaves with the DEBUG attribute and KVM guests
+//! without SEV. Other keeps ignore the seed.
+
+use std::mem::size_of;
+use std::sync::Mutex;
+
+use enarx_syscall::SYS_ENARX_DETERMINISTIC;
+use primordial::Register;
+use sallyport::Block;
+
+/// The realtime clock starts at 2001-09-09 01:46:40 UTC
+const EPOCH: u64 = 1_000_000_000;
+
+/// How much the virtual time advances on every read, in nanoseconds
+const STEP: u64 = 1_000;
+
+/// The most bytes returned by `getrandom()` at once, as on Linux
+const GRND_MAX: usize = (1 << 25) - 1;
+
+struct State {
+    /// The state of the random number generator
+    random: u64,
+
+    /// The virtual time since the start of the keep, in nanoseconds
+    now: u64,
+}
+
+/// The virtual source of the time and the random numbers of a keep
+pub struct Deterministic(Mutex<State>);
+
+impl Deterministic {
+    /// Starts the virtual source from a seed
+    pub fn new(seed: u64) -> Self {
+        Self(Mutex::new(State {
+            random: seed,
+            now: 0,
+        }))
+    }
+
+    /// Answers `SYS_ENARX_DETERMINISTIC`, `clock_gettime()` and `getrandom()`
+    ///
+    /// The results are written to the block, where the shims place them.
+    /// Returns `None` for all other syscalls.
+    pub fn syscall(&self, block: &mut Block) -> Option<sallyport::Result> {
+        let req = unsafe { block.msg.req };
+        let num: i64 = req.num.into();
+        let arg = |i: usize| usize::from(req.arg[i]);
+
+        let ret = match num {
+            SYS_ENARX_DETERMINISTIC => Ok([Register::default(), Register::default()]),
+
+            libc::SYS_clock_gettime => {
+                let now = {
+                    let mut state = self.0.lock().unwrap();
+                    state.now += STEP;
+                    state.now
+                };
+
+                let nanos = match arg(0) as libc::clockid_t {
+                    libc::CLOCK_REALTIME | libc::CLOCK_REALTIME_COARSE => {
+                        now + EPOCH * 1_000_000_000
+                    }
+                    _ => now,
+                };
+
+                let ts = libc::timespec {
+                    tv_sec: (nanos / 1_000_000_000) as _,
+                    tv_nsec: (nanos % 1_000_000_000) as _,
+                };
+
+                match within(block, arg(1), size_of::<libc::timespec>()) {
+                    Some(ptr) => {
+                        unsafe { (ptr as *mut libc::timespec).write_unaligned(ts) };
+                        Ok([Register::default(), Register::default()])
+                    }
+                    None => Err(libc::EFAULT),
+                }
+            }
+
+            libc::SYS_getrandom => {
+                let len = arg(1).min(GRND_MAX);
+                match within(block, arg(0), len) {
+                    Some(ptr) => {
+                        let buf = unsafe { std::slice::from_raw_parts_mut(ptr as *mut u8, len) };
+                        let mut state = self.0.lock().unwrap();
+                        for chunk in buf.chunks_mut(8) {
+                            let bytes = next(&mut state.random).to_le_bytes();
+                            chunk.copy_from_slice(&bytes[..chunk.len()]);
+                        }
+
+                        Ok([len.into(), Register::default()])
+                    }
+                    None => Err(libc::EFAULT),
+                }
+            }
+
+            _ => return None,
+        };
+
+        Some(ret)
+    }
+}
+
+/// Checks that a buffer of the shim lies within the block
+fn within(block: &mut Block, addr: usize, len: usize) -> Option<usize> {
+    let start = block as *mut Block as usize;
+    let end = start + size_of::<Block>();
+    match addr.checked_add(len) {
+        Some(last) if start <= addr && last <= end => Some(addr),
+        _ => None,
+    }
+}
+
+/// Returns the next number of the generator (SplitMix64)
+fn next(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(block: &mut Block, num: i64, args: &[usize]) {
+        let req = unsafe { &mut block.msg.req };
+        req.num = (num as usize).into();
+        for (reg, arg) in req.arg.iter_mut().zip(args) {
+            *reg = (*arg).into();
+        }
+    }
+
+    /// The address of a buffer at the end of the block
+    fn tail(block: &mut Block, len: usize) -> usize {
+        block as *mut Block as usize + size_of::<Block>() - len
+    }
+
+    fn clock(deterministic: &Deterministic, clockid: libc::clockid_t) -> libc::timespec {
+        let mut block = Block::default();
+        let addr = tail(&mut block, size_of::<libc::timespec>());
+        request(
+            &mut block,
+            libc::SYS_clock_gettime,
+            &[clockid as usize, addr],
+        );
+        deterministic.syscall(&mut block).unwrap().unwrap();
+        unsafe { (addr as *const libc::timespec).read_unaligned() }
+    }
+
+    fn random(deterministic: &Deterministic) -> [u8; 12] {
+        let mut block = Block::default();
+        let addr = tail(&mut block, 12);
+        request(&mut block, libc::SYS_getrandom, &[addr, 12, 0]);
+        let ret = deterministic.syscall(&mut block).unwrap().unwrap();
+        assert_eq!(usize::from(ret[0]), 12);
+        unsafe { (addr as *const [u8; 12]).read_unaligned() }
+    }
+
+    #[test]
+    fn replay() {
+        let first = Deterministic::new(7);
+        let second = Deterministic::new(7);
+        assert_eq!(random(&first), random(&second));
+        assert_ne!(random(&first), random(&Deterministic::new(8)));
+
+        let a = clock(&first, libc::CLOCK_MONOTONIC);
+        let b = clock(&first, libc::CLOCK_MONOTONIC);
+        assert_eq!((a.tv_sec, a.tv_nsec), (0, STEP as _));
+        assert_eq!((b.tv_sec, b.tv_nsec), (0, 2 * STEP as libc::c_long));
+
+        let c = clock(&second, libc::CLOCK_REALTIME);
+        assert_eq!((c.tv_sec, c.tv_nsec), (EPOCH as _, STEP as _));
+    }
+
+    #[test]
+    fn syscalls() {
+        let deterministic = Deterministic::new(0);
+        let mut block = Block::default();
+
+        request(&mut block, SYS_ENARX_DETERMINISTIC, &[]);
+        assert!(deterministic.syscall(&mut block).unwrap().is_ok());
+
+        // Buffers outside of the block are rejected.
+        let mut outside = [0u8; 8];
+        request(
+            &mut block,
+            libc::SYS_getrandom,
+            &[outside.as_mut_ptr() as _, outside.len(), 0],
+        );
+        let ret = deterministic.syscall(&mut block).unwrap();
+        assert_eq!(ret.err(), Some(libc::EFAULT));
+
+        request(&mut block, libc::SYS_getpid, &[]);
+        assert!(deterministic.syscall(&mut block).is_none());
+    }
+}
@@ -0,0 +1,140 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The arguments and environment of the payload
+//!
+//! The shims ask for them while they set up the initial stack of the
+//! payload. The first argument (`/init`) is added by the shims.
+//!
+//! The payload gets `LANG=C` in its environment. `--arg` and `--env` add to
+//! them, where `--env NAME` passes the value of `NAME` on the host:
+//!
+//! ```text
+//! $ enarx-keepldr exec --arg -v --env RUST_LOG=info ./app
+//! ```
+
+use anyhow::{anyhow, bail, Result};
+use enarx_syscall::SYS_ENARX_ENVIRON;
+use primordial::Register;
+use sallyport::Block;
+
+/// The maximum size of the list, which must fit in the block
+const MAX_SIZE: usize = 2048;
+
+/// The arguments and environment of the payload
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Environ {
+    args: Vec<String>,
+    vars: Vec<String>,
+}
+
+impl Environ {
+    /// Validates the arguments and environment variables
+    ///
+    /// Variables are given as `NAME=VALUE` or as `NAME`, which takes the
+    /// value from the environment of the host. A variable given more than
+    /// once takes the last value. `LANG=C` is set unless `LANG` is given.
+    pub fn new(args: Vec<String>, vars: Vec<String>) -> Result<Self> {
+        let mut set = vec![String::from("LANG=C")];
+
+        for var in vars {
+            let var = match var.contains('=') {
+                true => var,
+                false => {
+                    let value = std::env::var(&var)
+                        .map_err(|_| anyhow!("unable to pass {}: not set on the host", var))?;
+                    format!("{}={}", var, value)
+                }
+            };
+
+            let name = var.split('=').next().unwrap();
+            if name.is_empty() {
+                bail!("invalid environment variable: {}", var);
+            }
+
+            match set.iter_mut().find(|v| v.split('=').next() == Some(name)) {
+                Some(old) => *old = var,
+                None => set.push(var),
+            }
+        }
+
+        let environ = Self { args, vars: set };
+
+        if environ.strings().any(|s| s.contains('\0')) {
+            bail!("arguments and environment variables cannot contain NUL");
+        }
+
+        let size = environ.strings().map(|s| s.len() + 1).sum::<usize>();
+        if size > MAX_SIZE {
+            bail!(
+                "the arguments and environment are too large ({} > {} bytes)",
+                size,
+                MAX_SIZE
+            );
+        }
+
+        Ok(environ)
+    }
+
+    fn strings(&self) -> impl Iterator<Item = &String> {
+        self.args.iter().chain(self.vars.iter())
+    }
+
+    /// Handles `SYS_ENARX_ENVIRON`
+    ///
+    /// Returns `None` for all other syscalls.
+    pub fn syscall(&self, block: &mut Block) -> Option<sallyport::Result> {
+        let num: i64 = unsafe { block.msg.req.num.into() };
+        if num != SYS_ENARX_ENVIRON {
+            return None;
+        }
+
+        let mut list = Vec::with_capacity(MAX_SIZE);
+        for string in self.strings() {
+            list.extend_from_slice(string.as_bytes());
+            list.push(0);
+        }
+
+        let ret = block
+            .cursor()
+            .copy_from_slice(&list)
+            .map(|_| [Register::from(list.len()), self.args.len().into()])
+            .or(Err(libc::EMSGSIZE));
+
+        Some(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[&str]) -> Vec<String> {
+        let environ = Environ::new(vec![], vars.iter().map(|v| v.to_string()).collect());
+        environ.unwrap().vars
+    }
+
+    #[test]
+    fn new() {
+        assert_eq!(vars(&[]), ["LANG=C"]);
+        assert_eq!(vars(&["A=1", "LANG=en", "A=2"]), ["LANG=en", "A=2"]);
+        assert_eq!(vars(&["A==b"]), ["LANG=C", "A==b"]);
+
+        std::env::set_var("ENARX_ENVIRON_TEST", "host");
+        assert_eq!(
+            vars(&["ENARX_ENVIRON_TEST"]),
+            ["LANG=C", "ENARX_ENVIRON_TEST=host"]
+        );
+
+        let invalid = |args: &[&str], vars: &[&str]| {
+            let args = args.iter().map(|a| a.to_string()).collect();
+            let vars = vars.iter().map(|v| v.to_string()).collect();
+            Environ::new(args, vars).is_err()
+        };
+
+        assert!(invalid(&[], &["=a"]));
+        assert!(invalid(&[], &["ENARX_ENVIRON_UNSET"]));
+        assert!(invalid(&["a\0b"], &[]));
+        assert!(invalid(&[], &["A=\0"]));
+        assert!(invalid(&[&"a".repeat(MAX_SIZE)], &[]));
+    }
+}
@@ -0,0 +1,272 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Executing the syscalls of a keep
+//!
+//! An `Executor` answers the requests which a keep leaves in its blocks: the
+//! requests to the loader itself, the interceptors of the environment, the
+//! mounts and the streams, and everything else by executing the syscall on
+//! the host, unless the policy denies it. It only sees the blocks, so it
+//! runs without a keep, e.g. in the fuzzer beneath `fuzz/`.
+
+use crate::backtrace::{self, Symbols};
+use crate::batch;
+use crate::control::{Control, Event};
+use crate::deterministic::Deterministic;
+use crate::environ::Environ;
+use crate::exit::Exit;
+use crate::metrics::Metrics;
+use crate::mount::Mounts;
+use crate::policy::{Guard, SyscallPolicy};
+use crate::profile::Profile;
+use crate::record::{Recorder, Replayer};
+use crate::scheduler;
+use crate::shutdown;
+use crate::streams::Streams;
+use crate::trace;
+use crate::watchdog::Watchdog;
+
+use enarx_syscall::{SYS_ENARX_BACKTRACE, SYS_ENARX_BATCH, SYS_ENARX_DOORBELL, SYS_ENARX_MEMORY};
+use sallyport::Block;
+use tracing::{info, trace, warn};
+
+use std::time::Instant;
+
+/// Executes the syscalls which a keep requests from the host
+pub struct Executor<'a> {
+    pub(crate) deterministic: Option<Deterministic>,
+    pub(crate) environ: &'a Environ,
+    pub(crate) mounts: &'a Mounts,
+    pub(crate) streams: &'a Streams,
+    pub(crate) control: Option<&'a Control>,
+    pub(crate) metrics: Option<&'a Metrics>,
+    pub(crate) policy: Guard<'a>,
+    pub(crate) watchdog: Option<&'a Watchdog>,
+    pub(crate) profile: Option<Profile>,
+    pub(crate) trace: bool,
+    pub(crate) recorder: Option<Recorder>,
+    pub(crate) replayer: Option<Replayer>,
+    pub(crate) exited: Option<Exit>,
+
+    /// Why the host can't execute the syscalls of the keep any longer
+    pub(crate) failed: Option<anyhow::Error>,
+
+    /// Whether the shim listens for shutdown requests
+    pub(crate) doorbell: bool,
+
+    /// The functions of the payload, which its backtraces are printed with
+    pub(crate) symbols: Symbols,
+
+    /// Whether the memory which the keep used is printed when it exits
+    pub(crate) usage: bool,
+}
+
+impl<'a> Executor<'a> {
+    /// Serves a keep with the environment, mounts and streams of the host,
+    /// restricted by the policy
+    pub fn new(
+        environ: &'a Environ,
+        mounts: &'a Mounts,
+        streams: &'a Streams,
+        policy: &'a SyscallPolicy,
+    ) -> Self {
+        Self {
+            deterministic: None,
+            environ,
+            mounts,
+            streams,
+            control: None,
+            metrics: None,
+            policy: Guard::new(policy),
+            watchdog: None,
+            profile: None,
+            trace: false,
+            recorder: None,
+            replayer: None,
+            exited: None,
+            failed: None,
+            doorbell: false,
+            symbols: Symbols::default(),
+            usage: false,
+        }
+    }
+
+    /// Makes the time and the random numbers of the keep deterministic (see
+    /// the `deterministic` module)
+    pub fn deterministic(mut self, seed: u64) -> Self {
+        self.deterministic = Some(Deterministic::new(seed));
+        self
+    }
+
+    /// How the keep exited, once it requested to
+    pub fn exited(&self) -> Option<Exit> {
+        self.exited
+    }
+
+    /// Executes the request in the block and leaves the reply there
+    ///
+    /// The block holds whatever the keep put there, so nothing in it is
+    /// trusted.
+    pub fn syscall(&mut self, block: &mut Block) {
+        unsafe {
+            let req = block.msg.req;
+            let num: i64 = req.num.into();
+            if num == SYS_ENARX_BATCH {
+                block.msg.rep = batch::syscall(block, |block| self.syscall(block)).into();
+                return;
+            }
+
+            if num == SYS_ENARX_DOORBELL {
+                let reply = shutdown::reply();
+                self.doorbell = reply.is_ok();
+                block.msg.rep = reply.into();
+                return;
+            }
+
+            if num == SYS_ENARX_BACKTRACE {
+                block.msg.rep = backtrace::syscall(block, &self.symbols).into();
+                return;
+            }
+
+            if num == SYS_ENARX_MEMORY {
+                block.msg.rep = match self.metrics {
+                    Some(metrics) => metrics.memory(block),
+                    None => Ok(Default::default()),
+                }
+                .into();
+                return;
+            }
+
+            trace!(num, "proxying syscall");
+            if let Some(metrics) = self.metrics {
+                metrics.syscall(num);
+            }
+
+            let call = match self.trace {
+                true => Some(trace::call(block, &req)),
+                false => None,
+            };
+
+            if let Some(exit) = Exit::request(&req) {
+                if let Some(call) = &call {
+                    eprintln!("{} = ?", call);
+                }
+
+                self.exit(exit);
+                return;
+            }
+
+            let proxied = Instant::now();
+            if let Some(replayer) = self.replayer.as_mut() {
+                if let Err(e) = replayer.replay(block) {
+                    self.failed.get_or_insert(e);
+                    return;
+                }
+            } else {
+                block.msg.rep = match self.policy.check(&req) {
+                    Err(e) => {
+                        warn!(syscall = %trace::name(num), "denied by the syscall policy: {}", e);
+                        sallyport::Result::Err(libc::EPERM).into()
+                    }
+                    Ok(()) => {
+                        let ret = self
+                            .deterministic
+                            .as_ref()
+                            .and_then(|d| d.syscall(block))
+                            .or_else(|| self.environ.syscall(block))
+                            .or_else(|| self.streams.syscall(block))
+                            .or_else(|| self.mounts.syscall(block));
+                        match ret {
+                            Some(ret) => ret.into(),
+                            None if !scheduler::ready(&block.msg.req) => {
+                                sallyport::Result::Err(libc::EINTR).into()
+                            }
+                            None => block.msg.req.syscall(),
+                        }
+                    }
+                };
+            }
+
+            if let Some(recorder) = self.recorder.as_mut() {
+                if let Err(e) = recorder.record(block) {
+                    self.failed.get_or_insert(e);
+                }
+            }
+
+            if let Some(profile) = self.profile.as_mut() {
+                profile.syscall(num, proxied.elapsed());
+            }
+
+            let ret: sallyport::Result = block.msg.rep.into();
+            self.policy.executed(block, &req, &ret);
+            if let Some(watchdog) = self.watchdog {
+                watchdog.syscall(num, &ret);
+            }
+
+            if let Some(call) = call {
+                eprintln!("{} {}", call, trace::result(&ret));
+            }
+        }
+    }
+
+    /// Ends the keep
+    pub(crate) fn exit(&mut self, exit: Exit) {
+        info!("{}", exit);
+
+        if let Some(profile) = &self.profile {
+            eprint!("{}", profile.report());
+        }
+
+        if let (true, Some(metrics)) = (self.usage, self.metrics) {
+            eprint!("{}", metrics.usage());
+        }
+
+        if let Some(control) = self.control {
+            control.emit(match exit {
+                Exit::Code(code) => Event::Exited { code },
+                Exit::Signal(signal) => Event::Killed { signal },
+                Exit::Attacked => Event::Attacked,
+                Exit::TimedOut => Event::TimedOut,
+            });
+            control.close();
+        }
+
+        self.exited = Some(exit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(num: i64, args: &[usize]) -> Block {
+        let mut block = Block::default();
+        block.msg.req.num = (num as usize).into();
+        for (reg, arg) in unsafe { block.msg.req.arg.iter_mut() }.zip(args) {
+            *reg = (*arg).into();
+        }
+        block
+    }
+
+    #[test]
+    fn execute() {
+        let environ = Environ::new(vec![], vec![]).unwrap();
+        let mounts = Mounts::new(vec![]).unwrap();
+        let streams = Streams::default();
+        let policy = SyscallPolicy::default().syscalls(&["getpid"]).unwrap();
+        let mut executor = Executor::new(&environ, &mounts, &streams, &policy);
+
+        let mut block = request(libc::SYS_getpid, &[]);
+        executor.syscall(&mut block);
+        let ret: sallyport::Result = unsafe { block.msg.rep }.into();
+        assert_eq!(usize::from(ret.unwrap()[0]), std::process::id() as usize);
+
+        let mut block = request(libc::SYS_getppid, &[]);
+        executor.syscall(&mut block);
+        let ret: sallyport::Result = unsafe { block.msg.rep }.into();
+        assert_eq!(ret.err(), Some(libc::EPERM));
+
+        assert_eq!(executor.exited(), None);
+        executor.syscall(&mut request(libc::SYS_exit_group, &[3]));
+        assert_eq!(executor.exited(), Some(Exit::Code(3)));
+    }
+}
@@ -0,0 +1,158 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! How a payload ends
+//!
+//! The `exit()` and `exit_group()` of the payload are not executed by the
+//! host: the loader takes the code and the keep ends, so that programs which
+//! embed the loader learn how the payload ended (see `KeepBuilder::spawn()`).
+//! Likewise, a payload which sends itself a fatal signal, as `abort()` does,
+//! ends as if it was killed by the signal.
+//!
+//! The shims stop a keep when they detect that the host attacks it, e.g. by
+//! answering a syscall with something impossible. This is reported as well,
+//! since such a keep can't have finished its work, and so are keeps which the
+//! watchdog stopped.
+
+use sallyport::Request;
+
+use std::fmt;
+
+/// The signals which end a payload that sends them to itself
+const FATAL: &[i32] = &[
+    libc::SIGABRT,
+    libc::SIGBUS,
+    libc::SIGFPE,
+    libc::SIGILL,
+    libc::SIGKILL,
+    libc::SIGSEGV,
+    libc::SIGTERM,
+];
+
+/// The exit status of the loader for keeps which were attacked
+pub const ATTACKED: i32 = 125;
+
+/// The exit status of the loader for keeps which the watchdog stopped
+///
+/// This is what `timeout(1)` exits with. The watchdog exits the process with
+/// it when it can't pull the thread out of the keep (see
+/// `KeepBuilder::timeout()`).
+pub const TIMED_OUT: i32 = 124;
+
+/// How a payload ended
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Exit {
+    /// The payload exited with this code
+    Code(i32),
+
+    /// The payload was killed by this signal
+    Signal(i32),
+
+    /// The shim stopped the keep, because the host attacked it
+    Attacked,
+
+    /// The watchdog stopped the keep, because it ran for too long or hung
+    TimedOut,
+}
+
+impl Exit {
+    /// The exit status of the loader, as shells report it
+    ///
+    /// The code for payloads which exited, 128 plus the signal for payloads
+    /// which were killed, and `ATTACKED` or `TIMED_OUT` otherwise.
+    pub fn status(&self) -> i32 {
+        match self {
+            Self::Code(code) => code & 0xff,
+            Self::Signal(signal) => 128 + signal,
+            Self::Attacked => ATTACKED,
+            Self::TimedOut => TIMED_OUT,
+        }
+    }
+
+    /// Returns how the payload ends, if the request ends it
+    pub(crate) fn request(req: &Request) -> Option<Self> {
+        let num: i64 = req.num.into();
+        let arg = |i: usize| usize::from(req.arg[i]) as i32;
+
+        match num {
+            libc::SYS_exit | libc::SYS_exit_group => Some(Self::Code(arg(0))),
+            libc::SYS_kill if itself(arg(0)) && FATAL.contains(&arg(1)) => {
+                Some(Self::Signal(arg(1)))
+            }
+            libc::SYS_tgkill if itself(arg(0)) && FATAL.contains(&arg(2)) => {
+                Some(Self::Signal(arg(2)))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Code(code) => write!(f, "the payload exited with code {}", code),
+            Self::Signal(signal) => write!(f, "the payload was killed by signal {}", signal),
+            Self::Attacked => write!(f, "the keep was stopped, because it was attacked"),
+            Self::TimedOut => write!(f, "the keep was stopped, because it timed out"),
+        }
+    }
+}
+
+/// Whether the process ID names the process of the keep
+///
+/// The payload learns the ID with the `getpid()` which the host executes.
+fn itself(pid: i32) -> bool {
+    pid == 0 || pid == std::process::id() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sallyport::Block;
+
+    fn request(num: i64, args: &[usize]) -> Option<Exit> {
+        let mut block = Block::default();
+        let req = unsafe { &mut block.msg.req };
+        req.num = (num as usize).into();
+        for (reg, arg) in req.arg.iter_mut().zip(args) {
+            *reg = (*arg).into();
+        }
+
+        Exit::request(req)
+    }
+
+    #[test]
+    fn requests() {
+        let pid = std::process::id() as usize;
+
+        assert_eq!(request(libc::SYS_exit_group, &[3]), Some(Exit::Code(3)));
+        assert_eq!(request(libc::SYS_exit, &[0]), Some(Exit::Code(0)));
+
+        let abort = libc::SIGABRT as usize;
+        assert_eq!(
+            request(libc::SYS_tgkill, &[pid, pid, abort]),
+            Some(Exit::Signal(libc::SIGABRT))
+        );
+        assert_eq!(
+            request(libc::SYS_kill, &[0, libc::SIGKILL as usize]),
+            Some(Exit::Signal(libc::SIGKILL))
+        );
+
+        // Signals to other processes and signals which don't kill are sent.
+        assert_eq!(request(libc::SYS_kill, &[pid + 1, abort]), None);
+        assert_eq!(
+            request(libc::SYS_kill, &[pid, libc::SIGCHLD as usize]),
+            None
+        );
+        assert_eq!(request(libc::SYS_write, &[1, 0, 0]), None);
+    }
+
+    #[test]
+    fn statuses() {
+        assert_eq!(Exit::Code(3).status(), 3);
+        assert_eq!(Exit::Code(256).status(), 0);
+        assert_eq!(Exit::Signal(libc::SIGABRT).status(), 134);
+        assert_eq!(Exit::Attacked.status(), ATTACKED);
+        assert_eq!(Exit::TimedOut.status(), TIMED_OUT);
+    }
+}
@@ -0,0 +1,506 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A GDB remote stub for debugging payloads
+//!
+//! With `--gdb`, the keep stops before its first instruction and waits for
+//! GDB to connect to the given address:
+//!
+//! ```text
+//! $ enarx-keepldr exec --backend kvm --gdb localhost:1234 ./app
+//! $ gdb -ex "target remote localhost:1234" ./app
+//! ```
+//!
+//! Only the general purpose registers, memory, software breakpoints and
+//! single-stepping are supported. Backends provide access to the stopped
+//! thread by implementing `Target`, which only the `kvm` backend does so far.
+//! The keep starts in the shim, so the symbols of the payload have to be
+//! loaded at the address which the shim loads it to.
+
+use std::convert::TryInto;
+use std::io::{BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use anyhow::{anyhow, bail, Result};
+use tracing::info;
+
+/// The `SIGTRAP` signal reported to GDB for every stop
+pub const SIGTRAP: u8 = 5;
+
+/// The `int3` instruction
+const INT3: u8 = 0xcc;
+
+/// The general purpose registers in the order GDB expects them
+///
+/// That is `rax`, `rbx`, `rcx`, `rdx`, `rsi`, `rdi`, `rbp`, `rsp`, `r8`
+/// to `r15` and `rip`, followed by `eflags`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Registers {
+    /// The 64-bit registers
+    pub gpr: [u64; 17],
+
+    /// The flags register
+    pub eflags: u32,
+}
+
+impl Registers {
+    fn to_hex(self) -> String {
+        let mut bytes = Vec::with_capacity(17 * 8 + 4);
+        for reg in &self.gpr {
+            bytes.extend_from_slice(&reg.to_le_bytes());
+        }
+
+        bytes.extend_from_slice(&self.eflags.to_le_bytes());
+        hex(&bytes)
+    }
+
+    fn from_hex(text: &str) -> Option<Self> {
+        let bytes = unhex(text)?;
+        if bytes.len() < 17 * 8 + 4 {
+            return None;
+        }
+
+        let mut registers = Self::default();
+        for (reg, chunk) in registers.gpr.iter_mut().zip(bytes.chunks_exact(8)) {
+            *reg = u64::from_le_bytes(chunk.try_into().ok()?);
+        }
+
+        registers.eflags = u32::from_le_bytes(bytes[17 * 8..][..4].try_into().ok()?);
+        Some(registers)
+    }
+}
+
+/// A stopped thread which can be inspected by GDB
+pub trait Target {
+    /// Reads the registers of the thread
+    fn read_registers(&mut self) -> Result<Registers>;
+
+    /// Replaces the registers of the thread
+    fn write_registers(&mut self, registers: &Registers) -> Result<()>;
+
+    /// Reads memory at a virtual address of the thread
+    fn read_memory(&mut self, addr: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// Writes memory at a virtual address of the thread
+    fn write_memory(&mut self, addr: u64, data: &[u8]) -> Result<()>;
+
+    /// Prepares the thread to resume as GDB asked
+    ///
+    /// Until GDB detaches, breakpoints and completed steps stop the thread
+    /// with `Command::Trap`.
+    fn resume(&mut self, resume: Resume) -> Result<()>;
+}
+
+/// How the keep continues after a stop
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Resume {
+    /// Run until the next stop
+    Continue,
+
+    /// Run a single instruction
+    Step,
+
+    /// Run without the debugger
+    Detach,
+
+    /// Terminate the keep
+    Kill,
+}
+
+/// Formats bytes as hex digits
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses hex digits as bytes
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Parses an `ADDR,LENGTH` argument
+fn range(text: &str) -> Option<(u64, usize)> {
+    let (addr, len) = text.split_once(',')?;
+    let addr = u64::from_str_radix(addr, 16).ok()?;
+    let len = usize::from_str_radix(len, 16).ok()?;
+    Some((addr, len))
+}
+
+/// A connection to GDB
+pub struct Gdb {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    breakpoints: Vec<(u64, u8)>,
+}
+
+impl Gdb {
+    /// Waits for GDB to connect to the given address
+    pub fn accept(addr: &str) -> Result<Self> {
+        let listener =
+            TcpListener::bind(addr).map_err(|e| anyhow!("unable to bind {}: {}", addr, e))?;
+
+        info!("waiting for GDB on {}", listener.local_addr()?);
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            breakpoints: Vec::new(),
+        })
+    }
+
+    /// Receives a packet, acknowledging it
+    fn recv(&mut self) -> Result<String> {
+        let mut byte = [0u8];
+
+        loop {
+            // Skip acknowledgements and interrupts until a packet starts.
+            loop {
+                self.reader.read_exact(&mut byte)?;
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+
+            let mut data = Vec::new();
+            loop {
+                self.reader.read_exact(&mut byte)?;
+                match byte[0] {
+                    b'#' => break,
+                    b'}' => {
+                        self.reader.read_exact(&mut byte)?;
+                        data.push(byte[0] ^ 0x20);
+                    }
+                    b => data.push(b),
+                }
+            }
+
+            let mut sum = [0u8; 2];
+            self.reader.read_exact(&mut sum)?;
+
+            let expected = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+            let actual = data.iter().fold(0u8, |a, b| a.wrapping_add(*b));
+
+            if expected == Some(actual) {
+                self.writer.write_all(b"+")?;
+                return String::from_utf8(data).map_err(|_| anyhow!("invalid GDB packet"));
+            }
+
+            self.writer.write_all(b"-")?;
+        }
+    }
+
+    /// Sends a packet
+    ///
+    /// Replies only contain hex digits and letters, so nothing is escaped.
+    fn send(&mut self, data: &str) -> Result<()> {
+        let sum = data.bytes().fold(0u8, |a, b| a.wrapping_add(b));
+        write!(self.writer, "${}#{:02x}", data, sum)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Inserts a software breakpoint
+    fn insert(&mut self, target: &mut dyn Target, addr: u64) -> Result<()> {
+        if self.breakpoints.iter().any(|(a, _)| *a == addr) {
+            return Ok(());
+        }
+
+        let mut old = [0u8];
+        target.read_memory(addr, &mut old)?;
+        target.write_memory(addr, &[INT3])?;
+        self.breakpoints.push((addr, old[0]));
+        Ok(())
+    }
+
+    /// Removes a software breakpoint
+    fn remove(&mut self, target: &mut dyn Target, addr: u64) -> Result<()> {
+        if let Some(i) = self.breakpoints.iter().position(|(a, _)| *a == addr) {
+            let (_, old) = self.breakpoints.swap_remove(i);
+            target.write_memory(addr, &[old])?;
+        }
+
+        Ok(())
+    }
+
+    /// Handles one packet, returning how to resume if it ends the stop
+    fn packet(
+        &mut self,
+        target: &mut dyn Target,
+        packet: &str,
+        signal: u8,
+    ) -> Result<Option<Resume>> {
+        let split = packet
+            .char_indices()
+            .nth(1)
+            .map_or(packet.len(), |(i, _)| i);
+        let (cmd, args) = packet.split_at(split);
+
+        let reply = match cmd {
+            "?" => format!("S{:02x}", signal),
+            "g" => target.read_registers()?.to_hex(),
+            "G" => match Registers::from_hex(args) {
+                Some(registers) => {
+                    target.write_registers(&registers)?;
+                    "OK".into()
+                }
+                None => "E01".into(),
+            },
+            "m" => match range(args) {
+                Some((addr, len)) => {
+                    let mut buf = vec![0u8; len];
+                    match target.read_memory(addr, &mut buf) {
+                        Ok(()) => {
+                            // GDB expects to see the instructions beneath breakpoints.
+                            for (bp, old) in &self.breakpoints {
+                                if let Some(i) = bp.checked_sub(addr) {
+                                    if let Some(byte) = buf.get_mut(i as usize) {
+                                        *byte = *old;
+                                    }
+                                }
+                            }
+
+                            hex(&buf)
+                        }
+                        Err(_) => "E14".into(),
+                    }
+                }
+                None => "E01".into(),
+            },
+            "M" => {
+                let write = args.split_once(':').and_then(|(range_, data)| {
+                    let (addr, len) = range(range_)?;
+                    let data = unhex(data)?;
+                    match data.len() == len {
+                        true => Some((addr, data)),
+                        false => None,
+                    }
+                });
+
+                match write {
+                    Some((addr, data)) => match target.write_memory(addr, &data) {
+                        Ok(()) => "OK".into(),
+                        Err(_) => "E14".into(),
+                    },
+                    None => "E01".into(),
+                }
+            }
+            "Z" | "z" if args.starts_with("0,") => {
+                match args[2..]
+                    .split(',')
+                    .next()
+                    .map(|a| u64::from_str_radix(a, 16))
+                {
+                    Some(Ok(addr)) => {
+                        let result = match cmd {
+                            "Z" => self.insert(target, addr),
+                            _ => self.remove(target, addr),
+                        };
+
+                        match result {
+                            Ok(()) => "OK".into(),
+                            Err(_) => "E14".into(),
+                        }
+                    }
+                    _ => "E01".into(),
+                }
+            }
+            "c" | "s" if !args.is_empty() => bail!("GDB resumed at an address: {}", packet),
+            "c" => return Ok(Some(Resume::Continue)),
+            "s" => return Ok(Some(Resume::Step)),
+            "D" => {
+                self.send("OK")?;
+                return Ok(Some(Resume::Detach));
+            }
+            "k" => return Ok(Some(Resume::Kill)),
+            "H" => "OK".into(),
+            "q" if args.starts_with("Supported") => "PacketSize=4000".into(),
+            "q" if args == "Attached" => "1".into(),
+            _ => String::new(),
+        };
+
+        self.send(&reply)?;
+        Ok(None)
+    }
+
+    /// Reports a stop to GDB and serves its requests until it resumes
+    pub fn stop(&mut self, target: &mut dyn Target, signal: u8) -> Result<Resume> {
+        // The first stop is reported when GDB asks for it with `?`.
+        if signal != 0 {
+            self.send(&format!("S{:02x}", signal))?;
+        }
+
+        loop {
+            let packet = self.recv()?;
+            if let Some(resume) = self.packet(target, &packet, SIGTRAP)? {
+                if resume == Resume::Detach {
+                    while let Some((addr, _)) = self.breakpoints.first().copied() {
+                        self.remove(target, addr)?;
+                    }
+                }
+
+                target.resume(resume)?;
+                return Ok(resume);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread::spawn;
+
+    #[derive(Default)]
+    struct Mock {
+        registers: Registers,
+        memory: Vec<u8>,
+        resume: Option<Resume>,
+    }
+
+    impl Target for Mock {
+        fn read_registers(&mut self) -> Result<Registers> {
+            Ok(self.registers)
+        }
+
+        fn write_registers(&mut self, registers: &Registers) -> Result<()> {
+            self.registers = *registers;
+            Ok(())
+        }
+
+        fn read_memory(&mut self, addr: u64, buf: &mut [u8]) -> Result<()> {
+            let src = self.memory.get(addr as usize..addr as usize + buf.len());
+            buf.copy_from_slice(src.ok_or_else(|| anyhow!("out of range"))?);
+            Ok(())
+        }
+
+        fn write_memory(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+            let dst = self
+                .memory
+                .get_mut(addr as usize..addr as usize + data.len());
+            dst.ok_or_else(|| anyhow!("out of range"))?
+                .copy_from_slice(data);
+            Ok(())
+        }
+
+        fn resume(&mut self, resume: Resume) -> Result<()> {
+            self.resume = Some(resume);
+            Ok(())
+        }
+    }
+
+    /// Sends packets to the stub and returns its replies
+    fn session(target: &mut Mock, packets: &'static [&'static str]) -> (Resume, Vec<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let client = spawn(move || {
+            let mut stream = loop {
+                if let Ok(stream) = TcpStream::connect(addr) {
+                    break stream;
+                }
+            };
+
+            let mut replies = Vec::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            for packet in packets {
+                let sum = packet.bytes().fold(0u8, |a, b| a.wrapping_add(b));
+                write!(stream, "${}#{:02x}", packet, sum).unwrap();
+
+                let mut ack = [0u8];
+                reader.read_exact(&mut ack).unwrap();
+                assert_eq!(&ack, b"+");
+
+                // Resuming and killing have no reply.
+                if matches!(packet.as_bytes()[0], b'c' | b's' | b'k') {
+                    break;
+                }
+
+                let mut reply = Vec::new();
+                let mut byte = [0u8];
+                reader.read_exact(&mut byte).unwrap();
+                assert_eq!(&byte, b"$");
+                loop {
+                    reader.read_exact(&mut byte).unwrap();
+                    if byte[0] == b'#' {
+                        break;
+                    }
+                    reply.push(byte[0]);
+                }
+
+                let mut sum = [0u8; 2];
+                reader.read_exact(&mut sum).unwrap();
+                replies.push(String::from_utf8(reply).unwrap());
+            }
+
+            replies
+        });
+
+        let mut gdb = Gdb::accept(&addr.to_string()).unwrap();
+        let resume = gdb.stop(target, 0).unwrap();
+        (resume, client.join().unwrap())
+    }
+
+    #[test]
+    fn stop() {
+        let mut target = Mock {
+            memory: vec![0x90; 16],
+            ..Default::default()
+        };
+        target.registers.gpr[16] = 0x1234;
+        target.registers.eflags = 0x202;
+
+        let (resume, replies) = session(
+            &mut target,
+            &[
+                "qSupported:swbreak+",
+                "?",
+                "m4,2",
+                "M4,2:aabb",
+                "Z0,8,1",
+                "m7,3",
+                "g",
+                "s",
+            ],
+        );
+
+        assert_eq!(resume, Resume::Step);
+        assert_eq!(target.resume, Some(Resume::Step));
+        assert_eq!(replies[0], "PacketSize=4000");
+        assert_eq!(replies[1], "S05");
+        assert_eq!(replies[2], "9090");
+        assert_eq!(replies[3], "OK");
+        assert_eq!(replies[4], "OK");
+        assert_eq!(replies[5], "909090");
+        assert_eq!(target.memory[8], INT3);
+        assert_eq!(&target.memory[4..6], &[0xaa, 0xbb]);
+
+        let g = &replies[6];
+        assert_eq!(g.len(), (17 * 8 + 4) * 2);
+        assert_eq!(&g[16 * 16..17 * 16], "3412000000000000");
+        assert_eq!(&g[17 * 16..], "02020000");
+    }
+
+    #[test]
+    fn detach() {
+        let mut target = Mock {
+            memory: vec![0x90; 16],
+            ..Default::default()
+        };
+
+        let (resume, replies) = session(&mut target, &["Z0,3,1", "Z0,5,1", "D"]);
+        assert_eq!(resume, Resume::Detach);
+        assert_eq!(replies, ["OK", "OK", "OK"]);
+        assert_eq!(target.resume, Some(Resume::Detach));
+        assert_eq!(target.memory, vec![0x90; 16]);
+    }
+}
@@ -0,0 +1,206 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Isolating the loader once the keep is built
+//!
+//! Building a keep takes privileges which running it doesn't: the devices of
+//! the backends (e.g. `/dev/sgx_enclave`) are often only accessible to root.
+//! Once the keep is built, the loader can move the thread which executes the
+//! syscalls of the keep to new namespaces and switch the process to an
+//! unprivileged user, so that a compromised shim which exploits the host
+//! gets as little as possible.
+//!
+//! Whatever the keep uses afterwards must be accessible to that user, such as
+//! the mounted directories, the files of its streams and the AESM socket for
+//! the attestation of SGX keeps.
+
+use anyhow::{anyhow, bail, Result};
+
+use std::ffi::{CStr, CString};
+use std::str::FromStr;
+
+/// A namespace which the loader can leave
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Namespace {
+    /// The mounts, which no longer propagate to the host
+    Mount,
+
+    /// The network, which only has a loopback device that is down
+    Net,
+
+    /// The process IDs, for the processes which the loader starts
+    Pid,
+
+    /// System V IPC and POSIX message queues
+    Ipc,
+
+    /// The host name
+    Uts,
+}
+
+impl Namespace {
+    fn flag(self) -> libc::c_int {
+        match self {
+            Self::Mount => libc::CLONE_NEWNS,
+            Self::Net => libc::CLONE_NEWNET,
+            Self::Pid => libc::CLONE_NEWPID,
+            Self::Ipc => libc::CLONE_NEWIPC,
+            Self::Uts => libc::CLONE_NEWUTS,
+        }
+    }
+}
+
+impl FromStr for Namespace {
+    type Err = anyhow::Error;
+
+    /// Parses `mount`, `net`, `pid`, `ipc` or `uts`
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "mount" => Self::Mount,
+            "net" => Self::Net,
+            "pid" => Self::Pid,
+            "ipc" => Self::Ipc,
+            "uts" => Self::Uts,
+            _ => bail!("unknown namespace: {}", s),
+        })
+    }
+}
+
+/// The user which the loader switches to
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct User {
+    /// The user ID
+    pub uid: libc::uid_t,
+
+    /// The group ID
+    pub gid: libc::gid_t,
+}
+
+impl FromStr for User {
+    type Err = anyhow::Error;
+
+    /// Parses `NAME` or `UID[:GID]`
+    ///
+    /// Without a group, the primary group of the user is taken, or the group
+    /// with the same ID for users which don't exist.
+    fn from_str(s: &str) -> Result<Self> {
+        let (user, group) = match s.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (s, None),
+        };
+
+        let entry = match user.parse::<libc::uid_t>() {
+            Ok(uid) => passwd(|pw, buf, res| unsafe {
+                libc::getpwuid_r(uid, pw, buf.as_mut_ptr(), buf.len(), res)
+            })?
+            .or(Some((uid, uid))),
+            Err(_) => {
+                let name = CString::new(user)?;
+                passwd(|pw, buf, res| unsafe {
+                    libc::getpwnam_r(name.as_ptr(), pw, buf.as_mut_ptr(), buf.len(), res)
+                })?
+            }
+        };
+
+        let (uid, primary) = entry.ok_or_else(|| anyhow!("unknown user: {}", user))?;
+        let gid = match group {
+            Some(group) => group
+                .parse()
+                .map_err(|_| anyhow!("invalid group ID: {}", group))?,
+            None => primary,
+        };
+
+        Ok(Self { uid, gid })
+    }
+}
+
+/// Looks up a user, returning its ID and the ID of its primary group
+fn passwd<F>(lookup: F) -> Result<Option<(libc::uid_t, libc::gid_t)>>
+where
+    F: Fn(&mut libc::passwd, &mut [libc::c_char], &mut *mut libc::passwd) -> libc::c_int,
+{
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut pw: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut res = std::ptr::null_mut();
+
+    match lookup(&mut pw, &mut buf, &mut res) {
+        0 if res.is_null() => Ok(None),
+        0 => Ok(Some((pw.pw_uid, pw.pw_gid))),
+        err => Err(std::io::Error::from_raw_os_error(err).into()),
+    }
+}
+
+/// How the loader isolates itself once the keep is built
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Isolation {
+    /// The namespaces which are replaced by new ones
+    pub namespaces: Vec<Namespace>,
+
+    /// The user which the process switches to
+    pub user: Option<User>,
+}
+
+impl Isolation {
+    /// Isolates the calling thread
+    ///
+    /// The namespaces are only replaced for the calling thread, but the whole
+    /// process switches to the user.
+    pub(crate) fn apply(&self) -> Result<()> {
+        let flags = self.namespaces.iter().fold(0, |f, ns| f | ns.flag());
+        if flags != 0 && unsafe { libc::unshare(flags) } < 0 {
+            let err = std::io::Error::last_os_error();
+            bail!("unable to unshare the namespaces: {}", err);
+        }
+
+        if self.namespaces.contains(&Namespace::Mount) {
+            let root = CStr::from_bytes_with_nul(b"/\0").unwrap();
+            let flags = libc::MS_REC | libc::MS_PRIVATE;
+            let null = std::ptr::null();
+            if unsafe { libc::mount(null, root.as_ptr(), null, flags, null.cast()) } < 0 {
+                let err = std::io::Error::last_os_error();
+                bail!("unable to make the mounts private: {}", err);
+            }
+        }
+
+        if let Some(user) = self.user {
+            // glibc switches all threads of the process.
+            unsafe {
+                if libc::setgroups(0, std::ptr::null()) < 0
+                    || libc::setgid(user.gid) < 0
+                    || libc::setuid(user.uid) < 0
+                {
+                    let err = std::io::Error::last_os_error();
+                    bail!("unable to switch to user {}: {}", user.uid, err);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!("net".parse::<Namespace>().unwrap(), Namespace::Net);
+        assert!("user".parse::<Namespace>().is_err());
+
+        let root = "root".parse::<User>().unwrap();
+        assert_eq!(root, User { uid: 0, gid: 0 });
+        assert_eq!(
+            "4242:7".parse::<User>().unwrap(),
+            User { uid: 4242, gid: 7 }
+        );
+        assert_eq!(
+            "4243".parse::<User>().unwrap(),
+            User {
+                uid: 4243,
+                gid: 4243
+            }
+        );
+        assert!("no-such-user-here".parse::<User>().is_err());
+        assert!("0:wheel".parse::<User>().is_err());
+    }
+}
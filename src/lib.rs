@@ -0,0 +1,141 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! This crate provides the `enarx-keepldr` executable which loads `static-pie`
+//! binaries into an Enarx Keep - that is a hardware isolated environment using
+//! technologies such as Intel SGX or AMD SEV - and the library it is built on.
+//!
+//! # Building
+//!
+//! Please see **BUILD.md** for instructions.
+//!
+//! # Run Tests
+//!
+//!     $ cargo test
+//!
+//! The syscall conformance tests run every payload of `tests/syscall` on each
+//! backend which the host supports, or only on the one `ENARX_BACKEND` names:
+//!
+//!     $ ENARX_BACKEND=sev cargo test --test syscall
+//!
+//! # Build and Run an Application
+//!
+//!     $ cat > test.c <<EOF
+//!     #include <stdio.h>
+//!
+//!     int main() {
+//!         printf("Hello World!\n");
+//!         return 0;
+//!     }
+//!     EOF
+//!
+//!     $ musl-gcc -static-pie -fPIC -o test test.c
+//!     $ target/debug/enarx-keepldr exec ./test
+//!     Hello World!
+//!
+//! `enarx-keepldr help exec` lists the options of a keep, which can also be
+//! kept in a configuration file (`--config`). `validate` checks whether a
+//! payload can run in a keep, `measure` prints the measurement of a keep
+//! without launching it, `info` shows what the host supports and `bench`
+//! measures how keeps perform on it.
+//!
+//! # Select a Different Backend
+//!
+//! `enarx-keepldr exec` will probe the machine it is running on
+//! in an attempt to deduce an appropriate deployment backend. The
+//! backends are tried in a fixed order (`sgx`, `sev`, then `kvm`) and the
+//! first one which is supported is used. To manually select a backend, use
+//! `--backend`:
+//!
+//!     $ target/debug/enarx-keepldr exec --backend sgx ./test
+//!
+//! The `nil` backend runs the payload directly on the host, without any
+//! protection, so it is never picked by `auto`. It is meant for checking
+//! whether a payload works in a keep on machines without a TEE, e.g. in CI.
+//! Which backends are available depends on the `backend-*` features the crate
+//! is built with (see `BUILD.md`).
+//!
+//! # Attest a Keep
+//!
+//! SEV keeps are attested before their payload starts. With `--sev-owner`,
+//! the loader has a remote guest owner check the platform and the launch
+//! measurement of the keep, and the guest owner releases its secret to the
+//! keep only if it accepts them. The payload reads the secret with
+//! `SYS_ENARX_GETSECRET`. `enarx-keepldr measure` prints the measurement
+//! which the guest owner expects.
+//!
+//! SGX keeps have no such handshake, since their shim doesn't ask for quotes
+//! yet. Payloads seal their secrets with keys from `SYS_ENARX_GETKEY`
+//! instead, which are bound to the identity of the enclave.
+//!
+//! # Embed the Loader
+//!
+//! The loader is a library as well, for programs which launch keeps
+//! themselves. `KeepBuilder` takes what `exec` takes as options, and the
+//! fields of `backend::Config` the settings of the backends:
+//!
+//! ```no_run
+//! use enarx_keepldr::binary::Component;
+//! use enarx_keepldr::{backend, KeepBuilder};
+//!
+//! let backends = backend::all();
+//! let payload = std::fs::read("hello-world")?;
+//!
+//! KeepBuilder::new()
+//!     .backend(backend::select(&backends, "auto")?)
+//!     .code(Component::from_bytes(&payload)?)
+//!     .spawn()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Each feature of a keep is documented with the module which implements it,
+//! e.g. `mount` for host directories, `policy` for restricting the syscalls
+//! of a keep and `metrics` for exporting its statistics. The `registry`
+//! module shows how to host several keeps in one process, the `pool` module
+//! how to keep WebAssembly keeps warm for fast launches, the `scheduler`
+//! module how to run many keeps on a few host threads, and the `serve` module
+//! how `enarx-keepldr serve` manages keeps for orchestrators.
+
+#![deny(clippy::all)]
+#![deny(missing_docs)]
+#![feature(asm)]
+
+pub mod backend;
+pub mod backtrace;
+pub mod bench;
+pub mod binary;
+pub mod cgroup;
+pub mod control;
+pub mod coredump;
+pub mod cpuid;
+pub mod deterministic;
+pub mod environ;
+pub mod executor;
+pub mod exit;
+pub mod gdb;
+pub mod isolation;
+pub mod manifest;
+pub mod metrics;
+pub mod mount;
+pub mod policy;
+pub mod pool;
+pub mod registry;
+pub mod scheduler;
+#[cfg(feature = "serve")]
+pub mod serve;
+pub mod streams;
+pub mod wasm;
+
+mod batch;
+mod builder;
+mod profile;
+mod protobuf;
+mod record;
+mod sandbox;
+mod shutdown;
+mod trace;
+mod watchdog;
+
+pub use builder::KeepBuilder;
+
+// workaround for sallyport tests, until we have internal crates
+pub use sallyport::Request;
@@ -1,185 +1,1001 @@
 // SPDX-License-Identifier: Apache-2.0
 
-//! This crate provides the `enarx-keepldr` executable which loads `static-pie`
-//! binaries into an Enarx Keep - that is a hardware isolated environment using
-//! technologies such as Intel SGX or AMD SEV.
+//! The `enarx-keepldr` executable
 //!
-//! # Building
-//!
-//! Please see **BUILD.md** for instructions.
-//!
-//! # Run Tests
-//!
-//!     $ cargo test
-//!
-//! # Build and Run an Application
-//!
-//!     $ cat > test.c <<EOF
-//!     #include <stdio.h>
-//!
-//!     int main() {
-//!         printf("Hello World!\n");
-//!         return 0;
-//!     }
-//!     EOF
-//!
-//!     $ musl-gcc -static-pie -fPIC -o test test.c
-//!     $ target/debug/enarx-keepldr exec ./test
-//!     Hello World!
-//!
-//! # Select a Different Backend
-//!
-//! `enarx-keepldr exec` will probe the machine it is running on
-//! in an attempt to deduce an appropriate deployment backend unless
-//! that target is already specified in an environment variable
-//! called `ENARX_BACKEND`.
-//!
-//! To see what backends are supported on your system, run:
-//!
-//!     $ target/debug/enarx-keepldr info
-//!
-//! To manually select a backend, set the `ENARX_BACKEND` environment
-//! variable:
-//!
-//!     $ ENARX_BACKEND=sgx target/debug/enarx-keepldr exec ./test
-//!
-//! Note that some backends are conditionally compiled. They can all
-//! be compiled in like so:
-//!
-//!     $ cargo build --all-features
-//!
-//! Or specific backends can be compiled in:
-//!
-//!     $ cargo build --features=backend-sgx,backend-kvm
+//! Its subcommands build keeps with the library of the crate, whose docs
+//! give an overview; `enarx-keepldr help` lists them and their options.
 
 #![deny(clippy::all)]
 #![deny(missing_docs)]
-#![feature(asm)]
-
-mod backend;
-mod binary;
-mod protobuf;
 
-// workaround for sallyport tests, until we have internal crates
-pub use sallyport::Request;
+mod config;
 
-use backend::{Backend, Command};
-use binary::Component;
+use config::ConfigFile;
+use enarx_keepldr::backend::{self, Backend, Config, Datum, GuestOwner};
+use enarx_keepldr::backend::{SallyportAbi, SevParameters, SgxParameters};
+use enarx_keepldr::binary::{self, Component};
+use enarx_keepldr::cgroup::Limits;
+use enarx_keepldr::control::Control;
+use enarx_keepldr::cpuid::Rule;
+use enarx_keepldr::environ::Environ;
+use enarx_keepldr::isolation::{Isolation, Namespace, User};
+use enarx_keepldr::metrics::Metrics;
+use enarx_keepldr::mount::{Mount, Mounts};
+use enarx_keepldr::streams::Streams;
+use enarx_keepldr::{bench, manifest, wasm, KeepBuilder};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use structopt::StructOpt;
+use tracing_subscriber::EnvFilter;
 
+use std::convert::TryFrom;
+use std::io::Read;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::time::Duration;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
 
 /// Prints information about your current platform
 #[derive(StructOpt)]
-struct Info {}
+struct Info {
+    #[structopt(flatten)]
+    launch: Launch,
+
+    /// Also predicts how much protected memory this payload needs
+    code: Option<PathBuf>,
+}
+
+/// Settings which change the measurement of a keep
+#[derive(StructOpt)]
+struct Launch {
+    /// The size of the shim heap (e.g. `512M`; SGX only)
+    #[structopt(long, parse(try_from_str = parse_size))]
+    heap_size: Option<usize>,
+
+    /// The XFRM of the enclave (e.g. `0xe7` for AVX-512; SGX only)
+    #[structopt(long, parse(try_from_str = parse_number::<u64>))]
+    sgx_xfrm: Option<u64>,
+
+    /// The MISCSELECT of the enclave (`1` for EXINFO; SGX only)
+    #[structopt(long, parse(try_from_str = parse_number::<u32>))]
+    sgx_miscselect: Option<u32>,
+
+    /// The product ID (ISVPRODID) of the enclave (SGX only)
+    #[structopt(long, parse(try_from_str = parse_number::<u16>))]
+    sgx_prod_id: Option<u16>,
+
+    /// The security version (ISVSVN) of the enclave (SGX only)
+    #[structopt(long, parse(try_from_str = parse_number::<u16>))]
+    sgx_svn: Option<u16>,
+
+    /// Adds the heap pages when they are first touched (SGX2 only)
+    #[structopt(long)]
+    sgx_lazy_heap: bool,
+
+    /// Encrypts the register state of the keep as well (SEV only)
+    #[structopt(long)]
+    sev_es: bool,
+
+    /// Forbids the host to debug the keep (SEV only)
+    #[structopt(long)]
+    sev_no_debug: bool,
+
+    /// Forbids the keep to share its key with other guests (SEV only)
+    #[structopt(long)]
+    sev_no_key_sharing: bool,
+
+    /// The oldest SEV firmware the keep may run on (e.g. `0.24`; SEV only)
+    #[structopt(long, parse(try_from_str = parse_version))]
+    sev_min_firmware: Option<(u8, u8)>,
+
+    /// The number of vCPUs (KVM and SEV only)
+    ///
+    /// The payload runs on the first one only, and the others halt.
+    #[structopt(long)]
+    cpus: Option<NonZeroUsize>,
+
+    /// Places this signed manifest after the payload (KVM and SEV only)
+    #[structopt(long)]
+    manifest: Option<PathBuf>,
+
+    /// Builds a keep which can be debugged, and whose memory the host can read
+    #[structopt(long)]
+    debug_keep: bool,
+}
+
+impl Launch {
+    /// Merges the settings with those of a configuration file
+    fn config(self, file: &ConfigFile) -> Config {
+        Config {
+            heap_size: self.heap_size.or(file.heap_size),
+            cpus: self.cpus.or(file.cpus),
+            manifest: self.manifest.or_else(|| file.manifest.clone()),
+            sgx: SgxParameters {
+                xfrm: self.sgx_xfrm.or(file.sgx.xfrm),
+                misc_select: self.sgx_miscselect.or(file.sgx.misc_select),
+                prod_id: self.sgx_prod_id.or(file.sgx.prod_id),
+                svn: self.sgx_svn.or(file.sgx.svn),
+                lazy_heap: self.sgx_lazy_heap || file.sgx.lazy_heap,
+            },
+            sev: SevParameters {
+                es: self.sev_es || file.sev.es,
+                no_debug: self.sev_no_debug || file.sev.no_debug,
+                no_key_sharing: self.sev_no_key_sharing || file.sev.no_key_sharing,
+                min_firmware: self.sev_min_firmware.or(file.sev.min_firmware),
+            },
+            debug: self.debug_keep || file.debug_keep,
+            ..Default::default()
+        }
+    }
+}
 
 /// Executes a keep
 #[derive(StructOpt)]
 struct Exec {
+    /// Reads settings from a configuration file (e.g. `Enarx.toml`)
+    #[structopt(long)]
+    config: Option<PathBuf>,
+
+    /// The keep backend to use (`auto` picks the first supported one)
+    ///
+    /// The backend of the configuration file is used otherwise, or else that
+    /// of `ENARX_BACKEND`.
+    #[structopt(long)]
+    backend: Option<String>,
+
+    /// Runs this shim instead of the builtin one, once it is checked against
+    /// the backend
+    #[structopt(long)]
+    shim: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    launch: Launch,
+
+    /// Passes an argument to the payload
+    #[structopt(long = "arg", number_of_values = 1, allow_hyphen_values = true)]
+    args: Vec<String>,
+
+    /// Sets an environment variable of the payload (`NAME=VALUE` or `NAME`)
+    #[structopt(long = "env", number_of_values = 1)]
+    env: Vec<String>,
+
+    /// Exposes a host directory to the keep (`HOST:KEEP[:OPTIONS]`)
+    ///
+    /// The options are a comma-separated list of `ro`, `rw` and `sealed`.
+    /// Read-only mounts take `CAP_SYS_ADMIN` and Linux 5.12.
+    #[structopt(long = "mount", number_of_values = 1)]
+    mounts: Vec<Mount>,
+
+    /// Injects a secret read from a file (`-` for stdin) into the keep
+    #[structopt(long)]
+    secret: Option<PathBuf>,
+
+    /// Writes the stdout of the payload to this file instead
+    #[structopt(long)]
+    stdout: Option<PathBuf>,
+
+    /// Writes the stderr of the payload to this file instead
+    #[structopt(long)]
+    stderr: Option<PathBuf>,
+
+    /// Emits lifecycle events on a Unix socket bound at this path
+    #[structopt(long)]
+    control: Option<PathBuf>,
+
+    /// Serves Prometheus metrics over HTTP at this address
+    #[structopt(long)]
+    metrics: Option<String>,
+
+    /// Changes what CPUID reports to the payload (`LEAF[.SUBLEAF].REG=VALUE`)
+    ///
+    /// The operator can also be `&=` or `|=`.
+    #[structopt(long = "cpuid", number_of_values = 1)]
+    cpuid: Vec<Rule>,
+
+    /// Ignores cached SGX signatures and SEV certificates
+    #[structopt(long)]
+    no_cache: bool,
+
+    /// Launches a keep which needs more EPC than the platform has (SGX only)
+    #[structopt(long)]
+    sgx_overcommit: bool,
+
+    /// The most memory the keep may use (e.g. `512M`; KVM and SEV only)
+    #[structopt(long, parse(try_from_str = parse_size))]
+    memory: Option<usize>,
+
+    /// Has the guest owner at this address attest the keep (`HOST:PORT`; SEV only)
+    #[structopt(long)]
+    sev_owner: Option<String>,
+
+    /// Verifies the guest owner with the CA certificates in this PEM file
+    #[structopt(long)]
+    sev_owner_ca: Option<PathBuf>,
+
+    /// Saves a snapshot of the keep here when the payload asks for one (KVM only)
+    #[structopt(long)]
+    snapshot: Option<PathBuf>,
+
+    /// Starts the keep from a snapshot instead of the payload (KVM only)
+    #[structopt(long, conflicts_with = "code")]
+    restore: Option<PathBuf>,
+
+    /// Reserves an empty code slot of this size (e.g. `16M`), into which the
+    /// guest owner delivers the payload (SEV only)
+    #[structopt(long, parse(try_from_str = parse_size), conflicts_with_all = &["code", "restore"])]
+    code_slot: Option<usize>,
+
+    /// Waits for GDB to connect to this address before starting (KVM only)
+    #[structopt(long)]
+    gdb: Option<String>,
+
+    /// Prints the syscalls which the keep requests from the host
+    #[structopt(long)]
+    trace: bool,
+
+    /// Prints where the keep spent its time when the payload exits
+    #[structopt(long)]
+    profile: bool,
+
+    /// Prints the memory which the keep used when the payload exits
+    #[structopt(long)]
+    memory_usage: bool,
+
+    /// Makes the time and the random numbers of the payload deterministic,
+    /// starting from this seed (debug keeps only)
+    #[structopt(long)]
+    deterministic: Option<u64>,
+
+    /// Records the syscalls which the host executes for the keep to this file
+    #[structopt(long)]
+    record: Option<PathBuf>,
+
+    /// Answers the syscalls of the keep from a recording instead of the host OS
+    #[structopt(long, conflicts_with = "record")]
+    replay: Option<PathBuf>,
+
+    /// Writes a core file to this path when the payload crashes (debug keeps
+    /// only)
+    #[structopt(long)]
+    core: Option<PathBuf>,
+
+    /// The seconds the payload gets to exit after SIGTERM or SIGINT
+    #[structopt(long, default_value = "10")]
+    grace: u64,
+
+    /// Stops the keep after it ran for this many seconds
+    #[structopt(long)]
+    timeout: Option<u64>,
+
+    /// Stops the keep when it hangs for this many seconds: when it doesn't
+    /// exit to the host, or when the payload repeats a failing syscall
+    #[structopt(long)]
+    watchdog: Option<u64>,
+
+    /// Moves the loader to new namespaces once the keep is built (`mount`,
+    /// `net`, `pid`, `ipc` or `uts`, comma-separated)
+    #[structopt(long, use_delimiter = true)]
+    unshare: Vec<Namespace>,
+
+    /// Switches the loader to this user once the keep is built (`NAME` or
+    /// `UID[:GID]`)
+    #[structopt(long)]
+    user: Option<User>,
+
+    /// The most CPUs the keep may use, e.g. `1.5` (in a cgroup of its own)
+    #[structopt(long)]
+    cpu_quota: Option<f64>,
+
+    /// The most host memory the keep may use, e.g. `2G` (in a cgroup of its
+    /// own)
+    #[structopt(long, parse(try_from_str = parse_size))]
+    memory_limit: Option<usize>,
+
+    /// The most threads the keep may use (in a cgroup of its own)
+    #[structopt(long)]
+    pids_limit: Option<u64>,
+
+    /// Lets the loader make any syscall which the shim requests
+    #[structopt(long)]
+    no_sandbox: bool,
+
+    /// The format of the log written to stderr (`text` or `json`)
+    #[structopt(
+        long,
+        env = "ENARX_LOG_FORMAT",
+        default_value = "text",
+        possible_values = &["text", "json"]
+    )]
+    log_format: String,
+
     /// The payload to run inside the keep
+    code: Option<PathBuf>,
+}
+
+/// Prints the measurement of a keep without launching it
+#[derive(StructOpt)]
+struct Measure {
+    /// Reads settings from a configuration file (e.g. `Enarx.toml`)
+    #[structopt(long)]
+    config: Option<PathBuf>,
+
+    /// The keep backend to measure for
+    ///
+    /// The backend of the configuration file is used otherwise, or else that
+    /// of `ENARX_BACKEND`.
+    #[structopt(long)]
+    backend: Option<String>,
+
+    /// Measures this shim instead of the builtin one
+    #[structopt(long)]
+    shim: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    launch: Launch,
+
+    /// The payload to measure
+    code: Option<PathBuf>,
+}
+
+/// Signs a manifest which allows a payload to run
+#[derive(StructOpt)]
+struct Sign {
+    /// The Ed25519 private key to sign with (PEM)
+    #[structopt(long)]
+    key: PathBuf,
+
+    /// A backend which may run the payload (`kvm` or `sev`; all by default)
+    #[structopt(long = "host", number_of_values = 1, parse(try_from_str = manifest::host))]
+    hosts: Vec<u32>,
+
+    /// When the manifest expires, in seconds since the Unix epoch
+    #[structopt(long)]
+    expires: Option<u64>,
+
+    /// Where to write the manifest (`PAYLOAD.manifest` by default)
+    #[structopt(long)]
+    output: Option<PathBuf>,
+
+    /// The payload to sign
+    code: PathBuf,
+}
+
+/// Checks whether a payload can run in a keep, without launching it
+#[derive(StructOpt)]
+struct Validate {
+    /// The payload to check
     code: PathBuf,
 }
 
+/// Measures how fast keeps start and talk to the host on this machine
+#[derive(StructOpt)]
+struct Bench {
+    /// Reads settings from a configuration file (e.g. `Enarx.toml`)
+    #[structopt(long)]
+    config: Option<PathBuf>,
+
+    /// The keep backend to measure
+    ///
+    /// The backend of the configuration file is used otherwise, or else that
+    /// of `ENARX_BACKEND`, or else all supported ones.
+    #[structopt(long)]
+    backend: Option<String>,
+
+    #[structopt(flatten)]
+    launch: Launch,
+
+    /// How many keeps are built to time their startup
+    #[structopt(long, default_value = "10")]
+    builds: usize,
+
+    /// How many seconds the syscalls and the writes are measured for
+    #[structopt(long, default_value = "3")]
+    seconds: u64,
+}
+
+/// Serves a gRPC API which creates, queries and stops keeps
+#[cfg(feature = "serve")]
+#[derive(StructOpt)]
+struct Serve {
+    /// Reads settings from a configuration file (e.g. `Enarx.toml`)
+    #[structopt(long)]
+    config: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    launch: Launch,
+
+    /// The format of the log written to stderr (`text` or `json`)
+    #[structopt(
+        long,
+        env = "ENARX_LOG_FORMAT",
+        default_value = "text",
+        possible_values = &["text", "json"]
+    )]
+    log_format: String,
+
+    /// The Unix socket to listen on
+    socket: PathBuf,
+}
+
 #[derive(StructOpt)]
 #[structopt(version=VERSION, author=AUTHORS.split(";").nth(0).unwrap())]
 enum Options {
     Info(Info),
     Exec(Exec),
+    Measure(Measure),
+    Sign(Sign),
+    Validate(Validate),
+    Bench(Bench),
+    #[cfg(feature = "serve")]
+    Serve(Serve),
+}
+
+/// Parses a size in bytes with an optional `K`, `M` or `G` suffix
+fn parse_size(size: &str) -> Result<usize> {
+    let (digits, shift) = match size.as_bytes().last() {
+        Some(b'K') | Some(b'k') => (&size[..size.len() - 1], 10),
+        Some(b'M') | Some(b'm') => (&size[..size.len() - 1], 20),
+        Some(b'G') | Some(b'g') => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+
+    digits
+        .parse::<usize>()?
+        .checked_mul(1 << shift)
+        .ok_or_else(|| anyhow::anyhow!("size is too large: {}", size))
+}
+
+/// Parses a decimal or a hexadecimal (`0x`) number
+fn parse_number<T: TryFrom<u64>>(text: &str) -> Result<T> {
+    let number = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16)?,
+        None => text.parse()?,
+    };
+
+    T::try_from(number).map_err(|_| anyhow::anyhow!("number is too large: {}", text))
+}
+
+/// Parses a firmware version (`MAJOR.MINOR`)
+fn parse_version(text: &str) -> Result<(u8, u8)> {
+    let (major, minor) = text
+        .split_once('.')
+        .ok_or_else(|| anyhow::anyhow!("invalid version: {}", text))?;
+
+    Ok((major.parse()?, minor.parse()?))
 }
 
 #[allow(clippy::unnecessary_wraps)]
 fn main() -> Result<()> {
-    let backends: &[Box<dyn Backend>] = &[
-        #[cfg(feature = "backend-sgx")]
-        Box::new(backend::sgx::Backend),
-        #[cfg(feature = "backend-kvm")]
-        Box::new(backend::kvm::Backend),
-    ];
+    let backends = backend::all();
 
     match Options::from_args() {
-        Options::Info(_) => info(backends),
-        Options::Exec(e) => exec(backends, e),
+        Options::Info(i) => info(&backends, i),
+        Options::Exec(e) => exec(&backends, e),
+        Options::Measure(m) => measure(&backends, m),
+        Options::Sign(s) => sign(s),
+        Options::Validate(v) => validate(v),
+        Options::Bench(b) => bench(&backends, b),
+        #[cfg(feature = "serve")]
+        Options::Serve(s) => serve(backends, s),
     }
 }
 
 #[allow(clippy::unnecessary_wraps)]
-fn info(backends: &[Box<dyn Backend>]) -> Result<()> {
-    use colorful::*;
+fn info(backends: &[Box<dyn Backend>], opts: Info) -> Result<()> {
+    let wasm = match &opts.code {
+        Some(path) => wasm::is_module(path)?,
+        None => false,
+    };
+    let map = match &opts.code {
+        Some(path) if !wasm => {
+            Some(mmarinus::Kind::Private.load::<mmarinus::perms::Read, _>(path)?)
+        }
+        _ => None,
+    };
+    let config = opts.launch.config(&ConfigFile::default());
 
     for backend in backends {
         println!("Backend: {}", backend.name());
 
-        let data = backend.data();
+        // Backends without a shim have no protected memory to fit in.
+        let mut data = backend.data();
+        match backend.shim() {
+            Err(e) => data.push(Datum {
+                name: "Shim".into(),
+                pass: false,
+                info: None,
+                mesg: Some(format!("{:#}", e)),
+            }),
+
+            Ok([]) => (),
 
-        for datum in &data {
-            let icon = match datum.pass {
-                true => "✔".green(),
-                false => "✗".red(),
-            };
+            Ok(shim) => {
+                let shim = Component::from_bytes(shim)?;
+                data.push(sallyport(&shim));
 
-            if let Some(info) = datum.info.as_ref() {
-                println!(" {} {}: {}", icon, datum.name, info);
-            } else {
-                println!(" {} {}", icon, datum.name);
+                if opts.code.is_some() {
+                    let code = match map.as_ref() {
+                        Some(map) => Component::from_bytes(map)?,
+                        None => wasm::runtime()?,
+                    };
+                    match backend.footprint(shim, code, &config) {
+                        Ok(footprint) => data.extend(footprint),
+                        Err(e) => data.push(Datum {
+                            name: "  Footprint".into(),
+                            pass: false,
+                            info: Some(e.to_string()),
+                            mesg: None,
+                        }),
+                    }
+                }
             }
         }
 
-        for datum in &data {
-            if let Some(mesg) = datum.mesg.as_ref() {
-                println!("\n{}\n", mesg);
-            }
+        print(&data);
+    }
+
+    Ok(())
+}
+
+/// Whether the shim speaks the sallyport ABI of the loader
+fn sallyport(shim: &Component) -> Datum {
+    let (pass, info, mesg) = match SallyportAbi::of(shim) {
+        Ok(abi) if abi == SallyportAbi::LOADER => (true, abi.to_string(), None),
+        Ok(abi) => (
+            false,
+            abi.to_string(),
+            Some(format!(
+                "The shim speaks sallyport ABI {}, but the loader speaks {}.",
+                abi,
+                SallyportAbi::LOADER
+            )),
+        ),
+        Err(e) => (false, "unknown".into(), Some(format!("{:#}", e))),
+    };
+
+    Datum {
+        name: "Sallyport ABI".into(),
+        pass,
+        info: Some(info),
+        mesg,
+    }
+}
+
+/// Prints the data of a check, followed by the messages of the failed ones
+fn print(data: &[Datum]) {
+    use colorful::*;
+
+    for datum in data {
+        let icon = match datum.pass {
+            true => "✔".green(),
+            false => "✗".red(),
+        };
+
+        if let Some(info) = datum.info.as_ref() {
+            println!(" {} {}: {}", icon, datum.name, info);
+        } else {
+            println!(" {} {}", icon, datum.name);
+        }
+    }
+
+    for datum in data {
+        if let Some(mesg) = datum.mesg.as_ref() {
+            println!("\n{}\n", mesg);
+        }
+    }
+}
+
+/// Prints whether a payload can run in a keep
+///
+/// Exits with status 1 if it can't.
+fn validate(opts: Validate) -> Result<()> {
+    let data = match wasm::is_module(&opts.code)? {
+        true => {
+            let runtime = wasm::runtime();
+            vec![Datum {
+                name: "WebAssembly".into(),
+                pass: runtime.is_ok(),
+                info: Some("runs in the bundled runtime".into()),
+                mesg: runtime.err().map(|e| e.to_string()),
+            }]
+        }
+        false => {
+            let bytes = std::fs::read(&opts.code)
+                .map_err(|e| anyhow!("unable to read {}: {}", opts.code.display(), e))?;
+            binary::validate(&bytes)
         }
+    };
+
+    println!("Payload: {}", opts.code.display());
+    print(&data);
+
+    if data.iter().any(|datum| !datum.pass) {
+        std::process::exit(1);
     }
 
     Ok(())
 }
 
-#[inline]
-fn backend(backends: &[Box<dyn Backend>]) -> &dyn Backend {
-    let keep = std::env::var_os("ENARX_BACKEND").map(|x| x.into_string().unwrap());
+/// Prints the measurement of a keep, such as SGX's MRENCLAVE
+///
+/// The hardware of the backend is not needed, so the backend is picked by
+/// name only (`sgx` by default).
+fn measure(backends: &[Box<dyn Backend>], opts: Measure) -> Result<()> {
+    let file = match opts.config {
+        Some(path) => ConfigFile::load(&path)?,
+        None => ConfigFile::default(),
+    };
 
+    let name = config::backend(
+        opts.backend,
+        file.backend.clone(),
+        std::env::var("ENARX_BACKEND").ok(),
+    );
+    let name = name.as_deref().unwrap_or("sgx");
     let backend = backends
         .iter()
-        .filter(|b| keep.is_none() || keep == Some(b.name().into()))
-        .find(|b| b.have());
+        .find(|b| b.name() == name)
+        .ok_or_else(|| anyhow!("unknown keep backend '{}'", name))?;
+
+    let code = opts
+        .code
+        .or_else(|| file.code.clone())
+        .ok_or_else(|| anyhow!("no payload given"))?;
 
-    match (keep, backend) {
-        (Some(name), None) => panic!("Keep backend '{:?}' is unsupported.", name),
-        (None, None) => panic!("No supported backend found!"),
-        (_, Some(backend)) => &**backend,
+    let shim = match opts.shim.or_else(|| file.shim.clone()) {
+        Some(path) => Some(mmarinus::Kind::Private.load::<mmarinus::perms::Read, _>(&path)?),
+        None => None,
+    };
+    let shim = match shim.as_ref() {
+        Some(map) => {
+            let shim = Component::from_bytes(map)?;
+            backend::check_shim(&**backend, &shim)?;
+            shim
+        }
+        None => match backend.shim()? {
+            [] => return Err(anyhow!("the {} backend has no measurement", backend.name())),
+            shim => Component::from_bytes(shim)?,
+        },
+    };
+
+    let map = match wasm::is_module(&code)? {
+        true => None,
+        false => Some(mmarinus::Kind::Private.load::<mmarinus::perms::Read, _>(&code)?),
+    };
+    let code = match &map {
+        Some(map) => Component::from_bytes(map)?,
+        None => wasm::runtime()?,
+    };
+
+    let config = opts.launch.config(&file);
+    for byte in backend.measure(shim, code, &config)? {
+        print!("{:02x}", byte);
     }
+
+    println!();
+    Ok(())
 }
 
-fn exec(backends: &[Box<dyn Backend>], opts: Exec) -> Result<()> {
-    let backend = backend(backends);
+/// Prints how fast keeps start and talk to the host on each backend
+fn bench(backends: &[Box<dyn Backend>], opts: Bench) -> Result<()> {
+    let file = match opts.config {
+        Some(path) => ConfigFile::load(&path)?,
+        None => ConfigFile::default(),
+    };
+
+    let name = config::backend(
+        opts.backend,
+        file.backend.clone(),
+        std::env::var("ENARX_BACKEND").ok(),
+    );
+    let selected = match name.as_deref() {
+        Some(name) => vec![backend::select(backends, name)?],
+        None => backends.iter().filter(|b| b.have()).map(|b| &**b).collect(),
+    };
+
+    let config = opts.launch.config(&file);
+    let time = Duration::from_secs(opts.seconds);
+
+    for backend in selected {
+        println!("Backend: {}", backend.name());
+
+        // Only one nil keep can be built in a process.
+        let builds = match backend.name() {
+            "nil" => 1,
+            _ => opts.builds.max(1),
+        };
+
+        let data = match measurements(backend, &config, builds, time) {
+            Ok(data) => data,
+            Err(e) => vec![Datum {
+                name: "Benchmark".into(),
+                pass: false,
+                info: None,
+                mesg: Some(format!("{:#}", e)),
+            }],
+        };
+
+        print(&data);
+    }
+
+    Ok(())
+}
+
+/// Measures the startup, the syscalls and the writes of keeps of a backend
+fn measurements(
+    backend: &dyn Backend,
+    config: &Config,
+    builds: usize,
+    time: Duration,
+) -> Result<Vec<Datum>> {
+    let mut startups = Vec::with_capacity(builds);
+    let mut keep = bench::Bench::new(backend, config)?;
+    startups.push(keep.startup());
+    for _ in 1..builds {
+        keep = bench::Bench::new(backend, config)?;
+        startups.push(keep.startup());
+    }
+    startups.sort();
+
+    let syscalls = rate(time, |count| keep.syscalls(count))?;
+    let writes = rate(time, |count| keep.write(count << 20))?;
+
+    let datum = |name: &str, info| Datum {
+        name: name.into(),
+        pass: true,
+        info: Some(info),
+        mesg: None,
+    };
+
+    Ok(vec![
+        datum(
+            "Startup",
+            format!("{:.1?} (median of {} builds)", startups[builds / 2], builds),
+        ),
+        datum("Syscalls", format!("{:.0}/s", syscalls)),
+        datum("Writes", format!("{:.1} MiB/s", writes)),
+    ])
+}
+
+/// Measures how many units per second `run` gets through, in about `time`
+///
+/// The count is calibrated with shorter runs first.
+fn rate(time: Duration, mut run: impl FnMut(u64) -> Result<Duration>) -> Result<f64> {
+    let mut count = 1;
+    loop {
+        let elapsed = run(count)?;
+        if elapsed >= time / 10 {
+            let scaled = count as f64 * time.as_secs_f64() / elapsed.as_secs_f64();
+            let count = (scaled as u64).max(1);
+            return Ok(count as f64 / run(count)?.as_secs_f64());
+        }
+
+        count *= 10;
+    }
+}
+
+/// Signs a manifest for a payload
+fn sign(opts: Sign) -> Result<()> {
+    let pem = std::fs::read(&opts.key)
+        .map_err(|e| anyhow!("unable to read {}: {}", opts.key.display(), e))?;
+    let key = openssl::pkey::PKey::private_key_from_pem(&pem)?;
+
+    let hosts = match opts.hosts.iter().fold(0, |all, host| all | host) {
+        0 => manifest::KVM | manifest::SEV,
+        hosts => hosts,
+    };
 
     let map = mmarinus::Kind::Private.load::<mmarinus::perms::Read, _>(&opts.code)?;
-    let shim = Component::from_bytes(backend.shim())?;
     let code = Component::from_bytes(&map)?;
+    let signed = manifest::sign(&key, &code, hosts, opts.expires.unwrap_or(0))?;
+
+    let code = opts.code;
+    let output = opts.output.unwrap_or_else(|| {
+        let mut path = code.into_os_string();
+        path.push(".manifest");
+        path.into()
+    });
+
+    std::fs::write(&output, signed)
+        .map_err(|e| anyhow!("unable to write {}: {}", output.display(), e))
+}
+
+/// Sets up the log, which is filtered by `RUST_LOG`
+///
+/// Building, measuring, spawning and every entry into the keep happen in
+/// spans of their own, inside a span for the keep and its thread. In the
+/// `json` format, every line is a JSON object which can be handed to a log
+/// collector.
+fn logging(format: &str) {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("enarx_keepldr=info"));
 
-    let version = semver::Version::parse(sallyport::VERSION).unwrap();
-    let supported = shim
-        .filter_notes("sallyport", 0)
-        .filter_map(|n| std::str::from_utf8(n).ok())
-        .filter_map(|n| semver::VersionReq::parse(n).ok())
-        .any(|req| req.matches(&version));
-    if !supported {
-        panic!("Unable to satisfy sallyport version requirement.");
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+
+    match format {
+        "json" => builder.json().init(),
+        _ => builder.init(),
     }
+}
 
-    let keep = backend.build(shim, code)?;
-    let mut thread = keep.clone().spawn()?.unwrap();
-    loop {
-        match thread.enter()? {
-            Command::SysCall(block) => unsafe {
-                block.msg.rep = block.msg.req.syscall();
-            },
-            Command::Continue => (),
+#[cfg(feature = "serve")]
+fn serve(backends: Vec<Box<dyn Backend>>, opts: Serve) -> Result<()> {
+    logging(&opts.log_format);
+
+    let file = match opts.config {
+        Some(path) => ConfigFile::load(&path)?,
+        None => ConfigFile::default(),
+    };
+
+    // The keeps use the backends until the process ends.
+    let backends = Box::leak(backends.into_boxed_slice());
+    enarx_keepldr::serve::serve(&opts.socket, backends, opts.launch.config(&file))
+}
+
+fn exec(backends: &[Box<dyn Backend>], opts: Exec) -> Result<()> {
+    logging(&opts.log_format);
+
+    let file = match opts.config {
+        Some(path) => ConfigFile::load(&path)?,
+        None => ConfigFile::default(),
+    };
+    let launch = opts.launch.config(&file);
+
+    let name = config::backend(
+        opts.backend,
+        file.backend,
+        std::env::var("ENARX_BACKEND").ok(),
+    );
+    let backend = backend::select(backends, name.as_deref().unwrap_or("auto"))?;
+
+    let code = match (&opts.restore, opts.code_slot) {
+        (None, None) => Some(
+            opts.code
+                .or(file.code)
+                .ok_or_else(|| anyhow!("no payload given"))?,
+        ),
+        _ => None,
+    };
+
+    let module = code
+        .as_deref()
+        .map(wasm::Module::open)
+        .transpose()?
+        .flatten();
+    let map = match (&code, &module) {
+        (Some(code), None) => Some(mmarinus::Kind::Private.load::<mmarinus::perms::Read, _>(code)?),
+        _ => None,
+    };
+
+    let shim = match opts.shim.or(file.shim) {
+        Some(path) => Some(mmarinus::Kind::Private.load::<mmarinus::perms::Read, _>(&path)?),
+        None => None,
+    };
+
+    let keep = KeepBuilder::new().backend(backend);
+    let keep = match &shim {
+        Some(map) => keep.shim(Component::from_bytes(map)?),
+        None => keep,
+    };
+    let keep = match (&opts.restore, opts.code_slot, &map) {
+        (Some(path), ..) => keep.restore(path),
+        (None, Some(size), _) => keep.code_slot(size),
+        (None, None, Some(map)) => keep.code(Component::from_bytes(map)?),
+        (None, None, None) => keep.code(wasm::runtime()?),
+    };
+
+    let secret = match opts.secret.or(file.secret) {
+        Some(path) if path.as_os_str() == "-" => {
+            let mut secret = Vec::new();
+            std::io::stdin().read_to_end(&mut secret)?;
+            Some(secret)
         }
+        Some(path) => Some(std::fs::read(path)?),
+        None => None,
+    };
+
+    let metrics = opts.metrics.or(file.metrics);
+    let metrics = metrics.as_deref().map(Metrics::serve).transpose()?;
+
+    let ca = opts.sev_owner_ca.or(file.sev_owner_ca);
+    let owner = opts
+        .sev_owner
+        .or(file.sev_owner)
+        .map(|addr| GuestOwner { addr, ca });
+
+    let config = Config {
+        secret,
+        metrics,
+        owner,
+        cpuid: file.cpuid.into_iter().chain(opts.cpuid).collect(),
+        cache: !opts.no_cache,
+        overcommit: opts.sgx_overcommit || file.overcommit,
+        memory: opts.memory.or(file.memory),
+        snapshot: opts.snapshot,
+        ..launch
+    };
+
+    let args = match opts.args.is_empty() {
+        true => file.args,
+        false => opts.args,
+    };
+
+    let env = file
+        .env
+        .into_iter()
+        .chain(opts.env)
+        .chain(module.as_ref().map(wasm::Module::var))
+        .collect();
+    let environ = Environ::new(args, env)?;
+
+    let mounts = Mounts::new(file.mounts.into_iter().chain(opts.mounts).collect())?;
+
+    let mut streams = Streams::default();
+    if let Some(path) = opts.stdout.or(file.stdout) {
+        streams.create(libc::STDOUT_FILENO, &path)?;
+    }
+    if let Some(path) = opts.stderr.or(file.stderr) {
+        streams.create(libc::STDERR_FILENO, &path)?;
+    }
+
+    let mut keep = keep
+        .config(config)
+        .environ(environ)
+        .mounts(mounts)
+        .streams(streams)
+        .policy(file.policy)
+        .trace(opts.trace)
+        .profile(opts.profile)
+        .usage(opts.memory_usage)
+        .sandbox(!opts.no_sandbox)
+        .limits(Limits {
+            cpus: opts.cpu_quota,
+            memory: opts.memory_limit,
+            pids: opts.pids_limit,
+        })
+        .isolation(Isolation {
+            namespaces: opts.unshare,
+            user: opts.user,
+        })
+        .grace(Duration::from_secs(opts.grace));
+
+    if let Some(addr) = opts.gdb.as_deref() {
+        keep = keep.gdb(addr);
+    }
+
+    if let Some(timeout) = opts.timeout {
+        keep = keep.timeout(Duration::from_secs(timeout));
+    }
+
+    if let Some(stall) = opts.watchdog {
+        keep = keep.watchdog(Duration::from_secs(stall));
+    }
+
+    if let Some(seed) = opts.deterministic {
+        keep = keep.deterministic(seed);
+    }
+
+    if let Some(path) = opts.record.as_deref() {
+        keep = keep.record(path);
     }
+
+    if let Some(path) = opts.replay.as_deref() {
+        keep = keep.replay(path);
+    }
+
+    if let Some(path) = opts.core.as_deref() {
+        keep = keep.core(path);
+    }
+
+    let control = opts.control.or(file.control);
+    if let Some(control) = control.as_deref().map(Control::bind).transpose()? {
+        keep = keep.control(control);
+    }
+
+    let exit = keep.spawn()?;
+    std::process::exit(exit.status())
 }
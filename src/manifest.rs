@@ -0,0 +1,148 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Signed payload manifests
+//!
+//! A manifest allows a payload to run on some hosts until it expires. The
+//! loader places it on the page after the image of the payload in the code
+//! slot, where shims built with `ENARX_MANIFEST_KEY` (the public key of the
+//! signer, in hexadecimal) verify it before they start the payload. Such
+//! shims refuse to run payloads without a valid manifest.
+//!
+//! The format is that of `internal/manifest`, which the shims use: a fixed
+//! layout of `SIZE` bytes, ending with an Ed25519 signature over the rest.
+//! It holds the digest of the payload, the backends which may run it and
+//! when it expires:
+//!
+//! ```text
+//! $ enarx-keepldr sign --key signer.pem --host sev --expires 1735689600 ./app
+//! $ enarx-keepldr exec --manifest ./app.manifest ./app
+//! ```
+//!
+//! The key is part of the shim, and so of the measurement. The expiry is
+//! checked against the clock of the host, so it only holds for honest hosts.
+//!
+//! SGX keeps don't verify manifests yet: they refuse `--manifest`, and a
+//! loader built with `ENARX_MANIFEST_KEY` refuses to build SGX keeps at all,
+//! rather than running payloads which nobody signed.
+
+use crate::binary::Component;
+
+use anyhow::{bail, Result};
+use goblin::elf::program_header::PT_LOAD;
+use openssl::pkey::{Id, PKey, Private};
+use openssl::sha::sha256;
+use openssl::sign::Signer;
+use primordial::Page;
+
+/// The first bytes of every manifest
+const MAGIC: &[u8; 8] = b"ENARXMF1";
+
+/// The number of bytes of a manifest
+pub const SIZE: usize = 160;
+
+/// The number of bytes covered by the signature
+const SIGNED_SIZE: usize = 96;
+
+/// The payload may run in plain KVM keeps
+pub const KVM: u32 = 1 << 0;
+
+/// The payload may run in SEV keeps
+pub const SEV: u32 = 1 << 1;
+
+/// Parses the name of a backend which may run the payload
+pub fn host(name: &str) -> Result<u32> {
+    match name {
+        "kvm" => Ok(KVM),
+        "sev" => Ok(SEV),
+        _ => bail!(
+            "manifests are only verified by `kvm` and `sev` keeps, not `{}`",
+            name
+        ),
+    }
+}
+
+/// The number of bytes of the image of the payload, which the manifest follows
+pub fn image_size(code: &Component) -> usize {
+    let end = code
+        .filter_header(PT_LOAD)
+        .map(|ph| ph.p_paddr + ph.p_memsz)
+        .max()
+        .unwrap_or(0) as usize;
+
+    (end + Page::SIZE - 1) & !(Page::SIZE - 1)
+}
+
+/// The image of the payload: its `PT_LOAD` segments at their physical
+/// addresses, as the code slot holds it
+fn image(code: &Component) -> Vec<u8> {
+    let mut image = vec![0; image_size(code)];
+
+    for seg in code.filter_header(PT_LOAD) {
+        let src = &code.bytes[seg.p_offset as usize..][..seg.p_filesz as usize];
+        image[seg.p_paddr as usize..][..src.len()].copy_from_slice(src);
+    }
+
+    image
+}
+
+/// Signs a manifest for the payload
+///
+/// `hosts` are the backends which may run it and `expires` is when the
+/// manifest expires, in seconds since the Unix epoch (0 for never).
+pub fn sign(key: &PKey<Private>, code: &Component, hosts: u32, expires: u64) -> Result<Vec<u8>> {
+    manifest(key, &image(code), hosts, expires)
+}
+
+fn manifest(key: &PKey<Private>, image: &[u8], hosts: u32, expires: u64) -> Result<Vec<u8>> {
+    if key.id() != Id::ED25519 {
+        bail!("manifests are signed with Ed25519 keys");
+    }
+
+    let mut manifest = vec![0; SIZE];
+    manifest[..8].copy_from_slice(MAGIC);
+    manifest[8..40].copy_from_slice(&sha256(image));
+    manifest[40..48].copy_from_slice(&(image.len() as u64).to_le_bytes());
+    manifest[48..52].copy_from_slice(&hosts.to_le_bytes());
+    manifest[56..64].copy_from_slice(&expires.to_le_bytes());
+    manifest[64..96].copy_from_slice(&key.raw_public_key()?);
+
+    let signature =
+        Signer::new_without_digest(key)?.sign_oneshot_to_vec(&manifest[..SIGNED_SIZE])?;
+    manifest[SIGNED_SIZE..].copy_from_slice(&signature);
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use openssl::sign::Verifier;
+
+    #[test]
+    fn signing() {
+        let key = PKey::generate_ed25519().unwrap();
+        let image = vec![1; Page::SIZE];
+
+        let manifest = manifest(&key, &image, KVM | SEV, 1000).unwrap();
+        assert_eq!(manifest.len(), SIZE);
+        assert_eq!(&manifest[..8], MAGIC);
+        assert_eq!(manifest[8..40], sha256(&image));
+        assert_eq!(manifest[40..48], 4096u64.to_le_bytes());
+        assert_eq!(manifest[48..52], 3u32.to_le_bytes());
+        assert_eq!(manifest[56..64], 1000u64.to_le_bytes());
+        assert_eq!(manifest[64..96], key.raw_public_key().unwrap()[..]);
+
+        let (signed, signature) = manifest.split_at(SIGNED_SIZE);
+        let mut verifier = Verifier::new_without_digest(&key).unwrap();
+        assert!(verifier.verify_oneshot(signature, signed).unwrap());
+
+        let rsa = PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap();
+        assert!(super::manifest(&rsa, &image, KVM, 0).is_err());
+    }
+
+    #[test]
+    fn hosts() {
+        assert_eq!(host("kvm").unwrap() | host("sev").unwrap(), KVM | SEV);
+        assert!(host("sgx").is_err());
+    }
+}
@@ -0,0 +1,354 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Keep runtime statistics for Prometheus
+//!
+//! With `--metrics`, an HTTP server is bound at the given address. It answers
+//! every request with the statistics of the keep in the Prometheus text
+//! format:
+//!
+//! ```text
+//! enarx_keep_entries_total 1024
+//! enarx_keep_syscalls_total{nr="1"} 12
+//! enarx_keep_exceptions_total{vector="6"} 512
+//! enarx_keep_epc_pages_added_total 16
+//! enarx_keep_payload_seconds 1.5
+//! ```
+//!
+//! Rates, such as the entries per second, are left to the queries.
+//!
+//! The shims report the peak memory usage of the payload with
+//! `SYS_ENARX_MEMORY` when it exits, which `usage()` summarizes along with
+//! the EPC pages of the keep, and which are served as gauges from then on.
+//! `--memory-usage` prints the summary to stderr, to size the heap and the
+//! resources which the payload requests:
+//!
+//! ```text
+//! payload memory at most: 0 B brk, 0 B mmap, 4.1 KiB stack
+//! EPC pages: 4163 added when built (16.3 MiB), 0 added while running (0 B)
+//! ```
+//!
+//! Exceptions and EPC pages are only counted by the `sgx` backend, and only
+//! the SGX shim reports the memory of the payload.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug, Formatter, Write as _};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use enarx_syscall::SYS_ENARX_MEMORY;
+use primordial::Register;
+use sallyport::Block;
+
+/// The peak memory usage of a payload, in bytes
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+struct Usage {
+    brk: u64,
+    mmap: u64,
+    stack: u64,
+}
+
+#[derive(Default)]
+struct State {
+    launched: Option<Instant>,
+    entries: u64,
+    exits: u64,
+    syscalls: BTreeMap<i64, u64>,
+    exceptions: BTreeMap<u8, u64>,
+    epc_pages: u64,
+    epc_built: u64,
+    usage: Option<Usage>,
+}
+
+/// The runtime statistics of a keep
+///
+/// Clones share the same statistics.
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<Mutex<State>>);
+
+impl Debug for Metrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("Metrics")
+    }
+}
+
+impl Metrics {
+    /// Binds the metrics endpoint and starts answering requests
+    pub fn serve(addr: &str) -> Result<Self> {
+        let listener =
+            TcpListener::bind(addr).map_err(|e| anyhow!("unable to bind {}: {}", addr, e))?;
+
+        Ok(Self::spawn(listener))
+    }
+
+    /// Answers the requests of a listener on a new thread
+    fn spawn(listener: TcpListener) -> Self {
+        let metrics = Self::default();
+        let shared = metrics.clone();
+        std::thread::spawn(move || {
+            for client in listener.incoming().flatten() {
+                let _ = shared.answer(client);
+            }
+        });
+
+        metrics
+    }
+
+    /// Answers an HTTP request with the statistics
+    fn answer(&self, client: TcpStream) -> std::io::Result<()> {
+        // The request itself doesn't matter, but it has to be read.
+        let mut reader = BufReader::new(&client);
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 && line != "\r\n" && line != "\n" {
+            line.clear();
+        }
+
+        let body = self.render();
+        write!(
+            &client,
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\
+             \r\n\
+             {}",
+            body.len(),
+            body
+        )
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.0.lock().unwrap()
+    }
+
+    /// Starts the wall clock of the payload
+    pub fn launched(&self) {
+        self.state().launched = Some(Instant::now());
+    }
+
+    /// Counts an entry into the keep
+    pub fn entered(&self) {
+        self.state().entries += 1;
+    }
+
+    /// Counts an exit from the keep
+    pub fn exited(&self) {
+        self.state().exits += 1;
+    }
+
+    /// Counts a syscall proxied to the host
+    pub fn syscall(&self, num: i64) {
+        *self.state().syscalls.entry(num).or_default() += 1;
+    }
+
+    /// Counts an asynchronous exit caused by an exception
+    pub fn exception(&self, vector: u8) {
+        *self.state().exceptions.entry(vector).or_default() += 1;
+    }
+
+    /// Counts EPC pages added to the keep
+    pub fn epc_added(&self, pages: usize) {
+        self.state().epc_pages += pages as u64;
+    }
+
+    /// Counts EPC pages added to the keep when it was built
+    pub fn epc_built(&self, pages: usize) {
+        self.state().epc_built += pages as u64;
+    }
+
+    /// Handles `SYS_ENARX_MEMORY`
+    pub fn memory(&self, block: &mut Block) -> sallyport::Result {
+        let req = unsafe { block.msg.req };
+        let arg = |i: usize| usize::from(req.arg[i]) as u64;
+        self.state().usage = Some(Usage {
+            brk: arg(0),
+            mmap: arg(1),
+            stack: arg(2),
+        });
+
+        Ok([Register::default(), Register::default()])
+    }
+
+    /// Summarizes the memory which the keep used
+    pub fn usage(&self) -> String {
+        let state = self.state();
+        let mut out = match state.usage {
+            Some(usage) => format!(
+                "payload memory at most: {} brk, {} mmap, {} stack\n",
+                size(usage.brk),
+                size(usage.mmap),
+                size(usage.stack)
+            ),
+            None => "payload memory: not reported by the shim\n".into(),
+        };
+
+        if state.epc_built > 0 {
+            let (built, added) = (state.epc_built, state.epc_pages);
+            writeln!(
+                out,
+                "EPC pages: {} added when built ({}), {} added while running ({})",
+                built,
+                size(built * 4096),
+                added,
+                size(added * 4096)
+            )
+            .unwrap();
+        }
+
+        out
+    }
+
+    /// Formats the statistics in the Prometheus text format
+    fn render(&self) -> String {
+        let state = self.state();
+        let seconds = state.launched.map(|l| l.elapsed().as_secs_f64());
+        let mut out = String::new();
+
+        let help = "Entries into the keep";
+        header(&mut out, "entries_total", "counter", help);
+        writeln!(out, "enarx_keep_entries_total {}", state.entries).unwrap();
+
+        let help = "Exits from the keep";
+        header(&mut out, "exits_total", "counter", help);
+        writeln!(out, "enarx_keep_exits_total {}", state.exits).unwrap();
+
+        let help = "Syscalls proxied to the host, by number";
+        header(&mut out, "syscalls_total", "counter", help);
+        for (num, count) in &state.syscalls {
+            writeln!(out, "enarx_keep_syscalls_total{{nr=\"{}\"}} {}", num, count).unwrap();
+        }
+
+        let help = "Asynchronous exits caused by exceptions, by vector";
+        header(&mut out, "exceptions_total", "counter", help);
+        for (vector, count) in &state.exceptions {
+            let line = format!("enarx_keep_exceptions_total{{vector=\"{}\"}}", vector);
+            writeln!(out, "{} {}", line, count).unwrap();
+        }
+
+        let help = "EPC pages added to the keep after it was built";
+        header(&mut out, "epc_pages_added_total", "counter", help);
+        writeln!(out, "enarx_keep_epc_pages_added_total {}", state.epc_pages).unwrap();
+
+        let help = "EPC pages added to the keep when it was built";
+        header(&mut out, "epc_pages_built", "gauge", help);
+        writeln!(out, "enarx_keep_epc_pages_built {}", state.epc_built).unwrap();
+
+        let Usage { brk, mmap, stack } = state.usage.unwrap_or_default();
+        for (name, bytes) in [("brk", brk), ("mmap", mmap), ("stack", stack)] {
+            let metric = format!("payload_{}_peak_bytes", name);
+            let help = format!("The most {} memory of the payload, as it exited", name);
+            header(&mut out, &metric, "gauge", &help);
+            writeln!(out, "enarx_keep_{} {}", metric, bytes).unwrap();
+        }
+
+        let help = "Wall-clock time since the payload was launched";
+        header(&mut out, "payload_seconds", "gauge", help);
+        writeln!(out, "enarx_keep_payload_seconds {}", seconds.unwrap_or(0.0)).unwrap();
+
+        out
+    }
+}
+
+/// Formats a size in bytes with a binary unit
+fn size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 30 => format!("{:.1} GiB", b as f64 / (1u64 << 30) as f64),
+        b if b >= 1 << 20 => format!("{:.1} MiB", b as f64 / (1u64 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.1} KiB", b as f64 / (1u64 << 10) as f64),
+        b => format!("{} B", b),
+    }
+}
+
+/// Writes the `HELP` and `TYPE` lines of a metric
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP enarx_keep_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE enarx_keep_{} {}", name, kind).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        metrics.entered();
+        metrics.entered();
+        metrics.exited();
+        metrics.syscall(1);
+        metrics.syscall(1);
+        metrics.syscall(60);
+        metrics.exception(6);
+        metrics.epc_added(4);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE enarx_keep_entries_total counter\n"));
+        assert!(text.contains("\nenarx_keep_entries_total 2\n"));
+        assert!(text.contains("\nenarx_keep_exits_total 1\n"));
+        assert!(text.contains("\nenarx_keep_syscalls_total{nr=\"1\"} 2\n"));
+        assert!(text.contains("\nenarx_keep_syscalls_total{nr=\"60\"} 1\n"));
+        assert!(text.contains("\nenarx_keep_exceptions_total{vector=\"6\"} 1\n"));
+        assert!(text.contains("\nenarx_keep_epc_pages_added_total 4\n"));
+        assert!(text.contains("\nenarx_keep_epc_pages_built 0\n"));
+        assert!(text.contains("\nenarx_keep_payload_stack_peak_bytes 0\n"));
+        assert!(text.contains("\nenarx_keep_payload_seconds 0\n"));
+    }
+
+    #[test]
+    fn usage() {
+        let metrics = Metrics::default();
+        assert_eq!(
+            metrics.usage(),
+            "payload memory: not reported by the shim\n"
+        );
+
+        let mut block = Block::default();
+        let req = unsafe { &mut block.msg.req };
+        req.num = (SYS_ENARX_MEMORY as usize).into();
+        req.arg[0] = 512.into();
+        req.arg[1] = (3usize << 20).into();
+        req.arg[2] = (12usize << 10).into();
+        assert!(metrics.memory(&mut block).is_ok());
+        metrics.epc_built(256);
+        metrics.epc_added(16);
+
+        let usage = metrics.usage();
+        let lines: Vec<_> = usage.lines().collect();
+        assert_eq!(
+            lines[0],
+            "payload memory at most: 512 B brk, 3.0 MiB mmap, 12.0 KiB stack"
+        );
+        assert_eq!(
+            lines[1],
+            "EPC pages: 256 added when built (1.0 MiB), 16 added while running (64.0 KiB)"
+        );
+
+        let text = metrics.render();
+        assert!(text.contains("\nenarx_keep_epc_pages_built 256\n"));
+        assert!(text.contains("\nenarx_keep_payload_mmap_peak_bytes 3145728\n"));
+    }
+
+    #[test]
+    fn serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = Metrics::spawn(listener);
+        metrics.launched();
+        metrics.syscall(1);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let (_, body) = reply.split_once("\r\n\r\n").unwrap();
+        assert!(reply.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert!(body.contains("\nenarx_keep_syscalls_total{nr=\"1\"} 1\n"));
+    }
+}
@@ -0,0 +1,617 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host directories exposed to the keep
+//!
+//! The shims serve paths from an in-keep filesystem. Each `--mount` appears
+//! there as a mount point and syscalls on paths beneath it are forwarded to
+//! the host. The host resolves these paths strictly beneath the mounted
+//! directory and refuses every other path it is asked to resolve:
+//!
+//! ```text
+//! $ enarx-keepldr exec --mount ./data:/data:ro ./app
+//! ```
+//!
+//! Mounts are writable unless they are marked as `ro`. The host opens the
+//! directory of a read-only mount on a detached, read-only copy of its mount,
+//! so the kernel refuses every change beneath it, including those made
+//! through descriptors (e.g. `fchmod()`, `fsetxattr()` or `linkat()`). This
+//! takes `CAP_SYS_ADMIN` and Linux 5.12.
+//!
+//! With `sealed`, the
+//! files in the directory are encrypted and authenticated with a key bound
+//! to the measurement of the keep and to the security version of the
+//! platform, so they can only be read back by the same payload on a platform
+//! which wasn't updated since (SGX only).
+//!
+//! Descriptors of in-keep files can be duplicated with `dup()`, `dup2()` and
+//! `fcntl()`, but only onto other descriptors of in-keep files. Pipes are
+//! created by the host.
+
+use std::ffi::{CString, OsStr};
+use std::fs::{File, OpenOptions};
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use enarx_syscall::SYS_ENARX_MOUNTS;
+use libc::{c_int, c_uint};
+use primordial::Register;
+use sallyport::Block;
+
+const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+const RESOLVE_BENEATH: u64 = 0x08;
+
+/// The argument of `openat2()`
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+const OPEN_TREE_CLONE: c_uint = 0x01;
+const AT_RECURSIVE: c_uint = 0x8000;
+const MOUNT_ATTR_RDONLY: u64 = 0x01;
+
+/// The argument of `mount_setattr()`
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+/// A host directory exposed to the keep
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mount {
+    /// The directory on the host
+    pub host: PathBuf,
+
+    /// Where the directory appears inside the keep
+    pub keep: PathBuf,
+
+    /// Whether the keep is denied writes to the directory
+    ///
+    /// The kernel enforces it; the checks of the path syscalls only fail
+    /// earlier.
+    pub read_only: bool,
+
+    /// Whether the shim seals the files in the directory
+    pub sealed: bool,
+}
+
+impl FromStr for Mount {
+    type Err = anyhow::Error;
+
+    /// Parses `HOST:KEEP[:OPTIONS]`, where `OPTIONS` is a comma-separated
+    /// list of `ro`, `rw` and `sealed`
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(':');
+
+        let (host, keep) = match (parts.next(), parts.next()) {
+            (Some(host), Some(keep)) if !host.is_empty() => (host, Path::new(keep)),
+            _ => bail!("invalid mount (expected HOST:KEEP[:OPTIONS]): {}", s),
+        };
+
+        let mut read_only = false;
+        let mut sealed = false;
+        for option in parts.next().into_iter().flat_map(|o| o.split(',')) {
+            match option {
+                "rw" => read_only = false,
+                "ro" => read_only = true,
+                "sealed" => sealed = true,
+                _ => bail!("invalid mount option: {}", option),
+            }
+        }
+
+        if parts.next().is_some() {
+            bail!("invalid mount (expected HOST:KEEP[:OPTIONS]): {}", s);
+        }
+
+        let components = normalize(keep);
+        if !keep.is_absolute() || components.is_empty() {
+            bail!("the keep path must be an absolute path below /: {}", s);
+        }
+
+        Ok(Self {
+            host: host.into(),
+            keep: Path::new("/").join(components.iter().collect::<PathBuf>()),
+            read_only,
+            sealed,
+        })
+    }
+}
+
+/// Resolves `.` and `..` lexically, returning the remaining components
+///
+/// This matches the resolution of the in-keep filesystem.
+fn normalize(path: &Path) -> Vec<&OsStr> {
+    let mut components = Vec::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => components.push(name),
+            Component::ParentDir => {
+                components.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(..) => (),
+        }
+    }
+
+    components
+}
+
+/// Returns the error number of the last failed libc call
+fn errno() -> c_int {
+    std::io::Error::last_os_error().raw_os_error().unwrap()
+}
+
+/// Opens a path without leaving the directory it is relative to
+fn open_beneath(dir: &File, path: &Path, flags: c_int, mode: libc::mode_t) -> Result<File, c_int> {
+    let path = match path.as_os_str().is_empty() {
+        true => Path::new("."),
+        false => path,
+    };
+
+    // The mode must be zero unless a file may be created.
+    let mode = match flags & libc::O_CREAT != 0 || flags & libc::O_TMPFILE == libc::O_TMPFILE {
+        true => mode as u64,
+        false => 0,
+    };
+
+    let path = CString::new(path.as_os_str().as_bytes()).or(Err(libc::EINVAL))?;
+    let how = OpenHow {
+        flags: (flags | libc::O_CLOEXEC) as libc::c_uint as u64,
+        mode,
+        resolve: RESOLVE_BENEATH | RESOLVE_NO_MAGICLINKS,
+    };
+
+    let how: *const OpenHow = &how;
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            dir.as_raw_fd(),
+            path.as_ptr(),
+            how,
+            size_of::<OpenHow>(),
+        )
+    };
+
+    match fd {
+        -1 => Err(errno()),
+        fd => Ok(unsafe { File::from_raw_fd(fd as RawFd) }),
+    }
+}
+
+/// Opens a directory on a read-only copy of its mount
+///
+/// The copy isn't attached anywhere, so only the returned descriptor (and
+/// those opened beneath it) reach it.
+fn open_read_only(path: &Path) -> std::io::Result<File> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let flags = OPEN_TREE_CLONE | AT_RECURSIVE | libc::O_CLOEXEC as c_uint;
+    let fd = unsafe { libc::syscall(libc::SYS_open_tree, libc::AT_FDCWD, path.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let tree = unsafe { File::from_raw_fd(fd as RawFd) };
+    let attr = MountAttr {
+        attr_set: MOUNT_ATTR_RDONLY,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: 0,
+    };
+
+    let attr: *const MountAttr = &attr;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            tree.as_raw_fd(),
+            b"\0".as_ptr(),
+            libc::AT_EMPTY_PATH as c_uint | AT_RECURSIVE,
+            attr,
+            size_of::<MountAttr>(),
+        )
+    };
+
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // The tree may be a file; the mount must be a directory.
+    open_beneath(&tree, Path::new(""), libc::O_RDONLY | libc::O_DIRECTORY, 0)
+        .map_err(std::io::Error::from_raw_os_error)
+}
+
+/// Splits a path into its parent directory and its final component
+///
+/// The mounted directory itself has no parent that the keep may change.
+fn split(path: &Path) -> Result<(&Path, CString), c_int> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Ok((parent, CString::new(name.as_bytes()).unwrap())),
+        _ => Err(libc::EBUSY),
+    }
+}
+
+/// The mounts of a keep, with their host directories opened
+pub struct Mounts(Vec<(Mount, File)>);
+
+impl Mounts {
+    /// Opens the host directory of each mount
+    pub fn new(mounts: Vec<Mount>) -> Result<Self> {
+        for (i, a) in mounts.iter().enumerate() {
+            for b in &mounts[i + 1..] {
+                if a.keep.starts_with(&b.keep) || b.keep.starts_with(&a.keep) {
+                    bail!(
+                        "overlapping mounts: {} and {}",
+                        a.keep.display(),
+                        b.keep.display()
+                    );
+                }
+            }
+        }
+
+        let mut opened = Vec::with_capacity(mounts.len());
+        for mount in mounts {
+            let dir = match mount.read_only {
+                true => open_read_only(&mount.host).map_err(|e| {
+                    anyhow!(
+                        "unable to mount {} read-only (it takes CAP_SYS_ADMIN and Linux 5.12): {}",
+                        mount.host.display(),
+                        e
+                    )
+                })?,
+
+                false => OpenOptions::new()
+                    .read(true)
+                    .custom_flags(libc::O_DIRECTORY)
+                    .open(&mount.host)
+                    .map_err(|e| anyhow!("unable to mount {}: {}", mount.host.display(), e))?,
+            };
+
+            opened.push((mount, dir));
+        }
+
+        Ok(Self(opened))
+    }
+
+    /// Finds the mount containing a keep path
+    ///
+    /// Returns the mount, its host directory and the path relative to it.
+    fn resolve(&self, path: &Path) -> Result<(&Mount, &File, PathBuf), c_int> {
+        if !path.is_absolute() {
+            return Err(libc::EACCES);
+        }
+
+        let components = normalize(path);
+        for (mount, dir) in &self.0 {
+            let keep = normalize(&mount.keep);
+            if components.starts_with(&keep) {
+                let relative = components[keep.len()..].iter().collect();
+                return Ok((mount, dir, relative));
+            }
+        }
+
+        Err(libc::EACCES)
+    }
+
+    /// Handles a syscall which may refer to a mounted path
+    ///
+    /// Returns `None` for syscalls which don't take a path; these are passed
+    /// through as before. Paths must lie in the block and be absolute.
+    pub fn syscall(&self, block: &mut Block) -> Option<sallyport::Result> {
+        let req = unsafe { block.msg.req };
+        let num: i64 = req.num.into();
+        let arg = |i: usize| usize::from(req.arg[i]);
+
+        let ret = match num {
+            SYS_ENARX_MOUNTS => self.list(block),
+
+            libc::SYS_openat => self.openat(block, arg(1), arg(2) as _, arg(3) as _),
+            libc::SYS_newfstatat => self.newfstatat(block, arg(1), arg(2), arg(3) as _),
+            libc::SYS_faccessat => self.faccessat(block, arg(1), arg(2) as _),
+            libc::SYS_mkdirat => self.mkdirat(block, arg(1), arg(2) as _),
+            libc::SYS_unlinkat => self.unlinkat(block, arg(1), arg(2) as _),
+            libc::SYS_renameat2 => self.renameat2(block, arg(1), arg(3), arg(4) as _),
+
+            // The shims only forward the syscalls above for mounted paths.
+            libc::SYS_open
+            | libc::SYS_creat
+            | libc::SYS_stat
+            | libc::SYS_lstat
+            | libc::SYS_access
+            | libc::SYS_mkdir
+            | libc::SYS_rmdir
+            | libc::SYS_unlink
+            | libc::SYS_rename
+            | libc::SYS_renameat => Err(libc::EACCES),
+
+            _ => return None,
+        };
+
+        Some(ret.map(|ret| [ret.into(), Register::default()]))
+    }
+
+    /// Lists the keep paths of the mounts
+    fn list(&self, block: &mut Block) -> Result<usize, c_int> {
+        let mut list = Vec::new();
+        for (mount, _) in &self.0 {
+            list.extend_from_slice(mount.keep.as_os_str().as_bytes());
+            if mount.sealed {
+                list.extend_from_slice(b":sealed");
+            }
+            list.push(0);
+        }
+
+        block
+            .cursor()
+            .copy_from_slice(&list)
+            .or(Err(libc::EMSGSIZE))?;
+        Ok(list.len())
+    }
+
+    /// Reads a path from the block
+    fn path(block: &Block, ptr: usize) -> Result<&Path, c_int> {
+        let start = block as *const Block as usize;
+        let end = start + size_of::<Block>();
+        if ptr < start || ptr >= end {
+            return Err(libc::EFAULT);
+        }
+
+        let bytes = unsafe { std::slice::from_raw_parts(ptr as *const u8, end - ptr) };
+        let len = bytes.iter().position(|b| *b == 0).ok_or(libc::EFAULT)?;
+        Ok(Path::new(OsStr::from_bytes(&bytes[..len])))
+    }
+
+    fn openat(
+        &self,
+        block: &Block,
+        path: usize,
+        flags: c_int,
+        mode: libc::mode_t,
+    ) -> Result<usize, c_int> {
+        let (mount, dir, path) = self.resolve(Self::path(block, path)?)?;
+
+        let writes = flags & libc::O_ACCMODE != libc::O_RDONLY
+            || flags & (libc::O_CREAT | libc::O_TRUNC) != 0
+            || flags & libc::O_TMPFILE == libc::O_TMPFILE;
+        if mount.read_only && writes {
+            return Err(libc::EROFS);
+        }
+
+        let file = open_beneath(dir, &path, flags, mode)?;
+        Ok(file.into_raw_fd() as usize)
+    }
+
+    fn newfstatat(
+        &self,
+        block: &Block,
+        path: usize,
+        statbuf: usize,
+        flags: c_int,
+    ) -> Result<usize, c_int> {
+        let (_, dir, path) = self.resolve(Self::path(block, path)?)?;
+
+        let start = block as *const Block as usize;
+        let end = start + size_of::<Block>();
+        match statbuf.checked_add(size_of::<libc::stat>()) {
+            Some(last) if statbuf >= start && last <= end => (),
+            _ => return Err(libc::EFAULT),
+        }
+
+        let nofollow = match flags & libc::AT_SYMLINK_NOFOLLOW {
+            0 => 0,
+            _ => libc::O_NOFOLLOW,
+        };
+
+        let file = open_beneath(dir, &path, libc::O_PATH | nofollow, 0)?;
+        match unsafe { libc::fstat(file.as_raw_fd(), statbuf as *mut libc::stat) } {
+            0 => Ok(0),
+            _ => Err(errno()),
+        }
+    }
+
+    fn faccessat(&self, block: &Block, path: usize, mode: c_int) -> Result<usize, c_int> {
+        let (mount, dir, path) = self.resolve(Self::path(block, path)?)?;
+        if mount.read_only && mode & libc::W_OK != 0 {
+            return Err(libc::EROFS);
+        }
+
+        // Check the file which was found beneath the mount.
+        let file = open_beneath(dir, &path, libc::O_PATH, 0)?;
+        let proc = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd())).unwrap();
+        match unsafe { libc::faccessat(libc::AT_FDCWD, proc.as_ptr(), mode, 0) } {
+            0 => Ok(0),
+            _ => Err(errno()),
+        }
+    }
+
+    fn mkdirat(&self, block: &Block, path: usize, mode: libc::mode_t) -> Result<usize, c_int> {
+        let (mount, dir, path) = self.resolve(Self::path(block, path)?)?;
+        if mount.read_only {
+            return Err(libc::EROFS);
+        }
+
+        let (parent, name) = split(&path).map_err(|_| libc::EEXIST)?;
+        let parent = open_beneath(dir, parent, libc::O_PATH | libc::O_DIRECTORY, 0)?;
+        match unsafe { libc::mkdirat(parent.as_raw_fd(), name.as_ptr(), mode) } {
+            0 => Ok(0),
+            _ => Err(errno()),
+        }
+    }
+
+    fn unlinkat(&self, block: &Block, path: usize, flags: c_int) -> Result<usize, c_int> {
+        let (mount, dir, path) = self.resolve(Self::path(block, path)?)?;
+        if mount.read_only {
+            return Err(libc::EROFS);
+        }
+
+        let (parent, name) = split(&path)?;
+        let parent = open_beneath(dir, parent, libc::O_PATH | libc::O_DIRECTORY, 0)?;
+        match unsafe { libc::unlinkat(parent.as_raw_fd(), name.as_ptr(), flags) } {
+            0 => Ok(0),
+            _ => Err(errno()),
+        }
+    }
+
+    fn renameat2(
+        &self,
+        block: &Block,
+        oldpath: usize,
+        newpath: usize,
+        flags: libc::c_uint,
+    ) -> Result<usize, c_int> {
+        let (omount, odir, oldpath) = self.resolve(Self::path(block, oldpath)?)?;
+        let (nmount, _, newpath) = self.resolve(Self::path(block, newpath)?)?;
+        if !std::ptr::eq(omount, nmount) {
+            return Err(libc::EXDEV);
+        }
+
+        if omount.read_only {
+            return Err(libc::EROFS);
+        }
+
+        let (oparent, oname) = split(&oldpath)?;
+        let (nparent, nname) = split(&newpath)?;
+        let dirflags = libc::O_PATH | libc::O_DIRECTORY;
+        let oparent = open_beneath(odir, oparent, dirflags, 0)?;
+        let nparent = open_beneath(odir, nparent, dirflags, 0)?;
+
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_renameat2,
+                oparent.as_raw_fd(),
+                oname.as_ptr(),
+                nparent.as_raw_fd(),
+                nname.as_ptr(),
+                flags,
+            )
+        };
+
+        match ret {
+            0 => Ok(0),
+            _ => Err(errno()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let mount: Mount = "/srv/data:/data/./x/../y/:ro".parse().unwrap();
+        assert_eq!(mount.host, Path::new("/srv/data"));
+        assert_eq!(mount.keep, Path::new("/data/y"));
+        assert!(mount.read_only);
+        assert!(!mount.sealed);
+
+        let mount: Mount = "data:/data".parse().unwrap();
+        assert_eq!(mount.host, Path::new("data"));
+        assert!(!mount.read_only);
+
+        let mount: Mount = "data:/data:sealed,ro".parse().unwrap();
+        assert!(mount.read_only);
+        assert!(mount.sealed);
+
+        for invalid in &[
+            "/srv",
+            "/srv:data",
+            "/srv:/",
+            "/srv:/..",
+            "/srv:/x:rx",
+            "/srv:/x:ro,",
+            "/srv:/x:ro:sealed",
+            ":/x",
+        ] {
+            assert!(invalid.parse::<Mount>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn resolve() {
+        let dir = tempdir::TempDir::new("mount").unwrap();
+        let host = dir.path().to_str().unwrap();
+
+        let mount = format!("{}:/mnt/data", host).parse().unwrap();
+        let mounts = Mounts::new(vec![mount]).unwrap();
+
+        let relative = |path| mounts.resolve(Path::new(path)).map(|(_, _, rel)| rel);
+        assert_eq!(relative("/mnt/data"), Ok(PathBuf::new()));
+        assert_eq!(relative("/mnt//data/a/../b"), Ok(PathBuf::from("b")));
+        assert_eq!(relative("/mnt/data/../../etc"), Err(libc::EACCES));
+        assert_eq!(relative("/mnt/database"), Err(libc::EACCES));
+        assert_eq!(relative("mnt/data"), Err(libc::EACCES));
+
+        let overlapping = vec![
+            format!("{}:/a", host).parse().unwrap(),
+            format!("{}:/a/b", host).parse().unwrap(),
+        ];
+        assert!(Mounts::new(overlapping).is_err());
+    }
+
+    #[test]
+    fn beneath() {
+        let dir = tempdir::TempDir::new("mount").unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::os::unix::fs::symlink("/etc", dir.path().join("sub/escape")).unwrap();
+
+        let root = File::open(dir.path()).unwrap();
+        assert!(open_beneath(&root, Path::new("sub"), libc::O_RDONLY, 0).is_ok());
+        assert!(open_beneath(&root, Path::new(""), libc::O_RDONLY, 0).is_ok());
+        assert_eq!(
+            open_beneath(&root, Path::new("sub/escape/passwd"), libc::O_RDONLY, 0).err(),
+            Some(libc::EXDEV)
+        );
+    }
+
+    #[test]
+    fn read_only() {
+        let dir = tempdir::TempDir::new("mount").unwrap();
+        std::fs::write(dir.path().join("file"), b"data").unwrap();
+
+        let host = dir.path().to_str().unwrap();
+        let mount = format!("{}:/data:ro", host).parse().unwrap();
+        let mounts = match Mounts::new(vec![mount]) {
+            Ok(mounts) => mounts,
+
+            // Unprivileged hosts can't have read-only mounts at all.
+            Err(_) if unsafe { libc::geteuid() } != 0 => return,
+            Err(e) => panic!("{}", e),
+        };
+
+        let erofs = |ret: c_int| {
+            assert_eq!(ret, -1);
+            assert_eq!(errno(), libc::EROFS);
+        };
+
+        // Writes through the descriptors which the keep is given fail, too.
+        let (_, root, _) = mounts.resolve(Path::new("/data")).unwrap();
+        let file = open_beneath(root, Path::new("file"), libc::O_RDONLY, 0).unwrap();
+        let (dirfd, fd) = (root.as_raw_fd(), file.as_raw_fd());
+        let old = CString::new("file").unwrap();
+        let new = CString::new("link").unwrap();
+        let attr = CString::new("user.enarx").unwrap();
+        let value = b"x".as_ptr().cast();
+
+        unsafe {
+            erofs(libc::fchmod(fd, 0o777));
+            erofs(libc::fchown(fd, 0, 0));
+            erofs(libc::futimens(fd, std::ptr::null()));
+            erofs(libc::fsetxattr(fd, attr.as_ptr(), value, 1, 0));
+            erofs(libc::fchmodat(dirfd, old.as_ptr(), 0o777, 0));
+            erofs(libc::symlinkat(old.as_ptr(), dirfd, new.as_ptr()));
+            erofs(libc::linkat(dirfd, old.as_ptr(), dirfd, new.as_ptr(), 0));
+        }
+
+        // The host directory itself stays writable.
+        std::fs::write(dir.path().join("file"), b"more").unwrap();
+    }
+}
@@ -0,0 +1,321 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Which syscalls a keep may have the host execute
+//!
+//! A `SyscallPolicy` restricts the syscalls which the host executes for a
+//! keep, the file descriptors they may use and the address families of the
+//! sockets they may create. Denied requests fail with `EPERM` and are logged.
+//! Each restriction is optional, and a keep without any is unrestricted.
+//!
+//! The file descriptors which the keep opens itself are always allowed, so
+//! the allowed descriptors are those the keep inherits, e.g. `0`, `1` and `2`
+//! for the standard streams. Exiting and the requests which the shims send to
+//! the loader itself (e.g. `SYS_ENARX_MOUNTS`) are never denied.
+
+use crate::trace;
+
+use std::collections::BTreeSet;
+use std::mem::size_of;
+use std::ops::RangeInclusive;
+use std::os::unix::io::RawFd;
+
+use anyhow::{anyhow, bail, Result};
+use sallyport::{Block, Request};
+
+/// The requests which the shims send to the loader itself
+const ENARX: RangeInclusive<i64> = 0xEA00..=0xEAFF;
+
+/// The syscalls whose first argument is a file descriptor
+const FD_SYSCALLS: &[i64] = &[
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_close,
+    libc::SYS_lseek,
+    libc::SYS_fstat,
+    libc::SYS_ioctl,
+    libc::SYS_fcntl,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_ftruncate,
+    libc::SYS_getdents64,
+    libc::SYS_dup,
+    libc::SYS_dup2,
+    libc::SYS_dup3,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_connect,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_shutdown,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_wait,
+    libc::SYS_epoll_pwait,
+    libc::SYS_timerfd_settime,
+    libc::SYS_timerfd_gettime,
+];
+
+/// The syscalls whose first argument is a directory file descriptor
+const DIRFD_SYSCALLS: &[i64] = &[
+    libc::SYS_openat,
+    libc::SYS_mkdirat,
+    libc::SYS_newfstatat,
+    libc::SYS_unlinkat,
+    libc::SYS_faccessat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+];
+
+/// The syscalls which return a new file descriptor
+const NEW_FD_SYSCALLS: &[i64] = &[
+    libc::SYS_open,
+    libc::SYS_openat,
+    libc::SYS_creat,
+    libc::SYS_socket,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_dup,
+    libc::SYS_dup2,
+    libc::SYS_dup3,
+    libc::SYS_epoll_create,
+    libc::SYS_epoll_create1,
+    libc::SYS_eventfd2,
+    libc::SYS_timerfd_create,
+];
+
+/// The address families which can be named
+const FAMILIES: &[(&str, i32)] = &[
+    ("unix", libc::AF_UNIX),
+    ("inet", libc::AF_INET),
+    ("inet6", libc::AF_INET6),
+    ("netlink", libc::AF_NETLINK),
+    ("packet", libc::AF_PACKET),
+    ("vsock", libc::AF_VSOCK),
+];
+
+/// Which syscalls a keep may have the host execute
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyscallPolicy {
+    /// The syscalls which the keep may request, or all of them
+    pub syscalls: Option<BTreeSet<i64>>,
+
+    /// The inherited file descriptors which the keep may use, or all of them
+    pub fds: Option<BTreeSet<RawFd>>,
+
+    /// The address families of the sockets the keep may create, or all of
+    /// them
+    pub families: Option<BTreeSet<i32>>,
+}
+
+impl SyscallPolicy {
+    /// Allows only the syscalls with these names (as `--trace` prints them)
+    /// or numbers
+    pub fn syscalls<T: AsRef<str>>(mut self, syscalls: &[T]) -> Result<Self> {
+        let syscalls = syscalls.iter().map(|s| {
+            let s = s.as_ref();
+            trace::number(s)
+                .or_else(|| s.parse().ok())
+                .ok_or_else(|| anyhow!("unknown syscall: {}", s))
+        });
+
+        self.syscalls = Some(syscalls.collect::<Result<_>>()?);
+        Ok(self)
+    }
+
+    /// Allows only these inherited file descriptors
+    pub fn fds(mut self, fds: &[RawFd]) -> Self {
+        self.fds = Some(fds.iter().copied().collect());
+        self
+    }
+
+    /// Allows only sockets of the address families with these names (`unix`,
+    /// `inet`, `inet6`, `netlink`, `packet` and `vsock`) or numbers
+    pub fn families<T: AsRef<str>>(mut self, families: &[T]) -> Result<Self> {
+        let families = families.iter().map(|f| {
+            let f = f.as_ref();
+            match FAMILIES.iter().find(|(name, _)| *name == f) {
+                Some((_, family)) => Ok(*family),
+                None => f
+                    .parse()
+                    .map_err(|_| anyhow!("unknown address family: {}", f)),
+            }
+        });
+
+        self.families = Some(families.collect::<Result<_>>()?);
+        Ok(self)
+    }
+}
+
+/// Enforces a policy for a keep, tracking the descriptors it opened
+pub(crate) struct Guard<'a> {
+    policy: &'a SyscallPolicy,
+    opened: BTreeSet<RawFd>,
+}
+
+impl<'a> Guard<'a> {
+    pub fn new(policy: &'a SyscallPolicy) -> Self {
+        Self {
+            policy,
+            opened: BTreeSet::new(),
+        }
+    }
+
+    /// Returns why the request is denied, if it is
+    pub fn check(&self, req: &Request) -> Result<()> {
+        let num: i64 = req.num.into();
+        let arg = |i: usize| usize::from(req.arg[i]) as i32;
+
+        if ENARX.contains(&num) {
+            return Ok(());
+        }
+
+        if let Some(syscalls) = &self.policy.syscalls {
+            if !syscalls.contains(&num) {
+                bail!("the syscall is not allowed");
+            }
+        }
+
+        let mut fds = Vec::new();
+        if FD_SYSCALLS.contains(&num) {
+            fds.push(arg(0));
+        }
+        if DIRFD_SYSCALLS.contains(&num) && arg(0) != libc::AT_FDCWD {
+            fds.push(arg(0));
+        }
+        if num == libc::SYS_epoll_ctl {
+            fds.push(arg(2));
+        }
+        if num == libc::SYS_renameat || num == libc::SYS_renameat2 {
+            fds.extend(Some(arg(2)).filter(|fd| *fd != libc::AT_FDCWD));
+        }
+
+        if let Some(allowed) = &self.policy.fds {
+            for fd in fds {
+                if !allowed.contains(&fd) && !self.opened.contains(&fd) {
+                    bail!("file descriptor {} is not allowed", fd);
+                }
+            }
+        }
+
+        if let Some(families) = &self.policy.families {
+            let creates = num == libc::SYS_socket || num == libc::SYS_socketpair;
+            if creates && !families.contains(&arg(0)) {
+                bail!("address family {} is not allowed", arg(0));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Notes the descriptors which an executed request opened or closed
+    pub fn executed(&mut self, block: &Block, req: &Request, result: &sallyport::Result) {
+        let num: i64 = req.num.into();
+        let ret = match result {
+            Ok([ret, _]) => usize::from(*ret) as RawFd,
+            Err(_) => return,
+        };
+
+        match num {
+            libc::SYS_close => {
+                self.opened.remove(&(usize::from(req.arg[0]) as RawFd));
+            }
+            libc::SYS_fcntl => match usize::from(req.arg[1]) as i32 {
+                libc::F_DUPFD | libc::F_DUPFD_CLOEXEC => {
+                    self.opened.insert(ret);
+                }
+                _ => (),
+            },
+            libc::SYS_pipe2 => self.opened.extend(pair(block, req.arg[0].into())),
+            libc::SYS_socketpair => self.opened.extend(pair(block, req.arg[3].into())),
+            num if NEW_FD_SYSCALLS.contains(&num) => {
+                self.opened.insert(ret);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Reads the pair of descriptors which `pipe2()` and `socketpair()` return
+///
+/// Only pairs within the block are read.
+fn pair(block: &Block, ptr: usize) -> Vec<RawFd> {
+    let start = block as *const Block as usize;
+    match ptr.checked_sub(start) {
+        Some(offset) if offset + 2 * size_of::<RawFd>() <= size_of::<Block>() => {
+            let fds = unsafe { std::slice::from_raw_parts(ptr as *const RawFd, 2) };
+            fds.to_vec()
+        }
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(num: i64, args: &[usize]) -> Request {
+        let mut block = Block::default();
+        let req = unsafe { &mut block.msg.req };
+        req.num = (num as usize).into();
+        for (reg, arg) in req.arg.iter_mut().zip(args) {
+            *reg = (*arg).into();
+        }
+
+        *req
+    }
+
+    #[test]
+    fn enforce() {
+        let policy = SyscallPolicy::default()
+            .syscalls(&["read", "write", "openat", "close", "socket", "2"])
+            .unwrap()
+            .fds(&[0, 1, 2])
+            .families(&["inet", "unix"])
+            .unwrap();
+        let mut guard = Guard::new(&policy);
+
+        let read = |fd: usize| request(libc::SYS_read, &[fd, 0, 0]);
+        assert!(guard.check(&read(0)).is_ok());
+        assert!(guard.check(&read(3)).is_err());
+        assert!(guard.check(&request(libc::SYS_getpid, &[])).is_err());
+        assert!(guard.check(&request(libc::SYS_open, &[0, 0])).is_ok());
+        assert!(guard.check(&request(0xEA20, &[])).is_ok());
+
+        let inet6 = request(libc::SYS_socket, &[libc::AF_INET6 as usize, 0, 0]);
+        assert!(guard.check(&inet6).is_err());
+
+        // Descriptors which the keep opened are allowed until it closes them.
+        let block = Block::default();
+        let at = libc::AT_FDCWD as usize;
+        let openat = request(libc::SYS_openat, &[at, 0, 0, 0]);
+        assert!(guard.check(&openat).is_ok());
+        guard.executed(&block, &openat, &Ok([3usize.into(), 0usize.into()]));
+        assert!(guard.check(&read(3)).is_ok());
+
+        let close = request(libc::SYS_close, &[3]);
+        guard.executed(&block, &close, &Ok([0usize.into(), 0usize.into()]));
+        assert!(guard.check(&read(3)).is_err());
+    }
+
+    #[test]
+    fn unrestricted() {
+        let policy = SyscallPolicy::default();
+        let guard = Guard::new(&policy);
+        assert!(guard.check(&request(libc::SYS_getpid, &[])).is_ok());
+        assert!(guard.check(&request(libc::SYS_read, &[7, 0, 0])).is_ok());
+
+        assert!(policy.clone().syscalls(&["execveat"]).is_err());
+        assert!(policy.families(&["appletalk"]).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A pool of warm keeps for low-latency launches
+//!
+//! Building a keep takes long, mostly in the platform (e.g. `EINIT` for SGX),
+//! while functions run briefly. A `Pool` builds keeps in advance, so that a
+//! payload is bound to a keep which already runs. The keeps of a pool run
+//! `wasmldr` (see the `wasm` module), which waits on a pipe for the module to
+//! run; their measurement only covers the shim and the runtime, so it is the
+//! same for all payloads.
+//!
+//! Each keep runs on a thread of its own, and is recorded in the registry of
+//! the pool, where it is `Running` once it is warm. Taking a keep from the
+//! pool starts building a replacement, so that the pool stays full. Keeps
+//! which are still in the pool when it is dropped get an empty module and
+//! end.
+
+use crate::backend::{Backend, Config};
+use crate::environ::Environ;
+use crate::exit::Exit;
+use crate::registry::{KeepId, Registry};
+use crate::{wasm, KeepBuilder};
+
+use anyhow::{anyhow, Result};
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+
+/// A keep which waits for its module
+pub struct Warm {
+    id: KeepId,
+    module: File,
+    keep: JoinHandle<Result<Exit>>,
+}
+
+impl Warm {
+    /// The ID of the keep in the registry of the pool
+    pub fn id(&self) -> KeepId {
+        self.id
+    }
+
+    /// Delivers the module to the keep
+    ///
+    /// Returns the thread of the keep, which ends with the keep.
+    pub fn deliver(mut self, module: &[u8]) -> Result<JoinHandle<Result<Exit>>> {
+        self.module
+            .write_all(module)
+            .map_err(|e| anyhow!("unable to deliver the module to {}: {}", self.id, e))?;
+
+        Ok(self.keep)
+    }
+
+    /// Delivers the module to the keep and waits for it to end
+    pub fn run(self, module: &[u8]) -> Result<Exit> {
+        self.deliver(module)?
+            .join()
+            .map_err(|_| anyhow!("the thread of the keep panicked"))?
+    }
+}
+
+/// Keeps which are built in advance
+pub struct Pool {
+    backend: &'static dyn Backend,
+    config: Config,
+    registry: Registry,
+    warm: Mutex<VecDeque<Warm>>,
+}
+
+impl Pool {
+    /// Starts building `size` keeps with the backend and the config
+    pub fn new(backend: &'static dyn Backend, config: Config, size: usize) -> Result<Self> {
+        let pool = Self {
+            backend,
+            config,
+            registry: Registry::default(),
+            warm: Mutex::new(VecDeque::with_capacity(size)),
+        };
+
+        for _ in 0..size {
+            let warm = pool.start()?;
+            pool.warm.lock().unwrap().push_back(warm);
+        }
+
+        Ok(pool)
+    }
+
+    /// Takes the keep which was started first, and starts a replacement
+    pub fn take(&self) -> Result<Warm> {
+        let replacement = self.start()?;
+
+        let mut warm = self.warm.lock().unwrap();
+        warm.push_back(replacement);
+        Ok(warm.pop_front().unwrap())
+    }
+
+    /// The registry of the keeps of the pool, including those taken
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Starts a keep which waits for its module on a pipe
+    fn start(&self) -> Result<Warm> {
+        let mut ends = [0; 2];
+        if unsafe { libc::pipe2(ends.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let (read, module) = unsafe { (File::from_raw_fd(ends[0]), File::from_raw_fd(ends[1])) };
+        let var = format!("ENARX_WASM_FD={}", read.as_raw_fd());
+        let environ = Environ::new(vec![], vec![var])?;
+
+        let (backend, config) = (self.backend, self.config.clone());
+        let (registry, id) = (self.registry.clone(), self.registry.add());
+        let keep = std::thread::spawn(move || {
+            let exit = KeepBuilder::new()
+                .backend(backend)
+                .code(wasm::runtime()?)
+                .config(config)
+                .environ(environ)
+                .registry(&registry, id)
+                .spawn();
+
+            // The keep reads the module from the pipe until it ends.
+            drop(read);
+            exit
+        });
+
+        Ok(Warm { id, module, keep })
+    }
+}
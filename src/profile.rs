@@ -0,0 +1,197 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Profiling of where a keep spends its time
+//!
+//! With `--profile`, the time spent inside the keep and the time spent
+//! proxying syscalls on the host are recorded. When the payload exits, a
+//! summary is printed to stderr:
+//!
+//! ```text
+//! 1.52s in total: 1.20s inside the keep (78.9%), 310.04ms proxying syscalls (20.4%)
+//! exits: 2048 continue, 2048 syscall
+//!
+//! syscall              count       total        mean   <1us  <10us <100us   <1ms  <10ms   more
+//! read                   512    210.02ms     410.2us      0     10    200    300      2      0
+//! write                 1536    100.02ms      65.1us      0    900    636      0      0      0
+//! ```
+//!
+//! The syscalls are listed by their cumulative latency, followed by the
+//! number of calls in each latency bucket.
+
+use crate::trace;
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// The upper bounds of the latency buckets, in nanoseconds
+const BUCKETS: [u128; 5] = [1_000, 10_000, 100_000, 1_000_000, 10_000_000];
+
+/// The number of syscalls listed in the summary
+const TOP: usize = 20;
+
+#[derive(Default)]
+struct Latency {
+    count: u64,
+    total: Duration,
+    buckets: [u64; BUCKETS.len() + 1],
+}
+
+impl Latency {
+    fn record(&mut self, latency: Duration) {
+        let nanos = latency.as_nanos();
+        let bucket = BUCKETS.iter().position(|b| nanos < *b);
+
+        self.count += 1;
+        self.total += latency;
+        self.buckets[bucket.unwrap_or(BUCKETS.len())] += 1;
+    }
+}
+
+/// The time spent by a keep
+pub struct Profile {
+    started: Instant,
+    inside: Duration,
+    host: Duration,
+    exits: BTreeMap<&'static str, u64>,
+    syscalls: BTreeMap<i64, Latency>,
+}
+
+impl Default for Profile {
+    /// Starts profiling
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            inside: Duration::default(),
+            host: Duration::default(),
+            exits: BTreeMap::new(),
+            syscalls: BTreeMap::new(),
+        }
+    }
+}
+
+impl Profile {
+    /// Records the time spent inside the keep and the reason it exited
+    pub fn entered(&mut self, inside: Duration, exit: &'static str) {
+        self.inside += inside;
+        *self.exits.entry(exit).or_default() += 1;
+    }
+
+    /// Records the time spent proxying a syscall
+    pub fn syscall(&mut self, num: i64, latency: Duration) {
+        self.host += latency;
+        self.syscalls.entry(num).or_default().record(latency);
+    }
+
+    /// Formats the summary
+    pub fn report(&self) -> String {
+        let total = self.started.elapsed();
+        let share = |d: Duration| match total.as_secs_f64() {
+            t if t > 0.0 => d.as_secs_f64() / t * 100.0,
+            _ => 0.0,
+        };
+
+        let mut out = String::new();
+        writeln!(
+            out,
+            "{} in total: {} inside the keep ({:.1}%), {} proxying syscalls ({:.1}%)",
+            duration(total),
+            duration(self.inside),
+            share(self.inside),
+            duration(self.host),
+            share(self.host),
+        )
+        .unwrap();
+
+        let exits = self.exits.iter().map(|(e, n)| format!("{} {}", n, e));
+        writeln!(out, "exits: {}", exits.collect::<Vec<_>>().join(", ")).unwrap();
+
+        if self.syscalls.is_empty() {
+            return out;
+        }
+
+        write!(
+            out,
+            "\n{:<16} {:>8} {:>11} {:>11}",
+            "syscall", "count", "total", "mean"
+        )
+        .unwrap();
+        for label in &["<1us", "<10us", "<100us", "<1ms", "<10ms", "more"] {
+            write!(out, " {:>6}", label).unwrap();
+        }
+        out.push('\n');
+
+        let mut syscalls = self.syscalls.iter().collect::<Vec<_>>();
+        syscalls.sort_by_key(|(_, l)| Reverse((l.total, l.count)));
+
+        for (num, latency) in syscalls.into_iter().take(TOP) {
+            write!(
+                out,
+                "{:<16} {:>8} {:>11} {:>11}",
+                trace::name(*num),
+                latency.count,
+                duration(latency.total),
+                duration(latency.total / latency.count as u32),
+            )
+            .unwrap();
+            for count in &latency.buckets {
+                write!(out, " {:>6}", count).unwrap();
+            }
+            out.push('\n');
+        }
+
+        out
+    }
+}
+
+/// Formats a duration in the most readable unit
+fn duration(d: Duration) -> String {
+    match d.as_secs_f64() {
+        s if s >= 1.0 => format!("{:.2}s", s),
+        s if s >= 1e-3 => format!("{:.2}ms", s * 1e3),
+        s => format!("{:.1}us", s * 1e6),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let mut profile = Profile::default();
+        profile.entered(Duration::from_millis(3), "syscall");
+        profile.entered(Duration::from_millis(1), "continue");
+        profile.entered(Duration::from_millis(1), "syscall");
+        profile.syscall(libc::SYS_read, Duration::from_micros(5));
+        profile.syscall(libc::SYS_read, Duration::from_micros(15));
+        profile.syscall(libc::SYS_write, Duration::from_millis(20));
+
+        let report = profile.report();
+        let lines = report.lines().collect::<Vec<_>>();
+        assert!(lines[0].contains(" 5.00ms inside the keep ("));
+        assert!(lines[0].contains(" 20.02ms proxying syscalls ("));
+        assert_eq!(lines[1], "exits: 1 continue, 2 syscall");
+        assert!(lines[3].starts_with("syscall "));
+
+        let write = lines[4].split_whitespace().collect::<Vec<_>>();
+        assert_eq!(
+            write,
+            ["write", "1", "20.00ms", "20.00ms", "0", "0", "0", "0", "0", "1"]
+        );
+
+        let read = lines[5].split_whitespace().collect::<Vec<_>>();
+        assert_eq!(
+            read,
+            ["read", "2", "20.0us", "10.0us", "0", "1", "1", "0", "0", "0"]
+        );
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(duration(Duration::from_millis(1500)), "1.50s");
+        assert_eq!(duration(Duration::from_micros(2500)), "2.50ms");
+        assert_eq!(duration(Duration::from_nanos(1500)), "1.5us");
+    }
+}
@@ -0,0 +1,187 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Recording and replaying the syscalls of a keep
+//!
+//! With `--record PATH`, the block of every request which the host executes
+//! for the keep is written to a file once the reply is in it, so that the
+//! data which the host returned is kept along with the reply. Each block is
+//! written as soon as it is executed, so the recording survives a crash of
+//! the loader.
+//!
+//! With `--replay PATH`, the requests are answered from such a recording
+//! instead of the host OS: the recorded block replaces the one of the shim,
+//! which sees the same replies and data as in the recorded run. The keep
+//! stops when it requests another syscall than the recorded one, or more
+//! syscalls than were recorded.
+//!
+//! Requests which the host handles without the OS, such as exits and
+//! `SYS_ENARX_DOORBELL`, and those which the backends handle themselves,
+//! such as those of the SGX ring, are neither recorded nor replayed.
+
+use crate::trace;
+
+use anyhow::{anyhow, bail, Context, Result};
+use sallyport::Block;
+
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::mem::{size_of, MaybeUninit};
+use std::path::Path;
+
+/// Starts every recording
+const MAGIC: [u8; 8] = *b"ENARXREC";
+
+/// Changes whenever the format of the recordings changes
+const FORMAT: u32 = 1;
+
+/// The header of a recording: the magic, the format and the size of a block
+fn header() -> [u8; 16] {
+    let mut header = [0; 16];
+    header[..8].copy_from_slice(&MAGIC);
+    header[8..12].copy_from_slice(&FORMAT.to_le_bytes());
+    header[12..].copy_from_slice(&(size_of::<Block>() as u32).to_le_bytes());
+    header
+}
+
+fn bytes(block: &Block) -> &[u8] {
+    unsafe { std::slice::from_raw_parts((block as *const Block).cast(), size_of::<Block>()) }
+}
+
+/// Writes the syscalls of a keep to a file
+pub struct Recorder(File);
+
+impl Recorder {
+    /// Creates the recording at `path`, replacing any file there
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut file = File::create(path)
+            .with_context(|| format!("unable to create the recording {}", path.display()))?;
+        file.write_all(&header())?;
+        Ok(Self(file))
+    }
+
+    /// Records a block with the reply to its request
+    pub fn record(&mut self, block: &Block) -> Result<()> {
+        self.0
+            .write_all(bytes(block))
+            .context("unable to write the recording")
+    }
+}
+
+/// Answers the syscalls of a keep from a recording
+pub struct Replayer {
+    file: File,
+    count: u64,
+}
+
+impl Replayer {
+    /// Opens the recording at `path`
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = File::open(path)
+            .with_context(|| format!("unable to open the recording {}", path.display()))?;
+
+        let mut found = [0; 16];
+        file.read_exact(&mut found)
+            .ok()
+            .filter(|_| found == header())
+            .ok_or_else(|| anyhow!("{} is not a recording of this loader", path.display()))?;
+
+        Ok(Self { file, count: 0 })
+    }
+
+    /// Replaces the block with the next recorded one
+    ///
+    /// It fails unless the recorded block holds the same syscall.
+    pub fn replay(&mut self, block: &mut Block) -> Result<()> {
+        let mut recorded = MaybeUninit::<Block>::uninit();
+        let buf = unsafe {
+            std::slice::from_raw_parts_mut(recorded.as_mut_ptr().cast::<u8>(), size_of::<Block>())
+        };
+
+        match self.file.read_exact(buf) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                bail!(
+                    "the keep requested more syscalls than the {} recorded",
+                    self.count
+                )
+            }
+            Err(e) => return Err(e).context("unable to read the recording"),
+        }
+
+        let recorded = unsafe { recorded.assume_init() };
+        let expected: i64 = unsafe { recorded.msg.req.num.into() };
+        let found: i64 = unsafe { block.msg.req.num.into() };
+        if expected != found {
+            bail!(
+                "the keep diverged from the recording at syscall {}: it requested {} instead of {}",
+                self.count,
+                trace::name(found),
+                trace::name(expected),
+            );
+        }
+
+        *block = recorded;
+        self.count += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(num: i64) -> Block {
+        let mut block = Block::default();
+        block.msg.req.num = (num as usize).into();
+        block
+    }
+
+    fn record(path: &Path) {
+        let mut recorder = Recorder::create(path).unwrap();
+
+        let mut read = request(libc::SYS_read);
+        read.buf[..4].copy_from_slice(b"data");
+        read.msg.rep = sallyport::Result::Ok([4usize.into(), 0usize.into()]).into();
+        recorder.record(&read).unwrap();
+
+        let mut write = request(libc::SYS_write);
+        write.msg.rep = sallyport::Result::Err(libc::EPIPE).into();
+        recorder.record(&write).unwrap();
+    }
+
+    #[test]
+    fn replay() {
+        let dir = tempdir::TempDir::new("record").unwrap();
+        let path = dir.path().join("trace.bin");
+        record(&path);
+
+        let mut replayer = Replayer::open(&path).unwrap();
+        let mut read = request(libc::SYS_read);
+        replayer.replay(&mut read).unwrap();
+        let ret: sallyport::Result = unsafe { read.msg.rep }.into();
+        assert_eq!(usize::from(ret.unwrap()[0]), 4);
+        assert_eq!(&read.buf[..4], b"data");
+
+        let mut write = request(libc::SYS_write);
+        replayer.replay(&mut write).unwrap();
+        let ret: sallyport::Result = unsafe { write.msg.rep }.into();
+        assert_eq!(ret.err(), Some(libc::EPIPE));
+
+        // The recording is exhausted.
+        assert!(replayer.replay(&mut request(libc::SYS_write)).is_err());
+
+        // The keep diverged from the recording.
+        let mut replayer = Replayer::open(&path).unwrap();
+        assert!(replayer.replay(&mut request(libc::SYS_close)).is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        let dir = tempdir::TempDir::new("record").unwrap();
+        let path = dir.path().join("trace.bin");
+
+        std::fs::write(&path, b"ENARXREC").unwrap();
+        assert!(Replayer::open(&path).is_err());
+        assert!(Replayer::open(&dir.path().join("missing")).is_err());
+    }
+}
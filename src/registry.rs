@@ -0,0 +1,271 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Hosting many keeps in one process
+//!
+//! `KeepBuilder::spawn()` runs a keep on the calling thread, so a process
+//! hosts several keeps at once by spawning each on a thread of its own. The
+//! backends hold no state of the keeps they build, so all the keeps share
+//! them without locking. A `Registry` gives each keep an ID and a record,
+//! through which other threads query the status of the keep, its measurement
+//! for attestation, its metrics while it runs and, once it ended, the memory
+//! which its shutdown released (see `Keep::shutdown()`). They stop the keep
+//! with `Registry::stop()`:
+//!
+//! ```no_run
+//! use enarx_keepldr::binary::Component;
+//! use enarx_keepldr::registry::Registry;
+//! use enarx_keepldr::{backend, KeepBuilder};
+//!
+//! // The backends are zero-sized, so leaking them costs nothing.
+//! let backends: &'static [_] = Box::leak(backend::all().into_boxed_slice());
+//! let backend = backend::select(backends, "auto")?;
+//! let registry = Registry::default();
+//!
+//! for path in &["a", "b"] {
+//!     let (registry, id) = (registry.clone(), registry.add());
+//!     let payload = std::fs::read(path)?;
+//!     std::thread::spawn(move || {
+//!         KeepBuilder::new()
+//!             .backend(backend)
+//!             .code(Component::from_bytes(&payload)?)
+//!             .registry(&registry, id)
+//!             .spawn()
+//!     });
+//! }
+//!
+//! for (id, record) in registry.list() {
+//!     println!("{}: {:?}", id, record.status);
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! The limits, the isolation and the handlers installed by `grace()` apply to
+//! the whole process, and with it to all of its keeps.
+
+use crate::backend::interrupt::Interrupt;
+use crate::backend::Usage;
+use crate::exit::Exit;
+use crate::metrics::Metrics;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// The ID of a keep in a registry
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KeepId(pub(crate) u64);
+
+impl fmt::Display for KeepId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "keep-{}", self.0)
+    }
+}
+
+/// Where a keep is in its lifecycle
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// The keep was added, but not spawned yet
+    Pending,
+
+    /// The backend builds the keep
+    Building,
+
+    /// The payload runs
+    Running,
+
+    /// The payload ended
+    Exited(Exit),
+
+    /// The keep failed with this error
+    Failed(String),
+}
+
+/// What a registry knows about a keep
+#[derive(Clone, Debug)]
+pub struct Record {
+    /// Where the keep is in its lifecycle
+    pub status: Status,
+
+    /// The name of the backend which built the keep
+    pub backend: Option<&'static str>,
+
+    /// The measurement of the keep, in hexadecimal, for backends which
+    /// measure keeps
+    pub measurement: Option<String>,
+
+    /// The runtime statistics of the keep, if it collects them
+    pub metrics: Option<Metrics>,
+
+    /// What the keep released when it was shut down, once it ended
+    pub usage: Option<Usage>,
+}
+
+/// What stops a keep
+#[derive(Default)]
+struct Stop {
+    /// Whether the keep was asked to stop
+    requested: bool,
+
+    /// The thread which runs the keep, once it runs
+    interrupt: Option<Interrupt>,
+}
+
+/// The keeps of a process, by ID
+///
+/// Clones share the same keeps.
+#[derive(Clone, Default)]
+pub struct Registry {
+    keeps: Arc<RwLock<BTreeMap<KeepId, Record>>>,
+    stops: Arc<Mutex<BTreeMap<KeepId, Stop>>>,
+    next: Arc<AtomicU64>,
+}
+
+impl Registry {
+    /// Adds a pending keep and returns its ID
+    pub fn add(&self) -> KeepId {
+        let id = KeepId(self.next.fetch_add(1, Ordering::Relaxed));
+        let record = Record {
+            status: Status::Pending,
+            backend: None,
+            measurement: None,
+            metrics: None,
+            usage: None,
+        };
+
+        self.keeps.write().unwrap().insert(id, record);
+        id
+    }
+
+    /// Returns the record of a keep
+    pub fn get(&self, id: KeepId) -> Option<Record> {
+        self.keeps.read().unwrap().get(&id).cloned()
+    }
+
+    /// Returns the records of all keeps, ordered by ID
+    pub fn list(&self) -> Vec<(KeepId, Record)> {
+        let keeps = self.keeps.read().unwrap();
+        keeps.iter().map(|(id, r)| (*id, r.clone())).collect()
+    }
+
+    /// Forgets a keep, returning its last record
+    ///
+    /// A keep which still runs is not stopped, but no longer recorded.
+    pub fn remove(&self, id: KeepId) -> Option<Record> {
+        self.keeps.write().unwrap().remove(&id)
+    }
+
+    /// Stops a keep which didn't end yet
+    ///
+    /// The thread which runs the keep is interrupted, and the keep ends with
+    /// `Exit::Signal(SIGKILL)` once the thread is out of it. A keep which
+    /// isn't running yet ends as soon as it is built. Returns `false` if the
+    /// keep ended already, or isn't recorded.
+    pub fn stop(&self, id: KeepId) -> bool {
+        let interrupt = {
+            let mut stops = self.stops.lock().unwrap();
+            match self.get(id).map(|r| r.status) {
+                Some(Status::Pending) | Some(Status::Building) | Some(Status::Running) => (),
+                _ => return false,
+            }
+
+            let stop = stops.entry(id).or_default();
+            stop.requested = true;
+            stop.interrupt.clone()
+        };
+
+        // The lock is released, so that the thread can see the request.
+        if let Some(interrupt) = interrupt {
+            interrupt.interrupt();
+
+            // A syscall which the host executes for the keep fails with EINTR.
+            interrupt.signal();
+        }
+
+        true
+    }
+
+    /// Lets `stop()` interrupt the thread which runs a keep
+    ///
+    /// Returns whether the keep was asked to stop already.
+    pub(crate) fn interruptible(&self, id: KeepId, interrupt: Interrupt) -> bool {
+        let mut stops = self.stops.lock().unwrap();
+        let stop = stops.entry(id).or_default();
+        stop.interrupt = Some(interrupt);
+        stop.requested
+    }
+
+    /// Whether a keep was asked to stop
+    pub(crate) fn stopping(&self, id: KeepId) -> bool {
+        let stops = self.stops.lock().unwrap();
+        stops.get(&id).map_or(false, |s| s.requested)
+    }
+
+    /// Forgets how to stop a keep, once its status says that it ended
+    pub(crate) fn stopped(&self, id: KeepId) {
+        self.stops.lock().unwrap().remove(&id);
+    }
+
+    /// Updates the record of a keep, unless it was removed
+    pub(crate) fn update(&self, id: KeepId, update: impl FnOnce(&mut Record)) {
+        if let Some(record) = self.keeps.write().unwrap().get_mut(&id) {
+            update(record);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lifecycle() {
+        let registry = Registry::default();
+        let a = registry.add();
+        let b = registry.clone().add();
+        assert_ne!(a, b);
+
+        registry.update(a, |r| {
+            r.status = Status::Running;
+            r.backend = Some("kvm");
+        });
+        registry.update(b, |r| r.status = Status::Exited(Exit::Code(3)));
+
+        let list = registry.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].1.status, Status::Running);
+        assert_eq!(list[0].1.backend, Some("kvm"));
+        assert_eq!(list[1].1.status, Status::Exited(Exit::Code(3)));
+
+        assert!(registry.remove(a).is_some());
+        assert!(registry.get(a).is_none());
+        registry.update(a, |r| r.status = Status::Running);
+        assert!(registry.get(a).is_none());
+        assert_eq!(
+            registry.get(b).unwrap().status,
+            Status::Exited(Exit::Code(3))
+        );
+    }
+
+    #[test]
+    fn stop() {
+        let registry = Registry::default();
+        let (a, b) = (registry.add(), registry.add());
+
+        // A keep which is asked to stop before it runs stops once it does.
+        assert!(registry.stop(a));
+        assert!(registry.stopping(a));
+        assert!(!registry.stopping(b));
+
+        let interrupt = Interrupt::current().unwrap();
+        assert!(registry.interruptible(a, interrupt.clone()));
+        assert!(!registry.interruptible(b, interrupt));
+
+        // Keeps which ended can't be stopped.
+        registry.update(a, |r| r.status = Status::Exited(Exit::Code(0)));
+        registry.stopped(a);
+        assert!(!registry.stopping(a));
+        assert!(!registry.stop(a));
+        assert!(!registry.stop(KeepId(100)));
+    }
+}
@@ -0,0 +1,293 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A seccomp sandbox around the syscalls which the host executes for a keep
+//!
+//! The shim asks the host to execute syscalls for the payload, and the host
+//! executes most of them as they are. A compromised shim could use this to
+//! make any syscall on the host, such as `execve()`. So before the main thread
+//! of the keep runs, it confines itself to the syscalls which the shims
+//! legitimately request and which the loader needs to serve them. Any other
+//! syscall fails with `ENOSYS`, which the payload gets for syscalls that the
+//! shims don't support, too.
+//!
+//! The filter only applies to the calling thread and the threads it spawns,
+//! and it can't be lifted again. Signals may only be sent to the loader
+//! itself. Threads can't be spawned at all, since the host would clone
+//! itself onto a stack of the shim's choosing for a request, so the backends
+//! start the threads of a keep (e.g. the SGX clock) before it is sandboxed.
+//!
+//! The nil backend builds the filter which traps the syscalls of its payload
+//! from the same instructions.
+
+use anyhow::{bail, Result};
+
+/// A classic BPF instruction (see `linux/filter.h`)
+#[repr(C)]
+pub(crate) struct Filter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+/// A classic BPF program (see `linux/filter.h`)
+#[repr(C)]
+struct Program {
+    len: u16,
+    filter: *const Filter,
+}
+
+const BPF_LD_W_ABS: u16 = 0x20;
+pub(crate) const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+
+pub(crate) const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+pub(crate) const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+pub(crate) const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+pub(crate) const AUDIT_ARCH_X86_64: u32 = 0xc000_003e;
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// The offsets in `struct seccomp_data`
+pub(crate) const NR: u32 = 0;
+pub(crate) const ARCH: u32 = 4;
+pub(crate) const IP: u32 = 8;
+const ARG0: u32 = 16;
+
+/// The syscalls which are executed for the keep
+const ALLOWED: &[libc::c_long] = &[
+    // Files
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_open,
+    libc::SYS_openat,
+    libc::SYS_creat,
+    libc::SYS_close,
+    libc::SYS_lseek,
+    libc::SYS_stat,
+    libc::SYS_fstat,
+    libc::SYS_lstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_access,
+    libc::SYS_faccessat,
+    libc::SYS_readlink,
+    libc::SYS_readlinkat,
+    libc::SYS_getdents64,
+    libc::SYS_mkdir,
+    libc::SYS_mkdirat,
+    libc::SYS_rmdir,
+    libc::SYS_unlink,
+    libc::SYS_unlinkat,
+    libc::SYS_rename,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_ftruncate,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_dup,
+    libc::SYS_dup2,
+    libc::SYS_dup3,
+    libc::SYS_pipe,
+    libc::SYS_pipe2,
+    libc::SYS_getcwd,
+    // Sockets
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_connect,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_shutdown,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    // Waiting
+    libc::SYS_poll,
+    libc::SYS_ppoll,
+    libc::SYS_select,
+    libc::SYS_pselect6,
+    libc::SYS_epoll_create,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_wait,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_timerfd_create,
+    libc::SYS_timerfd_settime,
+    libc::SYS_timerfd_gettime,
+    libc::SYS_futex,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_sched_yield,
+    // Memory, also for the keeps of the SGX backend
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mprotect,
+    libc::SYS_mremap,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    // The process
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_uname,
+    libc::SYS_getrandom,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_gettimeofday,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+];
+
+/// The syscalls which may only send signals to the loader itself
+const SIGNALS: &[libc::c_long] = &[libc::SYS_kill, libc::SYS_tgkill];
+
+/// Whether the syscall is executed for keeps
+///
+/// The syscalls which may only send signals to the loader are, too.
+pub(crate) fn allowed(num: libc::c_long) -> bool {
+    ALLOWED.contains(&num) || SIGNALS.contains(&num)
+}
+
+/// Confines the calling thread to the syscalls which are executed for keeps
+pub fn install() -> Result<()> {
+    confine(&filter(std::process::id()))
+}
+
+/// Applies a filter to the calling thread
+pub(crate) fn confine(filter: &[Filter]) -> Result<()> {
+    let program = Program {
+        len: filter.len() as u16,
+        filter: filter.as_ptr(),
+    };
+
+    unsafe {
+        let (one, zero): (libc::c_ulong, libc::c_ulong) = (1, 0);
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, one, zero, zero, zero) < 0 {
+            bail!(
+                "unable to sandbox the loader: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+
+        let program: *const Program = &program;
+        let mode = libc::SECCOMP_MODE_FILTER as libc::c_ulong;
+        if libc::prctl(libc::PR_SET_SECCOMP, mode, program) < 0 {
+            bail!(
+                "unable to sandbox the loader: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn load(offset: u32) -> Filter {
+    Filter {
+        code: BPF_LD_W_ABS,
+        jt: 0,
+        jf: 0,
+        k: offset,
+    }
+}
+
+pub(crate) fn jump(code: u16, k: u32, jt: u8, jf: u8) -> Filter {
+    Filter { code, jt, jf, k }
+}
+
+pub(crate) fn ret(k: u32) -> Filter {
+    Filter {
+        code: BPF_RET_K,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+/// Builds the filter for the process with the ID
+fn filter(pid: u32) -> Vec<Filter> {
+    let mut filter = vec![
+        // Syscalls of other ABIs would bypass the checks.
+        load(ARCH),
+        jump(BPF_JMP_JEQ_K, AUDIT_ARCH_X86_64, 1, 0),
+        ret(SECCOMP_RET_KILL_PROCESS),
+        load(NR),
+        jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1),
+        ret(SECCOMP_RET_KILL_PROCESS),
+    ];
+
+    for num in ALLOWED {
+        filter.push(jump(BPF_JMP_JEQ_K, *num as u32, 0, 1));
+        filter.push(ret(SECCOMP_RET_ALLOW));
+    }
+
+    // The first argument of these is the process ID. Only its lower half is
+    // compared, but process IDs are below 2^22.
+    for num in SIGNALS {
+        filter.push(jump(BPF_JMP_JEQ_K, *num as u32, 0, 4));
+        filter.push(load(ARG0));
+        filter.push(jump(BPF_JMP_JEQ_K, pid, 0, 1));
+        filter.push(ret(SECCOMP_RET_ALLOW));
+        filter.push(ret(SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    }
+
+    filter.push(ret(SECCOMP_RET_ERRNO | libc::ENOSYS as u32));
+    filter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confines() {
+        // The filter only applies to the thread which installs it.
+        std::thread::spawn(|| {
+            install().unwrap();
+
+            assert_eq!(unsafe { libc::getpid() } as u32, std::process::id());
+
+            let null = std::ptr::null::<libc::c_char>();
+            assert_eq!(unsafe { libc::execve(null, &null, &null) }, -1);
+            assert_eq!(
+                std::io::Error::last_os_error().raw_os_error(),
+                Some(libc::ENOSYS)
+            );
+
+            assert!(std::thread::Builder::new().spawn(|| ()).is_err());
+
+            let other = std::process::id() as libc::pid_t + 1;
+            assert_eq!(unsafe { libc::kill(other, 0) }, -1);
+            assert_eq!(
+                std::io::Error::last_os_error().raw_os_error(),
+                Some(libc::EPERM)
+            );
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,753 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Running many keeps on a few host threads
+//!
+//! A keep normally has a host thread to itself (see `KeepBuilder::spawn()`),
+//! which blocks whenever the payload waits for I/O, e.g. for a connection or
+//! for its stdin. Hosting hundreds of keeps like this takes hundreds of host
+//! threads which mostly wait. A `Scheduler` runs keeps on a fixed number of
+//! host threads instead: each keep runs on a stack of its own, and a host
+//! thread switches between its keeps whenever the one it runs would block.
+//!
+//! Keeps switch where the host executes their syscalls: before a `read()`,
+//! `write()`, `accept()` and the like on a descriptor which isn't ready, the
+//! keep is parked until it is (see `ready()`), and the host thread runs
+//! another keep. A keep which runs for a whole `SLICE` while others wait is
+//! interrupted (see `backend::interrupt`), so that it yields the next time it
+//! exits to the host; a syscall which the host executes for it at that moment
+//! may fail with `EINTR`.
+//!
+//! A keep stays on the host thread which it started on, since that is the
+//! thread which its interrupts are sent to. The keeps on a host thread share
+//! its namespaces, so they can't be isolated one by one, and they can't be
+//! debugged, since GDB would stop them all. The other threads of a keep, such
+//! as the application processors of VM-based keeps, and its watchdog still
+//! get host threads of their own.
+//!
+//! Keeps on a scheduler aren't sandboxed (see `KeepBuilder::sandbox()`): the
+//! host threads build the keeps which are spawned on them, which takes
+//! syscalls that the filter forbids, such as `clone()`, and the filter can't
+//! be lifted again. So the host executes any syscall which their shims
+//! request. Callers who host untrusted payloads this way confine the whole
+//! loader instead, e.g. with a seccomp profile of their container runtime.
+//!
+//! The `tracing` spans which a keep entered are entered on its host thread.
+//! They are exited while the keep is switched out (see `suspend()`), so that
+//! the next keep doesn't run in them.
+
+use crate::backend::interrupt::Interrupt;
+use crate::exit::Exit;
+
+use anyhow::{anyhow, bail, Result};
+use mmarinus::{perms, Kind, Map};
+use sallyport::Request;
+use tracing::{dispatcher, error, Span};
+
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::panic::AssertUnwindSafe;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+/// How long a keep runs before it yields to the others which wait
+const SLICE: Duration = Duration::from_millis(10);
+
+/// The size of the stack of a keep, including its guard page
+const STACK: usize = 1 << 20;
+
+const PAGE: usize = 4096;
+
+/// The token of the descriptor which wakes a host thread
+const WAKE: u64 = u64::MAX;
+
+thread_local! {
+    /// The keep which runs on the thread, if it is a host thread of a scheduler
+    static CURRENT: Cell<*mut Task> = Cell::new(null_mut());
+}
+
+/// Why a keep switched back to its host thread
+enum Switch {
+    /// The keep was preempted
+    Yield,
+
+    /// The keep waits for `events` (`POLLIN` or `POLLOUT`) on `fd`
+    Park { fd: RawFd, events: i16 },
+
+    /// The keep ended
+    Done,
+}
+
+/// A keep on a stack of its own
+struct Task {
+    /// Where the keep resumes
+    context: libc::ucontext_t,
+
+    /// Where its host thread resumes when the keep switches back
+    back: libc::ucontext_t,
+
+    stack: Map<perms::ReadWrite>,
+
+    /// What the keep runs, until it starts
+    body: Option<Box<dyn FnOnce() + Send>>,
+
+    /// The host thread of the keep, once it took the keep
+    worker: *const Worker,
+
+    /// Why the keep switched back last
+    switch: Switch,
+
+    /// Whether the keep was interrupted while it was parked
+    interrupted: bool,
+
+    /// The interrupt of the keep, which wakes it while it is parked
+    interrupt: Option<Interrupt>,
+
+    /// Where the owner of the keep waits for how it ended
+    result: mpsc::Sender<Result<Exit>>,
+}
+
+// The context and the stack of a task are only used by the host thread which
+// runs it, and its body is `Send`.
+unsafe impl Send for Task {}
+
+impl Task {
+    fn new(body: Box<dyn FnOnce() + Send>, result: mpsc::Sender<Result<Exit>>) -> Result<Self> {
+        let stack = Map::map(STACK)
+            .anywhere()
+            .anonymously()
+            .known::<perms::ReadWrite>(Kind::Private)?;
+
+        // The stack grows down towards its guard page.
+        let guard = stack.addr() as *mut libc::c_void;
+        if unsafe { libc::mprotect(guard, PAGE, libc::PROT_NONE) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok(Self {
+            context: unsafe { std::mem::zeroed() },
+            back: unsafe { std::mem::zeroed() },
+            stack,
+            body: Some(body),
+            worker: std::ptr::null(),
+            switch: Switch::Done,
+            interrupted: false,
+            interrupt: None,
+            result,
+        })
+    }
+
+    /// Prepares the keep to start on the calling host thread
+    ///
+    /// The task must not move from now on.
+    fn prepare(&mut self, worker: &Worker) -> Result<()> {
+        self.worker = worker;
+
+        unsafe {
+            if libc::getcontext(&mut self.context) < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+
+            self.context.uc_stack.ss_sp = self.stack.addr() as *mut _;
+            self.context.uc_stack.ss_size = STACK;
+            self.context.uc_link = &mut self.back;
+            libc::makecontext(&mut self.context, start, 0);
+        }
+
+        Ok(())
+    }
+
+    /// Runs the keep until it switches back
+    fn resume(&mut self) {
+        CURRENT.with(|c| c.set(self));
+        unsafe { libc::swapcontext(&mut self.back, &self.context) };
+        CURRENT.with(|c| c.set(null_mut()));
+    }
+}
+
+/// Starts the keep which the host thread switched to
+extern "C" fn start() {
+    let task = CURRENT.with(Cell::get);
+    let body = unsafe { (*task).body.take() }.unwrap();
+    body();
+
+    // Returning resumes the host thread (see `uc_link`).
+    unsafe { (*task).switch = Switch::Done };
+}
+
+/// Switches from the keep back to its host thread, until the keep resumes
+///
+/// Returns whether the keep was interrupted while it was parked.
+fn suspend(task: *mut Task, switch: Switch) -> bool {
+    let spans = exit_spans();
+
+    let interrupted = unsafe {
+        (*task).switch = switch;
+        (*task).interrupted = false;
+        libc::swapcontext(&mut (*task).context, &(*task).back);
+        (*task).interrupted
+    };
+
+    enter_spans(&spans);
+    interrupted
+}
+
+/// Exits the spans which are entered on the calling thread
+///
+/// They are returned innermost first. Subscribers which don't track the
+/// current span have none to exit.
+fn exit_spans() -> Vec<Span> {
+    let mut spans: Vec<Span> = Vec::new();
+
+    loop {
+        let span = Span::current();
+        let id = match span.id() {
+            Some(id) if !spans.iter().any(|s| s.id() == Some(id.clone())) => id,
+            _ => return spans,
+        };
+
+        dispatcher::get_default(|d| d.exit(&id));
+        spans.push(span);
+    }
+}
+
+/// Enters the spans which `exit_spans()` returned again
+fn enter_spans(spans: &[Span]) {
+    for id in spans.iter().rev().filter_map(Span::id) {
+        dispatcher::get_default(|d| d.enter(&id));
+    }
+}
+
+/// What a host thread of a scheduler shares
+struct Worker {
+    /// The keeps which were spawned on the thread, until it takes them
+    inbox: Mutex<Vec<Task>>,
+
+    /// An `eventfd` which wakes the thread when keeps are spawned on it
+    wake: File,
+
+    /// The number of keeps on the thread
+    load: AtomicUsize,
+
+    /// Interrupts the thread, once it runs
+    thread: Mutex<Option<Interrupt>>,
+
+    /// The number of the slice which the thread runs a keep in, or 0
+    slice: AtomicU64,
+
+    /// Whether other keeps wait for the thread in the current slice
+    contended: AtomicBool,
+
+    /// Whether the keep which runs should yield
+    preempt: AtomicBool,
+
+    /// Whether the thread failed, so that it takes no more keeps
+    failed: AtomicBool,
+}
+
+/// What the scheduler and its host threads share
+struct Shared {
+    workers: Vec<Worker>,
+
+    /// Whether the scheduler was dropped
+    ended: AtomicBool,
+}
+
+impl Shared {
+    /// Whether the scheduler was dropped and all of its keeps ended
+    fn done(&self) -> bool {
+        self.ended.load(Ordering::SeqCst)
+            && self
+                .workers
+                .iter()
+                .all(|w| w.load.load(Ordering::SeqCst) == 0)
+    }
+}
+
+/// Runs keeps on a fixed number of host threads
+///
+/// Dropping the scheduler lets its host threads end once their keeps have.
+pub struct Scheduler(Arc<Shared>);
+
+impl Scheduler {
+    /// Starts `threads` host threads
+    pub fn new(threads: usize) -> Result<Self> {
+        if threads == 0 {
+            bail!("a scheduler needs at least one host thread");
+        }
+
+        let mut workers = Vec::with_capacity(threads);
+        for _ in 0..threads {
+            let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+
+            workers.push(Worker {
+                inbox: Mutex::new(Vec::new()),
+                wake: unsafe { File::from_raw_fd(fd) },
+                load: AtomicUsize::new(0),
+                thread: Mutex::new(None),
+                slice: AtomicU64::new(0),
+                contended: AtomicBool::new(false),
+                preempt: AtomicBool::new(false),
+                failed: AtomicBool::new(false),
+            });
+        }
+
+        let shared = Arc::new(Shared {
+            workers,
+            ended: AtomicBool::new(false),
+        });
+
+        for index in 0..threads {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name(format!("scheduler-{}", index))
+                .spawn(move || work(&shared, index))?;
+        }
+
+        let ticker = shared.clone();
+        std::thread::Builder::new()
+            .name("scheduler".into())
+            .spawn(move || tick(&ticker))?;
+
+        Ok(Self(shared))
+    }
+
+    /// Runs a keep on the host thread with the fewest keeps
+    ///
+    /// `keep` is what a host thread of its own would run, i.e. usually
+    /// `KeepBuilder::spawn()`.
+    pub fn spawn<F>(&self, keep: F) -> Result<Scheduled>
+    where
+        F: FnOnce() -> Result<Exit> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let result = tx.clone();
+        let task = Task::new(
+            Box::new(move || {
+                // Unwinding must not leave the stack of the keep.
+                let exit = std::panic::catch_unwind(AssertUnwindSafe(keep))
+                    .unwrap_or_else(|_| Err(anyhow!("the keep panicked")));
+                let _ = tx.send(exit);
+            }),
+            result,
+        )?;
+
+        let worker = self
+            .0
+            .workers
+            .iter()
+            .filter(|w| !w.failed.load(Ordering::SeqCst))
+            .min_by_key(|w| w.load.load(Ordering::SeqCst))
+            .ok_or_else(|| anyhow!("all host threads of the scheduler failed"))?;
+
+        // A thread which fails marks itself while it holds its inbox.
+        let mut inbox = worker.inbox.lock().unwrap();
+        if worker.failed.load(Ordering::SeqCst) {
+            bail!("the host thread of the keep failed");
+        }
+
+        worker.load.fetch_add(1, Ordering::SeqCst);
+        inbox.push(task);
+        drop(inbox);
+        (&worker.wake).write_all(&1u64.to_ne_bytes())?;
+
+        Ok(Scheduled(rx))
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.0.ended.store(true, Ordering::SeqCst);
+        for worker in &self.0.workers {
+            let _ = (&worker.wake).write_all(&1u64.to_ne_bytes());
+        }
+    }
+}
+
+/// A keep which runs on a scheduler
+pub struct Scheduled(mpsc::Receiver<Result<Exit>>);
+
+impl Scheduled {
+    /// Waits for the keep to end
+    pub fn join(self) -> Result<Exit> {
+        self.0
+            .recv()
+            .map_err(|_| anyhow!("the host thread of the keep ended"))?
+    }
+}
+
+/// Runs the keeps of a host thread until the scheduler was dropped and they
+/// all ended
+///
+/// If the thread fails, the keeps which it holds fail with its error, and
+/// the thread takes no more keeps.
+fn work(shared: &Shared, index: usize) {
+    let worker = &shared.workers[index];
+    let mut ready = VecDeque::new();
+    let mut parked = HashMap::new();
+
+    let e = match serve(shared, worker, &mut ready, &mut parked) {
+        Ok(()) => return,
+        Err(e) => e,
+    };
+    error!(index, "the host thread failed: {:#}", e);
+
+    // The keeps which started can't be resumed, so they are dropped with
+    // whatever their stacks hold.
+    let mut inbox = worker.inbox.lock().unwrap();
+    worker.failed.store(true, Ordering::SeqCst);
+    let fail = |task: &Task| {
+        let error = anyhow!("the host thread of the keep failed: {:#}", e);
+        let _ = task.result.send(Err(error));
+    };
+
+    inbox.drain(..).for_each(|task| fail(&task));
+    ready.drain(..).for_each(|task| fail(&task));
+    parked.drain().for_each(|(_, (task, _))| fail(&task));
+    worker.load.store(0, Ordering::SeqCst);
+}
+
+/// The loop of `work()`, which fails with the host thread
+fn serve(
+    shared: &Shared,
+    worker: &Worker,
+    ready: &mut VecDeque<Box<Task>>,
+    parked: &mut HashMap<u64, (Box<Task>, File)>,
+) -> Result<()> {
+    *worker.thread.lock().unwrap() = Some(Interrupt::current()?);
+
+    let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+    if epoll < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let epoll = unsafe { File::from_raw_fd(epoll) };
+    let wake = worker.wake.as_raw_fd();
+    control(
+        &epoll,
+        libc::EPOLL_CTL_ADD,
+        wake,
+        libc::EPOLLIN as u32,
+        WAKE,
+    )?;
+
+    let mut token = 0;
+    let mut slice = 0;
+
+    loop {
+        for task in worker.inbox.lock().unwrap().drain(..) {
+            // The task is boxed first, since its contexts point into it.
+            let mut task = Box::new(task);
+            match task.prepare(worker) {
+                Ok(()) => ready.push_back(task),
+                Err(e) => {
+                    let _ = task.result.send(Err(e));
+                    worker.load.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+        }
+
+        if ready.is_empty() && parked.is_empty() && shared.ended.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        // Polls the parked keeps, and waits for them if no keep is ready.
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; 64];
+        let timeout = if ready.is_empty() { -1 } else { 0 };
+        let len = events.len() as _;
+        let n = unsafe { libc::epoll_wait(epoll.as_raw_fd(), events.as_mut_ptr(), len, timeout) };
+        if n < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EINTR) {
+                bail!("unable to wait for the keeps: {}", err);
+            }
+        }
+
+        for event in &events[..n.max(0) as usize] {
+            let token = event.u64;
+            match token {
+                WAKE => {
+                    let _ = (&worker.wake).read(&mut [0; 8]);
+                }
+
+                _ => {
+                    if let Some((task, fd)) = parked.remove(&token) {
+                        unpark(&epoll, fd);
+                        ready.push_back(task);
+                    }
+                }
+            }
+        }
+
+        // Interrupted keeps stop waiting, e.g. for their watchdog.
+        let interrupted: Vec<u64> = parked
+            .iter()
+            .filter(|(_, (task, _))| task.interrupt.as_ref().map_or(false, Interrupt::pending))
+            .map(|(token, _)| *token)
+            .collect();
+        for token in interrupted {
+            let (mut task, fd) = parked.remove(&token).unwrap();
+            unpark(&epoll, fd);
+            task.interrupted = true;
+            ready.push_back(task);
+        }
+
+        let mut task = match ready.pop_front() {
+            Some(task) => task,
+            None => continue,
+        };
+
+        slice += 1;
+        let contended = !ready.is_empty() || !parked.is_empty();
+        worker.contended.store(contended, Ordering::SeqCst);
+        worker.slice.store(slice, Ordering::SeqCst);
+        task.resume();
+        worker.slice.store(0, Ordering::SeqCst);
+        worker.preempt.store(false, Ordering::SeqCst);
+
+        match task.switch {
+            Switch::Yield => ready.push_back(task),
+
+            // A duplicate of the descriptor is registered, since several keeps
+            // may wait for the same one (e.g. the stdin of the loader).
+            Switch::Park { fd, events } => {
+                let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+                if dup < 0 {
+                    ready.push_back(task);
+                    continue;
+                }
+                let dup = unsafe { File::from_raw_fd(dup) };
+
+                token += 1;
+                let events = events as u32 | libc::EPOLLONESHOT as u32;
+                match control(&epoll, libc::EPOLL_CTL_ADD, dup.as_raw_fd(), events, token) {
+                    Ok(()) => {
+                        parked.insert(token, (task, dup));
+                    }
+
+                    // Descriptors which epoll doesn't support, such as those
+                    // of regular files, never block.
+                    Err(_) => ready.push_back(task),
+                }
+            }
+
+            Switch::Done => {
+                worker.load.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// Adds, modifies or deletes the registration of `fd` with epoll
+fn control(epoll: &File, op: libc::c_int, fd: RawFd, events: u32, token: u64) -> Result<()> {
+    let mut event = libc::epoll_event { events, u64: token };
+    if unsafe { libc::epoll_ctl(epoll.as_raw_fd(), op, fd, &mut event) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+/// Stops waiting for the descriptor of a parked keep
+///
+/// The duplicate is deleted first, since epoll only forgets it once all
+/// descriptors of its file are closed.
+fn unpark(epoll: &File, fd: File) {
+    let _ = control(epoll, libc::EPOLL_CTL_DEL, fd.as_raw_fd(), 0, 0);
+}
+
+/// Preempts the keeps which ran for a whole slice while others waited
+fn tick(shared: &Shared) {
+    let mut last = vec![0; shared.workers.len()];
+
+    while !shared.done() {
+        std::thread::sleep(SLICE);
+
+        for (worker, last) in shared.workers.iter().zip(last.iter_mut()) {
+            let slice = worker.slice.load(Ordering::SeqCst);
+            let waiting =
+                worker.contended.load(Ordering::SeqCst) || !worker.inbox.lock().unwrap().is_empty();
+
+            if slice != 0 && slice == *last && waiting {
+                worker.preempt.store(true, Ordering::SeqCst);
+                if let Some(thread) = &*worker.thread.lock().unwrap() {
+                    thread.signal();
+                }
+            }
+
+            *last = slice;
+        }
+    }
+}
+
+/// Whether the calling thread runs a keep of a scheduler
+pub(crate) fn scheduled() -> bool {
+    !CURRENT.with(Cell::get).is_null()
+}
+
+/// Lets the interrupt of the keep wake it while it is parked
+pub(crate) fn interrupt(interrupt: &Interrupt) {
+    let task = CURRENT.with(Cell::get);
+    if !task.is_null() {
+        unsafe { (*task).interrupt = Some(interrupt.clone()) };
+    }
+}
+
+/// Yields the host thread to the other keeps on it, if the keep was preempted
+pub(crate) fn checkpoint() {
+    let task = CURRENT.with(Cell::get);
+    if task.is_null() {
+        return;
+    }
+
+    if unsafe { (*(*task).worker).preempt.swap(false, Ordering::SeqCst) } {
+        suspend(task, Switch::Yield);
+    }
+}
+
+/// Waits until the host can execute a syscall of the keep without blocking
+///
+/// Keeps which don't run on a scheduler don't wait. Returns `false` if the
+/// keep was interrupted while it waited, so that the syscall fails with
+/// `EINTR`.
+pub(crate) fn ready(req: &Request) -> bool {
+    let task = CURRENT.with(Cell::get);
+    if task.is_null() {
+        return true;
+    }
+
+    let num: i64 = req.num.into();
+    let events = match num {
+        libc::SYS_read
+        | libc::SYS_readv
+        | libc::SYS_recvfrom
+        | libc::SYS_recvmsg
+        | libc::SYS_accept
+        | libc::SYS_accept4 => libc::POLLIN,
+
+        libc::SYS_write | libc::SYS_writev | libc::SYS_sendto | libc::SYS_sendmsg => libc::POLLOUT,
+
+        _ => return true,
+    };
+
+    // Descriptors which are ready, broken or non-blocking are left to the
+    // syscall.
+    let fd = usize::from(req.arg[0]) as RawFd;
+    let mut poll = libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+    if unsafe { libc::poll(&mut poll, 1, 0) } != 0 {
+        return true;
+    }
+
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || flags & libc::O_NONBLOCK != 0 {
+        return true;
+    }
+
+    // The interrupt only signals keeps which are inside.
+    let interrupt = unsafe { (*task).interrupt.clone() };
+    let _inside = interrupt.as_ref().map(Interrupt::enter);
+    !suspend(task, Switch::Park { fd, events })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sallyport::Block;
+
+    fn pipe() -> (File, File) {
+        let mut ends = [0; 2];
+        assert_eq!(
+            unsafe { libc::pipe2(ends.as_mut_ptr(), libc::O_CLOEXEC) },
+            0
+        );
+        unsafe { (File::from_raw_fd(ends[0]), File::from_raw_fd(ends[1])) }
+    }
+
+    #[test]
+    fn parks() {
+        let scheduler = Scheduler::new(1).unwrap();
+        let (mut read, mut write) = pipe();
+
+        // The reader would block the only host thread if it wasn't parked.
+        let reader = scheduler
+            .spawn(move || {
+                let mut block = Block::default();
+                let req = unsafe { &mut block.msg.req };
+                req.num = (libc::SYS_read as usize).into();
+                req.arg[0] = (read.as_raw_fd() as usize).into();
+                assert!(ready(req));
+
+                let mut byte = [0];
+                read.read_exact(&mut byte)?;
+                Ok(Exit::Code(byte[0].into()))
+            })
+            .unwrap();
+
+        let writer = scheduler
+            .spawn(move || {
+                write.write_all(&[42])?;
+                Ok(Exit::Code(0))
+            })
+            .unwrap();
+
+        assert_eq!(writer.join().unwrap(), Exit::Code(0));
+        assert_eq!(reader.join().unwrap(), Exit::Code(42));
+    }
+
+    #[test]
+    fn preempts() {
+        let scheduler = Scheduler::new(1).unwrap();
+        let flag = Arc::new(AtomicBool::new(false));
+
+        // The spinner only yields when it is preempted.
+        let spun = flag.clone();
+        let spinner = scheduler
+            .spawn(move || {
+                while !spun.load(Ordering::SeqCst) {
+                    checkpoint();
+                }
+
+                Ok(Exit::Code(0))
+            })
+            .unwrap();
+
+        let setter = scheduler
+            .spawn(move || {
+                flag.store(true, Ordering::SeqCst);
+                Ok(Exit::Code(1))
+            })
+            .unwrap();
+
+        assert_eq!(setter.join().unwrap(), Exit::Code(1));
+        assert_eq!(spinner.join().unwrap(), Exit::Code(0));
+    }
+
+    #[test]
+    fn spans() {
+        tracing::subscriber::with_default(tracing_subscriber::registry(), || {
+            let outer = tracing::info_span!("outer").entered();
+            let inner = tracing::info_span!("inner").entered();
+
+            let spans = exit_spans();
+            assert_eq!(spans.len(), 2);
+            assert!(Span::current().is_none());
+            assert!(exit_spans().is_empty());
+
+            enter_spans(&spans);
+            assert_eq!(Span::current().id(), inner.id());
+            drop(inner);
+            assert_eq!(Span::current().id(), outer.id());
+        });
+    }
+}
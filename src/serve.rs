@@ -0,0 +1,285 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A daemon which manages keeps for orchestrators
+//!
+//! `enarx-keepldr serve` hosts keeps for other programs, such as the device
+//! plugins of Kubernetes, so that they don't run `enarx-keepldr exec` for
+//! each keep. It listens on a Unix socket for the gRPC requests which
+//! `src/protobuf/keepldr.proto` defines:
+//!
+//!   * `Create` streams a payload, and launches a keep with it.
+//!   * `Get` and `List` return the status of keeps, and their measurements
+//!     for attestation.
+//!   * `Terminate` stops a keep (see `Registry::stop()`).
+//!
+//! The keeps run on threads of the daemon (see the `registry` module), with
+//! the settings which the daemon was started with. They share its standard
+//! streams, and get no environment variables of the host. A keep stays
+//! listed once it ended, until the daemon ends.
+
+use crate::backend::{self, Backend, Config};
+use crate::binary::Component;
+use crate::environ::Environ;
+use crate::exit::Exit;
+use crate::registry::{KeepId, Record, Registry, Status};
+use crate::KeepBuilder;
+
+use anyhow::{anyhow, bail, Result};
+use tokio::net::UnixListener;
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::transport::Server;
+use tonic::{Request, Response, Streaming};
+
+use std::path::Path;
+
+mod proto {
+    #![allow(missing_docs)]
+
+    tonic::include_proto!("keepldr");
+}
+
+use proto::keeps_server::{Keeps, KeepsServer};
+
+/// The largest payload which a keep is launched with
+const MAX_PAYLOAD: usize = 256 << 20;
+
+/// Serves the API on a Unix socket until the process ends
+///
+/// A keep which is created without a backend gets the first supported one
+/// (see `backend::select()`).
+pub fn serve(path: &Path, backends: &'static [Box<dyn Backend>], config: Config) -> Result<()> {
+    let service = Service {
+        backends,
+        config,
+        registry: Registry::default(),
+    };
+
+    tokio::runtime::Runtime::new()?.block_on(async {
+        let listener = UnixListener::bind(path)
+            .map_err(|e| anyhow!("unable to bind {}: {}", path.display(), e))?;
+
+        Server::builder()
+            .add_service(KeepsServer::new(service))
+            .serve_with_incoming(UnixListenerStream::new(listener))
+            .await?;
+
+        Ok(())
+    })
+}
+
+struct Service {
+    backends: &'static [Box<dyn Backend>],
+    config: Config,
+    registry: Registry,
+}
+
+impl Service {
+    /// Launches a keep on a thread of its own
+    fn launch(&self, first: proto::Payload, payload: Vec<u8>) -> Result<KeepId> {
+        let backend = match first.backend.as_str() {
+            "" => "auto",
+            name => name,
+        };
+        let backend = backend::select(self.backends, backend)?;
+        let environ = environ(first.args, first.env)?;
+
+        // The payload is parsed once, by the thread which owns it, which
+        // reports whether it is valid before it builds the keep.
+        let config = self.config.clone();
+        let (registry, id) = (self.registry.clone(), self.registry.add());
+        let (parsed, checked) = std::sync::mpsc::sync_channel(1);
+        std::thread::spawn(move || {
+            let code = match Component::from_bytes(&payload) {
+                Ok(code) => code,
+                Err(e) => {
+                    let _ = parsed.send(Err(e));
+                    return;
+                }
+            };
+            let _ = parsed.send(Ok(()));
+
+            // `spawn()` records how the keep ended, or why it failed, in the
+            // registry, which is where the result is read from.
+            let _ = KeepBuilder::new()
+                .backend(backend)
+                .code(code)
+                .config(config)
+                .environ(environ)
+                .registry(&registry, id)
+                .spawn();
+        });
+
+        match checked.recv() {
+            Ok(Ok(())) => Ok(id),
+            Ok(Err(e)) => {
+                self.registry.remove(id);
+                Err(e)
+            }
+            Err(_) => {
+                self.registry.remove(id);
+                bail!("the keep thread ended before it parsed the payload")
+            }
+        }
+    }
+
+    /// Returns a keep of the registry
+    fn keep(&self, id: KeepId) -> Result<proto::Keep, tonic::Status> {
+        match self.registry.get(id) {
+            Some(record) => Ok(keep(id, record)),
+            None => Err(tonic::Status::not_found(format!("there is no {}", id))),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Keeps for Service {
+    async fn create(
+        &self,
+        request: Request<Streaming<proto::Payload>>,
+    ) -> Result<Response<proto::Keep>, tonic::Status> {
+        let mut stream = request.into_inner();
+        let mut first = stream
+            .message()
+            .await?
+            .ok_or_else(|| tonic::Status::invalid_argument("no payload given"))?;
+
+        let mut payload = std::mem::take(&mut first.chunk);
+        while let Some(part) = stream.message().await? {
+            payload.extend_from_slice(&part.chunk);
+            if payload.len() > MAX_PAYLOAD {
+                let error = format!("the payload is larger than {} bytes", MAX_PAYLOAD);
+                return Err(tonic::Status::invalid_argument(error));
+            }
+        }
+
+        let id = self
+            .launch(first, payload)
+            .map_err(|e| tonic::Status::invalid_argument(format!("{:#}", e)))?;
+
+        Ok(Response::new(self.keep(id)?))
+    }
+
+    async fn get(
+        &self,
+        request: Request<proto::KeepId>,
+    ) -> Result<Response<proto::Keep>, tonic::Status> {
+        let id = KeepId(request.into_inner().id);
+        Ok(Response::new(self.keep(id)?))
+    }
+
+    async fn list(
+        &self,
+        _: Request<proto::ListRequest>,
+    ) -> Result<Response<proto::KeepList>, tonic::Status> {
+        let keeps = self.registry.list().into_iter();
+        let keeps = keeps.map(|(id, record)| keep(id, record)).collect();
+        Ok(Response::new(proto::KeepList { keeps }))
+    }
+
+    async fn terminate(
+        &self,
+        request: Request<proto::KeepId>,
+    ) -> Result<Response<proto::Keep>, tonic::Status> {
+        let id = KeepId(request.into_inner().id);
+        let keep = self.keep(id)?;
+
+        // Stopping waits for the thread of the keep to leave it.
+        let registry = self.registry.clone();
+        let stopped = tokio::task::spawn_blocking(move || registry.stop(id))
+            .await
+            .map_err(|e| tonic::Status::internal(e.to_string()))?;
+
+        match stopped {
+            true => Ok(Response::new(keep)),
+            false => Err(tonic::Status::failed_precondition(format!(
+                "{} ended already",
+                id
+            ))),
+        }
+    }
+}
+
+/// The environment of a keep, whose variables all have values
+///
+/// A variable without a value would take that of the daemon.
+fn environ(args: Vec<String>, vars: Vec<String>) -> Result<Environ> {
+    if let Some(var) = vars.iter().find(|v| !v.contains('=')) {
+        bail!("the environment variable {} has no value", var);
+    }
+
+    Environ::new(args, vars)
+}
+
+/// Converts the record of a keep for the API
+fn keep(id: KeepId, record: Record) -> proto::Keep {
+    let mut keep = proto::Keep {
+        id: id.0,
+        backend: record.backend.unwrap_or_default().into(),
+        measurement: record.measurement.unwrap_or_default(),
+        ..Default::default()
+    };
+
+    let status = match record.status {
+        Status::Pending => proto::Status::Pending,
+        Status::Building => proto::Status::Building,
+        Status::Running => proto::Status::Running,
+        Status::Exited(Exit::Code(code)) => {
+            keep.code = code;
+            proto::Status::Exited
+        }
+        Status::Exited(Exit::Signal(signal)) => {
+            keep.signal = signal;
+            proto::Status::Killed
+        }
+        Status::Exited(Exit::Attacked) => proto::Status::Attacked,
+        Status::Exited(Exit::TimedOut) => proto::Status::TimedOut,
+        Status::Failed(error) => {
+            keep.error = error;
+            proto::Status::Failed
+        }
+    };
+
+    keep.set_status(status);
+    keep
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variables() {
+        let args = vec!["a".to_string()];
+        assert!(environ(args.clone(), vec!["A=1".into()]).is_ok());
+        assert!(environ(args.clone(), vec!["A=".into()]).is_ok());
+        assert!(environ(args, vec!["PATH".into()]).is_err());
+    }
+
+    #[test]
+    fn records() {
+        let registry = Registry::default();
+        let id = registry.add();
+
+        let pending = keep(id, registry.get(id).unwrap());
+        assert_eq!(pending.id, id.0);
+        assert_eq!(pending.status(), proto::Status::Pending);
+        assert_eq!(pending.backend, "");
+
+        registry.update(id, |r| {
+            r.status = Status::Exited(Exit::Signal(libc::SIGKILL));
+            r.backend = Some("kvm");
+            r.measurement = Some("00ff".into());
+        });
+
+        let killed = keep(id, registry.get(id).unwrap());
+        assert_eq!(killed.status(), proto::Status::Killed);
+        assert_eq!(killed.signal, libc::SIGKILL);
+        assert_eq!(killed.backend, "kvm");
+        assert_eq!(killed.measurement, "00ff");
+
+        registry.update(id, |r| r.status = Status::Failed("no".into()));
+        let failed = keep(id, registry.get(id).unwrap());
+        assert_eq!(failed.status(), proto::Status::Failed);
+        assert_eq!(failed.error, "no");
+    }
+}
@@ -0,0 +1,154 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Orderly shutdown on `SIGTERM` and `SIGINT`
+//!
+//! When the loader receives one of these signals, it rings a doorbell: a page
+//! of the host which the shim reads without leaving the keep, and which it
+//! asks for with `SYS_ENARX_DOORBELL`. It then interrupts the threads inside
+//! the keeps (see `backend::interrupt`), so that they exit to the host. The
+//! SGX shim raises the signal in the payload then, or when a syscall returns
+//! to it, so a payload which handles the signal can wind down and exit. Keeps
+//! whose shim didn't ask for the page can't be told, so they are stopped once
+//! they exit to the host.
+//!
+//! If the keep is still running when the grace period ends, or when another
+//! signal arrives, the loader is killed by the signal, and the kernel
+//! destroys the enclave or the VM along with it.
+
+use crate::backend::interrupt::Interrupt;
+
+use anyhow::Result;
+use tracing::debug;
+
+use std::alloc::{alloc_zeroed, Layout};
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::FromRawFd;
+use std::sync::atomic::{AtomicI32, AtomicPtr, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The memory shared with the shim
+#[repr(C, align(4096))]
+struct Shared {
+    signal: AtomicI32,
+}
+
+/// The doorbell page, once the handlers are installed
+static DOORBELL: AtomicPtr<Shared> = AtomicPtr::new(std::ptr::null_mut());
+
+/// The pipe end which wakes up the watchdog
+static WAKE: AtomicI32 = AtomicI32::new(-1);
+
+/// The threads which are interrupted when the doorbell rings
+static THREADS: AtomicPtr<Mutex<Vec<Interrupt>>> = AtomicPtr::new(std::ptr::null_mut());
+
+/// Installs the handlers of `SIGTERM` and `SIGINT`
+///
+/// The payload gets `grace` to exit after the first signal. This is only
+/// done once per process.
+pub fn install(grace: Duration) -> Result<()> {
+    if !DOORBELL.load(Ordering::Relaxed).is_null() {
+        return Ok(());
+    }
+
+    let shared = unsafe { alloc_zeroed(Layout::new::<Shared>()) }.cast::<Shared>();
+    if shared.is_null() {
+        anyhow::bail!("unable to allocate the doorbell page");
+    }
+
+    let mut ends = [0; 2];
+    if unsafe { libc::pipe2(ends.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let threads = Box::leak(Box::new(Mutex::new(Vec::<Interrupt>::new())));
+    THREADS.store(threads, Ordering::Release);
+
+    let mut wake = unsafe { File::from_raw_fd(ends[0]) };
+    WAKE.store(ends[1], Ordering::Relaxed);
+    DOORBELL.store(shared, Ordering::Release);
+
+    std::thread::spawn(move || {
+        let mut signal = [0];
+        if wake.read_exact(&mut signal).is_ok() {
+            let start = std::time::Instant::now();
+            for thread in threads.lock().unwrap().iter() {
+                thread.interrupt();
+            }
+
+            std::thread::sleep(grace.saturating_sub(start.elapsed()));
+            kill(signal[0].into());
+        }
+    });
+
+    // Without `SA_RESTART`, the syscalls which the host executes for the keep
+    // are interrupted, so that it notices the signal.
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+
+    for signal in &[libc::SIGTERM, libc::SIGINT] {
+        if unsafe { libc::sigaction(*signal, &action, std::ptr::null_mut()) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the signal which asked for the shutdown, if one did
+pub fn pending() -> Option<i32> {
+    let shared = DOORBELL.load(Ordering::Acquire);
+    if shared.is_null() {
+        return None;
+    }
+
+    match unsafe { &*shared }.signal.load(Ordering::Relaxed) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+/// Interrupts the thread when the doorbell rings, if the handlers are
+/// installed
+pub fn interrupt(thread: &Interrupt) {
+    let threads = THREADS.load(Ordering::Acquire);
+    if let Some(threads) = unsafe { threads.as_ref() } {
+        let mut threads = threads.lock().unwrap();
+        threads.retain(|t| !t.orphaned());
+        threads.push(thread.clone());
+    }
+}
+
+/// Handles `SYS_ENARX_DOORBELL`
+pub fn reply() -> sallyport::Result {
+    let shared = DOORBELL.load(Ordering::Acquire);
+    if shared.is_null() {
+        return Err(libc::ENOSYS);
+    }
+
+    debug!("the shim listens for shutdown requests");
+    Ok([
+        (shared as usize).into(),
+        Layout::new::<Shared>().size().into(),
+    ])
+}
+
+/// Rings the doorbell, or kills the loader when it was rung already
+extern "C" fn handle(signal: libc::c_int) {
+    let shared = unsafe { &*DOORBELL.load(Ordering::Relaxed) };
+    if shared.signal.swap(signal, Ordering::Relaxed) != 0 {
+        kill(signal);
+    }
+
+    let byte = signal as u8;
+    unsafe { libc::write(WAKE.load(Ordering::Relaxed), (&byte as *const u8).cast(), 1) };
+}
+
+/// Kills the loader with the signal
+fn kill(signal: libc::c_int) {
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
@@ -0,0 +1,149 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The standard streams of the payload
+//!
+//! The keep proxies the syscalls of the payload to the host, so by default
+//! the payload shares the stdin, stdout and stderr of the loader, where its
+//! output is interleaved with the log. `Streams` gives each standard stream
+//! its own host file instead: the proxied syscalls on descriptors 0, 1 and 2
+//! are redirected to it before they are executed.
+//!
+//! A stream can also be captured through a pipe, whose other end the caller
+//! reads (or, for stdin, writes). The keep blocks while such a pipe is full.
+
+use anyhow::{anyhow, Result};
+use primordial::Register;
+use sallyport::Block;
+
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+
+/// The proxied syscalls whose first argument is a file descriptor
+const FD_SYSCALLS: &[i64] = &[
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_lseek,
+    libc::SYS_fstat,
+    libc::SYS_ioctl,
+    libc::SYS_fcntl,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_ftruncate,
+    libc::SYS_dup,
+    libc::SYS_dup2,
+    libc::SYS_dup3,
+];
+
+/// The host files of the standard streams, indexed by descriptor
+///
+/// Streams without a file are those of the loader.
+#[derive(Debug, Default)]
+pub struct Streams([Option<File>; 3]);
+
+impl Streams {
+    /// Redirects the stream `fd` (0, 1 or 2) to a file
+    ///
+    /// The file is created, or truncated if it exists.
+    pub fn create(&mut self, fd: RawFd, path: &Path) -> Result<()> {
+        let file = File::create(path)
+            .map_err(|e| anyhow!("unable to create {}: {}", path.display(), e))?;
+
+        self.0[fd as usize] = Some(file);
+        Ok(())
+    }
+
+    /// Captures the stream `fd` (0, 1 or 2) in a pipe
+    ///
+    /// Returns the end of the pipe which the caller reads, or writes for
+    /// stdin.
+    pub fn pipe(&mut self, fd: RawFd) -> Result<File> {
+        let mut ends = [0; 2];
+        if unsafe { libc::pipe2(ends.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let (read, write) = unsafe { (File::from_raw_fd(ends[0]), File::from_raw_fd(ends[1])) };
+        let (keep, caller) = match fd {
+            libc::STDIN_FILENO => (read, write),
+            _ => (write, read),
+        };
+
+        self.0[fd as usize] = Some(keep);
+        Ok(caller)
+    }
+
+    /// Redirects a syscall on a standard stream to its file
+    ///
+    /// The file of a stream stays open when the payload closes the stream,
+    /// so that the host doesn't hand its descriptor out again; the reply to
+    /// such a `close()` is returned. `None` is returned for all other
+    /// syscalls, which are left to be executed.
+    pub fn syscall(&self, block: &mut Block) -> Option<sallyport::Result> {
+        let req = unsafe { &mut block.msg.req };
+        let num: i64 = req.num.into();
+
+        let file = match self.0.get(usize::from(req.arg[0])) {
+            Some(Some(file)) => file,
+            _ => return None,
+        };
+
+        match num {
+            libc::SYS_close => Some(Ok([Register::default(), Register::default()])),
+            num if FD_SYSCALLS.contains(&num) => {
+                req.arg[0] = (file.as_raw_fd() as usize).into();
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    fn request(block: &mut Block, num: i64, args: &[usize]) {
+        let req = unsafe { &mut block.msg.req };
+        req.num = (num as usize).into();
+        for (reg, arg) in req.arg.iter_mut().zip(args) {
+            *reg = (*arg).into();
+        }
+    }
+
+    #[test]
+    fn capture() {
+        let mut streams = Streams::default();
+        let mut stdout = streams.pipe(libc::STDOUT_FILENO).unwrap();
+
+        let text = b"hello";
+        let mut block = Block::default();
+        request(
+            &mut block,
+            libc::SYS_write,
+            &[1, text.as_ptr() as _, text.len()],
+        );
+        assert!(streams.syscall(&mut block).is_none());
+
+        let ret: sallyport::Result = unsafe { block.msg.req.syscall() }.into();
+        assert_eq!(usize::from(ret.unwrap()[0]), text.len());
+
+        let mut read = [0; 5];
+        stdout.read_exact(&mut read).unwrap();
+        assert_eq!(&read, text);
+
+        request(&mut block, libc::SYS_close, &[1]);
+        assert!(streams.syscall(&mut block).unwrap().is_ok());
+
+        // Streams without a file are left alone.
+        request(&mut block, libc::SYS_write, &[2, 0, 0]);
+        assert!(streams.syscall(&mut block).is_none());
+        assert_eq!(usize::from(unsafe { block.msg.req.arg[0] }), 2);
+    }
+}
@@ -0,0 +1,400 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! strace-style tracing of the syscalls requested by a keep
+//!
+//! With `--trace`, every request which reaches the host is printed to
+//! stderr with its result, once it has been executed:
+//!
+//! ```text
+//! openat(AT_FDCWD, "/etc/hosts", O_RDONLY|O_CLOEXEC) = 3
+//! read(3, 0x7f2a4c0011d0, 4096) = -1 EBADF (Bad file descriptor)
+//! exit_group(0) = ?
+//! ```
+//!
+//! Strings are only shown when they are within the syscall block. Requests
+//! which the backends handle themselves (e.g. `cpuid`) are not traced.
+
+use std::ffi::CStr;
+use std::fmt::Write;
+use std::mem::size_of;
+
+use enarx_syscall::{
+    SYS_ENARX_BACKTRACE, SYS_ENARX_DETERMINISTIC, SYS_ENARX_ENVIRON, SYS_ENARX_MEMORY,
+    SYS_ENARX_MOUNTS,
+};
+use sallyport::{Block, Request};
+
+/// The name and the argument kinds of a syscall
+///
+/// Each argument is one character:
+///
+///   * `i`: an `int`
+///   * `u`: an unsigned size
+///   * `x`: a pointer
+///   * `s`: a string
+///   * `d`: a directory file descriptor
+///   * `o`: `open()` flags
+///   * `m`: a file mode
+///   * `D`: a socket domain
+///   * `T`: a socket type with flags
+///   * `c`: a clock
+fn signature(num: i64) -> Option<(&'static str, &'static str)> {
+    Some(match num {
+        libc::SYS_read => ("read", "ixu"),
+        libc::SYS_write => ("write", "ixu"),
+        libc::SYS_open => ("open", "som"),
+        libc::SYS_close => ("close", "i"),
+        libc::SYS_stat => ("stat", "sx"),
+        libc::SYS_fstat => ("fstat", "ix"),
+        libc::SYS_lstat => ("lstat", "sx"),
+        libc::SYS_poll => ("poll", "xui"),
+        libc::SYS_lseek => ("lseek", "iui"),
+        libc::SYS_ioctl => ("ioctl", "iux"),
+        libc::SYS_pread64 => ("pread64", "ixuu"),
+        libc::SYS_pwrite64 => ("pwrite64", "ixuu"),
+        libc::SYS_readv => ("readv", "ixi"),
+        libc::SYS_writev => ("writev", "ixi"),
+        libc::SYS_access => ("access", "si"),
+        libc::SYS_select => ("select", "ixxxx"),
+        libc::SYS_sched_yield => ("sched_yield", ""),
+        libc::SYS_madvise => ("madvise", "xui"),
+        libc::SYS_dup => ("dup", "i"),
+        libc::SYS_dup2 => ("dup2", "ii"),
+        libc::SYS_nanosleep => ("nanosleep", "xx"),
+        libc::SYS_getpid => ("getpid", ""),
+        libc::SYS_socket => ("socket", "DTi"),
+        libc::SYS_connect => ("connect", "ixu"),
+        libc::SYS_accept => ("accept", "ixx"),
+        libc::SYS_sendto => ("sendto", "ixuixu"),
+        libc::SYS_recvfrom => ("recvfrom", "ixuixx"),
+        libc::SYS_sendmsg => ("sendmsg", "ixi"),
+        libc::SYS_recvmsg => ("recvmsg", "ixi"),
+        libc::SYS_shutdown => ("shutdown", "ii"),
+        libc::SYS_bind => ("bind", "ixu"),
+        libc::SYS_listen => ("listen", "ii"),
+        libc::SYS_getsockname => ("getsockname", "ixx"),
+        libc::SYS_getpeername => ("getpeername", "ixx"),
+        libc::SYS_socketpair => ("socketpair", "DTix"),
+        libc::SYS_setsockopt => ("setsockopt", "iiixu"),
+        libc::SYS_getsockopt => ("getsockopt", "iiixx"),
+        libc::SYS_exit => ("exit", "i"),
+        libc::SYS_kill => ("kill", "ii"),
+        libc::SYS_uname => ("uname", "x"),
+        libc::SYS_fcntl => ("fcntl", "iii"),
+        libc::SYS_fsync => ("fsync", "i"),
+        libc::SYS_fdatasync => ("fdatasync", "i"),
+        libc::SYS_ftruncate => ("ftruncate", "iu"),
+        libc::SYS_rename => ("rename", "ss"),
+        libc::SYS_mkdir => ("mkdir", "sm"),
+        libc::SYS_rmdir => ("rmdir", "s"),
+        libc::SYS_creat => ("creat", "sm"),
+        libc::SYS_unlink => ("unlink", "s"),
+        libc::SYS_readlink => ("readlink", "sxu"),
+        libc::SYS_gettid => ("gettid", ""),
+        libc::SYS_clock_gettime => ("clock_gettime", "cx"),
+        libc::SYS_clock_nanosleep => ("clock_nanosleep", "cixx"),
+        libc::SYS_exit_group => ("exit_group", "i"),
+        libc::SYS_epoll_wait => ("epoll_wait", "ixii"),
+        libc::SYS_epoll_ctl => ("epoll_ctl", "iiix"),
+        libc::SYS_getdents64 => ("getdents64", "ixu"),
+        libc::SYS_openat => ("openat", "dsom"),
+        libc::SYS_mkdirat => ("mkdirat", "dsm"),
+        libc::SYS_newfstatat => ("newfstatat", "dsxi"),
+        libc::SYS_unlinkat => ("unlinkat", "dsi"),
+        libc::SYS_faccessat => ("faccessat", "dsi"),
+        libc::SYS_epoll_pwait => ("epoll_pwait", "ixiixu"),
+        libc::SYS_timerfd_create => ("timerfd_create", "ci"),
+        libc::SYS_timerfd_settime => ("timerfd_settime", "iixx"),
+        libc::SYS_timerfd_gettime => ("timerfd_gettime", "ix"),
+        libc::SYS_accept4 => ("accept4", "ixxT"),
+        libc::SYS_eventfd2 => ("eventfd2", "ui"),
+        libc::SYS_epoll_create1 => ("epoll_create1", "i"),
+        libc::SYS_dup3 => ("dup3", "iii"),
+        libc::SYS_pipe2 => ("pipe2", "xi"),
+        libc::SYS_renameat2 => ("renameat2", "dsdsi"),
+        libc::SYS_getrandom => ("getrandom", "xuu"),
+        SYS_ENARX_MOUNTS => ("enarx_mounts", ""),
+        SYS_ENARX_ENVIRON => ("enarx_environ", ""),
+        SYS_ENARX_DETERMINISTIC => ("enarx_deterministic", ""),
+        SYS_ENARX_BACKTRACE => ("enarx_backtrace", ""),
+        SYS_ENARX_MEMORY => ("enarx_memory", ""),
+        _ => return None,
+    })
+}
+
+const OPEN_FLAGS: &[(usize, &str)] = &[
+    (libc::O_CREAT as _, "O_CREAT"),
+    (libc::O_EXCL as _, "O_EXCL"),
+    (libc::O_NOCTTY as _, "O_NOCTTY"),
+    (libc::O_TRUNC as _, "O_TRUNC"),
+    (libc::O_APPEND as _, "O_APPEND"),
+    (libc::O_NONBLOCK as _, "O_NONBLOCK"),
+    (libc::O_DSYNC as _, "O_DSYNC"),
+    (libc::O_DIRECT as _, "O_DIRECT"),
+    (libc::O_DIRECTORY as _, "O_DIRECTORY"),
+    (libc::O_NOFOLLOW as _, "O_NOFOLLOW"),
+    (libc::O_NOATIME as _, "O_NOATIME"),
+    (libc::O_CLOEXEC as _, "O_CLOEXEC"),
+    (libc::O_PATH as _, "O_PATH"),
+];
+
+const SOCK_FLAGS: &[(usize, &str)] = &[
+    (libc::SOCK_NONBLOCK as _, "SOCK_NONBLOCK"),
+    (libc::SOCK_CLOEXEC as _, "SOCK_CLOEXEC"),
+];
+
+/// Formats a set of flags, with unknown bits in hex
+fn flags(value: usize, names: &[(usize, &str)], mut out: String) -> String {
+    let mut rest = value;
+    for (flag, name) in names {
+        if rest & flag == *flag {
+            if !out.is_empty() {
+                out.push('|');
+            }
+
+            out.push_str(name);
+            rest &= !flag;
+        }
+    }
+
+    match (rest, out.is_empty()) {
+        (0, true) => "0".into(),
+        (0, false) => out,
+        (rest, true) => format!("{:#x}", rest),
+        (rest, false) => format!("{}|{:#x}", out, rest),
+    }
+}
+
+/// Formats a string in the block, if the pointer is within it
+fn string(block: &Block, ptr: usize) -> Option<String> {
+    const MAX: usize = 64;
+
+    let start = block as *const Block as usize;
+    let offset = ptr.checked_sub(start).filter(|o| *o < size_of::<Block>())?;
+    let bytes =
+        unsafe { std::slice::from_raw_parts(ptr as *const u8, size_of::<Block>() - offset) };
+    let string = CStr::from_bytes_with_nul(&bytes[..=bytes.iter().position(|b| *b == 0)?]).ok()?;
+
+    let string = string.to_string_lossy();
+    Some(match string.chars().count() > MAX {
+        true => format!("{:?}...", string.chars().take(MAX).collect::<String>()),
+        false => format!("{:?}", string),
+    })
+}
+
+/// Formats one argument
+fn argument(block: &Block, kind: char, arg: usize) -> String {
+    match kind {
+        'i' => (arg as i32).to_string(),
+        'x' | 's' if arg == 0 => "NULL".into(),
+        's' => string(block, arg).unwrap_or_else(|| format!("{:#x}", arg)),
+        'd' if arg as i32 == libc::AT_FDCWD => "AT_FDCWD".into(),
+        'd' => (arg as i32).to_string(),
+        'm' => format!("0{:o}", arg),
+        'o' => {
+            let access = match arg as i32 & libc::O_ACCMODE {
+                libc::O_RDONLY => "O_RDONLY",
+                libc::O_WRONLY => "O_WRONLY",
+                libc::O_RDWR => "O_RDWR",
+                _ => "O_ACCMODE",
+            };
+
+            flags(arg & !(libc::O_ACCMODE as usize), OPEN_FLAGS, access.into())
+        }
+        'D' => match arg as i32 {
+            libc::AF_UNIX => "AF_UNIX".into(),
+            libc::AF_INET => "AF_INET".into(),
+            libc::AF_INET6 => "AF_INET6".into(),
+            domain => domain.to_string(),
+        },
+        'T' => {
+            let flag_bits = (libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC) as usize;
+            let kind = match (arg & !flag_bits) as i32 {
+                0 => String::new(),
+                libc::SOCK_STREAM => "SOCK_STREAM".into(),
+                libc::SOCK_DGRAM => "SOCK_DGRAM".into(),
+                libc::SOCK_RAW => "SOCK_RAW".into(),
+                libc::SOCK_SEQPACKET => "SOCK_SEQPACKET".into(),
+                kind => kind.to_string(),
+            };
+
+            flags(arg & flag_bits, SOCK_FLAGS, kind)
+        }
+        'c' => match arg as i32 {
+            libc::CLOCK_REALTIME => "CLOCK_REALTIME".into(),
+            libc::CLOCK_MONOTONIC => "CLOCK_MONOTONIC".into(),
+            libc::CLOCK_PROCESS_CPUTIME_ID => "CLOCK_PROCESS_CPUTIME_ID".into(),
+            libc::CLOCK_THREAD_CPUTIME_ID => "CLOCK_THREAD_CPUTIME_ID".into(),
+            libc::CLOCK_MONOTONIC_RAW => "CLOCK_MONOTONIC_RAW".into(),
+            libc::CLOCK_BOOTTIME => "CLOCK_BOOTTIME".into(),
+            clock => clock.to_string(),
+        },
+        'x' => format!("{:#x}", arg),
+        _ => arg.to_string(),
+    }
+}
+
+/// Formats a request as a call, e.g. `close(3)`
+pub fn call(block: &Block, req: &Request) -> String {
+    let num: i64 = req.num.into();
+    let args = req.arg.iter().map(|a| usize::from(*a));
+
+    match signature(num) {
+        Some((name, kinds)) => {
+            let args = kinds.chars().zip(args).map(|(k, a)| argument(block, k, a));
+            format!("{}({})", name, args.collect::<Vec<_>>().join(", "))
+        }
+        None => {
+            let args = args.take(6).map(|a| format!("{:#x}", a));
+            format!("{}({})", name(num), args.collect::<Vec<_>>().join(", "))
+        }
+    }
+}
+
+/// Returns the name of a syscall, e.g. `close`
+pub fn name(num: i64) -> String {
+    match signature(num) {
+        Some((name, _)) => name.into(),
+        None => format!("syscall_{:#x}", num),
+    }
+}
+
+/// Returns the number of a syscall which `name()` knows by its name
+pub fn number(name: &str) -> Option<i64> {
+    (0..512).find(|num| matches!(signature(*num), Some((n, _)) if n == name))
+}
+
+/// Returns the name of an error number
+fn errno(err: libc::c_int) -> Option<&'static str> {
+    Some(match err {
+        libc::EPERM => "EPERM",
+        libc::ENOENT => "ENOENT",
+        libc::ESRCH => "ESRCH",
+        libc::EINTR => "EINTR",
+        libc::EIO => "EIO",
+        libc::ENXIO => "ENXIO",
+        libc::E2BIG => "E2BIG",
+        libc::EBADF => "EBADF",
+        libc::EAGAIN => "EAGAIN",
+        libc::ENOMEM => "ENOMEM",
+        libc::EACCES => "EACCES",
+        libc::EFAULT => "EFAULT",
+        libc::EBUSY => "EBUSY",
+        libc::EEXIST => "EEXIST",
+        libc::EXDEV => "EXDEV",
+        libc::ENOTDIR => "ENOTDIR",
+        libc::EISDIR => "EISDIR",
+        libc::EINVAL => "EINVAL",
+        libc::EMFILE => "EMFILE",
+        libc::ENOTTY => "ENOTTY",
+        libc::EFBIG => "EFBIG",
+        libc::ENOSPC => "ENOSPC",
+        libc::ESPIPE => "ESPIPE",
+        libc::EROFS => "EROFS",
+        libc::EPIPE => "EPIPE",
+        libc::ERANGE => "ERANGE",
+        libc::ENAMETOOLONG => "ENAMETOOLONG",
+        libc::ENOSYS => "ENOSYS",
+        libc::ENOTEMPTY => "ENOTEMPTY",
+        libc::ELOOP => "ELOOP",
+        libc::ENOTSOCK => "ENOTSOCK",
+        libc::EMSGSIZE => "EMSGSIZE",
+        libc::ENOPROTOOPT => "ENOPROTOOPT",
+        libc::EAFNOSUPPORT => "EAFNOSUPPORT",
+        libc::EADDRINUSE => "EADDRINUSE",
+        libc::EADDRNOTAVAIL => "EADDRNOTAVAIL",
+        libc::ENETUNREACH => "ENETUNREACH",
+        libc::ECONNRESET => "ECONNRESET",
+        libc::EISCONN => "EISCONN",
+        libc::ENOTCONN => "ENOTCONN",
+        libc::ETIMEDOUT => "ETIMEDOUT",
+        libc::ECONNREFUSED => "ECONNREFUSED",
+        libc::EINPROGRESS => "EINPROGRESS",
+        _ => return None,
+    })
+}
+
+/// Formats the result of a call, e.g. `= -1 EBADF (Bad file descriptor)`
+pub fn result(result: &sallyport::Result) -> String {
+    match result {
+        Ok([ret, _]) => format!("= {}", usize::from(*ret) as isize),
+        Err(err) => {
+            let mut line = String::from("= -1 ");
+            match errno(*err) {
+                Some(name) => line.push_str(name),
+                None => write!(line, "errno {}", err).unwrap(),
+            }
+
+            let desc = unsafe { CStr::from_ptr(libc::strerror(*err)) };
+            write!(line, " ({})", desc.to_string_lossy()).unwrap();
+            line
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arguments() {
+        let block = Block::default();
+        let o = libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC;
+        assert_eq!(
+            argument(&block, 'o', o as usize),
+            "O_WRONLY|O_CREAT|O_CLOEXEC"
+        );
+        assert_eq!(argument(&block, 'o', 0), "O_RDONLY");
+        assert_eq!(argument(&block, 'd', -100i32 as usize), "AT_FDCWD");
+        assert_eq!(argument(&block, 'm', 0o644), "0644");
+        assert_eq!(argument(&block, 'x', 0), "NULL");
+        assert_eq!(argument(&block, 'i', -1i32 as u32 as usize), "-1");
+
+        let t = libc::SOCK_STREAM | libc::SOCK_CLOEXEC;
+        assert_eq!(
+            argument(&block, 'T', t as usize),
+            "SOCK_STREAM|SOCK_CLOEXEC"
+        );
+        assert_eq!(
+            argument(&block, 'T', libc::SOCK_NONBLOCK as usize),
+            "SOCK_NONBLOCK"
+        );
+        assert_eq!(argument(&block, 'T', 0), "0");
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(name(libc::SYS_openat), "openat");
+        assert_eq!(name(0xfff), "syscall_0xfff");
+        assert_eq!(number("openat"), Some(libc::SYS_openat));
+        assert_eq!(number("exit_group"), Some(libc::SYS_exit_group));
+        assert_eq!(number("execve"), None);
+    }
+
+    #[test]
+    fn strings() {
+        let mut block = Block::default();
+        let (_, init) = block.cursor().copy_from_slice(b"/init\0").unwrap();
+        let init = init.as_ptr() as usize;
+
+        assert_eq!(string(&block, init).as_deref(), Some("\"/init\""));
+        assert_eq!(string(&block, init + 5).as_deref(), Some("\"\""));
+        assert_eq!(string(&block, 1), None);
+
+        // Unterminated strings are not shown.
+        let last = size_of::<Block>() - 1;
+        let last = unsafe { (&mut block as *mut Block as *mut u8).add(last) };
+        unsafe { *last = b'x' };
+        assert_eq!(string(&block, last as usize), None);
+    }
+
+    #[test]
+    fn results() {
+        assert_eq!(result(&Ok([3usize.into(), 0usize.into()])), "= 3");
+        assert_eq!(
+            result(&Err(libc::EBADF)),
+            "= -1 EBADF (Bad file descriptor)"
+        );
+        assert_eq!(result(&Err(4095)), "= -1 errno 4095 (Unknown error 4095)");
+    }
+}
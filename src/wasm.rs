@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! WebAssembly payloads
+//!
+//! A payload which is a WebAssembly module is run by `wasmldr`, the WASI
+//! runtime bundled with the loader (see `internal/wasmldr`), which is the
+//! code component of the keep instead. The module is opened on the host and
+//! its file descriptor is passed in `ENARX_WASM_FD`, from which `wasmldr`
+//! reads it.
+//!
+//! The module is not part of the measurement of the keep; only the runtime
+//! is. It gets the standard streams, the arguments and the environment, but
+//! no preopened directories yet. The runtime is built with the `wasm` feature,
+//! which is on by default:
+//!
+//! ```text
+//! $ cargo build --target wasm32-wasi --release
+//! $ enarx-keepldr exec ./target/wasm32-wasi/release/app.wasm
+//! ```
+
+use crate::binary::Component;
+
+use anyhow::Result;
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// The first bytes of every module
+const MAGIC: &[u8; 4] = b"\0asm";
+
+/// The environment variable which holds the descriptor of the module
+const FD_VAR: &str = "ENARX_WASM_FD";
+
+#[cfg(feature = "wasm")]
+const WASMLDR: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/bin/wasmldr"));
+
+/// A module, open for the keep to read
+pub struct Module(File);
+
+impl Module {
+    /// Opens the file, if it is a module
+    pub fn open(path: &Path) -> Result<Option<Self>> {
+        let mut file = File::open(path)?;
+
+        let mut magic = [0; MAGIC.len()];
+        if file.read_exact(&mut magic).is_err() || &magic != MAGIC {
+            return Ok(None);
+        }
+
+        // The runtime reads the module from the start.
+        file.seek(SeekFrom::Start(0))?;
+        Ok(Some(Self(file)))
+    }
+
+    /// The environment variable which tells the runtime where the module is
+    pub fn var(&self) -> String {
+        format!("{}={}", FD_VAR, self.0.as_raw_fd())
+    }
+}
+
+/// Returns true, if the file is a module
+pub fn is_module(path: &Path) -> Result<bool> {
+    Ok(Module::open(path)?.is_some())
+}
+
+/// The runtime, which is the code component of keeps running a module
+#[cfg(feature = "wasm")]
+pub fn runtime() -> Result<Component<'static>> {
+    Component::from_bytes(WASMLDR)
+}
+
+/// The runtime, which is the code component of keeps running a module
+#[cfg(not(feature = "wasm"))]
+pub fn runtime() -> Result<Component<'static>> {
+    anyhow::bail!("WebAssembly payloads are not supported (enable the `wasm` feature)")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    #[test]
+    fn detection() {
+        let dir = tempdir::TempDir::new("wasm").unwrap();
+
+        let module = dir.path().join("module.wasm");
+        File::create(&module)
+            .unwrap()
+            .write_all(b"\0asm\x01\0\0\0")
+            .unwrap();
+        assert!(is_module(&module).unwrap());
+
+        let elf = dir.path().join("elf");
+        File::create(&elf).unwrap().write_all(b"\x7fELF").unwrap();
+        assert!(!is_module(&elf).unwrap());
+
+        let empty = dir.path().join("empty");
+        File::create(&empty).unwrap();
+        assert!(!is_module(&empty).unwrap());
+    }
+}
@@ -0,0 +1,216 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A watchdog for hung keeps
+//!
+//! A thread of the host checks the main thread of the keep regularly, and
+//! kills the loader when the keep ran for longer than its timeout, or when it
+//! hangs for longer than its stall limit: when the thread stays inside the
+//! keep without exiting to the host, or when the payload keeps requesting the
+//! same syscall, which keeps failing in the same way.
+//!
+//! The watchdog logs why, with the last syscall and the state of the thread
+//! when it last entered the keep (e.g. the CSSA of SGX threads), and
+//! interrupts the thread (see `backend::interrupt`), which ends the keep with
+//! `Exit::TimedOut` once it is out of the keep. If it doesn't come out in
+//! time, e.g. because the host blocks in a syscall for it, the watchdog exits
+//! the process with `TIMED_OUT`, and the kernel destroys the keep along with
+//! it.
+
+use crate::backend::interrupt::Interrupt;
+use crate::exit::TIMED_OUT;
+use crate::trace;
+
+use tracing::error;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The longest interval between the checks
+const INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long the keep gets to end once its thread was interrupted
+const GRACE: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct State {
+    /// When the thread last entered the keep, while it is inside
+    entered: Option<Instant>,
+
+    /// The state of the thread when it last entered the keep
+    thread: Option<String>,
+
+    /// The last syscall which the host executed
+    syscall: Option<i64>,
+
+    /// The failing syscall which is repeated, its error and since when
+    spin: Option<(i64, libc::c_int, Instant)>,
+
+    /// The thread which runs the keep
+    interrupt: Option<Interrupt>,
+
+    /// Whether the keep must end
+    expired: bool,
+
+    /// Whether the keep ended
+    ended: bool,
+}
+
+/// The watchdog of a keep
+pub struct Watchdog {
+    state: Arc<Mutex<State>>,
+}
+
+impl Watchdog {
+    /// Starts watching the keep
+    pub fn start(timeout: Option<Duration>, stall: Option<Duration>) -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        let start = Instant::now();
+
+        let interval = timeout
+            .iter()
+            .chain(&stall)
+            .map(|limit| *limit / 10)
+            .fold(INTERVAL, Duration::min);
+
+        let watched = state.clone();
+        std::thread::spawn(move || {
+            let interrupt = loop {
+                std::thread::sleep(interval);
+
+                let mut state = watched.lock().unwrap();
+                if state.ended {
+                    return;
+                }
+
+                if let Some(reason) = check(&state, start.elapsed(), timeout, stall) {
+                    let syscall = state.syscall.map(trace::name);
+                    error!(
+                        syscall = syscall.as_deref().unwrap_or("none"),
+                        thread = state.thread.as_deref().unwrap_or("unknown"),
+                        "{}",
+                        reason
+                    );
+
+                    state.expired = true;
+                    break state.interrupt.clone();
+                }
+            };
+
+            // The lock is released, so that the thread can leave the keep.
+            if interrupt.map_or(false, |i| i.interrupt()) {
+                let deadline = Instant::now() + GRACE;
+                while Instant::now() < deadline {
+                    if watched.lock().unwrap().ended {
+                        return;
+                    }
+
+                    std::thread::sleep(INTERVAL.min(GRACE / 10));
+                }
+            }
+
+            std::process::exit(TIMED_OUT);
+        });
+
+        Self { state }
+    }
+
+    /// Interrupts this thread when the keep must end
+    pub fn interrupt(&self, interrupt: Interrupt) {
+        self.state.lock().unwrap().interrupt = Some(interrupt);
+    }
+
+    /// Whether the keep must end, because it ran for too long or hung
+    pub fn expired(&self) -> bool {
+        self.state.lock().unwrap().expired
+    }
+
+    /// Notes that the thread enters the keep
+    pub fn enter(&self, thread: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        state.entered = Some(Instant::now());
+        state.thread = thread;
+    }
+
+    /// Notes that the thread exited to the host
+    pub fn exited(&self) {
+        self.state.lock().unwrap().entered = None;
+    }
+
+    /// Notes a syscall which the host executed
+    pub fn syscall(&self, num: i64, result: &sallyport::Result) {
+        let mut state = self.state.lock().unwrap();
+        state.syscall = Some(num);
+        state.spin = match (state.spin, result) {
+            (Some((n, e, since)), Err(err)) if n == num && e == *err => Some((n, e, since)),
+            (_, Err(err)) => Some((num, *err, Instant::now())),
+            (_, Ok(_)) => None,
+        };
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().ended = true;
+    }
+}
+
+/// Returns why the keep hangs, if it does
+fn check(
+    state: &State,
+    elapsed: Duration,
+    timeout: Option<Duration>,
+    stall: Option<Duration>,
+) -> Option<String> {
+    if let Some(timeout) = timeout.filter(|t| elapsed > *t) {
+        return Some(format!(
+            "the keep ran for longer than {}s",
+            timeout.as_secs_f64()
+        ));
+    }
+
+    let stall = stall?;
+
+    if let Some(entered) = state.entered.filter(|e| e.elapsed() > stall) {
+        return Some(format!(
+            "the keep didn't exit to the host for {}s",
+            entered.elapsed().as_secs()
+        ));
+    }
+
+    match state.spin {
+        Some((num, err, since)) if since.elapsed() > stall => Some(format!(
+            "the payload repeated {}() {} for {}s",
+            trace::name(num),
+            trace::result(&Err(err)),
+            since.elapsed().as_secs()
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hangs() {
+        let long = Duration::from_secs(60);
+        let short = Duration::from_millis(1);
+        let mut state = State::default();
+
+        assert!(check(&state, long, None, Some(short)).is_none());
+        assert!(check(&state, long, Some(long / 2), None).is_some());
+        assert!(check(&state, short, Some(long), Some(long)).is_none());
+
+        state.entered = Some(Instant::now());
+        std::thread::sleep(short * 2);
+        assert!(check(&state, short, None, Some(short)).is_some());
+        assert!(check(&state, short, None, Some(long)).is_none());
+
+        state.entered = None;
+        state.spin = Some((libc::SYS_read, libc::EAGAIN, Instant::now()));
+        std::thread::sleep(short * 2);
+        let reason = check(&state, short, None, Some(short)).unwrap();
+        assert!(reason.contains("read()"), "{}", reason);
+    }
+}
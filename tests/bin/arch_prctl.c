@@ -0,0 +1,32 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+
+/* Sets up a thread pointer like a libc does for TLS and reads through it. */
+int main(void) {
+    static unsigned long tls[2];
+    unsigned long fs = 0;
+    unsigned long value;
+
+    tls[0] = (unsigned long) tls;
+    tls[1] = 42;
+
+    if (arch_prctl(ARCH_SET_FS, (unsigned long) tls) < 0)
+        return 1;
+
+    if (arch_prctl(ARCH_GET_FS, (unsigned long) &fs) < 0)
+        return 2;
+
+    if (fs != (unsigned long) tls)
+        return 3;
+
+    asm volatile("mov %%fs:8, %0" : "=r" (value));
+    if (value != 42)
+        return 4;
+
+    /* Non-canonical bases are refused, as on Linux. */
+    if (arch_prctl(ARCH_SET_GS, 1UL << 63) >= 0 || errno != EPERM)
+        return 5;
+
+    return 0;
+}
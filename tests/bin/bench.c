@@ -0,0 +1,49 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Runs the benchmarks of `enarx_keepldr::bench`: reads a command from stdin,
+// does what it says and reads the next one. A read which fails, as it does
+// with `EAGAIN` while the host has no command yet, is retried.
+
+#include "libc.h"
+
+#define SYSCALLS 0
+#define WRITE 1
+
+struct command {
+    unsigned long op;
+    unsigned long count;
+};
+
+static char buf[1 << 20];
+
+int main(void) {
+    struct command cmd;
+    unsigned long left;
+    ssize_t n;
+
+    for (;;) {
+        n = read(0, &cmd, sizeof(cmd));
+        if (n == 0)
+            return 0;
+        if (n != sizeof(cmd))
+            continue;
+
+        switch (cmd.op) {
+        case SYSCALLS:
+            for (; cmd.count > 0; cmd.count--)
+                close(-1);
+            break;
+
+        case WRITE:
+            for (left = cmd.count; left > 0; left -= n) {
+                n = write(1, buf, left < sizeof(buf) ? left : sizeof(buf));
+                if (n < 0)
+                    return 1;
+            }
+            break;
+
+        default:
+            return 1;
+        }
+    }
+}
@@ -0,0 +1,12 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Requests `close(-1)` from the host until the keep is stopped, which only
+// its timeout or its watchdog does.
+
+#include "libc.h"
+
+int main(void) {
+    for (;;) {
+        close(-1);
+    }
+}
@@ -0,0 +1,44 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+
+static const char DIGITS[] = "0123456789abcdef";
+
+/* Appends the bytes of a value in hexadecimal. */
+static char *hex(char *out, const void *data, size_t len) {
+    const unsigned char *bytes = data;
+
+    for (size_t i = 0; i < len; i++) {
+        *out++ = DIGITS[bytes[i] >> 4];
+        *out++ = DIGITS[bytes[i] & 0xf];
+    }
+
+    *out++ = '\n';
+    return out;
+}
+
+/* Prints the time and random numbers, which only repeat in deterministic runs. */
+int main(void) {
+    struct timespec monotonic, realtime;
+    unsigned char random[16];
+    char line[128];
+    char *end = line;
+
+    if (clock_gettime(CLOCK_MONOTONIC, &monotonic) < 0)
+        return 1;
+
+    if (clock_gettime(CLOCK_REALTIME, &realtime) < 0)
+        return 1;
+
+    if (getrandom(random, sizeof(random), 0) != sizeof(random))
+        return 2;
+
+    end = hex(end, &monotonic, sizeof(monotonic));
+    end = hex(end, &realtime, sizeof(realtime));
+    end = hex(end, random, sizeof(random));
+
+    if (write(STDOUT_FILENO, line, end - line) != end - line)
+        return 3;
+
+    return 0;
+}
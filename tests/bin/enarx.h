@@ -9,5 +9,9 @@ enum tee_tech {
     TEE_SGX,
 };
 
+/* Seal key policies for get_key() */
+#define KEY_POLICY_MRENCLAVE 0x1
+#define KEY_POLICY_MRSIGNER  0x2
+
 #endif
 
@@ -0,0 +1,9 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! `environ` prints its arguments and the `GREETING` environment variable.
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    println!("{:?}", args);
+    println!("{}", std::env::var("GREETING").unwrap());
+}
@@ -0,0 +1,69 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+
+int main(void) {
+    static const char msg[] = "ping";
+    struct epoll_event ev = {0};
+    struct pollfd pfd = {0};
+    struct timeval tv = {0};
+    char buf[sizeof(msg)];
+    fd_set rfds;
+    int sv[2];
+    int epfd;
+
+    if (socketpair(AF_UNIX, SOCK_STREAM, 0, sv) < 0)
+        return 1;
+
+    epfd = epoll_create1(EPOLL_CLOEXEC);
+    if (epfd < 0)
+        return 2;
+
+    ev.events = EPOLLIN;
+    ev.data.u64 = 0x1234;
+    if (epoll_ctl(epfd, EPOLL_CTL_ADD, sv[1], &ev) < 0)
+        return 3;
+
+    /* Nothing has been written yet. */
+    if (epoll_wait(epfd, &ev, 1, 0) != 0)
+        return 4;
+
+    pfd.fd = sv[1];
+    pfd.events = POLLIN;
+    if (poll(&pfd, 1, 0) != 0 || pfd.revents != 0)
+        return 5;
+
+    if (sendto(sv[0], msg, sizeof(msg), 0, NULL, 0) != sizeof(msg))
+        return 6;
+
+    ev.events = 0;
+    ev.data.u64 = 0;
+    if (epoll_wait(epfd, &ev, 1, -1) != 1)
+        return 7;
+
+    if (ev.events != EPOLLIN || ev.data.u64 != 0x1234)
+        return 8;
+
+    if (poll(&pfd, 1, -1) != 1 || pfd.revents != POLLIN)
+        return 9;
+
+    FD_ZERO(&rfds);
+    FD_SET(sv[1], &rfds);
+    if (select(sv[1] + 1, &rfds, NULL, NULL, &tv) != 1 || !FD_ISSET(sv[1], &rfds))
+        return 10;
+
+    if (epoll_ctl(epfd, EPOLL_CTL_DEL, sv[1], NULL) < 0)
+        return 11;
+
+    /* The descriptor is no longer watched. */
+    if (epoll_wait(epfd, &ev, 1, 0) != 0)
+        return 12;
+
+    if (read(sv[1], buf, sizeof(buf)) != sizeof(msg))
+        return 13;
+
+    close(epfd);
+    close(sv[0]);
+    close(sv[1]);
+    return 0;
+}
@@ -0,0 +1,74 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+
+int main(void) {
+    struct itimerspec its;
+    struct epoll_event ev = {0};
+    uint64_t val = 0;
+    int efd, tfd, epfd;
+
+    efd = eventfd(0, EFD_NONBLOCK | EFD_CLOEXEC);
+    if (efd < 0)
+        return 1;
+
+    /* Nothing has been written yet. */
+    if (read(efd, &val, sizeof(val)) != -1 || errno != EAGAIN)
+        return 2;
+
+    val = 3;
+    if (write(efd, &val, sizeof(val)) != sizeof(val))
+        return 3;
+
+    val = 0;
+    if (read(efd, &val, sizeof(val)) != sizeof(val) || val != 3)
+        return 4;
+
+    if (eventfd(0, O_APPEND) != -1 || errno != EINVAL)
+        return 5;
+
+    tfd = timerfd_create(CLOCK_MONOTONIC, TFD_NONBLOCK | TFD_CLOEXEC);
+    if (tfd < 0)
+        return 6;
+
+    /* The timer isn't armed yet. */
+    if (read(tfd, &val, sizeof(val)) != -1 || errno != EAGAIN)
+        return 7;
+
+    epfd = epoll_create1(EPOLL_CLOEXEC);
+    if (epfd < 0)
+        return 8;
+
+    ev.events = EPOLLIN;
+    ev.data.fd = tfd;
+    if (epoll_ctl(epfd, EPOLL_CTL_ADD, tfd, &ev) < 0)
+        return 9;
+
+    its.it_interval.tv_sec = 0;
+    its.it_interval.tv_nsec = 0;
+    its.it_value.tv_sec = 0;
+    its.it_value.tv_nsec = 1000000;
+    if (timerfd_settime(tfd, 0, &its, NULL) < 0)
+        return 10;
+
+    if (epoll_wait(epfd, &ev, 1, -1) != 1 || ev.data.fd != tfd)
+        return 11;
+
+    if (read(tfd, &val, sizeof(val)) != sizeof(val) || val != 1)
+        return 12;
+
+    /* The timer expired once and wasn't rearmed. */
+    if (timerfd_gettime(tfd, &its) < 0)
+        return 13;
+
+    if (its.it_value.tv_sec != 0 || its.it_value.tv_nsec != 0)
+        return 14;
+
+    if (timerfd_create(CLOCK_PROCESS_CPUTIME_ID, 0) != -1 || errno != EINVAL)
+        return 15;
+
+    close(epfd);
+    close(tfd);
+    close(efd);
+    return 0;
+}
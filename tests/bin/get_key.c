@@ -0,0 +1,41 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+#include "enarx.h"
+#include <errno.h>
+
+static int equal(const unsigned char *a, const unsigned char *b, size_t len) {
+    for (size_t i = 0; i < len; i++) {
+        if (a[i] != b[i])
+            return 0;
+    }
+
+    return 1;
+}
+
+int main(void) {
+    unsigned char enclave[16];
+    unsigned char again[16];
+    unsigned char signer[16];
+
+    /* Keeps without a measurement-bound key don't support this. */
+    if (get_key(KEY_POLICY_MRENCLAVE, enclave, sizeof(enclave)) < 0)
+        return errno != ENOSYS;
+
+    if (get_key(KEY_POLICY_MRENCLAVE, again, sizeof(again)) != sizeof(again))
+        return 2;
+
+    if (get_key(KEY_POLICY_MRSIGNER, signer, sizeof(signer)) != sizeof(signer))
+        return 3;
+
+    if (!equal(enclave, again, sizeof(enclave)) || equal(enclave, signer, sizeof(enclave)))
+        return 4;
+
+    if (get_key(0, again, sizeof(again)) >= 0 || errno != EINVAL)
+        return 5;
+
+    if (get_key(KEY_POLICY_MRENCLAVE, again, 8) >= 0 || errno != EINVAL)
+        return 6;
+
+    return 0;
+}
@@ -0,0 +1,28 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+#include <stddef.h>
+
+static int equal(const char *a, const char *b, size_t len) {
+    for (size_t i = 0; i < len; i++) {
+        if (a[i] != b[i])
+            return 0;
+    }
+
+    return 1;
+}
+
+/* Expects the secret "Hello, World!" to be injected. */
+int main(void) {
+    static const char msg[] = "Hello, World!";
+    char buf[sizeof(msg)] = {0};
+
+    /* The length of the whole secret is returned. */
+    if (get_secret(buf, 5) != sizeof(msg) - 1 || !equal(buf, msg, 5) || buf[5] != 0)
+        return 1;
+
+    if (get_secret(buf, sizeof(buf)) != sizeof(msg) - 1 || !equal(buf, msg, sizeof(msg) - 1))
+        return 2;
+
+    return 0;
+}
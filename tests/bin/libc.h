@@ -8,6 +8,18 @@
 #include <sys/types.h>
 #include <sys/socket.h>
 #include <sys/utsname.h>
+#include <sys/sysinfo.h>
+#include <sys/epoll.h>
+#include <sys/eventfd.h>
+#include <sys/timerfd.h>
+#include <sys/select.h>
+#include <poll.h>
+#include <sys/stat.h>
+#include <sys/mman.h>
+#include <fcntl.h>
+#include <stdarg.h>
+#include <asm/prctl.h> /* ARCH_SET_FS */
+#include <linux/prctl.h> /* PR_SET_NAME */
 
 int *__errno_location(void) {
     static int errnum = 0;
@@ -66,6 +78,44 @@ ssize_t readv(int fd, const struct iovec *iov, int iovcnt) {
     return rax;
 }
 
+ssize_t pread(int fd, void *buf, size_t count, off_t offset) {
+    ssize_t rax;
+    register off_t r10 __asm__("r10") = offset;
+
+    asm(
+        "syscall"
+        : "=a" (rax)
+        : "a" (SYS_pread64), "D" (fd), "S" (buf), "d" (count), "r" (r10)
+        : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+ssize_t pwrite(int fd, const void *buf, size_t count, off_t offset) {
+    ssize_t rax;
+    register off_t r10 __asm__("r10") = offset;
+
+    asm(
+        "syscall"
+        : "=a" (rax)
+        : "a" (SYS_pwrite64), "D" (fd), "S" (buf), "d" (count), "r" (r10)
+        : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
 ssize_t write(int fd, const void *buf, size_t count) {
     ssize_t rax;
 
@@ -102,6 +152,57 @@ int clock_gettime(clockid_t clk_id, struct timespec *tp) {
     return rax;
 }
 
+int nanosleep(const struct timespec *req, struct timespec *rem) {
+    int rax;
+
+    asm(
+        "syscall"
+        : "=a" (rax)
+        : "a" (SYS_nanosleep), "D" (req), "S" (rem)
+        : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+/* Like glibc, this returns the error rather than setting errno. */
+int clock_nanosleep(clockid_t clockid, int flags, const struct timespec *request, struct timespec *remain) {
+    int rax;
+    register struct timespec *r10 __asm__("r10") = remain;
+
+    asm(
+        "syscall"
+        : "=a" (rax)
+        : "a" (SYS_clock_nanosleep), "D" (clockid), "S" (flags), "d" (request), "r" (r10)
+        : "%rcx", "%r11"
+    );
+
+    return -rax;
+}
+
+ssize_t getrandom(void *buf, size_t buflen, unsigned int flags) {
+    ssize_t rax;
+
+    asm(
+        "syscall"
+        : "=a" (rax)
+        : "a" (SYS_getrandom), "D" (buf), "S" (buflen), "d" (flags)
+        : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
 int is_enarx() {
     ssize_t rax;
 
@@ -140,6 +241,42 @@ ssize_t get_att(void *nonce, size_t nonce_len, void *buf, size_t buf_len, size_t
     return rax;
 }
 
+ssize_t get_key(int policy, void *buf, size_t buf_len) {
+    ssize_t rax;
+
+    asm(
+        "syscall"
+        : "=a" (rax)
+        : "a" (0xEA02), "D" (policy), "S" (buf), "d" (buf_len)
+        : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+ssize_t get_secret(void *buf, size_t buf_len) {
+    ssize_t rax;
+
+    asm(
+        "syscall"
+        : "=a" (rax)
+        : "a" (0xEA03), "D" (buf), "S" (buf_len)
+        : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
 uid_t getuid() {
     uid_t rax;
     asm(
@@ -222,6 +359,24 @@ int uname(struct utsname *buf) {
     return rax;
 }
 
+int sysinfo(struct sysinfo *info) {
+    ssize_t rax;
+
+    asm(
+        "syscall"
+        : "=a" (rax)
+        : "a" (SYS_sysinfo), "D" (info)
+        : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
 int socket(int domain, int type, int protocol) {
     int rax;
 
@@ -352,3 +507,750 @@ ssize_t recvfrom(int sockfd, void *buf, size_t len, int flags,
 
     return rax;
 }
+
+ssize_t sendto(int sockfd, const void *buf, size_t len, int flags,
+        const struct sockaddr *dest_addr, socklen_t addrlen) {
+    ssize_t rax;
+    register int r10 __asm__("r10") = flags;
+    register const struct sockaddr *r8 __asm__("r8") = dest_addr;
+    register socklen_t r9 __asm__("r9") = addrlen;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_sendto), "D" (sockfd), "S" (buf), "d" (len), "r" (r10), "r" (r8), "r" (r9)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int getsockopt(int sockfd, int level, int optname, void *optval, socklen_t *optlen) {
+    int rax;
+    register void *r10 __asm__("r10") = optval;
+    register socklen_t *r8 __asm__("r8") = optlen;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_getsockopt), "D" (sockfd), "S" (level), "d" (optname), "r" (r10), "r" (r8)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int shutdown(int sockfd, int how) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_shutdown), "D" (sockfd), "S" (how)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int socketpair(int domain, int type, int protocol, int sv[2]) {
+    int rax;
+    register int *r10 __asm__("r10") = sv;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_socketpair), "D" (domain), "S" (type), "d" (protocol), "r" (r10)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int epoll_create1(int flags) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_epoll_create1), "D" (flags)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int epoll_ctl(int epfd, int op, int fd, struct epoll_event *event) {
+    int rax;
+    register struct epoll_event *r10 __asm__("r10") = event;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_epoll_ctl), "D" (epfd), "S" (op), "d" (fd), "r" (r10)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int epoll_wait(int epfd, struct epoll_event *events, int maxevents, int timeout) {
+    int rax;
+    register int r10 __asm__("r10") = timeout;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_epoll_wait), "D" (epfd), "S" (events), "d" (maxevents), "r" (r10)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int poll(struct pollfd *fds, nfds_t nfds, int timeout) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_poll), "D" (fds), "S" (nfds), "d" (timeout)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int select(int nfds, fd_set *readfds, fd_set *writefds, fd_set *exceptfds, struct timeval *timeout) {
+    int rax;
+    register fd_set *r10 __asm__("r10") = exceptfds;
+    register struct timeval *r8 __asm__("r8") = timeout;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_select), "D" (nfds), "S" (readfds), "d" (writefds), "r" (r10), "r" (r8)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int eventfd(unsigned int initval, int flags) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_eventfd2), "D" (initval), "S" (flags)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int timerfd_create(clockid_t clockid, int flags) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_timerfd_create), "D" (clockid), "S" (flags)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int timerfd_settime(int fd, int flags, const struct itimerspec *new_value, struct itimerspec *old_value) {
+    int rax;
+    register struct itimerspec *r10 __asm__("r10") = old_value;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_timerfd_settime), "D" (fd), "S" (flags), "d" (new_value), "r" (r10)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int timerfd_gettime(int fd, struct itimerspec *curr_value) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_timerfd_gettime), "D" (fd), "S" (curr_value)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int open(const char *pathname, int flags, ...) {
+    int rax;
+    mode_t mode = 0;
+    va_list ap;
+
+    va_start(ap, flags);
+    if (flags & O_CREAT)
+        mode = va_arg(ap, mode_t);
+    va_end(ap);
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_open), "D" (pathname), "S" (flags), "d" (mode)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+off_t lseek(int fd, off_t offset, int whence) {
+    off_t rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_lseek), "D" (fd), "S" (offset), "d" (whence)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int fcntl(int fd, int cmd, ...) {
+    int rax;
+    long arg;
+    va_list ap;
+
+    va_start(ap, cmd);
+    arg = va_arg(ap, long);
+    va_end(ap);
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_fcntl), "D" (fd), "S" (cmd), "d" (arg)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int dup(int oldfd) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_dup), "D" (oldfd)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int dup2(int oldfd, int newfd) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_dup2), "D" (oldfd), "S" (newfd)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int pipe2(int pipefd[2], int flags) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_pipe2), "D" (pipefd), "S" (flags)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int fstat(int fd, struct stat *statbuf) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_fstat), "D" (fd), "S" (statbuf)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int mkdir(const char *pathname, mode_t mode) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_mkdir), "D" (pathname), "S" (mode)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int rmdir(const char *pathname) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_rmdir), "D" (pathname)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int unlink(const char *pathname) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_unlink), "D" (pathname)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int rename(const char *oldpath, const char *newpath) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_rename), "D" (oldpath), "S" (newpath)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+ssize_t getdents64(int fd, void *dirp, size_t count) {
+    ssize_t rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_getdents64), "D" (fd), "S" (dirp), "d" (count)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int arch_prctl(int code, unsigned long addr) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_arch_prctl), "D" (code), "S" (addr)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int prctl(int option, unsigned long arg2, unsigned long arg3, unsigned long arg4, unsigned long arg5) {
+    int rax;
+    register unsigned long r10 __asm__("r10") = arg4;
+    register unsigned long r8 __asm__("r8") = arg5;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_prctl), "D" (option), "S" (arg2), "d" (arg3), "r" (r10), "r" (r8)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+/* The raw system call, which takes the size of the kernel's signal set */
+int k_epoll_pwait(int epfd, struct epoll_event *events, int maxevents, int timeout, const unsigned long *sigmask, size_t sigsetsize) {
+    int rax;
+    register int r10 __asm__("r10") = timeout;
+    register const unsigned long *r8 __asm__("r8") = sigmask;
+    register size_t r9 __asm__("r9") = sigsetsize;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_epoll_pwait), "D" (epfd), "S" (events), "d" (maxevents), "r" (r10), "r" (r8), "r" (r9)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int rt_sigprocmask(int how, const unsigned long *set, unsigned long *oldset) {
+    int rax;
+    register size_t r10 __asm__("r10") = sizeof(*set);
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_rt_sigprocmask), "D" (how), "S" (set), "d" (oldset), "r" (r10)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+/* The kernel's `struct sigaction`, which differs from the one of a libc */
+struct k_sigaction {
+    void (*handler)(int, void *, void *);
+    unsigned long flags;
+    void (*restorer)(void);
+    unsigned long mask;
+};
+
+int rt_sigaction(int signum, const struct k_sigaction *act, struct k_sigaction *oldact) {
+    int rax;
+    register size_t r10 __asm__("r10") = sizeof(act->mask);
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_rt_sigaction), "D" (signum), "S" (act), "d" (oldact), "r" (r10)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+/* The raw system call, which returns the size of the CPU set it filled in */
+int sched_getaffinity(pid_t pid, size_t len, unsigned long *mask) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_sched_getaffinity), "D" (pid), "S" (len), "d" (mask)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int getcpu(unsigned int *cpu, unsigned int *node) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_getcpu), "D" (cpu), "S" (node)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+void *mmap(void *addr, size_t length, int prot, int flags, int fd, off_t offset) {
+    long rax;
+    register long r10 __asm__("r10") = flags;
+    register long r8 __asm__("r8") = fd;
+    register long r9 __asm__("r9") = offset;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_mmap), "D" (addr), "S" (length), "d" (prot), "r" (r10), "r" (r8), "r" (r9)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return MAP_FAILED;
+    }
+
+    return (void *) rax;
+}
+
+int munmap(void *addr, size_t length) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_munmap), "D" (addr), "S" (length)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int madvise(void *addr, size_t length, int advice) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_madvise), "D" (addr), "S" (length), "d" (advice)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int mlock(const void *addr, size_t len) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_mlock), "D" (addr), "S" (len)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int munlock(const void *addr, size_t len) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_munlock), "D" (addr), "S" (len)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int mlockall(int flags) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_mlockall), "D" (flags)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
+
+int munlockall(void) {
+    int rax;
+
+    asm(
+    "syscall"
+    : "=a" (rax)
+    : "a" (SYS_munlockall)
+    : "%rcx", "%r11"
+    );
+
+    if (rax < 0) {
+        errno = -rax;
+        return -1;
+    }
+
+    return rax;
+}
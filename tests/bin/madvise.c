@@ -0,0 +1,52 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+
+#define SIZE 8192
+
+int main(void) {
+    unsigned char *map;
+    int i;
+
+    map = mmap(NULL, SIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    if (map == MAP_FAILED)
+        return 1;
+
+    for (i = 0; i < SIZE; i++)
+        map[i] = 0xff;
+
+    /* Allocators rely on these pages reading back as zeroes. */
+    if (madvise(map + 4096, 4096, MADV_DONTNEED) < 0)
+        return 2;
+
+    if (map[0] != 0xff || map[4095] != 0xff)
+        return 3;
+
+    for (i = 4096; i < SIZE; i++) {
+        if (map[i] != 0)
+            return 4;
+    }
+
+    if (madvise(map, SIZE, MADV_FREE) < 0 || madvise(map, SIZE, MADV_WILLNEED) < 0)
+        return 5;
+
+    if (madvise(map + 1, 4096, MADV_DONTNEED) != -1 || errno != EINVAL)
+        return 6;
+
+    if (madvise(map, SIZE, 12345) != -1 || errno != EINVAL)
+        return 7;
+
+    if (mlock(map + 1, 100) < 0 || munlock(map + 1, 100) < 0)
+        return 8;
+
+    if (mlockall(MCL_CURRENT | MCL_FUTURE) < 0 || munlockall() < 0)
+        return 9;
+
+    if (mlockall(0) != -1 || errno != EINVAL)
+        return 10;
+
+    if (munmap(map, SIZE) < 0)
+        return 11;
+
+    return 0;
+}
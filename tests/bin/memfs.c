@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+#include <stddef.h>
+#include <stdint.h>
+
+static int equal(const char *a, const char *b, size_t len) {
+    for (size_t i = 0; i < len; i++) {
+        if (a[i] != b[i])
+            return 0;
+    }
+
+    return 1;
+}
+
+/* Counts the entries of a directory, including "." and "..". */
+static int entries(const char *path) {
+    char buf[256];
+    ssize_t len;
+    int count = 0;
+    int fd;
+
+    fd = open(path, O_RDONLY | O_DIRECTORY);
+    if (fd < 0)
+        return -1;
+
+    while ((len = getdents64(fd, buf, sizeof(buf))) > 0) {
+        for (ssize_t off = 0; off < len; count++)
+            off += *(uint16_t *) &buf[off + 16];
+    }
+
+    close(fd);
+    return len < 0 ? -1 : count;
+}
+
+int main(void) {
+    static const char msg[] = "Hello, World!";
+    char buf[sizeof(msg)] = {0};
+    struct stat st;
+    int fd;
+
+    if (mkdir("/tmp", 0777) < 0)
+        return 1;
+
+    fd = open("/tmp/file", O_RDWR | O_CREAT | O_EXCL, 0644);
+    if (fd < 0)
+        return 2;
+
+    if (write(fd, msg, sizeof(msg)) != sizeof(msg))
+        return 3;
+
+    if (lseek(fd, 0, SEEK_SET) != 0)
+        return 4;
+
+    if (read(fd, buf, sizeof(buf)) != sizeof(msg) || !equal(buf, msg, sizeof(msg)))
+        return 5;
+
+    if (fstat(fd, &st) < 0 || st.st_size != sizeof(msg) || !S_ISREG(st.st_mode))
+        return 6;
+
+    close(fd);
+
+    if (rename("/tmp/file", "/tmp/renamed") < 0)
+        return 7;
+
+    if (open("/tmp/file", O_RDONLY) >= 0 || errno != ENOENT)
+        return 8;
+
+    if (entries("/tmp") != 3)
+        return 9;
+
+    if (rmdir("/tmp") >= 0 || errno != ENOTEMPTY)
+        return 10;
+
+    if (unlink("/tmp/renamed") < 0)
+        return 11;
+
+    if (rmdir("/tmp") < 0)
+        return 12;
+
+    if (entries("/") != 2)
+        return 13;
+
+    return 0;
+}
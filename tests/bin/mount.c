@@ -0,0 +1,102 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+#include <stddef.h>
+#include <stdint.h>
+
+static int equal(const char *a, const char *b, size_t len) {
+    for (size_t i = 0; i < len; i++) {
+        if (a[i] != b[i])
+            return 0;
+    }
+
+    return 1;
+}
+
+/* Counts the entries of a directory, including "." and "..". */
+static int entries(const char *path) {
+    char buf[256];
+    ssize_t len;
+    int count = 0;
+    int fd;
+
+    fd = open(path, O_RDONLY | O_DIRECTORY);
+    if (fd < 0)
+        return -1;
+
+    while ((len = getdents64(fd, buf, sizeof(buf))) > 0) {
+        for (ssize_t off = 0; off < len; count++)
+            off += *(uint16_t *) &buf[off + 16];
+    }
+
+    close(fd);
+    return len < 0 ? -1 : count;
+}
+
+/* Expects `/data` to hold a file `in` and `/ro` a file `file` (read-only). */
+int main(void) {
+    static const char msg[] = "Hello, World!";
+    char buf[sizeof(msg)] = {0};
+    int fd;
+
+    if (entries("/") != 4)
+        return 1;
+
+    fd = open("/data/in", O_RDONLY);
+    if (fd < 0)
+        return 2;
+
+    if (read(fd, buf, sizeof(buf)) != sizeof(msg) - 1 || !equal(buf, msg, sizeof(msg) - 1))
+        return 3;
+
+    /* Host files are read at an offset without moving the file position. */
+    if (pread(fd, buf, sizeof(buf), 7) != 6 || !equal(buf, "World!", 6))
+        return 3;
+
+    close(fd);
+
+    fd = open("/data/out", O_WRONLY | O_CREAT | O_TRUNC, 0644);
+    if (fd < 0)
+        return 4;
+
+    if (write(fd, msg, sizeof(msg) - 1) != sizeof(msg) - 1)
+        return 5;
+
+    if (pwrite(fd, "w", 1, 7) != 1)
+        return 5;
+
+    close(fd);
+
+    if (mkdir("/data/dir", 0755) < 0)
+        return 6;
+
+    if (rename("/data/out", "/data/dir/out") < 0)
+        return 7;
+
+    if (entries("/data/dir") != 3)
+        return 8;
+
+    if (open("/ro/file", O_WRONLY) >= 0 || errno != EROFS)
+        return 9;
+
+    fd = open("/ro/file", O_RDONLY);
+    if (fd < 0)
+        return 10;
+
+    close(fd);
+
+    if (rename("/data/in", "/in") >= 0 || errno != EXDEV)
+        return 11;
+
+    /* Paths outside of the mounts never reach the host. */
+    if (open("/data/../../etc/passwd", O_RDONLY) >= 0 || errno != ENOENT)
+        return 12;
+
+    if (unlink("/data/in") < 0)
+        return 13;
+
+    if (rmdir("/data") >= 0 || errno != EBUSY)
+        return 14;
+
+    return 0;
+}
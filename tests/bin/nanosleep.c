@@ -0,0 +1,65 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+
+#define NSEC_PER_SEC 1000000000L
+
+static long elapsed(const struct timespec *start) {
+    struct timespec now;
+
+    if (clock_gettime(CLOCK_MONOTONIC, &now) < 0)
+        return -1;
+
+    return (now.tv_sec - start->tv_sec) * NSEC_PER_SEC + now.tv_nsec - start->tv_nsec;
+}
+
+int main(void) {
+    struct timespec start, req, rem;
+
+    if (clock_gettime(CLOCK_MONOTONIC, &start) < 0)
+        return 1;
+
+    /* Short enough to be spun in the keep */
+    req.tv_sec = 0;
+    req.tv_nsec = 50000;
+    if (nanosleep(&req, &rem) < 0 || elapsed(&start) < 0)
+        return 2;
+
+    if (clock_nanosleep(CLOCK_MONOTONIC, 0, &req, NULL) != 0)
+        return 3;
+
+    /* Long enough for the host to sleep; the clock may lag a little. */
+    if (clock_gettime(CLOCK_MONOTONIC, &start) < 0)
+        return 4;
+
+    req.tv_nsec = 20000000;
+    if (nanosleep(&req, &rem) < 0)
+        return 5;
+
+    if (elapsed(&start) < 19000000)
+        return 6;
+
+    /* Sleep until 10ms from now */
+    if (clock_gettime(CLOCK_MONOTONIC, &req) < 0)
+        return 7;
+
+    start = req;
+    req.tv_nsec += 10000000;
+    if (req.tv_nsec >= NSEC_PER_SEC) {
+        req.tv_nsec -= NSEC_PER_SEC;
+        req.tv_sec += 1;
+    }
+
+    if (clock_nanosleep(CLOCK_MONOTONIC, TIMER_ABSTIME, &req, NULL) != 0)
+        return 8;
+
+    if (elapsed(&start) < 9000000)
+        return 9;
+
+    req.tv_sec = 0;
+    req.tv_nsec = NSEC_PER_SEC;
+    if (nanosleep(&req, NULL) != -1 || errno != EINVAL)
+        return 10;
+
+    return 0;
+}
@@ -0,0 +1,54 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+
+/* Passes data through a pipe and shares files between duplicated descriptors. */
+int main(void) {
+    char buf[8] = { 0 };
+    int fds[2];
+    int fd, dupfd;
+
+    if (pipe2(fds, O_CLOEXEC) < 0)
+        return 1;
+
+    if (fcntl(fds[0], F_GETFD) != FD_CLOEXEC)
+        return 2;
+
+    dupfd = dup(fds[1]);
+    if (dupfd < 0 || write(dupfd, "pipe", 4) != 4)
+        return 3;
+
+    if (read(fds[0], buf, sizeof(buf)) != 4 || buf[0] != 'p' || buf[3] != 'e')
+        return 4;
+
+    if (fcntl(fds[0], F_SETFL, O_NONBLOCK) < 0 || !(fcntl(fds[0], F_GETFL) & O_NONBLOCK))
+        return 5;
+
+    if (read(fds[0], buf, sizeof(buf)) >= 0 || errno != EAGAIN)
+        return 6;
+
+    close(dupfd);
+    close(fds[1]);
+    close(fds[0]);
+
+    /* Descriptors of in-keep files share the file position. */
+    fd = open("/file", O_RDWR | O_CREAT, 0600);
+    if (fd < 0)
+        return 7;
+
+    dupfd = fcntl(fd, F_DUPFD, 0);
+    if (dupfd < 0 || dupfd == fd || write(fd, "abc", 3) != 3)
+        return 8;
+
+    if (lseek(dupfd, 0, SEEK_CUR) != 3)
+        return 9;
+
+    if (dup2(fd, 1) >= 0 || errno != EBADF)
+        return 10;
+
+    close(fd);
+    if (lseek(dupfd, 0, SEEK_SET) != 0 || read(dupfd, buf, 3) != 3 || buf[2] != 'c')
+        return 11;
+
+    return 0;
+}
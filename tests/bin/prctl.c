@@ -0,0 +1,30 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+
+/* Names the payload and drops privileges, like runtimes do at startup. */
+int main(void) {
+    char name[16] = { 0 };
+    const char *expected = "a-long-thread-n";
+
+    if (prctl(PR_SET_NAME, (unsigned long) "a-long-thread-name", 0, 0, 0) != 0)
+        return 1;
+
+    if (prctl(PR_GET_NAME, (unsigned long) name, 0, 0, 0) != 0)
+        return 2;
+
+    for (int i = 0; i < 16; i++)
+        if (name[i] != expected[i])
+            return 3;
+
+    if (prctl(PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0)
+        return 4;
+
+    if (prctl(PR_GET_NO_NEW_PRIVS, 0, 0, 0, 0) != 1)
+        return 5;
+
+    if (prctl(PR_SET_NO_NEW_PRIVS, 2, 0, 0, 0) >= 0 || errno != EINVAL)
+        return 6;
+
+    return 0;
+}
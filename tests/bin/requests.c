@@ -0,0 +1,26 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+
+/* Requests a heap of 16 MiB, a stack of 1 MiB and two threads */
+asm(
+    ".pushsection .note.enarx, \"a\", @note\n"
+    ".p2align 2\n"
+    ".long 6, 8, 0x70617900\n"
+    ".asciz \"enarx\"\n"
+    ".p2align 2\n"
+    ".quad 16 * 1024 * 1024\n"
+    ".long 6, 8, 0x70617901\n"
+    ".asciz \"enarx\"\n"
+    ".p2align 2\n"
+    ".quad 1024 * 1024\n"
+    ".long 6, 4, 0x70617902\n"
+    ".asciz \"enarx\"\n"
+    ".p2align 2\n"
+    ".long 2\n"
+    ".popsection\n"
+);
+
+int main(void) {
+    return 0;
+}
@@ -0,0 +1,29 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+
+/* Checks that the payload sees the single vCPU of the keep. */
+int main(void) {
+    unsigned long mask[16] = { 0 };
+    unsigned int cpu = 1, node = 1;
+    int size;
+
+    size = sched_getaffinity(0, sizeof(mask), mask);
+    if (size <= 0 || size % sizeof(unsigned long) != 0)
+        return 1;
+
+    if (mask[0] != 1)
+        return 2;
+
+    for (int i = 1; i < 16; i++)
+        if (mask[i] != 0)
+            return 2;
+
+    if (sched_getaffinity(0, 1, mask) >= 0 || errno != EINVAL)
+        return 3;
+
+    if (getcpu(&cpu, &node) != 0 || cpu != 0 || node != 0)
+        return 4;
+
+    return 0;
+}
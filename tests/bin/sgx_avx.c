@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+#include <signal.h>
+
+#ifndef SA_RESTORER
+#define SA_RESTORER 0x04000000
+#endif
+
+/* Returns from a signal handler, as the restorer of a libc does. */
+void restore_rt(void);
+asm(
+    ".text\n"
+    "restore_rt:\n"
+    "    mov $15, %rax\n"
+    "    syscall\n"
+);
+
+/* The start of the kernel's `struct ucontext` */
+struct k_ucontext {
+    unsigned long flags;
+    void *link;
+    stack_t stack;
+    unsigned long r8, r9, r10, r11, r12, r13, r14, r15;
+    unsigned long rdi, rsi, rbp, rbx, rdx, rax, rcx, rsp, rip;
+};
+
+static const unsigned char pattern[32] __attribute__((aligned(32))) = {
+     1,  2,  3,  4,  5,  6,  7,  8,  9, 10, 11, 12, 13, 14, 15, 16,
+    17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32,
+};
+
+static unsigned char syscalled[32] __attribute__((aligned(32)));
+static unsigned char signaled[32] __attribute__((aligned(32)));
+
+static volatile int caught = 0;
+
+static int same(const unsigned char *a, const unsigned char *b) {
+    for (int i = 0; i < 32; i++) {
+        if (a[i] != b[i])
+            return 0;
+    }
+
+    return 1;
+}
+
+/* Clobbers the vector registers and MXCSR, and skips the `ud2`. */
+static void handler(int signum, void *info, void *context) {
+    struct k_ucontext *uc = context;
+    unsigned int mxcsr = 0x7f80; /* round down */
+
+    (void) info;
+    if (signum != SIGILL)
+        _exit(6);
+
+    asm volatile(
+        "vpcmpeqd %%ymm0, %%ymm0, %%ymm0\n"
+        "vmovdqa  %%ymm0, %%ymm14\n"
+        "vmovdqa  %%ymm0, %%ymm15\n"
+        "ldmxcsr  %0\n"
+        :: "m" (mxcsr) : "xmm0", "xmm14", "xmm15"
+    );
+
+    caught++;
+    uc->rip += 2;
+}
+
+int main(void) {
+    struct k_sigaction act = {
+        .handler = handler,
+        .flags = SA_SIGINFO | SA_RESTORER,
+        .restorer = restore_rt,
+        .mask = 0,
+    };
+    unsigned int mxcsr = 0;
+
+    if (rt_sigaction(SIGILL, &act, NULL) < 0)
+        return 1;
+
+    /* ymm14 must survive a syscall, ymm15 and MXCSR a signal handler. */
+    asm volatile(
+        "vmovdqa  (%[pattern]), %%ymm14\n"
+        "vmovdqa  (%[pattern]), %%ymm15\n"
+        "mov      $102, %%eax\n" /* getuid() */
+        "syscall\n"
+        "vmovdqa  %%ymm14, (%[syscalled])\n"
+        "ud2\n"
+        "vmovdqa  %%ymm15, (%[signaled])\n"
+        "stmxcsr  %[mxcsr]\n"
+        : [mxcsr] "=m" (mxcsr)
+        : [pattern] "r" (pattern), [syscalled] "r" (syscalled), [signaled] "r" (signaled)
+        : "rax", "rcx", "r11", "xmm14", "xmm15", "memory"
+    );
+
+    if (caught != 1)
+        return 2;
+
+    if (!same(syscalled, pattern))
+        return 3;
+
+    if (!same(signaled, pattern))
+        return 4;
+
+    if (mxcsr != 0x1f80)
+        return 5;
+
+    return 0;
+}
@@ -0,0 +1,64 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+#include <stddef.h>
+
+static int equal(const char *a, const char *b, size_t len) {
+    for (size_t i = 0; i < len; i++) {
+        if (a[i] != b[i])
+            return 0;
+    }
+
+    return 1;
+}
+
+/*
+ * Expects `/state` to be a sealed mount. The first run creates `/state/file`
+ * and later runs check that it can be read back.
+ */
+int main(void) {
+    static const char msg[] = "Hello, World!";
+    char buf[sizeof(msg)] = {0};
+    struct stat st;
+    int fd;
+
+    fd = open("/state/file", O_RDWR | O_CREAT, 0600);
+    if (fd < 0)
+        return 1;
+
+    if (fstat(fd, &st) < 0)
+        return 2;
+
+    if (st.st_size == 0) {
+        if (write(fd, msg, 7) != 7)
+            return 3;
+
+        close(fd);
+
+        /* Appending is done by the shim. */
+        fd = open("/state/file", O_WRONLY | O_APPEND);
+        if (fd < 0)
+            return 4;
+
+        if (lseek(fd, 0, SEEK_SET) != 0)
+            return 5;
+
+        if (write(fd, msg + 7, sizeof(msg) - 8) != sizeof(msg) - 8)
+            return 6;
+
+        if (fstat(fd, &st) < 0 || st.st_size != sizeof(msg) - 1)
+            return 7;
+
+        close(fd);
+        return 0;
+    }
+
+    if (st.st_size != sizeof(msg) - 1)
+        return 8;
+
+    if (read(fd, buf, sizeof(buf)) != sizeof(msg) - 1 || !equal(buf, msg, sizeof(msg) - 1))
+        return 9;
+
+    close(fd);
+    return 0;
+}
@@ -0,0 +1,11 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+
+/* Divides by zero without a SIGFPE handler, which terminates the keep. */
+int main(void) {
+    volatile int one = 1;
+    volatile int zero = 0;
+
+    return one / zero;
+}
@@ -0,0 +1,59 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+#include <signal.h>
+
+#ifndef SA_RESTORER
+#define SA_RESTORER 0x04000000
+#endif
+
+/* Returns from a signal handler, as the restorer of a libc does. */
+void restore_rt(void);
+asm(
+    ".text\n"
+    "restore_rt:\n"
+    "    mov $15, %rax\n"
+    "    syscall\n"
+);
+
+/* The start of the kernel's `struct ucontext` */
+struct k_ucontext {
+    unsigned long flags;
+    void *link;
+    stack_t stack;
+    unsigned long r8, r9, r10, r11, r12, r13, r14, r15;
+    unsigned long rdi, rsi, rbp, rbx, rdx, rax, rcx, rsp, rip;
+};
+
+static volatile int caught = 0;
+
+/* Skips the faulting `ud2` instruction. */
+static void handler(int signum, void *info, void *context) {
+    struct k_ucontext *uc = context;
+
+    (void) info;
+    if (signum != SIGILL)
+        _exit(3);
+
+    caught++;
+    uc->rip += 2;
+}
+
+int main(void) {
+    struct k_sigaction act = {
+        .handler = handler,
+        .flags = SA_SIGINFO | SA_RESTORER,
+        .restorer = restore_rt,
+        .mask = 0,
+    };
+
+    if (rt_sigaction(SIGILL, &act, NULL) < 0)
+        return 1;
+
+    asm volatile("ud2");
+
+    if (caught != 1)
+        return 2;
+
+    return 0;
+}
@@ -0,0 +1,31 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+#include <signal.h>
+
+int main(void) {
+    unsigned long set = 1UL << (SIGUSR1 - 1);
+    unsigned long wait = 1UL << (SIGUSR2 - 1);
+    unsigned long old = 0;
+    struct epoll_event event;
+    int epfd;
+
+    if (rt_sigprocmask(SIG_SETMASK, &set, NULL) < 0)
+        return 1;
+
+    epfd = epoll_create1(0);
+    if (epfd < 0)
+        return 2;
+
+    /* The mask of the wait only holds until it returns. */
+    if (k_epoll_pwait(epfd, &event, 1, 0, &wait, sizeof(wait)) != 0)
+        return 3;
+
+    if (rt_sigprocmask(SIG_BLOCK, NULL, &old) < 0 || old != set)
+        return 4;
+
+    if (k_epoll_pwait(epfd, &event, 1, 0, &wait, 4) != -1 || errno != EINVAL)
+        return 5;
+
+    return 0;
+}
@@ -0,0 +1,15 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+
+/* Recurses until the stack runs into the guard pages below it. */
+static int recurse(volatile char *prev) {
+    volatile char frame[1024];
+
+    frame[0] = prev ? prev[0] + 1 : 0;
+    return recurse(frame) + frame[1];
+}
+
+int main(void) {
+    return recurse(0);
+}
@@ -0,0 +1,44 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+#include <sys/socket.h>
+#include <stddef.h>
+
+int main(void) {
+    static const char msg[] = "Hello, World!";
+    char buf[sizeof(msg)] = {0};
+    int sv[2] = {-1, -1};
+    int type = 0;
+    socklen_t len = sizeof(type);
+
+    if (socketpair(AF_UNIX, SOCK_STREAM, 0, sv) < 0)
+        return 1;
+
+    if (getsockopt(sv[0], SOL_SOCKET, SO_TYPE, &type, &len) < 0)
+        return 2;
+
+    if (type != SOCK_STREAM || len != sizeof(type))
+        return 3;
+
+    if (sendto(sv[0], msg, sizeof(msg), 0, NULL, 0) != sizeof(msg))
+        return 4;
+
+    if (shutdown(sv[0], SHUT_WR) < 0)
+        return 5;
+
+    if (recvfrom(sv[1], buf, sizeof(buf), 0, NULL, NULL) != sizeof(msg))
+        return 6;
+
+    for (size_t i = 0; i < sizeof(msg); i++) {
+        if (buf[i] != msg[i])
+            return 7;
+    }
+
+    /* The peer has shut down writing, so we see EOF. */
+    if (recvfrom(sv[1], buf, sizeof(buf), 0, NULL, NULL) != 0)
+        return 8;
+
+    close(sv[0]);
+    close(sv[1]);
+    return 0;
+}
@@ -0,0 +1,19 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "libc.h"
+
+/* Checks that the payload sees the memory of the keep, without swap. */
+int main(void) {
+    struct sysinfo info;
+
+    if (sysinfo(&info) != 0)
+        return 1;
+
+    if (info.mem_unit == 0 || info.totalram == 0 || info.freeram > info.totalram)
+        return 2;
+
+    if (info.totalswap != 0 || info.procs != 1)
+        return 3;
+
+    return 0;
+}
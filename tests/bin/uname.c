@@ -25,5 +25,10 @@ int main(void) {
         return 1;
     }
 
+    /* Keeps describe themselves, not the host. */
+    if (strcmp(buffer.nodename, "enarx") != 0) {
+        return 1;
+    }
+
     return 0;
 }
@@ -54,6 +54,18 @@ fn run_test<'a>(
     input: impl Into<Option<&'a [u8]>>,
     expected_stdout: impl Into<Option<&'a [u8]>>,
     expected_stderr: impl Into<Option<&'a [u8]>>,
+) -> Output {
+    run_test_with_args(bin, &[], status, input, expected_stdout, expected_stderr)
+}
+
+/// Like `run_test()`, but passes additional arguments to `exec`.
+fn run_test_with_args<'a>(
+    bin: &str,
+    args: &[&str],
+    status: i32,
+    input: impl Into<Option<&'a [u8]>>,
+    expected_stdout: impl Into<Option<&'a [u8]>>,
+    expected_stderr: impl Into<Option<&'a [u8]>>,
 ) -> Output {
     let expected_stdout = expected_stdout.into();
     let expected_stderr = expected_stderr.into();
@@ -62,6 +74,7 @@ fn run_test<'a>(
     let mut child = Command::new(&String::from(KEEP_BIN))
         .current_dir(CRATE)
         .arg("exec")
+        .args(args)
         .arg(bin_path)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
@@ -151,6 +164,31 @@ fn exit_one() {
     run_test("exit_one", 1, None, None, None);
 }
 
+#[test]
+#[serial]
+fn unknown_backend() {
+    let output = run_test_with_args("exit_zero", &["--backend", "none"], 1, None, None, None);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown keep backend"));
+}
+
+/// A keep which never ends is stopped by its timeout, and one whose payload
+/// repeats a failing syscall by its watchdog.
+#[test]
+#[serial]
+fn timeout() {
+    use enarx_keepldr::exit::TIMED_OUT;
+
+    for args in [["--timeout", "1"], ["--watchdog", "1"]].iter() {
+        run_test_with_args("close_loop", args, TIMED_OUT, None, None, None);
+    }
+}
+
+#[test]
+#[serial]
+fn arch_prctl() {
+    run_test("arch_prctl", 0, None, None, None);
+}
+
 #[test]
 #[serial]
 fn clock_gettime() {
@@ -180,6 +218,12 @@ fn clock_gettime() {
     assert!(nsec < MAX_SEC * NSEC_PER_SEC);
 }
 
+#[test]
+#[serial]
+fn nanosleep() {
+    run_test("nanosleep", 0, None, None, None);
+}
+
 #[test]
 #[serial]
 fn close() {
@@ -192,6 +236,37 @@ fn write_stdout() {
     run_test("write_stdout", 0, None, &b"hi\n"[..], None);
 }
 
+#[test]
+#[serial]
+fn sandbox() {
+    // The keeps of `exec` are sandboxed unless `--no-sandbox` is given. The
+    // SGX backend serves the clock and the deferred writes of the shim from
+    // threads of its own, which have to be running before.
+    run_test("clock_gettime", 0, None, None, None);
+    run_test("write_stdout", 0, None, &b"hi\n"[..], None);
+}
+
+#[test]
+#[serial]
+fn trace() {
+    let output = run_test_with_args("write_stdout", &["--trace"], 0, None, &b"hi\n"[..], None);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr
+        .lines()
+        .any(|l| l.starts_with("write(1, ") && l.ends_with(", 3) = 3")));
+}
+
+#[test]
+#[serial]
+fn profile() {
+    let output = run_test_with_args("write_stdout", &["--profile"], 0, None, &b"hi\n"[..], None);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(" inside the keep ("));
+    assert!(stderr
+        .lines()
+        .any(|l| l.starts_with("write ") && l.split_whitespace().nth(1) == Some("1")));
+}
+
 #[test]
 #[serial]
 fn write_stderr() {
@@ -232,6 +307,30 @@ fn echo() {
     run_test("echo", 0, input.as_slice(), input.as_slice(), None);
 }
 
+#[test]
+#[serial]
+fn pipe() {
+    run_test("pipe", 0, None, None, None);
+}
+
+#[test]
+#[serial]
+fn prctl() {
+    run_test("prctl", 0, None, None, None);
+}
+
+#[test]
+#[serial]
+fn sched_getaffinity() {
+    run_test("sched_getaffinity", 0, None, None, None);
+}
+
+#[test]
+#[serial]
+fn sysinfo() {
+    run_test("sysinfo", 0, None, None, None);
+}
+
 #[test]
 #[serial]
 fn uname() {
@@ -310,6 +409,103 @@ fn get_att() {
     run_test("get_att", 0, None, None, None);
 }
 
+/// Measures a test binary with the `measure` subcommand
+#[cfg(feature = "backend-sgx")]
+fn measure(bin: &str, args: &[&str]) -> String {
+    let bin_path = Path::new(CRATE).join(OUT_DIR).join(TEST_BINS_OUT).join(bin);
+
+    let output = Command::new(KEEP_BIN)
+        .arg("measure")
+        .args(&["--backend", "sgx"])
+        .args(args)
+        .arg(bin_path)
+        .output()
+        .unwrap_or_else(|e| panic!("failed to measure `{}`: {:#?}", bin, e));
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[cfg(feature = "backend-sgx")]
+#[test]
+fn sgx_measure_stable() {
+    let first = measure("exit_zero", &[]);
+    assert_eq!(first.trim_end().len(), 64);
+    assert_eq!(measure("exit_zero", &[]), first);
+
+    assert_ne!(measure("exit_one", &[]), first);
+    assert_ne!(measure("exit_zero", &["--heap-size", "1M"]), first);
+
+    // A lazy heap is not measured, whatever its size.
+    let lazy = measure("exit_zero", &["--sgx-lazy-heap"]);
+    assert_ne!(lazy, first);
+    assert_eq!(
+        measure("exit_zero", &["--sgx-lazy-heap", "--heap-size", "1M"]),
+        lazy
+    );
+}
+
+#[cfg(all(
+    feature = "backend-sgx",
+    feature = "backend-kvm",
+    feature = "builtin-shims"
+))]
+#[test]
+fn sgx_measure_foreign_shim() {
+    // The shim of KVM keeps lacks the notes which size the enclave.
+    let shim = Path::new(OUT_DIR).join(TEST_BINS_OUT).join("shim-sev");
+    let bin = Path::new(OUT_DIR).join(TEST_BINS_OUT).join("exit_zero");
+
+    let output = Command::new(KEEP_BIN)
+        .arg("measure")
+        .args(&["--backend", "sgx"])
+        .arg("--shim")
+        .arg(shim)
+        .arg(bin)
+        .output()
+        .unwrap_or_else(|e| panic!("failed to measure `exit_zero`: {:#?}", e));
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("NOTE_ENARX_SGX_SIZE"), "{}", stderr);
+}
+
+#[cfg(feature = "backend-sgx")]
+#[test]
+fn sgx_measure_requests() {
+    // The payload requests a heap of 16 MiB, unless `--heap-size` is given.
+    let requested = measure("requests", &[]);
+    assert_eq!(measure("requests", &["--heap-size", "16M"]), requested);
+    assert_ne!(measure("requests", &["--heap-size", "1M"]), requested);
+}
+
+#[test]
+#[serial]
+fn requests() {
+    run_test("requests", 0, None, None, None);
+}
+
+#[test]
+fn validate() {
+    let bin_path = Path::new(CRATE).join(OUT_DIR).join(TEST_BINS_OUT);
+    let validate = |path: &Path| {
+        Command::new(KEEP_BIN)
+            .arg("validate")
+            .arg(path)
+            .output()
+            .unwrap_or_else(|e| panic!("failed to validate `{}`: {:#?}", path.display(), e))
+    };
+
+    let output = validate(&bin_path.join("exit_zero"));
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Static PIE"));
+
+    // The loader itself is dynamically linked.
+    let output = validate(Path::new(KEEP_BIN));
+    assert_eq!(output.status.code(), Some(1));
+}
+
 #[cfg(feature = "backend-sgx")]
 #[test]
 #[serial]
@@ -324,6 +520,12 @@ fn sgx_get_att_quote_size() {
     run_test("sgx_get_att_quote_size", 0, None, None, None);
 }
 
+#[test]
+#[serial]
+fn get_key() {
+    run_test("get_key", 0, None, None, None);
+}
+
 #[test]
 #[serial]
 fn getuid() {
@@ -366,6 +568,208 @@ fn listen() {
     run_test("listen", 0, None, None, None);
 }
 
+#[test]
+#[serial]
+fn socketpair() {
+    run_test("socketpair", 0, None, None, None);
+}
+
+#[test]
+#[serial]
+fn epoll() {
+    run_test("epoll", 0, None, None, None);
+}
+
+#[test]
+#[serial]
+fn eventfd() {
+    run_test("eventfd", 0, None, None, None);
+}
+
+#[test]
+#[serial]
+fn madvise() {
+    run_test("madvise", 0, None, None, None);
+}
+
+#[test]
+#[serial]
+fn memfs() {
+    run_test("memfs", 0, None, None, None);
+}
+
+#[test]
+#[serial]
+fn mount() {
+    let data = TempDir::new("mount_data").unwrap();
+    let ro = TempDir::new("mount_ro").unwrap();
+    fs::write(data.path().join("in"), "Hello, World!").unwrap();
+    fs::write(ro.path().join("file"), "").unwrap();
+
+    let data_mount = format!("{}:/data", data.path().display());
+    let ro_mount = format!("{}:/ro:ro", ro.path().display());
+    let args = ["--mount", &data_mount, "--mount", &ro_mount];
+    run_test_with_args("mount", &args, 0, None, None, None);
+
+    let out = fs::read(data.path().join("dir").join("out")).unwrap();
+    assert_eq!(out, b"Hello, world!");
+    assert!(!data.path().join("in").exists());
+}
+
+#[cfg(feature = "backend-sgx")]
+#[test]
+#[serial]
+fn sgx_sealed() {
+    let state = TempDir::new("sgx_sealed").unwrap();
+    let mount = format!("{}:/state:sealed", state.path().display());
+    let args = ["--mount", &mount];
+
+    // The first run creates the file and the second one reads it back.
+    run_test_with_args("sgx_sealed", &args, 0, None, None, None);
+    run_test_with_args("sgx_sealed", &args, 0, None, None, None);
+
+    let mut sealed = fs::read(state.path().join("file")).unwrap();
+    assert!(!sealed.windows(5).any(|w| w == b"Hello"));
+
+    // Tampering with the header is detected when the file is opened.
+    sealed[20] ^= 1;
+    fs::write(state.path().join("file"), &sealed).unwrap();
+    run_test_with_args("sgx_sealed", &args, 1, None, None, None);
+}
+
+#[cfg(feature = "backend-sgx")]
+#[test]
+#[serial]
+fn sgx_sigill() {
+    run_test("sgx_sigill", 0, None, None, None);
+}
+
+#[cfg(feature = "backend-sgx")]
+#[test]
+#[serial]
+fn sgx_sigmask() {
+    run_test("sgx_sigmask", 0, None, None, None);
+}
+
+#[cfg(feature = "backend-sgx")]
+#[test]
+#[serial]
+fn sgx_sigfpe() {
+    // 128 + SIGFPE, as a shell reports it
+    run_test("sgx_sigfpe", 136, None, None, None);
+}
+
+#[cfg(feature = "backend-sgx")]
+#[test]
+#[serial]
+fn sgx_core() {
+    let dir = TempDir::new("core").unwrap();
+    let path = dir.path().join("core");
+    let args = ["--debug-keep", "--core", path.to_str().unwrap()];
+    run_test_with_args("sgx_sigfpe", &args, 136, None, None, None);
+
+    let core = fs::read(&path).unwrap();
+    let elf = goblin::elf::Elf::parse(&core).unwrap();
+    assert_eq!(elf.header.e_type, goblin::elf::header::ET_CORE);
+    assert!(elf.program_headers.len() > 1);
+}
+
+#[cfg(feature = "backend-sgx")]
+#[test]
+#[serial]
+fn sgx_backtrace() {
+    let output = run_test_with_args("sgx_sigfpe", &["--debug-keep"], 136, None, None, None);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("killed by signal 8"), "{}", stderr);
+    assert!(stderr.contains("   0: 0x"), "{}", stderr);
+    assert!(stderr.contains(" main+0x"), "{}", stderr);
+}
+
+#[cfg(feature = "backend-sgx")]
+#[test]
+#[serial]
+fn sgx_memory_usage() {
+    // memspike allocates 40 MB.
+    let output = run_test_with_args("memspike", &["--memory-usage"], 0, None, None, None);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let usage = stderr
+        .lines()
+        .find(|l| l.starts_with("payload memory at most: "))
+        .unwrap_or_else(|| panic!("{}", stderr));
+    assert!(usage.contains(" MiB mmap, "), "{}", usage);
+    assert!(stderr.contains("\nEPC pages: "), "{}", stderr);
+}
+
+#[cfg(feature = "backend-sgx")]
+#[test]
+#[serial]
+fn sgx_avx() {
+    // x87, SSE and AVX
+    let args = ["--sgx-xfrm", "0x7"];
+    run_test_with_args("sgx_avx", &args, 0, None, None, None);
+}
+
+#[cfg(feature = "backend-sgx")]
+#[test]
+#[serial]
+fn sgx_stack_overflow() {
+    let output = run_test("sgx_stack_overflow", 1, None, None, None);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("payload stack overflow at 0x"),
+        "{}",
+        stderr
+    );
+}
+
+// Only KVM keeps take secrets from the host, so the backend is picked by name.
+#[cfg(feature = "backend-kvm")]
+#[test]
+#[serial]
+fn kvm_secret() {
+    let args = ["--backend", "kvm", "--secret", "-"];
+    run_test_with_args("kvm_secret", &args, 0, &b"Hello, World!"[..], None, None);
+}
+
+#[test]
+#[serial]
+fn environ() {
+    let args = ["--arg", "a", "--arg", "b c", "--env", "GREETING=hello"];
+    let stdout = b"[\"/init\", \"a\", \"b c\"]\nhello\n";
+    run_test_with_args("environ", &args, 0, None, &stdout[..], None);
+}
+
+#[cfg(any(feature = "backend-kvm", feature = "backend-sgx"))]
+#[test]
+#[serial]
+fn deterministic() {
+    let args = ["--debug-keep", "--deterministic", "42"];
+    let first = run_test_with_args("deterministic", &args, 0, None, None, None);
+    let second = run_test_with_args("deterministic", &args, 0, None, None, None);
+    assert_eq_slices(&first.stdout, &second.stdout, "stdout");
+
+    let args = ["--debug-keep", "--deterministic", "43"];
+    let other = run_test_with_args("deterministic", &args, 0, None, None, None);
+    assert_ne!(first.stdout, other.stdout);
+}
+
+#[test]
+#[serial]
+fn record() {
+    let dir = TempDir::new("record").unwrap();
+    let path = dir.path().join("trace.bin");
+    let path = path.to_str().unwrap();
+
+    let input: &[u8] = b"hello";
+    run_test_with_args("echo", &["--record", path], 0, input, input, None);
+
+    // The replay reads the recorded input, not stdin.
+    run_test_with_args("echo", &["--replay", path], 0, None, None, None);
+
+    // Another payload diverges from the recording.
+    run_test_with_args("uname", &["--replay", path], 1, None, None, None);
+}
+
 #[test]
 #[serial]
 fn memspike() {
@@ -0,0 +1,201 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Golden measurement tests
+//!
+//! `golden.txt` records the measurements of the payloads in `fixtures` with
+//! the shims of past releases, one per line:
+//!
+//!     <backend> <shim> <payload> [<option>...] <measurement>
+//!
+//! The shims are the stripped `shim-sgx` and `shim-sev` of each release,
+//! named after it (e.g. `shim-sgx-0.1.0`). The payloads are `exit_zero` and
+//! `requests` of `tests/bin`, built with the flags of `build.rs` and
+//! `-O2 -s -Wl,--build-id=none`. The fixtures never change, so a measurement
+//! only changes with the layout or the hashing of the loader, which would
+//! break the attestation policies which expect it.
+//!
+//! Each release of the shims adds its shims to `fixtures` and to `SHIMS`, and
+//! every payload must have a recorded measurement with each shim, so a
+//! missing one fails the tests. Running the tests with `ENARX_BLESS=1` records
+//! the missing measurements, and those of a change which alters them on
+//! purpose.
+#![cfg(all(not(miri), any(feature = "backend-sgx", feature = "backend-sev")))]
+
+use std::path::Path;
+use std::process::Command;
+
+use serial_test::serial;
+
+const CRATE: &str = env!("CARGO_MANIFEST_DIR");
+const KEEP_BIN: &str = env!("CARGO_BIN_EXE_enarx-keepldr");
+const GOLDEN: &str = "tests/measure/golden.txt";
+const FIXTURES: &str = "tests/measure/fixtures";
+
+/// The shims of the releases, by backend
+const SHIMS: &[(&str, &str)] = &[("sgx", "shim-sgx-0.1.0"), ("sev", "shim-sev-0.1.0")];
+
+/// The payloads which are measured with every shim
+const PAYLOADS: &[&str] = &["exit_zero", "requests"];
+
+/// A recorded measurement
+struct Golden<'a> {
+    backend: &'a str,
+    shim: &'a str,
+    payload: &'a str,
+    options: Vec<&'a str>,
+    measurement: &'a str,
+}
+
+impl<'a> Golden<'a> {
+    /// Parses a line of `golden.txt`, unless it is blank or a comment
+    fn parse(line: &'a str) -> Option<Self> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let mut words: Vec<_> = line.split_whitespace().collect();
+        assert!(words.len() >= 4, "invalid line in {}: `{}`", GOLDEN, line);
+
+        let measurement = words.pop().unwrap();
+        let options = words.split_off(3);
+        Some(Self {
+            backend: words[0],
+            shim: words[1],
+            payload: words[2],
+            options,
+            measurement,
+        })
+    }
+
+    /// Measures the payload with the shim, as recorded
+    fn measure(&self) -> String {
+        let fixtures = Path::new(CRATE).join(FIXTURES);
+        assert!(
+            fixtures.join(self.shim).exists(),
+            "{}: the shim is missing from {}",
+            self,
+            FIXTURES
+        );
+
+        let output = Command::new(KEEP_BIN)
+            .current_dir(CRATE)
+            .arg("measure")
+            .args(&["--backend", self.backend])
+            .arg("--shim")
+            .arg(fixtures.join(self.shim))
+            .args(&self.options)
+            .arg(fixtures.join(self.payload))
+            .output()
+            .unwrap_or_else(|e| panic!("failed to measure `{}`: {:#?}", self.payload, e));
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{}: {}", self, stderr);
+        String::from_utf8(output.stdout).unwrap().trim_end().into()
+    }
+}
+
+impl std::fmt::Display for Golden<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "`{}` on {} with `{}`",
+            self.payload, self.backend, self.shim
+        )?;
+        for option in &self.options {
+            write!(f, " {}", option)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks the recorded measurements of a backend, or records them again
+fn check(backend: &str) {
+    let path = Path::new(CRATE).join(GOLDEN);
+    let golden = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("failed to read {}: {:#?}", GOLDEN, e));
+
+    let mut lines = Vec::new();
+    let mut failures = Vec::new();
+    let mut missing: Vec<_> = SHIMS
+        .iter()
+        .filter(|(b, _)| *b == backend)
+        .flat_map(|(_, shim)| PAYLOADS.iter().map(move |payload| (*shim, *payload)))
+        .collect();
+
+    for line in golden.lines() {
+        let recorded = match Golden::parse(line) {
+            Some(recorded) if recorded.backend == backend => recorded,
+            _ => {
+                lines.push(line.to_string());
+                continue;
+            }
+        };
+
+        if recorded.options.is_empty() {
+            missing.retain(|case| *case != (recorded.shim, recorded.payload));
+        }
+
+        let measured = recorded.measure();
+        if measured == recorded.measurement {
+            lines.push(line.to_string());
+            continue;
+        }
+
+        failures.push(format!(
+            "{}: recorded {}, measured {}",
+            recorded, recorded.measurement, measured
+        ));
+
+        let end = line.rfind(recorded.measurement).unwrap();
+        lines.push(format!("{}{}", &line[..end], measured));
+    }
+
+    for (shim, payload) in missing {
+        let unrecorded = Golden {
+            backend,
+            shim,
+            payload,
+            options: Vec::new(),
+            measurement: "",
+        };
+
+        let measured = unrecorded.measure();
+        failures.push(format!(
+            "{}: not recorded, measured {}",
+            unrecorded, measured
+        ));
+        lines.push(format!("{} {} {} {}", backend, shim, payload, measured));
+    }
+
+    if std::env::var_os("ENARX_BLESS").is_some() {
+        if !failures.is_empty() {
+            lines.push(String::new());
+            std::fs::write(&path, lines.join("\n"))
+                .unwrap_or_else(|e| panic!("failed to write {}: {:#?}", GOLDEN, e));
+        }
+
+        return;
+    }
+
+    assert!(
+        failures.is_empty(),
+        "the measurements changed or are missing (run with `ENARX_BLESS=1` to record them):\n{}",
+        failures.join("\n")
+    );
+}
+
+#[cfg(feature = "backend-sgx")]
+#[test]
+#[serial]
+fn sgx() {
+    check("sgx");
+}
+
+#[cfg(feature = "backend-sev")]
+#[test]
+#[serial]
+fn sev() {
+    check("sev");
+}
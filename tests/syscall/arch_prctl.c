@@ -0,0 +1,21 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Sets and gets the FS base, and refuses unknown codes. */
+int main(void) {
+    static unsigned long tls[1];
+    unsigned long fs = 0;
+
+    tls[0] = (unsigned long) tls;
+    if (arch_prctl(ARCH_SET_FS, (unsigned long) tls) != 0)
+        return 1;
+
+    if (arch_prctl(ARCH_GET_FS, (unsigned long) &fs) != 0 || fs != (unsigned long) tls)
+        return 2;
+
+    if (arch_prctl(0, 0) >= 0 || errno != EINVAL)
+        return 3;
+
+    return 0;
+}
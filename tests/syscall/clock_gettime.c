@@ -0,0 +1,22 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Reads clocks which are set and don't go backwards. */
+int main(void) {
+    struct timespec a, b;
+
+    if (clock_gettime(CLOCK_REALTIME, &a) != 0 || a.tv_sec <= 0)
+        return 1;
+
+    if (clock_gettime(CLOCK_MONOTONIC, &a) != 0 || clock_gettime(CLOCK_MONOTONIC, &b) != 0)
+        return 2;
+
+    if (b.tv_sec < a.tv_sec || (b.tv_sec == a.tv_sec && b.tv_nsec < a.tv_nsec))
+        return 3;
+
+    if (a.tv_nsec < 0 || a.tv_nsec >= 1000000000 || b.tv_nsec < 0 || b.tv_nsec >= 1000000000)
+        return 4;
+
+    return 0;
+}
@@ -0,0 +1,22 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Closes a descriptor once, and only once. */
+int main(void) {
+    int fds[2];
+
+    if (pipe2(fds, 0) < 0)
+        return 1;
+
+    if (close(fds[1]) != 0)
+        return 2;
+
+    if (close(fds[1]) >= 0 || errno != EBADF)
+        return 3;
+
+    if (close(-1) >= 0 || errno != EBADF)
+        return 4;
+
+    return close(fds[0]) != 0 ? 5 : 0;
+}
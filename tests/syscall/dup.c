@@ -0,0 +1,28 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Duplicated descriptors refer to the same pipe. */
+int main(void) {
+    char buf[4];
+    int fds[2];
+    int fd;
+
+    if (pipe2(fds, 0) < 0)
+        return 1;
+
+    fd = dup(fds[1]);
+    if (fd < 0 || fd == fds[0] || fd == fds[1])
+        return 2;
+
+    if (write(fd, "dup", 3) != 3 || read(fds[0], buf, sizeof(buf)) != 3)
+        return 3;
+
+    if (dup2(fds[1], fd) != fd)
+        return 4;
+
+    if (dup(-1) >= 0 || errno != EBADF)
+        return 5;
+
+    return 0;
+}
@@ -0,0 +1,33 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Waits for a pipe to become readable. */
+int main(void) {
+    struct epoll_event ev, out;
+    int fds[2];
+    int epfd;
+
+    epfd = epoll_create1(0);
+    if (epfd < 0 || pipe2(fds, 0) < 0)
+        return 1;
+
+    ev.events = EPOLLIN;
+    ev.data.u64 = 42;
+    if (epoll_ctl(epfd, EPOLL_CTL_ADD, fds[0], &ev) != 0)
+        return 2;
+
+    if (epoll_wait(epfd, &out, 1, 0) != 0)
+        return 3;
+
+    if (write(fds[1], "x", 1) != 1)
+        return 4;
+
+    if (epoll_wait(epfd, &out, 1, 1000) != 1 || out.events != EPOLLIN || out.data.u64 != 42)
+        return 5;
+
+    if (epoll_ctl(epfd, EPOLL_CTL_ADD, fds[0], &ev) >= 0 || errno != EEXIST)
+        return 6;
+
+    return 0;
+}
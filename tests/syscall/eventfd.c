@@ -0,0 +1,27 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Adds to the counter of an eventfd and reads it back. */
+int main(void) {
+    unsigned long value = 2;
+    int fd;
+
+    fd = eventfd(1, EFD_NONBLOCK);
+    if (fd < 0)
+        return 1;
+
+    if (write(fd, &value, sizeof(value)) != sizeof(value))
+        return 2;
+
+    if (read(fd, &value, sizeof(value)) != sizeof(value) || value != 3)
+        return 3;
+
+    if (read(fd, &value, sizeof(value)) >= 0 || errno != EAGAIN)
+        return 4;
+
+    if (eventfd(0, -1) >= 0 || errno != EINVAL)
+        return 5;
+
+    return 0;
+}
@@ -0,0 +1,27 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Gets and sets the flags of descriptors, and duplicates them. */
+int main(void) {
+    int fds[2];
+    int fd;
+
+    if (pipe2(fds, 0) < 0)
+        return 1;
+
+    if (fcntl(fds[0], F_SETFD, FD_CLOEXEC) != 0 || fcntl(fds[0], F_GETFD) != FD_CLOEXEC)
+        return 2;
+
+    if (fcntl(fds[0], F_SETFL, O_NONBLOCK) != 0 || !(fcntl(fds[0], F_GETFL) & O_NONBLOCK))
+        return 3;
+
+    fd = fcntl(fds[1], F_DUPFD, 100);
+    if (fd < 100)
+        return 4;
+
+    if (fcntl(-1, F_GETFD) >= 0 || errno != EBADF)
+        return 5;
+
+    return 0;
+}
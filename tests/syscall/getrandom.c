@@ -0,0 +1,27 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Fills a buffer with random bytes. */
+int main(void) {
+    unsigned char buf[64];
+    int zero = 0;
+
+    for (size_t i = 0; i < sizeof(buf); i++)
+        buf[i] = 0;
+
+    if (getrandom(buf, sizeof(buf), 0) != sizeof(buf))
+        return 1;
+
+    for (size_t i = 0; i < sizeof(buf); i++)
+        zero += buf[i] == 0;
+
+    /* 64 zero bytes are as likely as winning the lottery every week, forever. */
+    if (zero == sizeof(buf))
+        return 2;
+
+    if (getrandom(buf, 0, 0) != 0)
+        return 3;
+
+    return 0;
+}
@@ -0,0 +1,14 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* The payload runs as the same fixed user and group in every keep. */
+int main(void) {
+    if (getuid() != 1000 || geteuid() != 1000)
+        return 1;
+
+    if (getgid() != 1000 || getegid() != 1000)
+        return 2;
+
+    return 0;
+}
@@ -0,0 +1,29 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Drops the contents of private anonymous pages, and ignores hints. */
+int main(void) {
+    char *map;
+
+    map = mmap(NULL, 2 * 4096, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    if (map == MAP_FAILED)
+        return 1;
+
+    map[0] = 1;
+    map[4096] = 1;
+
+    if (madvise(map, 4096, MADV_DONTNEED) != 0 || map[0] != 0 || map[4096] != 1)
+        return 2;
+
+    if (madvise(map, 2 * 4096, MADV_WILLNEED) != 0 || map[4096] != 1)
+        return 3;
+
+    if (madvise(map + 1, 4096, MADV_DONTNEED) >= 0 || errno != EINVAL)
+        return 4;
+
+    if (madvise(map, 4096, -1) >= 0 || errno != EINVAL)
+        return 5;
+
+    return 0;
+}
@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Syscall conformance tests
+//!
+//! Each payload in this directory exercises one syscall, or a few which only
+//! make sense together, and exits with 0 if it behaved like it does on Linux,
+//! or with the number of the check which failed. Every test runs its payload
+//! on every backend which the host supports, so that a regression in one shim
+//! isn't hidden by the others. `ENARX_BACKEND` restricts the tests to one
+//! backend, which must then be supported.
+#![cfg(not(miri))]
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use enarx_keepldr::backend;
+use process_control::{ChildExt, Timeout};
+use serial_test::serial;
+
+const CRATE: &str = env!("CARGO_MANIFEST_DIR");
+const KEEP_BIN: &str = env!("CARGO_BIN_EXE_enarx-keepldr");
+const OUT_DIR: &str = env!("OUT_DIR");
+const TEST_SYSCALLS_OUT: &str = "syscall";
+const TIMEOUT_SECS: u64 = 10;
+
+/// The names of the backends to run the payloads on
+fn backends() -> Vec<&'static str> {
+    let backends = backend::all();
+
+    let names: Vec<_> = match std::env::var("ENARX_BACKEND") {
+        Ok(name) => vec![backend::select(&backends, &name)
+            .unwrap_or_else(|e| panic!("{:#}", e))
+            .name()],
+        Err(_) => backends
+            .iter()
+            .filter(|b| b.have())
+            .map(|b| b.name())
+            .collect(),
+    };
+
+    assert!(!names.is_empty(), "no keep backend is supported");
+    names
+}
+
+/// Runs a payload on a backend, and describes how it failed if it did
+fn exec(backend: &str, payload: &str) -> Result<(), String> {
+    let path = Path::new(OUT_DIR).join(TEST_SYSCALLS_OUT).join(payload);
+
+    let child = Command::new(KEEP_BIN)
+        .current_dir(CRATE)
+        .arg("exec")
+        .arg("--backend")
+        .arg(backend)
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|e| panic!("failed to run `{}`: {:#?}", payload, e));
+
+    let output = child
+        .with_output_timeout(Duration::from_secs(TIMEOUT_SECS))
+        .terminating()
+        .wait()
+        .unwrap_or_else(|e| panic!("failed to run `{}`: {:#?}", payload, e))
+        .ok_or_else(|| format!("{}: timed out", backend))?;
+
+    let _ = std::io::stderr().write_all(&output.stderr);
+
+    match output.status.code() {
+        Some(0) => Ok(()),
+        Some(code) => Err(format!("{}: check {} failed", backend, code)),
+        None => Err(format!(
+            "{}: terminated by signal {:?}",
+            backend,
+            output.status.signal()
+        )),
+    }
+}
+
+/// Runs a payload on all backends, and fails if it fails on any of them
+fn run(payload: &str) {
+    let failures: Vec<_> = backends()
+        .into_iter()
+        .filter_map(|backend| exec(backend, payload).err())
+        .collect();
+
+    assert!(
+        failures.is_empty(),
+        "`{}` failed on {}",
+        payload,
+        failures.join(", ")
+    );
+}
+
+/// Declares a test for each payload, named after it
+macro_rules! syscalls {
+    ($($payload:ident),* $(,)?) => {
+        $(
+            #[test]
+            #[serial]
+            fn $payload() {
+                run(stringify!($payload));
+            }
+        )*
+    };
+}
+
+syscalls! {
+    arch_prctl,
+    clock_gettime,
+    close,
+    dup,
+    epoll,
+    eventfd,
+    fcntl,
+    getrandom,
+    getuid,
+    madvise,
+    mlock,
+    mmap,
+    nanosleep,
+    pipe2,
+    poll,
+    read,
+    readv,
+    sched_getaffinity,
+    socketpair,
+    sysinfo,
+    uname,
+    write,
+}
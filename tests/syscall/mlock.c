@@ -0,0 +1,19 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Locks memory, which never leaves the keep anyway. */
+int main(void) {
+    static char page[4096];
+
+    if (mlock(page, sizeof(page)) != 0 || munlock(page, sizeof(page)) != 0)
+        return 1;
+
+    if (mlockall(MCL_CURRENT | MCL_FUTURE) != 0 || munlockall() != 0)
+        return 2;
+
+    if (mlockall(0) >= 0 || errno != EINVAL)
+        return 3;
+
+    return 0;
+}
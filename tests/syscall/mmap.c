@@ -0,0 +1,29 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Maps zeroed anonymous memory, and unmaps it again. */
+int main(void) {
+    size_t len = 4 * 4096;
+    char *map;
+
+    map = mmap(NULL, len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    if (map == MAP_FAILED || (unsigned long) map % 4096 != 0)
+        return 1;
+
+    for (size_t i = 0; i < len; i++)
+        if (map[i] != 0)
+            return 2;
+
+    map[0] = 1;
+    map[len - 1] = 1;
+
+    if (munmap(map, len) != 0)
+        return 3;
+
+    map = mmap(NULL, len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    if (map == MAP_FAILED || map[0] != 0 || map[len - 1] != 0)
+        return 4;
+
+    return 0;
+}
@@ -0,0 +1,32 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Sleeps for short and invalid times. */
+int main(void) {
+    struct timespec req, rem;
+
+    req.tv_sec = 0;
+    req.tv_nsec = 1000;
+    if (nanosleep(&req, &rem) != 0)
+        return 1;
+
+    req.tv_nsec = 1000000;
+    if (nanosleep(&req, NULL) != 0)
+        return 2;
+
+    req.tv_nsec = 1000000000;
+    if (nanosleep(&req, NULL) >= 0 || errno != EINVAL)
+        return 3;
+
+    req.tv_sec = -1;
+    req.tv_nsec = 0;
+    if (nanosleep(&req, NULL) >= 0 || errno != EINVAL)
+        return 4;
+
+    req.tv_sec = 0;
+    if (clock_nanosleep(CLOCK_MONOTONIC, 0, &req, NULL) != 0)
+        return 5;
+
+    return 0;
+}
@@ -0,0 +1,25 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Creates pipes with and without flags. */
+int main(void) {
+    int fds[2] = { -1, -1 };
+
+    if (pipe2(fds, 0) < 0 || fds[0] < 0 || fds[1] < 0 || fds[0] == fds[1])
+        return 1;
+
+    if (fcntl(fds[0], F_GETFD) != 0)
+        return 2;
+
+    if (pipe2(fds, O_CLOEXEC | O_NONBLOCK) < 0)
+        return 3;
+
+    if (fcntl(fds[1], F_GETFD) != FD_CLOEXEC || !(fcntl(fds[0], F_GETFL) & O_NONBLOCK))
+        return 4;
+
+    if (pipe2(fds, -1) >= 0 || errno != EINVAL)
+        return 5;
+
+    return 0;
+}
@@ -0,0 +1,31 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Polls a pipe before and after it becomes readable. */
+int main(void) {
+    struct pollfd pfd;
+    int fds[2];
+    char c;
+
+    if (pipe2(fds, 0) < 0)
+        return 1;
+
+    pfd.fd = fds[0];
+    pfd.events = POLLIN;
+    pfd.revents = 0;
+    if (poll(&pfd, 1, 0) != 0 || pfd.revents != 0)
+        return 2;
+
+    if (write(fds[1], "x", 1) != 1)
+        return 3;
+
+    if (poll(&pfd, 1, 1000) != 1 || pfd.revents != POLLIN)
+        return 4;
+
+    close(fds[1]);
+    if (read(fds[0], &c, 1) != 1 || poll(&pfd, 1, 0) != 1 || !(pfd.revents & POLLHUP))
+        return 5;
+
+    return 0;
+}
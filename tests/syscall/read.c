@@ -0,0 +1,31 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Reads back what was written to a pipe, and fails on closed descriptors. */
+int main(void) {
+    char buf[8];
+    int fds[2];
+
+    if (pipe2(fds, 0) < 0)
+        return 1;
+
+    if (write(fds[1], "read", 4) != 4)
+        return 2;
+
+    if (read(fds[0], buf, sizeof(buf)) != 4 || buf[0] != 'r' || buf[3] != 'd')
+        return 3;
+
+    if (read(fds[0], buf, 0) != 0)
+        return 4;
+
+    close(fds[1]);
+    if (read(fds[0], buf, sizeof(buf)) != 0)
+        return 5;
+
+    close(fds[0]);
+    if (read(fds[0], buf, sizeof(buf)) >= 0 || errno != EBADF)
+        return 6;
+
+    return 0;
+}
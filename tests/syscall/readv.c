@@ -0,0 +1,32 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Scatters a read from a pipe over two buffers, in order. */
+int main(void) {
+    char head[2], tail[8];
+    struct iovec iov[2];
+    int fds[2];
+
+    iov[0].iov_base = head;
+    iov[0].iov_len = sizeof(head);
+    iov[1].iov_base = tail;
+    iov[1].iov_len = sizeof(tail);
+
+    if (pipe2(fds, 0) < 0)
+        return 1;
+
+    if (write(fds[1], "readv", 5) != 5)
+        return 2;
+
+    if (readv(fds[0], iov, 2) != 5)
+        return 3;
+
+    if (head[0] != 'r' || head[1] != 'e' || tail[0] != 'a' || tail[2] != 'v')
+        return 4;
+
+    if (readv(fds[0], iov, 0) != 0)
+        return 5;
+
+    return 0;
+}
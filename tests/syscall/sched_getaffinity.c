@@ -0,0 +1,25 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Runs on CPU 0, which is in the affinity mask. */
+int main(void) {
+    unsigned long mask[16];
+    unsigned int cpu = 1, node = 1;
+    int size;
+
+    for (int i = 0; i < 16; i++)
+        mask[i] = 0;
+
+    size = sched_getaffinity(0, sizeof(mask), mask);
+    if (size <= 0 || size % sizeof(unsigned long) != 0 || !(mask[0] & 1))
+        return 1;
+
+    if (sched_getaffinity(0, 1, mask) >= 0 || errno != EINVAL)
+        return 2;
+
+    if (getcpu(&cpu, &node) != 0 || cpu != 0 || node != 0)
+        return 3;
+
+    return 0;
+}
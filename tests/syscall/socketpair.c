@@ -0,0 +1,23 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Passes data both ways between a pair of connected sockets. */
+int main(void) {
+    char buf[8];
+    int sv[2];
+
+    if (socketpair(AF_UNIX, SOCK_STREAM, 0, sv) < 0)
+        return 1;
+
+    if (write(sv[0], "ping", 4) != 4 || read(sv[1], buf, sizeof(buf)) != 4 || buf[1] != 'i')
+        return 2;
+
+    if (write(sv[1], "pong", 4) != 4 || read(sv[0], buf, sizeof(buf)) != 4 || buf[1] != 'o')
+        return 3;
+
+    if (shutdown(sv[0], SHUT_WR) != 0 || read(sv[1], buf, sizeof(buf)) != 0)
+        return 4;
+
+    return 0;
+}
@@ -0,0 +1,19 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Reports some memory to the payload. */
+int main(void) {
+    static struct sysinfo info;
+
+    if (sysinfo(&info) != 0)
+        return 1;
+
+    if (info.mem_unit == 0 || info.totalram == 0)
+        return 2;
+
+    if (info.freeram > info.totalram)
+        return 3;
+
+    return 0;
+}
@@ -0,0 +1,19 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Claims to be Linux on x86_64. */
+int main(void) {
+    static struct utsname buf;
+
+    if (uname(&buf) != 0)
+        return 1;
+
+    if (buf.sysname[0] != 'L' || buf.sysname[5] != '\0')
+        return 2;
+
+    if (buf.machine[0] != 'x' || buf.machine[6] != '\0')
+        return 3;
+
+    return 0;
+}
@@ -0,0 +1,29 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#include "../bin/libc.h"
+
+/* Writes to a pipe, and fails on descriptors which aren't open for writing. */
+int main(void) {
+    char buf[8];
+    int fds[2];
+
+    if (pipe2(fds, 0) < 0)
+        return 1;
+
+    if (write(fds[1], "write", 5) != 5)
+        return 2;
+
+    if (write(fds[1], buf, 0) != 0)
+        return 3;
+
+    if (read(fds[0], buf, sizeof(buf)) != 5 || buf[4] != 'e')
+        return 4;
+
+    if (write(fds[0], "write", 5) >= 0 || errno != EBADF)
+        return 5;
+
+    if (write(-1, "write", 5) >= 0 || errno != EBADF)
+        return 6;
+
+    return 0;
+}